@@ -0,0 +1,251 @@
+//! This module implements parsing of the ACPI tables the hypervisor needs: the
+//! RSDP, the XSDT or RSDT, and the MADT.
+//!
+//! Tables are read from physical memory with a function given by the caller,
+//! so that parsing does not depend on how the platform maps memory. See
+//! [`super::boot_info::record_acpi_tables`].
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+
+use super::boot_info::AcpiTable;
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    /// The APIC ID, or the x2APIC ID for the Processor Local x2APIC structure.
+    pub apic_id: u32,
+
+    /// The processor is usable at boot.
+    pub enabled: bool,
+
+    /// The processor is not usable at boot but can be enabled later, for
+    /// example, by hot-add or by un-parking.
+    pub online_capable: bool,
+}
+
+/// Returns the locations of the RSDP at `rsdp` and the tables listed in the
+/// XSDT or RSDT, reading physical memory with `read`.
+///
+/// See: 5.2.5 Root System Description Pointer (RSDP), ACPI Specification
+pub(crate) fn parse_tables(rsdp: u64, read: impl Fn(u64, &mut [u8])) -> Vec<AcpiTable> {
+    const SDT_HEADER_SIZE: u32 = 36;
+
+    let read_u32 = |address| {
+        let mut bytes = [0u8; 4];
+        read(address, &mut bytes);
+        u32::from_le_bytes(bytes)
+    };
+    let read_u64 = |address| {
+        let mut bytes = [0u8; 8];
+        read(address, &mut bytes);
+        u64::from_le_bytes(bytes)
+    };
+
+    let mut signature = [0u8; 8];
+    read(rsdp, &mut signature);
+    if &signature != b"RSD PTR " {
+        log::warn!("The RSDP at {rsdp:#x} is not valid");
+        return Vec::new();
+    }
+
+    // ACPI 2.0 and later have the XSDT with 64-bit entries. Prefer it.
+    let mut revision = [0u8; 1];
+    read(rsdp + 15, &mut revision);
+    let xsdt = if revision[0] >= 2 {
+        read_u64(rsdp + 24)
+    } else {
+        0
+    };
+    let (root, entry_size, rsdp_length) = if xsdt == 0 {
+        (u64::from(read_u32(rsdp + 16)), 4, 20)
+    } else {
+        (xsdt, 8, read_u32(rsdp + 20))
+    };
+
+    let mut tables = Vec::new();
+    tables.push(AcpiTable {
+        signature: *b"RSDP",
+        address: rsdp,
+        length: rsdp_length,
+    });
+    if root == 0 {
+        return tables;
+    }
+
+    let table_at = |address| {
+        let mut signature = [0u8; 4];
+        read(address, &mut signature);
+        AcpiTable {
+            signature,
+            address,
+            length: read_u32(address + 4),
+        }
+    };
+    let root = table_at(root);
+    tables.push(root);
+    let count = root.length.saturating_sub(SDT_HEADER_SIZE) / entry_size;
+    for index in 0..count {
+        let entry = root.address + u64::from(SDT_HEADER_SIZE + index * entry_size);
+        let address = if entry_size == 8 {
+            read_u64(entry)
+        } else {
+            u64::from(read_u32(entry))
+        };
+        if address != 0 {
+            tables.push(table_at(address));
+        }
+    }
+    tables
+}
+
+/// Returns the processors listed in the MADT at `address`, reading physical
+/// memory with `read`. Processors neither enabled nor online-capable are not
+/// included.
+///
+/// See: 5.2.12 Multiple APIC Description Table (MADT), ACPI Specification
+pub(crate) fn parse_madt(address: u64, read: impl Fn(u64, &mut [u8])) -> Vec<LocalApic> {
+    // The header is followed by the Local Interrupt Controller Address and
+    // Flags fields, and then the interrupt controller structures.
+    const STRUCTURES_OFFSET: u64 = 44;
+    const PROCESSOR_LOCAL_APIC: u8 = 0;
+    const PROCESSOR_LOCAL_X2APIC: u8 = 9;
+
+    let mut length = [0u8; 4];
+    read(address + 4, &mut length);
+    let end = address + u64::from(u32::from_le_bytes(length));
+
+    let mut apics = Vec::new();
+    let mut offset = address + STRUCTURES_OFFSET;
+    while offset + 2 <= end {
+        let mut header = [0u8; 2];
+        read(offset, &mut header);
+        let [structure_type, structure_length] = header;
+        if structure_length < 2 || offset + u64::from(structure_length) > end {
+            log::warn!("The MADT at {address:#x} is malformed at {offset:#x}");
+            break;
+        }
+
+        let mut structure = [0u8; 16];
+        let structure = &mut structure[..usize::from(structure_length).min(16)];
+        read(offset, structure);
+        let apic = match (structure_type, structure.len()) {
+            (PROCESSOR_LOCAL_APIC, 8..) => Some((
+                u32::from(structure[3]),
+                u32::from_le_bytes(structure[4..8].try_into().unwrap()),
+            )),
+            (PROCESSOR_LOCAL_X2APIC, 16..) => Some((
+                u32::from_le_bytes(structure[4..8].try_into().unwrap()),
+                u32::from_le_bytes(structure[8..12].try_into().unwrap()),
+            )),
+            _ => None,
+        };
+        if let Some((apic_id, flags)) = apic {
+            let apic = LocalApic {
+                apic_id,
+                enabled: flags.get_bit(0),
+                online_capable: flags.get_bit(1),
+            };
+            if apic.enabled || apic.online_capable {
+                apics.push(apic);
+            }
+        }
+        offset += u64::from(structure_length);
+    }
+    apics
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn tables() {
+        let mut memory = vec![0u8; 0x1000];
+        // The RSDP at 0x0 pointing to the XSDT at 0x100.
+        memory[..8].copy_from_slice(b"RSD PTR ");
+        memory[15] = 2;
+        memory[20..24].copy_from_slice(&36u32.to_le_bytes());
+        memory[24..32].copy_from_slice(&0x100u64.to_le_bytes());
+        // The XSDT with the FADT at 0x200 and the MADT at 0x300.
+        memory[0x100..0x104].copy_from_slice(b"XSDT");
+        memory[0x104..0x108].copy_from_slice(&52u32.to_le_bytes());
+        memory[0x124..0x12c].copy_from_slice(&0x200u64.to_le_bytes());
+        memory[0x12c..0x134].copy_from_slice(&0x300u64.to_le_bytes());
+        memory[0x200..0x204].copy_from_slice(b"FACP");
+        memory[0x204..0x208].copy_from_slice(&276u32.to_le_bytes());
+        memory[0x300..0x304].copy_from_slice(b"APIC");
+        memory[0x304..0x308].copy_from_slice(&0x6cu32.to_le_bytes());
+
+        let read = |address: u64, buffer: &mut [u8]| {
+            let address = address as usize;
+            buffer.copy_from_slice(&memory[address..address + buffer.len()]);
+        };
+        let table = |signature: &[u8; 4], address, length| AcpiTable {
+            signature: *signature,
+            address,
+            length,
+        };
+        assert_eq!(
+            parse_tables(0, read),
+            [
+                table(b"RSDP", 0, 36),
+                table(b"XSDT", 0x100, 52),
+                table(b"FACP", 0x200, 276),
+                table(b"APIC", 0x300, 0x6c),
+            ]
+        );
+
+        // ACPI 1.0 with the RSDT.
+        memory[15] = 0;
+        memory[16..20].copy_from_slice(&0x400u32.to_le_bytes());
+        memory[0x400..0x404].copy_from_slice(b"RSDT");
+        memory[0x404..0x408].copy_from_slice(&40u32.to_le_bytes());
+        memory[0x424..0x428].copy_from_slice(&0x300u32.to_le_bytes());
+        let read = |address: u64, buffer: &mut [u8]| {
+            let address = address as usize;
+            buffer.copy_from_slice(&memory[address..address + buffer.len()]);
+        };
+        assert_eq!(
+            parse_tables(0, read),
+            [
+                table(b"RSDP", 0, 20),
+                table(b"RSDT", 0x400, 40),
+                table(b"APIC", 0x300, 0x6c),
+            ]
+        );
+    }
+
+    #[test]
+    fn madt() {
+        let mut memory = vec![0u8; 0x100];
+        memory[..4].copy_from_slice(b"APIC");
+        memory[4..8].copy_from_slice(&(44u32 + 8 * 3 + 16).to_le_bytes());
+        // An enabled, an online-capable and an unusable local APIC.
+        memory[44..52].copy_from_slice(&[0, 8, 0, 0x00, 1, 0, 0, 0]);
+        memory[52..60].copy_from_slice(&[0, 8, 1, 0x02, 2, 0, 0, 0]);
+        memory[60..68].copy_from_slice(&[0, 8, 2, 0x04, 0, 0, 0, 0]);
+        // An enabled local x2APIC.
+        memory[68..84].copy_from_slice(&[9, 16, 0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0]);
+
+        let read = |address: u64, buffer: &mut [u8]| {
+            let address = address as usize;
+            buffer.copy_from_slice(&memory[address..address + buffer.len()]);
+        };
+        let apic = |apic_id, enabled, online_capable| LocalApic {
+            apic_id,
+            enabled,
+            online_capable,
+        };
+        assert_eq!(
+            parse_madt(0, read),
+            [
+                apic(0x00, true, false),
+                apic(0x02, false, true),
+                apic(0x100, true, false),
+            ]
+        );
+    }
+}
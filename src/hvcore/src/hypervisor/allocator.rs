@@ -2,15 +2,24 @@
 //! heap and provides allocator for fixed-sized blocks. This allocator eliminates
 //! dependencies onto platform API for memory management at runtime. This is
 //! important as calling platform API from the hypervisor is unsound.
+//!
+//! As the heap is fixed-size, the allocator keeps counters readable at any
+//! time with [`stats`], and can call back when free memory runs low with
+//! [`set_low_watermark`]. Allocations in non-critical paths should use fallible
+//! APIs such as `Vec::try_reserve`, so that exhaustion fails the operation
+//! instead of aborting the system.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::{NonNull, addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
 use bitvec::{array::BitArray, prelude::*};
 use spin::{Mutex, Once};
 
+use super::irq;
+
 pub const ALLOCATION_BYTES: usize = 0x80_0000;
 pub const ALLOCATION_PAGES: usize = ALLOCATION_BYTES / 0x1000;
 
@@ -20,6 +29,86 @@ pub fn init(ptr: *mut u8) {
     let _ = METADATA.call_once(|| Mutex::new(Metadata::new(ptr)));
 }
 
+/// The counters of the allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// The number of allocations that failed due to exhaustion.
+    pub failures: u64,
+    /// The number of bytes in use, rounded up to the block sizes.
+    pub used_bytes: usize,
+    /// The largest `used_bytes` so far.
+    pub peak_used_bytes: usize,
+}
+
+impl AllocatorStats {
+    /// Returns the number of bytes not in use. Blocks of 4096 and 128 bytes are
+    /// taken from separate regions, so an allocation may fail before this
+    /// reaches zero.
+    pub fn free_bytes(&self) -> usize {
+        ALLOCATION_BYTES - self.used_bytes
+    }
+}
+
+/// The callback given to [`set_low_watermark`].
+pub type LowWatermarkCallback = fn(&AllocatorStats);
+
+/// Returns the current counters of the allocator.
+pub fn stats() -> AllocatorStats {
+    AllocatorStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        used_bytes: USED_BYTES.load(Ordering::Relaxed),
+        peak_used_bytes: PEAK_USED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Sets `callback` to be called when free bytes fall below `free_bytes`. It is
+/// called once each time free bytes fall below it, after the allocation that
+/// caused it, on the processor that made the allocation. Only the first call
+/// takes effect.
+pub fn set_low_watermark(free_bytes: usize, callback: LowWatermarkCallback) {
+    let _ = LOW_WATERMARK.call_once(|| (free_bytes, callback));
+}
+
+/// Updates the counters after `bytes` were allocated.
+fn record_allocation(bytes: usize) {
+    let _ = ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let used = USED_BYTES.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    let _ = PEAK_USED_BYTES.fetch_max(used, Ordering::Relaxed);
+    check_low_watermark(used);
+}
+
+/// Updates the counters after `bytes` were freed.
+fn record_deallocation(bytes: usize) {
+    let _ = DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let used = USED_BYTES.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+    check_low_watermark(used);
+}
+
+/// Calls the low-watermark callback if free bytes fell below the watermark
+/// with `used` bytes in use.
+fn check_low_watermark(used: usize) {
+    let Some(&(free_bytes, callback)) = LOW_WATERMARK.get() else {
+        return;
+    };
+    if ALLOCATION_BYTES - used >= free_bytes {
+        BELOW_LOW_WATERMARK.store(false, Ordering::Relaxed);
+    } else if !BELOW_LOW_WATERMARK.swap(true, Ordering::Relaxed) {
+        callback(&stats());
+    }
+}
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LOW_WATERMARK: Once<(usize, LowWatermarkCallback)> = Once::new();
+static BELOW_LOW_WATERMARK: AtomicBool = AtomicBool::new(false);
+
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator;
 
@@ -33,25 +122,42 @@ unsafe impl GlobalAlloc for Allocator {
     /// Allocation of memory that is physically continuous for 2 or more pages are
     /// not supported.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        irq::debug_assert_may_block();
         let mut meta = METADATA.get().expect("init() is not called").lock();
         let blocks = unsafe { meta.blocks.as_mut() };
 
-        if layout.size() >= BLOCK_SIZE_4096 {
-            alloc_internal(layout, &mut blocks.block4096, &mut meta.bitmap4096)
+        let (ptr, block_size) = if layout.size() >= BLOCK_SIZE_4096 {
+            let ptr = alloc_internal(layout, &mut blocks.block4096, &mut meta.bitmap4096);
+            (ptr, BLOCK_SIZE_4096)
         } else {
-            alloc_internal(layout, &mut blocks.block128, &mut meta.bitmap128)
+            let ptr = alloc_internal(layout, &mut blocks.block128, &mut meta.bitmap128);
+            (ptr, BLOCK_SIZE_128)
+        };
+        drop(meta);
+
+        if ptr.is_null() {
+            let _ = FAILURES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            record_allocation(round_up_by(layout.size(), block_size) * block_size);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        irq::debug_assert_may_block();
         let mut meta = METADATA.get().expect("init() is not called").lock();
         let blocks = unsafe { meta.blocks.as_mut() };
 
-        if layout.size() >= BLOCK_SIZE_4096 {
+        let block_size = if layout.size() >= BLOCK_SIZE_4096 {
             dealloc_internal(ptr, layout, &blocks.block4096, &mut meta.bitmap4096);
+            BLOCK_SIZE_4096
         } else {
             dealloc_internal(ptr, layout, &blocks.block128, &mut meta.bitmap128);
-        }
+            BLOCK_SIZE_128
+        };
+        drop(meta);
+
+        record_deallocation(round_up_by(layout.size(), block_size) * block_size);
     }
 }
 
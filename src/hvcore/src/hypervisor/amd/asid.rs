@@ -0,0 +1,49 @@
+//! This module implements allocation of address space identifiers (ASIDs).
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::percpu::{MAX_PROCESSORS, PerCpu};
+
+/// Returns the ASID of the guest on the logical processor `processor_id`,
+/// allocating one on the first call. The guest keeps the ASID across
+/// devirtualization and re-virtualization, so that they do not exhaust ASIDs.
+/// TLB entries tagged with it may be stale, and must be flushed on the first
+/// VMRUN of each new guest.
+// TLB entries are tagged with an ASID per logical processor. Thus, the same
+// ASID value may be used on different processors, and we only need to avoid
+// duplication within the same processor.
+pub(crate) fn get(processor_id: usize) -> u32 {
+    let assigned = &ASSIGNED_ASIDS[processor_id];
+    match assigned.load(Ordering::Relaxed) {
+        0 => {
+            let asid = allocate(processor_id);
+            assigned.store(asid, Ordering::Relaxed);
+            asid
+        }
+        asid => asid,
+    }
+}
+
+/// Allocates a new ASID on the logical processor `processor_id`.
+fn allocate(processor_id: usize) -> u32 {
+    // "EBX: NASID: number of address space identifiers (ASID)."
+    // See: E.4.10 Function 8000_000Ah—SVM Revision and Feature Identification
+    let asid_count = cpuid!(0x8000_000a).ebx;
+
+    let asid = NEXT_ASIDS[processor_id].fetch_add(1, Ordering::Relaxed);
+    assert!(
+        asid < asid_count,
+        "No ASID available for {processor_id}. Max {asid_count}"
+    );
+    asid
+}
+
+// "ASID 0 is reserved for use by the host; all guests must have non-zero ASIDs."
+// See: 15.16.1 TLB Flush
+static NEXT_ASIDS: PerCpu<AtomicU32> = PerCpu::new([const { AtomicU32::new(1) }; MAX_PROCESSORS]);
+
+/// The ASID of the guest on each processor. 0 if not allocated yet.
+static ASSIGNED_ASIDS: PerCpu<AtomicU32> =
+    PerCpu::new([const { AtomicU32::new(0) }; MAX_PROCESSORS]);
@@ -0,0 +1,106 @@
+//! This module defines the exit codes of SVM.
+//!
+//! See: Appendix C SVM Intercept Exit Codes
+
+pub(super) const VMEXIT_CR0_READ: u64 = 0x0;
+pub(super) const VMEXIT_CR15_WRITE: u64 = 0x1f;
+pub(super) const VMEXIT_EXCEPTION_DE: u64 = 0x40;
+pub(super) const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
+pub(super) const VMEXIT_EXCEPTION_31: u64 = 0x5f;
+pub(super) const VMEXIT_SMI: u64 = 0x62;
+pub(super) const VMEXIT_IDTR_READ: u64 = 0x66;
+pub(super) const VMEXIT_GDTR_READ: u64 = 0x67;
+pub(super) const VMEXIT_LDTR_READ: u64 = 0x68;
+pub(super) const VMEXIT_TR_READ: u64 = 0x69;
+pub(super) const VMEXIT_RDTSC: u64 = 0x6e;
+pub(super) const VMEXIT_RDPMC: u64 = 0x6f;
+pub(super) const VMEXIT_CPUID: u64 = 0x72;
+pub(super) const VMEXIT_INVD: u64 = 0x76;
+pub(super) const VMEXIT_PAUSE: u64 = 0x77;
+pub(super) const VMEXIT_HLT: u64 = 0x78;
+pub(super) const VMEXIT_IOIO: u64 = 0x7b;
+pub(super) const VMEXIT_MSR: u64 = 0x7c;
+pub(super) const VMEXIT_SHUTDOWN: u64 = 0x7f;
+pub(super) const VMEXIT_VMMCALL: u64 = 0x81;
+pub(super) const VMEXIT_RDTSCP: u64 = 0x87;
+pub(super) const VMEXIT_WBINVD: u64 = 0x89;
+pub(super) const VMEXIT_MONITOR: u64 = 0x8a;
+pub(super) const VMEXIT_MWAIT: u64 = 0x8b;
+pub(super) const VMEXIT_NPF: u64 = 0x400;
+pub(super) const VMEXIT_VMGEXIT: u64 = 0x403;
+
+/// The number of exit codes up to the largest one, except VMEXIT_INVALID and
+/// VMEXIT_BUSY.
+pub(super) const COUNT: usize = VMEXIT_VMGEXIT as usize + 1;
+
+/// Returns the name of the exit `code` for diagnostics.
+pub(super) fn name(code: u64) -> &'static str {
+    match code {
+        0x00..=0x0f => "VMEXIT_CR[0-15]_READ",
+        0x10..=0x1f => "VMEXIT_CR[0-15]_WRITE",
+        0x20..=0x2f => "VMEXIT_DR[0-15]_READ",
+        0x30..=0x3f => "VMEXIT_DR[0-15]_WRITE",
+        0x40..=0x5f => "VMEXIT_EXCP[0-31]",
+        0x60 => "VMEXIT_INTR",
+        0x61 => "VMEXIT_NMI",
+        0x62 => "VMEXIT_SMI",
+        0x63 => "VMEXIT_INIT",
+        0x64 => "VMEXIT_VINTR",
+        0x65 => "VMEXIT_CR0_SEL_WRITE",
+        0x66 => "VMEXIT_IDTR_READ",
+        0x67 => "VMEXIT_GDTR_READ",
+        0x68 => "VMEXIT_LDTR_READ",
+        0x69 => "VMEXIT_TR_READ",
+        0x6a => "VMEXIT_IDTR_WRITE",
+        0x6b => "VMEXIT_GDTR_WRITE",
+        0x6c => "VMEXIT_LDTR_WRITE",
+        0x6d => "VMEXIT_TR_WRITE",
+        0x6e => "VMEXIT_RDTSC",
+        0x6f => "VMEXIT_RDPMC",
+        0x70 => "VMEXIT_PUSHF",
+        0x71 => "VMEXIT_POPF",
+        0x72 => "VMEXIT_CPUID",
+        0x73 => "VMEXIT_RSM",
+        0x74 => "VMEXIT_IRET",
+        0x75 => "VMEXIT_SWINT",
+        0x76 => "VMEXIT_INVD",
+        0x77 => "VMEXIT_PAUSE",
+        0x78 => "VMEXIT_HLT",
+        0x79 => "VMEXIT_INVLPG",
+        0x7a => "VMEXIT_INVLPGA",
+        0x7b => "VMEXIT_IOIO",
+        0x7c => "VMEXIT_MSR",
+        0x7d => "VMEXIT_TASK_SWITCH",
+        0x7e => "VMEXIT_FERR_FREEZE",
+        0x7f => "VMEXIT_SHUTDOWN",
+        0x80 => "VMEXIT_VMRUN",
+        0x81 => "VMEXIT_VMMCALL",
+        0x82 => "VMEXIT_VMLOAD",
+        0x83 => "VMEXIT_VMSAVE",
+        0x84 => "VMEXIT_STGI",
+        0x85 => "VMEXIT_CLGI",
+        0x86 => "VMEXIT_SKINIT",
+        0x87 => "VMEXIT_RDTSCP",
+        0x88 => "VMEXIT_ICEBP",
+        0x89 => "VMEXIT_WBINVD",
+        0x8a => "VMEXIT_MONITOR",
+        0x8b => "VMEXIT_MWAIT",
+        0x8c => "VMEXIT_MWAIT_CONDITIONAL",
+        0x8d => "VMEXIT_XSETBV",
+        0x8e => "VMEXIT_RDPRU",
+        0x8f => "VMEXIT_EFER_WRITE_TRAP",
+        0x90..=0x9f => "VMEXIT_CR[0-15]_WRITE_TRAP",
+        0xa0 => "VMEXIT_INVLPGB",
+        0xa1 => "VMEXIT_INVLPGB_ILLEGAL",
+        0xa2 => "VMEXIT_INVPCID",
+        0xa3 => "VMEXIT_MCOMMIT",
+        0xa4 => "VMEXIT_TLBSYNC",
+        0x400 => "VMEXIT_NPF",
+        0x401 => "AVIC_INCOMPLETE_IPI",
+        0x402 => "AVIC_NOACCEL",
+        0x403 => "VMEXIT_VMGEXIT",
+        0xffff_ffff_ffff_ffff => "VMEXIT_INVALID",
+        0xffff_ffff_ffff_fffe => "VMEXIT_BUSY",
+        _ => "(unknown)",
+    }
+}
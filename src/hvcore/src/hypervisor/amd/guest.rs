@@ -2,31 +2,91 @@
 
 use core::{
     arch::{asm, global_asm},
+    ops::Range,
     ptr::addr_of,
-    sync::atomic::{AtomicU8, Ordering},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
 };
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
 use bit_field::BitField;
 use derive_more::Debug;
-use spin::{Lazy, RwLock};
+use spin::{Lazy, Once, RwLock};
 use x86::{
-    bits64::{paging::BASE_PAGE_SHIFT, rflags::RFlags},
-    controlregs::cr3_write,
+    bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE},
+    controlregs::{Cr4, cr3_write},
     cpuid::cpuid,
+    dtables::DescriptorTablePointer,
     segmentation::{cs, ds, es, ss},
 };
 
 use crate::hypervisor::{
-    SHARED_HOST_DATA, apic_id,
-    host::{Guest, InstructionInfo, VmExitReason},
-    platform_ops,
-    registers::Registers,
-    support::zeroed_box,
-    x86_instructions::{cr0, cr3, cr4, lidt, rdmsr, sgdt, sidt, wrmsr},
+    SHARED_HOST_DATA, ap_startup,
+    apic_base::{self, ApicBase, ApicMode},
+    apic_id,
+    config::HltPolicy,
+    decoder::{self, MAX_INSTRUCTION_LENGTH, Operand},
+    deterministic_time,
+    ept_views::{EptPermissions, EptViewError},
+    event_injection::{self, Event, EventKind, Resolution},
+    exception_policy,
+    exit_dispatch::DispatchTable,
+    exit_profile::push_bits,
+    gva, hooks,
+    host::{
+        CrAccessInfo, DescriptorTableInfo, DescriptorTableInstruction, DescriptorTableOperand,
+        ExceptionInfo, Guest, InstructionInfo, IoInfo, NestedPageFaultInfo, VmExitReason,
+    },
+    hyperv,
+    lbr::{GuestLastBranches, LastBranch},
+    mini_vm::{MiniVmError, MiniVmExit, MiniVmRequest},
+    msr_monitor, mwait,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops::{self, PaError},
+    pmu,
+    registers::{FullGuestState, Registers},
+    snapshot::SnapshotError,
+    speculation,
+    support::{ContiguousBox, zeroed_box},
+    tsc_scaling, umip,
+    x86_instructions::{cr0, cr3, cr4, cr4_write, lgdt, lidt, rdmsr, sgdt, sidt, wrmsr},
 };
 
-use super::npts::NestedPageTables;
+use super::{
+    asid,
+    exit_codes::{
+        self, VMEXIT_CPUID, VMEXIT_CR0_READ, VMEXIT_CR15_WRITE, VMEXIT_EXCEPTION_31,
+        VMEXIT_EXCEPTION_DE, VMEXIT_EXCEPTION_SX, VMEXIT_GDTR_READ, VMEXIT_HLT, VMEXIT_IDTR_READ,
+        VMEXIT_INVD, VMEXIT_IOIO, VMEXIT_LDTR_READ, VMEXIT_MONITOR, VMEXIT_MSR, VMEXIT_MWAIT,
+        VMEXIT_NPF, VMEXIT_PAUSE, VMEXIT_RDPMC, VMEXIT_RDTSC, VMEXIT_RDTSCP, VMEXIT_SHUTDOWN,
+        VMEXIT_SMI, VMEXIT_TR_READ, VMEXIT_VMMCALL, VMEXIT_WBINVD,
+    },
+    npts::NestedPageTables,
+    svm::SvmFeatures,
+};
+
+const SVM_INTERCEPT_MISC1_SMI: u32 = 1 << 2;
+const SVM_INTERCEPT_MISC1_IDTR_READ: u32 = 1 << 6;
+const SVM_INTERCEPT_MISC1_GDTR_READ: u32 = 1 << 7;
+const SVM_INTERCEPT_MISC1_LDTR_READ: u32 = 1 << 8;
+const SVM_INTERCEPT_MISC1_TR_READ: u32 = 1 << 9;
+const SVM_INTERCEPT_MISC1_DESCRIPTOR_TABLE_READS: u32 = SVM_INTERCEPT_MISC1_IDTR_READ
+    | SVM_INTERCEPT_MISC1_GDTR_READ
+    | SVM_INTERCEPT_MISC1_LDTR_READ
+    | SVM_INTERCEPT_MISC1_TR_READ;
+const SVM_INTERCEPT_MISC1_RDTSC: u32 = 1 << 14;
+const SVM_INTERCEPT_MISC1_RDPMC: u32 = 1 << 15;
+const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
+const SVM_INTERCEPT_MISC1_INVD: u32 = 1 << 22;
+const SVM_INTERCEPT_MISC1_PAUSE: u32 = 1 << 23;
+const SVM_INTERCEPT_MISC1_HLT: u32 = 1 << 24;
+const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
+const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
+const SVM_INTERCEPT_MISC2_VMMCALL: u32 = 1 << 1;
+const SVM_INTERCEPT_MISC2_RDTSCP: u32 = 1 << 7;
+const SVM_INTERCEPT_MISC2_WBINVD: u32 = 1 << 9;
+const SVM_INTERCEPT_MISC2_MONITOR: u32 = 1 << 10;
+const SVM_INTERCEPT_MISC2_MWAIT: u32 = 1 << 11;
+const SECURITY_EXCEPTION: u32 = 1 << 30;
 
 #[derive(Debug)]
 pub(crate) struct SvmGuest {
@@ -39,6 +99,22 @@ pub(crate) struct SvmGuest {
     #[debug(skip)]
     host_state: HostStateArea,
     activity_state: &'static AtomicU8,
+    features: SvmFeatures,
+    dirty_vmcb_fields: u32,
+    /// The guest state saved with `Guest::save_state`.
+    saved_state: Option<Box<(Registers, StateSaveArea)>>,
+    /// The exceptions intercepted for [`exception_policy`], excluding #SX.
+    exception_bitmap: u32,
+    /// The IA32_SPEC_CTRL value of the host if the host switches it around
+    /// VMRUN. See [`speculation`].
+    host_spec_ctrl: Option<u64>,
+    /// Whether writes to the APIC page are intercepted to emulate SIPI.
+    apic_write_intercepted: bool,
+    /// The event being delivered at #VMEXIT, to be injected on VMRUN. See
+    /// [`event_injection`].
+    pending_event: Option<Event>,
+    /// The guest RIP at the last #VMEXIT.
+    exit_rip: u64,
 }
 
 impl Guest for SvmGuest {
@@ -52,11 +128,24 @@ impl Guest for SvmGuest {
             host_vmcb_pa: 0,
             host_state: HostStateArea::default(),
             activity_state: &SHARED_GUEST_DATA.activity_states[id],
+            features: SvmFeatures::get(),
+            // Everything is dirty on the first VMRUN.
+            dirty_vmcb_fields: u32::MAX,
+            saved_state: None,
+            exception_bitmap: 0,
+            host_spec_ctrl: None,
+            apic_write_intercepted: false,
+            pending_event: None,
+            exit_rip: 0,
         };
 
-        vm.vmcb_pa = platform_ops::get().pa(addr_of!(*vm.vmcb.as_ref()) as _);
-        vm.host_vmcb_pa = platform_ops::get().pa(addr_of!(*vm.host_vmcb.as_ref()) as _);
-        if cfg!(feature = "uefi") && vm.id == 0 {
+        vm.vmcb_pa = platform_ops::get()
+            .pa(addr_of!(*vm.vmcb.as_ref()) as _)
+            .unwrap();
+        vm.host_vmcb_pa = platform_ops::get()
+            .pa(addr_of!(*vm.host_vmcb.as_ref()) as _)
+            .unwrap();
+        if cfg!(feature = "uefi") && vm.id == 0 && vm.features.np() {
             vm.intercept_apic_write(true);
         }
         vm
@@ -72,7 +161,9 @@ impl Guest for SvmGuest {
         //  the host state-save area in main memory at the physical address
         //  specified in the VM_HSAVE_PA MSR".
         // See: 15.5.1 Basic Operation
-        let pa = platform_ops::get().pa(addr_of!(*self.host_state.as_ref()) as _);
+        let pa = platform_ops::get()
+            .pa(addr_of!(*self.host_state.as_ref()) as _)
+            .unwrap();
         wrmsr(SVM_MSR_VM_HSAVE_PA, pa);
     }
 
@@ -84,32 +175,60 @@ impl Guest for SvmGuest {
     }
 
     fn run(&mut self) -> VmExitReason {
-        const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
-        const VMEXIT_CPUID: u64 = 0x72;
-        const VMEXIT_NPF: u64 = 0x400;
+        if !self.complete_event_delivery() {
+            return VmExitReason::Shutdown;
+        }
 
         self.vmcb.state_save_area.rax = self.registers.rax;
         self.vmcb.state_save_area.rip = self.registers.rip;
         self.vmcb.state_save_area.rsp = self.registers.rsp;
         self.vmcb.state_save_area.rflags = self.registers.rflags;
 
+        // Intercept exceptions as the policy table, possibly changed by other
+        // processors, tells.
+        let exception_bitmap = exception_policy::bitmap();
+        if exception_bitmap != self.exception_bitmap {
+            self.vmcb.control_area.intercept_exception = SECURITY_EXCEPTION | exception_bitmap;
+            self.exception_bitmap = exception_bitmap;
+            self.mark_vmcb_dirty(VmcbCleanBit::I as u32);
+        }
+        self.update_vmcb_clean_bits();
+
         log::trace!("Entering the guest");
 
+        // Switch IA32_SPEC_CTRL around VMRUN if the processor does not. Keep
+        // the guest value in the VMCB as the processor would.
+        let guest_spec_ctrl = self.vmcb.state_save_area.spec_ctl;
+        if let Some(host_spec_ctrl) = self.host_spec_ctrl
+            && guest_spec_ctrl != host_spec_ctrl
+        {
+            wrmsr(speculation::IA32_SPEC_CTRL, guest_spec_ctrl);
+        }
+
         // Run the guest until the #VMEXIT occurs.
         unsafe { run_svm_guest(&mut self.registers, self.vmcb_pa, self.host_vmcb_pa) };
 
         log::trace!("Exited the guest");
 
+        if let Some(host_spec_ctrl) = self.host_spec_ctrl {
+            let guest_spec_ctrl = rdmsr(speculation::IA32_SPEC_CTRL);
+            self.vmcb.state_save_area.spec_ctl = guest_spec_ctrl;
+            if guest_spec_ctrl != host_spec_ctrl {
+                wrmsr(speculation::IA32_SPEC_CTRL, host_spec_ctrl);
+            }
+        }
+
         // #VMEXIT occurred. Copy the guest register values from VMCB so that
         // `self.registers` is complete and up to date.
         self.registers.rax = self.vmcb.state_save_area.rax;
         self.registers.rip = self.vmcb.state_save_area.rip;
         self.registers.rsp = self.vmcb.state_save_area.rsp;
         self.registers.rflags = self.vmcb.state_save_area.rflags;
+        self.exit_rip = self.registers.rip;
+        self.record_pending_event();
 
         // We might have requested flushing TLB. Clear the request.
         self.vmcb.control_area.tlb_control = TlbControl::DoNotFlush as _;
-        self.vmcb.control_area.vmcb_clean = u32::MAX;
 
         // Handle #VMEXIT by translating it to the `VmExitReason` type.
         //
@@ -120,34 +239,375 @@ impl Guest for SvmGuest {
         //
         // For the list of possible exit codes,
         // See: Appendix C SVM Intercept Exit Codes
-        match self.vmcb.control_area.exit_code {
-            VMEXIT_EXCEPTION_SX => {
-                self.handle_security_exception();
-                VmExitReason::InitSignal
-            }
-            VMEXIT_CPUID => VmExitReason::Cpuid(InstructionInfo {
-                next_rip: self.vmcb.control_area.nrip,
-            }),
-            VMEXIT_NPF => {
-                self.handle_nested_page_fault();
-                VmExitReason::NestedPageFault
-            }
-            _ => {
-                log::error!("{:#x?}", self.vmcb);
-                panic!(
-                    "Unhandled #VMEXIT reason: {:?}",
-                    self.vmcb.control_area.exit_code
-                )
-            }
+        let exit_code = self.vmcb.control_area.exit_code;
+        match EXIT_HANDLERS.get(exit_code) {
+            Some(handler) => handler(self),
+            None => self.unhandled_exit(exit_code),
         }
     }
 
     fn regs(&mut self) -> &mut Registers {
         &mut self.registers
     }
+
+    fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        const NMI: u8 = 2;
+        const TYPE_NMI: u64 = 2;
+        const TYPE_EXCEPTION: u64 = 3;
+
+        // See: Figure 15-4. EVENTINJ Field in the VMCB
+        let mut event = u64::from(vector);
+        let _ = event.set_bits(
+            8..=10,
+            if vector == NMI {
+                TYPE_NMI
+            } else {
+                TYPE_EXCEPTION
+            },
+        );
+        if let Some(error_code) = error_code {
+            let _ = event.set_bit(11, true);
+            let _ = event.set_bits(32..=63, u64::from(error_code));
+        }
+        let _ = event.set_bit(31, true);
+        self.vmcb.control_area.event_inj = event;
+    }
+
+    fn inject_software_exception(&mut self, vector: u8, next_rip: u64) {
+        const GP: u8 = 13;
+        const ERROR_CODE_IDT: u32 = 1 << 1;
+
+        // The exception is injected without the DPL check of the IDT gate that
+        // INT3 and INTO perform. Check it here, and fail with #GP at the
+        // instruction as the processor would.
+        // See: 15.20 Event Injection
+        let cpl = self.vmcb.state_save_area.cpl;
+        if self.idt_gate_dpl(vector).is_some_and(|dpl| dpl < cpl) {
+            self.inject_exception(GP, Some(u32::from(vector) << 3 | ERROR_CODE_IDT));
+            return;
+        }
+        self.registers.rip = next_rip;
+        self.inject_exception(vector, None);
+    }
+
+    fn cpl(&self) -> u8 {
+        self.vmcb.state_save_area.cpl
+    }
+
+    fn halt(&mut self) -> bool {
+        // SVM has no activity state.
+        false
+    }
+
+    fn reset(&mut self) -> bool {
+        // SIPI is emulated only with the `uefi` feature, and the BSP is never
+        // sent SIPI. See `emulate_sipi`.
+        if self.id == 0 || !cfg!(feature = "uefi") {
+            return false;
+        }
+        self.handle_security_exception();
+        true
+    }
+
+    fn devirtualize(&mut self) {
+        // Load the guest values of the registers that may differ from the host
+        // values. FS, GS, TR, LDTR, KernelGsBase and the system call MSRs were
+        // saved into the guest VMCB with VMSAVE after #VMEXIT. The rest is the
+        // same as the host, as the guest started as a copy of it.
+        vmload(self.vmcb_pa);
+        let state = &self.vmcb.state_save_area;
+        // The guest may have changed CR4, for example, to enable SMEP or SMAP.
+        // Load it before CR3, which may have a PCID only with CR4.PCIDE.
+        cr4_write(Cr4::from_bits_truncate(state.cr4 as usize));
+        unsafe { cr3_write(state.cr3) };
+        lgdt(&DescriptorTablePointer {
+            base: state.gdtr_base as *const u64,
+            limit: u16::try_from(state.gdtr_limit).unwrap(),
+        });
+        lidt(&DescriptorTablePointer {
+            base: state.idtr_base as *const u64,
+            limit: u16::try_from(state.idtr_limit).unwrap(),
+        });
+        if self.features.lbr_virt() {
+            wrmsr(x86::msr::IA32_DEBUGCTL, state.dbg_ctl);
+        }
+        if speculation::is_spec_ctrl_supported() {
+            wrmsr(speculation::IA32_SPEC_CTRL, state.spec_ctl);
+        }
+    }
+
+    fn save_state(&mut self) {
+        // The state save area includes the registers saved with VMSAVE, such as
+        // FS, GS, KernelGsBase and the system call MSRs.
+        self.saved_state = Some(Box::new((self.registers, self.vmcb.state_save_area)));
+    }
+
+    fn restore_state(&mut self) -> bool {
+        let Some(state) = &self.saved_state else {
+            return false;
+        };
+        self.registers = state.0;
+        self.vmcb.state_save_area = state.1;
+        self.mark_vmcb_dirty(u32::MAX);
+        true
+    }
+
+    fn take_snapshot(&mut self) -> Result<(), SnapshotError> {
+        Err(SnapshotError::Unsupported)
+    }
+
+    fn restore_snapshot(&mut self) -> Result<(), SnapshotError> {
+        Err(SnapshotError::Unsupported)
+    }
+
+    fn discard_snapshot(&mut self) -> Result<(), SnapshotError> {
+        Err(SnapshotError::Unsupported)
+    }
+
+    fn handle_snapshot_write(&mut self, _gpa: u64) -> bool {
+        false
+    }
+
+    fn run_mini_vm(&mut self, _request: &MiniVmRequest) -> Result<(MiniVmExit, u64), MiniVmError> {
+        Err(MiniVmError::Unsupported)
+    }
+
+    fn switch_ept_view(&mut self, _view: usize) -> Result<(), EptViewError> {
+        Err(EptViewError::Unsupported)
+    }
+
+    fn protect_ept_view(
+        &mut self,
+        _view: usize,
+        _range: Range<u64>,
+        _permissions: EptPermissions,
+    ) -> Result<(), EptViewError> {
+        Err(EptViewError::Unsupported)
+    }
+
+    fn protect_sub_pages(
+        &mut self,
+        _view: usize,
+        _gpa: u64,
+        _writable: u32,
+    ) -> Result<(), EptViewError> {
+        Err(EptViewError::Unsupported)
+    }
+
+    fn handle_ept_view_fault(&mut self) -> bool {
+        false
+    }
+
+    fn bind_ept_view(&mut self, _cr3: u64, _view: usize) -> Result<(), EptViewError> {
+        Err(EptViewError::Unsupported)
+    }
+
+    fn write_cr3(&mut self, cr3: u64) {
+        self.vmcb.state_save_area.cr3 = cr3;
+        self.mark_vmcb_dirty(VmcbCleanBit::CrX as u32);
+    }
+
+    fn long_mode_cr3(&self) -> Option<u64> {
+        const EFER_LMA: usize = 10;
+        const CS_ATTRIBUTES_L: usize = 9;
+        const CR4_LA57: usize = 12;
+
+        // The attributes hold bits 55:52 of the segment descriptor in bits 11:8,
+        // thus, the L bit in bit 9.
+        let state = &self.vmcb.state_save_area;
+        (state.efer.get_bit(EFER_LMA)
+            && state.cs_attrib.get_bit(CS_ATTRIBUTES_L)
+            && !state.cr4.get_bit(CR4_LA57))
+        .then_some(state.cr3)
+    }
+
+    fn full_state(&self) -> FullGuestState {
+        let state = &self.vmcb.state_save_area;
+        FullGuestState {
+            cs: state.cs_selector,
+            ss: state.ss_selector,
+            cr0: state.cr0,
+            cr3: state.cr3,
+            cr4: state.cr4,
+            efer: state.efer,
+            gs_base: state.gs_base,
+            gdtr_base: state.gdtr_base,
+            gdtr_limit: state.gdtr_limit as u16,
+            idtr_base: state.idtr_base,
+            idtr_limit: state.idtr_limit as u16,
+            ldtr: state.ldtr_selector,
+            tr: state.tr_selector,
+        }
+    }
+
+    fn set_monitor_trap(&mut self, enable: bool) -> bool {
+        // SVM has no monitor trap flag.
+        !enable
+    }
+
+    fn set_descriptor_table_exiting(&mut self, enable: bool) {
+        // Only the reads are intercepted, and always emulated. See `umip`.
+        if enable {
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_DESCRIPTOR_TABLE_READS;
+        } else {
+            self.vmcb.control_area.intercept_misc1 &= !SVM_INTERCEPT_MISC1_DESCRIPTOR_TABLE_READS;
+        }
+        self.mark_vmcb_dirty(VmcbCleanBit::I as u32);
+    }
+
+    fn reprobe_capabilities(&self) {
+        let features = SvmFeatures::get();
+        if features != self.features {
+            log::warn!("SVM features changed after the microcode update: {features:#x?}");
+        }
+    }
+
+    fn lstar(&self) -> u64 {
+        self.vmcb.state_save_area.lstar
+    }
+
+    fn last_branches(&self) -> GuestLastBranches {
+        // #VMEXIT saved the LBR MSRs of the guest into the VMCB.
+        if self.features.lbr_virt() {
+            let state = &self.vmcb.state_save_area;
+            GuestLastBranches::Saved(LastBranch {
+                from: state.br_from,
+                to: state.br_to,
+            })
+        } else {
+            GuestLastBranches::Unavailable
+        }
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) -> Option<u64> {
+        // Make the counters the guest programs count only in the guest.
+        if pmu::isolated() && pmu::amd_event_selectors().any(|selector| selector == msr) {
+            return Some(value | pmu::GUEST_ONLY);
+        }
+        // The processor values are overwritten with VMLOAD and VMRUN. Update
+        // the values in the VMCB instead.
+        match msr {
+            x86::msr::IA32_LSTAR => self.vmcb.state_save_area.lstar = value,
+            x86::msr::IA32_SYSENTER_EIP => self.vmcb.state_save_area.sysenter_eip = value,
+            x86::msr::IA32_APIC_BASE => {
+                self.update_apic_base(ApicBase(value));
+                return Some(value);
+            }
+            _ => return Some(value),
+        }
+        None
+    }
+
+    fn dump(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        write!(out, "{:#x?}", self.vmcb)
+    }
+
+    fn optional_intercepts(&self) -> Vec<String> {
+        // See: Table B-1. VMCB Layout, Control Area
+        const MISC1: [(u64, &str); 16] = [
+            (1 << 0, "INTR"),
+            (1 << 1, "NMI"),
+            (1 << 2, "SMI"),
+            (1 << 3, "INIT"),
+            (1 << 6, "IDTR-reads"),
+            (1 << 7, "GDTR-reads"),
+            (1 << 8, "LDTR-reads"),
+            (1 << 9, "TR-reads"),
+            (1 << 14, "RDTSC"),
+            (1 << 15, "RDPMC"),
+            (1 << 22, "INVD"),
+            (1 << 23, "PAUSE"),
+            (1 << 24, "HLT"),
+            (1 << 25, "INVLPG"),
+            (1 << 27, "I/O"),
+            (1 << 28, "MSRs"),
+        ];
+        const MISC2: [(u64, &str); 5] = [
+            (1 << 7, "RDTSCP"),
+            (1 << 9, "WBINVD"),
+            (1 << 10, "MONITOR"),
+            (1 << 11, "MWAIT"),
+            (1 << 13, "XSETBV"),
+        ];
+
+        // VMRUN must be intercepted, and the rest are required by the
+        // hypervisor. See `initialize_control`.
+        // See: 15.5.1 Basic Operation
+        let mut required_misc1 = SVM_INTERCEPT_MISC1_CPUID | SVM_INTERCEPT_MISC1_INVD;
+        if cfg!(feature = "uefi") {
+            required_misc1 |= SVM_INTERCEPT_MISC1_MSR_PROT;
+        }
+        let required_misc2 =
+            SVM_INTERCEPT_MISC2_VMRUN | SVM_INTERCEPT_MISC2_VMMCALL | SVM_INTERCEPT_MISC2_WBINVD;
+
+        let control = &self.vmcb.control_area;
+        let mut intercepts = Vec::new();
+        for (name, value) in [
+            ("CR-reads", control.intercept_cr_read),
+            ("CR-writes", control.intercept_cr_write),
+            ("DR-reads", control.intercept_dr_read),
+            ("DR-writes", control.intercept_dr_write),
+        ] {
+            if value != 0 {
+                intercepts.push(format!("{name}={value:#x}"));
+            }
+        }
+        let exceptions =
+            control.intercept_exception & !(SECURITY_EXCEPTION | exception_policy::REQUIRED_BITMAP);
+        if exceptions != 0 {
+            intercepts.push(format!("exceptions={exceptions:#x}"));
+        }
+        push_bits(
+            &mut intercepts,
+            "misc1",
+            u64::from(control.intercept_misc1 & !required_misc1),
+            &MISC1,
+        );
+        push_bits(
+            &mut intercepts,
+            "misc2",
+            u64::from(control.intercept_misc2 & !required_misc2),
+            &MISC2,
+        );
+        push_bits(
+            &mut intercepts,
+            "misc3",
+            u64::from(control.intercept_misc3),
+            &[],
+        );
+        intercepts
+    }
 }
 
 impl SvmGuest {
+    /// Returns the exception that caused the current #VMEXIT.
+    fn exception_info(&mut self) -> ExceptionInfo {
+        const BP: u8 = 3;
+        const OF: u8 = 4;
+        const PF: u8 = 14;
+
+        let vector = (self.vmcb.control_area.exit_code - 0x40) as u8;
+
+        // "For exceptions that push an error code, EXITINFO1 contains the error
+        //  code" and "for #PF, EXITINFO2 contains the faulting address".
+        // See: 15.12 Exception Intercepts
+        let has_error_code = matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30);
+        let error_code = has_error_code.then_some(self.vmcb.control_area.exit_info1 as u32);
+        if vector == PF {
+            // CR2 is not updated on #VMEXIT due to #PF. Update it as the
+            // processor would do in case the exception is reflected.
+            self.vmcb.state_save_area.cr2 = self.vmcb.control_area.exit_info2;
+            self.mark_vmcb_dirty(VmcbCleanBit::Cr2 as u32);
+        }
+        // #BP and #OF are intercepted at INT3 and INTO, which are one byte long.
+        let next_rip = matches!(vector, BP | OF).then(|| self.vmcb.state_save_area.rip + 1);
+        ExceptionInfo {
+            vector,
+            error_code,
+            next_rip,
+        }
+    }
+
     fn handle_security_exception(&mut self) {
         assert!(self.id != 0);
         self.handle_init_signal();
@@ -169,83 +629,110 @@ impl SvmGuest {
 
         log::debug!("INIT");
 
-        // Extension Type
-        // Not Write-through
-        // Cache Disabled
-        let previous_cr0 = cr0().bits();
-        let new_cr0 = (1u64 << 4)
-            | ((previous_cr0.get_bit(29) as u64) << 29)
-            | ((previous_cr0.get_bit(30) as u64) << 30);
-        self.vmcb.state_save_area.cr0 = new_cr0;
-        self.vmcb.state_save_area.cr2 = 0;
-        self.vmcb.state_save_area.cr3 = 0;
-        self.vmcb.state_save_area.cr4 = 0;
-        self.vmcb.state_save_area.rflags = RFlags::FLAGS_A1.bits();
-        self.vmcb.state_save_area.efer = EFER_SVME;
-        self.vmcb.state_save_area.rip = 0xfff0;
-        self.vmcb.state_save_area.cs_selector = 0xf000;
-        self.vmcb.state_save_area.cs_base = 0xffff0000;
-        self.vmcb.state_save_area.cs_limit = 0xffff;
-        self.vmcb.state_save_area.cs_attrib = 0x9b;
-        self.vmcb.state_save_area.ds_selector = 0;
-        self.vmcb.state_save_area.ds_base = 0;
-        self.vmcb.state_save_area.ds_limit = 0xffff;
-        self.vmcb.state_save_area.ds_attrib = 0x93;
-        self.vmcb.state_save_area.es_selector = 0;
-        self.vmcb.state_save_area.es_base = 0;
-        self.vmcb.state_save_area.es_limit = 0xffff;
-        self.vmcb.state_save_area.es_attrib = 0x93;
-        self.vmcb.state_save_area.fs_selector = 0;
-        self.vmcb.state_save_area.fs_base = 0;
-        self.vmcb.state_save_area.fs_limit = 0xffff;
-        self.vmcb.state_save_area.fs_attrib = 0x93;
-        self.vmcb.state_save_area.gs_selector = 0;
-        self.vmcb.state_save_area.gs_base = 0;
-        self.vmcb.state_save_area.gs_limit = 0xffff;
-        self.vmcb.state_save_area.gs_attrib = 0x93;
-        self.vmcb.state_save_area.ds_selector = 0;
-        self.vmcb.state_save_area.ds_base = 0;
-        self.vmcb.state_save_area.ds_limit = 0xffff;
-        self.vmcb.state_save_area.ds_attrib = 0x93;
-        self.vmcb.state_save_area.gdtr_base = 0;
-        self.vmcb.state_save_area.gdtr_limit = 0xffff;
-        self.vmcb.state_save_area.idtr_base = 0;
-        self.vmcb.state_save_area.idtr_limit = 0xffff;
-        self.vmcb.state_save_area.ldtr_selector = 0;
-        self.vmcb.state_save_area.ldtr_base = 0;
-        self.vmcb.state_save_area.ldtr_limit = 0xffff;
-        self.vmcb.state_save_area.ldtr_attrib = 0x82;
-        self.vmcb.state_save_area.tr_selector = 0;
-        self.vmcb.state_save_area.tr_base = 0;
-        self.vmcb.state_save_area.tr_limit = 0xffff;
-        self.vmcb.state_save_area.tr_attrib = 0x8b;
-        self.registers.rax = 0;
-        self.registers.rdx = cpuid!(0x1).eax as _;
-        self.registers.rbx = 0;
-        self.registers.rcx = 0;
-        self.registers.rbp = 0;
-        self.vmcb.state_save_area.rsp = 0;
-        self.registers.rdi = 0;
-        self.registers.rsi = 0;
-        self.registers.r8 = 0;
-        self.registers.r9 = 0;
-        self.registers.r10 = 0;
-        self.registers.r11 = 0;
-        self.registers.r12 = 0;
-        self.registers.r13 = 0;
-        self.registers.r14 = 0;
-        self.registers.r15 = 0;
+        let init = &ap_startup::INIT_STATE;
+        ap_startup::reset_registers(&mut self.registers, cpuid!(0x1).eax);
+        let state = &mut self.vmcb.state_save_area;
+        state.cr0 = ap_startup::init_cr0(cr0().bits() as u64);
+        state.cr2 = init.cr2;
+        state.cr3 = init.cr3;
+        state.cr4 = init.cr4;
+        state.rflags = self.registers.rflags;
+        state.efer = init.efer | EFER_SVME;
+        state.rip = self.registers.rip;
+        state.rsp = self.registers.rsp;
+        write_segment(
+            (
+                &mut state.cs_selector,
+                &mut state.cs_attrib,
+                &mut state.cs_limit,
+                &mut state.cs_base,
+            ),
+            &init.cs,
+        );
+        write_segment(
+            (
+                &mut state.ss_selector,
+                &mut state.ss_attrib,
+                &mut state.ss_limit,
+                &mut state.ss_base,
+            ),
+            &init.data,
+        );
+        write_segment(
+            (
+                &mut state.ds_selector,
+                &mut state.ds_attrib,
+                &mut state.ds_limit,
+                &mut state.ds_base,
+            ),
+            &init.data,
+        );
+        write_segment(
+            (
+                &mut state.es_selector,
+                &mut state.es_attrib,
+                &mut state.es_limit,
+                &mut state.es_base,
+            ),
+            &init.data,
+        );
+        write_segment(
+            (
+                &mut state.fs_selector,
+                &mut state.fs_attrib,
+                &mut state.fs_limit,
+                &mut state.fs_base,
+            ),
+            &init.data,
+        );
+        write_segment(
+            (
+                &mut state.gs_selector,
+                &mut state.gs_attrib,
+                &mut state.gs_limit,
+                &mut state.gs_base,
+            ),
+            &init.data,
+        );
+        write_segment(
+            (
+                &mut state.ldtr_selector,
+                &mut state.ldtr_attrib,
+                &mut state.ldtr_limit,
+                &mut state.ldtr_base,
+            ),
+            &init.ldtr,
+        );
+        write_segment(
+            (
+                &mut state.tr_selector,
+                &mut state.tr_attrib,
+                &mut state.tr_limit,
+                &mut state.tr_base,
+            ),
+            &init.tr,
+        );
+        state.gdtr_base = init.gdtr_base;
+        state.gdtr_limit = init.gdtr_limit;
+        state.idtr_base = init.idtr_base;
+        state.idtr_limit = init.idtr_limit;
         unsafe {
             x86::debugregs::dr0_write(0);
             x86::debugregs::dr1_write(0);
             x86::debugregs::dr2_write(0);
             x86::debugregs::dr3_write(0);
         };
-        self.vmcb.state_save_area.dr6 = 0xffff0ff0;
-        self.vmcb.state_save_area.dr7 = 0x400;
-
-        self.vmcb.control_area.tlb_control = TlbControl::FlushAll as _;
-        self.vmcb.control_area.vmcb_clean = 0;
+        state.dr6 = init.dr6;
+        state.dr7 = init.dr7;
+
+        self.flush_tlb();
+        self.mark_vmcb_dirty(
+            VmcbCleanBit::CrX as u32
+                | VmcbCleanBit::DrX as u32
+                | VmcbCleanBit::Dt as u32
+                | VmcbCleanBit::Seg as u32
+                | VmcbCleanBit::Cr2 as u32,
+        );
     }
 
     fn wait_for_sipi(&self) -> u8 {
@@ -266,99 +753,351 @@ impl SvmGuest {
         assert!(self.activity_state.load(Ordering::Relaxed) == GuestActivityState::Active as u8);
         log::debug!("SIPI vector {vector:#x?}");
 
-        self.vmcb.state_save_area.cs_selector = (vector as u16) << 8;
-        self.vmcb.state_save_area.cs_base = (vector as u64) << 12;
-        self.vmcb.state_save_area.rip = 0;
-        self.registers.rip = 0;
+        let state = &mut self.vmcb.state_save_area;
+        write_segment(
+            (
+                &mut state.cs_selector,
+                &mut state.cs_attrib,
+                &mut state.cs_limit,
+                &mut state.cs_base,
+            ),
+            &ap_startup::sipi_cs(vector),
+        );
+        state.rip = ap_startup::SIPI_RIP;
+        self.registers.rip = ap_startup::SIPI_RIP;
+        self.mark_vmcb_dirty(VmcbCleanBit::Seg as u32);
     }
 
     fn intercept_apic_write(&mut self, enable: bool) {
-        let apic_base_raw = rdmsr(x86::msr::IA32_APIC_BASE);
-        let apic_base = apic_base_raw & !0xfff;
-        let pt_index = apic_base.get_bits(12..=20) as usize; // [20:12]
+        self.apic_write_intercepted = enable;
+        let apic_base = ApicBase::current();
+        if apic_base.mode() == Some(ApicMode::XApic) {
+            self.protect_apic_page(apic_base.address(), enable);
+        }
+    }
+
+    /// Moves the write-protection of the APIC page, with which SIPI is
+    /// intercepted, to the IA32_APIC_BASE value `new` the guest is writing. In
+    /// the x2APIC mode, the MMIO window no longer exists, and the page is not
+    /// write-protected.
+    fn update_apic_base(&mut self, new: ApicBase) {
+        let old = ApicBase::current();
+        apic_base::log_change(self.id, old, new);
+        if self.apic_write_intercepted && old != new {
+            // There is no MMIO window to intercept in the x2APIC mode. The ICR
+            // MSR is intercepted instead. See `handle_x2apic_icr_write`.
+            if old.mode() == Some(ApicMode::XApic) {
+                self.protect_apic_page(old.address(), false);
+            }
+            if new.mode() == Some(ApicMode::XApic) {
+                self.protect_apic_page(new.address(), true);
+            }
+        }
+    }
 
-        let mut npt = SHARED_GUEST_DATA.npt.write();
-        let pt = npt.apic_pt();
-        pt.0.entries[pt_index].set_writable(!enable);
+    /// Write-protects or unprotects the APIC page at `apic_base` in the NPTs.
+    fn protect_apic_page(&mut self, apic_base: u64, protect: bool) {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut npt = tables.npt.write();
+        let mut hooked_npt = tables.hooked_npt.as_ref().map(RwLock::write);
+        for npt in core::iter::once(&mut *npt).chain(hooked_npt.as_deref_mut()) {
+            match npt.pte_mut(apic_base) {
+                Ok(pte) => pte.set_writable(!protect),
+                Err(e) => log::warn!("Failed to protect the APIC page {apic_base:#x}: {e}"),
+            }
+        }
 
         // Other processors will have stale TLB entries as we do not do TLB
         // shootdown. It is fine because APIC writes we want to see are done by
         // this processors. We need to handle #VMEXIT(NFP) on other processors
         // if it happens.
-        self.vmcb.control_area.tlb_control = TlbControl::FlushAll as _;
+        self.flush_tlb();
     }
 
-    fn handle_nested_page_fault(&mut self) {
-        if self.id == apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed) - 1 {
-            log::debug!("Stopping APIC write interception");
-            self.intercept_apic_write(false);
+    /// Requests the processor to flush TLB entries of this guest on next VMRUN.
+    fn flush_tlb(&mut self) {
+        // Flush only entries associated with this guest's ASID if supported.
+        // Otherwise, flush entire TLB, including that of the host.
+        // See: 15.16.1 TLB Flush
+        self.vmcb.control_area.tlb_control = if self.features.flush_by_asid() {
+            TlbControl::FlushGuests as _
+        } else {
+            TlbControl::FlushAll as _
+        };
+    }
+
+    /// Returns the address of the instruction following the one that caused
+    /// the current #VMEXIT.
+    fn next_rip(&self) -> u64 {
+        // "On #VMEXIT, the processor saves the RIP of the next sequential
+        //  instruction in the nRIP field (...) only for (...) instruction
+        //  intercepts", if NRIP save is supported.
+        // See: 15.7.1 State Saved on Exit
+        if self.features.nrips() {
+            return self.vmcb.control_area.nrip;
         }
 
-        let instructions = unsafe {
-            core::slice::from_raw_parts(
-                self.vmcb.control_area.guest_instruction_bytes.as_ptr(),
-                self.vmcb.control_area.num_of_bytes_fetched as _,
-            )
+        // Otherwise, decode the instruction ourselves. If it is not readable,
+        // let the guest execute it again.
+        let rip = self.vmcb.state_save_area.rip;
+        let Some(bytes) = self.instruction_bytes() else {
+            log::warn!("Instruction at {rip:#x} is not readable");
+            return rip;
         };
+        let instruction = decoder::decode(&bytes)
+            .unwrap_or_else(|| panic!("Unsupported instruction {bytes:02x?}"));
+        rip + instruction.length as u64
+    }
 
-        // This one is by far the most frequent one. Micro-optimize this path by
-        // checking this pattern first.
-        let (value, instr_len) = if instructions
-            .starts_with(&[0xc7, 0x80, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
-        {
-            // MOV DWORD PTR [RAX+000000B0],00000000
-            (0u32, 10u64)
+    /// Records the event being delivered at #VMEXIT, if any, to inject it on
+    /// VMRUN. Clears EVENTINJ, so that the handler injecting an event can be
+    /// told. Software interrupts are not recorded, and the guest executes INTn
+    /// again.
+    fn record_pending_event(&mut self) {
+        self.vmcb.control_area.event_inj = 0;
+
+        // See: 15.7.2 Intercepts During IDT Interrupt Delivery
+        let info = self.vmcb.control_area.exit_int_info;
+        let Some(event) = Event::decode(info as u32, (info >> 32) as u32) else {
+            return;
+        };
+        // RIP points to INTn. Let the guest execute it again.
+        if event.kind == EventKind::SoftwareInterrupt {
+            return;
+        }
+        if let Some(deferred) = self.pending_event.replace(event) {
+            log::warn!("{deferred:x?} is dropped for {event:x?}");
+        }
+    }
+
+    /// Injects the event recorded at #VMEXIT, or combines it with the event
+    /// injected by the handler, and ends the interrupt shadow if the handler
+    /// advanced RIP. Returns `false` if the combination causes triple fault.
+    fn complete_event_delivery(&mut self) -> bool {
+        const INTERRUPT_SHADOW: u64 = 1 << 0;
+        const DF: u8 = 8;
+
+        // See: 15.21.5 Interrupt Shadows
+        if self.registers.rip != self.exit_rip {
+            self.vmcb.control_area.interrupt_shadow &= !INTERRUPT_SHADOW;
+        }
+
+        let Some(pending) = self.pending_event.take() else {
+            return true;
+        };
+        let event_inj = self.vmcb.control_area.event_inj;
+        let injected = Event::decode(event_inj as u32, (event_inj >> 32) as u32);
+        match event_injection::resolve(&pending, injected.as_ref()) {
+            Resolution::Reinject => {
+                self.vmcb.control_area.event_inj = u64::from(pending.encode())
+                    | (u64::from(pending.error_code.unwrap_or(0)) << 32);
+            }
+            Resolution::DoubleFault => self.inject_exception(DF, Some(0)),
+            Resolution::Defer => self.pending_event = Some(pending),
+            Resolution::Discard => {}
+            Resolution::TripleFault => {
+                log::error!("{pending:x?} and {injected:x?} cause triple fault");
+                self.vmcb.control_area.event_inj = 0;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the DPL of the IDT gate for `vector` in 64-bit mode, or `None` if
+    /// it is not readable.
+    ///
+    /// See: Figure 4-24. Interrupt-Gate and Trap-Gate Descriptors—Long Mode
+    fn idt_gate_dpl(&self, vector: u8) -> Option<u8> {
+        let space = gva::address_space(self.long_mode_cr3()?)?;
+        let gate = self.vmcb.state_save_area.idtr_base + u64::from(vector) * 16;
+        let high = space.read_u32(gate + 4)?;
+        Some(high.get_bits(13..=14) as u8)
+    }
+
+    /// Returns the information of the instruction that caused the #VMEXIT.
+    fn instruction_info(&self) -> InstructionInfo {
+        InstructionInfo {
+            next_rip: self.next_rip(),
+        }
+    }
+
+    /// Returns `instruction` that caused the #VMEXIT with the operand decoded
+    /// from the guest memory, or without it if the guest is not in 64-bit mode
+    /// or the bytes are not readable. No decode assist is available.
+    fn descriptor_table_info(
+        &self,
+        instruction: DescriptorTableInstruction,
+    ) -> DescriptorTableInfo {
+        const EFER_LMA: usize = 10;
+        const CS_ATTRIBUTES_L: usize = 9;
+
+        let state = &self.vmcb.state_save_area;
+        let long_mode = state.efer.get_bit(EFER_LMA) && state.cs_attrib.get_bit(CS_ATTRIBUTES_L);
+        let decoded = if long_mode {
+            self.instruction_bytes()
+                .and_then(|bytes| decoder::decode(&bytes))
         } else {
-            match instructions {
-                [0x45, 0x89, 0x65, 0x00, ..] => {
-                    // MOV DWORD PTR [R13],R12D
-                    (self.registers.r12 as _, 4)
-                }
-                [0x41, 0x89, 0x14, 0x00, ..] => {
-                    // MOV DWORD PTR [R8+RAX],EDX
-                    (self.registers.rdx as _, 4)
-                }
-                [
-                    0xc7,
-                    0x81,
-                    0xb0,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    ..,
-                ] => {
-                    // MOV DWORD PTR [RCX+000000B0],00000000
-                    (0, 10)
-                }
-                [0xa3, 0x00, 0x03, 0xe0, 0xfe, 0x00, 0x00, 0x00, 0x00, ..] => {
-                    // MOV DWORD PTR [00000000FEE00300],EAX
-                    (self.registers.rax as _, 9)
-                }
-                [0xa3, 0x10, 0x03, 0xe0, 0xfe, 0x00, 0x00, 0x00, 0x00, ..] => {
-                    // MOV DWORD PTR [00000000FEE00310],EAX
-                    (self.registers.rax as _, 9)
-                }
-                [0x89, 0x90, 0x00, 0x03, 0x00, 0x00, ..] => {
-                    // MOV DWORD PTR [RAX+00000300],EDX
-                    (self.registers.rdx as _, 6)
-                }
-                [0x89, 0x88, 0x10, 0x03, 0x00, 0x00, ..] => {
-                    // MOV DWORD PTR [RAX+00000310],ECX
-                    (self.registers.rcx as _, 6)
-                }
-                _ => {
-                    log::error!("{:#x?}", self.registers);
-                    log::error!("{:#x?}", self.vmcb);
-                    panic!("Unhandled APIC access instructions: {:02x?}", instructions);
-                }
+            None
+        };
+        let next_rip = match (&decoded, self.features.nrips()) {
+            (_, true) => self.vmcb.control_area.nrip,
+            (Some(decoded), false) => state.rip + decoded.length as u64,
+            (None, false) => state.rip,
+        };
+        let operand = decoded.and_then(|decoded| match decoded.operand? {
+            Operand::Register(gpr) => Some(DescriptorTableOperand::Register {
+                gpr,
+                size: decoded.operand_size,
+            }),
+            Operand::Memory(memory) => {
+                let bases = [
+                    state.es_base,
+                    state.cs_base,
+                    state.ss_base,
+                    state.ds_base,
+                    state.fs_base,
+                    state.gs_base,
+                ];
+                Some(DescriptorTableOperand::Memory(memory.linear_address(
+                    &self.registers,
+                    next_rip,
+                    long_mode,
+                    |segment| bases[usize::from(segment)],
+                )))
             }
+        });
+        DescriptorTableInfo {
+            next_rip,
+            instruction,
+            operand,
+            long_mode,
+        }
+    }
+
+    /// Logs the #VMEXIT with `exit_code` that [`EXIT_HANDLERS`] does not
+    /// handle, and panics.
+    fn unhandled_exit(&self, exit_code: u64) -> ! {
+        log::error!("{:#x?}", self.vmcb);
+        log::error!(
+            "EXITINFO1: {:#x}, EXITINFO2: {:#x}, EXITINTINFO: {:#x}",
+            self.vmcb.control_area.exit_info1,
+            self.vmcb.control_area.exit_info2,
+            self.vmcb.control_area.exit_int_info,
+        );
+        panic!(
+            "Unhandled #VMEXIT reason {exit_code:#x} ({}) at {:#x}",
+            exit_codes::name(exit_code),
+            self.registers.rip,
+        )
+    }
+
+    /// Returns the bytes of the instruction that caused the current #VMEXIT.
+    /// Returns `None` if decode assists do not provide them, and they are not
+    /// readable through the page tables of the guest, which requires 64-bit
+    /// mode and the identity mapping of the host.
+    fn instruction_bytes(&self) -> Option<[u8; MAX_INSTRUCTION_LENGTH]> {
+        let mut bytes = [0u8; MAX_INSTRUCTION_LENGTH];
+
+        // Decode assists provide the bytes on #VMEXIT(NPF) and some others.
+        // See: 15.33.4 Instruction Bytes
+        let fetched = self.vmcb.control_area.num_of_bytes_fetched as usize;
+        if self.features.decode_assists() && fetched != 0 {
+            let fetched = fetched.min(MAX_INSTRUCTION_LENGTH);
+            bytes[..fetched]
+                .copy_from_slice(&self.vmcb.control_area.guest_instruction_bytes[..fetched]);
+            return Some(bytes);
+        }
+
+        // Otherwise, read them through the page tables of the guest. The CS
+        // base is ignored in 64-bit mode. The bytes in the next page are
+        // optional, as the instruction may end before it.
+        let space = gva::address_space(self.long_mode_cr3()?)?;
+        let rip = self.vmcb.state_save_area.rip;
+        let in_page =
+            (BASE_PAGE_SIZE - (rip as usize & (BASE_PAGE_SIZE - 1))).min(MAX_INSTRUCTION_LENGTH);
+        space.read(rip, &mut bytes[..in_page])?;
+        let _ = space.read(rip + in_page as u64, &mut bytes[in_page..]);
+        Some(bytes)
+    }
+
+    /// Records that the host modified VMCB fields covered by `bits`, so that
+    /// the processor reloads them on next VMRUN.
+    fn mark_vmcb_dirty(&mut self, bits: u32) {
+        self.dirty_vmcb_fields |= bits;
+    }
+
+    /// Tells the processor which groups of VMCB fields have not been modified
+    /// since the last #VMEXIT and may be skipped from reloading.
+    fn update_vmcb_clean_bits(&mut self) {
+        // "Processors that do not support VMCB Clean bits, or software that
+        //  does not use them, must clear all bits to zero."
+        // See: 15.15.3 VMCB Clean Field
+        self.vmcb.control_area.vmcb_clean = if self.features.vmcb_clean() {
+            !self.dirty_vmcb_fields & VmcbCleanBit::ALL
+        } else {
+            0
+        };
+        self.dirty_vmcb_fields = 0;
+    }
+
+    /// Handles instruction fetch from non-executable pages by switching NPTs
+    /// between the view with the original pages, where hooked pages are not
+    /// executable, and the view with the shadow pages, where the other pages
+    /// are not executable.
+    fn handle_exec_hook_fault(&mut self) {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let Some(hooked_npt) = &tables.hooked_npt else {
+            log::error!("{:#x?}", self.vmcb);
+            panic!("Unexpected instruction fetch #VMEXIT(NPF)");
+        };
+
+        // Executing a hooked page in the original view, switch to the shadow
+        // view. Executing any other page in the shadow view, switch back.
+        let rw_view = platform_ops::get()
+            .pa(tables.npt.read().as_ref() as *const _ as _)
+            .unwrap();
+        let x_view = platform_ops::get()
+            .pa(hooked_npt.read().as_ref() as *const _ as _)
+            .unwrap();
+        self.vmcb.control_area.ncr3 = if self.vmcb.control_area.ncr3 == rw_view {
+            x_view
+        } else {
+            rw_view
+        };
+        self.mark_vmcb_dirty(VmcbCleanBit::Np as u32);
+
+        // Cached guest translations were derived from the previous NPTs.
+        self.flush_tlb();
+    }
+
+    fn handle_nested_page_fault(&mut self) {
+        let Some(bytes) = self.instruction_bytes() else {
+            log::error!("{:#x?}", self.vmcb);
+            panic!("APIC access instruction bytes are unavailable");
+        };
+        let Some(instruction) = decoder::decode(&bytes) else {
+            log::error!("{:#x?}", self.registers);
+            log::error!("{:#x?}", self.vmcb);
+            panic!("Unhandled APIC access instructions: {bytes:02x?}");
         };
 
-        self.registers.rip += instr_len;
+        // Find the value the guest attempted to write.
+        let value = match (instruction.opcode, instruction.reg, instruction.immediate) {
+            // MOV r/m32, r32. eg, MOV DWORD PTR [R13],R12D
+            (0x89, Some(reg), _) => self.registers.gpr(reg) as u32,
+            // MOV moffs32, EAX. eg, MOV DWORD PTR [00000000FEE00300],EAX
+            (0xa3, _, _) => self.registers.rax as u32,
+            // MOV r/m32, imm32. eg, MOV DWORD PTR [RAX+000000B0],00000000
+            (0xc7, _, Some(immediate)) => immediate as u32,
+            _ => {
+                log::error!("{:#x?}", self.registers);
+                log::error!("{:#x?}", self.vmcb);
+                panic!("Unhandled APIC access instructions: {instruction:#x?}");
+            }
+        };
+
+        self.registers.rip += instruction.length as u64;
 
         let message_type = value.get_bits(8..=10);
         let faulting_gpa = self.vmcb.control_area.exit_info2;
@@ -383,64 +1122,209 @@ impl SvmGuest {
         // The BSP is trying to send Startup IPI. This must not be allowed because
         // SVM does not intercept it or deliver #VMEXIT. We need to prevent the
         // BSP from sending it and emulate the effect in software instead.
-
-        // Figure 16-18. Interrupt Command Register (APIC Offset 300h–310h)
-        assert!(!value.get_bit(11), "Destination Mode must be 'Physical'");
-        assert!(
-            value.get_bits(18..=19) == 0b00,
-            "Destination Shorthand must be 'Destination'"
-        );
-
+        //
         // SAFETY: GPA is same as PA in our NTPs, and the faulting address
         // is always the local APIC page, which is writable in the host
         // address space.
         let icr_high_addr = (faulting_gpa & !0xfff) | 0x310;
         let icr_high_value = unsafe { *(icr_high_addr as *mut u32) };
 
+        // Figure 16-18. Interrupt Command Register (APIC Offset 300h–310h)
+        self.emulate_sipi(value, icr_high_value.get_bits(24..=31));
+
+        // Once all APs went through INIT-SIPI, stop intercepting writes to the
+        // APIC page. Otherwise, every EOI write keeps causing #VMEXIT(NPF).
+        if SHARED_GUEST_DATA.started_ap_count.load(Ordering::Relaxed)
+            >= apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed) - 1
+        {
+            log::debug!("Stopping APIC write interception");
+            self.intercept_apic_write(false);
+        }
+    }
+
+    /// Handles WRMSR to the x2APIC ICR, and returns `true` if it was the Startup
+    /// IPI and was emulated. Otherwise, returns `false` and the caller is
+    /// responsible for completing the write.
+    // When the OS enables the x2APIC mode, the ICR is written with the WRMSR
+    // instruction instead of MMIO, and NPT-based interception no longer works.
+    // This MSR is intercepted through the MSR permission map for this reason.
+    fn handle_x2apic_icr_write(&mut self) -> bool {
+        // "The 64-bit ICR is accessed as MSR 0830h."
+        // See: 16.11.4.2 x2APIC Register Address Space
+        const X2APIC_MSR_ICR: u64 = 0x830;
+
+        if self.registers.rcx & 0xffff_ffff != X2APIC_MSR_ICR {
+            return false;
+        }
+
+        // The lower 32 bits are the same as that of the xAPIC ICR Low, and the
+        // upper 32 bits are the 32-bit destination x2APIC ID.
+        // See: Figure 16-45. Interrupt Command Register (x2APIC)
+        let value = self.registers.rax as u32;
+        let message_type = value.get_bits(8..=10);
+        if message_type != 0b110 {
+            return false;
+        }
+
+        self.emulate_sipi(value, self.registers.rdx as u32);
+        self.registers.rip = self.next_rip();
+        true
+    }
+
+    /// Emulates the effect of the Startup IPI specified by the ICR low value
+    /// `icr_low` and the destination APIC ID `destination`.
+    fn emulate_sipi(&self, icr_low: u32, destination: u32) {
         // Collect necessary bits to emulate, that is, vector and destination.
-        let vector = value.get_bits(0..=7) as u8;
-        let apic_id = icr_high_value.get_bits(24..=31) as u8;
-        let processor_id = apic_id::processor_id_from(apic_id).unwrap();
-        log::debug!("SIPI to {apic_id} with vector {vector:#x?}");
+        assert!(!icr_low.get_bit(11), "Destination Mode must be 'Physical'");
+        let vector = icr_low.get_bits(0..=7) as u8;
         assert!(vector != GuestActivityState::WaitForSipi as u8);
 
-        // Update the activity state of the target processor with the obtained
-        // vector value. The target processor should get out from the busy loop
-        // after this. Note that it is possible that the target processor is not
-        // yet in the WaitForSipi state when #VMEXIT(#SX) has not been processed.
-        // It is fine, as SIPI will be sent twice, and almost certain that 2nd
-        // SIPI is late enough.
+        // "Destination Shorthand" is either 'Destination' (0b00) or 'All
+        // Excluding Self' (0b11) for the Startup IPI. The others are invalid.
+        // See: Table 16-6. Interrupt Command Register (APIC Offset 300h–310h)
+        match icr_low.get_bits(18..=19) {
+            0b00 => {
+                let processor_id = apic_id::processor_id_from(destination)
+                    .unwrap_or_else(|| panic!("Unknown APIC ID {destination:#x?}"));
+                log::debug!("SIPI to {destination} with vector {vector:#x?}");
+                Self::deliver_sipi(processor_id, vector);
+            }
+            0b11 => {
+                log::debug!("SIPI to all excluding self with vector {vector:#x?}");
+                for processor_id in 0..apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed) {
+                    if processor_id != self.id {
+                        Self::deliver_sipi(processor_id, vector);
+                    }
+                }
+            }
+            shorthand => panic!("Unsupported Destination Shorthand {shorthand:#b}"),
+        }
+    }
+
+    /// Updates the activity state of the processor `processor_id` with `vector`.
+    fn deliver_sipi(processor_id: usize, vector: u8) {
+        // The target processor should get out from the busy loop after this.
+        // Note that it is possible that the target processor is not yet in the
+        // WaitForSipi state when #VMEXIT(#SX) has not been processed. It is fine,
+        // as SIPI will be sent twice, and almost certain that 2nd SIPI is late
+        // enough.
         let activity_state = &SHARED_GUEST_DATA.activity_states[processor_id];
-        let _ = activity_state.compare_exchange(
-            GuestActivityState::WaitForSipi as u8,
-            vector,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
-        );
+        if activity_state
+            .compare_exchange(
+                GuestActivityState::WaitForSipi as u8,
+                vector,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            let _ = SHARED_GUEST_DATA
+                .started_ap_count
+                .fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     fn initialize_control(&mut self) {
-        const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
-        const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
         const SVM_NP_ENABLE_NP_ENABLE: u64 = 1 << 0;
 
-        self.vmcb.control_area.intercept_misc1 = SVM_INTERCEPT_MISC1_CPUID;
-        self.vmcb.control_area.intercept_misc2 = SVM_INTERCEPT_MISC2_VMRUN;
+        // Intercept INVD and WBINVD, so that the host writes back the caches
+        // on behalf of the guest. See `handle_cache_invalidation`.
+        self.vmcb.control_area.intercept_misc1 =
+            SVM_INTERCEPT_MISC1_CPUID | SVM_INTERCEPT_MISC1_INVD;
+        if cfg!(feature = "uefi") {
+            // Intercept writes to the x2APIC ICR in case the OS switches to the
+            // x2APIC mode and starts APs with WRMSR. See `handle_x2apic_icr_write`.
+            let msrpm = addr_of!(*SHARED_GUEST_DATA.tables(self.id).msr_permission_map);
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_MSR_PROT;
+            self.vmcb.control_area.msrpm_base_pa = platform_ops::get().pa(msrpm as _).unwrap();
+        }
+        self.vmcb.control_area.intercept_misc2 =
+            SVM_INTERCEPT_MISC2_VMRUN | SVM_INTERCEPT_MISC2_VMMCALL | SVM_INTERCEPT_MISC2_WBINVD;
+
+        // Intercept RDTSC, RDTSCP and the time related MSRs in the
+        // deterministic time mode. See `deterministic_time`.
+        if deterministic_time::enabled() {
+            let msrpm = addr_of!(*SHARED_GUEST_DATA.tables(self.id).msr_permission_map);
+            self.vmcb.control_area.intercept_misc1 |=
+                SVM_INTERCEPT_MISC1_RDTSC | SVM_INTERCEPT_MISC1_MSR_PROT;
+            self.vmcb.control_area.intercept_misc2 |= SVM_INTERCEPT_MISC2_RDTSCP;
+            self.vmcb.control_area.msrpm_base_pa = platform_ops::get().pa(msrpm as _).unwrap();
+        }
+
+        // Intercept writes to the event selectors to isolate the performance
+        // counters. See `pmu`. In the advertise-hypervisor mode, intercept
+        // access to the synthetic MSRs, which are outside the MSRPM and always
+        // intercepted with it. See `hyperv`.
+        if pmu::isolated() || hyperv::advertised() {
+            let msrpm = addr_of!(*SHARED_GUEST_DATA.tables(self.id).msr_permission_map);
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_MSR_PROT;
+            self.vmcb.control_area.msrpm_base_pa = platform_ops::get().pa(msrpm as _).unwrap();
+        }
+        if SHARED_HOST_DATA.get().unwrap().config.hlt == HltPolicy::Intercept {
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_HLT;
+        }
+        if umip::is_enabled() {
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_DESCRIPTOR_TABLE_READS;
+        }
+        if pmu::rdpmc_exiting() {
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_RDPMC;
+        }
+        if mwait::is_intercepted() {
+            self.vmcb.control_area.intercept_misc2 |=
+                SVM_INTERCEPT_MISC2_MONITOR | SVM_INTERCEPT_MISC2_MWAIT;
+        }
+        // Intercept SMIs to count them. See `smi`.
+        if SHARED_HOST_DATA.get().unwrap().config.smm_exiting {
+            self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_SMI;
+        }
         self.vmcb.control_area.pause_filter_count = u16::MAX;
 
+        // Intercept PAUSE after the configured number of PAUSEs in a loop.
+        // See: 15.14.4 Pause Intercept Filtering
+        if let Some(ple) = SHARED_HOST_DATA.get().unwrap().config.pause_loop_exiting {
+            if self.features.pause_filter() {
+                self.vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_PAUSE;
+                self.vmcb.control_area.pause_filter_count =
+                    u16::try_from(ple.window).unwrap_or(u16::MAX);
+                if self.features.pause_filter_threshold() {
+                    self.vmcb.control_area.pause_filter_threshold =
+                        u16::try_from(ple.gap).unwrap_or(u16::MAX);
+                }
+            } else {
+                log::warn!("PAUSE filter is not supported and disabled");
+            }
+        }
+
         // Address Space Identifier (ASID) is useful when the given logical processor
-        // runs more than one guests. We do not but still need to set non-zero value.
+        // runs more than one guests. TLB entries are tagged with it, so switching
+        // between guests does not require flushing TLB. The guest on this
+        // processor reuses the same one across re-virtualization, and its TLB
+        // entries may be left from the previous guest. Flush them on the first
+        // VMRUN.
         // See: 15.16 TLB Control
-        self.vmcb.control_area.guest_asid = 1;
+        self.vmcb.control_area.guest_asid = asid::get(self.id);
+        self.flush_tlb();
+
+        // Swap DebugCtl and the LBR MSRs of the host and the guest on VMRUN and
+        // #VMEXIT, so that the host does not record branches into the LBRs of
+        // the guest. See `lbr`.
+        // See: 15.23 Last Branch Record Virtualization
+        if self.features.lbr_virt() {
+            const LBR_VIRTUALIZATION_ENABLE: u64 = 1 << 0;
+            self.vmcb.control_area.lbr_virtualization_enable = LBR_VIRTUALIZATION_ENABLE;
+        }
 
         // Enable nested paging. This is done by:
         // - Setting the NP_ENABLE bit in VMCB, and
         // - Setting the base address of the nested PML4
         //
         // See: 15.25.3 Enabling Nested Paging
-        let nested_pml4_addr = SHARED_GUEST_DATA.npt.read().as_ref() as *const _;
-        self.vmcb.control_area.np_enable = SVM_NP_ENABLE_NP_ENABLE;
-        self.vmcb.control_area.ncr3 = platform_ops::get().pa(nested_pml4_addr as _);
+        if self.features.np() {
+            let nested_pml4_addr =
+                SHARED_GUEST_DATA.tables(self.id).npt.read().as_ref() as *const _;
+            self.vmcb.control_area.np_enable = SVM_NP_ENABLE_NP_ENABLE;
+            self.vmcb.control_area.ncr3 = platform_ops::get().pa(nested_pml4_addr as _).unwrap();
+        }
 
         // Convert #INIT to #SX. One cannot simply intercept #INIT because even
         // if we do, #INIT is still pending and will be delivered anyway.
@@ -448,8 +1332,23 @@ impl SvmGuest {
         const R_INIT: u64 = 1 << 1;
         wrmsr(SVM_MSR_VM_CR, rdmsr(SVM_MSR_VM_CR) | R_INIT);
 
-        const SECURITY_EXCEPTION: u32 = 1 << 30;
         self.vmcb.control_area.intercept_exception = SECURITY_EXCEPTION;
+
+        // Scale the guest TSC if configured. The ratio MSR applies only while
+        // the guest runs. See `tsc_scaling`.
+        // See: 15.30.5 TSC Ratio MSR (C000_0104h)
+        if let Some(ratio) = SHARED_HOST_DATA.get().unwrap().config.tsc_ratio {
+            const SVM_MSR_TSC_RATIO: u32 = 0xc000_0104;
+
+            match ratio.svm_ratio().filter(|_| self.features.tsc_rate_msr()) {
+                Some(value) => {
+                    wrmsr(SVM_MSR_TSC_RATIO, value);
+                    self.vmcb.control_area.tsc_offset =
+                        tsc_scaling::offset(value, tsc_scaling::SVM_FRACTION_BITS);
+                }
+                None => log::warn!("TSC scaling by {ratio:?} is not supported. Ignored"),
+            }
+        }
     }
 
     fn initialize_guest(&mut self) {
@@ -484,6 +1383,12 @@ impl SvmGuest {
         self.vmcb.state_save_area.rflags = self.registers.rflags;
         self.vmcb.state_save_area.rax = self.registers.rax;
         self.vmcb.state_save_area.gpat = rdmsr(x86::msr::IA32_PAT);
+        if self.features.lbr_virt() {
+            self.vmcb.state_save_area.dbg_ctl = rdmsr(x86::msr::IA32_DEBUGCTL);
+        }
+        if speculation::is_spec_ctrl_supported() {
+            self.vmcb.state_save_area.spec_ctl = rdmsr(speculation::IA32_SPEC_CTRL);
+        }
 
         // VMSAVE copies some of the current register values into VMCB. Take
         // advantage of it.
@@ -495,23 +1400,168 @@ impl SvmGuest {
 
         if let Some(host_pt) = &shared_host.pt {
             let pml4 = addr_of!(*host_pt.as_ref());
-            unsafe { cr3_write(platform_ops::get().pa(pml4 as _)) };
+            unsafe { cr3_write(platform_ops::get().pa(pml4 as _).unwrap()) };
         }
 
-        if let Some(host_gdt_and_tss) = &shared_host.gdts {
-            host_gdt_and_tss[self.id].apply().unwrap();
-        }
+        let host_gdt_and_tss = &shared_host.gdts[self.id];
+        lgdt(&host_gdt_and_tss.gdtr());
 
         if let Some(host_idt) = &shared_host.idt {
             lidt(&host_idt.idtr());
         }
 
         // Save some of the current register values as host state. They are
-        // restored shortly after #VMEXIT.
+        // restored shortly after #VMEXIT. This includes STAR, LSTAR, CSTAR,
+        // SFMASK and KernelGSBase, which are not switched by VMRUN and #VMEXIT,
+        // and isolates them between the guest and the host.
+        // See: 15.5.2 VMSAVE and VMLOAD Instructions
         vmsave(self.host_vmcb_pa);
+
+        // TR is not switched by VMRUN and #VMEXIT but by VMLOAD with this VMCB
+        // after #VMEXIT. Use the TSS of this processor for the host.
+        // See: 15.5.2 VMSAVE and VMLOAD Instructions
+        let tss = host_gdt_and_tss.tss.as_ref().unwrap();
+        let state = &mut self.host_vmcb.state_save_area;
+        state.tr_selector = host_gdt_and_tss.tr.unwrap().bits();
+        state.tr_base = core::ptr::from_ref(tss) as u64;
+        state.tr_limit = size_of_val(tss) as u32 - 1;
+        state.tr_attrib = 0x8b;
+
+        // Run the host without recording branches. VMRUN saves this value and
+        // #VMEXIT restores it. See `lbr`.
+        if self.features.lbr_virt() {
+            wrmsr(x86::msr::IA32_DEBUGCTL, 0);
+        }
+
+        // Run the host with the IA32_SPEC_CTRL value at virtualization. With
+        // SPEC_CTRL virtualization, VMRUN saves it and #VMEXIT restores it.
+        // Otherwise, `run` does. See `speculation`.
+        if speculation::is_spec_ctrl_supported() && !self.features.spec_ctrl() {
+            self.host_spec_ctrl = Some(rdmsr(speculation::IA32_SPEC_CTRL));
+        }
     }
 }
 
+/// The functions that translate #VMEXITs into [`VmExitReason`], indexed by the
+/// exit codes. See [`DispatchTable`].
+static EXIT_HANDLERS: DispatchTable<SvmGuest, { exit_codes::COUNT }> =
+    DispatchTable::<SvmGuest, { exit_codes::COUNT }>::new()
+        .with(VMEXIT_EXCEPTION_SX, |guest| {
+            guest.handle_security_exception();
+            VmExitReason::InitSignal
+        })
+        .with(VMEXIT_CPUID, |guest| {
+            VmExitReason::Cpuid(guest.instruction_info())
+        })
+        .with(VMEXIT_MSR, |guest| {
+            // "EXITINFO1 is set to 0 for RDMSR and 1 for WRMSR."
+            // See: 15.11 MSR Intercepts
+            let info = guest.instruction_info();
+            if guest.vmcb.control_area.exit_info1 == 0 {
+                VmExitReason::Rdmsr(info)
+            } else if guest.handle_x2apic_icr_write() {
+                VmExitReason::StartupIpi
+            } else {
+                VmExitReason::Wrmsr(info)
+            }
+        })
+        .with(VMEXIT_NPF, |guest| {
+            let info = nested_page_fault_info(
+                guest.vmcb.control_area.exit_info1,
+                guest.vmcb.control_area.exit_info2,
+            );
+            if info.execute {
+                guest.handle_exec_hook_fault();
+            } else {
+                guest.handle_nested_page_fault();
+            }
+            VmExitReason::NestedPageFault(info)
+        })
+        .with_range(VMEXIT_CR0_READ..=VMEXIT_CR15_WRITE, |guest| {
+            VmExitReason::CrAccess(cr_access_info(
+                guest.next_rip(),
+                guest.vmcb.control_area.exit_code,
+                guest.vmcb.control_area.exit_info1,
+                guest.features.decode_assists(),
+            ))
+        })
+        .with(VMEXIT_IOIO, |guest| {
+            VmExitReason::IoInstruction(io_info(
+                guest.vmcb.control_area.exit_info1,
+                guest.vmcb.control_area.exit_info2,
+            ))
+        })
+        // All exceptions but #SX, which is handled above.
+        .with_range(VMEXIT_EXCEPTION_DE..=VMEXIT_EXCEPTION_SX - 1, |guest| {
+            VmExitReason::Exception(guest.exception_info())
+        })
+        .with(VMEXIT_EXCEPTION_31, |guest| {
+            VmExitReason::Exception(guest.exception_info())
+        })
+        .with(VMEXIT_HLT, |guest| {
+            VmExitReason::Hlt(guest.instruction_info())
+        })
+        .with(VMEXIT_VMMCALL, |guest| {
+            VmExitReason::Hypercall(guest.instruction_info())
+        })
+        .with(VMEXIT_INVD, |guest| {
+            VmExitReason::CacheInvalidation(guest.instruction_info())
+        })
+        .with(VMEXIT_WBINVD, |guest| {
+            VmExitReason::CacheInvalidation(guest.instruction_info())
+        })
+        .with(VMEXIT_MONITOR, |guest| {
+            VmExitReason::Monitor(guest.instruction_info())
+        })
+        .with(VMEXIT_MWAIT, |guest| {
+            VmExitReason::Mwait(guest.instruction_info())
+        })
+        .with(VMEXIT_SMI, |_| VmExitReason::Smi)
+        .with(VMEXIT_RDPMC, |guest| {
+            VmExitReason::Rdpmc(guest.instruction_info())
+        })
+        .with(VMEXIT_RDTSC, |guest| {
+            VmExitReason::Rdtsc(guest.instruction_info())
+        })
+        .with(VMEXIT_RDTSCP, |guest| {
+            VmExitReason::Rdtscp(guest.instruction_info())
+        })
+        .with(VMEXIT_IDTR_READ, |guest| {
+            VmExitReason::DescriptorTableAccess(
+                guest.descriptor_table_info(DescriptorTableInstruction::Sidt),
+            )
+        })
+        .with(VMEXIT_GDTR_READ, |guest| {
+            VmExitReason::DescriptorTableAccess(
+                guest.descriptor_table_info(DescriptorTableInstruction::Sgdt),
+            )
+        })
+        .with(VMEXIT_LDTR_READ, |guest| {
+            VmExitReason::DescriptorTableAccess(
+                guest.descriptor_table_info(DescriptorTableInstruction::Sldt),
+            )
+        })
+        .with(VMEXIT_TR_READ, |guest| {
+            VmExitReason::DescriptorTableAccess(
+                guest.descriptor_table_info(DescriptorTableInstruction::Str),
+            )
+        })
+        .with(VMEXIT_SHUTDOWN, |_| VmExitReason::Shutdown)
+        .with(VMEXIT_PAUSE, |_| VmExitReason::Pause);
+
+/// Writes `segment` to the selector, attributes, limit and base `fields` of
+/// the state save area.
+fn write_segment(
+    fields: (&mut u16, &mut u16, &mut u32, &mut u64),
+    segment: &ap_startup::SegmentState,
+) {
+    let (selector, attributes, limit, base) = fields;
+    *selector = segment.selector;
+    *attributes = segment.attributes;
+    *limit = segment.limit;
+    *base = segment.base;
+}
+
 /// Table 15-9. TLB Control Byte Encodings
 #[expect(dead_code)]
 #[repr(u32)]
@@ -523,7 +1573,43 @@ enum TlbControl {
     FlushGuestsNonGlobal = 0x7,
 }
 
-#[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
+/// Table 15-10. VMCB Clean Field
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum VmcbCleanBit {
+    I = 1 << 0,
+    Iopm = 1 << 1,
+    Asid = 1 << 2,
+    Tpr = 1 << 3,
+    Np = 1 << 4,
+    CrX = 1 << 5,
+    DrX = 1 << 6,
+    Dt = 1 << 7,
+    Seg = 1 << 8,
+    Cr2 = 1 << 9,
+    Lbr = 1 << 10,
+    Avic = 1 << 11,
+    Cet = 1 << 12,
+}
+
+impl VmcbCleanBit {
+    /// The bits defined above. The other bits are reserved, and must be zero.
+    const ALL: u32 = Self::I as u32
+        | Self::Iopm as u32
+        | Self::Asid as u32
+        | Self::Tpr as u32
+        | Self::Np as u32
+        | Self::CrX as u32
+        | Self::DrX as u32
+        | Self::Dt as u32
+        | Self::Seg as u32
+        | Self::Cr2 as u32
+        | Self::Lbr as u32
+        | Self::Avic as u32
+        | Self::Cet as u32;
+}
+
+#[derive(derive_deref::Deref, derive_deref::DerefMut)]
 struct Vmcb {
     ptr: Box<VmcbRaw>,
 }
@@ -536,11 +1622,183 @@ impl Default for Vmcb {
     }
 }
 
+impl core::fmt::Debug for Vmcb {
+    #[rustfmt::skip]
+    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let control = &self.control_area;
+        let state = &self.state_save_area;
+
+        // Dump the VMCB. Note that this is not exhaustive.
+        format.debug_struct("Vmcb")
+        .field("Current VMCB                  ", &addr_of!(*self.ptr))
+
+        // Table B-1. VMCB Layout, Control Area
+        .field("Intercept CR reads            ", &control.intercept_cr_read)
+        .field("Intercept CR writes           ", &control.intercept_cr_write)
+        .field("Intercept DR reads            ", &control.intercept_dr_read)
+        .field("Intercept DR writes           ", &control.intercept_dr_write)
+        .field("Intercept exception vectors   ", &control.intercept_exception)
+        .field("Intercept misc vector 1       ", &control.intercept_misc1)
+        .field("Intercept misc vector 2       ", &control.intercept_misc2)
+        .field("Intercept misc vector 3       ", &control.intercept_misc3)
+        .field("PAUSE filter threshold        ", &control.pause_filter_threshold)
+        .field("PAUSE filter count            ", &control.pause_filter_count)
+        .field("IOPM base physical address    ", &control.iopm_base_pa)
+        .field("MSRPM base physical address   ", &control.msrpm_base_pa)
+        .field("TSC offset                    ", &control.tsc_offset)
+        .field("Guest ASID                    ", &control.guest_asid)
+        .field("TLB control                   ", &control.tlb_control)
+        .field("Virtual interrupt control     ", &control.vintr)
+        .field("Interrupt shadow              ", &control.interrupt_shadow)
+        .field("EXITCODE                      ", &control.exit_code)
+        .field("EXITCODE (decoded)            ", &exit_codes::name(control.exit_code))
+        .field("EXITINFO1                     ", &control.exit_info1)
+        .field("EXITINFO2                     ", &control.exit_info2)
+        .field("EXITINTINFO                   ", &control.exit_int_info)
+        .field("Nested paging control         ", &control.np_enable)
+        .field("AVIC APIC_BAR                 ", &control.avic_apic_bar)
+        .field("Guest PA of GHCB              ", &control.guest_pa_pf_ghcb)
+        .field("EVENTINJ                      ", &control.event_inj)
+        .field("N_CR3                         ", &control.ncr3)
+        .field("LBR virtualization enable     ", &control.lbr_virtualization_enable)
+        .field("VMCB clean bits               ", &control.vmcb_clean)
+        .field("nRIP                          ", &control.nrip)
+        .field("Number of bytes fetched       ", &control.num_of_bytes_fetched)
+        .field("Guest instruction bytes       ", &control.guest_instruction_bytes)
+        .field("AVIC APIC backing page pointer", &control.avic_apic_backing_page_pointer)
+        .field("AVIC logical table pointer    ", &control.avic_logical_table_pointer)
+        .field("AVIC physical table pointer   ", &control.avic_physical_table_pointer)
+        .field("VMSA pointer                  ", &control.vmcb_save_state_pointer)
+
+        // Table B-2. VMCB Layout, State Save Area
+        .field("ES selector                   ", &state.es_selector)
+        .field("ES attributes                 ", &state.es_attrib)
+        .field("ES limit                      ", &state.es_limit)
+        .field("ES base                       ", &state.es_base)
+        .field("CS selector                   ", &state.cs_selector)
+        .field("CS attributes                 ", &state.cs_attrib)
+        .field("CS limit                      ", &state.cs_limit)
+        .field("CS base                       ", &state.cs_base)
+        .field("SS selector                   ", &state.ss_selector)
+        .field("SS attributes                 ", &state.ss_attrib)
+        .field("SS limit                      ", &state.ss_limit)
+        .field("SS base                       ", &state.ss_base)
+        .field("DS selector                   ", &state.ds_selector)
+        .field("DS attributes                 ", &state.ds_attrib)
+        .field("DS limit                      ", &state.ds_limit)
+        .field("DS base                       ", &state.ds_base)
+        .field("FS selector                   ", &state.fs_selector)
+        .field("FS attributes                 ", &state.fs_attrib)
+        .field("FS limit                      ", &state.fs_limit)
+        .field("FS base                       ", &state.fs_base)
+        .field("GS selector                   ", &state.gs_selector)
+        .field("GS attributes                 ", &state.gs_attrib)
+        .field("GS limit                      ", &state.gs_limit)
+        .field("GS base                       ", &state.gs_base)
+        .field("GDTR limit                    ", &state.gdtr_limit)
+        .field("GDTR base                     ", &state.gdtr_base)
+        .field("LDTR selector                 ", &state.ldtr_selector)
+        .field("LDTR attributes               ", &state.ldtr_attrib)
+        .field("LDTR limit                    ", &state.ldtr_limit)
+        .field("LDTR base                     ", &state.ldtr_base)
+        .field("IDTR limit                    ", &state.idtr_limit)
+        .field("IDTR base                     ", &state.idtr_base)
+        .field("TR selector                   ", &state.tr_selector)
+        .field("TR attributes                 ", &state.tr_attrib)
+        .field("TR limit                      ", &state.tr_limit)
+        .field("TR base                       ", &state.tr_base)
+        .field("CPL                           ", &state.cpl)
+        .field("EFER                          ", &state.efer)
+        .field("CR4                           ", &state.cr4)
+        .field("CR3                           ", &state.cr3)
+        .field("CR0                           ", &state.cr0)
+        .field("DR7                           ", &state.dr7)
+        .field("DR6                           ", &state.dr6)
+        .field("RFLAGS                        ", &state.rflags)
+        .field("RIP                           ", &state.rip)
+        .field("RSP                           ", &state.rsp)
+        .field("S_CET                         ", &state.s_cet)
+        .field("SSP                           ", &state.ssp)
+        .field("ISST_ADDR                     ", &state.isst_addr)
+        .field("RAX                           ", &state.rax)
+        .field("STAR                          ", &state.star)
+        .field("LSTAR                         ", &state.lstar)
+        .field("CSTAR                         ", &state.cstar)
+        .field("SFMASK                        ", &state.sf_mask)
+        .field("KernelGsBase                  ", &state.kernel_gs_base)
+        .field("SYSENTER_CS                   ", &state.sysenter_cs)
+        .field("SYSENTER_ESP                  ", &state.sysenter_esp)
+        .field("SYSENTER_EIP                  ", &state.sysenter_eip)
+        .field("CR2                           ", &state.cr2)
+        .field("G_PAT                         ", &state.gpat)
+        .field("DBGCTL                        ", &state.dbg_ctl)
+        .field("BR_FROM                       ", &state.br_from)
+        .field("BR_TO                         ", &state.br_to)
+        .field("LASTEXCPFROM                  ", &state.last_excep_from)
+        .field("LASTEXCPTO                    ", &state.last_excep_to)
+        .field("SPEC_CTRL                     ", &state.spec_ctl)
+        .finish_non_exhaustive()
+    }
+}
+
+/// Decodes EXITINFO1 of #VMEXIT due to MOV to or from a control register.
+/// The GPR is only available with decode assists.
+pub(crate) fn cr_access_info(
+    next_rip: u64,
+    exit_code: u64,
+    exit_info1: u64,
+    decode_assists: bool,
+) -> CrAccessInfo {
+    // "EXITINFO1[63]: MOV CRx instruction. EXITINFO1[3:0]: GPR number"
+    // if decode assists are supported.
+    // See: 15.33.1 MOV CRx/DRx Intercepts
+    let gpr = (decode_assists && exit_info1.get_bit(63)).then(|| exit_info1.get_bits(0..=3) as u8);
+    CrAccessInfo {
+        next_rip,
+        cr: (exit_code & 0xf) as u8,
+        write: exit_code >= 0x10,
+        gpr,
+    }
+}
+
+/// Decodes EXITINFO1 and EXITINFO2 of #VMEXIT due to an I/O instruction.
+///
+/// See: Figure 15-2. EXITINFO1 for IOIO Intercept
+pub(crate) fn io_info(exit_info1: u64, exit_info2: u64) -> IoInfo {
+    IoInfo {
+        // "EXITINFO2: the rIP of the instruction following the IN/OUT"
+        next_rip: exit_info2,
+        port: exit_info1.get_bits(16..=31) as u16,
+        // SZ8, SZ16 and SZ32 bits are one-hot and equal to the size in bytes.
+        size: exit_info1.get_bits(4..=6) as u8,
+        input: exit_info1.get_bit(0),
+        string: exit_info1.get_bit(2),
+        rep: exit_info1.get_bit(3),
+    }
+}
+
+/// Decodes EXITINFO1 and EXITINFO2 of #VMEXIT due to a nested page fault.
+pub(crate) fn nested_page_fault_info(exit_info1: u64, exit_info2: u64) -> NestedPageFaultInfo {
+    // "EXITINFO1 (...) RW (Bit 1): set to 1 if the access causing the fault
+    //  was a write. (...) ID (Bit 4): set to 1 if the nested page fault
+    //  occurred while fetching an instruction."
+    // See: 15.25.6 Nested versus Guest Page Faults, Fault Ordering
+    //
+    // Instruction fetches are not reads.
+    let write = exit_info1.get_bit(1);
+    let execute = exit_info1.get_bit(4);
+    NestedPageFaultInfo {
+        gpa: exit_info2,
+        read: !write && !execute,
+        write,
+        execute,
+    }
+}
+
 /// The virtual machine control block (VMCB), which describes a virtual machine
 /// (guest) to be executed.
 ///
 /// See: Appendix B Layout of VMCB
-#[derive(Debug)]
 #[repr(C, align(4096))]
 struct VmcbRaw {
     control_area: ControlArea,
@@ -606,7 +1864,7 @@ const _: () = assert!(core::mem::size_of::<ControlArea>() == 0x400);
 /// The ares to specify and read guest register values.
 ///
 /// See: Table B-2. VMCB Layout, State Save Area
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 struct StateSaveArea {
     es_selector: u16,   // +0x000
@@ -739,6 +1997,17 @@ fn vmsave(vmcb_pa: u64) {
     };
 }
 
+/// Loads registers from VMCS
+fn vmload(vmcb_pa: u64) {
+    unsafe {
+        asm!(
+            "mov rax, {}",
+            "vmload rax",
+            in(reg) vmcb_pa, options(nostack, preserves_flags),
+        )
+    };
+}
+
 /// Returns the access rights of the given segment for SVM.
 fn get_segment_access_right(table_base: u64, selector: u16) -> u16 {
     let descriptor_value = get_segment_descriptor_value(table_base, selector);
@@ -778,22 +2047,146 @@ fn get_segment_limit(table_base: u64, selector: u16) -> u32 {
     limit as u32
 }
 
-struct SharedGuestData {
+/// The MSR permission map (MSRPM), where each MSR is represented by two bits
+/// to indicate whether RDMSR and WRMSR are intercepted, respectively.
+///
+/// See: 15.11 MSR Intercepts
+#[repr(C, align(4096))]
+struct MsrPermissionMap([u8; 0x2000]);
+const _: () = assert!(core::mem::size_of::<MsrPermissionMap>() == 0x2000);
+
+impl MsrPermissionMap {
+    /// Sets up the map to intercept RDMSR for `msr`.
+    fn intercept_read(&mut self, msr: u32) {
+        self.set(msr, 0);
+    }
+
+    /// Sets up the map to intercept WRMSR for `msr`.
+    fn intercept_write(&mut self, msr: u32) {
+        self.set(msr, 1);
+    }
+
+    fn set(&mut self, msr: u32, bit: u32) {
+        // See: Table 15-15. MSR Ranges of the MSRPM
+        let (base_offset, base_msr) = match msr {
+            0x0000_0000..=0x0000_1fff => (0x0, 0x0000_0000),
+            0xc000_0000..=0xc000_1fff => (0x800, 0xc000_0000),
+            0xc001_0000..=0xc001_1fff => (0x1000, 0xc001_0000),
+            _ => panic!("{msr:#x?} is outside the MSRPM"),
+        };
+        let bit_position = ((msr - base_msr) * 2 + bit) as usize;
+        let byte = &mut self.0[base_offset + bit_position / 8];
+        let _ = byte.set_bit(bit_position % 8, true);
+    }
+}
+
+/// The NPTs and MSR permission map used by a guest. Either shared by all guests
+/// or owned by each of them. See `HvConfig::per_core_guest_tables`.
+struct GuestTables {
     npt: RwLock<NestedPageTables>,
-    activity_states: [AtomicU8; 0xff],
+    /// The NPTs where execute hooks are effective. `None` if there is no hook.
+    hooked_npt: Option<RwLock<NestedPageTables>>,
+    msr_permission_map: ContiguousBox<MsrPermissionMap>,
+}
+
+impl GuestTables {
+    fn new() -> Result<Self, PaError> {
+        const X2APIC_MSR_ICR: u32 = 0x830;
+
+        let mut npt = NestedPageTables::new();
+        npt.build_identity()?;
+        npt.split_apic_page()?;
+
+        let exec_hooks = hooks::exec_hooks();
+        let hooked_npt = if exec_hooks.is_empty() {
+            None
+        } else {
+            // The NX bit in NPTs is reserved unless the host EFER.NXE is set.
+            // See: 15.25.5 Nested Table Walk
+            const EFER_NXE: u64 = 1 << 11;
+            assert!(rdmsr(x86::msr::IA32_EFER) & EFER_NXE != 0);
+
+            let mut hooked_npt = NestedPageTables::new();
+            hooked_npt.build_identity()?;
+            hooked_npt.split_apic_page()?;
+            hooked_npt.apply_exec_hooks_x_view(&exec_hooks)?;
+            npt.apply_exec_hooks_rw_view(&exec_hooks)?;
+            Some(RwLock::new(hooked_npt))
+        };
+
+        // The MSRPM spans two pages and must be physically contiguous.
+        let mut msr_permission_map = ContiguousBox::<MsrPermissionMap>::new(u64::MAX);
+        if cfg!(feature = "uefi") {
+            msr_permission_map.intercept_write(X2APIC_MSR_ICR);
+            // Let the write-protected APIC page follow the APIC base.
+            // See `apic_base`.
+            msr_permission_map.intercept_write(x86::msr::IA32_APIC_BASE);
+        }
+        if deterministic_time::enabled() {
+            for msr in deterministic_time::INTERCEPTED_READS {
+                msr_permission_map.intercept_read(msr);
+            }
+            for msr in deterministic_time::INTERCEPTED_WRITES {
+                msr_permission_map.intercept_write(msr);
+            }
+        }
+        if pmu::isolated() {
+            for msr in pmu::amd_event_selectors() {
+                msr_permission_map.intercept_write(msr);
+            }
+        }
+        if msr_monitor::enabled() {
+            for msr in msr_monitor::INTERCEPTED_WRITES {
+                msr_permission_map.intercept_write(msr);
+            }
+        }
+
+        Ok(Self {
+            npt: RwLock::new(npt),
+            hooked_npt,
+            msr_permission_map,
+        })
+    }
+}
+
+struct SharedGuestData {
+    /// The tables shared by all processors. `None` if each processor has its own.
+    shared_tables: Option<GuestTables>,
+    /// The tables of each processor, created on first use so that processors
+    /// that come online later get theirs too.
+    per_core_tables: PerCpu<Once<GuestTables>>,
+    activity_states: PerCpu<AtomicU8>,
+    started_ap_count: AtomicUsize,
 }
 
 impl SharedGuestData {
     fn new() -> Self {
-        let mut npt = NestedPageTables::new();
-        npt.build_identity();
-        npt.split_apic_page();
+        let config = &SHARED_HOST_DATA.get().unwrap().config;
+
+        // Nested paging may be unavailable when running nested under another
+        // hypervisor. Run without it, and features that depend on it.
+        if !SvmFeatures::get().np() {
+            log::warn!("Nested paging is not supported and disabled");
+            if !hooks::exec_hooks().is_empty() {
+                log::warn!("Execute hooks are not applied without nested paging");
+            }
+        }
 
         Self {
-            npt: RwLock::new(npt),
-            activity_states: core::array::from_fn(|_| {
-                AtomicU8::new(GuestActivityState::Active as u8)
-            }),
+            shared_tables: (!config.per_core_guest_tables).then(|| GuestTables::new().unwrap()),
+            per_core_tables: PerCpu::new([const { Once::new() }; MAX_PROCESSORS]),
+            activity_states: PerCpu::new(
+                [const { AtomicU8::new(GuestActivityState::Active as u8) }; MAX_PROCESSORS],
+            ),
+            started_ap_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the tables used by the guest on the processor.
+    fn tables(&self, processor_id: usize) -> &GuestTables {
+        match &self.shared_tables {
+            Some(tables) => tables,
+            None => self.per_core_tables[processor_id].call_once(|| GuestTables::new().unwrap()),
         }
     }
 }
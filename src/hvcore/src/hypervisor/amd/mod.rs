@@ -5,10 +5,15 @@
 
 use super::host::Architecture;
 
+mod asid;
+mod exit_codes;
 mod guest;
 mod npts;
 mod svm;
 
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use guest::{cr_access_info, io_info, nested_page_fault_info};
+
 /// The AMD processor implements SVM as a virtualization extension.
 pub(crate) struct Amd;
 
@@ -1,36 +1,51 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use bit_field::BitField;
-use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
+use x86::bits64::paging::{BASE_PAGE_SHIFT, LARGE_PAGE_SIZE};
 
 use crate::hypervisor::{
+    hooks::ExecHook,
     paging_structures::{Entry, PagingStructuresRaw, Pt, build_identity_internal},
-    platform_ops,
+    platform_ops::{self, PaError},
     support::zeroed_box,
     x86_instructions::rdmsr,
 };
 
-#[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
+#[derive(Debug)]
 pub(crate) struct NestedPageTables {
     ptr: Box<PagingStructuresRaw>,
+
+    /// PTs allocated to split 2MB pages, and the GPAs of the 2MB pages.
+    split_pts: Vec<(u64, Box<Pt>)>,
+}
+
+impl core::ops::Deref for NestedPageTables {
+    type Target = Box<PagingStructuresRaw>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl core::ops::DerefMut for NestedPageTables {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ptr
+    }
 }
 
 impl NestedPageTables {
     pub(crate) fn new() -> Self {
         Self {
             ptr: zeroed_box::<PagingStructuresRaw>(),
+            split_pts: Vec::new(),
         }
     }
 
-    pub(crate) fn build_identity(&mut self) {
-        build_identity_internal(self.as_mut(), true);
-    }
-
-    pub(crate) fn apic_pt(&mut self) -> &mut Pt {
-        &mut self.pt_apic
+    pub(crate) fn build_identity(&mut self) -> Result<(), PaError> {
+        build_identity_internal(self.as_mut(), true)
     }
 
     /// Splits the 2MB NTP entry for the APIC base page into 4KB entries.
-    pub(crate) fn split_apic_page(&mut self) {
+    pub(crate) fn split_apic_page(&mut self) -> Result<(), PaError> {
         let apic_base_raw = rdmsr(x86::msr::IA32_APIC_BASE);
         assert!(!apic_base_raw.get_bit(10), "x2APIC is enabled");
         assert!(apic_base_raw.get_bit(11), "APIC is disabled");
@@ -39,29 +54,123 @@ impl NestedPageTables {
         let pdpt_index = apic_base.get_bits(30..=38) as usize; // [38:30]
         let pd_index = apic_base.get_bits(21..=29) as usize; // [29:21]
         let pde = &mut self.ptr.pd[pdpt_index].0.entries[pd_index];
-        Self::split_2mb(pde, &mut self.ptr.pt_apic);
+        Self::split_2mb(pde, &mut self.ptr.pt_apic)
+    }
+
+    /// Makes `hooks` non-executable, so that instruction fetches from them
+    /// cause #VMEXIT(NPF). This is the view where the guest reads and writes
+    /// the original pages.
+    pub(crate) fn apply_exec_hooks_rw_view(&mut self, hooks: &[ExecHook]) -> Result<(), PaError> {
+        for hook in hooks {
+            self.pte_mut(hook.gpa)?.set_no_execute(true);
+        }
+        Ok(())
+    }
+
+    /// Makes all pages but `hooks` non-executable, and maps `hooks` to their
+    /// shadow pages. This is the view where the guest executes the shadow pages.
+    /// Reads and writes to the hooked pages also go to the shadow pages, as NPT
+    /// cannot express execute-only pages.
+    pub(crate) fn apply_exec_hooks_x_view(&mut self, hooks: &[ExecHook]) -> Result<(), PaError> {
+        for hook in hooks {
+            let _ = self.pte_mut(hook.gpa)?;
+        }
+
+        // NX is effective if set in any level of the paging structures. Set it
+        // to all leaf entries so that hooked pages can be executable.
+        for pd in &mut self.ptr.pd {
+            for pde in &mut pd.0.entries {
+                if pde.large() {
+                    pde.set_no_execute(true);
+                }
+            }
+        }
+        let split_pts = self.split_pts.iter_mut().map(|(_, pt)| pt.as_mut());
+        for pt in split_pts.chain(core::iter::once(&mut self.ptr.pt_apic)) {
+            for pte in &mut pt.0.entries {
+                pte.set_no_execute(true);
+            }
+        }
+
+        for hook in hooks {
+            let pte = self.pte_mut(hook.gpa)?;
+            pte.set_pfn(hook.shadow_pa >> BASE_PAGE_SHIFT);
+            pte.set_no_execute(false);
+        }
+        Ok(())
+    }
+
+    /// Returns the 4KB NPT entry for `gpa`, splitting the 2MB page if needed.
+    pub(crate) fn pte_mut(&mut self, gpa: u64) -> Result<&mut Entry, PaError> {
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        let large_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        let pde = &mut self.ptr.pd[pdpt_index].0.entries[pd_index];
+        if pde.large() {
+            let mut pt = zeroed_box::<Pt>();
+            Self::split_2mb(pde, &mut pt)?;
+            self.split_pts.push((large_gpa, pt));
+        }
+
+        // The only split 2MB page not in `split_pts` is the one for APIC.
+        let pt = match self.split_pts.iter_mut().find(|(pa, _)| *pa == large_gpa) {
+            Some((_, pt)) => pt.as_mut(),
+            None => &mut self.ptr.pt_apic,
+        };
+        Ok(&mut pt.0.entries[pt_index])
     }
 
     /// Update the `pde` to point to `pt` to split the page from 2MB to 4KBs.
-    fn split_2mb(pde: &mut Entry, pt: &mut Pt) {
+    fn split_2mb(pde: &mut Entry, pt: &mut Pt) -> Result<(), PaError> {
         assert!(pde.present());
         assert!(pde.large());
 
         let writable = pde.writable();
         let user = pde.user();
-        let mut pfn = pde.pfn();
-        for pte in &mut pt.0.entries {
+        let no_execute = pde.no_execute();
+        for (pfn, pte) in (pde.pfn()..).zip(pt.0.entries.iter_mut()) {
             assert!(!pte.present());
             pte.set_present(true);
             pte.set_writable(writable);
             pte.set_user(user);
+            pte.set_no_execute(no_execute);
             pte.set_large(false);
             pte.set_pfn(pfn);
-            pfn += BASE_PAGE_SIZE as u64;
         }
 
-        let pt_pa = platform_ops::get().pa(pt as *mut _ as _);
+        let pt_pa = platform_ops::get().pa(pt as *mut _ as _)?;
         pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
         pde.set_large(false);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::addr_of;
+
+    use super::*;
+    use crate::hypervisor::testing;
+
+    #[test]
+    fn split_2mb_maps_each_4kb_page() {
+        testing::init();
+
+        let mut pde = Entry(0);
+        pde.set_present(true);
+        pde.set_writable(true);
+        pde.set_large(true);
+        pde.set_pfn(0x4020_0000 >> BASE_PAGE_SHIFT);
+        let mut pt = zeroed_box::<Pt>();
+        NestedPageTables::split_2mb(&mut pde, &mut pt).unwrap();
+
+        for (i, pte) in pt.0.entries.iter().enumerate() {
+            assert!(pte.present() && pte.writable() && !pte.large());
+            assert_eq!(pte.pfn(), (0x4020_0000 >> BASE_PAGE_SHIFT) + i as u64);
+        }
+        assert!(!pde.large());
+        assert_eq!(pde.pfn(), addr_of!(*pt) as u64 >> BASE_PAGE_SHIFT);
     }
 }
@@ -1,6 +1,10 @@
 //! This module implements enablement of AMD SVM.
 
+use bit_field::BitField;
+
 use crate::hypervisor::{
+    capabilities::UnsupportedFeature,
+    hooks,
     host::Extension,
     x86_instructions::{rdmsr, wrmsr},
 };
@@ -9,6 +13,25 @@ use crate::hypervisor::{
 pub(crate) struct Svm;
 
 impl Extension for Svm {
+    fn check_support() -> Result<(), UnsupportedFeature> {
+        const SVM_MSR_VM_CR: u32 = 0xc001_0114;
+
+        // See: 15.4 Enabling SVM
+        if !x86::cpuid::cpuid!(0x8000_0001).ecx.get_bit(2) {
+            return Err(UnsupportedFeature::Svm);
+        }
+        if rdmsr(SVM_MSR_VM_CR).get_bit(4) {
+            return Err(UnsupportedFeature::SvmDisabledByFirmware);
+        }
+
+        // The UEFI version emulates the Startup IPI by intercepting writes to
+        // the APIC page with NPT, and execute hooks are implemented with NPT.
+        if (cfg!(feature = "uefi") || !hooks::exec_hooks().is_empty()) && !SvmFeatures::get().np() {
+            return Err(UnsupportedFeature::NestedPaging);
+        }
+        Ok(())
+    }
+
     fn enable(&mut self) {
         const EFER_SVME: u64 = 1 << 12;
 
@@ -16,4 +39,43 @@ impl Extension for Svm {
         // See: 15.4 Enabling SVM
         wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) | EFER_SVME);
     }
+
+    fn disable(&mut self) {
+        const EFER_SVME: u64 = 1 << 12;
+        const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+
+        // GIF is cleared on #VMEXIT. Set it before disabling SVM, as STGI is
+        // unavailable after that and interrupts would remain blocked.
+        // See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+        unsafe { core::arch::asm!("stgi") };
+        wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) & !EFER_SVME);
+        wrmsr(SVM_MSR_VM_HSAVE_PA, 0);
+    }
+}
+
+bitfield::bitfield! {
+    /// The SVM features supported by the processor.
+    ///
+    /// See: E.4.10 Function 8000_000Ah—SVM Revision and Feature Identification
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct SvmFeatures(u32);
+    impl Debug;
+    pub np, _: 0;
+    pub lbr_virt, _: 1;
+    pub nrips, _: 3;
+    pub tsc_rate_msr, _: 4;
+    pub vmcb_clean, _: 5;
+    pub flush_by_asid, _: 6;
+    pub decode_assists, _: 7;
+    pub pause_filter, _: 10;
+    pub pause_filter_threshold, _: 12;
+    pub avic, _: 13;
+    pub spec_ctrl, _: 20;
+}
+
+impl SvmFeatures {
+    /// Returns the SVM features supported by the current processor.
+    pub(crate) fn get() -> Self {
+        Self(x86::cpuid::cpuid!(0x8000_000a).edx)
+    }
 }
@@ -0,0 +1,214 @@
+//! This module implements the vendor-neutral part of the emulation of INIT
+//! and SIPI, with which the BSP starts the APs.
+
+use super::registers::Registers;
+
+/// The selector, base, limit and attributes of a segment register.
+///
+/// The attributes are the bits 7:0 of the access rights, which are encoded in
+/// the same way in the VMCS and VMCB: the type in bits 3:0, S in bit 4, DPL in
+/// bits 6:5, and P in bit 7.
+/// See: 25.4.1 Guest Register State
+/// See: Table B-2. VMCB Layout, State Save Area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SegmentState {
+    pub(crate) selector: u16,
+    pub(crate) base: u64,
+    pub(crate) limit: u32,
+    pub(crate) attributes: u16,
+}
+
+/// The state of the processor after INIT, except registers that depend on the
+/// processor. See [`init_cr0`] and [`init_rdx`]. The x87, SSE and MSR states
+/// are not reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InitState {
+    pub(crate) rflags: u64,
+    pub(crate) rip: u64,
+    pub(crate) rsp: u64,
+    pub(crate) cr2: u64,
+    pub(crate) cr3: u64,
+    pub(crate) cr4: u64,
+    pub(crate) efer: u64,
+    pub(crate) cs: SegmentState,
+    /// SS, DS, ES, FS and GS.
+    pub(crate) data: SegmentState,
+    pub(crate) gdtr_base: u64,
+    pub(crate) gdtr_limit: u32,
+    pub(crate) idtr_base: u64,
+    pub(crate) idtr_limit: u32,
+    pub(crate) ldtr: SegmentState,
+    pub(crate) tr: SegmentState,
+    pub(crate) dr6: u64,
+    pub(crate) dr7: u64,
+}
+
+/// The state of the processor after INIT.
+/// See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
+///      Reset, or INIT
+/// See: Table 14-1. Initial Processor State
+pub(crate) const INIT_STATE: InitState = InitState {
+    rflags: 0x2,
+    rip: 0xfff0,
+    rsp: 0,
+    cr2: 0,
+    cr3: 0,
+    cr4: 0,
+    efer: 0,
+    // Present, read/execute, accessed code segment.
+    cs: SegmentState {
+        selector: 0xf000,
+        base: 0xffff_0000,
+        limit: 0xffff,
+        attributes: 0x9b,
+    },
+    // Present, read/write, accessed data segment.
+    data: SegmentState {
+        selector: 0,
+        base: 0,
+        limit: 0xffff,
+        attributes: 0x93,
+    },
+    gdtr_base: 0,
+    gdtr_limit: 0xffff,
+    idtr_base: 0,
+    idtr_limit: 0xffff,
+    // Present LDT.
+    ldtr: SegmentState {
+        selector: 0,
+        base: 0,
+        limit: 0xffff,
+        attributes: 0x82,
+    },
+    // Present, busy 32-bit TSS.
+    tr: SegmentState {
+        selector: 0,
+        base: 0,
+        limit: 0xffff,
+        attributes: 0x8b,
+    },
+    dr6: 0xffff_0ff0,
+    dr7: 0x400,
+};
+
+/// The RIP after SIPI.
+pub(crate) const SIPI_RIP: u64 = 0;
+
+/// Returns CR0 after INIT from CR0 before it.
+pub(crate) fn init_cr0(previous: u64) -> u64 {
+    const CR0_ET: u64 = 1 << 4;
+    const CR0_NW: u64 = 1 << 29;
+    const CR0_CD: u64 = 1 << 30;
+
+    // "CD, NW unchanged, bit 4 set to 1, all other bits cleared"
+    // See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
+    //      Reset, or INIT
+    CR0_ET | (previous & (CR0_NW | CR0_CD))
+}
+
+/// Returns RDX after INIT from the processor signature, CPUID.01H:EAX.
+pub(crate) fn init_rdx(signature: u32) -> u64 {
+    // "EDX: 000n06xxH (n = Extended Model Value ...)" on Intel, and "EDX:
+    //  Processor family, model and stepping" on AMD, both of which are the
+    //  processor signature.
+    // See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
+    //      Reset, or INIT
+    // See: 14.1.3 Processor Initialization State
+    u64::from(signature)
+}
+
+/// Resets the general purpose registers, RFLAGS, RSP and RIP in `registers`
+/// to the values after INIT.
+pub(crate) fn reset_registers(registers: &mut Registers, signature: u32) {
+    *registers = Registers {
+        rdx: init_rdx(signature),
+        rflags: INIT_STATE.rflags,
+        rsp: INIT_STATE.rsp,
+        rip: INIT_STATE.rip,
+        xmm0: registers.xmm0,
+        xmm1: registers.xmm1,
+        xmm2: registers.xmm2,
+        xmm3: registers.xmm3,
+        xmm4: registers.xmm4,
+        xmm5: registers.xmm5,
+        ..Registers::default()
+    };
+}
+
+/// Returns CS after SIPI with `vector`.
+pub(crate) fn sipi_cs(vector: u8) -> SegmentState {
+    // "... the SIPI message contains a vector to the BIOS AP initialization
+    //  code (at 000VV000H, where VV is the vector contained in the SIPI
+    //  message)."
+    // See: 8.4.3 MP Initialization Protocol Algorithm for MP Systems
+    SegmentState {
+        selector: u16::from(vector) << 8,
+        base: u64::from(vector) << 12,
+        ..INIT_STATE.cs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_state() {
+        // The first instruction is fetched from FFFFFFF0H.
+        assert_eq!(INIT_STATE.cs.base + INIT_STATE.rip, 0xffff_fff0);
+        assert_eq!(INIT_STATE.cs.selector, 0xf000);
+        assert_eq!(INIT_STATE.rflags, 0x2);
+        assert_eq!((INIT_STATE.dr6, INIT_STATE.dr7), (0xffff_0ff0, 0x400));
+        assert_eq!(
+            (INIT_STATE.gdtr_limit, INIT_STATE.idtr_limit),
+            (0xffff, 0xffff)
+        );
+        for segment in [
+            INIT_STATE.cs,
+            INIT_STATE.data,
+            INIT_STATE.ldtr,
+            INIT_STATE.tr,
+        ] {
+            assert_eq!(segment.limit, 0xffff);
+            // Present.
+            assert_eq!(segment.attributes & 0x80, 0x80);
+        }
+        assert_eq!(INIT_STATE.cs.attributes, 0x9b);
+        assert_eq!(INIT_STATE.data.attributes, 0x93);
+        assert_eq!(INIT_STATE.ldtr.attributes, 0x82);
+        assert_eq!(INIT_STATE.tr.attributes, 0x8b);
+    }
+
+    #[test]
+    fn cr0_and_rdx() {
+        assert_eq!(init_cr0(0x8005_0033), 0x10);
+        assert_eq!(init_cr0(0xe005_0033), 0x6000_0010);
+        assert_eq!(init_rdx(0x000a_0f11), 0xa_0f11);
+    }
+
+    #[test]
+    fn registers() {
+        let mut registers = Registers {
+            rax: 1,
+            r15: 2,
+            rsp: 0x1000,
+            rip: 0x2000,
+            ..Registers::default()
+        };
+        reset_registers(&mut registers, 0x906ea);
+        assert_eq!(
+            (registers.rax, registers.r15, registers.rdx),
+            (0, 0, 0x906ea)
+        );
+        assert_eq!((registers.rsp, registers.rip), (0, 0xfff0));
+        assert_eq!(registers.rflags, 0x2);
+    }
+
+    #[test]
+    fn sipi() {
+        let cs = sipi_cs(0x9a);
+        assert_eq!((cs.selector, cs.base), (0x9a00, 0x9_a000));
+        assert_eq!((cs.limit, cs.attributes), (0xffff, 0x9b));
+        assert_eq!(cs.base + SIPI_RIP, 0x9_a000);
+    }
+}
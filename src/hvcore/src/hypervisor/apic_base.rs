@@ -0,0 +1,134 @@
+//! This module implements the handling of writes to IA32_APIC_BASE, with which
+//! the guest relocates the xAPIC MMIO window, or switches the APIC between the
+//! disabled, xAPIC and x2APIC modes.
+
+use bit_field::BitField;
+use x86::msr::IA32_APIC_BASE;
+
+use super::x86_instructions::rdmsr;
+
+/// The mode of the APIC, selected with the EN and EXTD bits.
+///
+/// See: Table 12-5. x2APIC Operating Mode Configurations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ApicMode {
+    Disabled,
+    XApic,
+    X2Apic,
+}
+
+/// A value of IA32_APIC_BASE.
+///
+/// See: Figure 12-26. IA32_APIC_BASE MSR Supporting x2APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ApicBase(pub(crate) u64);
+
+impl ApicBase {
+    /// Reads IA32_APIC_BASE of the current processor.
+    pub(crate) fn current() -> Self {
+        Self(rdmsr(IA32_APIC_BASE))
+    }
+
+    /// Returns the physical address of the xAPIC MMIO window.
+    pub(crate) fn address(self) -> u64 {
+        self.0 & !0xfff
+    }
+
+    /// Returns the mode, or `None` for the invalid combination of the bits.
+    pub(crate) fn mode(self) -> Option<ApicMode> {
+        match (self.0.get_bit(11), self.0.get_bit(10)) {
+            (false, false) => Some(ApicMode::Disabled),
+            (true, false) => Some(ApicMode::XApic),
+            (true, true) => Some(ApicMode::X2Apic),
+            (false, true) => None,
+        }
+    }
+}
+
+/// Returns `true` if `value` is written to `msr` by the guest, and writing it
+/// to IA32_APIC_BASE causes #GP(0). The host completes the write with WRMSR, so
+/// #GP(0) must be injected instead of it then.
+pub(crate) fn is_invalid_write(msr: u32, value: u64) -> bool {
+    if msr != IA32_APIC_BASE {
+        return false;
+    }
+    let max_phys_addr = x86::cpuid::cpuid!(0x8000_0008).eax.get_bits(0..=7);
+    !is_valid_transition(ApicBase::current(), ApicBase(value), max_phys_addr)
+}
+
+/// Logs the change of IA32_APIC_BASE from `old` to `new` on the processor
+/// `id`, if any.
+pub(crate) fn log_change(id: usize, old: ApicBase, new: ApicBase) {
+    if old.mode() != new.mode() {
+        log::info!(
+            "APIC of processor #{id} switched from {:?} to {:?}",
+            old.mode(),
+            new.mode()
+        );
+    }
+    if old.address() != new.address() {
+        log::info!(
+            "APIC of processor #{id} relocated from {:#x} to {:#x}",
+            old.address(),
+            new.address()
+        );
+    }
+}
+
+/// Returns `true` if IA32_APIC_BASE can be changed from `old` to `new`
+/// on a processor with `max_phys_addr` bits of physical addresses.
+fn is_valid_transition(old: ApicBase, new: ApicBase, max_phys_addr: u32) -> bool {
+    // Bits 7:0, 9 and those above MAXPHYADDR are reserved.
+    let reserved = 0xff | (1 << 9) | (u64::MAX << max_phys_addr.min(63));
+    if new.0 & reserved != 0 {
+        return false;
+    }
+
+    // "A state transition from x2APIC mode to xAPIC mode ... and from disabled
+    //  mode to x2APIC mode ... are invalid and result in #GP."
+    // See: 12.12.5 x2APIC State Transitions
+    let (Some(old), Some(new)) = (old.mode(), new.mode()) else {
+        return false;
+    };
+    !matches!(
+        (old, new),
+        (ApicMode::X2Apic, ApicMode::XApic) | (ApicMode::Disabled, ApicMode::X2Apic)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISABLED: ApicBase = ApicBase(0xfee0_0100);
+    const XAPIC: ApicBase = ApicBase(0xfee0_0900);
+    const X2APIC: ApicBase = ApicBase(0xfee0_0d00);
+
+    #[test]
+    fn mode() {
+        assert_eq!(DISABLED.mode(), Some(ApicMode::Disabled));
+        assert_eq!(XAPIC.mode(), Some(ApicMode::XApic));
+        assert_eq!(X2APIC.mode(), Some(ApicMode::X2Apic));
+        assert_eq!(ApicBase(0xfee0_0500).mode(), None);
+        assert_eq!(X2APIC.address(), 0xfee0_0000);
+    }
+
+    #[test]
+    fn transitions() {
+        assert!(is_valid_transition(XAPIC, X2APIC, 39));
+        assert!(is_valid_transition(XAPIC, ApicBase(0xfed0_0900), 39));
+        assert!(is_valid_transition(X2APIC, DISABLED, 39));
+        assert!(is_valid_transition(DISABLED, XAPIC, 39));
+        assert!(!is_valid_transition(X2APIC, XAPIC, 39));
+        assert!(!is_valid_transition(DISABLED, X2APIC, 39));
+        assert!(!is_valid_transition(XAPIC, ApicBase(0xfee0_0500), 39));
+    }
+
+    #[test]
+    fn reserved_bits() {
+        assert!(!is_valid_transition(XAPIC, ApicBase(0xfee0_0901), 39));
+        assert!(!is_valid_transition(XAPIC, ApicBase(0xfee0_0b00), 39));
+        assert!(!is_valid_transition(XAPIC, ApicBase(0x80_fee0_0900), 39));
+        assert!(is_valid_transition(XAPIC, ApicBase(0x80_fee0_0900), 40));
+    }
+}
@@ -1,34 +1,122 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
-use alloc::collections::BTreeMap;
-use spin::RwLock;
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::{Once, RwLock};
 
-use crate::hypervisor::platform_ops;
+use crate::hypervisor::{
+    acpi::LocalApic,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+};
 
-type ApicId = u8;
+pub(crate) type ApicId = u32;
 type ProcessorId = usize;
 pub(crate) static APIC_ID_MAP: RwLock<BTreeMap<ApicId, ProcessorId>> = RwLock::new(BTreeMap::new());
 pub(crate) static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+/// The APIC IDs indexed by the processor IDs, for the lookup without a lock.
+static APIC_IDS: PerCpu<AtomicU32> =
+    PerCpu::new([const { AtomicU32::new(u32::MAX) }; MAX_PROCESSORS]);
+
+/// The processors listed in the MADT, if the platform gave it.
+static TOPOLOGY: Once<Vec<LocalApic>> = Once::new();
+
 /// Gets an APIC ID.
+///
+/// This is the 32-bit x2APIC ID if the processor reports it, or the 8-bit
+/// initial APIC ID otherwise.
 pub(crate) fn get() -> ApicId {
+    // "EDX Bits 31-00: x2APIC ID the current logical processor." Leaf 1FH is
+    // preferred over 0BH when available. A leaf is not supported if EBX is 0.
+    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
+    // See: (AMD) CPUID Fn0000_000B_EDX Extended Topology Enumeration
+    let max_leaf = x86::cpuid::cpuid!(0x0).eax;
+    for leaf in [0x1f, 0xb] {
+        if max_leaf >= leaf {
+            let regs = x86::cpuid::cpuid!(leaf, 0);
+            if regs.ebx != 0 {
+                return regs.edx;
+            }
+        }
+    }
+
     // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
     // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
-    (x86::cpuid::cpuid!(0x1).ebx >> 24) as _
+    x86::cpuid::cpuid!(0x1).ebx >> 24
 }
 
+/// Sets the processors listed in the MADT as the authoritative topology of the
+/// system, including those not online at virtualization.
+pub(crate) fn set_topology(apics: Vec<LocalApic>) {
+    let _ = TOPOLOGY.call_once(|| apics);
+}
+
+/// Registers all logical processors currently online, and reports those in
+/// the topology but not online, which are virtualized when they start.
 pub(crate) fn init() {
-    assert!(PROCESSOR_COUNT.load(Ordering::Relaxed) == 0);
-    platform_ops::get().run_on_all_processors(|| {
-        let mut map = APIC_ID_MAP.write();
-        assert!(
-            map.insert(get(), PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed))
-                .is_none()
-        );
+    platform_ops::get().run_on_all_processors(&|| {
+        let _ = register_current();
     });
+
+    let Some(topology) = TOPOLOGY.get() else {
+        return;
+    };
+    let map = APIC_ID_MAP.read();
+    for apic in topology
+        .iter()
+        .filter(|apic| !map.contains_key(&apic.apic_id))
+    {
+        log::info!("APIC ID {:#x} is not online: {apic:x?}", apic.apic_id);
+    }
+    for apic_id in map.keys() {
+        if !topology.iter().any(|apic| apic.apic_id == *apic_id) {
+            log::warn!("APIC ID {apic_id:#x} is online but not in the MADT");
+        }
+    }
+}
+
+/// Returns the APIC IDs of the processors online or listed in the topology.
+pub(crate) fn known_apic_ids() -> Vec<ApicId> {
+    let mut apic_ids = APIC_ID_MAP.read().keys().copied().collect::<Vec<_>>();
+    if let Some(topology) = TOPOLOGY.get() {
+        apic_ids.extend(topology.iter().map(|apic| apic.apic_id));
+    }
+    apic_ids
+}
+
+/// Registers the current processor if not yet, and returns its processor ID.
+/// Processors that come online after `init` get the next unused IDs.
+pub(crate) fn register_current() -> ProcessorId {
+    let apic_id = get();
+    let mut map = APIC_ID_MAP.write();
+    *map.entry(apic_id).or_insert_with(|| {
+        let id = PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed);
+        if let Some(slot) = APIC_IDS.get(id) {
+            slot.store(apic_id, Ordering::Release);
+        }
+        id
+    })
+}
+
+/// Returns the processor ID of the current processor without taking a lock,
+/// or `None` if it is not registered or its ID is not below
+/// `MAX_PROCESSORS`. Usable in any context, including NMI handlers.
+pub(crate) fn current_processor_id() -> Option<ProcessorId> {
+    let apic_id = get();
+    let count = PROCESSOR_COUNT.load(Ordering::Relaxed).min(MAX_PROCESSORS);
+    APIC_IDS[..count]
+        .iter()
+        .position(|slot| slot.load(Ordering::Acquire) == apic_id)
 }
 
 pub(crate) fn processor_id_from(apic_id: ApicId) -> Option<ProcessorId> {
     let map = APIC_ID_MAP.read();
     map.get(&apic_id).copied()
 }
+
+pub(crate) fn apic_id_from(processor_id: ProcessorId) -> Option<ApicId> {
+    let map = APIC_ID_MAP.read();
+    map.iter()
+        .find(|(_, id)| **id == processor_id)
+        .map(|(apic_id, _)| *apic_id)
+}
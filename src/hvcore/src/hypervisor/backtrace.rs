@@ -0,0 +1,175 @@
+//! This module implements capturing call stacks of the guest.
+//!
+//! A backtrace starts with the guest RIP, followed by return addresses found by
+//! following the frame pointer (RBP) chain of the guest stack. When the chain
+//! yields no frame, for example, in code compiled without frame pointers, the
+//! stack is scanned from RSP for values pointing to executable pages instead,
+//! as those are likely return addresses.
+//!
+//! Only 64-bit guests with 4-level paging are supported, and only when guest
+//! memory is accessible. See [`super::gva`]. Otherwise, a backtrace contains
+//! only the guest RIP.
+
+use core::fmt;
+
+use super::{
+    gva::{self, GuestAddressSpace},
+    registers::Registers,
+};
+
+/// The largest number of addresses in a [`Backtrace`], including the RIP.
+pub const MAX_FRAMES: usize = 8;
+
+/// The number of stack slots scanned when the frame pointer chain is unusable.
+const MAX_SCANNED_SLOTS: u64 = 64;
+
+/// The largest distance between two frame pointers considered valid.
+const MAX_FRAME_SIZE: u64 = 0x1_0000;
+
+/// The guest RIP and the return addresses on the guest stack, innermost first.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Returns the captured addresses, innermost first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// Appends `address`, and returns `false` if this is full.
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        for address in self.frames() {
+            list.entry(&format_args!("{address:#x}"));
+        }
+        list.finish()
+    }
+}
+
+/// Captures the backtrace of the guest with `registers`. `cr3` is the guest
+/// CR3 if the guest uses 4-level paging in 64-bit mode.
+pub(crate) fn capture(registers: &Registers, cr3: Option<u64>) -> Backtrace {
+    let mut backtrace = Backtrace::default();
+    backtrace.push(registers.rip);
+    if let Some(space) = cr3.and_then(gva::address_space) {
+        walk_stack(&mut backtrace, registers, &space);
+    }
+    backtrace
+}
+
+/// Appends return addresses on the stack of `registers` to `backtrace`.
+fn walk_stack<F: Fn(u64) -> Option<u64>>(
+    backtrace: &mut Backtrace,
+    registers: &Registers,
+    space: &GuestAddressSpace<F>,
+) {
+    let is_code = |address: u64| {
+        space
+            .translate(address)
+            .is_some_and(|translation| translation.executable)
+    };
+
+    // Each frame holds the previous RBP at RBP and the return address above it.
+    // Frames must be in the stack, that is, above RSP, and grow upwards.
+    let mut rbp = registers.rbp;
+    let mut lowest = registers.rsp;
+    let initial_len = backtrace.len;
+    while rbp >= lowest && rbp - lowest <= MAX_FRAME_SIZE {
+        let (Some(next_rbp), Some(return_address)) =
+            (space.read_u64(rbp), space.read_u64(rbp.wrapping_add(8)))
+        else {
+            break;
+        };
+        if !is_code(return_address) || !backtrace.push(return_address) {
+            break;
+        }
+        lowest = rbp + 16;
+        rbp = next_rbp;
+    }
+    if backtrace.len != initial_len {
+        return;
+    }
+
+    let rsp = registers.rsp & !0x7;
+    for slot in (0..MAX_SCANNED_SLOTS).map(|i| rsp.wrapping_add(i * 8)) {
+        if let Some(value) = space.read_u64(slot)
+            && is_code(value)
+            && !backtrace.push(value)
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::gva::tests::FakeMemory;
+
+    const CODE: u64 = 0xffff_f800_0010_0000;
+    const STACK: u64 = 0xffff_f800_0020_0000;
+    const STACK_GPA: u64 = 0x10_0000;
+
+    fn memory() -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        memory.map(CODE, 0x8_0000, true);
+        memory.map(STACK, STACK_GPA, false);
+        memory
+    }
+
+    fn registers(rsp: u64, rbp: u64) -> Registers {
+        Registers {
+            rip: CODE + 0x10,
+            rsp,
+            rbp,
+            ..Registers::default()
+        }
+    }
+
+    #[test]
+    fn frame_pointers() {
+        let mut memory = memory();
+        // Two frames, where the outer one ends the chain with RBP 0.
+        memory.0.insert(STACK_GPA + 0x100, STACK + 0x200);
+        memory.0.insert(STACK_GPA + 0x108, CODE + 0x20);
+        memory.0.insert(STACK_GPA + 0x200, 0);
+        memory.0.insert(STACK_GPA + 0x208, CODE + 0x30);
+        // A stale code address that scanning would pick up.
+        memory.0.insert(STACK_GPA + 0x80, CODE + 0x40);
+
+        let mut backtrace = Backtrace::default();
+        backtrace.push(CODE + 0x10);
+        let registers = registers(STACK + 0x80, STACK + 0x100);
+        walk_stack(&mut backtrace, &registers, &memory.address_space());
+        assert_eq!(backtrace.frames(), &[CODE + 0x10, CODE + 0x20, CODE + 0x30]);
+    }
+
+    #[test]
+    fn stack_scan() {
+        let mut memory = memory();
+        memory.0.insert(STACK_GPA + 0x88, CODE + 0x20);
+        memory.0.insert(STACK_GPA + 0x90, STACK + 0x100);
+        memory.0.insert(STACK_GPA + 0x98, CODE + 0x30);
+
+        // RBP is not a frame pointer.
+        let mut backtrace = Backtrace::default();
+        backtrace.push(CODE + 0x10);
+        let registers = registers(STACK + 0x80, 0x1234);
+        walk_stack(&mut backtrace, &registers, &memory.address_space());
+        assert_eq!(backtrace.frames(), &[CODE + 0x10, CODE + 0x20, CODE + 0x30]);
+    }
+}
@@ -0,0 +1,201 @@
+//! This module implements the microbenchmark of VM-exit round-trip latency.
+//!
+//! When `HvConfig::exit_latency_budget` is set, each processor measures the
+//! cycles CPUID, RDMSR and VMCALL take from the guest, before and after
+//! virtualization, and the medians are logged. Medians after virtualization
+//! above the budget are flagged as regressions. The medians can also be
+//! queried with [`HC_BENCHMARK_RESULTS`], for example, by `check_hv_vendor`
+//! for UEFI.
+//!
+//! RDMSR reads IA32_EFER, which is not intercepted, and thus measures the cost
+//! of the MSR bitmap lookup rather than a VM-exit. VMCALL is measured only
+//! after virtualization.
+//!
+//! [`HC_BENCHMARK_RESULTS`]: super::hypercall::HC_BENCHMARK_RESULTS
+
+use core::{
+    arch::x86_64::{_mm_lfence, _rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86::{cpuid::cpuid, msr};
+
+use super::{
+    SHARED_HOST_DATA, apic_id,
+    hypercall::{HC_BENCHMARK_RESULTS, hypercall_with_token},
+    hypercall_auth::AUTH,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+    support::InterruptGuard,
+    x86_instructions::rdmsr,
+};
+
+/// The number of round trips measured for each instruction.
+const SAMPLES: usize = 101;
+
+/// The median cycles of the round trips on a processor. 0 if not measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Medians {
+    pub cpuid: u64,
+    pub rdmsr: u64,
+    pub vmcall: u64,
+}
+
+impl Medians {
+    /// Returns the names and medians that exceed `budget` cycles.
+    fn over_budget(self, budget: u64) -> impl Iterator<Item = (&'static str, u64)> {
+        [
+            ("CPUID", self.cpuid),
+            ("RDMSR", self.rdmsr),
+            ("VMCALL", self.vmcall),
+        ]
+        .into_iter()
+        .filter(move |(_, cycles)| *cycles > budget)
+    }
+}
+
+/// Returns the medians measured on the processor `id`, `virtualized` or not.
+pub fn results(id: usize, virtualized: bool) -> Medians {
+    let table = if virtualized { &VIRTUALIZED } else { &NATIVE };
+    table.get(id).map_or(Medians::default(), |medians| Medians {
+        cpuid: medians[0].load(Ordering::Relaxed),
+        rdmsr: medians[1].load(Ordering::Relaxed),
+        vmcall: medians[2].load(Ordering::Relaxed),
+    })
+}
+
+/// Returns the medians measured on the current processor.
+pub(crate) fn current_results(virtualized: bool) -> Medians {
+    apic_id::current_processor_id().map_or(Medians::default(), |id| results(id, virtualized))
+}
+
+/// Measures the round trips on all processors before virtualization, if
+/// enabled.
+pub(crate) fn measure_native() {
+    if budget() == 0 {
+        return;
+    }
+    platform_ops::get().run_on_all_processors(&|| measure_current_processor(false));
+}
+
+/// Measures the round trips on all processors after virtualization, if
+/// enabled, and logs the results.
+pub(crate) fn measure_virtualized() {
+    let budget = budget();
+    if budget == 0 {
+        return;
+    }
+    platform_ops::get().run_on_all_processors(&|| measure_current_processor(true));
+
+    let count = apic_id::PROCESSOR_COUNT
+        .load(Ordering::Relaxed)
+        .min(MAX_PROCESSORS);
+    let mut regressed = false;
+    for id in 0..count {
+        let native = results(id, false);
+        let virtualized = results(id, true);
+        log::info!(
+            "#{id}: CPUID {} -> {}, RDMSR {} -> {}, VMCALL {} cycles",
+            native.cpuid,
+            virtualized.cpuid,
+            native.rdmsr,
+            virtualized.rdmsr,
+            virtualized.vmcall,
+        );
+        for (name, cycles) in virtualized.over_budget(budget) {
+            log::warn!("#{id}: {name} took {cycles} cycles, over the budget of {budget}");
+            regressed = true;
+        }
+    }
+    if !regressed {
+        log::info!("All round trips are within {budget} cycles");
+    }
+}
+
+fn budget() -> u64 {
+    SHARED_HOST_DATA.get().unwrap().config.exit_latency_budget
+}
+
+fn measure_current_processor(virtualized: bool) {
+    let table = if virtualized { &VIRTUALIZED } else { &NATIVE };
+    let Some(medians) = table.current() else {
+        return;
+    };
+
+    let _intr_guard = InterruptGuard::new();
+    medians[0].store(
+        round_trip(|| {
+            let _ = cpuid!(0);
+        }),
+        Ordering::Relaxed,
+    );
+    medians[1].store(
+        round_trip(|| {
+            let _ = rdmsr(msr::IA32_EFER);
+        }),
+        Ordering::Relaxed,
+    );
+    if virtualized {
+        let token = AUTH.token();
+        medians[2].store(
+            round_trip(|| {
+                let _ = hypercall_with_token(HC_BENCHMARK_RESULTS, [0; 3], token);
+            }),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Returns the median cycles `operation` takes.
+fn round_trip(operation: impl Fn()) -> u64 {
+    let mut samples = [0u64; SAMPLES];
+    for sample in &mut samples {
+        // Serialize RDTSC with the operation.
+        // See: RDTSC—Read Time-Stamp Counter
+        let start = unsafe {
+            _mm_lfence();
+            _rdtsc()
+        };
+        operation();
+        let end = unsafe {
+            _mm_lfence();
+            _rdtsc()
+        };
+        *sample = end.wrapping_sub(start);
+    }
+    median(&mut samples)
+}
+
+fn median(samples: &mut [u64]) -> u64 {
+    samples.sort_unstable();
+    samples.get(samples.len() / 2).copied().unwrap_or(0)
+}
+
+/// The medians of CPUID, RDMSR and VMCALL of each processor.
+type MedianTable = PerCpu<[AtomicU64; 3]>;
+
+static NATIVE: MedianTable =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 3] }; MAX_PROCESSORS]);
+static VIRTUALIZED: MedianTable =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 3] }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn medians() {
+        assert_eq!(median(&mut [5, 1, 100, 2, 3]), 3);
+        assert_eq!(median(&mut []), 0);
+
+        let medians = Medians {
+            cpuid: 1500,
+            rdmsr: 90,
+            vmcall: 2500,
+        };
+        let over: Vec<_> = medians.over_budget(2000).collect();
+        assert_eq!(over, [("VMCALL", 2500)]);
+    }
+}
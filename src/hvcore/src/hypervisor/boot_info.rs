@@ -0,0 +1,167 @@
+//! This module implements the storage of the boot-phase information of the
+//! guest, the final UEFI memory map and the locations of the ACPI tables, for
+//! later introspection.
+//!
+//! The UEFI platform records the memory map with [`record_memory_map`] when
+//! the OS loader calls ExitBootServices(), and the ACPI tables with
+//! [`record_acpi_tables`] at load time. They are kept in host memory, so that
+//! post-boot tooling can reason about firmware-reserved ranges, such as those
+//! next to SMRAM, without re-parsing them from guest memory, which the OS may
+//! have reused or modified by then. The host reads them with [`memory_map`]
+//! and [`acpi_tables`], and the guest with [`HC_BOOT_MEMORY_MAP`] and
+//! [`HC_BOOT_ACPI_TABLE`].
+//!
+//! Nothing is recorded on Windows, where the driver loads after boot.
+//!
+//! [`HC_BOOT_MEMORY_MAP`]: super::hypercall::HC_BOOT_MEMORY_MAP
+//! [`HC_BOOT_ACPI_TABLE`]: super::hypercall::HC_BOOT_ACPI_TABLE
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use super::{acpi, apic_id};
+
+/// A region of the UEFI memory map, as `EFI_MEMORY_DESCRIPTOR`.
+///
+/// See: 7.2.3 EFI_BOOT_SERVICES.GetMemoryMap(), UEFI Specification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// The `EFI_MEMORY_TYPE` value, for example, 0 for EfiReservedMemoryType.
+    pub memory_type: u32,
+
+    /// The physical address of the region, 4KB aligned.
+    pub start: u64,
+
+    /// The number of 4KB pages in the region.
+    pub pages: u64,
+
+    /// The `EFI_MEMORY_*` attributes of the region.
+    pub attributes: u64,
+}
+
+impl MemoryRegion {
+    /// Returns `true` if `pa` is in the region.
+    pub fn contains(&self, pa: u64) -> bool {
+        pa >= self.start && pa - self.start < self.pages.saturating_mul(0x1000)
+    }
+}
+
+/// The location of an ACPI table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcpiTable {
+    /// The signature of the table, for example, `*b"FACP"`. The RSDP is
+    /// recorded with `*b"RSDP"`.
+    pub signature: [u8; 4],
+
+    /// The physical address of the table.
+    pub address: u64,
+
+    /// The length of the table in bytes.
+    pub length: u32,
+}
+
+/// The error type for [`HC_BOOT_MEMORY_MAP`] and [`HC_BOOT_ACPI_TABLE`]. The
+/// value is returned in RAX.
+///
+/// [`HC_BOOT_MEMORY_MAP`]: super::hypercall::HC_BOOT_MEMORY_MAP
+/// [`HC_BOOT_ACPI_TABLE`]: super::hypercall::HC_BOOT_ACPI_TABLE
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum BootInfoError {
+    #[error("the information is not recorded")]
+    NotRecorded = 1,
+
+    #[error("the index is out of range")]
+    IndexOutOfRange = 2,
+}
+
+/// Records `regions` as the final memory map. Only the first call takes
+/// effect.
+pub fn record_memory_map(regions: Vec<MemoryRegion>) {
+    let _ = MEMORY_MAP.call_once(|| regions);
+}
+
+/// Records the locations of the RSDP at `rsdp` and the tables listed in the
+/// XSDT, or the RSDT if the XSDT is not available. Only the first call takes
+/// effect.
+///
+/// The processors listed in the MADT are also given to `apic_id` as the
+/// topology of the system, including those not online at virtualization.
+/// Thus, this must be called before `virtualize_system` to take effect.
+///
+/// # Safety
+///
+/// `rsdp` and the tables must be readable at their physical addresses, as they
+/// are with the identity mapping of UEFI.
+pub unsafe fn record_acpi_tables(rsdp: u64) {
+    let read = |address: u64, buffer: &mut [u8]| {
+        // Safety: the caller guarantees the tables are readable.
+        unsafe {
+            core::ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len());
+        }
+    };
+    let tables = ACPI_TABLES.call_once(|| acpi::parse_tables(rsdp, read));
+    if let Some(madt) = tables.iter().find(|table| &table.signature == b"APIC") {
+        apic_id::set_topology(acpi::parse_madt(madt.address, read));
+    }
+}
+
+/// Returns the memory map recorded at ExitBootServices(), or an empty slice if
+/// not recorded.
+pub fn memory_map() -> &'static [MemoryRegion] {
+    MEMORY_MAP.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the recorded locations of the ACPI tables, or an empty slice if not
+/// recorded.
+pub fn acpi_tables() -> &'static [AcpiTable] {
+    ACPI_TABLES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the region of the recorded memory map that contains `pa`.
+pub fn find_region(pa: u64) -> Option<&'static MemoryRegion> {
+    memory_map().iter().find(|region| region.contains(pa))
+}
+
+/// Returns the `index`-th region of the memory map for
+/// [`HC_BOOT_MEMORY_MAP`](super::hypercall::HC_BOOT_MEMORY_MAP).
+pub(crate) fn memory_map_entry(index: u64) -> Result<MemoryRegion, BootInfoError> {
+    entry(MEMORY_MAP.get(), index)
+}
+
+/// Returns the `index`-th ACPI table for
+/// [`HC_BOOT_ACPI_TABLE`](super::hypercall::HC_BOOT_ACPI_TABLE).
+pub(crate) fn acpi_table_entry(index: u64) -> Result<AcpiTable, BootInfoError> {
+    entry(ACPI_TABLES.get(), index)
+}
+
+fn entry<T: Copy>(entries: Option<&Vec<T>>, index: u64) -> Result<T, BootInfoError> {
+    let entries = entries.ok_or(BootInfoError::NotRecorded)?;
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| entries.get(index))
+        .copied()
+        .ok_or(BootInfoError::IndexOutOfRange)
+}
+
+static MEMORY_MAP: Once<Vec<MemoryRegion>> = Once::new();
+static ACPI_TABLES: Once<Vec<AcpiTable>> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region() {
+        let region = MemoryRegion {
+            memory_type: 0,
+            start: 0x1000,
+            pages: 2,
+            attributes: 0,
+        };
+        assert!(!region.contains(0xfff));
+        assert!(region.contains(0x1000));
+        assert!(region.contains(0x2fff));
+        assert!(!region.contains(0x3000));
+    }
+}
@@ -0,0 +1,149 @@
+//! This module implements the builder API to embed the hypervisor into a
+//! driver or a UEFI application other than `win_hv` and `uefi_hv`.
+//!
+//! The embedder initializes the global allocator with [`crate::allocator`],
+//! then builds and starts the hypervisor with [`Hypervisor::builder`]:
+//!
+//! ```ignore
+//! hv::allocator::init(heap);
+//! hv::Hypervisor::builder()
+//!     .with_platform(Box::new(MyOps))
+//!     .with_config(hv::HvConfig::parse("hlt=intercept")?)
+//!     .with_processor_count(processor_count)
+//!     .virtualize()?;
+//! ```
+//!
+//! VM-exit handlers and execute hooks are registered with
+//! [`super::exit_handlers::register`] and [`super::hooks::add_exec_hook`]
+//! before [`HypervisorBuilder::virtualize`].
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::{
+    SHARED_HOST_DATA, SharedHostData, capabilities::UnsupportedFeature, config::HvConfig,
+    gdt_tss::GdtTss, interrupt_handlers::InterruptDescriptorTable,
+    paging_structures::PagingStructures, platform_ops::PlatformOps,
+};
+
+/// The entry point of the builder API. See the module documentation.
+#[derive(Debug)]
+pub struct Hypervisor;
+
+impl Hypervisor {
+    /// Returns a builder with the default configuration.
+    pub fn builder() -> HypervisorBuilder {
+        HypervisorBuilder::default()
+    }
+
+    /// Returns `true` if the system is virtualized with the builder or
+    /// [`super::virtualize_system`].
+    pub fn is_virtualized() -> bool {
+        SHARED_HOST_DATA.is_completed()
+    }
+}
+
+/// The builder of the hypervisor, created with [`Hypervisor::builder`].
+#[derive(Default)]
+pub struct HypervisorBuilder {
+    platform: Option<Box<dyn PlatformOps>>,
+    processor_count: Option<usize>,
+    shared_host: SharedHostData,
+}
+
+/// The error type for [`HypervisorBuilder::virtualize`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypervisorError {
+    #[error("the platform specific API is not given")]
+    NoPlatform,
+
+    #[error("neither the host GDTs nor the number of processors are given")]
+    NoProcessors,
+
+    #[error("the system is already virtualized")]
+    AlreadyVirtualized,
+
+    #[error(transparent)]
+    Unsupported(#[from] UnsupportedFeature),
+}
+
+impl HypervisorBuilder {
+    /// Uses `ops` as the platform specific API. Required unless
+    /// [`super::platform_ops::init`] has been called.
+    #[must_use]
+    pub fn with_platform(mut self, ops: Box<dyn PlatformOps>) -> Self {
+        self.platform = Some(ops);
+        self
+    }
+
+    /// Uses `config` for the optional features and policies.
+    #[must_use]
+    pub fn with_config(mut self, config: HvConfig) -> Self {
+        self.shared_host.config = config;
+        self
+    }
+
+    /// Creates a GDT and TSS with [`GdtTss::new_for_host`] for each of `count`
+    /// logical processors. `count` should include processors that may come
+    /// online later, as processors without them are not virtualized. Ignored
+    /// if [`Self::with_host_gdts`] is given.
+    #[must_use]
+    pub fn with_processor_count(mut self, count: usize) -> Self {
+        self.processor_count = Some(count);
+        self
+    }
+
+    /// Uses `gdts` as the GDT and TSS of the host, indexed by the processor
+    /// ID. See [`SharedHostData::gdts`].
+    #[must_use]
+    pub fn with_host_gdts(mut self, gdts: Vec<GdtTss>) -> Self {
+        self.shared_host.gdts = gdts;
+        self
+    }
+
+    /// Uses `idt` as the IDT of the host instead of the current IDT.
+    #[must_use]
+    pub fn with_host_idt(mut self, idt: InterruptDescriptorTable) -> Self {
+        self.shared_host.idt = Some(idt);
+        self
+    }
+
+    /// Uses `pt` as the paging structures of the host instead of the current
+    /// ones.
+    #[must_use]
+    pub fn with_host_paging_structures(mut self, pt: PagingStructures) -> Self {
+        self.shared_host.pt = Some(pt);
+        self
+    }
+
+    /// Virtualizes all logical processors with [`super::virtualize_system`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HypervisorError`] if a required part is not given, or the
+    /// processor is not supported. The system is left unvirtualized in that
+    /// case.
+    pub fn virtualize(self) -> Result<(), HypervisorError> {
+        let Self {
+            platform,
+            processor_count,
+            mut shared_host,
+        } = self;
+
+        if SHARED_HOST_DATA.is_completed() {
+            return Err(HypervisorError::AlreadyVirtualized);
+        }
+        if shared_host.gdts.is_empty() {
+            let count = processor_count.ok_or(HypervisorError::NoProcessors)?;
+            shared_host.gdts = (0..count).map(|_| GdtTss::new_for_host()).collect();
+        }
+        match platform {
+            Some(ops) => super::platform_ops::init(ops),
+            None if !super::platform_ops::is_initialized() => {
+                return Err(HypervisorError::NoPlatform);
+            }
+            None => {}
+        }
+        super::virtualize_system(shared_host)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! This module implements the check of processor capabilities the hypervisor
+//! requires.
+//!
+//! The check runs before virtualizing the system, so that the loader can refuse
+//! to load the hypervisor with a clear reason and keep the system running,
+//! instead of the hypervisor panicking in the middle of virtualization.
+
+#[cfg(feature = "amd")]
+use super::amd::Amd;
+use super::host::{Architecture, Extension};
+#[cfg(feature = "intel")]
+use super::intel::Intel;
+
+/// A feature the hypervisor requires but the processor does not support.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    #[error("the processor does not support VMX")]
+    Vmx,
+
+    #[error("VMX is disabled by the firmware")]
+    VmxDisabledByFirmware,
+
+    #[error("the VMX control `{name}` is not supported")]
+    VmxControl { name: &'static str },
+
+    #[error("EPT is not supported")]
+    Ept,
+
+    #[error("unrestricted guest is not supported")]
+    UnrestrictedGuest,
+
+    #[error("the processor does not support SVM")]
+    Svm,
+
+    #[error("SVM is disabled by the firmware")]
+    SvmDisabledByFirmware,
+
+    #[error("nested paging is not supported")]
+    NestedPaging,
+
+    #[error("the backend for the processor vendor is not compiled in")]
+    BackendNotCompiled,
+}
+
+/// Checks that the current processor supports all features the hypervisor
+/// requires with the current build and registered hooks.
+pub fn check_support() -> Result<(), UnsupportedFeature> {
+    let intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    #[cfg(feature = "intel")]
+    if intel {
+        return <Intel as Architecture>::VirtualizationExtension::check_support();
+    }
+    #[cfg(feature = "amd")]
+    if !intel {
+        return <Amd as Architecture>::VirtualizationExtension::check_support();
+    }
+    Err(UnsupportedFeature::BackendNotCompiled)
+}
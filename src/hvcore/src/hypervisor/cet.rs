@@ -0,0 +1,52 @@
+//! This module implements the handling of the supervisor state of control-flow
+//! enforcement technology (CET), that is, shadow stacks and indirect branch
+//! tracking in kernel mode.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bit_field::BitField;
+
+use super::{
+    percpu::{MAX_PROCESSORS, PerCpu},
+    x86_instructions::rdsspq,
+};
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+pub(crate) const IA32_S_CET: u32 = 0x6a2;
+pub(crate) const IA32_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6a8;
+
+/// Returns `true` if the processor supports shadow stacks or indirect branch
+/// tracking.
+///
+/// The guest starts with the CET state of the processor at virtualization, and
+/// the host runs with supervisor CET disabled, as the host code is not built
+/// for indirect branch tracking. The "load CET state" VM-entry and VM-exit
+/// controls switch the state between the two, so this is Intel only. The state
+/// of the guest is not restored on devirtualization.
+pub(crate) fn is_supported() -> bool {
+    // See: Table 1-23. Structured Extended Feature Leaf, Function 0, EBX, ECX,
+    //      and EDX Exx Bit Definitions
+    let leaf = x86::cpuid::cpuid!(0x7, 0x0);
+    leaf.ecx.get_bit(7) || leaf.edx.get_bit(20)
+}
+
+/// Captures the current SSP as that of the guest on the processor `id`. Must
+/// be called right after the guest registers are captured, at the same call
+/// depth.
+#[inline(always)]
+pub(crate) fn capture_guest_ssp(id: usize) {
+    if let Some(ssp) = GUEST_SSP.get(id) {
+        ssp.store(rdsspq(), Ordering::Relaxed);
+    }
+}
+
+/// Returns the SSP captured with [`capture_guest_ssp`] on the processor `id`,
+/// or 0 if shadow stacks were not enabled.
+pub(crate) fn guest_ssp(id: usize) -> u64 {
+    GUEST_SSP
+        .get(id)
+        .map_or(0, |ssp| ssp.load(Ordering::Relaxed))
+}
+
+static GUEST_SSP: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_PROCESSORS]);
@@ -0,0 +1,679 @@
+//! This module implements the configuration of the hypervisor.
+//!
+//! The UEFI app and the Windows driver load the configuration at boot time
+//! from text in the same format, parsed by [`HvConfig::parse`], or from
+//! key-value pairs applied with [`HvConfig::set`]. The text is entries
+//! separated by new lines or `;`, each of which is `key=value`, where `key` is
+//! the name of a field of [`HvConfig`]. Empty entries and entries starting
+//! with `#` are ignored. Values are:
+//! - `true`, `false`, `1` or `0` for `bool` fields.
+//! - Decimal numbers, or hexadecimal ones with the `0x` prefix, for integers.
+//! - Variant names in lowercase for policies, for example, `hlt=intercept`.
+//! - `none` to disable `Option` fields, or the value: `numerator/denominator`
+//!   for `tsc_ratio`, `gap,window` for `pause_loop_exiting`, and
+//!   `count,window` for `smi_storm`.
+//! - `off`, `error`, `warn`, `info`, `debug` or `trace` for `log_level`.
+
+use core::str::FromStr;
+
+use super::tsc_scaling::TscRatio;
+
+/// Optional features and policies of the hypervisor. The default value keeps
+/// the behavior of the hypervisor minimal.
+#[derive(Debug, Clone, Default)]
+pub struct HvConfig {
+    /// Saves the extended state of the guest (x87, SSE, AVX etc) with XSAVES on
+    /// VM-exit and restores it with XRSTORS before VM-entry, so that VM-exit
+    /// handlers may use vector instructions. Ignored if the processor does not
+    /// support XSAVES.
+    pub save_extended_state: bool,
+
+    /// Gives each processor its own MSR bitmap and EPT (or MSR permission map
+    /// and NPT on AMD) instead of ones shared by all processors.
+    ///
+    /// This costs memory per processor, but lets processors change their own
+    /// tables without affecting, or having to invalidate TLBs of, the others.
+    pub per_core_guest_tables: bool,
+
+    /// The number of 4KB pages reserved for copy-on-write snapshots of guest
+    /// memory, that is, the number of pages the guest may write to before the
+    /// snapshot is restored. `0` disables snapshots. Intel only.
+    ///
+    /// The pages are taken from the hypervisor heap, and reserved for each
+    /// set of guest tables. See [`crate::hypervisor::snapshot`].
+    pub snapshot_pool_pages: usize,
+
+    /// The number of EPT views created in addition to the default view, up to
+    /// `MAX_EPT_VIEWS - 1`. `0` disables views. Intel only.
+    ///
+    /// Like snapshots, views are created for each set of guest tables. While
+    /// any view is bound to a process, every MOV to CR3 causes VM-exit to
+    /// switch views. See [`crate::hypervisor::ept_views`].
+    pub ept_views: usize,
+
+    /// The number of page tables reserved for each set of EPTs, including
+    /// views, to split 2MB pages while handling VM-exits, for example, for
+    /// `HC_EPT_VIEW_PROTECT`. Page tables beyond this are allocated from the
+    /// hypervisor heap at that time. Intel only. See
+    /// [`crate::hypervisor::pool`].
+    pub split_pt_pool: usize,
+
+    /// Stops the processor on #DB and #BP in the host and on panic, and waits
+    /// for GDB to attach over the serial port. See
+    /// [`crate::hypervisor::gdb_stub`].
+    pub gdb_stub: bool,
+
+    /// Runs the self-test on all processors right after virtualization, and
+    /// logs the results. See [`crate::hypervisor::self_test`].
+    pub self_test: bool,
+
+    /// Measures the round-trip cycles of CPUID, RDMSR and VMCALL before and
+    /// after virtualization, and flags medians above this number of cycles as
+    /// regressions in the log. `0` disables the benchmark. See
+    /// [`crate::hypervisor::benchmark`].
+    pub exit_latency_budget: u64,
+
+    /// Enables the minimal-exit performance profile, which verifies on each
+    /// processor that no intercept is enabled beyond the architectural minimum
+    /// and those the hypervisor requires, such as CPUID and VMCALL, and logs
+    /// any other. The VM-exits after virtualization completes are counted as
+    /// the steady-state exit rate. See [`crate::hypervisor::exit_profile`].
+    pub minimal_exits: bool,
+
+    /// Scales the TSC frequency the guest sees by this ratio, for example, to
+    /// slow down guest-visible time. `None` disables scaling. Ignored with a
+    /// warning if the processor does not support TSC scaling or the ratio is
+    /// out of its range. See [`crate::hypervisor::tsc_scaling`].
+    pub tsc_ratio: Option<TscRatio>,
+
+    /// Enables the deterministic time mode with this seed for RDRAND and
+    /// RDSEED. The guest TSC, the x2APIC timer count and RDRAND and RDSEED are
+    /// fed from counters that advance only as the guest reads them, for
+    /// reproducible executions. `None` disables the mode. See
+    /// [`crate::hypervisor::deterministic_time`].
+    pub deterministic_time: Option<u64>,
+
+    /// What to do when the guest triple-faults, that is, enters the shutdown
+    /// state. The guest state is dumped before either.
+    pub triple_fault: TripleFaultPolicy,
+
+    /// Whether HLT causes VM-exit.
+    pub hlt: HltPolicy,
+
+    /// Whether MONITOR and MWAIT execute natively, idle as HLT, or are not
+    /// supported. See [`crate::hypervisor::mwait`].
+    pub mwait: MwaitPolicy,
+
+    /// Enables PAUSE-loop exiting with these parameters, so that guest spin
+    /// loops that run too long cause VM-exit and are counted for each
+    /// processor as [`VmExitKind::Pause`] by
+    /// [`crate::hypervisor::exit_stats`]. The counts show spinlock contention
+    /// in the guest, which virtualization amplifies when a lock holder is
+    /// delayed by VM-exits. `None` disables it. Ignored with a warning if the
+    /// processor does not support it.
+    ///
+    /// [`VmExitKind::Pause`]: crate::hypervisor::exit_handlers::VmExitKind::Pause
+    pub pause_loop_exiting: Option<PauseLoopExiting>,
+
+    /// Enables sub-page write permissions (SPP) if the processor supports it,
+    /// so that `HC_EPT_VIEW_PROTECT_SUB_PAGES` can write-protect pages in EPT
+    /// views at the granularity of 128 bytes. The sub-page permission table
+    /// takes two pages for each set of guest tables, and more as pages are
+    /// protected. Intel only.
+    pub sub_page_permissions: bool,
+
+    /// Whether the guest or the hypervisor uses Intel Processor Trace. See
+    /// [`crate::hypervisor::processor_trace`].
+    pub processor_trace: ProcessorTracePolicy,
+
+    /// Whether the performance counters keep counting while the host runs.
+    /// See [`crate::hypervisor::pmu`].
+    pub pmu: PmuPolicy,
+
+    /// Whether RDPMC causes VM-exit, and what it returns to the guest then.
+    /// See [`crate::hypervisor::pmu`].
+    pub rdpmc: RdpmcPolicy,
+
+    /// Issues IBPB on each VM-exit if the processor supports it, so that
+    /// branch predictions trained by the guest do not steer the host. This
+    /// costs a few thousand cycles per VM-exit. See
+    /// [`crate::hypervisor::speculation`].
+    pub ibpb_on_exit: bool,
+
+    /// Whether the guest may load microcode updates. Intel only. See
+    /// [`crate::hypervisor::microcode`].
+    pub microcode_update: MicrocodeUpdatePolicy,
+
+    /// Offers the paravirtual clock page, which converts the guest TSC into the
+    /// host TSC, to the guest. See [`crate::hypervisor::pv_clock`].
+    pub pv_clock: bool,
+
+    /// Answers CPUID leaves 0xB, 0x1F and 4 from a table of the topology
+    /// built at virtualization, so that they are consistent across
+    /// processors and with the APIC IDs the hypervisor uses. See
+    /// [`crate::hypervisor::topology`].
+    pub virtualize_topology: bool,
+
+    /// Reports the presence of a hypervisor with CPUID, as a Hyper-V
+    /// compatible hypervisor without any feature, so that Windows boots with
+    /// the hypervisor-present bit set. See [`crate::hypervisor::hyperv`].
+    pub advertise_hypervisor: bool,
+
+    /// Whether writes to the GDTs and IDTs of the guest are detected. Intel
+    /// only. See [`crate::hypervisor::table_integrity`].
+    pub descriptor_table_protection: DescriptorTablePolicy,
+
+    /// Whether SGDT, SIDT, SLDT and STR in user mode fail or return spoofed
+    /// values. See [`crate::hypervisor::umip`].
+    pub umip: UmipPolicy,
+
+    /// Logs writes to LSTAR, SYSENTER_EIP and APIC_BASE that change them from
+    /// the values at virtualization. See [`crate::hypervisor::msr_monitor`].
+    pub msr_monitor: bool,
+
+    /// Enables the watchdog with this timeout in TSC cycles. Processors
+    /// handling a VM-exit for longer are reported by the others. `None`
+    /// disables it. Intel only, as the checks are driven by the VMX-preemption
+    /// timer. See [`crate::hypervisor::watchdog`].
+    pub watchdog: Option<u64>,
+
+    /// Sends NMI to the processors the watchdog reports, so that they log
+    /// where they are stuck. See [`crate::hypervisor::watchdog`].
+    pub watchdog_nmi: bool,
+
+    /// Samples the number of SMIs each processor takes, and warns if it
+    /// reaches the count in the window of TSC cycles, as such SMI storms
+    /// distort the timing the guest observes. `None` disables it. See
+    /// [`crate::hypervisor::smi`].
+    pub smi_storm: Option<SmiStorm>,
+
+    /// Causes VM-exit on SMIs taken while the guest runs, so that they are
+    /// counted as [`VmExitKind::Smi`]. AMD only. On Intel, it requires the
+    /// dual-monitor treatment, which is refused with a warning. See
+    /// [`crate::hypervisor::smi`].
+    ///
+    /// [`VmExitKind::Smi`]: crate::hypervisor::exit_handlers::VmExitKind::Smi
+    pub smm_exiting: bool,
+
+    /// Copies the hypervisor image to a random physical address at load time
+    /// and runs the copy, so that the guest cannot assume where the host code
+    /// and data are. Only effective with the `uefi` feature. See
+    /// `uefi_hv::relocation`.
+    pub relocate_image: bool,
+
+    /// The maximum level of messages logged. `None` logs up to
+    /// [`log::LevelFilter::Info`].
+    pub log_level: Option<log::LevelFilter>,
+}
+
+/// The error type for [`HvConfig::parse`] and [`HvConfig::set`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("entry {0} is not in the key=value form")]
+    InvalidEntry(usize),
+
+    #[error("unknown key")]
+    UnknownKey,
+
+    #[error("invalid value")]
+    InvalidValue,
+}
+
+impl HvConfig {
+    /// Parses `text` in the format described in [`self`] into the
+    /// configuration. Keys not in `text` keep the default values.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError`] for the first entry that is not valid.
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        for (index, entry) in text.split(['\n', ';']).enumerate() {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or(ConfigError::InvalidEntry(index + 1))?;
+            config.set(key.trim(), value.trim())?;
+        }
+        Ok(config)
+    }
+
+    /// Sets the field named `key` to `value`, both in the format described in
+    /// [`self`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError`] if `key` is not a field or `value` is not valid
+    /// for the field. The configuration is unchanged in that case.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "save_extended_state" => self.save_extended_state = parse_bool(value)?,
+            "per_core_guest_tables" => self.per_core_guest_tables = parse_bool(value)?,
+            "snapshot_pool_pages" => self.snapshot_pool_pages = parse_number(value)?,
+            "ept_views" => self.ept_views = parse_number(value)?,
+            "split_pt_pool" => self.split_pt_pool = parse_number(value)?,
+            "gdb_stub" => self.gdb_stub = parse_bool(value)?,
+            "self_test" => self.self_test = parse_bool(value)?,
+            "exit_latency_budget" => self.exit_latency_budget = parse_number(value)?,
+            "minimal_exits" => self.minimal_exits = parse_bool(value)?,
+            "tsc_ratio" => {
+                self.tsc_ratio = parse_option(value, |value| {
+                    let (numerator, denominator) = value.split_once('/')?;
+                    Some(TscRatio {
+                        numerator: parse_number(numerator).ok()?,
+                        denominator: parse_number(denominator).ok()?,
+                    })
+                })?;
+            }
+            "deterministic_time" => {
+                self.deterministic_time = parse_option(value, |value| parse_number(value).ok())?;
+            }
+            "triple_fault" => {
+                self.triple_fault = match value {
+                    "freeze" => TripleFaultPolicy::Freeze,
+                    "reset" => TripleFaultPolicy::Reset,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "hlt" => {
+                self.hlt = match value {
+                    "passthrough" => HltPolicy::Passthrough,
+                    "intercept" => HltPolicy::Intercept,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "mwait" => {
+                self.mwait = match value {
+                    "native" => MwaitPolicy::Native,
+                    "yield" => MwaitPolicy::Yield,
+                    "disable" => MwaitPolicy::Disable,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "pause_loop_exiting" => {
+                self.pause_loop_exiting = parse_option(value, |value| {
+                    let (gap, window) = value.split_once(',')?;
+                    Some(PauseLoopExiting {
+                        gap: parse_number(gap.trim()).ok()?,
+                        window: parse_number(window.trim()).ok()?,
+                    })
+                })?;
+            }
+            "sub_page_permissions" => self.sub_page_permissions = parse_bool(value)?,
+            "processor_trace" => {
+                self.processor_trace = match value {
+                    "passthrough" => ProcessorTracePolicy::Passthrough,
+                    "host" => ProcessorTracePolicy::Host,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "pmu" => {
+                self.pmu = match value {
+                    "passthrough" => PmuPolicy::Passthrough,
+                    "isolate" => PmuPolicy::Isolate,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "rdpmc" => {
+                self.rdpmc = match value {
+                    "off" => RdpmcPolicy::Off,
+                    "passthrough" => RdpmcPolicy::Passthrough,
+                    "zero" => RdpmcPolicy::Zero,
+                    "virtualize" => RdpmcPolicy::Virtualize,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "ibpb_on_exit" => self.ibpb_on_exit = parse_bool(value)?,
+            "microcode_update" => {
+                self.microcode_update = match value {
+                    "passthrough" => MicrocodeUpdatePolicy::Passthrough,
+                    "block" => MicrocodeUpdatePolicy::Block,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "pv_clock" => self.pv_clock = parse_bool(value)?,
+            "virtualize_topology" => self.virtualize_topology = parse_bool(value)?,
+            "advertise_hypervisor" => self.advertise_hypervisor = parse_bool(value)?,
+            "descriptor_table_protection" => {
+                self.descriptor_table_protection = match value {
+                    "off" => DescriptorTablePolicy::Off,
+                    "log" => DescriptorTablePolicy::Log,
+                    "block" => DescriptorTablePolicy::Block,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "umip" => {
+                self.umip = match value {
+                    "off" => UmipPolicy::Off,
+                    "fault" => UmipPolicy::Fault,
+                    "spoof" => UmipPolicy::Spoof,
+                    _ => return Err(ConfigError::InvalidValue),
+                };
+            }
+            "msr_monitor" => self.msr_monitor = parse_bool(value)?,
+            "watchdog" => {
+                self.watchdog = parse_option(value, |value| parse_number(value).ok())?;
+            }
+            "watchdog_nmi" => self.watchdog_nmi = parse_bool(value)?,
+            "smi_storm" => {
+                self.smi_storm = parse_option(value, |value| {
+                    let (count, window) = value.split_once(',')?;
+                    Some(SmiStorm {
+                        count: parse_number(count.trim()).ok()?,
+                        window: parse_number(window.trim()).ok()?,
+                    })
+                    .filter(|storm| storm.count != 0 && storm.window != 0)
+                })?;
+            }
+            "smm_exiting" => self.smm_exiting = parse_bool(value)?,
+            "relocate_image" => self.relocate_image = parse_bool(value)?,
+            "log_level" => {
+                self.log_level =
+                    Some(log::LevelFilter::from_str(value).map_err(|_| ConfigError::InvalidValue)?);
+            }
+            _ => return Err(ConfigError::UnknownKey),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, ConfigError> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ConfigError::InvalidValue),
+    }
+}
+
+fn parse_number<T: TryFrom<u64>>(value: &str) -> Result<T, ConfigError> {
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    number
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or(ConfigError::InvalidValue)
+}
+
+fn parse_option<T>(
+    value: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, ConfigError> {
+    if value == "none" {
+        return Ok(None);
+    }
+    parse(value).map(Some).ok_or(ConfigError::InvalidValue)
+}
+
+/// The parameters of PAUSE-loop exiting, or the PAUSE filter on AMD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PauseLoopExiting {
+    /// On Intel, PLE_Gap, the maximum number of TSC cycles between two PAUSEs
+    /// in the same loop. On AMD, the PAUSE filter threshold in cycles, which
+    /// is ignored if not supported. Up to `u16::MAX` on AMD.
+    pub gap: u32,
+
+    /// On Intel, PLE_Window, the maximum number of TSC cycles a loop may run
+    /// before VM-exit. On AMD, the PAUSE filter count, that is, the number of
+    /// PAUSEs in a loop before VM-exit. Up to `u16::MAX` on AMD.
+    pub window: u32,
+}
+
+/// The threshold of SMI storms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmiStorm {
+    /// The number of SMIs in `window` that is reported as a storm. Not 0.
+    pub count: u64,
+
+    /// The length of the window in TSC cycles, which is also the interval of
+    /// sampling the number of SMIs. Not 0.
+    pub window: u64,
+}
+
+/// What the host does with a processor whose guest triple-faulted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TripleFaultPolicy {
+    /// Keeps the processor in the host with the guest state intact, so that it
+    /// can be inspected with the state dump or GDB. The processor no longer
+    /// runs the guest.
+    #[default]
+    Freeze,
+
+    /// Puts the processor into the wait-for-SIPI state as INIT does, so that
+    /// the guest can restart it with INIT-SIPI-SIPI. The bootstrap processor,
+    /// and processors where INIT cannot be emulated, are frozen instead.
+    Reset,
+}
+
+/// Whether HLT causes VM-exit. MONITOR and MWAIT, which most OSes idle with,
+/// never cause VM-exit either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HltPolicy {
+    /// HLT does not cause VM-exit, and the processor idles as it does without
+    /// the hypervisor.
+    #[default]
+    Passthrough,
+
+    /// HLT causes VM-exit, so that handlers registered with
+    /// [`crate::hypervisor::exit_handlers`] can observe idling or keep the
+    /// processor in the host. Otherwise, the host puts the guest into the HLT
+    /// activity state if the processor supports it, so that the guest idles
+    /// until the next interrupt. Where it is not supported, that is, on AMD
+    /// and some nested environments, the guest resumes after HLT immediately,
+    /// and its idle loop keeps the processor busy.
+    Intercept,
+}
+
+/// Whether the guest may load microcode updates. Intel only, as AMD processors
+/// load updates through a different MSR, which is not intercepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MicrocodeUpdatePolicy {
+    /// The host loads updates on behalf of the guest, and logs changes of the
+    /// processor capabilities they cause. The hypervisor keeps using the
+    /// capabilities at virtualization.
+    #[default]
+    Passthrough,
+
+    /// The host drops updates and logs them for audit. The guest sees the
+    /// revision unchanged as if the processor rejected the update.
+    Block,
+}
+
+/// Whether the performance counters keep counting while the host runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PmuPolicy {
+    /// The counters count in both the host and the guest, and the guest sees
+    /// the events of VM-exit handlers.
+    #[default]
+    Passthrough,
+
+    /// The counters the guest uses count only while the guest runs, and those
+    /// the host uses only while the host runs. Ignored with a warning if the
+    /// processor does not support it.
+    ///
+    /// On Intel, IA32_PERF_GLOBAL_CTRL is loaded with the guest value on
+    /// VM-entry and cleared on VM-exit. On AMD, the GuestOnly bit is set in the
+    /// event selectors the guest writes, and RDMSR of them returns it set.
+    Isolate,
+}
+
+/// What MONITOR and MWAIT do in the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MwaitPolicy {
+    /// MONITOR and MWAIT do not cause VM-exit, and the guest controls the
+    /// power states of the processor.
+    #[default]
+    Native,
+
+    /// MONITOR completes without effect, and MWAIT idles as HLT does with
+    /// [`HltPolicy::Intercept`].
+    Yield,
+
+    /// MONITOR and MWAIT cause #UD, and CPUID reports them, as well as
+    /// MONITORX and MWAITX on AMD, as not supported.
+    Disable,
+}
+
+/// Whether RDPMC causes VM-exit, and what it returns to the guest then.
+///
+/// When RDPMC causes VM-exit, RDPMC of the AMD northbridge and L2 cache
+/// counters, and with the fast-read bit on Intel, injects #GP(0).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RdpmcPolicy {
+    /// RDPMC does not cause VM-exit.
+    #[default]
+    Off,
+
+    /// RDPMC causes VM-exit, and returns the actual value of the counter.
+    Passthrough,
+
+    /// RDPMC causes VM-exit, and returns zero.
+    Zero,
+
+    /// RDPMC causes VM-exit, and returns a virtual counter of the processor
+    /// that advances by one on each read, regardless of the events that occur.
+    Virtualize,
+}
+
+/// Whether the guest or the hypervisor uses Intel Processor Trace (PT).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProcessorTracePolicy {
+    /// The guest uses PT as it does without the hypervisor, and the host is
+    /// not traced. IA32_RTIT_CTL is cleared on VM-exit and the guest value is
+    /// loaded on VM-entry, so that VMX transitions are concealed from the trace.
+    #[default]
+    Passthrough,
+
+    /// PT is hidden from the guest, and the hypervisor traces the guest into
+    /// host-owned buffers. Same as `Passthrough` if the processor does not
+    /// support PT. PT is still hidden but nothing is traced, with a warning,
+    /// if the processor does not support PT in VMX operation. Access to the
+    /// IA32_RTIT_* MSRs causes #GP(0) as it would without PT. Intel only.
+    Host,
+}
+
+/// What happens when the guest writes to its GDTs or IDTs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DescriptorTablePolicy {
+    /// Writes are not detected.
+    #[default]
+    Off,
+
+    /// Writes are logged and then completed by making the page writable for a
+    /// single instruction with the monitor trap flag. Writes by other
+    /// processors in the meantime are not detected.
+    Log,
+
+    /// Writes are logged and fail with #GP(0).
+    Block,
+}
+
+/// What SGDT, SIDT, SLDT and STR do in user mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UmipPolicy {
+    /// The instructions do not cause VM-exit, and return the actual values
+    /// unless the guest enables UMIP.
+    #[default]
+    Off,
+
+    /// The instructions fail with #GP(0), as they do with CR4.UMIP, including
+    /// on processors without UMIP.
+    Fault,
+
+    /// The instructions complete with fixed values instead of the actual
+    /// ones, as Linux emulates them for user mode with UMIP.
+    Spoof,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = HvConfig::parse(
+            "# comment\n\
+             \n\
+             gdb_stub = true\n\
+             snapshot_pool_pages=0x100\n\
+             tsc_ratio=1/2\n\
+             pause_loop_exiting=128, 4096\n\
+             hlt=intercept; log_level=debug\n\
+             umip=spoof\n\
+             rdpmc=zero\n\
+             mwait=yield\n\
+             smi_storm=10, 0x1000000\n\
+             virtualize_topology=1\n\
+             relocate_image=true\n",
+        )
+        .unwrap();
+        assert!(config.gdb_stub);
+        assert_eq!(config.snapshot_pool_pages, 0x100);
+        assert_eq!(
+            config.tsc_ratio,
+            Some(TscRatio {
+                numerator: 1,
+                denominator: 2
+            })
+        );
+        assert_eq!(
+            config.pause_loop_exiting,
+            Some(PauseLoopExiting {
+                gap: 128,
+                window: 4096
+            })
+        );
+        assert_eq!(config.hlt, HltPolicy::Intercept);
+        assert_eq!(config.log_level, Some(log::LevelFilter::Debug));
+        assert_eq!(config.umip, UmipPolicy::Spoof);
+        assert_eq!(config.rdpmc, RdpmcPolicy::Zero);
+        assert_eq!(config.mwait, MwaitPolicy::Yield);
+        assert_eq!(
+            config.smi_storm,
+            Some(SmiStorm {
+                count: 10,
+                window: 0x100_0000
+            })
+        );
+        assert!(config.virtualize_topology);
+        assert!(config.relocate_image);
+        assert!(!config.self_test);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            HvConfig::parse("gdb_stub").unwrap_err(),
+            ConfigError::InvalidEntry(1)
+        );
+        assert_eq!(
+            HvConfig::parse("\nfoo=1").unwrap_err(),
+            ConfigError::UnknownKey
+        );
+        assert_eq!(
+            HvConfig::parse("gdb_stub=yes").unwrap_err(),
+            ConfigError::InvalidValue
+        );
+        assert_eq!(
+            HvConfig::parse("tsc_ratio=1").unwrap_err(),
+            ConfigError::InvalidValue
+        );
+        assert_eq!(
+            HvConfig::parse("smi_storm=0,100").unwrap_err(),
+            ConfigError::InvalidValue
+        );
+        assert_eq!(
+            HvConfig::parse("ept_views=-1").unwrap_err(),
+            ConfigError::InvalidValue
+        );
+
+        let mut config = HvConfig::parse("deterministic_time=5").unwrap();
+        assert_eq!(config.deterministic_time, Some(5));
+        config.set("deterministic_time", "none").unwrap();
+        assert_eq!(config.deterministic_time, None);
+    }
+}
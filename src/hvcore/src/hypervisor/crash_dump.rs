@@ -0,0 +1,108 @@
+//! This module implements the report of the hypervisor state for crash dumps.
+//!
+//! When the guest crashes, the platform appends the report to the crash dump
+//! so that crashes caused by the hypervisor can be investigated without a
+//! serial port. The report consists of:
+//! - the VMCS or VMCB of the current processor, dumped by the host with
+//!   [`HC_DUMP_STATE`],
+//! - the counts of VM-exits of all processors, see [`super::exit_stats`], and
+//! - the recent log output, see [`super::log_ring`].
+//!
+//! The report is written by the guest, thus, this is only useful on platforms
+//! where the host and the guest share the address space of the hypervisor,
+//! that is, Windows.
+//!
+//! [`HC_DUMP_STATE`]: super::hypercall::HC_DUMP_STATE
+
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use super::{
+    exit_stats,
+    host::Guest,
+    hypercall::{HC_DUMP_STATE, hypercall_with_token},
+    hypercall_auth::AUTH,
+    is_our_hypervisor_present, log_ring,
+};
+
+/// The size of the buffer for the dump of the VMCS or VMCB.
+const STATE_DUMP_SIZE: usize = 0x4000;
+
+/// Writes bytes into a buffer, dropping those beyond its end.
+pub struct BufferWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufferWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Returns the number of bytes written into the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if nothing is written into the buffer.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Write for BufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Writes the report of the hypervisor state. This may be called when the
+/// system has crashed, for example, from a bugcheck callback.
+pub fn write_report(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "Guest state of the current processor:")?;
+    if is_our_hypervisor_present() {
+        let _ = hypercall_with_token(HC_DUMP_STATE, [0; 3], AUTH.token());
+        if let Some(dump) = STATE_DUMP.try_lock() {
+            let (bytes, len) = &*dump;
+            for chunk in bytes[..*len].utf8_chunks() {
+                out.write_str(chunk.valid())?;
+            }
+            writeln!(out)?;
+        }
+    }
+    writeln!(out, "VM-exits:")?;
+    exit_stats::write_summary(out)?;
+    writeln!(out, "Recent log:")?;
+    log_ring::write_recent(out)
+}
+
+/// Dumps the state of `guest` for [`HC_DUMP_STATE`].
+pub(crate) fn dump_state<T: Guest>(guest: &T) {
+    let mut dump = STATE_DUMP.lock();
+    let (bytes, len) = &mut *dump;
+    let mut writer = BufferWriter::new(bytes);
+    let _ = guest.dump(&mut writer);
+    *len = writer.len();
+}
+
+static STATE_DUMP: Mutex<([u8; STATE_DUMP_SIZE], usize)> = Mutex::new(([0; STATE_DUMP_SIZE], 0));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_writer() {
+        let mut buffer = [0u8; 8];
+        let mut writer = BufferWriter::new(&mut buffer);
+        assert!(writer.is_empty());
+        write!(writer, "{:#x}", 0x1234).unwrap();
+        write!(writer, "abcdef").unwrap();
+        assert_eq!(writer.len(), 8);
+        assert_eq!(&buffer, b"0x1234ab");
+    }
+}
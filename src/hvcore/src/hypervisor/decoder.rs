@@ -0,0 +1,426 @@
+//! This module implements a minimal x86-64 instruction decoder.
+//!
+//! It only understands instructions the hypervisor needs to skip or emulate,
+//! that is, instructions that cause VM-exits and simple MOVs to MMIO. It is
+//! used when the processor does not report the length of the instruction, or
+//! the operands of it.
+
+use bit_field::BitField;
+
+use super::registers::Registers;
+
+/// The maximum length of an x86 instruction.
+pub(crate) const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Instruction {
+    /// The length of the instruction in bytes, including prefixes.
+    pub(crate) length: usize,
+
+    /// The opcode byte following the prefixes, and `0x0f` if any.
+    pub(crate) opcode: u8,
+
+    /// The register operand in the ModR/M byte, extended with REX.R.
+    pub(crate) reg: Option<u8>,
+
+    /// The immediate operand, if any.
+    pub(crate) immediate: Option<u64>,
+
+    /// The register or memory operand in the ModR/M byte, if any.
+    pub(crate) operand: Option<Operand>,
+
+    /// The operand size in bytes given by REX.W and the operand-size override
+    /// prefix, for instructions whose default operand size is 32 bits.
+    pub(crate) operand_size: u8,
+}
+
+/// The register or memory operand in the ModR/M byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand {
+    /// The register, extended with REX.B. See `Registers::gpr`.
+    Register(u8),
+    Memory(MemoryOperand),
+}
+
+/// A memory operand, that is, `segment:[base + (index << scale) + displacement]`.
+///
+/// See: 3.7.5 Specifying an Offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MemoryOperand {
+    /// The segment register. 0 to 5 for ES, CS, SS, DS, FS and GS.
+    pub(crate) segment: u8,
+
+    /// The base register, if any. See `Registers::gpr`.
+    pub(crate) base: Option<u8>,
+
+    /// The index register, if any. See `Registers::gpr`.
+    pub(crate) index: Option<u8>,
+
+    /// The number of bits the index is shifted by, 0 to 3.
+    pub(crate) scale: u8,
+
+    pub(crate) displacement: i64,
+
+    /// Whether the displacement is relative to RIP of the next instruction.
+    pub(crate) rip_relative: bool,
+
+    /// The address size in bytes.
+    pub(crate) address_size: u8,
+}
+
+impl MemoryOperand {
+    /// Returns the linear address of this operand with the registers `regs`
+    /// and the RIP of the next instruction `next_rip`. `segment_base` returns
+    /// the base of the given segment register, which only FS and GS have in
+    /// 64-bit mode, that is, if `long_mode` is `true`.
+    ///
+    /// See: 3.4.4 Segment Loading Instructions in IA-32e Mode
+    pub(crate) fn linear_address(
+        &self,
+        regs: &Registers,
+        next_rip: u64,
+        long_mode: bool,
+        segment_base: impl FnOnce(u8) -> u64,
+    ) -> u64 {
+        const FS: u8 = 4;
+        const GS: u8 = 5;
+
+        let base = match (self.rip_relative, self.base) {
+            (true, _) => next_rip,
+            (false, Some(base)) => regs.gpr(base),
+            (false, None) => 0,
+        };
+        let index = self.index.map_or(0, |index| regs.gpr(index) << self.scale);
+        let offset = base
+            .wrapping_add(index)
+            .wrapping_add(self.displacement as u64);
+        let offset = match self.address_size {
+            2 => offset & 0xffff,
+            4 => offset & 0xffff_ffff,
+            _ => offset,
+        };
+        if long_mode {
+            if self.segment == FS || self.segment == GS {
+                segment_base(self.segment).wrapping_add(offset)
+            } else {
+                offset
+            }
+        } else {
+            segment_base(self.segment).wrapping_add(offset) & 0xffff_ffff
+        }
+    }
+}
+
+/// Decodes the instruction at the beginning of `bytes`, assuming the 64-bit
+/// mode. Returns `None` if the instruction is unsupported or truncated.
+pub(crate) fn decode(bytes: &[u8]) -> Option<Instruction> {
+    let mut cursor = Cursor { bytes, position: 0 };
+
+    // Legacy prefixes, followed by an optional REX prefix.
+    // See: 2.1.1 Instruction Prefixes
+    let mut operand_size_override = false;
+    let mut address_size_override = false;
+    let mut segment = None;
+    let mut opcode = cursor.next()?;
+    while matches!(
+        opcode,
+        0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 | 0x66 | 0x67
+    ) {
+        operand_size_override |= opcode == 0x66;
+        address_size_override |= opcode == 0x67;
+        segment = match opcode {
+            0x26 => Some(0),
+            0x2e => Some(1),
+            0x36 => Some(2),
+            0x3e => Some(3),
+            0x64 => Some(4),
+            0x65 => Some(5),
+            _ => segment,
+        };
+        opcode = cursor.next()?;
+    }
+    let mut rex = 0;
+    if (0x40..=0x4f).contains(&opcode) {
+        rex = opcode;
+        opcode = cursor.next()?;
+    }
+    let rex_r = rex.get_bit(2);
+
+    let mut reg = None;
+    let mut immediate = None;
+    let mut operand = None;
+    if opcode == 0x0f {
+        match cursor.next()? {
+            // INVD, WBINVD, WRMSR, RDTSC, RDMSR, RDPMC, CPUID
+            0x08 | 0x09 | 0x30 | 0x31 | 0x32 | 0x33 | 0xa2 => {}
+            // Group 6, eg, SLDT and STR, and group 7, eg, SGDT, SIDT, XSETBV,
+            // VMMCALL, VMCALL and RDTSCP
+            0x00 | 0x01 => {
+                operand = Some(cursor.modrm(rex)?.1);
+            }
+            _ => return None,
+        }
+    } else {
+        match opcode {
+            // MOV r/m8, r8 / MOV r/m, r / MOV r8, r/m8 / MOV r, r/m
+            0x88..=0x8b => {
+                let (modrm_reg, modrm_operand) = cursor.modrm(rex)?;
+                reg = Some(modrm_reg | (u8::from(rex_r) << 3));
+                operand = Some(modrm_operand);
+            }
+            // MOV r/m, imm
+            0xc6 | 0xc7 => {
+                operand = Some(cursor.modrm(rex)?.1);
+                let size = match (opcode, operand_size_override) {
+                    (0xc6, _) => 1,
+                    (_, true) => 2,
+                    _ => 4,
+                };
+                immediate = Some(cursor.immediate(size)?);
+            }
+            // MOV moffs, AL / MOV moffs, eAX
+            0xa2 | 0xa3 => {
+                let size = if address_size_override { 4 } else { 8 };
+                immediate = Some(cursor.immediate(size)?);
+            }
+            // IN / OUT with imm8
+            0xe4..=0xe7 => {
+                immediate = Some(cursor.immediate(1)?);
+            }
+            // IN / OUT with DX, INS / OUTS, HLT
+            0xec..=0xef | 0x6c..=0x6f | 0xf4 => {}
+            _ => return None,
+        }
+    }
+
+    // See: 2.2.1.2 More on REX Prefix Fields
+    let operand_size = match (rex.get_bit(3), operand_size_override) {
+        (true, _) => 8,
+        (false, true) => 2,
+        (false, false) => 4,
+    };
+    if let Some(Operand::Memory(memory)) = &mut operand {
+        memory.address_size = if address_size_override { 4 } else { 8 };
+        if let Some(segment) = segment {
+            memory.segment = segment;
+        }
+    }
+    Some(Instruction {
+        length: cursor.position,
+        opcode,
+        reg,
+        immediate,
+        operand,
+        operand_size,
+    })
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Cursor<'_> {
+    fn next(&mut self) -> Option<u8> {
+        if self.position >= MAX_INSTRUCTION_LENGTH {
+            return None;
+        }
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    /// Consumes the ModR/M byte and the following SIB and displacement bytes,
+    /// and returns the `reg` field of the ModR/M byte and the operand the
+    /// `r/m` field specifies, extended with `rex`. The segment and address size
+    /// of the memory operand are the defaults.
+    // See: 2.1.5 Addressing-Mode Encoding of ModR/M and SIB Bytes
+    // See: 2.2.1.2 More on REX Prefix Fields
+    fn modrm(&mut self, rex: u8) -> Option<(u8, Operand)> {
+        const SS: u8 = 2;
+        const DS: u8 = 3;
+        const RSP: u8 = 4;
+        const RBP: u8 = 5;
+
+        let modrm = self.next()?;
+        let mode = modrm.get_bits(6..=7);
+        let rm = modrm.get_bits(0..=2);
+        let reg = modrm.get_bits(3..=5);
+        let rex_x = u8::from(rex.get_bit(1)) << 3;
+        let rex_b = u8::from(rex.get_bit(0)) << 3;
+        if mode == 0b11 {
+            return Some((reg, Operand::Register(rm | rex_b)));
+        }
+
+        let mut memory = MemoryOperand {
+            segment: DS,
+            base: Some(rm | rex_b),
+            index: None,
+            scale: 0,
+            displacement: 0,
+            rip_relative: false,
+            address_size: 8,
+        };
+        let mut displacement = match mode {
+            0b01 => 1,
+            0b10 => 4,
+            _ => 0,
+        };
+        if rm == 0b100 {
+            let sib = self.next()?;
+            let index = sib.get_bits(3..=5) | rex_x;
+            memory.index = (index != RSP).then_some(index);
+            memory.scale = sib.get_bits(6..=7);
+            memory.base = Some(sib.get_bits(0..=2) | rex_b);
+            if mode == 0b00 && sib.get_bits(0..=2) == 0b101 {
+                memory.base = None;
+                displacement = 4;
+            }
+        } else if mode == 0b00 && rm == 0b101 {
+            // RIP-relative addressing.
+            memory.base = None;
+            memory.rip_relative = true;
+            displacement = 4;
+        }
+        if matches!(memory.base, Some(RSP | RBP)) {
+            memory.segment = SS;
+        }
+        if displacement != 0 {
+            let value = self.immediate(displacement)?;
+            memory.displacement = if displacement == 1 {
+                i64::from(value as i8)
+            } else {
+                i64::from(value as i32)
+            };
+        }
+        Some((reg, Operand::Memory(memory)))
+    }
+
+    fn immediate(&mut self, size: usize) -> Option<u64> {
+        let mut value = 0u64;
+        for i in 0..size {
+            value |= u64::from(self.next()?) << (i * 8);
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_exiting_instructions() {
+        // CPUID
+        assert_eq!(decode(&[0x0f, 0xa2]).unwrap().length, 2);
+        // RDMSR with a redundant prefix
+        assert_eq!(decode(&[0x2e, 0x0f, 0x32]).unwrap().length, 3);
+        // XSETBV
+        assert_eq!(decode(&[0x0f, 0x01, 0xd1]).unwrap().length, 3);
+        // OUT 80h, AL
+        assert_eq!(decode(&[0xe6, 0x80]).unwrap().length, 2);
+        // Unsupported instruction
+        assert_eq!(decode(&[0x0f, 0x0b]), None);
+        // Truncated instruction
+        assert_eq!(decode(&[0x0f]), None);
+    }
+
+    #[test]
+    fn decode_mmio_moves() {
+        // MOV DWORD PTR [RAX+000000B0],00000000
+        let instruction =
+            decode(&[0xc7, 0x80, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(instruction.length, 10);
+        assert_eq!(instruction.immediate, Some(0));
+
+        // MOV DWORD PTR [R13],R12D
+        let instruction = decode(&[0x45, 0x89, 0x65, 0x00]).unwrap();
+        assert_eq!(instruction.length, 4);
+        assert_eq!(instruction.reg, Some(12));
+
+        // MOV DWORD PTR [R8+RAX],EDX
+        let instruction = decode(&[0x41, 0x89, 0x14, 0x00]).unwrap();
+        assert_eq!(instruction.length, 4);
+        assert_eq!(instruction.reg, Some(2));
+
+        // MOV DWORD PTR [00000000FEE00300],EAX
+        let instruction = decode(&[0xa3, 0x00, 0x03, 0xe0, 0xfe, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(instruction.length, 9);
+        assert_eq!(instruction.immediate, Some(0xfee0_0300));
+
+        // MOV DWORD PTR [RAX+00000300],EDX
+        let instruction = decode(&[0x89, 0x90, 0x00, 0x03, 0x00, 0x00]).unwrap();
+        assert_eq!(instruction.length, 6);
+        assert_eq!(instruction.reg, Some(2));
+    }
+
+    #[test]
+    fn decode_descriptor_table_operands() {
+        let memory = |instruction: Instruction| match instruction.operand {
+            Some(Operand::Memory(memory)) => memory,
+            operand => panic!("{operand:?}"),
+        };
+
+        // SGDT [RIP+10h]
+        let instruction = decode(&[0x0f, 0x01, 0x05, 0x10, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(instruction.length, 7);
+        let operand = memory(instruction);
+        assert!(operand.rip_relative);
+        assert_eq!(operand.displacement, 0x10);
+
+        // SIDT [RSP+8]
+        let operand = memory(decode(&[0x0f, 0x01, 0x4c, 0x24, 0x08]).unwrap());
+        assert_eq!(
+            (operand.segment, operand.base, operand.index),
+            (2, Some(4), None)
+        );
+        assert_eq!(operand.displacement, 8);
+
+        // SGDT GS:[RAX+RBX*4-8]
+        let instruction = decode(&[0x65, 0x0f, 0x01, 0x44, 0x98, 0xf8]).unwrap();
+        assert_eq!(instruction.length, 6);
+        let operand = memory(instruction);
+        assert_eq!(
+            operand,
+            MemoryOperand {
+                segment: 5,
+                base: Some(0),
+                index: Some(3),
+                scale: 2,
+                displacement: -8,
+                rip_relative: false,
+                address_size: 8,
+            }
+        );
+        let regs = Registers {
+            rax: 0x1000,
+            rbx: 0x10,
+            ..Registers::default()
+        };
+        assert_eq!(
+            operand.linear_address(&regs, 0, true, |_| 0x7000_0000),
+            0x7000_1038
+        );
+        // The base of DS is ignored in 64-bit mode, and not outside it.
+        let operand = MemoryOperand {
+            segment: 3,
+            address_size: 4,
+            ..operand
+        };
+        assert_eq!(operand.linear_address(&regs, 0, true, |_| 0x100), 0x1038);
+        assert_eq!(
+            operand.linear_address(&regs, 0, false, |_| 0xffff_ff00),
+            0xf38
+        );
+
+        // SLDT EAX, and STR R9 with REX.W
+        let instruction = decode(&[0x0f, 0x00, 0xc0]).unwrap();
+        assert_eq!(instruction.operand, Some(Operand::Register(0)));
+        assert_eq!(instruction.operand_size, 4);
+        let instruction = decode(&[0x49, 0x0f, 0x00, 0xc9]).unwrap();
+        assert_eq!(instruction.length, 4);
+        assert_eq!(instruction.operand, Some(Operand::Register(9)));
+        assert_eq!(instruction.operand_size, 8);
+    }
+}
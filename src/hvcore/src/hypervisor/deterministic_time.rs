@@ -0,0 +1,236 @@
+//! This module implements the deterministic time mode, where the guest reads
+//! time and random numbers from counters that depend only on its execution.
+
+use core::arch::x86_64::_rdtsc;
+
+use spin::Once;
+use x86::cpuid::CpuIdResult;
+
+use super::SHARED_HOST_DATA;
+
+/// The number of cycles the virtual TSC advances on every read.
+pub const TSC_STEP: u64 = 1_000;
+
+/// The MSRs whose reads are intercepted in this mode. The xAPIC current count
+/// register, read through MMIO, is not virtualized.
+pub(crate) const INTERCEPTED_READS: [u32; 3] =
+    [IA32_TSC, IA32_TSC_DEADLINE, X2APIC_MSR_CURRENT_COUNT];
+
+/// The MSRs whose writes are intercepted in this mode.
+pub(crate) const INTERCEPTED_WRITES: [u32; 4] = [
+    IA32_TSC,
+    IA32_TSC_DEADLINE,
+    X2APIC_MSR_LVT_TIMER,
+    X2APIC_MSR_INITIAL_COUNT,
+];
+
+const IA32_TSC: u32 = 0x10;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+// See: 11.12.1.2 x2APIC Register Address Space
+const X2APIC_MSR_LVT_TIMER: u32 = 0x832;
+const X2APIC_MSR_INITIAL_COUNT: u32 = 0x838;
+const X2APIC_MSR_CURRENT_COUNT: u32 = 0x839;
+
+/// Returns `true` if the mode is enabled.
+pub(crate) fn enabled() -> bool {
+    SHARED_HOST_DATA
+        .get()
+        .unwrap()
+        .config
+        .deterministic_time
+        .is_some()
+}
+
+/// Returns the result of CPUID `leaf` and `sub_leaf` the guest sees in this
+/// mode, given that of the processor. RDRAND and RDSEED are reported as
+/// unsupported on AMD, where they cannot be intercepted.
+pub(crate) fn filter_cpuid(leaf: u32, sub_leaf: u32, mut cpuid_result: CpuIdResult) -> CpuIdResult {
+    if !enabled() || is_intel() {
+        return cpuid_result;
+    }
+    // See: E.3.2 Function 1h—Processor and Processor Feature Identifiers
+    // See: E.3.6 Function 7h—Structured Extended Feature Identifiers
+    if leaf == 1 {
+        cpuid_result.ecx &= !(1 << 30);
+    } else if leaf == 7 && sub_leaf == 0 {
+        cpuid_result.ebx &= !(1 << 18);
+    }
+    cpuid_result
+}
+
+fn is_intel() -> bool {
+    x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel"
+}
+
+/// The virtual time and random number sequence of a processor.
+///
+/// The virtual TSCs of processors advance independently of each other.
+/// Interrupts, including those of the APIC timer, still arrive at their real
+/// times, as replaying them would require recording them.
+#[derive(Debug)]
+pub(crate) struct DeterministicClock {
+    /// The current virtual TSC.
+    tsc: u64,
+    /// The last value written to IA32_TSC_DEADLINE, in the virtual TSC.
+    deadline: u64,
+    /// Whether the APIC timer is in the periodic mode.
+    periodic: bool,
+    /// The initial count of the APIC timer, and the virtual TSC when it was
+    /// written.
+    timer: (u32, u64),
+    /// The state of the pseudo-random number generator.
+    random: u64,
+}
+
+impl DeterministicClock {
+    /// Creates the clock of the processor `id`, or `None` if the mode is not
+    /// enabled. The virtual TSCs of all processors start from the host TSC
+    /// when the first clock is created.
+    pub(crate) fn new(id: usize) -> Option<Self> {
+        let seed = SHARED_HOST_DATA.get().unwrap().config.deterministic_time?;
+        let start = *START_TSC.call_once(|| unsafe { _rdtsc() });
+        Some(Self::with_start(id, seed, start))
+    }
+
+    fn with_start(id: usize, seed: u64, start: u64) -> Self {
+        Self {
+            tsc: start,
+            deadline: 0,
+            periodic: false,
+            timer: (0, start),
+            random: seed ^ (id as u64).wrapping_mul(GOLDEN_RATIO),
+        }
+    }
+
+    /// Advances the virtual TSC by a step and returns it.
+    pub(crate) fn read_tsc(&mut self) -> u64 {
+        self.tsc = self.tsc.wrapping_add(TSC_STEP);
+        self.tsc
+    }
+
+    /// Returns the next pseudo-random number.
+    ///
+    /// This is SplitMix64, which is fast and has no state beyond a counter.
+    pub(crate) fn random(&mut self) -> u64 {
+        self.random = self.random.wrapping_add(GOLDEN_RATIO);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns the value of `msr` the guest reads, or `None` if the MSR is not
+    /// virtualized.
+    pub(crate) fn read_msr(&mut self, msr: u32) -> Option<u64> {
+        match msr {
+            IA32_TSC => Some(self.read_tsc()),
+            IA32_TSC_DEADLINE => Some(self.deadline),
+            X2APIC_MSR_CURRENT_COUNT => Some(u64::from(self.current_count())),
+            _ => None,
+        }
+    }
+
+    /// Records the guest writing `value` to `msr`, and returns the value to
+    /// write to the processor, or `None` if the write is emulated entirely.
+    pub(crate) fn write_msr(&mut self, msr: u32, value: u64) -> Option<u64> {
+        match msr {
+            IA32_TSC => {
+                self.tsc = value;
+                return None;
+            }
+            IA32_TSC_DEADLINE => {
+                self.deadline = value;
+                return Some(self.host_deadline(value, unsafe { _rdtsc() }));
+            }
+            // See: Figure 11-8. Local Vector Table (LVT)
+            X2APIC_MSR_LVT_TIMER => self.periodic = (value >> 17) & 0b11 == 0b01,
+            X2APIC_MSR_INITIAL_COUNT => self.timer = (value as u32, self.tsc),
+            _ => {}
+        }
+        Some(value)
+    }
+
+    /// Advances the virtual TSC by a step and returns the current count of the
+    /// APIC timer, which counts down by one on every step.
+    fn current_count(&mut self) -> u32 {
+        let (initial, start) = self.timer;
+        let elapsed = self.read_tsc().wrapping_sub(start) / TSC_STEP;
+        if initial == 0 {
+            0
+        } else if self.periodic {
+            initial - (elapsed % u64::from(initial)) as u32
+        } else {
+            u64::from(initial).saturating_sub(elapsed) as u32
+        }
+    }
+
+    /// Converts the virtual TSC `deadline` into the host TSC, given the
+    /// current host TSC. `0` disarms the timer and is kept as is.
+    fn host_deadline(&self, deadline: u64, host_tsc: u64) -> u64 {
+        if deadline == 0 {
+            return 0;
+        }
+        let remaining = deadline.saturating_sub(self.tsc);
+        host_tsc.saturating_add(remaining).max(1)
+    }
+}
+
+/// The fractional part of the golden ratio, used as the increment of SplitMix64.
+const GOLDEN_RATIO: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The host TSC when the first clock was created.
+static START_TSC: Once<u64> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_tsc() {
+        let mut clock = DeterministicClock::with_start(0, 0, 0x1000);
+        assert_eq!(clock.read_tsc(), 0x1000 + TSC_STEP);
+        assert_eq!(clock.read_msr(IA32_TSC), Some(0x1000 + TSC_STEP * 2));
+        assert_eq!(clock.write_msr(IA32_TSC, 0x5000), None);
+        assert_eq!(clock.read_tsc(), 0x5000 + TSC_STEP);
+
+        // The deadline is as far from the host TSC as from the virtual TSC.
+        assert_eq!(
+            clock.host_deadline(0x5000 + TSC_STEP * 10, 0x10_0000),
+            0x10_0000 + TSC_STEP * 9
+        );
+        assert_eq!(clock.host_deadline(0x10, 0x10_0000), 0x10_0000);
+        assert_eq!(clock.host_deadline(0, 0x10_0000), 0);
+    }
+
+    #[test]
+    fn apic_timer() {
+        let mut clock = DeterministicClock::with_start(0, 0, 0);
+        assert_eq!(clock.read_msr(X2APIC_MSR_CURRENT_COUNT), Some(0));
+
+        // One-shot mode stops at 0.
+        assert_eq!(clock.write_msr(X2APIC_MSR_INITIAL_COUNT, 3), Some(3));
+        let counts: [_; 4] = core::array::from_fn(|_| clock.current_count());
+        assert_eq!(counts, [2, 1, 0, 0]);
+
+        // Periodic mode reloads the initial count.
+        assert_eq!(
+            clock.write_msr(X2APIC_MSR_LVT_TIMER, 1 << 17 | 0xef),
+            Some(1 << 17 | 0xef)
+        );
+        let _ = clock.write_msr(X2APIC_MSR_INITIAL_COUNT, 3);
+        let counts: [_; 4] = core::array::from_fn(|_| clock.current_count());
+        assert_eq!(counts, [2, 1, 3, 2]);
+    }
+
+    #[test]
+    fn random() {
+        let sequence = |id, seed| {
+            let mut clock = DeterministicClock::with_start(id, seed, 0);
+            [clock.random(), clock.random()]
+        };
+        assert_eq!(sequence(0, 1), sequence(0, 1));
+        assert_ne!(sequence(0, 1), sequence(1, 1));
+        assert_ne!(sequence(0, 1), sequence(0, 2));
+    }
+}
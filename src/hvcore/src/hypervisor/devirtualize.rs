@@ -0,0 +1,121 @@
+//! This module implements devirtualization, that is, stopping the hypervisor
+//! and letting the guest continue to run on the bare processors.
+
+use alloc::vec::Vec;
+use core::arch::global_asm;
+
+use spin::Mutex;
+
+use super::{
+    apic_id,
+    hypercall::{HC_DEVIRTUALIZE, hypercall_with_token},
+    hypercall_auth::AUTH,
+    is_our_hypervisor_present, platform_ops,
+    registers::Registers,
+    revirtualize_system,
+};
+
+/// The error type for [`devirtualize_system`].
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+#[error("processors {failed:?} could not be devirtualized, and the others were re-virtualized")]
+pub struct DevirtualizeError {
+    /// The IDs of the processors that stayed virtualized.
+    pub failed: Vec<usize>,
+}
+
+/// Devirtualizes all logical processors on this system.
+///
+/// # Errors
+///
+/// Returns [`DevirtualizeError`] if any processor could not be devirtualized.
+/// All processors are virtualized in that case, and the caller must keep the
+/// hypervisor resident, as they still run on its host code.
+pub fn devirtualize_system() -> Result<(), DevirtualizeError> {
+    log::info!("Devirtualizing the all processors");
+    let failed = Mutex::new(Vec::new());
+    platform_ops::get().run_on_all_processors(&|| {
+        if !devirtualize_current_processor() {
+            let id = apic_id::processor_id_from(apic_id::get()).unwrap_or(usize::MAX);
+            failed.lock().push(id);
+        }
+    });
+
+    let mut failed = failed.into_inner();
+    if failed.is_empty() {
+        log::info!("Devirtualized the all processors");
+        return Ok(());
+    }
+
+    // Roll back, so that the system is not left partially virtualized.
+    failed.sort_unstable();
+    log::error!("Processors {failed:?} could not be devirtualized. Rolling back");
+    revirtualize_system();
+    Err(DevirtualizeError { failed })
+}
+
+/// Devirtualizes the current logical processor if it is virtualized with
+/// [`HC_DEVIRTUALIZE`]. Returns `false` if the processor stays virtualized, for
+/// example, because the hypercall is denied.
+pub fn devirtualize_current_processor() -> bool {
+    if !is_our_hypervisor_present() {
+        return true;
+    }
+    let _ = hypercall_with_token(HC_DEVIRTUALIZE, [0; 3], AUTH.token());
+    if is_our_hypervisor_present() {
+        log::error!("Failed to devirtualize the current processor");
+        return false;
+    }
+    log::info!("Devirtualized the current processor");
+    true
+}
+
+/// Loads `registers` into the processor and jumps to its RIP.
+pub(crate) fn resume_guest(registers: &Registers) -> ! {
+    unsafe { restore_registers_and_jump(registers) };
+}
+
+unsafe extern "C" {
+    /// Loads `registers` into the processor and jumps to its RIP.
+    unsafe fn restore_registers_and_jump(registers: &Registers) -> !;
+}
+global_asm!(include_str!("capture_registers.inc"));
+global_asm!(
+    r#"
+    .align 16
+    .global restore_registers_and_jump
+    restore_registers_and_jump:
+        xchg    bx, bx
+        mov     r15, rcx
+
+        # Switch to the guest stack and place RIP and RFLAGS on it for RET and
+        # POPFQ. The guest does not use the area below RSP.
+        mov     rsp, [r15 + registers_rsp]
+        push    qword ptr [r15 + registers_rip]
+        push    qword ptr [r15 + registers_rflags]
+
+        movaps  xmm0, [r15 + registers_xmm0]
+        movaps  xmm1, [r15 + registers_xmm1]
+        movaps  xmm2, [r15 + registers_xmm2]
+        movaps  xmm3, [r15 + registers_xmm3]
+        movaps  xmm4, [r15 + registers_xmm4]
+        movaps  xmm5, [r15 + registers_xmm5]
+        mov     rax, [r15 + registers_rax]
+        mov     rbx, [r15 + registers_rbx]
+        mov     rcx, [r15 + registers_rcx]
+        mov     rdx, [r15 + registers_rdx]
+        mov     rdi, [r15 + registers_rdi]
+        mov     rsi, [r15 + registers_rsi]
+        mov     rbp, [r15 + registers_rbp]
+        mov      r8, [r15 + registers_r8]
+        mov      r9, [r15 + registers_r9]
+        mov     r10, [r15 + registers_r10]
+        mov     r11, [r15 + registers_r11]
+        mov     r12, [r15 + registers_r12]
+        mov     r13, [r15 + registers_r13]
+        mov     r14, [r15 + registers_r14]
+        mov     r15, [r15 + registers_r15]
+
+        popfq
+        ret
+"#
+);
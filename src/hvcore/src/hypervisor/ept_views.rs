@@ -0,0 +1,261 @@
+//! This module implements the vendor-neutral interface of EPT views, that is,
+//! sets of nested paging structures with their own permissions.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::paging_structures::IDENTITY_MAP_SIZE;
+
+/// The largest number of views, including the default view. The EPTs of each
+/// view take about 2MB.
+pub const MAX_EPT_VIEWS: usize = 8;
+
+/// The index of the view the guest starts with. When the guest accesses a page
+/// in a way the active view does not permit, the processor switches back to
+/// this view and retries the access.
+pub const DEFAULT_EPT_VIEW: usize = 0;
+
+/// The permissions of guest physical pages in a view.
+///
+/// With mode-based execute control (MBEC), execution from supervisor-mode and
+/// user-mode linear addresses is permitted separately, for example, to keep
+/// kernel pages from ever being executed in user mode, or to hook a page only
+/// when the kernel executes it. MBEC is enabled where the processor supports
+/// it. Without it, `execute` applies to both modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptPermissions {
+    pub read: bool,
+    pub write: bool,
+
+    /// Execute access, for supervisor-mode linear addresses only with MBEC.
+    pub execute: bool,
+
+    /// Execute access for user-mode linear addresses. Must be the same as
+    /// `execute` unless the processor supports MBEC.
+    pub user_execute: bool,
+}
+
+impl EptPermissions {
+    /// The bits of the raw value that hold the permissions.
+    const RAW_MASK: u64 = 0b111 | 1 << 10 | 1 << 11;
+
+    /// Returns the permissions encoded in `value`, with the bits in the same
+    /// positions as in EPT entries: read (bit 0), write (bit 1), execute (bit
+    /// 2) and user-mode execute (bit 10). Bit 10 is used only if bit 11 is set.
+    /// Otherwise, user-mode execute is the same as execute.
+    pub fn from_raw(value: u64) -> Self {
+        let execute = value.get_bit(2);
+        Self {
+            read: value.get_bit(0),
+            write: value.get_bit(1),
+            execute,
+            user_execute: if value.get_bit(11) {
+                value.get_bit(10)
+            } else {
+                execute
+            },
+        }
+    }
+
+    /// Returns the permissions with `execute` for both modes.
+    pub fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+            user_execute: execute,
+        }
+    }
+}
+
+/// Decodes the arguments of [`HC_EPT_VIEW_PROTECT`], that is, the GPA with
+/// the permissions in the low bits, and the size.
+///
+/// [`HC_EPT_VIEW_PROTECT`]: super::hypercall::HC_EPT_VIEW_PROTECT
+pub(crate) fn decode_protect_args(
+    gpa_and_permissions: u64,
+    size: u64,
+) -> Result<(Range<u64>, EptPermissions), EptViewError> {
+    let page_mask = BASE_PAGE_SIZE as u64 - 1;
+    let gpa = gpa_and_permissions & !page_mask;
+    if gpa_and_permissions & page_mask & !EptPermissions::RAW_MASK != 0
+        || size == 0
+        || size & page_mask != 0
+        || gpa.saturating_add(size) > IDENTITY_MAP_SIZE
+    {
+        return Err(EptViewError::InvalidRange);
+    }
+    Ok((
+        gpa..gpa + size,
+        EptPermissions::from_raw(gpa_and_permissions),
+    ))
+}
+
+/// Decodes the arguments of [`HC_EPT_VIEW_PROTECT_SUB_PAGES`], that is, the
+/// GPA and the bits of writable sub-pages.
+///
+/// [`HC_EPT_VIEW_PROTECT_SUB_PAGES`]: super::hypercall::HC_EPT_VIEW_PROTECT_SUB_PAGES
+pub(crate) fn decode_sub_page_args(gpa: u64, writable: u64) -> Result<(u64, u32), EptViewError> {
+    if !gpa.is_multiple_of(BASE_PAGE_SIZE as u64) || gpa >= IDENTITY_MAP_SIZE {
+        return Err(EptViewError::InvalidRange);
+    }
+    let writable = u32::try_from(writable).map_err(|_| EptViewError::InvalidRange)?;
+    Ok((gpa, writable))
+}
+
+/// The views bound to the address spaces of processes.
+///
+/// While any view is bound, every write to CR3 selects the view bound to the
+/// new CR3, or the default view. This scopes hooks to processes: a view that
+/// makes a shared DLL page non-executable is active only while the process with
+/// the bound CR3 runs.
+#[derive(Debug, Default)]
+pub(crate) struct Cr3Bindings {
+    /// The page-aligned CR3 values and the views bound to them.
+    bindings: Vec<(u64, usize)>,
+}
+
+impl Cr3Bindings {
+    /// Bits 51:12 of CR3, that is, the address of the top-level paging
+    /// structure without the PCID and flags.
+    /// See: Table 4-12. Use of CR3 with 4-Level Paging and 5-level Paging and CR4.PCIDE = 1
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Binds `view` to the address space `cr3`, replacing the current binding.
+    /// Binding [`DEFAULT_EPT_VIEW`] removes the binding.
+    pub(crate) fn bind(&mut self, cr3: u64, view: usize) -> Result<(), EptViewError> {
+        let cr3 = cr3 & Self::ADDRESS_MASK;
+        if view != DEFAULT_EPT_VIEW {
+            self.bindings
+                .try_reserve(1)
+                .map_err(|_| EptViewError::OutOfMemory)?;
+        }
+        self.bindings.retain(|(bound_cr3, _)| *bound_cr3 != cr3);
+        if view != DEFAULT_EPT_VIEW {
+            self.bindings.push((cr3, view));
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if no view is bound.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Returns the view bound to the address space `cr3`, or the default view.
+    pub(crate) fn view_for(&self, cr3: u64) -> usize {
+        let cr3 = cr3 & Self::ADDRESS_MASK;
+        self.bindings
+            .iter()
+            .find(|(bound_cr3, _)| *bound_cr3 == cr3)
+            .map_or(DEFAULT_EPT_VIEW, |(_, view)| *view)
+    }
+}
+
+/// The error type for EPT view hypercalls. The value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum EptViewError {
+    #[error("EPT views, or the requested permissions, are not supported on this processor")]
+    Unsupported = 1,
+
+    #[error("the view does not exist")]
+    InvalidView = 2,
+
+    #[error("the range is not aligned, empty or not mapped")]
+    InvalidRange = 3,
+
+    #[error("the hypervisor heap is exhausted")]
+    OutOfMemory = 4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protect_args() {
+        assert_eq!(
+            decode_protect_args(0x1000_0005, 0x2000),
+            Ok((
+                0x1000_0000..0x1000_2000,
+                EptPermissions::new(true, false, true)
+            ))
+        );
+        assert_eq!(
+            decode_protect_args(0x1000_0c05, 0x1000),
+            Ok((
+                0x1000_0000..0x1000_1000,
+                EptPermissions {
+                    read: true,
+                    write: false,
+                    execute: true,
+                    user_execute: true,
+                }
+            ))
+        );
+        assert_eq!(
+            decode_protect_args(0x1000_0803, 0x1000).map(|(_, permissions)| permissions),
+            Ok(EptPermissions {
+                read: true,
+                write: true,
+                execute: false,
+                user_execute: false,
+            })
+        );
+        assert_eq!(
+            decode_protect_args(0x1000_0404, 0x1000).map(|(_, permissions)| permissions),
+            Ok(EptPermissions::new(false, false, true))
+        );
+        for (gpa_and_permissions, size) in [
+            (0x1000_0008, 0x2000),
+            (0x1000_0000, 0),
+            (0x1000_0000, 0x2010),
+            (IDENTITY_MAP_SIZE - 0x1000, 0x2000),
+        ] {
+            assert_eq!(
+                decode_protect_args(gpa_and_permissions, size),
+                Err(EptViewError::InvalidRange)
+            );
+        }
+    }
+
+    #[test]
+    fn sub_page_args() {
+        assert_eq!(
+            decode_sub_page_args(0x1000_0000, 0xffff_0000),
+            Ok((0x1000_0000, 0xffff_0000))
+        );
+        for (gpa, writable) in [
+            (0x1000_0080, 0),
+            (IDENTITY_MAP_SIZE, 0),
+            (0x1000_0000, 0x1_0000_0000),
+        ] {
+            assert_eq!(
+                decode_sub_page_args(gpa, writable),
+                Err(EptViewError::InvalidRange)
+            );
+        }
+    }
+
+    #[test]
+    fn cr3_bindings() {
+        let mut bindings = Cr3Bindings::default();
+        assert!(bindings.is_empty());
+        assert_eq!(bindings.view_for(0x1aa000), DEFAULT_EPT_VIEW);
+
+        // The PCID and the no-flush bit are ignored.
+        bindings.bind(0x1aa000, 2).unwrap();
+        assert_eq!(bindings.view_for(0x8000_0000_001a_a003), 2);
+        assert_eq!(bindings.view_for(0x1ab000), DEFAULT_EPT_VIEW);
+
+        bindings.bind(0x1aa005, 1).unwrap();
+        assert_eq!(bindings.view_for(0x1aa000), 1);
+
+        bindings.bind(0x1aa000, DEFAULT_EPT_VIEW).unwrap();
+        assert!(bindings.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! This module implements the re-injection of events whose delivery was
+//! interrupted by VM-exit, such as an EPT violation on the IDT or the stack.
+
+use bit_field::BitField;
+
+/// The type of an event, encoded in bits 10:8 of the IDT-vectoring information
+/// and EXITINTINFO.
+///
+/// See: Table 25-20. Format of the IDT-Vectoring Information Field
+/// See: Figure 15-4. EVENTINJ Field in the VMCB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum EventKind {
+    ExternalInterrupt = 0,
+    Nmi = 2,
+    HardwareException = 3,
+    SoftwareInterrupt = 4,
+    PrivilegedSoftwareException = 5,
+    SoftwareException = 6,
+}
+
+/// An event being delivered at VM-exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Event {
+    pub(crate) vector: u8,
+    pub(crate) kind: EventKind,
+    pub(crate) error_code: Option<u32>,
+}
+
+impl Event {
+    /// Decodes the lower 32 bits of the IDT-vectoring information or
+    /// EXITINTINFO, which share the layout, with `error_code`. Returns `None`
+    /// if the valid bit is clear, or the type is not one of [`EventKind`].
+    pub(crate) fn decode(info: u32, error_code: u32) -> Option<Self> {
+        if !info.get_bit(31) {
+            return None;
+        }
+        let kind = match info.get_bits(8..=10) {
+            0 => EventKind::ExternalInterrupt,
+            2 => EventKind::Nmi,
+            3 => EventKind::HardwareException,
+            4 => EventKind::SoftwareInterrupt,
+            5 => EventKind::PrivilegedSoftwareException,
+            6 => EventKind::SoftwareException,
+            _ => return None,
+        };
+        Some(Self {
+            vector: info.get_bits(0..=7) as u8,
+            kind,
+            error_code: info.get_bit(11).then_some(error_code),
+        })
+    }
+
+    /// Encodes the event into the lower 32 bits of the VM-entry
+    /// interruption-information field or EVENTINJ, with the valid bit set.
+    pub(crate) fn encode(&self) -> u32 {
+        let mut info = u32::from(self.vector);
+        let _ = info.set_bits(8..=10, self.kind as u32);
+        let _ = info.set_bit(11, self.error_code.is_some());
+        let _ = info.set_bit(31, true);
+        info
+    }
+}
+
+/// What to inject on VM-entry, given the event being delivered at VM-exit and
+/// the one the handler injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    /// Nothing is injected by the handler. Inject the event again.
+    Reinject,
+    /// Inject #DF(0) instead of either.
+    DoubleFault,
+    /// Inject the event of the handler, and the interrupted one on the next
+    /// VM-entry that injects nothing, which may be later than the processor
+    /// would deliver it.
+    Defer,
+    /// Inject the event of the handler. The interrupted one recurs when the
+    /// guest executes the instruction again.
+    Discard,
+    /// The combination causes triple fault. The processor enters the shutdown
+    /// state instead of injecting either.
+    TripleFault,
+}
+
+/// Returns how to combine `pending`, the event being delivered at VM-exit, with
+/// `injected`, the event injected by the handler if any.
+///
+/// See: Table 7-5. Conditions for Generating a Double Fault
+/// See: 8.2.9 Double-Fault Exception (#DF)
+pub(crate) fn resolve(pending: &Event, injected: Option<&Event>) -> Resolution {
+    const DB: u8 = 1;
+    const DF: u8 = 8;
+    const PF: u8 = 14;
+    const MC: u8 = 18;
+
+    let Some(injected) = injected else {
+        return Resolution::Reinject;
+    };
+    match pending.kind {
+        EventKind::ExternalInterrupt | EventKind::Nmi => return Resolution::Defer,
+        EventKind::SoftwareInterrupt
+        | EventKind::PrivilegedSoftwareException
+        | EventKind::SoftwareException => return Resolution::Discard,
+        EventKind::HardwareException => {}
+    }
+
+    // Faults recur when the guest executes the instruction again, but #DB
+    // traps and #MC do not, and are delivered after the event of the handler
+    // as the processor handles benign exceptions serially.
+    let serial = if matches!(pending.vector, DB | MC) {
+        Resolution::Defer
+    } else {
+        Resolution::Discard
+    };
+    if injected.kind != EventKind::HardwareException {
+        return serial;
+    }
+
+    let is_contributory = |vector| matches!(vector, 0 | 10..=13);
+    let second = injected.vector;
+    match pending.vector {
+        DF if is_contributory(second) || second == PF => Resolution::TripleFault,
+        PF if is_contributory(second) || second == PF => Resolution::DoubleFault,
+        first if is_contributory(first) && is_contributory(second) => Resolution::DoubleFault,
+        _ => serial,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exception(vector: u8) -> Event {
+        Event {
+            vector,
+            kind: EventKind::HardwareException,
+            error_code: None,
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        // #PF with an error code, and an external interrupt 0x30.
+        let page_fault = Event::decode(0x8000_0b0e, 2).unwrap();
+        assert_eq!(
+            page_fault,
+            Event {
+                vector: 14,
+                kind: EventKind::HardwareException,
+                error_code: Some(2),
+            }
+        );
+        assert_eq!(page_fault.encode(), 0x8000_0b0e);
+        let interrupt = Event::decode(0x8000_0030, 0xdead).unwrap();
+        assert_eq!(interrupt.kind, EventKind::ExternalInterrupt);
+        assert_eq!(interrupt.error_code, None);
+        assert_eq!(interrupt.encode(), 0x8000_0030);
+
+        assert_eq!(Event::decode(0x0000_0b0e, 0), None);
+        // Type 7, other event.
+        assert_eq!(Event::decode(0x8000_0700, 0), None);
+    }
+
+    #[test]
+    fn resolution() {
+        let interrupt = Event {
+            vector: 0x30,
+            kind: EventKind::ExternalInterrupt,
+            error_code: None,
+        };
+        let int3 = Event {
+            vector: 3,
+            kind: EventKind::SoftwareException,
+            error_code: None,
+        };
+        let (de, df, gp, pf, ud) = (
+            exception(0),
+            exception(8),
+            exception(13),
+            exception(14),
+            exception(6),
+        );
+
+        assert_eq!(resolve(&interrupt, None), Resolution::Reinject);
+        assert_eq!(resolve(&pf, None), Resolution::Reinject);
+        assert_eq!(resolve(&interrupt, Some(&gp)), Resolution::Defer);
+        assert_eq!(resolve(&int3, Some(&gp)), Resolution::Discard);
+
+        assert_eq!(resolve(&gp, Some(&de)), Resolution::DoubleFault);
+        assert_eq!(resolve(&pf, Some(&pf)), Resolution::DoubleFault);
+        assert_eq!(resolve(&pf, Some(&gp)), Resolution::DoubleFault);
+        assert_eq!(resolve(&gp, Some(&pf)), Resolution::Discard);
+        assert_eq!(resolve(&ud, Some(&gp)), Resolution::Discard);
+        assert_eq!(resolve(&gp, Some(&ud)), Resolution::Discard);
+        assert_eq!(resolve(&df, Some(&gp)), Resolution::TripleFault);
+        assert_eq!(resolve(&df, Some(&ud)), Resolution::Discard);
+    }
+
+    #[test]
+    fn benign_resolution() {
+        let nmi = Event {
+            vector: 2,
+            kind: EventKind::Nmi,
+            error_code: None,
+        };
+        let int3 = Event {
+            vector: 3,
+            kind: EventKind::SoftwareException,
+            error_code: None,
+        };
+        let (db, ud, gp, pf, mc) = (
+            exception(1),
+            exception(6),
+            exception(13),
+            exception(14),
+            exception(18),
+        );
+
+        // #DB and #MC do not recur, and are delivered after the other event.
+        assert_eq!(resolve(&db, Some(&gp)), Resolution::Defer);
+        assert_eq!(resolve(&db, Some(&pf)), Resolution::Defer);
+        assert_eq!(resolve(&db, Some(&int3)), Resolution::Defer);
+        assert_eq!(resolve(&mc, Some(&ud)), Resolution::Defer);
+        assert_eq!(resolve(&nmi, Some(&db)), Resolution::Defer);
+        assert_eq!(resolve(&nmi, Some(&int3)), Resolution::Defer);
+        // Faults recur.
+        assert_eq!(resolve(&ud, Some(&pf)), Resolution::Discard);
+        assert_eq!(resolve(&ud, Some(&nmi)), Resolution::Discard);
+    }
+}
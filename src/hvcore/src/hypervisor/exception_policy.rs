@@ -0,0 +1,200 @@
+//! This module implements the policy table of guest exceptions.
+//!
+//! Each exception vector has an [`ExceptionPolicy`] that decides whether the
+//! exception causes VM-exit, and what the host does with it. All vectors are
+//! [`ExceptionPolicy::Passthrough`] by default, that is, not intercepted. The
+//! table can be changed at any time with [`set_policy`], or by the guest with
+//! [`HC_EXCEPTION_POLICY`], and each processor updates its exception bitmap on
+//! its next VM-entry.
+//!
+//! INT3 and INTO are reported at the instruction, unlike when the exception is
+//! delivered to the guest. The host moves RIP past the instruction before
+//! applying the policy, so that reflecting or swallowing them does not execute
+//! them again. Swallowing a fault, on the other hand, resumes the guest at the
+//! faulting instruction, which faults again unless a callback fixes the cause.
+//!
+//! NMI, #MC and #SX cannot be configured, as the hypervisor handles them
+//! itself. #MC is always intercepted. See [`super::machine_check`].
+//!
+//! [`HC_EXCEPTION_POLICY`]: super::hypercall::HC_EXCEPTION_POLICY
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::RwLock;
+
+use super::{host::ExceptionInfo, machine_check::MC, registers::Registers};
+
+/// The number of exception vectors.
+const VECTOR_COUNT: usize = 32;
+
+const NMI: u8 = 2;
+const SX: u8 = 30;
+
+/// A function called with the guest registers on an intercepted exception.
+/// Returns `true` to reflect the exception to the guest, or `false` to
+/// swallow it.
+pub type ExceptionCallback = fn(&mut Registers, &ExceptionInfo) -> bool;
+
+/// What to do with an exception of a vector.
+#[derive(Debug, Clone, Copy)]
+pub enum ExceptionPolicy {
+    /// The exception does not cause VM-exit.
+    Passthrough,
+
+    /// The exception causes VM-exit and is injected back to the guest.
+    Reflect,
+
+    /// The exception causes VM-exit and is not delivered to the guest.
+    Swallow,
+
+    /// The exception causes VM-exit, and the callback decides whether to
+    /// reflect it.
+    Callback(ExceptionCallback),
+
+    /// The exception causes VM-exit and is reflected. If it occurs in the
+    /// address space with this CR3, a backtrace of the guest is logged as for
+    /// a breakpoint.
+    BreakpointFromCr3(u64),
+}
+
+impl ExceptionPolicy {
+    /// Decodes the policy from the arguments of
+    /// [`HC_EXCEPTION_POLICY`](super::hypercall::HC_EXCEPTION_POLICY): `kind`
+    /// is 0 for `Passthrough`, 1 for `Reflect`, 2 for `Swallow`, and 3 for
+    /// `BreakpointFromCr3` with `cr3`. Callbacks cannot be set by the guest.
+    pub fn from_raw(kind: u64, cr3: u64) -> Result<Self, ExceptionPolicyError> {
+        match kind {
+            0 => Ok(Self::Passthrough),
+            1 => Ok(Self::Reflect),
+            2 => Ok(Self::Swallow),
+            3 => Ok(Self::BreakpointFromCr3(cr3 & CR3_ADDRESS_MASK)),
+            _ => Err(ExceptionPolicyError::InvalidPolicy),
+        }
+    }
+
+    /// Returns `true` if the exception with this policy is reflected to the
+    /// guest. `cr3` is the current guest CR3 if known.
+    pub(crate) fn should_reflect(
+        self,
+        registers: &mut Registers,
+        info: &ExceptionInfo,
+        cr3: Option<u64>,
+    ) -> bool {
+        match self {
+            Self::Passthrough | Self::Reflect => true,
+            Self::Swallow => false,
+            Self::Callback(callback) => callback(registers, info),
+            Self::BreakpointFromCr3(target) => {
+                if cr3.is_some_and(|cr3| cr3 & CR3_ADDRESS_MASK == target) {
+                    log::debug!(
+                        "Breakpoint #{} from {:?}",
+                        info.vector,
+                        super::backtrace::capture(registers, cr3)
+                    );
+                }
+                true
+            }
+        }
+    }
+}
+
+/// The error type for [`set_policy`] and
+/// [`HC_EXCEPTION_POLICY`](super::hypercall::HC_EXCEPTION_POLICY). The value
+/// is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ExceptionPolicyError {
+    #[error("the vector is not an exception, or handled by the hypervisor")]
+    InvalidVector = 1,
+
+    #[error("the policy is unknown")]
+    InvalidPolicy = 2,
+}
+
+/// Sets the policy of the exception `vector`.
+pub fn set_policy(vector: u8, policy: ExceptionPolicy) -> Result<(), ExceptionPolicyError> {
+    if usize::from(vector) >= VECTOR_COUNT || matches!(vector, NMI | MC | SX) {
+        return Err(ExceptionPolicyError::InvalidVector);
+    }
+
+    let mut policies = POLICIES.write();
+    policies[usize::from(vector)] = policy;
+    BITMAP.store(bitmap_of(&policies), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Resets the policies of all exceptions to [`ExceptionPolicy::Passthrough`].
+pub fn reset() {
+    let mut policies = POLICIES.write();
+    *policies = [ExceptionPolicy::Passthrough; VECTOR_COUNT];
+    BITMAP.store(0, Ordering::Relaxed);
+}
+
+/// Returns the policy of the exception `vector`.
+pub fn policy(vector: u8) -> ExceptionPolicy {
+    POLICIES
+        .read()
+        .get(usize::from(vector))
+        .copied()
+        .unwrap_or(ExceptionPolicy::Passthrough)
+}
+
+/// Returns the exception bitmap for the current table, where a set bit makes
+/// the exception of the vector cause VM-exit. This includes
+/// [`REQUIRED_BITMAP`].
+pub(crate) fn bitmap() -> u32 {
+    BITMAP.load(Ordering::Relaxed) | REQUIRED_BITMAP
+}
+
+/// The exceptions the hypervisor always intercepts.
+pub(crate) const REQUIRED_BITMAP: u32 = 1 << MC;
+
+fn bitmap_of(policies: &[ExceptionPolicy; VECTOR_COUNT]) -> u32 {
+    policies
+        .iter()
+        .enumerate()
+        .filter(|(_, policy)| !matches!(policy, ExceptionPolicy::Passthrough))
+        .fold(0, |bitmap, (vector, _)| bitmap | 1 << vector)
+}
+
+/// The bits of CR3 that hold the address of the paging structures.
+const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+static POLICIES: RwLock<[ExceptionPolicy; VECTOR_COUNT]> =
+    RwLock::new([ExceptionPolicy::Passthrough; VECTOR_COUNT]);
+static BITMAP: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmap_of_policies() {
+        let mut policies = [ExceptionPolicy::Passthrough; VECTOR_COUNT];
+        assert_eq!(bitmap_of(&policies), 0);
+
+        policies[3] = ExceptionPolicy::BreakpointFromCr3(0x1000);
+        policies[14] = ExceptionPolicy::Swallow;
+        assert_eq!(bitmap_of(&policies), 1 << 3 | 1 << 14);
+    }
+
+    #[test]
+    fn from_raw() {
+        assert!(matches!(
+            ExceptionPolicy::from_raw(3, 0x1234_5fff),
+            Ok(ExceptionPolicy::BreakpointFromCr3(0x1234_5000))
+        ));
+        assert!(matches!(
+            ExceptionPolicy::from_raw(4, 0),
+            Err(ExceptionPolicyError::InvalidPolicy)
+        ));
+        assert_eq!(
+            set_policy(NMI, ExceptionPolicy::Reflect),
+            Err(ExceptionPolicyError::InvalidVector)
+        );
+        assert_eq!(
+            set_policy(32, ExceptionPolicy::Reflect),
+            Err(ExceptionPolicyError::InvalidVector)
+        );
+    }
+}
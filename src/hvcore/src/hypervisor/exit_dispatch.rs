@@ -0,0 +1,99 @@
+//! This module implements the tables that map architectural exit codes to the
+//! functions that translate VM-exits into [`VmExitReason`].
+
+use core::ops::RangeInclusive;
+
+use super::host::VmExitReason;
+
+/// A function that translates the VM-exit of `T` into [`VmExitReason`],
+/// handling the parts specific to the architecture if any.
+pub(crate) type ExitHandler<T> = fn(&mut T) -> VmExitReason;
+
+/// The [`ExitHandler`]s indexed by exit codes smaller than `N`: the basic exit
+/// reasons on Intel, and EXITCODE on AMD.
+///
+/// The table is built at compile time, and registering the same code twice
+/// fails the build. Exit codes without an entry go to the default handler of
+/// the backend, which logs the code and panics.
+pub(crate) struct DispatchTable<T, const N: usize> {
+    handlers: [Option<ExitHandler<T>>; N],
+}
+
+impl<T, const N: usize> DispatchTable<T, N> {
+    /// Returns the table without any handler.
+    pub(crate) const fn new() -> Self {
+        Self {
+            handlers: [None; N],
+        }
+    }
+
+    /// Returns the table with `handler` registered for `code`.
+    pub(crate) const fn with(self, code: u64, handler: ExitHandler<T>) -> Self {
+        self.with_range(code..=code, handler)
+    }
+
+    /// Returns the table with `handler` registered for all of `codes`.
+    pub(crate) const fn with_range(
+        mut self,
+        codes: RangeInclusive<u64>,
+        handler: ExitHandler<T>,
+    ) -> Self {
+        assert!(*codes.end() < N as u64, "the exit code is out of range");
+        let mut code = *codes.start() as usize;
+        while code <= *codes.end() as usize {
+            assert!(
+                self.handlers[code].is_none(),
+                "the exit code is already registered"
+            );
+            self.handlers[code] = Some(handler);
+            code += 1;
+        }
+        self
+    }
+
+    /// Returns the handler for `code`. Codes not smaller than `N`, such as
+    /// VMEXIT_INVALID on AMD, never have one.
+    pub(crate) fn get(&self, code: u64) -> Option<ExitHandler<T>> {
+        let index = usize::try_from(code).ok()?;
+        self.handlers.get(index).copied().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(u64);
+
+    static TABLE: DispatchTable<Counter, 8> = DispatchTable::<Counter, 8>::new()
+        .with(1, |counter| {
+            counter.0 += 1;
+            VmExitReason::Shutdown
+        })
+        .with_range(4..=6, |_| VmExitReason::Pause);
+
+    #[test]
+    fn dispatch() {
+        let mut counter = Counter(0);
+        let handler = TABLE.get(1).unwrap();
+        assert!(matches!(handler(&mut counter), VmExitReason::Shutdown));
+        assert_eq!(counter.0, 1);
+        for code in 4..=6 {
+            assert!(matches!(
+                TABLE.get(code).unwrap()(&mut counter),
+                VmExitReason::Pause
+            ));
+        }
+        for code in [0, 2, 3, 7, 8, u64::MAX] {
+            assert!(TABLE.get(code).is_none());
+        }
+    }
+
+    #[test]
+    #[should_panic = "already registered"]
+    fn duplicate() {
+        let _ = DispatchTable::<Counter, 8>::new()
+            .with_range(0..=3, |_| VmExitReason::Pause)
+            .with(3, |_| VmExitReason::Pause);
+    }
+}
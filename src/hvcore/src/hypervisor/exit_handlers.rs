@@ -0,0 +1,176 @@
+//! This module implements registration of VM-exit handlers.
+//!
+//! Consumers of this crate may implement [`VmExitHandler`] and register it with
+//! [`register`] to add behavior on VM-exits without modifying the architecture
+//! specific code. Registered handlers are called in the descending order of
+//! their priorities, before the default handling in the host.
+
+use alloc::{boxed::Box, vec::Vec};
+use spin::RwLock;
+
+use super::{
+    backtrace::{self, Backtrace},
+    gva,
+    host::Guest,
+    introspection::{IntrospectionError, linux::LinuxKernel, windows::WindowsKernel},
+    irq,
+    lbr::{GuestLastBranches, LastBranch},
+    registers::{FullGuestState, Registers},
+};
+
+pub use super::host::{
+    CrAccessInfo, ExceptionInfo, InstructionInfo, IoInfo, NestedPageFaultInfo, VmExitKind,
+    VmExitReason,
+};
+
+/// A handler of VM-exits.
+pub trait VmExitHandler: Send + Sync {
+    /// Returns the kinds of VM-exit this handler is called for.
+    fn kinds(&self) -> &[VmExitKind];
+
+    /// Returns the priority of this handler. Handlers with higher priority are
+    /// called first.
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Handles the VM-exit described by `context`.
+    fn handle(&self, context: &mut VmExitContext<'_>) -> VmExitAction;
+}
+
+/// What to do after a [`VmExitHandler`] returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitAction {
+    /// Call the next handler, or the default handling if there is none.
+    Continue,
+
+    /// The VM-exit is fully handled. Neither the remaining handlers nor the
+    /// default handling are called.
+    Handled,
+}
+
+/// The state of the guest passed to [`VmExitHandler`].
+#[derive(Debug)]
+pub struct VmExitContext<'a> {
+    registers: &'a mut Registers,
+    reason: &'a VmExitReason,
+    exception: Option<(u8, Option<u32>)>,
+    cr3: Option<u64>,
+    lstar: u64,
+    last_branches: GuestLastBranches,
+    state: FullGuestState,
+}
+
+impl VmExitContext<'_> {
+    /// Returns the reason of the VM-exit.
+    pub fn reason(&self) -> &VmExitReason {
+        self.reason
+    }
+
+    /// Returns the guest registers. Changes are reflected into the guest.
+    pub fn regs(&mut self) -> &mut Registers {
+        self.registers
+    }
+
+    /// Returns the system registers of the guest, such as CR3 and the CS
+    /// selector, as of the VM-exit.
+    pub fn state(&self) -> &FullGuestState {
+        &self.state
+    }
+
+    /// Advances the guest RIP to the next instruction, completing emulation of
+    /// the instruction that caused the VM-exit.
+    pub fn skip_instruction(&mut self) {
+        let next_rip = self
+            .reason
+            .next_rip()
+            .unwrap_or_else(|| panic!("{:?} is not caused by an instruction", self.reason));
+        self.registers.rip = next_rip;
+    }
+
+    /// Injects the exception `vector` with `error_code` to the guest.
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        self.exception = Some((vector, error_code));
+    }
+
+    /// Captures the call stack of the guest, for example, to attribute the
+    /// VM-exit to guest code paths. See [`super::backtrace`].
+    pub fn backtrace(&self) -> Backtrace {
+        backtrace::capture(self.registers, self.cr3)
+    }
+
+    /// Captures the branches the guest took last, from the most recent one,
+    /// for example, to see how the guest reached a hook site. Empty if the
+    /// processor does not support it. See [`super::lbr`].
+    pub fn last_branches(&self) -> Vec<LastBranch> {
+        self.last_branches.read()
+    }
+
+    /// Translates the GVA `gva` with the current guest paging structures, and
+    /// returns the GPA. See [`super::introspection`] for requirements.
+    pub fn translate_gva(&self, gva: u64) -> Option<u64> {
+        let space = gva::address_space(self.cr3?)?;
+        space.translate(gva).map(|translation| translation.gpa)
+    }
+
+    /// Locates the kernel of the guest, assuming it is Windows.
+    pub fn windows_kernel(&self) -> Result<WindowsKernel, IntrospectionError> {
+        let cr3 = self.cr3.ok_or(IntrospectionError::MemoryUnavailable)?;
+        WindowsKernel::locate(cr3, self.lstar)
+    }
+
+    /// Locates the kernel of the guest, assuming it is Linux.
+    pub fn linux_kernel(&self) -> Result<LinuxKernel, IntrospectionError> {
+        let cr3 = self.cr3.ok_or(IntrospectionError::MemoryUnavailable)?;
+        LinuxKernel::locate(cr3, self.lstar)
+    }
+}
+
+/// Registers `handler` to be called on VM-exits of its kinds.
+pub fn register(handler: Box<dyn VmExitHandler>) {
+    let mut handlers = HANDLERS.write();
+    let index = handlers.partition_point(|h| h.priority() >= handler.priority());
+    handlers.insert(index, handler);
+}
+
+/// Calls registered handlers for the VM-exit `reason`, and returns `true` if
+/// any of them handled it.
+pub(crate) fn dispatch<T: Guest>(guest: &mut T, reason: &VmExitReason) -> bool {
+    let Some(kind) = reason.kind() else {
+        return false;
+    };
+
+    irq::debug_assert_may_block();
+    let handlers = HANDLERS.read();
+    if !handlers.iter().any(|h| h.kinds().contains(&kind)) {
+        return false;
+    }
+
+    let cr3 = guest.long_mode_cr3();
+    let lstar = guest.lstar();
+    let last_branches = guest.last_branches();
+    let state = guest.full_state();
+    let mut context = VmExitContext {
+        cr3,
+        lstar,
+        last_branches,
+        state,
+        registers: guest.regs(),
+        reason,
+        exception: None,
+    };
+    let mut handled = false;
+    for handler in handlers.iter().filter(|h| h.kinds().contains(&kind)) {
+        if handler.handle(&mut context) == VmExitAction::Handled {
+            handled = true;
+            break;
+        }
+    }
+
+    if let Some((vector, error_code)) = context.exception {
+        guest.inject_exception(vector, error_code);
+    }
+    handled
+}
+
+static HANDLERS: RwLock<Vec<Box<dyn VmExitHandler>>> = RwLock::new(Vec::new());
@@ -0,0 +1,107 @@
+//! This module implements the minimal-exit performance profile.
+//!
+//! With `HvConfig::minimal_exits`, each processor audits its VMCS or VMCB right
+//! after initialization and logs every intercept enabled beyond the minimum:
+//! the controls the processor does not allow to clear, and the intercepts the
+//! hypervisor requires, such as CPUID, VMCALL and those for starting APs on
+//! UEFI. Optional features that add intercepts, such as EPT views bound to
+//! processes, show up in the audit.
+//!
+//! Once virtualization completes, VM-exits are counted as the steady-state
+//! exit rate, which is included in [`super::exit_stats::write_summary`] and
+//! thus the crash dump report. Under a steady workload, the rate should stay
+//! close to that of the unavoidable VM-exits, such as CPUID.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use super::{SHARED_HOST_DATA, exit_stats};
+
+/// Logs the optional `intercepts` enabled on the processor `id`, if the
+/// profile is enabled.
+pub(crate) fn audit(id: usize, intercepts: &[String]) {
+    if !enabled() {
+        return;
+    }
+    let mut message = String::new();
+    let _ = write_audit(&mut message, id, intercepts);
+    if intercepts.is_empty() {
+        log::info!("{message}");
+    } else {
+        log::warn!("{message}");
+    }
+}
+
+/// Starts counting the steady-state VM-exits, if the profile is enabled.
+pub(crate) fn enter_steady_state() {
+    if enabled() {
+        exit_stats::mark_steady_state();
+    }
+}
+
+fn enabled() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.minimal_exits
+}
+
+fn write_audit(out: &mut dyn Write, id: usize, intercepts: &[String]) -> fmt::Result {
+    if intercepts.is_empty() {
+        return write!(out, "#{id}: No optional intercept is enabled");
+    }
+    write!(out, "#{id}: Optional intercepts are enabled:")?;
+    for intercept in intercepts {
+        write!(out, " {intercept}")?;
+    }
+    Ok(())
+}
+
+/// Appends the names of the bits set in `value`, or the raw value of the bits
+/// without names, to `intercepts`.
+pub(crate) fn push_bits(
+    intercepts: &mut Vec<String>,
+    field: &str,
+    value: u64,
+    names: &[(u64, &str)],
+) {
+    let mut unnamed = value;
+    for &(bit, name) in names {
+        if value & bit != 0 {
+            intercepts.push(String::from(name));
+            unnamed &= !bit;
+        }
+    }
+    if unnamed != 0 {
+        let mut intercept = String::new();
+        let _ = write!(intercept, "{field}={unnamed:#x}");
+        intercepts.push(intercept);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn audit_message() {
+        let mut intercepts = Vec::new();
+        push_bits(&mut intercepts, "misc1", 0, &[(1 << 0, "INTR")]);
+        let mut message = String::new();
+        write_audit(&mut message, 1, &intercepts).unwrap();
+        assert_eq!(message, "#1: No optional intercept is enabled");
+
+        push_bits(
+            &mut intercepts,
+            "misc1",
+            0b1011,
+            &[(1 << 0, "INTR"), (1 << 1, "NMI")],
+        );
+        assert_eq!(intercepts, vec!["INTR", "NMI", "misc1=0x8"]);
+        message.clear();
+        write_audit(&mut message, 1, &intercepts).unwrap();
+        assert_eq!(
+            message,
+            "#1: Optional intercepts are enabled: INTR NMI misc1=0x8"
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! This module implements counting of VM-exits for each processor.
+//!
+//! The host records every VM-exit with lock-free counters, so that the counts
+//! can be read at any time, including when the system crashes. See
+//! [`super::crash_dump`].
+//!
+//! With `HvConfig::pause_loop_exiting`, the count of [`VmExitKind::Pause`]
+//! tells how often guest spin loops on each processor ran long, that is,
+//! spinlock contention.
+//!
+//! With `HvConfig::smi_storm`, the summary also reports the number of SMIs
+//! each processor has taken since virtualization. See [`super::smi`].
+
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{
+    host::{VmExitKind, VmExitReason},
+    percpu::PerCpu,
+    smi,
+};
+
+/// The number of processors whose VM-exits are counted. VM-exits of processors
+/// with larger IDs are not counted.
+pub use super::percpu::MAX_PROCESSORS;
+
+/// All kinds of VM-exit, in the order of their values.
+pub(crate) const KINDS: [VmExitKind; 22] = [
+    VmExitKind::Cpuid,
+    VmExitKind::Rdmsr,
+    VmExitKind::Wrmsr,
+    VmExitKind::XSetBv,
+    VmExitKind::CrAccess,
+    VmExitKind::IoInstruction,
+    VmExitKind::Exception,
+    VmExitKind::Hlt,
+    VmExitKind::Hypercall,
+    VmExitKind::Shutdown,
+    VmExitKind::MonitorTrap,
+    VmExitKind::NestedPageFault,
+    VmExitKind::Rdtsc,
+    VmExitKind::Rdtscp,
+    VmExitKind::Rdrand,
+    VmExitKind::Pause,
+    VmExitKind::DescriptorTableAccess,
+    VmExitKind::Rdpmc,
+    VmExitKind::Monitor,
+    VmExitKind::Mwait,
+    VmExitKind::CacheInvalidation,
+    VmExitKind::Smi,
+];
+
+/// The VM-exits of a processor.
+struct ExitStatistics {
+    /// The counts of each kind, followed by that of VM-exits handled entirely
+    /// by the architecture specific code.
+    counts: [AtomicU64; KINDS.len() + 1],
+
+    /// The guest RIP on the last VM-exit.
+    last_rip: AtomicU64,
+
+    /// The total count when the steady state began. See [`mark_steady_state`].
+    steady_state_base: AtomicU64,
+}
+
+impl ExitStatistics {
+    const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; KINDS.len() + 1],
+            last_rip: AtomicU64::new(0),
+            steady_state_base: AtomicU64::new(0),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Records the VM-exit `reason` of the processor `id` at the guest `rip`.
+pub(crate) fn record(id: usize, reason: &VmExitReason, rip: u64) {
+    let Some(statistics) = STATISTICS.get(id) else {
+        return;
+    };
+    let index = reason.kind().map_or(KINDS.len(), |kind| kind as usize);
+    statistics.counts[index].fetch_add(1, Ordering::Relaxed);
+    statistics.last_rip.store(rip, Ordering::Relaxed);
+}
+
+/// Returns the number of VM-exits of `kind` on the processor `id`.
+pub fn count(id: usize, kind: VmExitKind) -> u64 {
+    STATISTICS.get(id).map_or(0, |statistics| {
+        statistics.counts[kind as usize].load(Ordering::Relaxed)
+    })
+}
+
+/// Returns the total number of VM-exits on the processor `id`.
+pub fn total(id: usize) -> u64 {
+    STATISTICS.get(id).map_or(0, ExitStatistics::total)
+}
+
+/// The number of VM-exits over a number of TSC cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitRate {
+    pub exits: u64,
+    pub cycles: u64,
+}
+
+impl ExitRate {
+    /// Returns the number of VM-exits per million TSC cycles.
+    pub fn per_million_cycles(self) -> u64 {
+        if self.cycles == 0 {
+            return 0;
+        }
+        (u128::from(self.exits) * 1_000_000 / u128::from(self.cycles)) as u64
+    }
+}
+
+/// Marks the current point as the beginning of the steady state, that is, the
+/// system runs its workload after virtualization completed. VM-exits from then
+/// on are counted for [`steady_state_rate`].
+pub(crate) fn mark_steady_state() {
+    for statistics in STATISTICS.iter() {
+        statistics
+            .steady_state_base
+            .store(statistics.total(), Ordering::Relaxed);
+    }
+    STEADY_STATE_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+}
+
+/// Returns the VM-exits of the processor `id` since the steady state began, or
+/// `None` if it has not.
+pub fn steady_state_rate(id: usize) -> Option<ExitRate> {
+    let start = STEADY_STATE_TSC.load(Ordering::Relaxed);
+    let statistics = STATISTICS.get(id)?;
+    (start != 0).then(|| ExitRate {
+        exits: statistics.total() - statistics.steady_state_base.load(Ordering::Relaxed),
+        cycles: unsafe { _rdtsc() }.wrapping_sub(start),
+    })
+}
+
+/// Writes the counts of VM-exits of all processors that had any, one line for
+/// each processor, followed by the steady-state exit rate if available.
+pub fn write_summary(out: &mut dyn fmt::Write) -> fmt::Result {
+    for (id, statistics) in STATISTICS.iter().enumerate() {
+        let total = statistics.total();
+        if total == 0 {
+            continue;
+        }
+        write!(
+            out,
+            "#{id}: total={total} last_rip={:#x}",
+            statistics.last_rip.load(Ordering::Relaxed)
+        )?;
+        for (kind, count) in KINDS.iter().zip(&statistics.counts) {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                write!(out, " {kind:?}={count}")?;
+            }
+        }
+        write!(
+            out,
+            " Other={}",
+            statistics.counts[KINDS.len()].load(Ordering::Relaxed)
+        )?;
+        if let Some(rate) = steady_state_rate(id) {
+            write!(
+                out,
+                " steady_state={} in {} cycles ({}/Mcycles)",
+                rate.exits,
+                rate.cycles,
+                rate.per_million_cycles()
+            )?;
+        }
+        if let Some(count) = smi::count(id) {
+            write!(out, " smi={count}")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+static STATISTICS: PerCpu<ExitStatistics> =
+    PerCpu::new([const { ExitStatistics::new() }; MAX_PROCESSORS]);
+
+/// The TSC when the steady state began, or 0 if it has not.
+static STEADY_STATE_TSC: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::hypervisor::host::InstructionInfo;
+
+    #[test]
+    fn kinds_are_in_order() {
+        for (i, kind) in KINDS.iter().enumerate() {
+            assert_eq!(*kind as usize, i);
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let id = MAX_PROCESSORS - 1;
+        let cpuid = VmExitReason::Cpuid(InstructionInfo { next_rip: 0 });
+        record(id, &cpuid, 0x1000);
+        record(id, &cpuid, 0x2000);
+        record(id, &VmExitReason::InitSignal, 0x3000);
+        record(MAX_PROCESSORS, &cpuid, 0x4000);
+        assert_eq!(count(id, VmExitKind::Cpuid), 2);
+        assert_eq!(count(MAX_PROCESSORS, VmExitKind::Cpuid), 0);
+
+        let mut summary = String::new();
+        write_summary(&mut summary).unwrap();
+        assert!(summary.ends_with(&alloc::format!(
+            "#{id}: total=3 last_rip=0x3000 Cpuid=2 Other=1\n"
+        )));
+    }
+
+    #[test]
+    fn rate() {
+        let rate = ExitRate {
+            exits: 30,
+            cycles: 3_000_000_000,
+        };
+        assert_eq!(rate.per_million_cycles(), 0);
+        let rate = ExitRate {
+            exits: 3_000,
+            cycles: 1_500_000_000,
+        };
+        assert_eq!(rate.per_million_cycles(), 2);
+        assert_eq!(
+            ExitRate {
+                exits: 1,
+                cycles: 0
+            }
+            .per_million_cycles(),
+            0
+        );
+    }
+}
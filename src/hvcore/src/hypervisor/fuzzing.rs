@@ -0,0 +1,171 @@
+//! This module exposes the code that parses guest-controlled data to the fuzz
+//! targets under `fuzz/`.
+//!
+//! Each function takes arbitrary bytes, feeds them to the parser as a guest
+//! would, and panics only if the parser breaks an invariant the host relies
+//! on. The parser itself must never panic, as a panic in the host brings down
+//! the system.
+//!
+//! ```shell
+//! cargo +nightly fuzz run gva_walk
+//! ```
+
+use x86::cpuid::CpuIdResult;
+
+#[cfg(feature = "amd")]
+use super::amd;
+#[cfg(feature = "intel")]
+use super::intel;
+use super::{
+    HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, OUR_HV_VENDOR_NAME_EBX, decoder,
+    gva::GuestAddressSpace,
+    host::{MsrAction, MsrPolicy, filter_cpuid, is_valid_xcr0},
+    hyperv, vmx_hiding,
+};
+
+/// Fuzzes the filter of CPUID results the guest sees.
+pub fn cpuid_filter(data: &[u8]) {
+    let mut data = data;
+    let leaf = take_u64(&mut data) as u32;
+    let result = CpuIdResult {
+        eax: take_u64(&mut data) as u32,
+        ebx: take_u64(&mut data) as u32,
+        ecx: take_u64(&mut data) as u32,
+        edx: take_u64(&mut data) as u32,
+    };
+    let filtered = filter_cpuid(leaf, result);
+    if leaf == 1 {
+        // VMX must be hidden from the guest.
+        assert!(filtered.ecx & (1 << 5) == 0);
+    } else if leaf == HV_CPUID_VENDOR_AND_MAX_FUNCTIONS {
+        assert_eq!(filtered.ebx, OUR_HV_VENDOR_NAME_EBX);
+    }
+}
+
+/// Fuzzes the validation of XCR0 values the guest writes with XSETBV.
+pub fn xcr0_policy(data: &[u8]) {
+    let mut data = data;
+    let value = take_u64(&mut data);
+    let supported = take_u64(&mut data);
+    if is_valid_xcr0(value, supported) {
+        // Only supported bits with x87 state may be written.
+        assert!(value & !supported == 0 && value & 1 != 0);
+    }
+}
+
+/// Fuzzes the policy on MSRs the guest reads and writes with RDMSR and WRMSR.
+pub fn msr_policy(data: &[u8]) {
+    let mut data = data;
+    let msr = take_u64(&mut data) as u32;
+    let flags = take_u64(&mut data);
+    let policy = MsrPolicy {
+        pt_host_owned: flags & 1 != 0,
+        hypervisor_advertised: flags & 2 != 0,
+    };
+    let read = policy.action(msr, false);
+    let write = policy.action(msr, true);
+
+    // The VMX capability MSRs and the synthetic MSRs must never reach the
+    // processor, and MSRs that do not exist for reads do not for writes.
+    if vmx_hiding::is_unreadable(msr) || hyperv::is_synthetic_msr(msr) {
+        assert!(read != MsrAction::Passthrough && write != MsrAction::Passthrough);
+    }
+    if read == MsrAction::InjectGp {
+        assert_eq!(write, MsrAction::InjectGp);
+    }
+}
+
+/// Fuzzes the decoders of exit qualifications (Intel) and EXITINFO (AMD).
+pub fn exit_qualifications(data: &[u8]) {
+    let mut data = data;
+    let qualification = take_u64(&mut data);
+    let address = take_u64(&mut data);
+    #[cfg_attr(not(feature = "amd"), allow(unused_variables))]
+    let exit_code = take_u64(&mut data);
+
+    #[cfg(feature = "intel")]
+    {
+        let info = intel::cr_access_info(address, qualification);
+        assert!(info.cr <= 0xf && info.gpr.is_some_and(|gpr| gpr <= 0xf));
+        let info = intel::io_info(address, qualification);
+        assert!((1..=8).contains(&info.size));
+        let info = intel::ept_violation_info(address, qualification);
+        assert_eq!(info.gpa, address);
+    }
+
+    #[cfg(feature = "amd")]
+    {
+        let info = amd::cr_access_info(address, exit_code, qualification, exit_code & 1 != 0);
+        assert!(info.cr <= 0xf && info.gpr.is_none_or(|gpr| gpr <= 0xf));
+        let info = amd::io_info(qualification, address);
+        assert!(info.size <= 7);
+        let info = amd::nested_page_fault_info(qualification, address);
+        assert!(!(info.execute && info.read));
+    }
+}
+
+/// Fuzzes the GVA page walker. `data` is the CR3, the GVA, and guest physical
+/// memory starting at 0.
+pub fn gva_walk(data: &[u8]) {
+    let mut data = data;
+    let cr3 = take_u64(&mut data);
+    let gva = take_u64(&mut data);
+    let memory = data;
+    let space = GuestAddressSpace::new(cr3, |gpa| {
+        let start = usize::try_from(gpa).ok()?;
+        let bytes = memory.get(start..start.checked_add(8)?)?;
+        (gpa.is_multiple_of(8)).then(|| u64::from_le_bytes(bytes.try_into().unwrap()))
+    });
+
+    if let Some(translation) = space.translate(gva) {
+        // Pages are at least 4KB, so the page offset is preserved.
+        assert_eq!(translation.gpa & 0xfff, gva & 0xfff);
+    }
+    let mut buffer = [0u8; 0x20];
+    let _ = space.read(gva, &mut buffer);
+    let _ = space.read_u16(gva);
+    let _ = space.read_u32(gva);
+    let _ = space.read_u64(gva);
+}
+
+/// Fuzzes the decoder of instructions that caused VM-exit, such as MMIO.
+pub fn instruction_decoder(data: &[u8]) {
+    if let Some(instruction) = decoder::decode(data) {
+        assert!(instruction.length <= data.len().min(decoder::MAX_INSTRUCTION_LENGTH));
+    }
+}
+
+/// Takes the next 8 bytes from `data` as a little-endian value, padding with 0
+/// if `data` is shorter.
+fn take_u64(data: &mut &[u8]) -> u64 {
+    let (value, rest) = data.split_at(data.len().min(8));
+    let mut bytes = [0u8; 8];
+    bytes[..value.len()].copy_from_slice(value);
+    *data = rest;
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn seeds() {
+        let seeds: [Vec<u8>; 4] = [
+            Vec::new(),
+            [0u8; 0x40].to_vec(),
+            [0xffu8; 0x40].to_vec(),
+            (0u8..=0xff).collect(),
+        ];
+        for seed in &seeds {
+            cpuid_filter(seed);
+            xcr0_policy(seed);
+            msr_policy(seed);
+            exit_qualifications(seed);
+            gva_walk(seed);
+            instruction_decoder(seed);
+        }
+    }
+}
@@ -0,0 +1,564 @@
+//! This module implements a minimal GDB remote stub for debugging the host
+//! over the serial port.
+//!
+//! When `HvConfig::gdb_stub` is set, #DB and #BP in the host, and panics stop
+//! the processor and wait for GDB on COM1, which can be attached with:
+//! ```text
+//! (gdb) set architecture i386:x86-64
+//! (gdb) target remote /dev/ttyUSB0
+//! ```
+//! The stub supports reading registers, reading and writing memory, single-step
+//! and continue. Breakpoints are set by GDB writing INT3 into memory. Execution
+//! cannot be resumed after a panic.
+//!
+//! Only the host IDT reaches the stub, that is, #DB and #BP in the host are
+//! handled only when `SharedHostData::idt` is given. Memory is accessed
+//! through the identity mapping, as the GVA translation does, thus, is only
+//! available when `SharedHostData::pt` is given. The log is written to the
+//! same serial port, and its output while GDB is attached confuses GDB.
+//!
+//! See: Appendix E Remote Serial Protocol, Debugging with GDB
+
+use core::fmt::Write;
+
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::{
+    SHARED_HOST_DATA, crash_dump::BufferWriter, gva, paging_structures::IDENTITY_MAP_SIZE,
+    registers::Registers, serial_logger, x86_instructions::cr3,
+};
+
+/// The maximum size of a packet payload, advertised with `qSupported`.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The signal reported to GDB when the processor stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Signal {
+    /// #DB or #BP, that is, single-step or breakpoint.
+    Trap = 5,
+
+    /// Panic, or a processor frozen after the guest triple-faulted.
+    Abort = 6,
+}
+
+/// How execution continues after the stub returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resume {
+    Continue,
+    Step,
+    Detach,
+}
+
+/// The registers reported to GDB, in the order of the `g` packet for x86-64.
+/// The registers GDB expects after GS, such as x87 ones, are reported as
+/// unavailable by omitting them.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct GdbRegisters {
+    /// RAX, RBX, RCX, RDX, RSI, RDI, RBP, RSP, R8-R15 and RIP.
+    pub(crate) gprs: [u64; 17],
+    pub(crate) eflags: u32,
+    pub(crate) cs: u32,
+    pub(crate) ss: u32,
+}
+
+impl From<&Registers> for GdbRegisters {
+    fn from(regs: &Registers) -> Self {
+        Self {
+            gprs: [
+                regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+                regs.rip,
+            ],
+            eflags: regs.rflags as u32,
+            cs: u32::from(x86::segmentation::cs().bits()),
+            ss: u32::from(x86::segmentation::ss().bits()),
+        }
+    }
+}
+
+/// Returns whether the stub is enabled.
+pub(crate) fn enabled() -> bool {
+    SHARED_HOST_DATA
+        .get()
+        .is_some_and(|shared_host| shared_host.config.gdb_stub)
+}
+
+/// Reports the stop with `signal` to GDB and serves its requests until it
+/// resumes execution. Only one processor talks to GDB at a time.
+pub(crate) fn stop(signal: Signal, registers: &GdbRegisters) -> Resume {
+    let mut state = STUB.lock();
+    let resume = serve(
+        &mut serial_logger::debug_port(),
+        &mut HostMemory,
+        registers,
+        signal,
+        state.waiting_for_stop,
+    );
+    state.waiting_for_stop = resume != Resume::Detach;
+    resume
+}
+
+/// Serves GDB on panic until it detaches. Does nothing if the stub is disabled
+/// or already in use, for example, when the stub itself panicked.
+pub(crate) fn stop_on_panic() {
+    if !enabled() {
+        return;
+    }
+    let Some(mut state) = STUB.try_lock() else {
+        return;
+    };
+    let registers = GdbRegisters::from(&Registers::capture_current());
+    let mut port = serial_logger::debug_port();
+    while serve(
+        &mut port,
+        &mut HostMemory,
+        &registers,
+        Signal::Abort,
+        state.waiting_for_stop,
+    ) != Resume::Detach
+    {
+        // Execution cannot be resumed. Report the stop again.
+        state.waiting_for_stop = true;
+    }
+    state.waiting_for_stop = false;
+}
+
+/// The byte stream GDB is connected through.
+pub(crate) trait Connection {
+    fn read_byte(&mut self) -> u8;
+    fn write_byte(&mut self, data: u8);
+}
+
+impl Connection for serial_logger::Uart {
+    fn read_byte(&mut self) -> u8 {
+        self.read_byte()
+    }
+
+    fn write_byte(&mut self, data: u8) {
+        self.write_byte(data);
+    }
+}
+
+/// The memory GDB reads and writes.
+pub(crate) trait Memory {
+    fn read(&mut self, address: u64, buffer: &mut [u8]) -> Option<()>;
+    fn write(&mut self, address: u64, data: &[u8]) -> Option<()>;
+}
+
+/// The memory of the host, accessed through the identity mapping.
+struct HostMemory;
+
+impl HostMemory {
+    /// Returns the pointer to `address` in the identity mapping, or `None` if
+    /// it is not mapped with the current paging structures.
+    fn translate(address: u64) -> Option<*mut u8> {
+        let pa = gva::address_space(cr3())?.translate(address)?.gpa;
+        (pa < IDENTITY_MAP_SIZE).then_some(pa as *mut u8)
+    }
+
+    /// Calls `access` for each part of `address..address+size` within a page.
+    fn for_each_page(
+        address: u64,
+        size: usize,
+        mut access: impl FnMut(*mut u8, core::ops::Range<usize>),
+    ) -> Option<()> {
+        let mut offset = 0;
+        while offset < size {
+            let current = address.checked_add(offset as u64)?;
+            let in_page = BASE_PAGE_SIZE - (current as usize % BASE_PAGE_SIZE);
+            let len = in_page.min(size - offset);
+            access(Self::translate(current)?, offset..offset + len);
+            offset += len;
+        }
+        Some(())
+    }
+}
+
+impl Memory for HostMemory {
+    fn read(&mut self, address: u64, buffer: &mut [u8]) -> Option<()> {
+        Self::for_each_page(address, buffer.len(), |ptr, range| {
+            let destination = &mut buffer[range];
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, destination.as_mut_ptr(), destination.len())
+            };
+        })
+    }
+
+    fn write(&mut self, address: u64, data: &[u8]) -> Option<()> {
+        // Check the whole range first, so that a write is all or nothing.
+        Self::for_each_page(address, data.len(), |_, _| {})?;
+        Self::for_each_page(address, data.len(), |ptr, range| {
+            let source = &data[range];
+            unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), ptr, source.len()) };
+        })
+    }
+}
+
+/// A request from GDB.
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    HaltReason,
+    ReadRegisters,
+    ReadMemory {
+        address: u64,
+        len: usize,
+    },
+    WriteMemory {
+        address: u64,
+        len: usize,
+        data: &'a [u8],
+    },
+    Step,
+    Continue,
+    Detach,
+    Kill,
+    Supported,
+    Unsupported,
+}
+
+impl<'a> Command<'a> {
+    /// Parses the payload of a packet, or returns `None` if it is malformed.
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (&kind, args) = packet.split_first()?;
+        Some(match kind {
+            b'?' => Self::HaltReason,
+            b'g' => Self::ReadRegisters,
+            b'm' => {
+                let (address, len) = split_once(args, b',')?;
+                Self::ReadMemory {
+                    address: parse_hex(address)?,
+                    len: usize::try_from(parse_hex(len)?).ok()?,
+                }
+            }
+            b'M' => {
+                let (location, data) = split_once(args, b':')?;
+                let (address, len) = split_once(location, b',')?;
+                let len = usize::try_from(parse_hex(len)?).ok()?;
+                if data.len() != len * 2 {
+                    return None;
+                }
+                Self::WriteMemory {
+                    address: parse_hex(address)?,
+                    len,
+                    data,
+                }
+            }
+            // The optional address to resume at is not supported.
+            b's' => Self::Step,
+            b'c' => Self::Continue,
+            b'D' => Self::Detach,
+            b'k' => Self::Kill,
+            b'q' if args.starts_with(b"Supported") => Self::Supported,
+            _ => Self::Unsupported,
+        })
+    }
+}
+
+/// Serves requests of GDB until it resumes execution. If GDB is waiting for
+/// the stop after resuming, the stop is reported first.
+fn serve(
+    connection: &mut impl Connection,
+    memory: &mut impl Memory,
+    registers: &GdbRegisters,
+    signal: Signal,
+    waiting_for_stop: bool,
+) -> Resume {
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut reply = [0u8; PACKET_SIZE];
+    let mut data = [0u8; PACKET_SIZE / 2];
+
+    if waiting_for_stop {
+        let mut writer = BufferWriter::new(&mut reply);
+        let _ = write!(writer, "S{:02x}", signal as u8);
+        let len = writer.len();
+        send(connection, &reply[..len]);
+    }
+
+    loop {
+        let len = receive(connection, &mut packet);
+        let mut writer = BufferWriter::new(&mut reply);
+        let resume = match Command::parse(&packet[..len]) {
+            None => {
+                let _ = writer.write_str("E01");
+                None
+            }
+            Some(Command::HaltReason) => {
+                let _ = write!(writer, "S{:02x}", signal as u8);
+                None
+            }
+            Some(Command::ReadRegisters) => {
+                for gpr in registers.gprs {
+                    write_hex(&mut writer, &gpr.to_le_bytes());
+                }
+                for value in [registers.eflags, registers.cs, registers.ss] {
+                    write_hex(&mut writer, &value.to_le_bytes());
+                }
+                None
+            }
+            Some(Command::ReadMemory { address, len }) => {
+                let buffer = &mut data[..len.min(PACKET_SIZE / 2)];
+                if memory.read(address, buffer).is_some() {
+                    write_hex(&mut writer, buffer);
+                } else {
+                    let _ = writer.write_str("E14");
+                }
+                None
+            }
+            Some(Command::WriteMemory {
+                address,
+                len,
+                data: hex,
+            }) => {
+                let buffer = &mut data[..len.min(PACKET_SIZE / 2)];
+                let written = decode_hex(hex, buffer).and_then(|()| memory.write(address, buffer));
+                let _ = writer.write_str(if written.is_some() { "OK" } else { "E14" });
+                None
+            }
+            Some(Command::Step) => Some(Resume::Step),
+            Some(Command::Continue) => Some(Resume::Continue),
+            Some(Command::Detach) => {
+                let _ = writer.write_str("OK");
+                Some(Resume::Detach)
+            }
+            // "The kill request does not have a reply."
+            Some(Command::Kill) => return Resume::Detach,
+            Some(Command::Supported) => {
+                let _ = write!(writer, "PacketSize={PACKET_SIZE:x}");
+                None
+            }
+            // "an empty response means the command is not supported"
+            Some(Command::Unsupported) => None,
+        };
+
+        // Resuming is not replied until the processor stops again.
+        if !matches!(resume, Some(Resume::Continue | Resume::Step)) {
+            let len = writer.len();
+            send(connection, &reply[..len]);
+        }
+        if let Some(resume) = resume {
+            return resume;
+        }
+    }
+}
+
+/// Receives a packet `$<payload>#<checksum>` into `buffer` and returns the
+/// size of the payload. Packets with a wrong checksum, or too large for
+/// `buffer` are rejected with `-`, and GDB retransmits them.
+fn receive(connection: &mut impl Connection, buffer: &mut [u8]) -> usize {
+    loop {
+        // Skip acknowledgments and interrupts (Ctrl-C) outside packets.
+        while connection.read_byte() != b'$' {}
+
+        let mut len = 0;
+        loop {
+            let byte = connection.read_byte();
+            if byte == b'#' {
+                break;
+            }
+            if let Some(slot) = buffer.get_mut(len) {
+                *slot = byte;
+            }
+            len += 1;
+        }
+        let high = connection.read_byte();
+        let low = connection.read_byte();
+        let expected = hex_digit(high).zip(hex_digit(low)).map(|(h, l)| h << 4 | l);
+        if len <= buffer.len() && expected == Some(checksum(&buffer[..len])) {
+            connection.write_byte(b'+');
+            return len;
+        }
+        connection.write_byte(b'-');
+    }
+}
+
+/// Sends a packet with `payload` until GDB acknowledges it.
+fn send(connection: &mut impl Connection, payload: &[u8]) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    loop {
+        connection.write_byte(b'$');
+        for byte in payload {
+            connection.write_byte(*byte);
+        }
+        let checksum = checksum(payload);
+        connection.write_byte(b'#');
+        connection.write_byte(HEX[usize::from(checksum >> 4)]);
+        connection.write_byte(HEX[usize::from(checksum & 0xf)]);
+
+        loop {
+            match connection.read_byte() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the checksum of a packet, the sum of the payload modulo 256.
+fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    char::from(byte).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes.iter().try_fold(0, |value, byte| {
+        Some(value << 4 | u64::from(hex_digit(*byte)?))
+    })
+}
+
+fn decode_hex(hex: &[u8], buffer: &mut [u8]) -> Option<()> {
+    for (byte, [high, low]) in buffer.iter_mut().zip(hex.as_chunks::<2>().0) {
+        *byte = hex_digit(*high)? << 4 | hex_digit(*low)?;
+    }
+    Some(())
+}
+
+fn write_hex(writer: &mut BufferWriter<'_>, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(writer, "{byte:02x}");
+    }
+}
+
+/// The state of the stub shared by all processors.
+struct StubState {
+    /// Whether GDB resumed execution and is waiting for the next stop.
+    waiting_for_stop: bool,
+}
+
+static STUB: Mutex<StubState> = Mutex::new(StubState {
+    waiting_for_stop: false,
+});
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::VecDeque, string::String, vec::Vec};
+
+    use super::*;
+
+    struct FakeConnection {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    /// Returns the packet of `payload` followed by `suffix`.
+    fn packet(payload: &str, suffix: &str) -> String {
+        alloc::format!("${payload}#{:02x}{suffix}", checksum(payload.as_bytes()))
+    }
+
+    impl Connection for FakeConnection {
+        fn read_byte(&mut self) -> u8 {
+            self.input.pop_front().unwrap()
+        }
+
+        fn write_byte(&mut self, data: u8) {
+            self.output.push(data);
+        }
+    }
+
+    struct FakeMemory([u8; 0x10]);
+
+    impl Memory for FakeMemory {
+        fn read(&mut self, address: u64, buffer: &mut [u8]) -> Option<()> {
+            let start = usize::try_from(address).ok()?;
+            buffer.copy_from_slice(self.0.get(start..start + buffer.len())?);
+            Some(())
+        }
+
+        fn write(&mut self, address: u64, data: &[u8]) -> Option<()> {
+            let start = usize::try_from(address).ok()?;
+            self.0
+                .get_mut(start..start + data.len())?
+                .copy_from_slice(data);
+            Some(())
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse(b"?"), Some(Command::HaltReason));
+        assert_eq!(
+            Command::parse(b"mfffff80000001000,8"),
+            Some(Command::ReadMemory {
+                address: 0xffff_f800_0000_1000,
+                len: 8
+            })
+        );
+        assert_eq!(
+            Command::parse(b"M10,2:cc90"),
+            Some(Command::WriteMemory {
+                address: 0x10,
+                len: 2,
+                data: b"cc90"
+            })
+        );
+        assert_eq!(Command::parse(b"M10,2:cc"), None);
+        assert_eq!(Command::parse(b"m10"), None);
+        assert_eq!(Command::parse(b"vCont?"), Some(Command::Unsupported));
+        assert_eq!(Command::parse(b""), None);
+    }
+
+    #[test]
+    fn session() {
+        // Each packet from GDB is followed by the acknowledgment of the reply.
+        let input = ["?", "M2,2:cc90", "m0,4", "mff,1"]
+            .map(|payload| packet(payload, "+"))
+            .concat()
+            + &packet("s", "");
+        let mut connection = FakeConnection {
+            input: input.bytes().collect(),
+            output: Vec::new(),
+        };
+        let mut memory = FakeMemory([0; 0x10]);
+        let registers = GdbRegisters {
+            gprs: [0; 17],
+            eflags: 0x246,
+            cs: 0x38,
+            ss: 0x30,
+        };
+
+        let resume = serve(
+            &mut connection,
+            &mut memory,
+            &registers,
+            Signal::Trap,
+            false,
+        );
+        assert_eq!(resume, Resume::Step);
+        assert!(connection.input.is_empty());
+        assert_eq!(
+            core::str::from_utf8(&connection.output).unwrap(),
+            ["S05", "OK", "0000cc90", "E14"]
+                .map(|payload| String::from("+") + &packet(payload, ""))
+                .concat()
+                + "+"
+        );
+        assert_eq!(memory.0[2..4], [0xcc, 0x90]);
+
+        // After resuming, the stop is reported first. A corrupted packet is
+        // rejected and retransmitted.
+        let input = String::from("+$g#00") + &packet("g", "+") + &packet("c", "");
+        connection.input = input.bytes().collect();
+        connection.output.clear();
+        let resume = serve(&mut connection, &mut memory, &registers, Signal::Trap, true);
+        assert_eq!(resume, Resume::Continue);
+        let registers = "0".repeat(17 * 16) + "460200003800000030000000";
+        assert_eq!(
+            core::str::from_utf8(&connection.output).unwrap(),
+            packet("S05", "-+") + &packet(&registers, "+")
+        );
+    }
+}
@@ -1,31 +1,95 @@
 //! This module implements management of GDT with TSS. TSS is used because Intel
 //! processors require the host GDT to have a valid TSS.
+//!
+//! The host GDT of each processor has its own TSS, whose IST1 to IST3 point at
+//! dedicated stacks. The host IDT delivers NMI, #DF and #MC on them, so that
+//! they do not reuse the interrupted stack, which may be the one overflowed.
 
 use alloc::{boxed::Box, vec::Vec};
 use x86::{
     bits64::task::TaskStateSegment,
     dtables::{DescriptorTablePointer, lgdt},
-    segmentation::{
-        BuildDescriptor, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector, cs,
-    },
+    segmentation::{SegmentSelector, cs},
     task::{load_tr, tr},
 };
 
-use super::segment::SegmentDescriptor;
+use super::{segment::SegmentDescriptor, support::zeroed_box};
 
 type Gdtr = DescriptorTablePointer<u64>;
 
-#[derive(Clone, Debug, derive_deref::Deref, derive_deref::DerefMut)]
+/// The IST index of the host NMI handler.
+pub(crate) const IST_NMI: u8 = 1;
+
+/// The IST index of the host #DF handler.
+pub(crate) const IST_DOUBLE_FAULT: u8 = 2;
+
+/// The IST index of the host #MC handler.
+pub(crate) const IST_MACHINE_CHECK: u8 = 3;
+
+/// The size of each IST stack.
+const IST_STACK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
 pub struct GdtTss {
     ptr: Box<GdtTssRaw>,
+    /// The stacks IST1 to IST3 of the TSS point at, if any. Only owned here.
+    _ist_stacks: Option<Box<IstStacks>>,
+}
+
+impl core::ops::Deref for GdtTss {
+    type Target = Box<GdtTssRaw>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl core::ops::DerefMut for GdtTss {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ptr
+    }
 }
 
 impl GdtTss {
     pub fn new_from_current() -> Self {
         Self {
             ptr: Box::new(GdtTssRaw::new_from_current()),
+            _ist_stacks: None,
         }
     }
+
+    /// Creates the GDT and TSS for the host of a processor, from the current
+    /// GDT. The TSS is a copy of the current one, if any, with IST1 to IST3
+    /// pointing at new stacks for [`IST_NMI`], [`IST_DOUBLE_FAULT`] and
+    /// [`IST_MACHINE_CHECK`]. The TSS replaces the current one in the GDT, or
+    /// is appended to it.
+    pub fn new_for_host() -> Self {
+        let mut ptr = Box::new(GdtTssRaw::new_from_current());
+        let ist_stacks = zeroed_box::<IstStacks>();
+
+        let mut tss = ptr.tss.unwrap_or_default();
+        let mut ist = tss.ist;
+        for (entry, stack) in ist.iter_mut().zip(&ist_stacks.0) {
+            *entry = stack.as_ptr_range().end as u64;
+        }
+        tss.ist = ist;
+        ptr.set_tss(tss);
+
+        Self {
+            ptr,
+            _ist_stacks: Some(ist_stacks),
+        }
+    }
+}
+
+/// The stacks for IST1 to IST3.
+#[repr(C, align(4096))]
+struct IstStacks([[u8; IST_STACK_SIZE]; 3]);
+
+impl core::fmt::Debug for IstStacks {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("IstStacks").finish_non_exhaustive()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -70,15 +134,31 @@ impl GdtTssRaw {
             return self;
         }
 
-        let index = self.gdt.len() as u16;
-        self.tr = Some(SegmentSelector::new(index, x86::Ring::Ring0));
-        self.tss = Some(tss);
+        self.set_tss(tss);
+        self
+    }
 
-        let tss = self.tss.as_ref().unwrap();
-        self.gdt.push(Self::task_segment_descriptor(tss).as_u64());
-        self.gdt.push(0);
+    /// Makes `tss` the TSS of this GDT, replacing the descriptor of the
+    /// current TSS if any, or appending one.
+    fn set_tss(&mut self, tss: TaskStateSegment) {
+        self.tss = Some(tss);
+        let descriptor = Self::task_segment_descriptor(self.tss.as_ref().unwrap());
+        match self.tr {
+            Some(tr) => {
+                let index = usize::from(tr.index());
+                self.gdt[index..index + 2].copy_from_slice(&descriptor);
+            }
+            None => {
+                let index = self.gdt.len() as u16;
+                self.tr = Some(SegmentSelector::new(index, x86::Ring::Ring0));
+                self.gdt.extend_from_slice(&descriptor);
+            }
+        }
+    }
 
-        self
+    /// Returns the GDTR to load this GDT.
+    pub(crate) fn gdtr(&self) -> Gdtr {
+        Gdtr::new_from_slice(&self.gdt)
     }
 
     pub fn apply(&self) -> Result<(), GdtTssError> {
@@ -96,15 +176,21 @@ impl GdtTssRaw {
         Ok(())
     }
 
-    /// Builds a segment descriptor from the task state segment.
-    // FIXME: Just define our own one and properly represent 128 bit width descriptor.
-    fn task_segment_descriptor(tss: &TaskStateSegment) -> Descriptor {
-        let base = tss as *const _ as _;
-        let limit = core::mem::size_of_val(tss) as u64 - 1;
-        <DescriptorBuilder as GateDescriptorBuilder<u32>>::tss_descriptor(base, limit, true)
-            .present()
-            .dpl(x86::Ring::Ring0)
-            .finish()
+    /// Builds the 16-byte descriptor of the available 64-bit TSS `tss`.
+    // See: 8.2.3 TSS Descriptor in 64-bit mode
+    fn task_segment_descriptor(tss: &TaskStateSegment) -> [u64; 2] {
+        const TYPE_AVAILABLE_TSS: u64 = 0b1001;
+        const PRESENT: u64 = 1 << 47;
+
+        let base = core::ptr::from_ref(tss) as u64;
+        let limit = size_of_val(tss) as u64 - 1;
+        let low = (limit & 0xffff)
+            | (base & 0xff_ffff) << 16
+            | TYPE_AVAILABLE_TSS << 40
+            | PRESENT
+            | (limit >> 16 & 0xf) << 48
+            | (base >> 24 & 0xff) << 56;
+        [low, base >> 32]
     }
 
     fn sgdt() -> Gdtr {
@@ -113,3 +199,23 @@ impl GdtTssRaw {
         gdtr
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_segment_descriptor() {
+        let tss = TaskStateSegment::new();
+        let [low, high] = GdtTssRaw::task_segment_descriptor(&tss);
+        let base = core::ptr::from_ref(&tss) as u64;
+
+        assert_eq!(low & 0xffff, size_of::<TaskStateSegment>() as u64 - 1);
+        assert_eq!(
+            (low >> 16) & 0xff_ffff | (low >> 56) << 24 | high << 32,
+            base
+        );
+        // Present, DPL 0, available 64-bit TSS.
+        assert_eq!((low >> 40) & 0xff, 0x89);
+    }
+}
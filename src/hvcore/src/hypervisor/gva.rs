@@ -0,0 +1,298 @@
+//! This module implements translation of guest virtual addresses (GVAs) with
+//! the 4-level paging structures of the guest.
+//!
+//! Guest physical memory is read through the identity mapping of the host, as
+//! snapshots do. Translation is only available when the host runs on the
+//! identity mapping given with `SharedHostData::pt`.
+//!
+//! The identity mapping consists of supervisor-mode pages, so SMAP of the host
+//! never applies to those reads. SMAP of the guest is not applied either:
+//! translation bypasses it, as introspection reads user-mode memory on purpose.
+//! Handlers accessing memory on behalf of guest code, such as the buffers of
+//! hypercalls, enforce it with [`GuestAddressSpace::enforce_smap`] instead.
+
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::{SHARED_HOST_DATA, paging_structures::IDENTITY_MAP_SIZE};
+
+/// The guest address space with the 4-level paging structures at `cr3`.
+pub(crate) struct GuestAddressSpace<F: Fn(u64) -> Option<u64>> {
+    cr3: u64,
+
+    /// Reads the 8 bytes at the given guest physical address.
+    read_gpa: F,
+
+    /// Whether user-mode pages are treated as not mapped.
+    smap: bool,
+}
+
+/// A GVA translated with [`GuestAddressSpace::translate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Translation {
+    pub(crate) gpa: u64,
+    pub(crate) writable: bool,
+    pub(crate) executable: bool,
+    /// Whether user-mode accesses are allowed, that is, the U/S flag is set in
+    /// all the paging-structure entries.
+    pub(crate) user: bool,
+}
+
+/// Returns the address space of `cr3`, if guest memory is accessible.
+pub(crate) fn address_space(cr3: u64) -> Option<GuestAddressSpace<impl Fn(u64) -> Option<u64>>> {
+    SHARED_HOST_DATA.get().unwrap().pt.as_ref()?;
+    Some(GuestAddressSpace::new(cr3, |gpa| {
+        (gpa.is_multiple_of(8) && gpa + 8 <= IDENTITY_MAP_SIZE)
+            .then(|| unsafe { (gpa as *const u64).read_volatile() })
+    }))
+}
+
+/// Returns `true` if SMAP prevents supervisor-mode data accesses of the guest
+/// with `cr4` and `rflags` to user-mode pages, that is, CR4.SMAP is set and
+/// RFLAGS.AC is clear.
+///
+/// See: 4.6.1 Determination of Access Rights
+pub(crate) fn smap_applies(cr4: u64, rflags: u64) -> bool {
+    const CR4_SMAP: usize = 21;
+    const RFLAGS_AC: usize = 18;
+    cr4.get_bit(CR4_SMAP) && !rflags.get_bit(RFLAGS_AC)
+}
+
+impl<F: Fn(u64) -> Option<u64>> GuestAddressSpace<F> {
+    /// Bits 51:12 of CR3 and paging-structure entries.
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    pub(crate) fn new(cr3: u64, read_gpa: F) -> Self {
+        Self {
+            cr3,
+            read_gpa,
+            smap: false,
+        }
+    }
+
+    /// Returns the address space where user-mode pages are not mapped if
+    /// `enforce` is `true`, as supervisor-mode data accesses see them with
+    /// SMAP. See [`smap_applies`].
+    ///
+    /// See: 4.6.1 Determination of Access Rights
+    pub(crate) fn enforce_smap(self, enforce: bool) -> Self {
+        Self {
+            smap: enforce,
+            ..self
+        }
+    }
+
+    /// Translates `gva` as the processor would for a supervisor-mode access,
+    /// and returns `None` if it is not mapped.
+    ///
+    /// See: 4.5.4 Linear-Address Translation with 4-Level Paging and 5-Level Paging
+    pub(crate) fn translate(&self, gva: u64) -> Option<Translation> {
+        const PRESENT: usize = 0;
+        const WRITABLE: usize = 1;
+        const USER: usize = 2;
+        const LARGE: usize = 7;
+        const EXECUTE_DISABLE: usize = 63;
+
+        // Bits 63:47 must be copies of bit 47. Otherwise the address is
+        // non-canonical and cannot be mapped.
+        let upper = gva >> 47;
+        if upper != 0 && upper != 0x1_ffff {
+            return None;
+        }
+
+        let mut table = self.cr3 & Self::ADDRESS_MASK;
+        let mut writable = true;
+        let mut executable = true;
+        let mut user = true;
+        // Walk the PML4, PDPT, PD and PT, indexed by bits 47:39, 38:30, 29:21
+        // and 20:12 of the GVA, respectively.
+        for level in (0..4).rev() {
+            let shift = 12 + 9 * level;
+            let index = (gva >> shift) & 0x1ff;
+            let entry = (self.read_gpa)(table + index * 8)?;
+            if !entry.get_bit(PRESENT) {
+                return None;
+            }
+            writable &= entry.get_bit(WRITABLE);
+            executable &= !entry.get_bit(EXECUTE_DISABLE);
+            user &= entry.get_bit(USER);
+
+            // The PDPTE and PDE may map a 1GB and 2MB page, respectively.
+            let address = entry & Self::ADDRESS_MASK;
+            if level == 0 || ((level == 1 || level == 2) && entry.get_bit(LARGE)) {
+                if user && self.smap {
+                    return None;
+                }
+                let offset_mask = (1 << shift) - 1;
+                return Some(Translation {
+                    gpa: (address & !offset_mask) | (gva & offset_mask),
+                    writable,
+                    executable,
+                    user,
+                });
+            }
+            table = address;
+        }
+        unreachable!()
+    }
+
+    /// Reads the 8 bytes at `gva`, which must be 8-byte aligned.
+    pub(crate) fn read_u64(&self, gva: u64) -> Option<u64> {
+        if !gva.is_multiple_of(8) {
+            return None;
+        }
+        (self.read_gpa)(self.translate(gva)?.gpa)
+    }
+
+    /// Reads the 2 bytes at `gva`.
+    pub(crate) fn read_u16(&self, gva: u64) -> Option<u16> {
+        let mut bytes = [0u8; 2];
+        self.read(gva, &mut bytes)?;
+        Some(u16::from_le_bytes(bytes))
+    }
+
+    /// Reads the 4 bytes at `gva`.
+    pub(crate) fn read_u32(&self, gva: u64) -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        self.read(gva, &mut bytes)?;
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Reads `buffer.len()` bytes from `gva`. Returns `None` if any of them is
+    /// not mapped.
+    pub(crate) fn read(&self, gva: u64, buffer: &mut [u8]) -> Option<()> {
+        let mut address = gva;
+        let mut remaining = buffer;
+        while !remaining.is_empty() {
+            // Translate once for each page, then read it in 8-byte units.
+            let page_offset = address & (BASE_PAGE_SIZE as u64 - 1);
+            let len = remaining
+                .len()
+                .min((BASE_PAGE_SIZE as u64 - page_offset) as usize);
+            let (head, tail) = remaining.split_at_mut(len);
+            let mut gpa = self.translate(address)?.gpa;
+            let mut chunk = head;
+            while !chunk.is_empty() {
+                let offset = (gpa & 0x7) as usize;
+                let bytes = (self.read_gpa)(gpa & !0x7)?.to_le_bytes();
+                let chunk_len = chunk.len().min(8 - offset);
+                let (bytes_head, bytes_tail) = chunk.split_at_mut(chunk_len);
+                bytes_head.copy_from_slice(&bytes[offset..offset + chunk_len]);
+                chunk = bytes_tail;
+                gpa += chunk_len as u64;
+            }
+            remaining = tail;
+            address = address.checked_add(len as u64)?;
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    /// Guest physical memory made of sparse 8-byte values.
+    #[derive(Default)]
+    pub(crate) struct FakeMemory(pub(crate) BTreeMap<u64, u64>);
+
+    impl FakeMemory {
+        /// Maps the 4KB page at `gva` to `gpa` with paging structures at
+        /// fixed GPAs from 0x1000 with CR3 0x1000.
+        pub(crate) fn map(&mut self, gva: u64, gpa: u64, executable: bool) {
+            const PRESENT_WRITABLE: u64 = 0b11;
+            let nx = if executable { 0 } else { 1 << 63 };
+            let indexes = [
+                (gva >> 39) & 0x1ff,
+                (gva >> 30) & 0x1ff,
+                (gva >> 21) & 0x1ff,
+            ];
+            let tables = [0x1000, 0x2000, 0x3000, 0x4000 + (gva >> 21 & 0xf) * 0x1000];
+            for (i, index) in indexes.into_iter().enumerate() {
+                self.0
+                    .insert(tables[i] + index * 8, tables[i + 1] | PRESENT_WRITABLE);
+            }
+            self.0.insert(
+                tables[3] + ((gva >> 12) & 0x1ff) * 8,
+                gpa | PRESENT_WRITABLE | nx,
+            );
+        }
+
+        /// Writes `bytes` to guest physical memory at `gpa`.
+        pub(crate) fn write(&mut self, gpa: u64, bytes: &[u8]) {
+            for (address, byte) in (gpa..).zip(bytes) {
+                let qword = self.0.entry(address & !0x7).or_default();
+                let shift = (address & 0x7) * 8;
+                *qword = (*qword & !(0xff << shift)) | (u64::from(*byte) << shift);
+            }
+        }
+
+        pub(crate) fn address_space(&self) -> GuestAddressSpace<impl Fn(u64) -> Option<u64>> {
+            GuestAddressSpace::new(0x1000, |gpa| self.0.get(&gpa).copied())
+        }
+    }
+
+    #[test]
+    fn translate() {
+        let mut memory = FakeMemory::default();
+        memory.map(0xffff_f800_1234_5000, 0x8_0000, false);
+        memory.map(0x7ff7_0000_1000, 0x9_0000, true);
+        memory.0.insert(0x8_0ff8, 0xaabb);
+        let space = memory.address_space();
+
+        assert_eq!(
+            space.translate(0xffff_f800_1234_5ff8),
+            Some(Translation {
+                gpa: 0x8_0ff8,
+                writable: true,
+                executable: false,
+                user: false,
+            })
+        );
+        assert!(space.translate(0x7ff7_0000_1010).unwrap().executable);
+        assert_eq!(space.read_u64(0xffff_f800_1234_5ff8), Some(0xaabb));
+        assert_eq!(space.read_u64(0xffff_f800_1234_5ff9), None);
+        let mut buffer = [0u8; 3];
+        assert_eq!(space.read(0xffff_f800_1234_5ff8, &mut buffer), Some(()));
+        assert_eq!(buffer, [0xbb, 0xaa, 0]);
+        assert_eq!(space.read_u16(0xffff_f800_1234_5ff9), Some(0xaa));
+        assert_eq!(space.read(0xffff_f800_1234_5ffe, &mut buffer), None);
+        assert_eq!(space.translate(0xffff_f800_1234_6000), None);
+        assert_eq!(space.translate(0x8000_0000_0000_0000), None);
+    }
+
+    #[test]
+    fn translate_large_page() {
+        let mut memory = FakeMemory::default();
+        memory.0.insert(0x1000, 0x2000 | 1);
+        memory.0.insert(0x2000, 0x4000_0000 | 1 << 7 | 1);
+        let space = memory.address_space();
+        assert_eq!(space.translate(0x1234_5678).unwrap().gpa, 0x5234_5678);
+    }
+
+    #[test]
+    fn smap() {
+        // A 1GB user-mode page at 0, and a supervisor-mode one at 1GB, as the
+        // PDPTE of the latter clears U/S.
+        const USER_PRESENT: u64 = 0b101;
+        let mut memory = FakeMemory::default();
+        memory.0.insert(0x1000, 0x2000 | USER_PRESENT);
+        memory.0.insert(0x2000, 0x8000_0000 | 1 << 7 | USER_PRESENT);
+        memory.0.insert(0x2008, 0xc000_0000 | 1 << 7 | 1);
+
+        let space = memory.address_space();
+        assert!(space.translate(0x1000).unwrap().user);
+        assert!(!space.translate(0x4000_1000).unwrap().user);
+        let space = space.enforce_smap(true);
+        assert_eq!(space.translate(0x1000), None);
+        assert_eq!(space.translate(0x4000_1000).unwrap().gpa, 0xc000_1000);
+
+        let smap = 1 << 21;
+        let ac = 1 << 18;
+        assert!(smap_applies(smap, 0x2));
+        assert!(!smap_applies(smap, 0x2 | ac));
+        assert!(!smap_applies(0, 0x2));
+    }
+}
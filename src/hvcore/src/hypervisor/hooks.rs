@@ -0,0 +1,68 @@
+//! This module implements execute hooks, which redirect instruction fetches
+//! from a guest page to a shadow page while data accesses see the original.
+
+use alloc::vec::Vec;
+use spin::RwLock;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::SHARED_HOST_DATA;
+
+/// An execute hook registered with [`add_exec_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecHook {
+    /// The guest physical address of the page to hook.
+    pub gpa: u64,
+
+    /// The physical address of the page executed instead of `gpa`.
+    pub shadow_pa: u64,
+}
+
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookError {
+    #[error("`{address:#x}` is not page aligned")]
+    NotPageAligned { address: u64 },
+
+    #[error("`{gpa:#x}` is already hooked")]
+    AlreadyHooked { gpa: u64 },
+
+    #[error("hooks must be added before virtualizing the system")]
+    AlreadyVirtualized,
+
+    #[error("the hypervisor heap is exhausted")]
+    OutOfMemory,
+}
+
+/// Registers an execute hook that redirects instruction fetches from `gpa` to
+/// `shadow_pa`. Both addresses must be 4KB aligned, and `shadow_pa` must remain
+/// valid for the lifetime of the hypervisor.
+///
+/// Instructions in the hooked page read and write `shadow_pa`, not `gpa`, as
+/// they may read their own page. On Intel, the hook applies to the default EPT
+/// view only, and writes to `shadow_pa` are not tracked by snapshots.
+///
+/// Must be called before [`crate::virtualize_system`].
+pub fn add_exec_hook(gpa: u64, shadow_pa: u64) -> Result<(), HookError> {
+    if SHARED_HOST_DATA.is_completed() {
+        return Err(HookError::AlreadyVirtualized);
+    }
+    for address in [gpa, shadow_pa] {
+        if address % BASE_PAGE_SIZE as u64 != 0 {
+            return Err(HookError::NotPageAligned { address });
+        }
+    }
+
+    let mut hooks = EXEC_HOOKS.write();
+    if hooks.iter().any(|hook| hook.gpa == gpa) {
+        return Err(HookError::AlreadyHooked { gpa });
+    }
+    hooks.try_reserve(1).map_err(|_| HookError::OutOfMemory)?;
+    hooks.push(ExecHook { gpa, shadow_pa });
+    Ok(())
+}
+
+/// Returns the registered execute hooks.
+pub(crate) fn exec_hooks() -> Vec<ExecHook> {
+    EXEC_HOOKS.read().clone()
+}
+
+static EXEC_HOOKS: RwLock<Vec<ExecHook>> = RwLock::new(Vec::new());
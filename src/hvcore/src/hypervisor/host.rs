@@ -1,18 +1,48 @@
 //! This module implements architecture agnostic parts of the host code.
 
+use alloc::{string::String, vec::Vec};
+use core::{arch::x86_64::_rdtsc, ops::Range};
+
 use x86::{
+    bits64::rflags::RFlags,
     controlregs::{Cr4, Xcr0},
-    cpuid::cpuid,
+    cpuid::{CpuIdResult, cpuid},
 };
 
 use crate::hypervisor::{
     HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, OUR_HV_VENDOR_NAME_EBX,
-    OUR_HV_VENDOR_NAME_ECX, OUR_HV_VENDOR_NAME_EDX, apic_id,
-    registers::Registers,
-    x86_instructions::{cr4, cr4_write, rdmsr, wrmsr, xsetbv},
+    OUR_HV_VENDOR_NAME_ECX, OUR_HV_VENDOR_NAME_EDX, SHARED_HOST_DATA, apic_base, apic_id,
+    backtrace, benchmark, boot_info,
+    capabilities::UnsupportedFeature,
+    config::TripleFaultPolicy,
+    crash_dump,
+    deterministic_time::{self, DeterministicClock},
+    devirtualize,
+    ept_views::{self, EptPermissions, EptViewError},
+    exception_policy::{self, ExceptionPolicy},
+    exit_handlers, exit_profile, exit_stats,
+    gdb_stub::{self, GdbRegisters, Signal},
+    gva, hypercall,
+    hypercall_auth::AUTH,
+    hyperv, irq,
+    lbr::GuestLastBranches,
+    machine_check, microcode,
+    mini_vm::{MiniVmError, MiniVmExit, MiniVmMode, MiniVmRequest},
+    msr_monitor, mwait, panic_buffer, phys_read,
+    phys_scan::{self, ScanProgress},
+    pmu, processor_trace, pv_clock,
+    registers::{FullGuestState, Registers},
+    self_test, shutdown, smi,
+    snapshot::SnapshotError,
+    speculation, status, table_integrity, topology, umip, vmx_hiding, watchdog,
+    x86_instructions::{cr4, cr4_write, rdmsr, wbinvd, wrmsr, xsetbv},
+    xstate::ExtendedState,
 };
 
-use super::{amd::Amd, intel::Intel};
+#[cfg(feature = "amd")]
+use super::amd::Amd;
+#[cfg(feature = "intel")]
+use super::intel::Intel;
 
 /// The entry point of the hypervisor.
 pub(crate) fn main(registers: &Registers) -> ! {
@@ -34,15 +64,22 @@ pub(crate) fn main(registers: &Registers) -> ! {
     // never observed it causing the described issues.
     unsafe { x86::irq::disable() };
 
-    // Start the host on the current processor.
-    if x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel" {
+    // Start the host on the current processor. `check_support` has refused
+    // processors whose backend is not compiled in.
+    let intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    #[cfg(feature = "intel")]
+    if intel {
         virtualize_core::<Intel>(registers)
-    } else {
+    }
+    #[cfg(feature = "amd")]
+    if !intel {
         virtualize_core::<Amd>(registers)
     }
+    unreachable!("The backend for the processor is not compiled in")
 }
 
-/// Enables the virtualization extension, sets up and runs the guest indefinitely.
+/// Enables the virtualization extension, sets up and runs the guest until
+/// devirtualization is requested.
 fn virtualize_core<Arch: Architecture>(registers: &Registers) -> ! {
     log::info!("Initializing the guest");
 
@@ -52,31 +89,160 @@ fn virtualize_core<Arch: Architecture>(registers: &Registers) -> ! {
 
     // Create a new (empty) guest instance and set up its initial state.
     let id = apic_id::processor_id_from(apic_id::get()).unwrap();
-    let guest = &mut Arch::Guest::new(id);
+    let mut guest = Arch::Guest::new(id);
     guest.activate();
+    panic_buffer::set_in_host(id, true);
     guest.initialize(registers);
+    table_integrity::protect_all(&mut guest);
+    exit_profile::audit(id, &guest.optional_intercepts());
+
+    // Save and restore the guest extended state around VM-exit handling if
+    // configured.
+    let mut extended_state = if SHARED_HOST_DATA.get().unwrap().config.save_extended_state {
+        ExtendedState::new()
+    } else {
+        None
+    };
+    // The guest extended state saved with `HC_STATE_SAVE`.
+    let mut saved_extended_state = None;
+
+    // The virtual time of this processor if the deterministic time mode is
+    // enabled.
+    let mut clock = DeterministicClock::new(id);
+
+    smi::initialize(id);
+    status::set_virtualized(id, true);
 
     log::info!("Starting the guest");
     loop {
+        if let Some(extended_state) = &mut extended_state {
+            extended_state.restore();
+        }
+
         // Then, run the guest until VM-exit occurs. Some of events are handled
         // within the architecture specific code and nothing to do here.
-        match guest.run() {
-            VmExitReason::Cpuid(info) => handle_cpuid(guest, &info),
-            VmExitReason::Rdmsr(info) => handle_rdmsr(guest, &info),
-            VmExitReason::Wrmsr(info) => handle_wrmsr(guest, &info),
-            VmExitReason::XSetBv(info) => handle_xsetbv(guest, &info),
-            VmExitReason::InitSignal | VmExitReason::StartupIpi | VmExitReason::NestedPageFault => {
+        watchdog::exit_completed(id);
+        panic_buffer::set_in_host(id, false);
+        let reason = guest.run();
+        speculation::barrier_after_exit();
+        panic_buffer::set_in_host(id, true);
+        // Handle the VM-exit with interrupts disabled, and without re-entrance.
+        let _guard = irq::ExitHandlerGuard::enter(id);
+        let rip = guest.regs().rip;
+        exit_stats::record(id, &reason, rip);
+        watchdog::exit_started(id, &reason, rip);
+        smi::sample(id);
+
+        if let Some(extended_state) = &mut extended_state {
+            extended_state.save();
+        }
+
+        // Let registered handlers process it first, if any.
+        if exit_handlers::dispatch(&mut guest, &reason) {
+            continue;
+        }
+
+        match reason {
+            VmExitReason::Cpuid(info) => handle_cpuid(&mut guest, id, &info),
+            VmExitReason::Rdmsr(info) => handle_rdmsr(&mut guest, &info, clock.as_mut()),
+            VmExitReason::Wrmsr(info) => handle_wrmsr(&mut guest, &info, clock.as_mut()),
+            VmExitReason::XSetBv(info) => handle_xsetbv(&mut guest, &info),
+            VmExitReason::IoInstruction(info) => handle_io(&mut guest, &info),
+            VmExitReason::Exception(info) => handle_exception(&mut guest, &info),
+            VmExitReason::Hypercall(info) => {
+                if handle_hypercall(
+                    &mut guest,
+                    &info,
+                    extended_state.as_mut(),
+                    &mut saved_extended_state,
+                ) {
+                    break;
+                }
+            }
+            VmExitReason::Hlt(info) => handle_hlt(&mut guest, &info),
+            VmExitReason::CacheInvalidation(info) => handle_cache_invalidation(&mut guest, &info),
+            VmExitReason::Monitor(info) => mwait::handle_monitor(&mut guest, &info),
+            VmExitReason::Mwait(info) => mwait::handle_mwait(&mut guest, &info),
+            VmExitReason::CrAccess(info) => handle_cr_access(&mut guest, &info),
+            VmExitReason::Shutdown => handle_shutdown(&mut guest, id),
+            VmExitReason::NestedPageFault(info) => {
+                if guest.handle_ept_view_fault() {
+                    let cr3 = guest.long_mode_cr3();
+                    log::debug!(
+                        "EPT view fault at {:#x?} from {:?}",
+                        info.gpa,
+                        backtrace::capture(guest.regs(), cr3)
+                    );
+                } else if !(info.write
+                    && (table_integrity::handle_write(&mut guest, id, info.gpa)
+                        || guest.handle_snapshot_write(info.gpa)))
+                {
+                    log::trace!(
+                        "NPF {:#x?} R:{} W:{} X:{}",
+                        info.gpa,
+                        info.read,
+                        info.write,
+                        info.execute
+                    );
+                }
             }
+            VmExitReason::Rdtsc(info) => handle_rdtsc(&mut guest, &info, clock.as_mut(), false),
+            VmExitReason::Rdtscp(info) => handle_rdtsc(&mut guest, &info, clock.as_mut(), true),
+            VmExitReason::Rdrand(info) => handle_rdrand(&mut guest, &info, clock.as_mut()),
+            VmExitReason::Rdpmc(info) => pmu::handle_rdpmc(&mut guest, id, &info),
+            VmExitReason::MonitorTrap => {
+                let _ = table_integrity::handle_monitor_trap(&mut guest, id);
+                let _ = umip::handle_monitor_trap(&mut guest, id);
+            }
+            VmExitReason::DescriptorTableAccess(info) => umip::handle(&mut guest, id, &info),
+            VmExitReason::PreemptionTimer => watchdog::check(id),
+            VmExitReason::Smi => smi::handle_smi(id),
+            VmExitReason::InitSignal | VmExitReason::StartupIpi | VmExitReason::Pause => {}
         }
     }
+
+    // Devirtualization is requested. Let the guest continue on the bare
+    // processor with the current guest state.
+    log::info!("Devirtualizing the guest");
+    watchdog::exit_completed(id);
+    if let Some(extended_state) = &mut extended_state {
+        extended_state.restore();
+    }
+    let registers = *guest.regs();
+    status::set_virtualized(id, false);
+    guest.devirtualize();
+    vt.disable();
+
+    // Nothing here is used anymore. Free them as this function never returns.
+    drop(extended_state);
+    drop(saved_extended_state);
+    drop(guest);
+    drop(vt);
+    devirtualize::resume_guest(&registers)
 }
 
-fn handle_cpuid<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+fn handle_cpuid<T: Guest>(guest: &mut T, id: usize, info: &InstructionInfo) {
     let leaf = guest.regs().rax as u32;
     let sub_leaf = guest.regs().rcx as u32;
     log::trace!("CPUID {leaf:#x?} {sub_leaf:#x?}");
-    let mut cpuid_result = cpuid!(leaf, sub_leaf);
+    let cpuid_result = filter_cpuid(leaf, cpuid!(leaf, sub_leaf));
+    let cpuid_result = deterministic_time::filter_cpuid(leaf, sub_leaf, cpuid_result);
+    let cpuid_result = processor_trace::filter_cpuid(leaf, sub_leaf, cpuid_result);
+    let cpuid_result = pv_clock::filter_cpuid(leaf, cpuid_result);
+    let cpuid_result = hyperv::filter_cpuid(leaf, cpuid_result);
+    let cpuid_result = mwait::filter_cpuid(leaf, cpuid_result);
+    let cpuid_result = topology::filter_cpuid(id, leaf, sub_leaf, cpuid_result);
 
+    guest.regs().rax = u64::from(cpuid_result.eax);
+    guest.regs().rbx = u64::from(cpuid_result.ebx);
+    guest.regs().rcx = u64::from(cpuid_result.ecx);
+    guest.regs().rdx = u64::from(cpuid_result.edx);
+    guest.regs().rip = info.next_rip;
+}
+
+/// Returns the result of CPUID `leaf` the guest sees, given that of the
+/// processor.
+pub(crate) fn filter_cpuid(leaf: u32, mut cpuid_result: CpuIdResult) -> CpuIdResult {
     if leaf == 1 {
         // On the Intel processor, CPUID.1.ECX[5] indicates if VT-x is supported.
         // Clear this to prevent other hypervisor tries to use it. On AMD, it is
@@ -99,25 +265,94 @@ fn handle_cpuid<T: Guest>(guest: &mut T, info: &InstructionInfo) {
         // See: Hypervisor Top Level Functional Specification
         cpuid_result.eax = 0;
     }
+    cpuid_result
+}
 
-    guest.regs().rax = u64::from(cpuid_result.eax);
-    guest.regs().rbx = u64::from(cpuid_result.ebx);
-    guest.regs().rcx = u64::from(cpuid_result.ecx);
-    guest.regs().rdx = u64::from(cpuid_result.edx);
-    guest.regs().rip = info.next_rip;
+/// How access to an MSR by the guest is handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MsrAction {
+    /// The MSR does not exist for the guest, and the access causes #GP(0).
+    InjectGp,
+    /// The MSR reads as 0, and writes are discarded.
+    Ignore,
+    /// The MSR of the processor is accessed, unless virtualized by the
+    /// deterministic time mode or other modules.
+    Passthrough,
+}
+
+/// The configuration that decides the [`MsrAction`] of each MSR.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MsrPolicy {
+    /// Whether PT is reserved for the host. See `processor_trace::host_owned`.
+    pub(crate) pt_host_owned: bool,
+    /// Whether the advertise-hypervisor mode is enabled. See `hyperv`.
+    pub(crate) hypervisor_advertised: bool,
+}
+
+impl MsrPolicy {
+    fn current() -> Self {
+        Self {
+            pt_host_owned: processor_trace::host_owned(),
+            hypervisor_advertised: hyperv::advertised(),
+        }
+    }
+
+    /// Returns how RDMSR (`write` is `false`) or WRMSR of `msr` by the guest
+    /// is handled. Values written are checked separately by `handle_wrmsr`.
+    pub(crate) fn action(&self, msr: u32, write: bool) -> MsrAction {
+        // The MSRs of PT reserved for the host, the VMX capability MSRs and the
+        // synthetic MSRs outside the advertise-hypervisor mode do not exist
+        // for the guest.
+        let pt_reserved = self.pt_host_owned && processor_trace::INTERCEPTED_MSRS.contains(&msr);
+        let vmx_hidden = if write {
+            vmx_hiding::is_unwritable(msr)
+        } else {
+            vmx_hiding::is_unreadable(msr)
+        };
+        let synthetic = hyperv::is_synthetic_msr(msr);
+        if pt_reserved || vmx_hidden || (synthetic && !self.hypervisor_advertised) {
+            MsrAction::InjectGp
+        } else if synthetic {
+            MsrAction::Ignore
+        } else {
+            MsrAction::Passthrough
+        }
+    }
 }
 
 /// Handles the `RDMSR` instruction for the range not covered by MSR bitmaps.
-fn handle_rdmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+fn handle_rdmsr<T: Guest>(
+    guest: &mut T,
+    info: &InstructionInfo,
+    clock: Option<&mut DeterministicClock>,
+) {
+    const GP: u8 = 13;
+
     let msr = guest.regs().rcx as u32;
     log::trace!("RDMSR {msr:#x?}");
 
+    let action = MsrPolicy::current().action(msr, false);
+    if action == MsrAction::InjectGp {
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+
     // Passthrough any MSR access. Beware of that VM-exit occurs even for an
     // invalid MSR access which causes #GP(0).
     // See: 26.1.1 Relative Priority of Faults and VM Exits
     //
     // One solution is to catch the exception and inject it into the guest.
-    let value = rdmsr(msr);
+    //
+    // The exceptions are the synthetic MSRs, which read as 0, and the MSRs
+    // virtualized by the deterministic time mode.
+    let value = if action == MsrAction::Ignore {
+        0
+    } else {
+        clock
+            .and_then(|clock| clock.read_msr(msr))
+            .unwrap_or_else(|| rdmsr(msr))
+    };
+    let value = vmx_hiding::filter_rdmsr(msr, value);
 
     guest.regs().rax = value & 0xffff_ffff;
     guest.regs().rdx = value >> 32;
@@ -125,35 +360,672 @@ fn handle_rdmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
 }
 
 /// Handles the `WRMSR` instruction for the range not covered by MSR bitmaps.
-fn handle_wrmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+fn handle_wrmsr<T: Guest>(
+    guest: &mut T,
+    info: &InstructionInfo,
+    clock: Option<&mut DeterministicClock>,
+) {
+    const GP: u8 = 13;
+
     let msr = guest.regs().rcx as u32;
     let value = (guest.regs().rax & 0xffff_ffff) | ((guest.regs().rdx & 0xffff_ffff) << 32);
     log::trace!("WRMSR {msr:#x?} {value:#x?}");
 
-    // See the comment in `handle_rdmsr`.
-    wrmsr(msr, value);
+    // See the comments in `handle_rdmsr`. A value the guest cannot load on
+    // VM-entry causes #GP(0) as WRMSR would.
+    let action = MsrPolicy::current().action(msr, true);
+    if action == MsrAction::InjectGp
+        || pmu::is_invalid_write(msr, value)
+        || apic_base::is_invalid_write(msr, value)
+    {
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+    if msr == microcode::IA32_BIOS_UPDT_TRIG {
+        let rip = guest.regs().rip;
+        if microcode::load_update(value, rip, guest.long_mode_cr3()) {
+            guest.reprobe_capabilities();
+        }
+        guest.regs().rip = info.next_rip;
+        return;
+    }
+    msr_monitor::check_write(msr, value, guest.regs().rip);
+    let value = match clock {
+        Some(clock) => clock.write_msr(msr, value),
+        None => Some(value),
+    };
+    let value = value.filter(|_| action == MsrAction::Passthrough);
+    if let Some(value) = value.and_then(|value| guest.write_msr(msr, value)) {
+        wrmsr(msr, value);
+    }
 
     guest.regs().rip = info.next_rip;
 }
 
 // Handles the `XSETBV` instruction.
 fn handle_xsetbv<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+    const GP: u8 = 13;
+
     let xcr: u32 = guest.regs().rcx as u32;
     let value = (guest.regs().rax & 0xffff_ffff) | ((guest.regs().rdx & 0xffff_ffff) << 32);
-    let value = Xcr0::from_bits(value).unwrap();
     log::trace!("XSETBV {xcr:#x?} {value:#x?}");
 
+    // Executing XSETBV with an invalid value causes #GP(0) in the host. Check
+    // the value and inject #GP(0) as the processor would do instead.
+    // See: CPUID—CPU Identification, Leaf 0DH
+    let regs = cpuid!(0xd, 0);
+    let supported = u64::from(regs.eax) | (u64::from(regs.edx) << 32);
+    if xcr != 0 || !is_valid_xcr0(value, supported) {
+        log::debug!("Injecting #GP(0) for XSETBV {xcr:#x?} {value:#x?}");
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+
     // The host CR4 might not have this bit, which is required for executing the
     // `XSETBV` instruction. Set this bit and run the instruction.
     cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
-
-    // XCR may be invalid and this instruction may cause #GP(0). See the comment
-    // in `handle_rdmsr`.
     xsetbv(xcr, value);
 
     guest.regs().rip = info.next_rip;
 }
 
+/// Tests whether `value` can be written to XCR0 on the processor that supports
+/// `supported` bits in XCR0.
+///
+/// See: XSETBV—Set Extended Control Register
+/// See: 13.3 Enabling the XSAVE Feature Set and XSAVE-Enabled Features
+pub(crate) fn is_valid_xcr0(value: u64, supported: u64) -> bool {
+    let xcr0 = Xcr0::from_bits_truncate(value);
+    let avx512 = Xcr0::XCR0_OPMASK_STATE | Xcr0::XCR0_ZMM_HI256_STATE | Xcr0::XCR0_HI16_ZMM_STATE;
+    let mpx = Xcr0::XCR0_BNDREG_STATE | Xcr0::XCR0_BNDCSR_STATE;
+    let amx = (1u64 << 17) | (1 << 18);
+
+    // Unsupported bits must not be set.
+    (value & !supported == 0)
+        // "If an attempt is made to clear bit 0 of XCR0".
+        && xcr0.contains(Xcr0::XCR0_FPU_MMX_STATE)
+        // "If an attempt is made to set XCR0[2] (AVX state) while clearing
+        //  XCR0[1] (SSE state)."
+        && (!xcr0.contains(Xcr0::XCR0_AVX_STATE) || xcr0.contains(Xcr0::XCR0_SSE_STATE))
+        // "If an attempt is made to set XCR0[3] (BNDREG state) and XCR0[4]
+        //  (BNDCSR state) to different values."
+        && (xcr0 & mpx).is_empty() == !xcr0.contains(mpx)
+        // "If an attempt is made to set any of XCR0[7:5] (AVX-512 state) while
+        //  clearing XCR0[2] (AVX state), or to set XCR0[7:5] to a value that is
+        //  neither 000b nor 111b."
+        && ((xcr0 & avx512).is_empty() || (xcr0.contains(avx512) && xcr0.contains(Xcr0::XCR0_AVX_STATE)))
+        // "If an attempt is made to set XCR0[17] (XTILECFG) and XCR0[18]
+        //  (XTILEDATA) to different values."
+        && (value & amx == 0 || value & amx == amx)
+}
+
+/// Handles the `IN` and `OUT` instructions by passing them through. `INS` and
+/// `OUTS` are not emulated, and #GP(0) is injected as if the port were not
+/// accessible.
+fn handle_io<T: Guest>(guest: &mut T, info: &IoInfo) {
+    const GP: u8 = 13;
+
+    log::trace!("I/O {info:#x?}");
+    if info.string || info.rep {
+        log::warn!("Injecting #GP(0) for string I/O {info:#x?}");
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+
+    let rax = guest.regs().rax;
+    if info.input {
+        let value = unsafe {
+            match info.size {
+                1 => u64::from(x86::io::inb(info.port)),
+                2 => u64::from(x86::io::inw(info.port)),
+                _ => u64::from(x86::io::inl(info.port)),
+            }
+        };
+        guest.regs().rax = merge_in_value(rax, info.size, value);
+    } else {
+        unsafe {
+            match info.size {
+                1 => x86::io::outb(info.port, rax as u8),
+                2 => x86::io::outw(info.port, rax as u16),
+                _ => x86::io::outl(info.port, rax as u32),
+            }
+        };
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+/// Returns RAX after `IN` of `size` bytes reads `value`. Writing EAX clears the
+/// upper 32 bits, while writing AL or AX preserves the rest of RAX.
+fn merge_in_value(rax: u64, size: u8, value: u64) -> u64 {
+    if size == 4 {
+        return value;
+    }
+    let mask = (1u64 << (size * 8)) - 1;
+    (rax & !mask) | value
+}
+
+/// Handles `MOV` to or from a control register. Only the accesses below are
+/// intercepted, so reaching the end is a bug.
+fn handle_cr_access<T: Guest>(guest: &mut T, info: &CrAccessInfo) {
+    const GP: u8 = 13;
+
+    // MOV to CR3 is intercepted only to switch EPT views bound to processes.
+    if info.cr == 3
+        && info.write
+        && let Some(gpr) = info.gpr
+    {
+        let cr3 = guest.regs().gpr(gpr);
+        guest.write_cr3(cr3);
+        guest.regs().rip = info.next_rip;
+        return;
+    }
+
+    // MOV to CR4 is intercepted only when it sets CR4.VMXE, which is hidden
+    // from the guest on Intel. Fail as on a processor without VMX. The other
+    // bits, including SMEP, SMAP, PKE and UMIP, are owned by the guest.
+    // See: 2.5 Control Registers
+    if info.cr == 4 && info.write {
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+
+    panic!(
+        "Unhandled MOV {} CR{} with {:?} at {:#x?}",
+        if info.write { "to" } else { "from" },
+        info.cr,
+        info.gpr,
+        info.next_rip
+    );
+}
+
+/// Handles an intercepted exception according to its policy. See
+/// [`exception_policy`].
+fn handle_exception<T: Guest>(guest: &mut T, info: &ExceptionInfo) {
+    log::trace!("Exception {info:#x?}");
+
+    // Let the guest handle machine checks as it would without the hypervisor.
+    if info.vector == machine_check::MC {
+        machine_check::log_banks("the guest");
+        guest.inject_exception(info.vector, None);
+        return;
+    }
+
+    // Complete INT3 and INTO, which are reported at the instruction, unless
+    // they are reflected.
+    let rip = guest.regs().rip;
+    if let Some(next_rip) = info.next_rip {
+        guest.regs().rip = next_rip;
+    }
+    let cr3 = guest.long_mode_cr3();
+    let policy = exception_policy::policy(info.vector);
+    if policy.should_reflect(guest.regs(), info, cr3) {
+        if let Some(next_rip) = info.next_rip {
+            guest.regs().rip = rip;
+            guest.inject_software_exception(info.vector, next_rip);
+        } else {
+            guest.inject_exception(info.vector, info.error_code);
+        }
+    }
+}
+
+/// Handles the `VMCALL` or `VMMCALL` instruction. Returns `true` if
+/// devirtualization is requested.
+fn handle_hypercall<T: Guest>(
+    guest: &mut T,
+    info: &InstructionInfo,
+    extended_state: Option<&mut ExtendedState>,
+    saved_extended_state: &mut Option<ExtendedState>,
+) -> bool {
+    const UD: u8 = 6;
+
+    let number = guest.regs().rcx;
+    log::trace!("Hypercall {number:#x?}");
+
+    // Do not let user-mode code control the hypervisor.
+    if guest.cpl() != 0 {
+        guest.inject_exception(UD, None);
+        return false;
+    }
+
+    // Nor code without the token, once it is registered.
+    let token = guest.regs().r10;
+    let cr3 = guest.long_mode_cr3();
+    if !matches!(number, hypercall::HC_AUTH_REGISTER | hypercall::HC_STATUS)
+        && !AUTH.is_authorized(token, cr3)
+    {
+        log::warn!("Hypercall {number:#x?} is not authenticated");
+        guest.regs().rax = hypercall::HC_ACCESS_DENIED;
+        guest.regs().rip = info.next_rip;
+        return false;
+    }
+
+    match number {
+        hypercall::HC_DEVIRTUALIZE => {
+            guest.regs().rax = 0;
+            guest.regs().rip = info.next_rip;
+            true
+        }
+        hypercall::HC_SHUTDOWN_QUERY => {
+            match shutdown::query(guest.regs().rdx as usize) {
+                Ok(status) => {
+                    guest.regs().rax = 0;
+                    guest.regs().rdx = status.exits;
+                    guest.regs().r8 = status.last_error;
+                    guest.regs().r9 = status.hooks;
+                }
+                Err(e) => {
+                    log_hypercall_failure(number, e as u64, &e);
+                    guest.regs().rax = e as u64;
+                }
+            }
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_STATUS => {
+            match status::query(guest.regs().rdx) {
+                Ok([rdx, r8, r9]) => {
+                    guest.regs().rax = 0;
+                    guest.regs().rdx = rdx;
+                    guest.regs().r8 = r8;
+                    guest.regs().r9 = r9;
+                }
+                Err(e) => {
+                    log_hypercall_failure(number, e as u64, &e);
+                    guest.regs().rax = e as u64;
+                }
+            }
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_SHUTDOWN_COMMIT => {
+            log::info!("Shutting down the hypervisor on this processor");
+            shutdown::commit(guest);
+            guest.regs().rax = 0;
+            guest.regs().rip = info.next_rip;
+            true
+        }
+        hypercall::HC_SNAPSHOT_TAKE
+        | hypercall::HC_SNAPSHOT_RESTORE
+        | hypercall::HC_SNAPSHOT_DISCARD => {
+            let result = match number {
+                hypercall::HC_SNAPSHOT_TAKE => guest.take_snapshot(),
+                hypercall::HC_SNAPSHOT_RESTORE => guest.restore_snapshot(),
+                _ => guest.discard_snapshot(),
+            };
+            if let Err(e) = result {
+                log_hypercall_failure(number, e as u64, &e);
+            }
+            guest.regs().rax = result.map_or_else(|e| e as u64, |()| 0);
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_STATE_SAVE => {
+            save_state(guest, info, extended_state, saved_extended_state);
+            false
+        }
+        hypercall::HC_STATE_RESTORE => {
+            if let Err(e) = restore_state(guest, extended_state, saved_extended_state.as_ref()) {
+                log_hypercall_failure(number, e as u64, &e);
+                guest.regs().rax = e as u64;
+                guest.regs().rip = info.next_rip;
+            }
+            false
+        }
+        hypercall::HC_RUN_MINI_VM_REAL | hypercall::HC_RUN_MINI_VM_LONG => {
+            let request = MiniVmRequest {
+                blob_gpa: guest.regs().rdx,
+                blob_size: guest.regs().r8,
+                mode: if number == hypercall::HC_RUN_MINI_VM_REAL {
+                    MiniVmMode::Real
+                } else {
+                    MiniVmMode::Long
+                },
+                entry: guest.regs().r9,
+            };
+            match guest.run_mini_vm(&request) {
+                Ok((exit, rax)) => {
+                    log::debug!("Mini VM exited with {exit:x?}");
+                    let (code, extra) = exit.to_raw();
+                    guest.regs().rax = 0;
+                    guest.regs().rdx = code;
+                    guest.regs().r8 = rax;
+                    guest.regs().r9 = extra;
+                }
+                Err(e) => {
+                    log_hypercall_failure(number, e as u64, &e);
+                    guest.regs().rax = e as u64;
+                }
+            }
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_EPT_VIEW_SWITCH
+        | hypercall::HC_EPT_VIEW_PROTECT
+        | hypercall::HC_EPT_VIEW_BIND_CR3
+        | hypercall::HC_EPT_VIEW_PROTECT_SUB_PAGES => {
+            let (rdx, r8, r9) = (guest.regs().rdx, guest.regs().r8, guest.regs().r9);
+            let result = match number {
+                hypercall::HC_EPT_VIEW_SWITCH => guest.switch_ept_view(rdx as usize),
+                hypercall::HC_EPT_VIEW_PROTECT => {
+                    ept_views::decode_protect_args(r8, r9).and_then(|(range, permissions)| {
+                        guest.protect_ept_view(rdx as usize, range, permissions)
+                    })
+                }
+                hypercall::HC_EPT_VIEW_PROTECT_SUB_PAGES => ept_views::decode_sub_page_args(r8, r9)
+                    .and_then(|(gpa, writable)| {
+                        guest.protect_sub_pages(rdx as usize, gpa, writable)
+                    }),
+                _ => guest.bind_ept_view(rdx, r8 as usize),
+            };
+            if let Err(e) = result {
+                log_hypercall_failure(number, e as u64, &e);
+            }
+            guest.regs().rax = result.map_or_else(|e| e as u64, |()| 0);
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_READ_PHYS => {
+            let (rdx, r8, r9) = (guest.regs().rdx, guest.regs().r8, guest.regs().r9);
+            let cr3 = guest.long_mode_cr3();
+            let smap = gva::smap_applies(guest.full_state().cr4, guest.regs().rflags);
+            let result = phys_read::read_phys(cr3, smap, rdx, r8, r9);
+            if let Err(e) = result {
+                log_hypercall_failure(number, e as u64, &e);
+            }
+            guest.regs().rax = result.map_or_else(|e| e as u64, |()| 0);
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_SCAN_PHYS => {
+            let (rdx, r8, r9) = (guest.regs().rdx, guest.regs().r8, guest.regs().r9);
+            let cr3 = guest.long_mode_cr3();
+            let smap = gva::smap_applies(guest.full_state().cr4, guest.regs().rflags);
+            match phys_scan::scan(cr3, smap, rdx, r8, r9) {
+                Ok(ScanProgress::Pending(next)) => {
+                    // Re-execute the hypercall to scan the next slice, letting
+                    // pending interrupts be delivered in between.
+                    guest.regs().r8 = next;
+                    return false;
+                }
+                Ok(ScanProgress::Found(gpa)) => {
+                    guest.regs().rax = 0;
+                    guest.regs().rdx = gpa;
+                    guest.regs().r8 = gpa + 1;
+                }
+                Err(e) => {
+                    if e != phys_scan::PhysScanError::NotFound {
+                        log_hypercall_failure(number, e as u64, &e);
+                    }
+                    guest.regs().rax = e as u64;
+                }
+            }
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_DUMP_STATE => {
+            crash_dump::dump_state(guest);
+            guest.regs().rax = 0;
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_SELF_TEST_RESULTS => {
+            guest.regs().rax = self_test::current_results().0;
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_BENCHMARK_RESULTS => {
+            let medians = benchmark::current_results(guest.regs().rdx != 0);
+            guest.regs().rax = 0;
+            guest.regs().rdx = medians.cpuid;
+            guest.regs().r8 = medians.rdmsr;
+            guest.regs().r9 = medians.vmcall;
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_EXCEPTION_POLICY => {
+            let (rdx, r8, r9) = (guest.regs().rdx, guest.regs().r8, guest.regs().r9);
+            let result = ExceptionPolicy::from_raw(r8, r9).and_then(|policy| {
+                let vector = u8::try_from(rdx).unwrap_or(u8::MAX);
+                exception_policy::set_policy(vector, policy)
+            });
+            if let Err(e) = result {
+                log_hypercall_failure(number, e as u64, &e);
+            }
+            guest.regs().rax = result.map_or_else(|e| e as u64, |()| 0);
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_PV_CLOCK_PAGE => {
+            let result = pv_clock::page_gpa();
+            if let Err(e) = result {
+                log_hypercall_failure(number, e as u64, &e);
+            }
+            guest.regs().rax = result.map_or_else(|e| e as u64, |_| 0);
+            guest.regs().rdx = result.unwrap_or(0);
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_BOOT_MEMORY_MAP | hypercall::HC_BOOT_ACPI_TABLE => {
+            let index = guest.regs().rdx;
+            let result = if number == hypercall::HC_BOOT_MEMORY_MAP {
+                boot_info::memory_map_entry(index)
+                    .map(|region| (region.start, region.pages, u64::from(region.memory_type)))
+            } else {
+                boot_info::acpi_table_entry(index).map(|table| {
+                    (
+                        u64::from(u32::from_le_bytes(table.signature)),
+                        table.address,
+                        u64::from(table.length),
+                    )
+                })
+            };
+            match result {
+                Ok((rdx, r8, r9)) => {
+                    guest.regs().rax = 0;
+                    guest.regs().rdx = rdx;
+                    guest.regs().r8 = r8;
+                    guest.regs().r9 = r9;
+                }
+                Err(e) => guest.regs().rax = e as u64,
+            }
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_AUTH_REGISTER => {
+            let token = guest.regs().rdx;
+            guest.regs().rax = if AUTH.register(token) {
+                0
+            } else {
+                log_hypercall_failure(
+                    number,
+                    hypercall::HC_ACCESS_DENIED,
+                    &"the token is 0 or already registered",
+                );
+                hypercall::HC_ACCESS_DENIED
+            };
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        hypercall::HC_AUTH_ALLOW_CR3 => {
+            AUTH.allow_cr3(guest.regs().rdx);
+            guest.regs().rax = 0;
+            guest.regs().rip = info.next_rip;
+            false
+        }
+        _ => {
+            guest.inject_exception(UD, None);
+            false
+        }
+    }
+}
+
+/// Logs the failure of the hypercall `number` that returns `value`, and records
+/// it for [`hypercall::HC_SHUTDOWN_QUERY`].
+fn log_hypercall_failure(number: u64, value: u64, error: &dyn core::fmt::Display) {
+    log::warn!("Hypercall {number:#x?} failed: {error}");
+    shutdown::record_error(number, value);
+}
+
+/// Saves the guest state for [`hypercall::HC_STATE_SAVE`], including the
+/// extended state and guest memory where possible.
+fn save_state<T: Guest>(
+    guest: &mut T,
+    info: &InstructionInfo,
+    extended_state: Option<&mut ExtendedState>,
+    saved_extended_state: &mut Option<ExtendedState>,
+) {
+    // Execution resumes after the hypercall when the state is restored, with
+    // RAX telling the guest so.
+    guest.regs().rip = info.next_rip;
+    guest.regs().rax = hypercall::HC_STATE_RESTORED;
+    guest.save_state();
+    guest.regs().rax = 0;
+
+    // The guest extended state is in `extended_state` if it is saved on
+    // VM-exit. Otherwise, it is still in the processor.
+    if saved_extended_state.is_none() {
+        *saved_extended_state = ExtendedState::new();
+    }
+    if let Some(saved) = saved_extended_state {
+        match extended_state {
+            Some(current) => saved.copy_from(current),
+            None => saved.save(),
+        }
+    }
+
+    match guest.take_snapshot() {
+        Ok(()) => {}
+        Err(SnapshotError::Unsupported) => {
+            log::info!("Saved the guest state without a snapshot of guest memory");
+        }
+        Err(e) => guest.regs().rax = e as u64,
+    }
+}
+
+/// Restores the guest state saved with [`hypercall::HC_STATE_SAVE`].
+fn restore_state<T: Guest>(
+    guest: &mut T,
+    extended_state: Option<&mut ExtendedState>,
+    saved_extended_state: Option<&ExtendedState>,
+) -> Result<(), SnapshotError> {
+    // Restore memory first, as it may fail without changing the guest state.
+    match guest.restore_snapshot() {
+        Ok(()) | Err(SnapshotError::Unsupported | SnapshotError::NoSnapshot) => {}
+        Err(e) => return Err(e),
+    }
+    if !guest.restore_state() {
+        return Err(SnapshotError::NoSnapshot);
+    }
+    if let Some(saved) = saved_extended_state {
+        match extended_state {
+            Some(current) => current.copy_from(saved),
+            None => saved.load(),
+        }
+    }
+    Ok(())
+}
+
+/// Handles the `RDTSC` and `RDTSCP` instructions, which are intercepted in the
+/// deterministic time mode.
+fn handle_rdtsc<T: Guest>(
+    guest: &mut T,
+    info: &InstructionInfo,
+    clock: Option<&mut DeterministicClock>,
+    rdtscp: bool,
+) {
+    let tsc = clock.map_or_else(|| unsafe { _rdtsc() }, DeterministicClock::read_tsc);
+    guest.regs().rax = tsc & 0xffff_ffff;
+    guest.regs().rdx = tsc >> 32;
+    if rdtscp {
+        guest.regs().rcx = rdmsr(x86::msr::IA32_TSC_AUX) & 0xffff_ffff;
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+/// Handles the `RDRAND` and `RDSEED` instructions, which are intercepted in
+/// the deterministic time mode.
+fn handle_rdrand<T: Guest>(
+    guest: &mut T,
+    info: &RandomInfo,
+    clock: Option<&mut DeterministicClock>,
+) {
+    let Some(clock) = clock else {
+        panic!("Unhandled {info:#x?} without the deterministic time mode");
+    };
+
+    // The destination is written as with MOV, that is, 16-bit operands keep
+    // the upper bits, and 32-bit operands clear them. The random number is
+    // always available: CF is set, and OF, SF, ZF, AF and PF are cleared.
+    // See: RDRAND—Read Random Number
+    let value = clock.random();
+    let value = match info.size {
+        2 => (guest.regs().gpr(info.gpr) & !0xffff) | (value & 0xffff),
+        4 => value & 0xffff_ffff,
+        _ => value,
+    };
+    guest.regs().set_gpr(info.gpr, value);
+    let mut rflags = RFlags::from_raw(guest.regs().rflags);
+    rflags.remove(
+        RFlags::FLAGS_OF
+            | RFlags::FLAGS_SF
+            | RFlags::FLAGS_ZF
+            | RFlags::FLAGS_AF
+            | RFlags::FLAGS_PF,
+    );
+    rflags.insert(RFlags::FLAGS_CF);
+    guest.regs().rflags = rflags.bits();
+    guest.regs().rip = info.next_rip;
+}
+
+/// Handles the triple fault of the guest according to
+/// [`HvConfig::triple_fault`](super::config::HvConfig::triple_fault), after
+/// dumping the guest state to the crash dump report and the log. Does not
+/// return if the processor is frozen.
+fn handle_shutdown<T: Guest>(guest: &mut T, id: usize) {
+    log::error!("The guest entered the shutdown state on processor {id}");
+    log::error!("{:#x?}", guest.regs());
+    crash_dump::dump_state(guest);
+
+    let policy = SHARED_HOST_DATA.get().unwrap().config.triple_fault;
+    if policy == TripleFaultPolicy::Reset {
+        if guest.reset() {
+            log::warn!("Reset processor {id} to the wait-for-SIPI state");
+            return;
+        }
+        log::error!("Processor {id} cannot be reset");
+    }
+
+    // Keep the processor in the host with the guest state intact. Serve GDB
+    // if enabled, as execution cannot be resumed.
+    log::error!("Freezing processor {id}");
+    let registers = GdbRegisters::from(&*guest.regs());
+    loop {
+        if gdb_stub::enabled() {
+            let _ = gdb_stub::stop(Signal::Abort, &registers);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Handles the `HLT` instruction by putting the guest into the halt state if
+/// possible. Otherwise, resumes the guest immediately, which is permitted as
+/// the processor may exit the halt state on any event. See
+/// [`super::config::HltPolicy`].
+fn handle_hlt<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+    guest.regs().rip = info.next_rip;
+    let _ = guest.halt();
+}
+
+/// Handles the `INVD` and `WBINVD` instructions, which are always intercepted.
+/// INVD discards modified cache lines, including those holding the data of the
+/// host, and thus, both write back and invalidate the caches.
+///
+/// See: INVD—Invalidate Internal Caches
+fn handle_cache_invalidation<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+    wbinvd();
+    guest.regs().rip = info.next_rip;
+}
+
 /// Represents a processor architecture that implements hardware-assisted virtualization.
 pub(crate) trait Architecture {
     type VirtualizationExtension: Extension;
@@ -162,8 +1034,15 @@ pub(crate) trait Architecture {
 
 /// Represents an implementation of a hardware-assisted virtualization extension.
 pub(crate) trait Extension: Default {
+    /// Checks that the processor supports the extension and the features the
+    /// hypervisor requires.
+    fn check_support() -> Result<(), UnsupportedFeature>;
+
     /// Enables the hardware-assisted virtualization extension.
     fn enable(&mut self);
+
+    /// Disables the hardware-assisted virtualization extension.
+    fn disable(&mut self);
 }
 
 /// Represents an implementation of a guest.
@@ -185,20 +1064,399 @@ pub(crate) trait Guest {
 
     /// Gets a reference to some of guest registers.
     fn regs(&mut self) -> &mut Registers;
+
+    /// Injects the exception `vector` with `error_code` to the guest on the next
+    /// VM-entry. NMI is injected as NMI.
+    fn inject_exception(&mut self, vector: u8, error_code: Option<u32>);
+
+    /// Injects #BP or #OF `vector` raised by INT3 or INTO at RIP, which ends at
+    /// `next_rip`, so that the processor checks the DPL of the IDT gate and
+    /// pushes `next_rip` as the instruction would.
+    fn inject_software_exception(&mut self, vector: u8, next_rip: u64);
+
+    /// Returns the current privilege level of the guest.
+    fn cpl(&self) -> u8;
+
+    /// Puts the guest into the HLT activity state, so that it idles until the
+    /// next interrupt without VM-exit. Returns `false` if the processor does
+    /// not support it.
+    fn halt(&mut self) -> bool;
+
+    /// Puts the guest into the wait-for-SIPI state as INIT does, for example,
+    /// after the guest triple-faulted. Returns `false` if INIT cannot be
+    /// emulated on this processor.
+    fn reset(&mut self) -> bool;
+
+    /// Loads the guest state that is not in `Registers` into the processor, and
+    /// stops operating on this guest. The guest cannot be run after this.
+    fn devirtualize(&mut self);
+
+    /// Saves the registers and the system state of the guest, such as control
+    /// registers, segments and MSRs, replacing the previously saved one.
+    fn save_state(&mut self);
+
+    /// Restores the state saved with `save_state`. Returns `false` if nothing
+    /// is saved.
+    fn restore_state(&mut self) -> bool;
+
+    /// Takes a copy-on-write snapshot of guest memory. See [`super::snapshot`].
+    fn take_snapshot(&mut self) -> Result<(), SnapshotError>;
+
+    /// Restores guest memory to the snapshot point.
+    fn restore_snapshot(&mut self) -> Result<(), SnapshotError>;
+
+    /// Discards the snapshot and makes guest memory writable again.
+    fn discard_snapshot(&mut self) -> Result<(), SnapshotError>;
+
+    /// Saves the page at `gpa` to the snapshot and makes it writable, if the
+    /// write to it faulted because of the snapshot. Returns `true` if so.
+    fn handle_snapshot_write(&mut self, gpa: u64) -> bool;
+
+    /// Runs the payload in `request` in a mini VM on the current processor
+    /// until the first VM-exit, and returns why it exited and the RAX of the
+    /// payload. See [`super::mini_vm`].
+    fn run_mini_vm(&mut self, request: &MiniVmRequest) -> Result<(MiniVmExit, u64), MiniVmError>;
+
+    /// Makes the EPT view `view` active on the current processor. See
+    /// [`super::ept_views`].
+    fn switch_ept_view(&mut self, view: usize) -> Result<(), EptViewError>;
+
+    /// Sets the permissions of the 4KB aligned GPA `range` in the EPT view
+    /// `view`. Separate user-mode execute permissions are unsupported without
+    /// MBEC.
+    fn protect_ept_view(
+        &mut self,
+        view: usize,
+        range: Range<u64>,
+        permissions: EptPermissions,
+    ) -> Result<(), EptViewError>;
+
+    /// Write-protects the 4KB page at `gpa` in the EPT view `view` except for
+    /// the 128-byte sub-pages whose bits are set in `writable`. `u32::MAX`
+    /// makes the whole page writable again. The sub-page permissions of a page
+    /// are shared by all views that protect it.
+    fn protect_sub_pages(
+        &mut self,
+        view: usize,
+        gpa: u64,
+        writable: u32,
+    ) -> Result<(), EptViewError>;
+
+    /// Switches to the default EPT view if another view is active, as the
+    /// nested page fault is caused by the permissions of that view. Returns
+    /// `true` if so.
+    fn handle_ept_view_fault(&mut self) -> bool;
+
+    /// Binds the EPT view `view` to the address space `cr3`.
+    fn bind_ept_view(&mut self, cr3: u64, view: usize) -> Result<(), EptViewError>;
+
+    /// Emulates MOV to CR3 with `cr3`, and switches to the EPT view bound to
+    /// the new address space if any view is bound.
+    fn write_cr3(&mut self, cr3: u64);
+
+    /// Returns the guest CR3 if the guest uses 4-level paging in 64-bit mode.
+    fn long_mode_cr3(&self) -> Option<u64>;
+
+    /// Returns the system registers of the guest that are not in
+    /// [`Registers`].
+    fn full_state(&self) -> FullGuestState;
+
+    /// Enables or disables VM-exit after each guest instruction with the
+    /// monitor trap flag. Returns `false` if the processor does not support
+    /// it.
+    fn set_monitor_trap(&mut self, enable: bool) -> bool;
+
+    /// Enables or disables VM-exit on the instructions accessing the
+    /// descriptor-table registers. See [`super::umip`].
+    fn set_descriptor_table_exiting(&mut self, enable: bool);
+
+    /// Re-reads the capabilities of the processor after a microcode update,
+    /// and logs any change. The capabilities at virtualization stay in use.
+    fn reprobe_capabilities(&self);
+
+    /// Returns the guest IA32_LSTAR, that is, the entry point of SYSCALL in
+    /// 64-bit mode.
+    fn lstar(&self) -> u64;
+
+    /// Returns where the branch records of the guest are. See [`super::lbr`].
+    fn last_branches(&self) -> GuestLastBranches;
+
+    /// Records the guest writing `value` to `msr` if the MSR is switched
+    /// between the host and the guest, and returns the value to write to the
+    /// processor, or `None` if the write is emulated entirely.
+    fn write_msr(&mut self, msr: u32, value: u64) -> Option<u64>;
+
+    /// Writes the VMCS or VMCB of the guest for diagnostics.
+    fn dump(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result;
+
+    /// Returns the names of the intercepts enabled beyond those the processor
+    /// or the hypervisor requires. See [`super::exit_profile`].
+    fn optional_intercepts(&self) -> Vec<String>;
 }
 
 /// The reasons of VM-exit and additional information.
-pub(crate) enum VmExitReason {
+#[derive(Debug)]
+pub enum VmExitReason {
     Cpuid(InstructionInfo),
     Rdmsr(InstructionInfo),
     Wrmsr(InstructionInfo),
     XSetBv(InstructionInfo),
+    /// `MOV` to or from a control register.
+    CrAccess(CrAccessInfo),
+    /// `IN`, `OUT`, `INS` or `OUTS`.
+    IoInstruction(IoInfo),
+    /// An exception or NMI.
+    Exception(ExceptionInfo),
+    Hlt(InstructionInfo),
+    /// `VMCALL` on Intel and `VMMCALL` on AMD.
+    Hypercall(InstructionInfo),
+    /// The guest entered the shutdown state, eg, due to triple fault.
+    Shutdown,
+    /// The guest executed a single instruction with the monitor trap flag.
+    MonitorTrap,
+    /// EPT violation on Intel and #VMEXIT(NPF) on AMD.
+    NestedPageFault(NestedPageFaultInfo),
     InitSignal,
     StartupIpi,
-    NestedPageFault,
+    Rdtsc(InstructionInfo),
+    Rdtscp(InstructionInfo),
+    /// `RDRAND` or `RDSEED`.
+    Rdrand(RandomInfo),
+    /// `RDPMC`. See [`super::pmu`].
+    Rdpmc(InstructionInfo),
+    /// `INVD` or `WBINVD`.
+    CacheInvalidation(InstructionInfo),
+    /// `MONITOR`, or `MONITORX` on AMD. See [`super::mwait`].
+    Monitor(InstructionInfo),
+    /// `MWAIT`, or `MWAITX` on AMD. See [`super::mwait`].
+    Mwait(InstructionInfo),
+    /// A guest spin loop with `PAUSE` ran longer than configured. See
+    /// [`HvConfig::pause_loop_exiting`](super::config::HvConfig::pause_loop_exiting).
+    Pause,
+    /// The VMX-preemption timer expired. See [`super::watchdog`].
+    PreemptionTimer,
+    /// An SMI occurred while the guest ran. AMD only. See [`super::smi`].
+    Smi,
+    /// `SGDT`, `SIDT`, `SLDT` or `STR`, or on Intel, `LGDT`, `LIDT`, `LLDT` or
+    /// `LTR`. See [`super::umip`].
+    DescriptorTableAccess(DescriptorTableInfo),
 }
 
-pub(crate) struct InstructionInfo {
+#[derive(Debug)]
+pub struct InstructionInfo {
     /// The next RIP of the guest in case the current instruction is emulated.
-    pub(crate) next_rip: u64,
+    pub next_rip: u64,
+}
+
+#[derive(Debug)]
+pub struct CrAccessInfo {
+    pub next_rip: u64,
+    /// The control register number.
+    pub cr: u8,
+    /// `true` for `MOV` to CR, `false` for `MOV` from CR.
+    pub write: bool,
+    /// The general purpose register operand, if known. See `Registers::gpr`.
+    pub gpr: Option<u8>,
+}
+
+#[derive(Debug)]
+pub struct IoInfo {
+    pub next_rip: u64,
+    pub port: u16,
+    /// The size of the access in bytes.
+    pub size: u8,
+    /// `true` for `IN` and `INS`.
+    pub input: bool,
+    /// `true` for `INS` and `OUTS`.
+    pub string: bool,
+    /// `true` if the `REP` prefix is used.
+    pub rep: bool,
+}
+
+#[derive(Debug)]
+pub struct RandomInfo {
+    pub next_rip: u64,
+    /// `true` for `RDSEED`.
+    pub seed: bool,
+    /// The destination register. See `Registers::gpr`.
+    pub gpr: u8,
+    /// The size of the operand in bytes.
+    pub size: u8,
+}
+
+#[derive(Debug)]
+pub struct DescriptorTableInfo {
+    pub next_rip: u64,
+    pub instruction: DescriptorTableInstruction,
+    /// The register or memory operand, or `None` if it could not be decoded,
+    /// for example, on AMD, where the instruction is decoded as a 64-bit one.
+    pub operand: Option<DescriptorTableOperand>,
+    /// `true` if the guest runs in 64-bit mode, where `SGDT` and `SIDT` store
+    /// 8-byte bases.
+    pub long_mode: bool,
+}
+
+/// The instructions accessing the descriptor-table registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTableInstruction {
+    Sgdt,
+    Sidt,
+    Sldt,
+    Str,
+    Lgdt,
+    Lidt,
+    Lldt,
+    Ltr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTableOperand {
+    /// The linear address of the memory operand.
+    Memory(u64),
+    /// The register operand and its size in bytes. See `Registers::gpr`.
+    Register { gpr: u8, size: u8 },
+}
+
+#[derive(Debug)]
+pub struct ExceptionInfo {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+    /// The address of the next instruction if the exception is caused by INT3
+    /// or INTO, which are reported at the instruction.
+    pub next_rip: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct NestedPageFaultInfo {
+    /// The guest physical address that caused the fault.
+    pub gpa: u64,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl VmExitReason {
+    /// Returns the kind of this VM-exit, or `None` if this VM-exit is handled
+    /// entirely by the architecture specific code.
+    pub fn kind(&self) -> Option<VmExitKind> {
+        Some(match self {
+            Self::Cpuid(_) => VmExitKind::Cpuid,
+            Self::Rdmsr(_) => VmExitKind::Rdmsr,
+            Self::Wrmsr(_) => VmExitKind::Wrmsr,
+            Self::XSetBv(_) => VmExitKind::XSetBv,
+            Self::CrAccess(_) => VmExitKind::CrAccess,
+            Self::IoInstruction(_) => VmExitKind::IoInstruction,
+            Self::Exception(_) => VmExitKind::Exception,
+            Self::Hlt(_) => VmExitKind::Hlt,
+            Self::Hypercall(_) => VmExitKind::Hypercall,
+            Self::Shutdown => VmExitKind::Shutdown,
+            Self::MonitorTrap => VmExitKind::MonitorTrap,
+            Self::NestedPageFault(_) => VmExitKind::NestedPageFault,
+            Self::Rdtsc(_) => VmExitKind::Rdtsc,
+            Self::Rdtscp(_) => VmExitKind::Rdtscp,
+            Self::Rdrand(_) => VmExitKind::Rdrand,
+            Self::Rdpmc(_) => VmExitKind::Rdpmc,
+            Self::CacheInvalidation(_) => VmExitKind::CacheInvalidation,
+            Self::Monitor(_) => VmExitKind::Monitor,
+            Self::Mwait(_) => VmExitKind::Mwait,
+            Self::Pause => VmExitKind::Pause,
+            Self::Smi => VmExitKind::Smi,
+            Self::DescriptorTableAccess(_) => VmExitKind::DescriptorTableAccess,
+            Self::InitSignal | Self::StartupIpi | Self::PreemptionTimer => {
+                return None;
+            }
+        })
+    }
+
+    /// Returns the RIP of the next instruction if this VM-exit is caused by
+    /// execution of an instruction.
+    pub fn next_rip(&self) -> Option<u64> {
+        match self {
+            Self::Cpuid(info)
+            | Self::Rdmsr(info)
+            | Self::Wrmsr(info)
+            | Self::XSetBv(info)
+            | Self::Hlt(info)
+            | Self::Hypercall(info)
+            | Self::Rdtsc(info)
+            | Self::Rdtscp(info)
+            | Self::Rdpmc(info)
+            | Self::CacheInvalidation(info)
+            | Self::Monitor(info)
+            | Self::Mwait(info) => Some(info.next_rip),
+            Self::CrAccess(info) => Some(info.next_rip),
+            Self::IoInstruction(info) => Some(info.next_rip),
+            Self::Rdrand(info) => Some(info.next_rip),
+            Self::DescriptorTableAccess(info) => Some(info.next_rip),
+            Self::Exception(info) => info.next_rip,
+            _ => None,
+        }
+    }
+}
+
+/// The kinds of VM-exit that can be handled by [`VmExitHandler`]s.
+///
+/// [`VmExitHandler`]: super::exit_handlers::VmExitHandler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitKind {
+    Cpuid,
+    Rdmsr,
+    Wrmsr,
+    XSetBv,
+    CrAccess,
+    IoInstruction,
+    Exception,
+    Hlt,
+    Hypercall,
+    Shutdown,
+    MonitorTrap,
+    NestedPageFault,
+    Rdtsc,
+    Rdtscp,
+    Rdrand,
+    Pause,
+    DescriptorTableAccess,
+    Rdpmc,
+    Monitor,
+    Mwait,
+    CacheInvalidation,
+    Smi,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_value_merge() {
+        let rax = 0x1122_3344_5566_7788;
+        assert_eq!(merge_in_value(rax, 1, 0xab), 0x1122_3344_5566_77ab);
+        assert_eq!(merge_in_value(rax, 2, 0xabcd), 0x1122_3344_5566_abcd);
+        assert_eq!(merge_in_value(rax, 4, 0xabcd_ef01), 0xabcd_ef01);
+    }
+
+    #[test]
+    fn xcr0_validation() {
+        // x87, SSE, AVX, MPX, AVX-512, PKRU and AMX.
+        let supported = 0x6_02ff;
+
+        assert!(is_valid_xcr0(0b1, supported));
+        assert!(is_valid_xcr0(0b111, supported));
+        assert!(is_valid_xcr0(0xe7, supported));
+        assert!(is_valid_xcr0(0x6_0007, supported));
+
+        // x87 must be enabled.
+        assert!(!is_valid_xcr0(0b110, supported));
+        // AVX requires SSE.
+        assert!(!is_valid_xcr0(0b101, supported));
+        // MPX bits must match.
+        assert!(!is_valid_xcr0(0b1_0111, supported));
+        // AVX-512 bits must match and require AVX.
+        assert!(!is_valid_xcr0(0x67, supported));
+        assert!(!is_valid_xcr0(0xe3, supported));
+        // AMX bits must match.
+        assert!(!is_valid_xcr0(0x2_0007, supported));
+        // Unsupported bits must not be set.
+        assert!(!is_valid_xcr0(0b111, 0b11));
+    }
 }
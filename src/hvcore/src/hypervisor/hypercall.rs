@@ -0,0 +1,239 @@
+//! This module implements the hypercall interface of the hypervisor.
+//!
+//! The guest makes a hypercall with the VMCALL instruction on Intel and the
+//! VMMCALL instruction on AMD processors, with the hypercall number in RCX and
+//! arguments in RDX, R8 and R9. The result is returned in RAX. Hypercalls are
+//! only accepted from CPL 0. Otherwise, or if the number is unknown, #UD is
+//! injected.
+//!
+//! Once a token is registered with [`HC_AUTH_REGISTER`], R10 must hold the
+//! token unless the address space is allowed with [`HC_AUTH_ALLOW_CR3`].
+//! Otherwise, [`HC_ACCESS_DENIED`] is returned. See
+//! [`hypercall_auth`](super::hypercall_auth).
+
+use core::arch::asm;
+
+/// Devirtualizes the current processor. The guest continues to run without the
+/// hypervisor after the hypercall.
+pub const HC_DEVIRTUALIZE: u64 = 0x1;
+
+/// Takes a copy-on-write snapshot of guest memory, discarding the current one.
+/// Returns 0 on success, or a
+/// [`SnapshotError`](super::snapshot::SnapshotError) value.
+///
+/// Only memory mapped with the write-back memory type is snapshotted, and
+/// writes by devices (DMA) are not tracked. Other processors invalidate their
+/// cached translations only on their next VM-exit, and should not run the code
+/// under test while a snapshot is taken or restored.
+pub const HC_SNAPSHOT_TAKE: u64 = 0x2;
+
+/// Restores guest memory to the snapshot point. The snapshot stays, and can be
+/// restored again. Returns 0 on success, or a
+/// [`SnapshotError`](super::snapshot::SnapshotError) value.
+pub const HC_SNAPSHOT_RESTORE: u64 = 0x3;
+
+/// Discards the snapshot and stops tracking writes to guest memory. Returns 0
+/// on success, or a
+/// [`SnapshotError`](super::snapshot::SnapshotError) value.
+pub const HC_SNAPSHOT_DISCARD: u64 = 0x4;
+
+/// Saves the registers, system and extended state of the current processor,
+/// and takes a snapshot of guest memory as [`HC_SNAPSHOT_TAKE`] if snapshots
+/// are available. Returns 0, or [`HC_STATE_RESTORED`] when execution resumes
+/// here with [`HC_STATE_RESTORE`].
+pub const HC_STATE_SAVE: u64 = 0x5;
+
+/// Restores the state saved with [`HC_STATE_SAVE`] on the current processor,
+/// and guest memory if a snapshot is taken. Does not return on success, and
+/// returns a [`SnapshotError`](super::snapshot::SnapshotError) value otherwise.
+pub const HC_STATE_RESTORE: u64 = 0x6;
+
+/// The value [`HC_STATE_SAVE`] returns when the saved state is restored.
+pub const HC_STATE_RESTORED: u64 = 0x100;
+
+/// Runs the payload in the blob at the GPA in RDX of the size in R8 in a mini
+/// VM, in real-mode from the RIP in R9. Returns 0, the exit code in RDX, the
+/// RAX of the payload in R8, and the vector, GPA or exit reason in R9. See
+/// [`MiniVmExit::to_raw`](super::mini_vm::MiniVmExit::to_raw). Returns a
+/// [`MiniVmError`](super::mini_vm::MiniVmError) value on failure.
+pub const HC_RUN_MINI_VM_REAL: u64 = 0x7;
+
+/// Same as [`HC_RUN_MINI_VM_REAL`] but runs the payload in 64-bit mode.
+pub const HC_RUN_MINI_VM_LONG: u64 = 0x8;
+
+/// Switches the EPT view of the current processor to the index in RDX. Returns
+/// 0 on success, or an [`EptViewError`](super::ept_views::EptViewError) value.
+/// Where the processor supports it, the guest can also switch views without
+/// VM-exit with VMFUNC leaf 0 and the index in ECX. Snapshots track writes
+/// through the default view only.
+pub const HC_EPT_VIEW_SWITCH: u64 = 0x9;
+
+/// Sets the permissions of pages in the EPT view of the index in RDX. R8 is
+/// the 4KB aligned GPA with the permissions in the low bits (see
+/// [`EptPermissions::from_raw`](super::ept_views::EptPermissions::from_raw)),
+/// and R9 is the size in bytes. Returns 0 on success, or an
+/// [`EptViewError`](super::ept_views::EptViewError) value. Other processors
+/// use stale cached translations until their next VM-exit, as with
+/// [`HC_SNAPSHOT_RESTORE`].
+pub const HC_EPT_VIEW_PROTECT: u64 = 0xa;
+
+/// Binds the EPT view of the index in R8 to the address space with the CR3 in
+/// RDX, so that the view becomes active whenever the guest switches to it.
+/// Binding the default view removes the binding. Returns 0 on success, or an
+/// [`EptViewError`](super::ept_views::EptViewError) value.
+pub const HC_EPT_VIEW_BIND_CR3: u64 = 0xb;
+
+/// Registers the secret token in RDX, which must not be 0. Every hypercall
+/// after this must pass the token in R10. Returns 0 on success, or
+/// [`HC_ACCESS_DENIED`] if a token is already registered.
+pub const HC_AUTH_REGISTER: u64 = 0xc;
+
+/// Allows hypercalls from the address space with the CR3 in RDX without the
+/// token. Returns 0.
+pub const HC_AUTH_ALLOW_CR3: u64 = 0xd;
+
+/// Copies the guest physical memory at the GPA in RDX into the buffer at the GVA
+/// in R8 in the current address space, which must be writable by the caller,
+/// including under SMAP. R9 is the size in bytes, up to
+/// [`MAX_READ_SIZE`](super::phys_read::MAX_READ_SIZE). Returns 0 on success,
+/// or a [`PhysReadError`](super::phys_read::PhysReadError) value.
+pub const HC_READ_PHYS: u64 = 0xe;
+
+/// Dumps the VMCS or VMCB of the current processor for the report of
+/// [`crash_dump`](super::crash_dump). Returns 0.
+pub const HC_DUMP_STATE: u64 = 0xf;
+
+/// Returns the results of the self-test on the current processor, packed as
+/// [`SelfTestResults`](super::self_test::SelfTestResults), or 0 if the
+/// self-test has not run.
+pub const HC_SELF_TEST_RESULTS: u64 = 0x10;
+
+/// Returns the median cycles of CPUID, RDMSR and VMCALL on the current
+/// processor in RDX, R8 and R9, measured after virtualization if RDX is
+/// non-zero, or before virtualization otherwise. Returns 0. See
+/// [`benchmark`](super::benchmark).
+pub const HC_BENCHMARK_RESULTS: u64 = 0x11;
+
+/// Sets the policy of the exception vector in RDX to the kind in R8, with the
+/// CR3 in R9 for breakpoints. See
+/// [`ExceptionPolicy::from_raw`](super::exception_policy::ExceptionPolicy::from_raw).
+/// Returns 0 on success, or an
+/// [`ExceptionPolicyError`](super::exception_policy::ExceptionPolicyError)
+/// value.
+pub const HC_EXCEPTION_POLICY: u64 = 0x12;
+
+/// Write-protects the page in the EPT view of the index in RDX except for some
+/// 128-byte sub-pages. R8 is the 4KB aligned GPA, and bit N of R9 permits
+/// writes to bytes `N * 128` to `N * 128 + 127`. `0xffff_ffff` removes the
+/// protection. Returns 0 on success, or an
+/// [`EptViewError`](super::ept_views::EptViewError) value. Requires
+/// `HvConfig::sub_page_permissions`.
+pub const HC_EPT_VIEW_PROTECT_SUB_PAGES: u64 = 0x13;
+
+/// Returns 0 and the GPA of the paravirtual clock page in RDX, or a
+/// [`PvClockError`](super::pv_clock::PvClockError) value. Requires
+/// `HvConfig::pv_clock`. See [`pv_clock`](super::pv_clock).
+pub const HC_PV_CLOCK_PAGE: u64 = 0x14;
+
+/// Returns 0 and the region of the index in RDX of the UEFI memory map
+/// recorded at ExitBootServices(): the physical address in RDX, the number of
+/// 4KB pages in R8 and the `EFI_MEMORY_TYPE` value in R9. Returns a
+/// [`BootInfoError`](super::boot_info::BootInfoError) value on failure. See
+/// [`boot_info`](super::boot_info).
+pub const HC_BOOT_MEMORY_MAP: u64 = 0x15;
+
+/// Returns 0 and the ACPI table of the index in RDX recorded at load time: the
+/// signature in the low 4 bytes of RDX, the physical address in R8 and the
+/// length in R9. Returns a
+/// [`BootInfoError`](super::boot_info::BootInfoError) value on failure. See
+/// [`boot_info`](super::boot_info).
+pub const HC_BOOT_ACPI_TABLE: u64 = 0x16;
+
+/// Searches guest physical memory from the GPA in R8 up to the GPA in R9 for
+/// the [`ScanPattern`](super::phys_scan::ScanPattern) at the GVA in RDX in the
+/// current address space. Returns 0 and the GPA of the first match in RDX, with
+/// R8 advanced past it to continue the scan from. Returns a
+/// [`PhysScanError`](super::phys_scan::PhysScanError) value on failure,
+/// including when there is no more match. The range should be RAM, for
+/// example, reported with [`HC_BOOT_MEMORY_MAP`]. See
+/// [`phys_scan`](super::phys_scan).
+pub const HC_SCAN_PHYS: u64 = 0x17;
+
+/// Returns 0 and the status of the processor of the ID in RDX: the number of
+/// VM-exits in RDX, the last failed hypercall in R8 and the number of hooks in
+/// effect in R9. See [`ProcessorStatus`](super::shutdown::ProcessorStatus).
+/// Returns a [`ShutdownError`](super::shutdown::ShutdownError) value on
+/// failure.
+pub const HC_SHUTDOWN_QUERY: u64 = 0x18;
+
+/// Tears down hooks, restores intercepted MSRs and devirtualizes the current
+/// processor, in this order. Returns 0 after devirtualization as
+/// [`HC_DEVIRTUALIZE`]. See [`shutdown`](super::shutdown).
+pub const HC_SHUTDOWN_COMMIT: u64 = 0x19;
+
+/// Returns 0 and the state of the hypervisor for the processors with the IDs
+/// from RDX times 64: the [`Backend`](super::status::Backend) in the lower 32
+/// bits of RDX and the number of processors in the upper 32 bits, the
+/// [`ActiveFeatures`](super::status::ActiveFeatures) in R8, and the bitmap of
+/// the processors virtualized in R9. Returns a
+/// [`StatusError`](super::status::StatusError) value on failure. Accepted
+/// without the token, as it changes nothing and CPUID tells the presence of
+/// the hypervisor anyway. See [`status`](super::status).
+pub const HC_STATUS: u64 = 0x1a;
+
+/// The value hypercalls return when they are not authenticated.
+pub const HC_ACCESS_DENIED: u64 = 0xffff_ffff_acce_55de;
+
+/// Makes the hypercall `number` with `args`, and returns the result.
+///
+/// The hypervisor must be present on the current processor. Otherwise, #UD
+/// occurs.
+pub fn hypercall(number: u64, args: [u64; 3]) -> u64 {
+    hypercall_with_token(number, args, 0)
+}
+
+/// Makes the hypercall `number` with `args` and the token registered with
+/// [`HC_AUTH_REGISTER`], and returns the result.
+pub fn hypercall_with_token(number: u64, args: [u64; 3], token: u64) -> u64 {
+    call(number, args, token)[0]
+}
+
+/// Makes the hypercall `number` with `args`, and returns RAX, RDX, R8 and R9,
+/// for hypercalls returning values in those registers.
+pub fn hypercall_with_outputs(number: u64, args: [u64; 3]) -> [u64; 4] {
+    call(number, args, 0)
+}
+
+fn call(number: u64, args: [u64; 3], token: u64) -> [u64; 4] {
+    let mut regs = [0u64; 4];
+    if is_intel() {
+        unsafe {
+            asm!(
+                "vmcall",
+                in("rcx") number,
+                inout("rdx") args[0] => regs[1],
+                inout("r8") args[1] => regs[2],
+                inout("r9") args[2] => regs[3],
+                in("r10") token,
+                lateout("rax") regs[0],
+            );
+        };
+    } else {
+        unsafe {
+            asm!(
+                "vmmcall",
+                in("rcx") number,
+                inout("rdx") args[0] => regs[1],
+                inout("r8") args[1] => regs[2],
+                inout("r9") args[2] => regs[3],
+                in("r10") token,
+                lateout("rax") regs[0],
+            );
+        };
+    }
+    regs
+}
+
+fn is_intel() -> bool {
+    x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel"
+}
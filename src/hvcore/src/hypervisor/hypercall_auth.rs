@@ -0,0 +1,112 @@
+//! This module implements authentication of hypercalls.
+//!
+//! Hypercalls are only accepted from CPL 0. In addition, a control driver may
+//! register a per-boot secret token with [`HC_AUTH_REGISTER`]. Once a token is
+//! registered, a hypercall is only accepted if R10 holds the token, or if it is
+//! made in an address space allowed with [`HC_AUTH_ALLOW_CR3`]. Other hypercalls
+//! return [`HC_ACCESS_DENIED`] without any effect, except [`HC_STATUS`], which
+//! only reports the state of the hypervisor.
+//!
+//! The token can only be registered once, so the control driver should register
+//! it as early as possible. Until then, all hypercalls from CPL 0 are accepted.
+//!
+//! [`HC_AUTH_REGISTER`]: super::hypercall::HC_AUTH_REGISTER
+//! [`HC_AUTH_ALLOW_CR3`]: super::hypercall::HC_AUTH_ALLOW_CR3
+//! [`HC_ACCESS_DENIED`]: super::hypercall::HC_ACCESS_DENIED
+//! [`HC_STATUS`]: super::hypercall::HC_STATUS
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+use spin::RwLock;
+
+/// The state of hypercall authentication shared by all processors.
+pub(crate) static AUTH: HypercallAuth = HypercallAuth::new();
+
+/// The registered token and the allowed address spaces.
+pub(crate) struct HypercallAuth {
+    /// The registered token, or 0 if none is registered. This is atomic, as
+    /// the guest reads it to make hypercalls itself. See [`Self::token`].
+    token: AtomicU64,
+
+    /// The page-aligned CR3 values of the allowed address spaces.
+    allowed_cr3s: RwLock<Vec<u64>>,
+}
+
+impl HypercallAuth {
+    /// Bits 51:12 of CR3, that is, the address of the top-level paging
+    /// structure without the PCID and flags.
+    /// See: Table 4-12. Use of CR3 with 4-Level Paging and 5-level Paging and CR4.PCIDE = 1
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    const fn new() -> Self {
+        Self {
+            token: AtomicU64::new(0),
+            allowed_cr3s: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Registers `token`, and returns `false` if a token is already registered
+    /// or `token` is 0.
+    pub(crate) fn register(&self, token: u64) -> bool {
+        token != 0
+            && self
+                .token
+                .compare_exchange(0, token, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    }
+
+    /// Returns the registered token, or 0 if none is registered.
+    ///
+    /// The hypervisor itself uses this to devirtualize processors, as the
+    /// control driver may be a different driver.
+    pub(crate) fn token(&self) -> u64 {
+        self.token.load(Ordering::SeqCst)
+    }
+
+    /// Allows hypercalls from the address space `cr3` without the token.
+    pub(crate) fn allow_cr3(&self, cr3: u64) {
+        let cr3 = cr3 & Self::ADDRESS_MASK;
+        let mut allowed_cr3s = self.allowed_cr3s.write();
+        if !allowed_cr3s.contains(&cr3) {
+            allowed_cr3s.push(cr3);
+        }
+    }
+
+    /// Returns `true` if a hypercall with `token` in the address space `cr3`
+    /// is accepted. `cr3` is `None` if it is unknown.
+    pub(crate) fn is_authorized(&self, token: u64, cr3: Option<u64>) -> bool {
+        let registered = self.token();
+        registered == 0
+            || token == registered
+            || cr3.is_some_and(|cr3| {
+                self.allowed_cr3s
+                    .read()
+                    .contains(&(cr3 & Self::ADDRESS_MASK))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize() {
+        let auth = HypercallAuth::new();
+        assert!(auth.is_authorized(0, None));
+        assert!(!auth.register(0));
+
+        assert!(auth.register(0x1234_5678));
+        assert!(!auth.register(0x8765_4321));
+        assert_eq!(auth.token(), 0x1234_5678);
+        assert!(auth.is_authorized(0x1234_5678, None));
+        assert!(!auth.is_authorized(0, Some(0x1aa000)));
+
+        // The PCID and the no-flush bit are ignored.
+        auth.allow_cr3(0x1aa000);
+        assert!(auth.is_authorized(0, Some(0x8000_0000_001a_a003)));
+        assert!(!auth.is_authorized(0, Some(0x1ab000)));
+        assert!(!auth.is_authorized(0, None));
+    }
+}
@@ -0,0 +1,103 @@
+//! This module implements the minimal Hyper-V compatible interface presented
+//! in the advertise-hypervisor mode.
+//!
+//! With [`HvConfig::advertise_hypervisor`], CPUID reports that a hypervisor is
+//! present with the hypervisor-present bit, CPUID.1:ECX[31]. Windows then
+//! probes the Hyper-V identification leaves and may access the synthetic MSRs.
+//! The hypervisor answers the leaves as a Hyper-V compatible hypervisor with
+//! no feature, so that Windows uses no enlightenment, and ignores the
+//! synthetic MSRs: RDMSR returns 0 and WRMSR is dropped. The vendor name in
+//! leaf 4000_0000h stays that of this hypervisor.
+//!
+//! Without the mode, the synthetic MSRs do not exist, and access to them
+//! causes #GP(0) as it would on bare metal.
+//!
+//! See: Hypervisor Top Level Functional Specification
+//!
+//! [`HvConfig::advertise_hypervisor`]: super::config::HvConfig::advertise_hypervisor
+
+use x86::cpuid::CpuIdResult;
+
+use super::{HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, SHARED_HOST_DATA};
+
+/// The interface signature in EAX of leaf 4000_0001h.
+const HV_INTERFACE_SIGNATURE: u32 = u32::from_le_bytes(*b"Hv#1");
+
+/// The largest leaf a Hyper-V compatible hypervisor must implement, the
+/// implementation limits leaf.
+const HV_CPUID_IMPLEMENTATION_LIMITS: u32 = 0x4000_0005;
+
+/// The range of the synthetic MSRs.
+const SYNTHETIC_MSRS: core::ops::RangeInclusive<u32> = 0x4000_0000..=0x4000_00ff;
+
+/// Returns `true` if the advertise-hypervisor mode is on.
+pub(crate) fn advertised() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.advertise_hypervisor
+}
+
+/// Returns the result of CPUID `leaf` the guest sees, given that of the
+/// processor.
+pub(crate) fn filter_cpuid(leaf: u32, cpuid_result: CpuIdResult) -> CpuIdResult {
+    if !advertised() {
+        return cpuid_result;
+    }
+    advertise(leaf, cpuid_result)
+}
+
+fn advertise(leaf: u32, mut cpuid_result: CpuIdResult) -> CpuIdResult {
+    const ZERO: CpuIdResult = CpuIdResult {
+        eax: 0,
+        ebx: 0,
+        ecx: 0,
+        edx: 0,
+    };
+
+    match leaf {
+        1 => cpuid_result.ecx |= 1 << 31,
+        HV_CPUID_VENDOR_AND_MAX_FUNCTIONS => cpuid_result.eax = HV_CPUID_IMPLEMENTATION_LIMITS,
+        HV_CPUID_INTERFACE => {
+            cpuid_result = CpuIdResult {
+                eax: HV_INTERFACE_SIGNATURE,
+                ..ZERO
+            }
+        }
+        // The version, the features, the recommendations and the limits. No
+        // feature or recommendation, except never to notify long spin waits.
+        0x4000_0004 => {
+            cpuid_result = CpuIdResult {
+                ebx: u32::MAX,
+                ..ZERO
+            }
+        }
+        0x4000_0002..=HV_CPUID_IMPLEMENTATION_LIMITS => cpuid_result = ZERO,
+        _ => {}
+    }
+    cpuid_result
+}
+
+/// Returns `true` if `msr` is a synthetic MSR.
+pub(crate) fn is_synthetic_msr(msr: u32) -> bool {
+    SYNTHETIC_MSRS.contains(&msr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised_leaves() {
+        let result = CpuIdResult {
+            eax: 0x11,
+            ebx: 0x22,
+            ecx: 0x33,
+            edx: 0x44,
+        };
+        assert_eq!(advertise(1, result).ecx, 0x8000_0033);
+        let vendor = advertise(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, result);
+        assert_eq!((vendor.eax, vendor.ebx), (0x4000_0005, 0x22));
+        assert_eq!(advertise(HV_CPUID_INTERFACE, result).eax, 0x3123_7648);
+        assert_eq!(advertise(0x4000_0003, result).eax, 0);
+        assert_eq!(advertise(0x4000_0004, result).ebx, u32::MAX);
+        assert_eq!(advertise(0x4000_0006, result).eax, 0x11);
+    }
+}
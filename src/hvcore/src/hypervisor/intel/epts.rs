@@ -1,23 +1,754 @@
-use core::ptr::addr_of;
+use core::{
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
-use crate::{hypervisor::intel::mtrr::MemoryType, hypervisor::platform_ops};
+use alloc::{boxed::Box, vec::Vec};
+use bit_field::BitField;
+
+use crate::{
+    hypervisor::ept_views::EptPermissions,
+    hypervisor::hooks::ExecHook,
+    hypervisor::intel::mtrr::MemoryType,
+    hypervisor::paging_structures::{IDENTITY_MAP_SIZE, IdentityMapError},
+    hypervisor::platform_ops::{self, PaError},
+    hypervisor::pool::Pool,
+    hypervisor::support::zeroed_box,
+    hypervisor::x86_instructions::rdmsr,
+};
 
 use super::mtrr::Mtrr;
 
-#[repr(C, align(4096))]
 pub(crate) struct Epts {
+    ptr: Box<EptsRaw>,
+
+    /// PTs allocated to split 2MB pages, and the GPAs of the 2MB pages.
+    split_pts: Vec<(u64, Box<Pt>)>,
+
+    /// PTs reserved for splitting 2MB pages. See `HvConfig::split_pt_pool`.
+    pt_pool: Pool<Pt>,
+}
+
+impl core::ops::Deref for Epts {
+    type Target = Box<EptsRaw>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl core::ops::DerefMut for Epts {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ptr
+    }
+}
+
+impl Epts {
+    pub(crate) fn new() -> Self {
+        Self {
+            ptr: zeroed_box::<EptsRaw>(),
+            split_pts: Vec::new(),
+            pt_pool: Pool::new(0),
+        }
+    }
+
+    /// Reserves `count` PTs for splitting 2MB pages later, so that splitting
+    /// does not allocate from the heap until they run out.
+    pub(crate) fn reserve_split_pts(&mut self, count: usize) {
+        self.pt_pool.fill(count);
+        self.split_pts.reserve(count);
+    }
+
+    /// Starts a batch of changes to these EPTs. The cached translations are
+    /// shot down through `shootdown` once, when the batch is committed.
+    pub(crate) fn transaction<'a>(&'a mut self, shootdown: &'a Shootdown) -> EptTransaction<'a> {
+        EptTransaction {
+            epts: self,
+            shootdown,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Updates the EPT entries for [`EptTransaction::set_ram_writable`]
+    /// without invalidating cached translations.
+    fn update_ram_permissions(&mut self, writable: bool) {
+        let write_back = MemoryType::WriteBack as u64;
+        for large_gpa in (0..IDENTITY_MAP_SIZE).step_by(LARGE_PAGE_SIZE) {
+            let pde = self.pde_mut(large_gpa);
+            if pde.large() {
+                if pde.memory_type() == write_back {
+                    pde.set_writable(writable);
+                }
+                continue;
+            }
+            for pte in &mut self.pt_mut(large_gpa).0.entries {
+                if pte.memory_type() == write_back {
+                    pte.set_writable(writable);
+                }
+            }
+        }
+    }
+
+    /// Updates the memory types of all leaf EPT entries for
+    /// [`EptTransaction::set_memory_types`] without invalidating cached
+    /// translations. Returns the number of the entries changed.
+    fn update_memory_types(&mut self, mtrr: &Mtrr) -> usize {
+        let mut changed = 0;
+        let mut update = |entry: &mut Entry, range: Range<u64>| {
+            let memory_type = mtrr.find(range) as u64;
+            if entry.memory_type() != memory_type {
+                entry.set_memory_type(memory_type);
+                changed += 1;
+            }
+        };
+        for large_gpa in (0..IDENTITY_MAP_SIZE).step_by(LARGE_PAGE_SIZE) {
+            let pde = self.pde_mut(large_gpa);
+            if pde.large() {
+                update(pde, large_gpa..large_gpa + LARGE_PAGE_SIZE as u64);
+                continue;
+            }
+            // The first 2MB, and pages split for permissions.
+            for (gpa, pte) in (large_gpa..)
+                .step_by(BASE_PAGE_SIZE)
+                .zip(&mut self.pt_mut(large_gpa).0.entries)
+            {
+                update(pte, gpa..gpa + BASE_PAGE_SIZE as u64);
+            }
+        }
+        changed
+    }
+
+    /// Returns `true` if the page at `gpa` has the write-back memory type.
+    pub(crate) fn is_ram(&self, gpa: u64) -> bool {
+        self.leaf(gpa).memory_type() == MemoryType::WriteBack as u64
+    }
+
+    /// Returns `true` if the page at `gpa` is writable.
+    pub(crate) fn is_writable(&self, gpa: u64) -> bool {
+        self.leaf(gpa).writable()
+    }
+
+    /// Returns `true` if the page at `gpa` is executable.
+    pub(crate) fn is_executable(&self, gpa: u64) -> bool {
+        self.leaf(gpa).executable()
+    }
+
+    /// Makes `hooks` non-executable, so that instruction fetches from them
+    /// cause VM-exit due to EPT violation. This is the view where the guest
+    /// reads and writes the original pages.
+    pub(crate) fn apply_exec_hooks_rw_view(&mut self, hooks: &[ExecHook]) -> Result<(), PaError> {
+        for hook in hooks {
+            let pte = self.pte_mut(hook.gpa)?;
+            pte.set_executable(false);
+            pte.set_user_executable(false);
+        }
+        self.debug_audit("applying execute hooks");
+        Ok(())
+    }
+
+    /// Makes all pages but `hooks` non-executable, and maps `hooks` to their
+    /// shadow pages. This is the view where the guest executes the shadow pages.
+    /// Reads and writes to the hooked pages also go to the shadow pages, as
+    /// only instructions in the hooked pages run in this view, and they may read
+    /// their own page, which an execute-only page would not let them do.
+    pub(crate) fn apply_exec_hooks_x_view(&mut self, hooks: &[ExecHook]) -> Result<(), PaError> {
+        for hook in hooks {
+            let _ = self.pte_mut(hook.gpa)?;
+        }
+
+        // Non-leaf entries grant full permissions. Clear execute permissions of
+        // all leaf entries so that only hooked pages can be executable.
+        for large_gpa in (0..IDENTITY_MAP_SIZE).step_by(LARGE_PAGE_SIZE) {
+            let pde = self.pde_mut(large_gpa);
+            if pde.large() {
+                pde.set_executable(false);
+                pde.set_user_executable(false);
+                continue;
+            }
+            for pte in &mut self.pt_mut(large_gpa).0.entries {
+                pte.set_executable(false);
+                pte.set_user_executable(false);
+            }
+        }
+
+        for hook in hooks {
+            let pte = self.pte_mut(hook.gpa)?;
+            pte.set_pfn(hook.shadow_pa >> BASE_PAGE_SHIFT);
+            pte.set_executable(true);
+            pte.set_user_executable(true);
+        }
+        self.debug_audit("applying execute hooks");
+        Ok(())
+    }
+
+    /// Checks every present entry of the EPTs, including PTs of split 2MB pages,
+    /// against the conditions that cause EPT misconfiguration under `rules`.
+    fn audit(&self, rules: &EptRules) -> Result<(), EptMisconfiguration> {
+        self.ptr.audit(rules)?;
+        for (large_gpa, pt) in &self.split_pts {
+            audit_table(rules, EptLevel::Pt, &pt.0, *large_gpa)?;
+        }
+        Ok(())
+    }
+
+    /// Panics if the EPTs are misconfigured, in debug builds. `context` tells
+    /// what has just been done to them.
+    fn debug_audit(&self, context: &str) {
+        // Unit tests cannot read the capability MSRs, and audit explicitly.
+        if cfg!(debug_assertions)
+            && !cfg!(test)
+            && let Err(e) = self.audit(&EptRules::current())
+        {
+            panic!("The EPTs are misconfigured after {context}: {e}");
+        }
+    }
+
+    /// Updates the EPT entries for [`EptTransaction::set_permissions`] without
+    /// invalidating cached translations.
+    fn update_permissions(
+        &mut self,
+        range: Range<u64>,
+        permissions: EptPermissions,
+    ) -> Result<(), PaError> {
+        assert!(
+            range.start.is_multiple_of(BASE_PAGE_SIZE as u64)
+                && range.end.is_multiple_of(BASE_PAGE_SIZE as u64),
+            "{range:#x?} is not 4KB aligned"
+        );
+        assert!(range.end <= IDENTITY_MAP_SIZE, "{range:#x?} is not mapped");
+
+        // Write-only and write-execute pages are EPT misconfigurations.
+        // See: 29.3.3.1 EPT Misconfigurations
+        assert!(
+            permissions.read || !permissions.write,
+            "EPT entries cannot be writable but not readable"
+        );
+
+        let mut gpa = range.start;
+        while gpa < range.end {
+            let large_end = gpa + LARGE_PAGE_SIZE as u64;
+            let pde = self.pde_mut(gpa);
+            let (entry, size) = if pde.large()
+                && gpa.is_multiple_of(LARGE_PAGE_SIZE as u64)
+                && large_end <= range.end
+            {
+                // The whole 2MB page is in the range. No need to split it.
+                (pde, LARGE_PAGE_SIZE as u64)
+            } else {
+                (self.pte_mut(gpa)?, BASE_PAGE_SIZE as u64)
+            };
+            entry.set_readable(permissions.read);
+            entry.set_writable(permissions.write);
+            entry.set_executable(permissions.execute);
+            entry.set_user_executable(permissions.user_execute);
+            gpa += size;
+        }
+        Ok(())
+    }
+
+    /// Updates the EPT entry for [`EptTransaction::set_sub_page_protection`]
+    /// without invalidating cached translations.
+    fn update_sub_page_protection(&mut self, gpa: u64, protect: bool) -> Result<(), PaError> {
+        // SPP applies to 4KB pages only, and only when the page is not
+        // writable.
+        let pte = self.pte_mut(gpa)?;
+        pte.set_sub_page_write(protect);
+        pte.set_writable(!protect && pte.readable());
+        Ok(())
+    }
+
+    /// Returns the leaf EPT entry for `gpa`, which is either a 2MB PDE or a
+    /// 4KB PTE.
+    fn leaf(&self, gpa: u64) -> &Entry {
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        let large_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        let pde = &self.ptr.pd[pdpt_index].0.entries[pd_index];
+        if pde.large() {
+            return pde;
+        }
+        let pt = match self.split_pts.iter().find(|(pa, _)| *pa == large_gpa) {
+            Some((_, pt)) => pt.as_ref(),
+            None => &self.ptr.pt,
+        };
+        &pt.0.entries[pt_index]
+    }
+
+    /// Returns the EPT PD entry for `gpa`.
+    fn pde_mut(&mut self, gpa: u64) -> &mut Entry {
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        &mut self.ptr.pd[pdpt_index].0.entries[pd_index]
+    }
+
+    /// Returns the 4KB EPT entry for `gpa`, splitting the 2MB page if needed.
+    fn pte_mut(&mut self, gpa: u64) -> Result<&mut Entry, PaError> {
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        let large_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        if self.pde_mut(gpa).large() {
+            let mut pt = self.pt_pool.take().unwrap_or_else(zeroed_box::<Pt>);
+            Self::split_2mb(self.pde_mut(gpa), &mut pt)?;
+            self.split_pts.push((large_gpa, pt));
+        }
+
+        Ok(&mut self.pt_mut(large_gpa).0.entries[pt_index])
+    }
+
+    /// Returns the EPT PT for the split 2MB page at `large_gpa`.
+    fn pt_mut(&mut self, large_gpa: u64) -> &mut Pt {
+        // The only split 2MB page not in `split_pts` is the first one.
+        match self.split_pts.iter_mut().find(|(pa, _)| *pa == large_gpa) {
+            Some((_, pt)) => pt.as_mut(),
+            None => &mut self.ptr.pt,
+        }
+    }
+
+    /// Updates the `pde` to point to `pt` to split the page from 2MB to 4KBs.
+    fn split_2mb(pde: &mut Entry, pt: &mut Pt) -> Result<(), PaError> {
+        assert!(pde.large());
+
+        for (pfn, pte) in (pde.pfn()..).zip(pt.0.entries.iter_mut()) {
+            pte.set_readable(pde.readable());
+            pte.set_writable(pde.writable());
+            pte.set_executable(pde.executable());
+            pte.set_user_executable(pde.user_executable());
+            pte.set_memory_type(pde.memory_type());
+            pte.set_pfn(pfn);
+        }
+
+        // Bits 6:3 are reserved in non-leaf entries. Grant full permissions so
+        // that the PTEs alone determine the permissions.
+        // See: Table 29-7. Format of an EPT Page-Directory Entry (PDE) that References an EPT Page Table
+        let pt_pa = platform_ops::get().pa(pt as *mut _ as _)?;
+        pde.set_readable(true);
+        pde.set_writable(true);
+        pde.set_executable(true);
+        pde.set_user_executable(true);
+        pde.set_memory_type(0);
+        pde.set_large(false);
+        pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+        Ok(())
+    }
+}
+
+/// A batch of changes to [`Epts`], applied together on [`EptTransaction::commit`].
+///
+/// Editing EPT entries makes the translations cached from them stale on every
+/// processor using the EPTs, and INVEPT only invalidates those of the executing
+/// processor. The transaction applies all changes first, and then requests a
+/// single invalidation from all processors through [`Shootdown`], rather than
+/// one INVEPT per edit on the current processor only.
+#[must_use]
+pub(crate) struct EptTransaction<'a> {
+    epts: &'a mut Epts,
+    shootdown: &'a Shootdown,
+    changes: Vec<EptChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EptChange {
+    Permissions(Range<u64>, EptPermissions),
+    RamWritable(bool),
+    SubPageProtection(u64, bool),
+    MemoryTypes(Mtrr),
+}
+
+impl EptTransaction<'_> {
+    /// Sets the permissions of the 4KB aligned GPA `range`, splitting 2MB pages
+    /// as needed.
+    pub(crate) fn set_permissions(&mut self, range: Range<u64>, permissions: EptPermissions) {
+        self.changes
+            .push(EptChange::Permissions(range, permissions));
+    }
+
+    /// Sets the write permission of all pages with the write-back memory type,
+    /// that is, RAM. Large pages are not split.
+    pub(crate) fn set_ram_writable(&mut self, writable: bool) {
+        self.changes.push(EptChange::RamWritable(writable));
+    }
+
+    /// Makes the 4KB page at `gpa` writable only where the sub-page permission
+    /// table permits if `protect` is `true`, or writable as a whole if it is
+    /// readable otherwise. 2MB pages are split.
+    pub(crate) fn set_sub_page_protection(&mut self, gpa: u64, protect: bool) {
+        self.changes
+            .push(EptChange::SubPageProtection(gpa, protect));
+    }
+
+    /// Sets the memory types of all pages to those given by `mtrr`, as
+    /// [`EptsRaw::build_identity`] does.
+    pub(crate) fn set_memory_types(&mut self, mtrr: Mtrr) {
+        self.changes.push(EptChange::MemoryTypes(mtrr));
+    }
+
+    /// Applies the changes in order, and requests the shootdown of cached
+    /// translations if any is applied. Must be called in VMX root operation.
+    ///
+    /// On error, the changes before the failed one remain applied, and are
+    /// shot down as well.
+    pub(crate) fn commit(self) -> Result<(), PaError> {
+        let mut result = Ok(());
+        let mut applied = false;
+        for change in self.changes {
+            result = match change {
+                EptChange::Permissions(range, permissions) => {
+                    self.epts.update_permissions(range, permissions)
+                }
+                EptChange::RamWritable(writable) => {
+                    self.epts.update_ram_permissions(writable);
+                    Ok(())
+                }
+                EptChange::SubPageProtection(gpa, protect) => {
+                    self.epts.update_sub_page_protection(gpa, protect)
+                }
+                EptChange::MemoryTypes(mtrr) => {
+                    let changed = self.epts.update_memory_types(&mtrr);
+                    log::debug!("Updated the memory types of {changed} EPT entries");
+                    Ok(())
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+            applied = true;
+        }
+        if applied {
+            self.epts.debug_audit("the transaction");
+            self.shootdown.request();
+        }
+        result
+    }
+}
+
+/// The level of an EPT paging structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EptLevel {
+    Pml4,
+    Pdpt,
+    Pd,
+    Pt,
+}
+
+/// The error type for the audit of EPT entries. Each variant is a condition
+/// that causes EPT misconfiguration when the processor walks the entry, and
+/// carries the first GPA translated with the entry and its raw value.
+///
+/// See: 29.3.3.1 EPT Misconfigurations
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EptMisconfiguration {
+    #[error("the {level:?} entry for {gpa:#x} ({entry:#x}) sets reserved bits")]
+    ReservedBits {
+        level: EptLevel,
+        gpa: u64,
+        entry: u64,
+    },
+
+    #[error("the {level:?} entry for {gpa:#x} ({entry:#x}) has an invalid memory type")]
+    InvalidMemoryType {
+        level: EptLevel,
+        gpa: u64,
+        entry: u64,
+    },
+
+    #[error("the {level:?} entry for {gpa:#x} ({entry:#x}) is writable but not readable")]
+    WritableNotReadable {
+        level: EptLevel,
+        gpa: u64,
+        entry: u64,
+    },
+
+    #[error(
+        "the {level:?} entry for {gpa:#x} ({entry:#x}) is execute-only, which is not supported"
+    )]
+    ExecuteOnly {
+        level: EptLevel,
+        gpa: u64,
+        entry: u64,
+    },
+}
+
+/// The capabilities of the processor that decide which EPT entries are
+/// misconfigured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EptRules {
+    /// The number of bits of physical addresses. Bits from it up to 51 are
+    /// reserved.
+    max_phys_addr: u32,
+
+    /// Whether execute-only translations are supported.
+    execute_only: bool,
+
+    /// Whether mode-based execute control is supported, with which bit 10 makes
+    /// an entry present too.
+    mode_based_execute: bool,
+}
+
+impl EptRules {
+    /// Reads the capabilities of the current processor.
+    fn current() -> Self {
+        const ACTIVATE_SECONDARY_CONTROLS: usize = 32 + 31;
+        const ENABLE_EPT: usize = 1;
+        const MODE_BASED_EPT: usize = 22;
+
+        // The capability MSRs exist only if the controls are supported.
+        // See: A.3.3 Secondary Processor-Based VM-Execution Controls
+        // See: A.10 VPID AND EPT CAPABILITIES
+        let secondary_allowed1 =
+            if rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS).get_bit(ACTIVATE_SECONDARY_CONTROLS) {
+                rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32
+            } else {
+                0
+            };
+        // "If bit 0 is read as 1, the processor supports execute-only
+        //  translations by EPT."
+        let execute_only = secondary_allowed1.get_bit(ENABLE_EPT)
+            && rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP).get_bit(0);
+        Self {
+            max_phys_addr: x86::cpuid::cpuid!(0x8000_0008).eax.get_bits(0..=7),
+            execute_only,
+            mode_based_execute: secondary_allowed1.get_bit(MODE_BASED_EPT),
+        }
+    }
+}
+
+/// Checks the present entries of `table` at `level`, whose first entry
+/// translates `base_gpa`.
+fn audit_table(
+    rules: &EptRules,
+    level: EptLevel,
+    table: &Table,
+    base_gpa: u64,
+) -> Result<(), EptMisconfiguration> {
+    let entry_size = match level {
+        EptLevel::Pml4 => 1 << 39,
+        EptLevel::Pdpt => 1 << 30,
+        EptLevel::Pd => LARGE_PAGE_SIZE as u64,
+        EptLevel::Pt => BASE_PAGE_SIZE as u64,
+    };
+    for (gpa, entry) in (base_gpa..)
+        .step_by(entry_size as usize)
+        .zip(&table.entries)
+    {
+        audit_entry(rules, level, entry, gpa)?;
+    }
+    Ok(())
+}
+
+/// Checks `entry` at `level` for the GPA `gpa`.
+fn audit_entry(
+    rules: &EptRules,
+    level: EptLevel,
+    entry: &Entry,
+    gpa: u64,
+) -> Result<(), EptMisconfiguration> {
+    let user_executable = rules.mode_based_execute && entry.user_executable();
+    if !entry.readable() && !entry.writable() && !entry.executable() && !user_executable {
+        return Ok(());
+    }
+
+    let value = entry.0;
+    if entry.writable() && !entry.readable() {
+        return Err(EptMisconfiguration::WritableNotReadable {
+            level,
+            gpa,
+            entry: value,
+        });
+    }
+    if !entry.readable() && !rules.execute_only {
+        return Err(EptMisconfiguration::ExecuteOnly {
+            level,
+            gpa,
+            entry: value,
+        });
+    }
+
+    // Bits from MAXPHYADDR up to 51 are reserved in all entries. Bits 7:3 are
+    // reserved in PML4Es, and bits 6:3 in the other non-leaf entries. Bits of
+    // the address below the page size are reserved in 1GB and 2MB pages.
+    // See: Table 29-1. Format of an EPT PML4 Entry (PML4E) that References an EPT Page-Directory-Pointer Table
+    // See: Table 29-3. Format of an EPT Page-Directory-Pointer-Table Entry (PDPTE) that References an EPT Page Directory
+    // See: Table 29-4. Format of an EPT Page-Directory Entry (PDE) that Maps a 2-MByte Page
+    let is_leaf = level == EptLevel::Pt || (level != EptLevel::Pml4 && entry.large());
+    let mut reserved = (u64::MAX << rules.max_phys_addr.min(52)) & ((1 << 52) - 1);
+    reserved |= match (level, is_leaf) {
+        (EptLevel::Pml4, _) => 0b1111_1000,
+        (EptLevel::Pdpt, true) => 0x3fff_f000,
+        (EptLevel::Pd, true) => 0x1f_f000,
+        (_, true) => 0,
+        (_, false) => 0b0111_1000,
+    };
+    if entry.0 & reserved != 0 {
+        return Err(EptMisconfiguration::ReservedBits {
+            level,
+            gpa,
+            entry: value,
+        });
+    }
+
+    // "The entry is present and ... the value of bits 5:3 (EPT memory type) is
+    //  2, 3, or 7 (these values are reserved)."
+    if is_leaf && matches!(entry.memory_type(), 2 | 3 | 7) {
+        return Err(EptMisconfiguration::InvalidMemoryType {
+            level,
+            gpa,
+            entry: value,
+        });
+    }
+    Ok(())
+}
+
+/// The generation of committed [`EptTransaction`]s, used to shoot down cached
+/// EPT translations on all processors sharing the EPTs.
+///
+/// The host cannot interrupt a processor running the guest without disturbing
+/// the guest, so the shootdown is requested by advancing the generation, and
+/// each processor invalidates the cached translations before its next VM-entry
+/// if it has not seen the generation yet. This includes the processor that
+/// committed the transaction. Other processors may use stale translations until
+/// their next VM-exit.
+#[derive(Debug, Default)]
+pub(crate) struct Shootdown {
+    generation: AtomicU64,
+}
+
+impl Shootdown {
+    fn request(&self) {
+        let _ = self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns `true` if a shootdown was requested since the generation `seen`,
+    /// and updates `seen` to the current one.
+    pub(crate) fn is_pending(&self, seen: &mut u64) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation == *seen {
+            return false;
+        }
+        *seen = generation;
+        true
+    }
+}
+
+#[repr(C, align(4096))]
+pub(crate) struct EptsRaw {
     pml4: Pml4,
     pdpt: Pdpt,
     pd: [Pd; 512],
     pt: Pt,
 }
 
-impl Epts {
-    pub(crate) fn build_identity(&mut self) {
+impl EptsRaw {
+    pub(crate) fn build_identity(&mut self) -> Result<(), PaError> {
         let mtrr = Mtrr::new();
         log::trace!("{mtrr:#x?}");
+        self.build_identity_with(&mtrr)?;
+
+        // Catch mistakes in the above early in debug builds, rather than as
+        // guest hangs later.
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_identity(&mtrr) {
+                panic!("The EPT identity mapping is broken: {e}");
+            }
+            if let Err(e) = self.audit(&EptRules::current()) {
+                panic!("The EPTs are misconfigured after building the identity mapping: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the present entries of the PML4, PDPT, PDs and the PT for the
+    /// first 2MB under `rules`. See [`Epts::audit`].
+    fn audit(&self, rules: &EptRules) -> Result<(), EptMisconfiguration> {
+        audit_table(rules, EptLevel::Pml4, &self.pml4.0, 0)?;
+        audit_table(rules, EptLevel::Pdpt, &self.pdpt.0, 0)?;
+        for (pdpt_index, pd) in self.pd.iter().enumerate() {
+            audit_table(rules, EptLevel::Pd, &pd.0, (pdpt_index as u64) << 30)?;
+        }
+        audit_table(rules, EptLevel::Pt, &self.pt.0, 0)
+    }
+
+    /// Walks the EPTs and verifies that every GPA in [`IDENTITY_MAP_SIZE`] maps
+    /// to itself with full permissions and the memory type given by `mtrr`.
+    fn check_identity(&self, mtrr: &Mtrr) -> Result<(), IdentityMapError> {
+        let ops = platform_ops::get();
+        let pdpt_pa = ops.pa(addr_of!(self.pdpt) as _)?;
+        let pt_pa = ops.pa(addr_of!(self.pt) as _)?;
+        let pd_pas = self
+            .pd
+            .iter()
+            .map(|pd| ops.pa(addr_of!(*pd) as _))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let check_entry = |gpa: u64, entry: &Entry| {
+            if !entry.readable() && !entry.writable() && !entry.executable() {
+                return Err(IdentityMapError::NotPresent { gpa });
+            }
+            if !entry.readable()
+                || !entry.writable()
+                || !entry.executable()
+                || !entry.user_executable()
+            {
+                return Err(IdentityMapError::WrongPermissions { gpa });
+            }
+            Ok(())
+        };
+        let check_table = |gpa: u64, entry: &Entry, expected: u64| {
+            check_entry(gpa, entry)?;
+            let pa = entry.pfn() << BASE_PAGE_SHIFT;
+            if entry.large() || pa != expected {
+                return Err(IdentityMapError::WrongTable { gpa, pa });
+            }
+            Ok(())
+        };
+
+        let mut gpa = 0;
+        while gpa < IDENTITY_MAP_SIZE {
+            let pml4e = &self.pml4.0.entries[(gpa >> 39) as usize & 0x1ff];
+            check_table(gpa, pml4e, pdpt_pa)?;
+            let pdpt_index = (gpa >> 30) as usize & 0x1ff;
+            let pdpte = &self.pdpt.0.entries[pdpt_index];
+            check_table(gpa, pdpte, pd_pas[pdpt_index])?;
+            let pde = &self.pd[pdpt_index].0.entries[(gpa >> 21) as usize & 0x1ff];
+
+            let (entry, size) = if gpa < LARGE_PAGE_SIZE as u64 {
+                check_table(gpa, pde, pt_pa)?;
+                let pte = &self.pt.0.entries[(gpa >> 12) as usize & 0x1ff];
+                (pte, BASE_PAGE_SIZE as u64)
+            } else {
+                if !pde.large() {
+                    return Err(IdentityMapError::WrongTable {
+                        gpa,
+                        pa: pde.pfn() << BASE_PAGE_SHIFT,
+                    });
+                }
+                (pde, LARGE_PAGE_SIZE as u64)
+            };
+
+            check_entry(gpa, entry)?;
+            let pa = entry.pfn() << BASE_PAGE_SHIFT;
+            if pa != gpa {
+                return Err(IdentityMapError::WrongAddress { gpa, pa });
+            }
+            let expected = mtrr.find(gpa..gpa + size) as u64;
+            if entry.memory_type() != expected {
+                return Err(IdentityMapError::WrongMemoryType {
+                    gpa,
+                    actual: entry.memory_type(),
+                    expected,
+                });
+            }
+            gpa += size;
+        }
+        Ok(())
+    }
+
+    /// Builds the identity mapping with the memory types given by `mtrr`.
+    fn build_identity_with(&mut self, mtrr: &Mtrr) -> Result<(), PaError> {
         log::trace!("Initializing EPTs");
 
         let ops = platform_ops::get();
@@ -27,12 +758,14 @@ impl Epts {
         self.pml4.0.entries[0].set_readable(true);
         self.pml4.0.entries[0].set_writable(true);
         self.pml4.0.entries[0].set_executable(true);
-        self.pml4.0.entries[0].set_pfn(ops.pa(addr_of!(self.pdpt) as _) >> BASE_PAGE_SHIFT);
+        self.pml4.0.entries[0].set_user_executable(true);
+        self.pml4.0.entries[0].set_pfn(ops.pa(addr_of!(self.pdpt) as _)? >> BASE_PAGE_SHIFT);
         for (i, pdpte) in self.pdpt.0.entries.iter_mut().enumerate() {
             pdpte.set_readable(true);
             pdpte.set_writable(true);
             pdpte.set_executable(true);
-            pdpte.set_pfn(ops.pa(addr_of!(self.pd[i]) as _) >> BASE_PAGE_SHIFT);
+            pdpte.set_user_executable(true);
+            pdpte.set_pfn(ops.pa(addr_of!(self.pd[i]) as _)? >> BASE_PAGE_SHIFT);
             for pde in &mut self.pd[i].0.entries {
                 if pa == 0 {
                     // First 2MB is managed by 4KB EPT PTs so MTRR memory types
@@ -40,30 +773,27 @@ impl Epts {
                     pde.set_readable(true);
                     pde.set_writable(true);
                     pde.set_executable(true);
-                    pde.set_pfn(ops.pa(addr_of!(self.pt) as _) >> BASE_PAGE_SHIFT);
+                    pde.set_user_executable(true);
+                    pde.set_pfn(ops.pa(addr_of!(self.pt) as _)? >> BASE_PAGE_SHIFT);
                     for pte in &mut self.pt.0.entries {
-                        let memory_type =
-                            mtrr.find(pa..pa + BASE_PAGE_SIZE as u64)
-                                .unwrap_or_else(|| {
-                                    panic!("Could not resolve a memory type for {pa:#x?}")
-                                });
+                        let memory_type = mtrr.find(pa..pa + BASE_PAGE_SIZE as u64);
                         pte.set_readable(true);
                         pte.set_writable(true);
                         pte.set_executable(true);
+                        pte.set_user_executable(true);
                         pte.set_memory_type(memory_type as u64);
                         pte.set_pfn(pa >> BASE_PAGE_SHIFT);
                         pa += BASE_PAGE_SIZE as u64;
                     }
                 } else {
                     // For the rest of GPAes, manage them with 2MB large page EPTs.
-                    // We assume MTRR memory types are configured for 2MB or greater
-                    // granularity.
-                    let memory_type = mtrr
-                        .find(pa..pa + LARGE_PAGE_SIZE as u64)
-                        .unwrap_or_else(|| panic!("Could not resolve a memory type for {pa:#x?}"));
+                    // If MTRRs are configured for smaller granularity, the page
+                    // gets the most conservative of the memory types within it.
+                    let memory_type = mtrr.find(pa..pa + LARGE_PAGE_SIZE as u64);
                     pde.set_readable(true);
                     pde.set_writable(true);
                     pde.set_executable(true);
+                    pde.set_user_executable(true);
                     pde.set_memory_type(memory_type as u64);
                     pde.set_large(true);
                     pde.set_pfn(pa >> BASE_PAGE_SHIFT);
@@ -71,29 +801,95 @@ impl Epts {
                 }
             }
         }
+        Ok(())
     }
 
     /// Returns an EPT pointer for this EPT.
-    pub(crate) fn eptp(&self) -> EptPointer {
-        let mut eptp = EptPointer::default();
-        let ept_pml4_pa = platform_ops::get().pa(addr_of!(*self) as *const _);
-        eptp.set_pfn(ept_pml4_pa >> BASE_PAGE_SHIFT);
+    pub(crate) fn eptp(&self) -> Result<EptPointer, PaError> {
+        eptp(&self.pml4)
+    }
+}
 
-        // Lower 12-bits of EPTP is made up of flags. We use the write-back memory
-        // type for accessing to any of EPT paging-structures, as it is most
-        // efficient.
-        // See: 29.3.7.1 Memory Type Used for Accessing EPT Paging Structures
-        eptp.set_memory_type(MemoryType::WriteBack as _);
+/// EPTs that map GPAs from 0 to a physically contiguous range of up to 2MB,
+/// such as the memory blob of a mini VM. Any other GPA is not mapped.
+#[repr(C, align(4096))]
+pub(crate) struct BlobEpts {
+    pml4: Pml4,
+    pdpt: Pdpt,
+    pd: Pd,
+    pt: Pt,
+}
+
+impl BlobEpts {
+    /// Maps GPAs from 0 to `size` to the PAs from `pa` as write-back memory.
+    /// Both must be 4KB aligned.
+    pub(crate) fn build(&mut self, pa: u64, size: u64) -> Result<(), PaError> {
+        assert!(
+            pa.is_multiple_of(BASE_PAGE_SIZE as u64) && size.is_multiple_of(BASE_PAGE_SIZE as u64)
+        );
+        assert!(size <= LARGE_PAGE_SIZE as u64);
+
+        let ops = platform_ops::get();
+        let tables = [
+            (
+                &mut self.pml4.0.entries[0],
+                ops.pa(addr_of!(self.pdpt) as _)?,
+            ),
+            (&mut self.pdpt.0.entries[0], ops.pa(addr_of!(self.pd) as _)?),
+            (&mut self.pd.0.entries[0], ops.pa(addr_of!(self.pt) as _)?),
+        ];
+        for (entry, table_pa) in tables {
+            entry.set_readable(true);
+            entry.set_writable(true);
+            entry.set_executable(true);
+            entry.set_pfn(table_pa >> BASE_PAGE_SHIFT);
+        }
+        let pages = (size / BASE_PAGE_SIZE as u64) as usize;
+        for (pfn, pte) in (pa >> BASE_PAGE_SHIFT..).zip(&mut self.pt.0.entries[..pages]) {
+            pte.set_readable(true);
+            pte.set_writable(true);
+            pte.set_executable(true);
+            pte.set_memory_type(MemoryType::WriteBack as u64);
+            pte.set_pfn(pfn);
+        }
+        Ok(())
+    }
 
-        // "This value is 1 less than the EPT page-walk length."
-        // "The EPT translation mechanism (...) uses a page-walk length of 4".
-        // See: Table 25-9. Format of Extended-Page-Table Pointer
-        // See: 29.3.2 EPT Translation Mechanism
-        eptp.set_page_levels_minus_one(3);
-        eptp
+    /// Returns an EPT pointer for this EPT.
+    pub(crate) fn eptp(&self) -> Result<EptPointer, PaError> {
+        eptp(&self.pml4)
     }
 }
 
+/// The EPTPs the guest can switch to with VMFUNC leaf 0 (EPTP switching). An
+/// index to an entry of zero is invalid, and VMFUNC causes VM-exit then.
+///
+/// See: 26.5.6.3 EPTP Switching
+#[repr(C, align(4096))]
+pub(crate) struct EptpList {
+    pub(crate) entries: [u64; 512],
+}
+
+/// Returns an EPT pointer for the EPTs starting with `pml4`.
+fn eptp(pml4: &Pml4) -> Result<EptPointer, PaError> {
+    let mut eptp = EptPointer::default();
+    let ept_pml4_pa = platform_ops::get().pa(addr_of!(*pml4) as *const _)?;
+    eptp.set_pfn(ept_pml4_pa >> BASE_PAGE_SHIFT);
+
+    // Lower 12-bits of EPTP is made up of flags. We use the write-back memory
+    // type for accessing to any of EPT paging-structures, as it is most
+    // efficient.
+    // See: 29.3.7.1 Memory Type Used for Accessing EPT Paging Structures
+    eptp.set_memory_type(MemoryType::WriteBack as _);
+
+    // "This value is 1 less than the EPT page-walk length."
+    // "The EPT translation mechanism (...) uses a page-walk length of 4".
+    // See: Table 25-9. Format of Extended-Page-Table Pointer
+    // See: 29.3.2 EPT Translation Mechanism
+    eptp.set_page_levels_minus_one(3);
+    Ok(eptp)
+}
+
 bitfield::bitfield! {
     /// A 64-bit VMCS field value to teach the processor how to walk EPTs.
     // It is equivalent to the CR3 in the normal
@@ -131,6 +927,11 @@ struct Table {
 
 bitfield::bitfield! {
     /// Figure 29-1. Formats of EPTP and EPT Paging-Structure Entries
+    ///
+    /// With mode-based execute control for EPT, `executable` permits execution
+    /// from supervisor-mode linear addresses, and `user_executable` from
+    /// user-mode ones, in both leaf and non-leaf entries. Otherwise,
+    /// `user_executable` is ignored.
     #[derive(Clone, Copy)]
     struct Entry(u64);
     impl Debug;
@@ -139,5 +940,452 @@ bitfield::bitfield! {
     executable, set_executable: 2;
     memory_type, set_memory_type: 5, 3;
     large, set_large: 7;
+    user_executable, set_user_executable: 10;
     pfn, set_pfn: 51, 12;
+    sub_page_write, set_sub_page_write: 61;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::testing;
+
+    #[test]
+    fn identity_with_memory_types() {
+        testing::init();
+
+        // 0xa0000 - 0xbffff is UC, and the rest is WB.
+        let mut fixed = [0x0606_0606_0606_0606; 11];
+        fixed[2] = 0;
+        let mtrr = Mtrr::from_values(MemoryType::WriteBack, fixed, &[]);
+
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+
+        let pdpt_pa = addr_of!(epts.pdpt) as u64;
+        assert_eq!(epts.pml4.0.entries[0].pfn(), pdpt_pa >> BASE_PAGE_SHIFT);
+
+        let pte = epts.pt.0.entries[0xa0];
+        assert!(pte.readable() && pte.writable() && pte.executable());
+        assert_eq!(pte.pfn(), 0xa0);
+        assert_eq!(pte.memory_type(), MemoryType::Uncachable as u64);
+        assert_eq!(
+            epts.pt.0.entries[0x9f].memory_type(),
+            MemoryType::WriteBack as u64
+        );
+
+        let pde = epts.pd[1].0.entries[2];
+        assert!(pde.large());
+        assert_eq!(pde.pfn() << BASE_PAGE_SHIFT, 0x4040_0000);
+        assert_eq!(pde.memory_type(), MemoryType::WriteBack as u64);
+    }
+
+    /// Returns MTRRs with UC legacy VGA, and UC and WT ranges below 4GB.
+    fn typical_mtrr() -> Mtrr {
+        const VALID: u64 = 1 << 11;
+
+        let mut fixed = [0x0606_0606_0606_0606; 11];
+        fixed[2] = 0;
+        Mtrr::from_values(
+            MemoryType::WriteBack,
+            fixed,
+            &[
+                (0xc000_0000, 0xf_c000_0000 | VALID),
+                (0x8000_0000 | 4, 0xf_f000_0000 | VALID),
+            ],
+        )
+    }
+
+    #[test]
+    fn identity_check() {
+        testing::init();
+
+        let mtrr = typical_mtrr();
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+        assert_eq!(epts.check_identity(&mtrr), Ok(()));
+        assert_eq!(
+            epts.pd[3].0.entries[0].memory_type(),
+            MemoryType::Uncachable as u64
+        );
+        assert_eq!(
+            epts.pd[2].0.entries[0].memory_type(),
+            MemoryType::WriteThrough as u64
+        );
+    }
+
+    #[test]
+    fn identity_check_detects_errors() {
+        testing::init();
+
+        let mtrr = typical_mtrr();
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+
+        let original = epts.pd[4].0.entries[5];
+        epts.pd[4].0.entries[5].set_pfn(0);
+        assert_eq!(
+            epts.check_identity(&mtrr),
+            Err(IdentityMapError::WrongAddress {
+                gpa: 0x1_00a0_0000,
+                pa: 0
+            })
+        );
+        epts.pd[4].0.entries[5].set_memory_type(MemoryType::Uncachable as u64);
+        epts.pd[4].0.entries[5].set_pfn(original.pfn());
+        assert_eq!(
+            epts.check_identity(&mtrr),
+            Err(IdentityMapError::WrongMemoryType {
+                gpa: 0x1_00a0_0000,
+                actual: MemoryType::Uncachable as u64,
+                expected: MemoryType::WriteBack as u64,
+            })
+        );
+        epts.pd[4].0.entries[5] = original;
+
+        epts.pt.0.entries[0x10].set_executable(false);
+        assert_eq!(
+            epts.check_identity(&mtrr),
+            Err(IdentityMapError::WrongPermissions { gpa: 0x10000 })
+        );
+        epts.pt.0.entries[0x10].set_executable(true);
+
+        let pd0 = epts.pdpt.0.entries[0];
+        epts.pdpt.0.entries[1] = pd0;
+        assert_eq!(
+            epts.check_identity(&mtrr),
+            Err(IdentityMapError::WrongTable {
+                gpa: 0x4000_0000,
+                pa: pd0.pfn() << BASE_PAGE_SHIFT,
+            })
+        );
+    }
+
+    #[test]
+    fn update_permissions_splits_large_pages() {
+        testing::init();
+
+        let mtrr = typical_mtrr();
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+
+        // Covers the last 4KB of the 2MB page at 0x8000_0000 (WT), the whole
+        // next 2MB page, and the first 4KB of the one after.
+        let range = 0x801f_f000..0x8040_1000;
+        epts.update_permissions(range, EptPermissions::new(true, false, false))
+            .unwrap();
+
+        let pde = epts.pd[2].0.entries[0];
+        assert!(!pde.large());
+        assert!(pde.readable() && pde.writable() && pde.executable());
+        assert_eq!(pde.memory_type(), 0);
+        assert_eq!(epts.split_pts.len(), 2);
+        let (gpa, pt) = &epts.split_pts[0];
+        assert_eq!(*gpa, 0x8000_0000);
+        assert_eq!(pde.pfn() << BASE_PAGE_SHIFT, addr_of!(**pt) as u64);
+
+        let pte = pt.0.entries[0x1fe];
+        assert!(pte.readable() && pte.writable() && pte.executable());
+        assert_eq!(pte.pfn() << BASE_PAGE_SHIFT, 0x801f_e000);
+        assert_eq!(pte.memory_type(), MemoryType::WriteThrough as u64);
+        let pte = pt.0.entries[0x1ff];
+        assert!(pte.readable() && !pte.writable() && !pte.executable());
+        assert_eq!(pte.pfn() << BASE_PAGE_SHIFT, 0x801f_f000);
+
+        // The whole 2MB page is updated without being split.
+        let pde = epts.pd[2].0.entries[1];
+        assert!(pde.large());
+        assert!(pde.readable() && !pde.writable() && !pde.executable());
+
+        let (gpa, pt) = &epts.split_pts[1];
+        assert_eq!(*gpa, 0x8040_0000);
+        assert!(!pt.0.entries[0].writable());
+        assert!(pt.0.entries[1].writable());
+        assert_eq!(pt.0.entries[1].pfn() << BASE_PAGE_SHIFT, 0x8040_1000);
+    }
+
+    #[test]
+    fn update_permissions_first_2mb() {
+        testing::init();
+
+        let mtrr = typical_mtrr();
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+
+        epts.update_permissions(0xa_0000..0xa_2000, EptPermissions::new(false, false, false))
+            .unwrap();
+        assert!(epts.split_pts.is_empty());
+        assert!(!epts.pt.0.entries[0xa0].readable());
+        assert!(!epts.pt.0.entries[0xa1].readable());
+        assert!(epts.pt.0.entries[0xa2].readable());
+        assert_eq!(
+            epts.check_identity(&mtrr),
+            Err(IdentityMapError::NotPresent { gpa: 0xa_0000 })
+        );
+    }
+
+    #[test]
+    fn update_permissions_user_execute() {
+        testing::init();
+
+        let mut epts = Epts::new();
+        epts.build_identity_with(&typical_mtrr()).unwrap();
+        let kernel_only = EptPermissions {
+            read: true,
+            write: false,
+            execute: true,
+            user_execute: false,
+        };
+        epts.update_permissions(0x4000_0000..0x4000_1000, kernel_only)
+            .unwrap();
+
+        // The PDE of the split page keeps permitting user-mode execution.
+        let pde = epts.pd[1].0.entries[0];
+        assert!(!pde.large() && pde.executable() && pde.user_executable());
+        let (_, pt) = &epts.split_pts[0];
+        assert!(pt.0.entries[0].executable() && !pt.0.entries[0].user_executable());
+        assert!(pt.0.entries[1].executable() && pt.0.entries[1].user_executable());
+    }
+
+    #[test]
+    fn exec_hook_views() {
+        testing::init();
+
+        let hooks = [ExecHook {
+            gpa: 0x4000_1000,
+            shadow_pa: 0x1234_5000,
+        }];
+        let mut rw_view = Epts::new();
+        rw_view.build_identity_with(&typical_mtrr()).unwrap();
+        rw_view.apply_exec_hooks_rw_view(&hooks).unwrap();
+        assert!(!rw_view.is_executable(0x4000_1000));
+        assert!(rw_view.is_executable(0x4000_0000) && rw_view.is_writable(0x4000_1000));
+
+        let mut x_view = Epts::new();
+        x_view.build_identity_with(&typical_mtrr()).unwrap();
+        x_view.apply_exec_hooks_x_view(&hooks).unwrap();
+        assert!(x_view.is_executable(0x4000_1000));
+        assert_eq!(
+            x_view.leaf(0x4000_1000).pfn(),
+            0x1234_5000 >> BASE_PAGE_SHIFT
+        );
+        for gpa in [0, 0x4000_0000, 0x4000_2000, 0x8000_0000] {
+            assert!(!x_view.is_executable(gpa) && x_view.is_writable(gpa));
+        }
+    }
+
+    #[test]
+    fn update_memory_types() {
+        testing::init();
+
+        let mut epts = Epts::new();
+        epts.build_identity_with(&typical_mtrr()).unwrap();
+        epts.update_permissions(
+            0x8000_0000..0x8000_1000,
+            EptPermissions::new(true, false, true),
+        )
+        .unwrap();
+
+        // The OS makes the legacy VGA range WC, and the 256MB WT range UC.
+        const VALID: u64 = 1 << 11;
+        let mut fixed = [0x0606_0606_0606_0606; 11];
+        fixed[2] = 0x0101_0101_0101_0101;
+        let mtrr = Mtrr::from_values(
+            MemoryType::WriteBack,
+            fixed,
+            &[
+                (0xc000_0000, 0xf_c000_0000 | VALID),
+                (0x8000_0000, 0xf_f000_0000 | VALID),
+            ],
+        );
+        // 32 PTEs for the VGA range, and 512 PTEs and 127 PDEs for the UC range.
+        assert_eq!(epts.update_memory_types(&mtrr), 32 + 512 + 127);
+        assert_eq!(
+            epts.pt.0.entries[0xa0].memory_type(),
+            MemoryType::WriteCombining as u64
+        );
+        assert_eq!(
+            epts.pd[2].0.entries[1].memory_type(),
+            MemoryType::Uncachable as u64
+        );
+        let (_, pt) = &epts.split_pts[0];
+        assert_eq!(pt.0.entries[0].memory_type(), MemoryType::Uncachable as u64);
+        assert!(!pt.0.entries[0].writable());
+        assert_eq!(epts.update_memory_types(&mtrr), 0);
+    }
+
+    #[test]
+    fn sub_page_protection() {
+        testing::init();
+
+        let mut epts = Epts::new();
+        epts.build_identity_with(&typical_mtrr()).unwrap();
+        epts.update_sub_page_protection(0x4000_1000, true).unwrap();
+        let (_, pt) = &epts.split_pts[0];
+        assert!(pt.0.entries[1].sub_page_write() && !pt.0.entries[1].writable());
+        assert!(!pt.0.entries[2].sub_page_write() && pt.0.entries[2].writable());
+
+        epts.update_sub_page_protection(0x4000_1000, false).unwrap();
+        assert!(!epts.leaf(0x4000_1000).sub_page_write());
+        assert!(epts.is_writable(0x4000_1000));
+    }
+
+    #[test]
+    fn transaction() {
+        testing::init();
+
+        let mut epts = Epts::new();
+        epts.build_identity_with(&typical_mtrr()).unwrap();
+        let shootdown = Shootdown::default();
+        let mut seen = 0;
+
+        let mut transaction = epts.transaction(&shootdown);
+        transaction.set_ram_writable(false);
+        transaction.set_permissions(0x1000..0x3000, EptPermissions::from_raw(0b111));
+        assert!(!shootdown.is_pending(&mut seen));
+        transaction.commit().unwrap();
+        assert!(!epts.is_writable(0));
+        assert!(epts.is_writable(0x1000) && epts.is_writable(0x2000));
+        assert!(!epts.is_writable(0x3000));
+
+        // One shootdown for the whole batch.
+        assert!(shootdown.is_pending(&mut seen));
+        assert!(!shootdown.is_pending(&mut seen));
+
+        // Nothing to shoot down for an empty batch.
+        epts.transaction(&shootdown).commit().unwrap();
+        assert!(!shootdown.is_pending(&mut seen));
+    }
+
+    #[test]
+    fn update_ram_permissions() {
+        testing::init();
+
+        let mtrr = typical_mtrr();
+        let mut epts = Epts::new();
+        epts.build_identity_with(&mtrr).unwrap();
+        epts.update_permissions(
+            0x1_0000_0000..0x1_0000_1000,
+            EptPermissions::new(true, true, true),
+        )
+        .unwrap();
+
+        epts.update_ram_permissions(false);
+        assert!(!epts.is_writable(0));
+        assert!(epts.is_writable(0xa_0000));
+        assert!(!epts.is_writable(0x4000_0000));
+        assert!(epts.is_writable(0x8000_0000));
+        assert!(epts.is_writable(0xc000_0000));
+        assert!(!epts.is_writable(0x1_0000_1000));
+        assert!(epts.is_ram(0x1_0000_1000));
+        assert!(!epts.is_ram(0xc000_0000));
+
+        epts.update_ram_permissions(true);
+        assert!(epts.is_writable(0));
+        assert!(epts.is_writable(0x1_0000_1000));
+    }
+
+    #[test]
+    fn audit() {
+        testing::init();
+
+        // The mock identity maps the heap, whose addresses use up to 47 bits.
+        let mut rules = EptRules {
+            max_phys_addr: 52,
+            execute_only: true,
+            mode_based_execute: true,
+        };
+        let mut epts = Epts::new();
+        epts.build_identity_with(&typical_mtrr()).unwrap();
+        assert_eq!(epts.audit(&rules), Ok(()));
+
+        epts.update_permissions(
+            0x4000_0000..0x4000_1000,
+            EptPermissions::new(false, false, true),
+        )
+        .unwrap();
+        assert_eq!(epts.audit(&rules), Ok(()));
+        rules.execute_only = false;
+        let entry = epts.split_pts[0].1.0.entries[0].0;
+        assert_eq!(
+            epts.audit(&rules),
+            Err(EptMisconfiguration::ExecuteOnly {
+                level: EptLevel::Pt,
+                gpa: 0x4000_0000,
+                entry,
+            })
+        );
+        rules.execute_only = true;
+
+        epts.pt.0.entries[0x10].set_readable(false);
+        let entry = epts.pt.0.entries[0x10].0;
+        assert_eq!(
+            epts.audit(&rules),
+            Err(EptMisconfiguration::WritableNotReadable {
+                level: EptLevel::Pt,
+                gpa: 0x10000,
+                entry,
+            })
+        );
+        epts.pt.0.entries[0x10].set_readable(true);
+
+        epts.pd[4].0.entries[5].set_memory_type(2);
+        let entry = epts.pd[4].0.entries[5].0;
+        assert_eq!(
+            epts.audit(&rules),
+            Err(EptMisconfiguration::InvalidMemoryType {
+                level: EptLevel::Pd,
+                gpa: 0x1_00a0_0000,
+                entry,
+            })
+        );
+        epts.pd[4].0.entries[5].set_memory_type(MemoryType::WriteBack as u64);
+
+        epts.pdpt.0.entries[1].set_memory_type(MemoryType::WriteBack as u64);
+        let entry = epts.pdpt.0.entries[1].0;
+        assert_eq!(
+            epts.audit(&rules),
+            Err(EptMisconfiguration::ReservedBits {
+                level: EptLevel::Pdpt,
+                gpa: 0x4000_0000,
+                entry,
+            })
+        );
+    }
+
+    #[test]
+    fn audit_reserved_address_bits() {
+        let rules = EptRules {
+            max_phys_addr: 39,
+            execute_only: false,
+            mode_based_execute: false,
+        };
+        let mut entry = Entry(0);
+        assert_eq!(audit_entry(&rules, EptLevel::Pd, &entry, 0), Ok(()));
+
+        entry.set_readable(true);
+        entry.set_large(true);
+        entry.set_memory_type(MemoryType::WriteBack as u64);
+        entry.set_pfn(0x7f_ffe0_0000 >> BASE_PAGE_SHIFT);
+        assert_eq!(audit_entry(&rules, EptLevel::Pd, &entry, 0), Ok(()));
+
+        entry.set_pfn(0x80_0000_0000 >> BASE_PAGE_SHIFT);
+        assert!(matches!(
+            audit_entry(&rules, EptLevel::Pd, &entry, 0),
+            Err(EptMisconfiguration::ReservedBits { .. })
+        ));
+
+        // A 2MB page must be 2MB aligned.
+        entry.set_pfn(0x20_1000 >> BASE_PAGE_SHIFT);
+        assert!(matches!(
+            audit_entry(&rules, EptLevel::Pd, &entry, 0),
+            Err(EptMisconfiguration::ReservedBits { .. })
+        ));
+
+        // Bit 10 alone does not make the entry present without mode-based
+        // execute control.
+        let mut entry = Entry(0);
+        entry.set_user_executable(true);
+        assert_eq!(audit_entry(&rules, EptLevel::Pt, &entry, 0), Ok(()));
+    }
 }
@@ -0,0 +1,142 @@
+//! This module defines the basic exit reasons of VMX.
+//!
+//! See: Table C-1. Basic Exit Reasons
+
+pub(super) const VMX_EXIT_REASON_EXCEPTION_OR_NMI: u64 = 0;
+pub(super) const VMX_EXIT_REASON_TRIPLE_FAULT: u64 = 2;
+pub(super) const VMX_EXIT_REASON_INIT: u64 = 3;
+pub(super) const VMX_EXIT_REASON_SIPI: u64 = 4;
+pub(super) const VMX_EXIT_REASON_CPUID: u64 = 10;
+pub(super) const VMX_EXIT_REASON_HLT: u64 = 12;
+pub(super) const VMX_EXIT_REASON_INVD: u64 = 13;
+pub(super) const VMX_EXIT_REASON_RDPMC: u64 = 15;
+pub(super) const VMX_EXIT_REASON_RDTSC: u64 = 16;
+pub(super) const VMX_EXIT_REASON_VMCALL: u64 = 18;
+pub(super) const VMX_EXIT_REASON_CR_ACCESS: u64 = 28;
+pub(super) const VMX_EXIT_REASON_IO_INSTRUCTION: u64 = 30;
+pub(super) const VMX_EXIT_REASON_RDMSR: u64 = 31;
+pub(super) const VMX_EXIT_REASON_WRMSR: u64 = 32;
+pub(super) const VMX_EXIT_REASON_MWAIT: u64 = 36;
+pub(super) const VMX_EXIT_REASON_MONITOR_TRAP_FLAG: u64 = 37;
+pub(super) const VMX_EXIT_REASON_MONITOR: u64 = 39;
+pub(super) const VMX_EXIT_REASON_PAUSE: u64 = 40;
+pub(super) const VMX_EXIT_REASON_MCE_DURING_VMENTRY: u64 = 41;
+pub(super) const VMX_EXIT_REASON_GDTR_IDTR_ACCESS: u64 = 46;
+pub(super) const VMX_EXIT_REASON_LDTR_TR_ACCESS: u64 = 47;
+pub(super) const VMX_EXIT_REASON_EPT_VIOLATION: u64 = 48;
+pub(super) const VMX_EXIT_REASON_RDTSCP: u64 = 51;
+pub(super) const VMX_EXIT_REASON_VMX_PREEMPTION_TIMER_EXPIRED: u64 = 52;
+pub(super) const VMX_EXIT_REASON_WBINVD: u64 = 54;
+pub(super) const VMX_EXIT_REASON_XSETBV: u64 = 55;
+pub(super) const VMX_EXIT_REASON_RDRAND: u64 = 57;
+pub(super) const VMX_EXIT_REASON_VMFUNC: u64 = 59;
+pub(super) const VMX_EXIT_REASON_RDSEED: u64 = 61;
+
+/// The number of the basic exit reasons, including reserved ones.
+pub(super) const COUNT: usize = NAMES.len();
+
+/// Returns the name of the basic exit `reason` for diagnostics.
+pub(super) fn name(reason: u64) -> &'static str {
+    usize::try_from(reason)
+        .ok()
+        .and_then(|reason| NAMES.get(reason))
+        .copied()
+        .unwrap_or("Unknown")
+}
+
+const NAMES: [&str; 80] = [
+    "Exception or NMI",
+    "External interrupt",
+    "Triple fault",
+    "INIT signal",
+    "Start-up IPI",
+    "I/O SMI",
+    "Other SMI",
+    "Interrupt window",
+    "NMI window",
+    "Task switch",
+    "CPUID",
+    "GETSEC",
+    "HLT",
+    "INVD",
+    "INVLPG",
+    "RDPMC",
+    "RDTSC",
+    "RSM",
+    "VMCALL",
+    "VMCLEAR",
+    "VMLAUNCH",
+    "VMPTRLD",
+    "VMPTRST",
+    "VMREAD",
+    "VMRESUME",
+    "VMWRITE",
+    "VMXOFF",
+    "VMXON",
+    "Control-register accesses",
+    "MOV DR",
+    "I/O instruction",
+    "RDMSR",
+    "WRMSR",
+    "VM-entry failure due to invalid guest state",
+    "VM-entry failure due to MSR loading",
+    "Reserved",
+    "MWAIT",
+    "Monitor trap flag",
+    "Reserved",
+    "MONITOR",
+    "PAUSE",
+    "VM-entry failure due to machine-check event",
+    "Reserved",
+    "TPR below threshold",
+    "APIC access",
+    "Virtualized EOI",
+    "Access to GDTR or IDTR",
+    "Access to LDTR or TR",
+    "EPT violation",
+    "EPT misconfiguration",
+    "INVEPT",
+    "RDTSCP",
+    "VMX-preemption timer expired",
+    "INVVPID",
+    "WBINVD or WBNOINVD",
+    "XSETBV",
+    "APIC write",
+    "RDRAND",
+    "INVPCID",
+    "VMFUNC",
+    "ENCLS",
+    "RDSEED",
+    "Page-modification log full",
+    "XSAVES",
+    "XRSTORS",
+    "PCONFIG",
+    "SPP-related event",
+    "UMWAIT",
+    "TPAUSE",
+    "LOADIWKEY",
+    "ENCLV",
+    "Reserved",
+    "ENQCMD PASID translation failure",
+    "ENQCMDS PASID translation failure",
+    "Bus lock",
+    "Instruction timeout",
+    "SEAMCALL",
+    "TDCALL",
+    "RDMSRLIST",
+    "WRMSRLIST",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(name(VMX_EXIT_REASON_CPUID), "CPUID");
+        assert_eq!(name(VMX_EXIT_REASON_EPT_VIOLATION), "EPT violation");
+        assert_eq!(name(VMX_EXIT_REASON_RDSEED), "RDSEED");
+        assert_eq!(name(79), "WRMSRLIST");
+        assert_eq!(name(80), "Unknown");
+    }
+}
@@ -1,41 +1,107 @@
 //! This module implements a guest management.
 
-use core::{arch::global_asm, ptr::addr_of};
+use core::{
+    arch::global_asm,
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use alloc::{
     boxed::Box,
     format,
     string::{String, ToString},
+    vec::Vec,
 };
+use bit_field::BitField;
 use derive_more::Debug;
-use spin::Lazy;
+use spin::{Lazy, Mutex, Once, RwLock};
 use x86::{
     bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
     controlregs::{Cr0, Cr4},
     debugregs::{Dr6, Dr7, dr0_write, dr1_write, dr2_write, dr3_write, dr6_write, dr7_write},
-    segmentation::{
-        CodeSegmentType, DataSegmentType, SystemDescriptorTypes64, cs, ds, es, fs, gs, ss,
-    },
+    dtables::DescriptorTablePointer,
+    segmentation::{SegmentSelector, cs, ds, es, fs, gs, ss},
     vmx::vmcs,
 };
 
 use crate::hypervisor::{
-    SHARED_HOST_DATA,
-    host::{Guest, InstructionInfo, VmExitReason},
-    platform_ops,
-    registers::Registers,
+    SHARED_HOST_DATA, ap_startup, cet,
+    config::HltPolicy,
+    decoder::{MemoryOperand, Operand},
+    deterministic_time,
+    ept_views::{Cr3Bindings, DEFAULT_EPT_VIEW, EptPermissions, EptViewError, MAX_EPT_VIEWS},
+    event_injection::{self, Event, EventKind, Resolution},
+    exception_policy,
+    exit_dispatch::DispatchTable,
+    exit_profile::push_bits,
+    hooks,
+    host::{
+        CrAccessInfo, DescriptorTableInfo, DescriptorTableInstruction, DescriptorTableOperand,
+        ExceptionInfo, Guest, InstructionInfo, IoInfo, NestedPageFaultInfo, RandomInfo,
+        VmExitReason,
+    },
+    lbr::{self, GuestLastBranches},
+    machine_check, microcode,
+    mini_vm::{MiniVmError, MiniVmExit, MiniVmMode, MiniVmRequest},
+    msr_monitor, mwait,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops::{self, PaError},
+    pmu, processor_trace,
+    registers::{FullGuestState, Registers},
     segment::SegmentDescriptor,
-    support::{Page, zeroed_box},
-    x86_instructions::{cr0, cr3, cr4, lar, ldtr, lsl, rdmsr, sgdt, sidt, tr, write_cr2},
+    snapshot::{Snapshot, SnapshotError},
+    speculation,
+    support::zeroed_box,
+    tsc_scaling, umip, vmx_hiding, watchdog,
+    x86_instructions::{
+        cr0, cr3, cr4, cr4_write, invept_single_context, lar, ldtr, lgdt, lidt, lsl, rdmsr, sgdt,
+        sidt, tr, write_cr2, wrmsr,
+    },
 };
 
-use super::epts::Epts;
+use super::{
+    epts::{EptpList, Epts, Shootdown},
+    exit_reasons::{
+        self, VMX_EXIT_REASON_CPUID, VMX_EXIT_REASON_CR_ACCESS, VMX_EXIT_REASON_EPT_VIOLATION,
+        VMX_EXIT_REASON_EXCEPTION_OR_NMI, VMX_EXIT_REASON_GDTR_IDTR_ACCESS, VMX_EXIT_REASON_HLT,
+        VMX_EXIT_REASON_INIT, VMX_EXIT_REASON_INVD, VMX_EXIT_REASON_IO_INSTRUCTION,
+        VMX_EXIT_REASON_LDTR_TR_ACCESS, VMX_EXIT_REASON_MCE_DURING_VMENTRY,
+        VMX_EXIT_REASON_MONITOR, VMX_EXIT_REASON_MONITOR_TRAP_FLAG, VMX_EXIT_REASON_MWAIT,
+        VMX_EXIT_REASON_PAUSE, VMX_EXIT_REASON_RDMSR, VMX_EXIT_REASON_RDPMC,
+        VMX_EXIT_REASON_RDRAND, VMX_EXIT_REASON_RDSEED, VMX_EXIT_REASON_RDTSC,
+        VMX_EXIT_REASON_RDTSCP, VMX_EXIT_REASON_SIPI, VMX_EXIT_REASON_TRIPLE_FAULT,
+        VMX_EXIT_REASON_VMCALL, VMX_EXIT_REASON_VMFUNC,
+        VMX_EXIT_REASON_VMX_PREEMPTION_TIMER_EXPIRED, VMX_EXIT_REASON_WBINVD,
+        VMX_EXIT_REASON_WRMSR, VMX_EXIT_REASON_XSETBV,
+    },
+    mini_vm::MiniVm,
+    mtrr::{self, Mtrr},
+    spp::SubPagePermissionTable,
+    vmcs_cache::VmcsCache,
+    vmx::VmxCapabilities,
+};
 
 /// Representation of a guest.
 pub(crate) struct VmxGuest {
     id: usize,
     registers: Registers,
     vmcs: Vmcs,
+    msr_areas: Box<MsrAreas>,
+    saved_state: Option<Box<SavedState>>,
+    cache: VmcsCache,
+    /// The generation of [`GuestTables::shootdown`] this processor last
+    /// invalidated cached EPT translations for.
+    ept_generation: u64,
+    /// Whether MOV to CR3 causes VM-exit. See [`GuestTables::cr3_exiting`].
+    cr3_exiting: bool,
+    /// The exception bitmap last written. See [`exception_policy::bitmap`].
+    exception_bitmap: u32,
+    /// The event being delivered at VM-exit and the VM-exit instruction length,
+    /// to be injected on VM-entry. See [`event_injection`].
+    pending_event: Option<(Event, u64)>,
+    /// The guest RIP at the last VM-exit.
+    exit_rip: u64,
 }
 
 impl Guest for VmxGuest {
@@ -59,6 +125,14 @@ impl Guest for VmxGuest {
             id,
             registers: Registers::default(),
             vmcs: Vmcs::new(),
+            msr_areas: zeroed_box::<MsrAreas>(),
+            saved_state: None,
+            cache: VmcsCache::default(),
+            ept_generation: 0,
+            cr3_exiting: false,
+            exception_bitmap: 0,
+            pending_event: None,
+            exit_rip: 0,
         }
     }
 
@@ -72,7 +146,7 @@ impl Guest for VmxGuest {
         //  VMCS region whose VMCS revision identifier differs from that used by
         //  the processor."
         // See: 25.2 FORMAT OF THE VMCS REGION
-        vmptrld(&mut self.vmcs);
+        self.vmcs.load();
 
         // The processor now have an associated VMCS (called a current VMCS) and
         // able to execute the VMREAD and VMWRITE instructions. Let us program it.
@@ -80,96 +154,1046 @@ impl Guest for VmxGuest {
 
     fn initialize(&mut self, registers: &Registers) {
         self.registers = *registers;
+        self.msr_areas.initialize();
         self.initialize_control();
         self.initialize_guest();
         self.initialize_host();
     }
 
     fn run(&mut self) -> VmExitReason {
-        const VMX_EXIT_REASON_INIT: u16 = 3;
-        const VMX_EXIT_REASON_SIPI: u16 = 4;
-        const VMX_EXIT_REASON_CPUID: u16 = 10;
-        const VMX_EXIT_REASON_RDMSR: u16 = 31;
-        const VMX_EXIT_REASON_WRMSR: u16 = 32;
-        const VMX_EXIT_REASON_XSETBV: u16 = 55;
+        // Invalidate the translations derived from EPTs changed since the last
+        // VM-entry, possibly by other processors.
+        if SHARED_GUEST_DATA.capabilities.ept {
+            let tables = SHARED_GUEST_DATA.tables(self.id);
+            if tables.shootdown.is_pending(&mut self.ept_generation) {
+                tables.invalidate();
+            }
 
-        vmwrite(vmcs::guest::RIP, self.registers.rip);
-        vmwrite(vmcs::guest::RSP, self.registers.rsp);
-        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+            // Intercept MOV to CR3 only while views are bound to processes,
+            // possibly by other processors.
+            let cr3_exiting = tables.cr3_exiting.load(Ordering::Relaxed);
+            if cr3_exiting != self.cr3_exiting {
+                self.set_cr3_exiting(cr3_exiting);
+            }
+        }
+
+        // Intercept exceptions as the policy table, possibly changed by other
+        // processors, tells.
+        let exception_bitmap = exception_policy::bitmap();
+        if exception_bitmap != self.exception_bitmap {
+            self.cache
+                .write(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+            self.exception_bitmap = exception_bitmap;
+        }
+
+        if !self.complete_event_delivery() {
+            return VmExitReason::Shutdown;
+        }
+
+        // Write back only the fields changed while handling the last VM-exit.
+        self.cache.write(vmcs::guest::RIP, self.registers.rip);
+        self.cache.write(vmcs::guest::RSP, self.registers.rsp);
+        self.cache.write(vmcs::guest::RFLAGS, self.registers.rflags);
+        self.cache.flush();
 
         // Execute the guest until VM-exit occurs.
         log::trace!("Entering the guest");
-        let flags = unsafe { run_vmx_guest(&mut self.registers) };
+        let flags = self.vmcs.enter(&mut self.registers);
         if let Err(err) = vmx_succeed(RFlags::from_raw(flags)) {
             panic!("{err}");
         }
         log::trace!("Exited the guest");
 
-        self.registers.rip = vmread(vmcs::guest::RIP);
-        self.registers.rsp = vmread(vmcs::guest::RSP);
-        self.registers.rflags = vmread(vmcs::guest::RFLAGS);
+        self.cache.invalidate();
+        self.registers.rip = self.cache.read(vmcs::guest::RIP);
+        self.registers.rsp = self.cache.read(vmcs::guest::RSP);
+        self.registers.rflags = self.cache.read(vmcs::guest::RFLAGS);
+        self.exit_rip = self.registers.rip;
+        self.record_pending_event();
 
         // Return VM-exit reason.
-        match vmread(vmcs::ro::EXIT_REASON) as u16 {
-            VMX_EXIT_REASON_INIT => {
-                self.handle_init_signal();
-                VmExitReason::InitSignal
+        let exit_reason = self.cache.read(vmcs::ro::EXIT_REASON);
+        match EXIT_HANDLERS.get(exit_reason.get_bits(0..=15)) {
+            Some(handler) => handler(self),
+            None => self.unhandled_exit(exit_reason),
+        }
+    }
+
+    fn regs(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        const NMI: u8 = 2;
+        const TYPE_NMI: u32 = 2;
+        const TYPE_HARDWARE_EXCEPTION: u32 = 3;
+
+        // See: Table 25-17. Format of the VM-Entry Interruption-Information Field
+        let mut info = u32::from(vector);
+        let _ = info.set_bits(
+            8..=10,
+            if vector == NMI {
+                TYPE_NMI
+            } else {
+                TYPE_HARDWARE_EXCEPTION
+            },
+        );
+        if let Some(error_code) = error_code {
+            let _ = info.set_bit(11, true);
+            self.cache
+                .write(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+        }
+        let _ = info.set_bit(31, true);
+        self.cache
+            .write(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, info);
+    }
+
+    fn inject_software_exception(&mut self, vector: u8, next_rip: u64) {
+        let event = Event {
+            vector,
+            kind: EventKind::SoftwareException,
+            error_code: None,
+        };
+
+        // The processor pushes RIP plus the instruction length.
+        // See: 27.6.1.1 Details of Vectored-Event Injection
+        self.cache.write(
+            vmcs::control::VMENTRY_INSTRUCTION_LEN,
+            next_rip - self.registers.rip,
+        );
+        self.cache.write(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+            event.encode(),
+        );
+    }
+
+    fn cpl(&self) -> u8 {
+        // "The value of the DPL field for SS is always equal to the logical
+        //  processor’s current privilege level (CPL)."
+        // See: 25.4.1 Guest Register State
+        self.cache
+            .read(vmcs::guest::SS_ACCESS_RIGHTS)
+            .get_bits(5..=6) as u8
+    }
+
+    fn halt(&mut self) -> bool {
+        const BLOCKING_BY_STI_AND_MOV_SS: u64 = 0b11;
+
+        if !SHARED_GUEST_DATA.capabilities.hlt_activity_state {
+            return false;
+        }
+
+        // HLT completed, ending blocking by STI and MOV SS, if any. The HLT
+        // state cannot be entered with either. See: 27.3.1.5 Checks on Guest
+        // Non-Register State
+        let interruptibility = self.cache.read(vmcs::guest::INTERRUPTIBILITY_STATE);
+        self.cache.write(
+            vmcs::guest::INTERRUPTIBILITY_STATE,
+            interruptibility & !BLOCKING_BY_STI_AND_MOV_SS,
+        );
+        self.cache
+            .write(vmcs::guest::ACTIVITY_STATE, GuestActivityState::Hlt as u32);
+        true
+    }
+
+    fn reset(&mut self) -> bool {
+        // The BSP is never sent SIPI.
+        if self.id == 0 || !SHARED_GUEST_DATA.capabilities.unrestricted_guest {
+            return false;
+        }
+        self.handle_init_signal();
+        true
+    }
+
+    fn devirtualize(&mut self) {
+        // Load the guest values of the registers that may differ from the host
+        // values. The rest is the same as the host, as the guest started as a
+        // copy of it.
+        self.cache.flush();
+        // The guest may have changed CR4, for example, to enable SMEP or SMAP.
+        // Load it before CR3, which may have a PCID only with CR4.PCIDE.
+        cr4_write(Cr4::from_bits_truncate(vmread(vmcs::guest::CR4) as usize));
+        unsafe { x86::controlregs::cr3_write(vmread(vmcs::guest::CR3)) };
+        lgdt(&DescriptorTablePointer {
+            base: vmread(vmcs::guest::GDTR_BASE) as *const u64,
+            limit: u16::try_from(vmread(vmcs::guest::GDTR_LIMIT)).unwrap(),
+        });
+        // VM-exit loaded the host TR. Load the guest TR back, after making its
+        // TSS descriptor available, as LTR requires and marks it busy again.
+        // See: 8.2.2 TSS Descriptor
+        let tr = SegmentSelector::from_raw(vmread(vmcs::guest::TR_SELECTOR) as u16);
+        if tr.index() != 0 {
+            const TSS_BUSY: u64 = 1 << 41;
+            let gdt = vmread(vmcs::guest::GDTR_BASE) as *mut u64;
+            unsafe {
+                *gdt.add(usize::from(tr.index())) &= !TSS_BUSY;
+                x86::task::load_tr(tr);
+            };
+        }
+        lidt(&DescriptorTablePointer {
+            base: vmread(vmcs::guest::IDTR_BASE) as *const u64,
+            limit: u16::try_from(vmread(vmcs::guest::IDTR_LIMIT)).unwrap(),
+        });
+        wrmsr(x86::msr::IA32_FS_BASE, vmread(vmcs::guest::FS_BASE));
+        wrmsr(x86::msr::IA32_GS_BASE, vmread(vmcs::guest::GS_BASE));
+        wrmsr(
+            x86::msr::IA32_SYSENTER_CS,
+            vmread(vmcs::guest::IA32_SYSENTER_CS),
+        );
+        wrmsr(
+            x86::msr::IA32_SYSENTER_EIP,
+            vmread(vmcs::guest::IA32_SYSENTER_EIP),
+        );
+        wrmsr(
+            x86::msr::IA32_SYSENTER_ESP,
+            vmread(vmcs::guest::IA32_SYSENTER_ESP),
+        );
+        for entry in &self.msr_areas.guest[..isolated_msrs().len()] {
+            wrmsr(entry.index, entry.data);
+        }
+        if SHARED_GUEST_DATA.capabilities.spec_ctrl_virtualization {
+            wrmsr(
+                speculation::IA32_SPEC_CTRL,
+                vmread(VMCS_CONTROL_IA32_SPEC_CTRL_SHADOW),
+            );
+        }
+        if SHARED_GUEST_DATA.capabilities.cet {
+            // The host runs without supervisor shadow stacks, and switching to
+            // the shadow stack of the guest requires a restore token on it,
+            // which the guest did not create. Leave supervisor CET disabled.
+            wrmsr(
+                cet::IA32_INTERRUPT_SSP_TABLE_ADDR,
+                vmread(VMCS_GUEST_IA32_INTERRUPT_SSP_TABLE_ADDR),
+            );
+            if vmread(VMCS_GUEST_IA32_S_CET) != 0 {
+                log::error!("Supervisor CET cannot be restored and remains disabled");
             }
-            VMX_EXIT_REASON_SIPI => {
-                self.handle_sipi_signal();
-                VmExitReason::StartupIpi
+        }
+        wrmsr(
+            x86::msr::IA32_DEBUGCTL,
+            vmread(vmcs::guest::IA32_DEBUGCTL_FULL),
+        );
+        if SHARED_GUEST_DATA.capabilities.arch_lbr {
+            wrmsr(lbr::IA32_LBR_CTL, vmread(VMCS_GUEST_IA32_LBR_CTL));
+        }
+        if isolates_pmu() {
+            wrmsr(
+                pmu::IA32_PERF_GLOBAL_CTRL,
+                vmread(vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL),
+            );
+        }
+        if SHARED_GUEST_DATA.capabilities.processor_trace && !processor_trace::host_owned() {
+            wrmsr(
+                x86::msr::MSR_IA32_RTIT_CTL,
+                vmread(vmcs::guest::IA32_RTIT_CTL_FULL),
+            );
+        }
+
+        // Make the VMCS inactive and write its data back to memory.
+        // See: 25.11.3 Initializing a VMCS
+        self.vmcs.clear();
+    }
+
+    fn save_state(&mut self) {
+        self.cache.flush();
+        let fields = GUEST_STATE_FIELDS
+            .iter()
+            .filter_map(|&field| {
+                unsafe { x86::bits64::vmx::vmread(field) }
+                    .ok()
+                    .map(|value| (field, value))
+            })
+            .collect();
+        self.saved_state = Some(Box::new(SavedState {
+            registers: self.registers,
+            fields,
+            cr0_read_shadow: vmread(vmcs::control::CR0_READ_SHADOW),
+            cr4_read_shadow: vmread(vmcs::control::CR4_READ_SHADOW),
+            msrs: self.msr_areas.guest,
+        }));
+    }
+
+    fn restore_state(&mut self) -> bool {
+        let Some(state) = &self.saved_state else {
+            return false;
+        };
+        self.registers = state.registers;
+        for &(field, value) in &state.fields {
+            vmwrite(field, value);
+        }
+        vmwrite(vmcs::control::CR0_READ_SHADOW, state.cr0_read_shadow);
+        vmwrite(vmcs::control::CR4_READ_SHADOW, state.cr4_read_shadow);
+        self.msr_areas.guest = state.msrs;
+        self.cache.invalidate();
+        true
+    }
+
+    fn take_snapshot(&mut self) -> Result<(), SnapshotError> {
+        // Guest memory is copied through the identity mapping.
+        if !SHARED_GUEST_DATA.capabilities.ept || SHARED_HOST_DATA.get().unwrap().pt.is_none() {
+            return Err(SnapshotError::Unsupported);
+        }
+
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut snapshot = tables.snapshot.lock();
+        if !snapshot.is_available() {
+            return Err(SnapshotError::Unsupported);
+        }
+        snapshot.take();
+        let mut epts = tables.epts.write();
+        let mut transaction = epts.transaction(&tables.shootdown);
+        transaction.set_ram_writable(false);
+        transaction.commit().unwrap();
+        Ok(())
+    }
+
+    fn restore_snapshot(&mut self) -> Result<(), SnapshotError> {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut snapshot = tables.snapshot.lock();
+        let mut epts = tables.epts.write();
+        let mut transaction = epts.transaction(&tables.shootdown);
+        let result = match snapshot.restore() {
+            Ok(gpas) => {
+                for gpa in gpas {
+                    transaction.set_permissions(
+                        gpa..gpa + BASE_PAGE_SIZE as u64,
+                        EptPermissions::new(true, false, true),
+                    );
+                }
+                Ok(())
             }
-            VMX_EXIT_REASON_CPUID => VmExitReason::Cpuid(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
-            }),
-            VMX_EXIT_REASON_RDMSR => VmExitReason::Rdmsr(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
-            }),
-            VMX_EXIT_REASON_WRMSR => VmExitReason::Wrmsr(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
-            }),
-            VMX_EXIT_REASON_XSETBV => VmExitReason::XSetBv(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
-            }),
-            _ => {
-                log::error!("{:#x?}", self.vmcs);
-                panic!(
-                    "Unhandled VM-exit reason: {:?}",
-                    vmread(vmcs::ro::EXIT_REASON)
-                )
+            Err(SnapshotError::PoolExhausted) => {
+                transaction.set_ram_writable(true);
+                Err(SnapshotError::PoolExhausted)
             }
+            Err(e) => Err(e),
+        };
+        transaction.commit().unwrap();
+        result
+    }
+
+    fn discard_snapshot(&mut self) -> Result<(), SnapshotError> {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut snapshot = tables.snapshot.lock();
+        if !snapshot.is_active() {
+            return Err(SnapshotError::NoSnapshot);
         }
+        snapshot.discard();
+        let mut epts = tables.epts.write();
+        let mut transaction = epts.transaction(&tables.shootdown);
+        transaction.set_ram_writable(true);
+        transaction.commit().unwrap();
+        Ok(())
     }
 
-    fn regs(&mut self) -> &mut Registers {
-        &mut self.registers
+    fn handle_snapshot_write(&mut self, gpa: u64) -> bool {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut snapshot = tables.snapshot.lock();
+        let mut epts = tables.epts.write();
+        if !snapshot.is_active() || !epts.is_ram(gpa) {
+            return false;
+        }
+
+        // Another processor sharing the EPTs may have saved the page already.
+        // Only this processor's cached translation is stale then, which is
+        // invalidated by the shootdown before the next VM-entry.
+        if epts.is_writable(gpa) {
+            return true;
+        }
+
+        // Let the guest write even if the page cannot be saved. The snapshot is
+        // lost then, and restoring it fails.
+        if let Err(e) = snapshot.save(gpa) {
+            log::warn!("Failed to save {gpa:#x} to the snapshot: {e}");
+        }
+        let page = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let mut transaction = epts.transaction(&tables.shootdown);
+        transaction.set_permissions(
+            page..page + BASE_PAGE_SIZE as u64,
+            EptPermissions::new(true, true, true),
+        );
+        transaction.commit().unwrap();
+        true
+    }
+
+    fn run_mini_vm(&mut self, request: &MiniVmRequest) -> Result<(MiniVmExit, u64), MiniVmError> {
+        let capabilities = &SHARED_GUEST_DATA.capabilities;
+        if !capabilities.ept
+            || (request.mode == MiniVmMode::Real && !capabilities.unrestricted_guest)
+        {
+            return Err(MiniVmError::Unsupported);
+        }
+        request.validate()?;
+
+        let mut mini_vm = MiniVm::new(request).map_err(|_| MiniVmError::InvalidBlob)?;
+
+        // Make the VMCS of the mini VM current, run it, and switch back. Pending
+        // writes must land in the guest VMCS before it stops being current. The
+        // guest VMCS stays launched as it is not cleared, while the mini VM's is
+        // cleared so that the processor does not keep it cached after freed.
+        // See: 25.11.1 Software Use of Virtual-Machine Control Structures
+        self.cache.flush();
+        mini_vm.vmcs.load();
+        mini_vm.initialize(request, self);
+        let result = mini_vm.run();
+        mini_vm.vmcs.clear();
+        self.vmcs.load();
+
+        let exit = result?;
+        if exit == MiniVmExit::Nmi {
+            // Reflect the NMI to the guest, which is the owner of the system.
+            self.inject_exception(2, None);
+        }
+        Ok((exit, mini_vm.regs().rax))
+    }
+
+    fn switch_ept_view(&mut self, view: usize) -> Result<(), EptViewError> {
+        if !SHARED_GUEST_DATA.capabilities.ept {
+            return Err(EptViewError::Unsupported);
+        }
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        if tables.view(view).is_none() {
+            return Err(EptViewError::InvalidView);
+        }
+        self.cache
+            .write(vmcs::control::EPTP_FULL, tables.eptp_list.entries[view]);
+        Ok(())
+    }
+
+    fn protect_ept_view(
+        &mut self,
+        view: usize,
+        range: Range<u64>,
+        permissions: EptPermissions,
+    ) -> Result<(), EptViewError> {
+        let capabilities = &SHARED_GUEST_DATA.capabilities;
+        if !capabilities.ept
+            || (permissions.user_execute != permissions.execute && !capabilities.mbec)
+        {
+            return Err(EptViewError::Unsupported);
+        }
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let mut epts = tables.view(view).ok_or(EptViewError::InvalidView)?.write();
+        let mut transaction = epts.transaction(&tables.shootdown);
+        transaction.set_permissions(range, permissions);
+        transaction.commit().map_err(|_| EptViewError::InvalidRange)
+    }
+
+    fn protect_sub_pages(
+        &mut self,
+        view: usize,
+        gpa: u64,
+        writable: u32,
+    ) -> Result<(), EptViewError> {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let sppt = tables.sppt.as_ref().ok_or(EptViewError::Unsupported)?;
+        let mut epts = tables.view(view).ok_or(EptViewError::InvalidView)?.write();
+        let protect = writable != u32::MAX;
+        if protect {
+            // Set the permissions before the EPT entry starts referring to them.
+            sppt.lock()
+                .set(gpa, writable)
+                .map_err(|_| EptViewError::InvalidRange)?;
+        }
+        let mut transaction = epts.transaction(&tables.shootdown);
+        transaction.set_sub_page_protection(gpa, protect);
+        transaction.commit().map_err(|_| EptViewError::InvalidRange)
+    }
+
+    fn handle_ept_view_fault(&mut self) -> bool {
+        if !SHARED_GUEST_DATA.capabilities.ept {
+            return false;
+        }
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let default_eptp = tables.eptp_list.entries[DEFAULT_EPT_VIEW];
+        let eptp = self.cache.read(vmcs::control::EPTP_FULL);
+        if eptp == default_eptp || Some(eptp) == tables.hooked_eptp() {
+            return false;
+        }
+        self.cache.write(vmcs::control::EPTP_FULL, default_eptp);
+        true
+    }
+
+    fn bind_ept_view(&mut self, cr3: u64, view: usize) -> Result<(), EptViewError> {
+        if !SHARED_GUEST_DATA.capabilities.ept {
+            return Err(EptViewError::Unsupported);
+        }
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        if tables.view(view).is_none() {
+            return Err(EptViewError::InvalidView);
+        }
+        let mut bindings = tables.cr3_bindings.write();
+        bindings.bind(cr3, view)?;
+        tables
+            .cr3_exiting
+            .store(!bindings.is_empty(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_cr3(&mut self, cr3: u64) {
+        let cr0 = Cr0::from_bits_truncate(self.cache.read(vmcs::guest::CR0) as usize);
+        let cr4 = Cr4::from_bits_truncate(self.cache.read(vmcs::guest::CR4) as usize);
+
+        // "If CR4.PCIDE = 1, bit 63 of the source operand to MOV to CR3
+        //  determines whether the instruction invalidates entries in the TLBs
+        //  (...). The instruction does not modify bit 63 of CR3, which is
+        //  reserved and always 0."
+        // See: 4.10.4.1 Operations that Invalidate TLBs and Paging-Structure Caches
+        //
+        // TLBs are invalidated on VM-entry anyway, as VPIDs are not enabled.
+        // See: 29.4.3.3 Guidelines for Use of the INVVPID Instruction
+        let cr3 = if cr4.contains(Cr4::CR4_ENABLE_PCID) {
+            cr3 & !(1 << 63)
+        } else {
+            cr3
+        };
+        self.cache.write(vmcs::guest::CR3, cr3);
+
+        // With PAE paging, MOV to CR3 loads the PDPTEs from the address in CR3,
+        // which VM-entry loads from the VMCS with EPT. Guest memory is read
+        // through the identity mapping as with snapshots.
+        // See: 4.4.1 PDPTE Registers
+        // See: 27.3.2.4 Loading Page-Directory-Pointer-Table Entries
+        let ia32e = self.cache.read(vmcs::control::VMENTRY_CONTROLS) as u32
+            & vmcs::control::EntryControls::IA32E_MODE_GUEST.bits()
+            != 0;
+        if cr0.contains(Cr0::CR0_ENABLE_PAGING) && cr4.contains(Cr4::CR4_ENABLE_PAE) && !ia32e {
+            let pdptes = unsafe { *((cr3 & !0x1f) as *const [u64; 4]) };
+            for (field, pdpte) in [
+                vmcs::guest::PDPTE0_FULL,
+                vmcs::guest::PDPTE1_FULL,
+                vmcs::guest::PDPTE2_FULL,
+                vmcs::guest::PDPTE3_FULL,
+            ]
+            .into_iter()
+            .zip(pdptes)
+            {
+                self.cache.write(field, pdpte);
+            }
+        }
+
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let bindings = tables.cr3_bindings.read();
+        if !bindings.is_empty() {
+            self.cache.write(
+                vmcs::control::EPTP_FULL,
+                tables.eptp_list.entries[bindings.view_for(cr3)],
+            );
+        }
+    }
+
+    fn long_mode_cr3(&self) -> Option<u64> {
+        let cr4 = Cr4::from_bits_truncate(self.cache.read(vmcs::guest::CR4) as usize);
+        (self.in_64bit_mode() && !cr4.contains(Cr4::CR4_ENABLE_LA57))
+            .then(|| self.cache.read(vmcs::guest::CR3))
+    }
+
+    fn full_state(&self) -> FullGuestState {
+        FullGuestState {
+            cs: self.cache.read(vmcs::guest::CS_SELECTOR) as u16,
+            ss: self.cache.read(vmcs::guest::SS_SELECTOR) as u16,
+            cr0: self.cache.read(vmcs::guest::CR0),
+            cr3: self.cache.read(vmcs::guest::CR3),
+            cr4: self.cache.read(vmcs::guest::CR4),
+            efer: self.cache.read(vmcs::guest::IA32_EFER_FULL),
+            gs_base: self.cache.read(vmcs::guest::GS_BASE),
+            gdtr_base: self.cache.read(vmcs::guest::GDTR_BASE),
+            gdtr_limit: self.cache.read(vmcs::guest::GDTR_LIMIT) as u16,
+            idtr_base: self.cache.read(vmcs::guest::IDTR_BASE),
+            idtr_limit: self.cache.read(vmcs::guest::IDTR_LIMIT) as u16,
+            ldtr: self.cache.read(vmcs::guest::LDTR_SELECTOR) as u16,
+            tr: self.cache.read(vmcs::guest::TR_SELECTOR) as u16,
+        }
+    }
+
+    fn set_monitor_trap(&mut self, enable: bool) -> bool {
+        // See: 26.5.2 Monitor Trap Flag
+        let mut controls = vmcs::control::PrimaryControls::from_bits_truncate(
+            self.cache
+                .read(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32,
+        );
+        controls.set(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG, enable);
+        let adjusted = Self::adjust_vmx_control(VmxControl::ProcessorBased, controls.bits().into());
+        self.cache
+            .write(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjusted);
+        !enable || adjusted as u32 & vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG.bits() != 0
+    }
+
+    fn set_descriptor_table_exiting(&mut self, enable: bool) {
+        let mut controls = vmcs::control::SecondaryControls::from_bits_truncate(
+            self.cache
+                .read(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) as u32,
+        );
+        controls.set(vmcs::control::SecondaryControls::DTABLE_EXITING, enable);
+        self.cache.write(
+            vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            controls.bits(),
+        );
+    }
+
+    fn reprobe_capabilities(&self) {
+        let capabilities = VmxCapabilities::read();
+        if capabilities != SHARED_GUEST_DATA.capabilities {
+            log::warn!("VMX capabilities changed after the microcode update: {capabilities:#x?}");
+        }
+    }
+
+    fn last_branches(&self) -> GuestLastBranches {
+        // VM-exit cleared IA32_LBR_CTL, and the LBR MSRs keep the records of
+        // the guest.
+        if SHARED_GUEST_DATA.capabilities.arch_lbr {
+            GuestLastBranches::ArchLbrMsrs
+        } else {
+            GuestLastBranches::Unavailable
+        }
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) -> Option<u64> {
+        // The processor value is overwritten on VM-entry. Update the value to
+        // load instead. `pmu::is_invalid_write` has validated it.
+        if msr == pmu::IA32_PERF_GLOBAL_CTRL && isolates_pmu() {
+            vmwrite(vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL, value);
+            return None;
+        }
+        // Likewise for the MSRs switched on VM-entry and those in the guest-state
+        // area.
+        if let Some(index) = isolated_msrs().iter().position(|&m| m == msr) {
+            self.msr_areas.guest[index].data = value;
+            return None;
+        }
+        if msr == x86::msr::IA32_SYSENTER_EIP {
+            vmwrite(vmcs::guest::IA32_SYSENTER_EIP, value);
+            return None;
+        }
+        // The MTRRs are intercepted to keep the EPT memory types in sync. An
+        // invalid value would cause #GP in the host, and is dropped instead.
+        if SHARED_GUEST_DATA.capabilities.ept && mtrr::intercepted_writes().any(|m| m == msr) {
+            if mtrr::is_valid_write(msr, value) {
+                wrmsr(msr, value);
+                self.update_memory_types();
+            } else {
+                log::warn!("Ignoring the invalid MTRR value {value:#x} for {msr:#x}");
+            }
+            return None;
+        }
+        Some(value)
+    }
+
+    fn lstar(&self) -> u64 {
+        let index = ISOLATED_MSRS
+            .iter()
+            .position(|&msr| msr == x86::msr::IA32_LSTAR)
+            .unwrap();
+        self.msr_areas.guest[index].data
+    }
+
+    fn dump(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        self.cache.flush();
+        write!(out, "{:#x?}", self.vmcs)
+    }
+
+    fn optional_intercepts(&self) -> Vec<String> {
+        use vmcs::control::{
+            PinbasedControls as Pin, PrimaryControls as Primary, SecondaryControls as Secondary,
+        };
+
+        const PIN: [(Pin, &str); 3] = [
+            (Pin::EXTERNAL_INTERRUPT_EXITING, "external-interrupts"),
+            (Pin::NMI_EXITING, "NMI"),
+            (Pin::VMX_PREEMPTION_TIMER, "preemption-timer"),
+        ];
+        const PRIMARY: [(Primary, &str); 16] = [
+            (Primary::INTERRUPT_WINDOW_EXITING, "interrupt-window"),
+            (Primary::HLT_EXITING, "HLT"),
+            (Primary::INVLPG_EXITING, "INVLPG"),
+            (Primary::MWAIT_EXITING, "MWAIT"),
+            (Primary::RDPMC_EXITING, "RDPMC"),
+            (Primary::RDTSC_EXITING, "RDTSC"),
+            (Primary::CR3_LOAD_EXITING, "MOV-to-CR3"),
+            (Primary::CR3_STORE_EXITING, "MOV-from-CR3"),
+            (Primary::CR8_LOAD_EXITING, "MOV-to-CR8"),
+            (Primary::CR8_STORE_EXITING, "MOV-from-CR8"),
+            (Primary::NMI_WINDOW_EXITING, "NMI-window"),
+            (Primary::MOV_DR_EXITING, "MOV-DR"),
+            (Primary::UNCOND_IO_EXITING, "I/O"),
+            (Primary::USE_IO_BITMAPS, "I/O-bitmaps"),
+            (Primary::MONITOR_TRAP_FLAG, "MTF"),
+            (Primary::MONITOR_EXITING, "MONITOR"),
+        ];
+        const SECONDARY: [(Secondary, &str); 6] = [
+            (Secondary::DTABLE_EXITING, "descriptor-tables"),
+            (Secondary::WBINVD_EXITING, "WBINVD"),
+            (Secondary::PAUSE_LOOP_EXITING, "PAUSE-loop"),
+            (Secondary::RDRAND_EXITING, "RDRAND"),
+            (Secondary::RDSEED_EXITING, "RDSEED"),
+            (Secondary::ENCLS_EXITING, "ENCLS"),
+        ];
+
+        // The controls that the processor does not allow to clear are the
+        // architectural minimum. Only those causing VM-exits are checked.
+        let optional = |control, field, names: &[(u64, &str)]| {
+            let exiting = names.iter().fold(0, |bits, (bit, _)| bits | bit);
+            self.cache.read(field) & !Self::adjust_vmx_control(control, 0) & exiting
+        };
+        let mut intercepts = Vec::new();
+
+        let names = PIN.map(|(bit, name)| (u64::from(bit.bits()), name));
+        let pin = optional(
+            VmxControl::PinBased,
+            vmcs::control::PINBASED_EXEC_CONTROLS,
+            &names,
+        );
+        push_bits(&mut intercepts, "pin-based", pin, &names);
+
+        let names = PRIMARY.map(|(bit, name)| (u64::from(bit.bits()), name));
+        let primary = optional(
+            VmxControl::ProcessorBased,
+            vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            &names,
+        );
+        push_bits(&mut intercepts, "primary", primary, &names);
+
+        let controls = Primary::from_bits_truncate(
+            self.cache
+                .read(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32,
+        );
+        if controls.contains(Primary::SECONDARY_CONTROLS) {
+            let names = SECONDARY.map(|(bit, name)| (u64::from(bit.bits()), name));
+            // WBINVD exiting is required by the hypervisor. See
+            // `initialize_control`.
+            let secondary = optional(
+                VmxControl::ProcessorBased2,
+                vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+                &names,
+            ) & !u64::from(Secondary::WBINVD_EXITING.bits());
+            push_bits(&mut intercepts, "secondary", secondary, &names);
+        }
+
+        // Without MSR bitmaps, every RDMSR and WRMSR causes VM-exit.
+        // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+        let msr_bitmaps = &SHARED_GUEST_DATA.tables(self.id).msr_bitmaps;
+        if !controls.contains(Primary::USE_MSR_BITMAPS) {
+            intercepts.push("RDMSR/WRMSR".to_string());
+        } else if !msr_bitmaps.is_zeroed() {
+            intercepts.push("MSR-bitmaps".to_string());
+        }
+
+        let exceptions = self.cache.read(vmcs::control::EXCEPTION_BITMAP)
+            & !u64::from(exception_policy::REQUIRED_BITMAP);
+        if exceptions != 0 {
+            intercepts.push(format!("exceptions={exceptions:#x}"));
+        }
+        for (field, name, required) in [
+            (vmcs::control::CR0_GUEST_HOST_MASK, "CR0-mask", 0),
+            (
+                vmcs::control::CR4_GUEST_HOST_MASK,
+                "CR4-mask",
+                CR4_HOST_OWNED,
+            ),
+        ] {
+            let mask = self.cache.read(field) & !required;
+            if mask != 0 {
+                intercepts.push(format!("{name}={mask:#x}"));
+            }
+        }
+        intercepts
     }
 }
 
 impl VmxGuest {
+    /// Returns the address of the instruction next to the one that caused the
+    /// VM-exit.
+    fn next_rip(&self) -> u64 {
+        self.registers.rip + self.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_LEN)
+    }
+
+    /// Returns `true` if the guest runs in 64-bit mode.
+    fn in_64bit_mode(&self) -> bool {
+        // "IA-32e mode guest" is set on VM-exit when the guest was in IA-32e
+        // mode. The guest is in 64-bit mode if CS.L is also set.
+        // See: 28.2 Recording VM-Exit Information and Updating VM-Entry Control Fields
+        let ia32e = self.cache.read(vmcs::control::VMENTRY_CONTROLS) as u32
+            & vmcs::control::EntryControls::IA32E_MODE_GUEST.bits()
+            != 0;
+        let cs = VmxSegmentAccessRights(self.cache.read(vmcs::guest::CS_ACCESS_RIGHTS) as u32);
+        ia32e && cs.long_mode()
+    }
+
+    /// Returns the instruction accessing LDTR or TR if `ldtr_tr` is `true`, or
+    /// GDTR or IDTR otherwise, that caused the VM-exit.
+    fn descriptor_table_info(&self, ldtr_tr: bool) -> DescriptorTableInfo {
+        const SEGMENT_BASES: [u32; 6] = [
+            vmcs::guest::ES_BASE,
+            vmcs::guest::CS_BASE,
+            vmcs::guest::SS_BASE,
+            vmcs::guest::DS_BASE,
+            vmcs::guest::FS_BASE,
+            vmcs::guest::GS_BASE,
+        ];
+
+        let next_rip = self.next_rip();
+        let long_mode = self.in_64bit_mode();
+        let (instruction, operand) = descriptor_table_access(
+            ldtr_tr,
+            self.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_INFO),
+            self.cache.read(vmcs::ro::EXIT_QUALIFICATION),
+        );
+        let operand = match operand {
+            // The operand size is not reported for the register operand. Assume
+            // 32 bits.
+            Operand::Register(gpr) => DescriptorTableOperand::Register { gpr, size: 4 },
+            Operand::Memory(memory) => DescriptorTableOperand::Memory(memory.linear_address(
+                &self.registers,
+                next_rip,
+                long_mode,
+                |segment| self.cache.read(SEGMENT_BASES[usize::from(segment)]),
+            )),
+        };
+        DescriptorTableInfo {
+            next_rip,
+            instruction,
+            operand: Some(operand),
+            long_mode,
+        }
+    }
+
+    /// Records the event being delivered at VM-exit, if any, to inject it on
+    /// VM-entry.
+    fn record_pending_event(&mut self) {
+        // See: 28.2.4 Information for VM Exits During Event Delivery
+        let info = self.cache.read(vmcs::ro::IDT_VECTORING_INFO) as u32;
+        if !info.get_bit(31) {
+            return;
+        }
+        let error_code = self.cache.read(vmcs::ro::IDT_VECTORING_ERR_CODE) as u32;
+        let Some(event) = Event::decode(info, error_code) else {
+            return;
+        };
+        let instruction_len = self.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_LEN);
+        if let Some((deferred, _)) = self.pending_event.replace((event, instruction_len)) {
+            log::warn!("{deferred:x?} is dropped for {event:x?}");
+        }
+    }
+
+    /// Injects the event recorded at VM-exit, or combines it with the event
+    /// injected by the handler, and ends blocking by STI and MOV SS if the
+    /// handler advanced RIP. Returns `false` if the combination causes triple
+    /// fault.
+    fn complete_event_delivery(&mut self) -> bool {
+        const BLOCKING_BY_STI_AND_MOV_SS: u64 = 0b11;
+        const DF: u8 = 8;
+
+        // See: Table 25-3. Format of Interruptibility State
+        if self.registers.rip != self.exit_rip {
+            let interruptibility = self.cache.read(vmcs::guest::INTERRUPTIBILITY_STATE);
+            if interruptibility & BLOCKING_BY_STI_AND_MOV_SS != 0 {
+                self.cache.write(
+                    vmcs::guest::INTERRUPTIBILITY_STATE,
+                    interruptibility & !BLOCKING_BY_STI_AND_MOV_SS,
+                );
+            }
+        }
+
+        let Some((pending, instruction_len)) = self.pending_event.take() else {
+            return true;
+        };
+        let injected = Event::decode(
+            self.cache
+                .read(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD) as u32,
+            self.cache.read(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE) as u32,
+        );
+        match event_injection::resolve(&pending, injected.as_ref()) {
+            Resolution::Reinject => {
+                // "the VM-entry instruction length (...) must be in the range
+                //  1–15" for software interrupts and exceptions, which is the
+                //  VM-exit instruction length.
+                // See: 29.2.4 Event Injection
+                if let Some(error_code) = pending.error_code {
+                    self.cache
+                        .write(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE, error_code);
+                }
+                if !matches!(
+                    pending.kind,
+                    EventKind::ExternalInterrupt | EventKind::Nmi | EventKind::HardwareException
+                ) {
+                    self.cache
+                        .write(vmcs::control::VMENTRY_INSTRUCTION_LEN, instruction_len);
+                }
+                self.cache.write(
+                    vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+                    pending.encode(),
+                );
+            }
+            Resolution::DoubleFault => self.inject_exception(DF, Some(0)),
+            Resolution::Defer => self.pending_event = Some((pending, instruction_len)),
+            Resolution::Discard => {}
+            Resolution::TripleFault => {
+                log::error!("{pending:x?} and {injected:x?} cause triple fault");
+                self.cache
+                    .write(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, 0u32);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the information of the instruction that caused the VM-exit.
+    fn instruction_info(&self) -> InstructionInfo {
+        InstructionInfo {
+            next_rip: self.next_rip(),
+        }
+    }
+
+    /// Logs the VM-exit with `exit_reason` that [`EXIT_HANDLERS`] does not
+    /// handle, and panics.
+    fn unhandled_exit(&self, exit_reason: u64) -> ! {
+        let basic = exit_reason.get_bits(0..=15);
+        log::error!("{:#x?}", self.vmcs);
+        log::error!(
+            "Exit qualification: {:#x}, instruction length: {}, guest-physical address: {:#x}",
+            self.cache.read(vmcs::ro::EXIT_QUALIFICATION),
+            self.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+            self.cache.read(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL),
+        );
+        // "Bit 31 is set to 1 if the VM exit occurred during VM entry"
+        // See: Table 25-18. Format of Exit Reason
+        panic!(
+            "Unhandled VM-exit reason {basic} ({}){} at {:#x}",
+            exit_reasons::name(basic),
+            if exit_reason.get_bit(31) {
+                " during VM-entry"
+            } else {
+                ""
+            },
+            self.registers.rip,
+        )
+    }
+
+    /// Enables or disables VM-exit on MOV to CR3. All writes cause VM-exit
+    /// with no CR3-target values.
+    /// See: 26.1.3 Instructions That Cause VM Exits Conditionally
+    fn set_cr3_exiting(&mut self, enable: bool) {
+        let mut controls = vmcs::control::PrimaryControls::from_bits_truncate(
+            self.cache
+                .read(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32,
+        );
+        controls.set(vmcs::control::PrimaryControls::CR3_LOAD_EXITING, enable);
+        self.cache.write(
+            vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            Self::adjust_vmx_control(VmxControl::ProcessorBased, controls.bits().into()),
+        );
+        self.cr3_exiting = enable;
+    }
+
+    /// Returns the exception that caused the VM-exit.
+    fn exception_info(&self) -> ExceptionInfo {
+        const PF: u8 = 14;
+        const TYPE_SOFTWARE_EXCEPTION: u64 = 6;
+
+        // See: Table 25-19. Format of the VM-Exit Interruption-Information Field
+        let info = self.cache.read(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
+        let vector = info.get_bits(0..=7) as u8;
+        let error_code = info
+            .get_bit(11)
+            .then(|| self.cache.read(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE) as u32);
+        // INT3 and INTO are software exceptions, and the VM-exit instruction
+        // length is saved for them.
+        let next_rip = (info.get_bits(8..=10) == TYPE_SOFTWARE_EXCEPTION).then(|| self.next_rip());
+
+        // "CR2 is not modified by VM exits due to page faults. The linear address
+        //  is saved in the exit qualification". Update CR2 as the processor would
+        //  do in case the exception is reflected.
+        // See: 28.2.1 Basic VM-Exit Information
+        if vector == PF {
+            write_cr2(self.cache.read(vmcs::ro::EXIT_QUALIFICATION));
+        }
+        ExceptionInfo {
+            vector,
+            error_code,
+            next_rip,
+        }
+    }
+
+    /// Returns the TSC multiplier for `HvConfig::tsc_ratio`, or `None` if
+    /// scaling is not configured or not supported.
+    fn tsc_multiplier() -> Option<u64> {
+        let ratio = SHARED_HOST_DATA.get().unwrap().config.tsc_ratio?;
+        let supported = SHARED_GUEST_DATA.capabilities.secondary_controls
+            & vmcs::control::SecondaryControls::USE_TSC_SCALING.bits()
+            != 0;
+        let multiplier = ratio.vmx_multiplier().filter(|_| supported);
+        if multiplier.is_none() {
+            log::warn!("TSC scaling by {ratio:?} is not supported. Ignored");
+        }
+        multiplier
+    }
+
     /// Initializes the control fields of the VMCS.
     fn initialize_control(&self) {
         // - Set HOST_ADDRESS_SPACE_SIZE to run the host on the 64bit mode.
         // - Set IA32E_MODE_GUEST to run the guest on the 64bit mode.
+        // - Set "load CET state" to switch the supervisor CET state between the
+        //   host and the guest if supported. See `cet`.
+        // - Clear IA32_RTIT_CTL on VM-exit and load the guest value on VM-entry,
+        //   and conceal VMX from PT, if supported. See `processor_trace`.
+        // - Save IA32_DEBUGCTL on VM-exit and load it on VM-entry, and do the
+        //   same for IA32_LBR_CTL if supported, so that the host does not
+        //   record branches into the LBRs of the guest. See `lbr`.
+        // - Load IA32_PERF_GLOBAL_CTRL on VM-exit and VM-entry if configured.
+        //   See `pmu`.
+        // - Save the VMX-preemption timer value on VM-exit if the watchdog is
+        //   enabled, so that the timer counts the time in the guest across
+        //   VM-exits. See `watchdog`.
+        let mut exit_controls = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE
+            | vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS)
+            .bits() as u64;
+        let mut entry_controls = (vmcs::control::EntryControls::IA32E_MODE_GUEST
+            | vmcs::control::EntryControls::LOAD_DEBUG_CONTROLS)
+            .bits() as u64;
+        if SHARED_GUEST_DATA.capabilities.arch_lbr {
+            exit_controls |= VMEXIT_CLEAR_IA32_LBR_CTL;
+            entry_controls |= VMENTRY_LOAD_IA32_LBR_CTL;
+        }
+        if isolates_pmu() {
+            exit_controls |= vmcs::control::ExitControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits() as u64;
+            entry_controls |=
+                vmcs::control::EntryControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits() as u64;
+        }
+        if SHARED_GUEST_DATA.capabilities.cet {
+            exit_controls |= VMEXIT_LOAD_CET_STATE;
+            entry_controls |= VMENTRY_LOAD_CET_STATE;
+        }
+        let watchdog_timer_value = watchdog_timer_value();
+        if watchdog_timer_value.is_some() {
+            exit_controls |= vmcs::control::ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64;
+        }
+        if SHARED_GUEST_DATA.capabilities.processor_trace {
+            exit_controls |= (vmcs::control::ExitControls::CONCEAL_VMX_FROM_PT
+                | vmcs::control::ExitControls::CLEAR_IA32_RTIT_CTL)
+                .bits() as u64;
+            entry_controls |= (vmcs::control::EntryControls::CONCEAL_VMX_FROM_PT
+                | vmcs::control::EntryControls::LOAD_IA32_RTIT_CTL)
+                .bits() as u64;
+        }
         vmwrite(
             vmcs::control::VMEXIT_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::VmExit,
-                vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as _,
-            ),
+            Self::adjust_vmx_control(VmxControl::VmExit, exit_controls),
         );
         vmwrite(
             vmcs::control::VMENTRY_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::VmEntry,
-                vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as _,
-            ),
+            Self::adjust_vmx_control(VmxControl::VmEntry, entry_controls),
         );
 
-        // Nothing to enable in the PINBASED_EXEC_CONTROLS.
+        // Enable the VMX-preemption timer for the watchdog. Nothing else to
+        // enable in the PINBASED_EXEC_CONTROLS.
+        let mut pin_based = vmcs::control::PinbasedControls::empty();
+        if let Some(value) = watchdog_timer_value {
+            pin_based |= vmcs::control::PinbasedControls::VMX_PREEMPTION_TIMER;
+            vmwrite(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE, value);
+        }
         vmwrite(
             vmcs::control::PINBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(VmxControl::PinBased, 0),
+            Self::adjust_vmx_control(VmxControl::PinBased, pin_based.bits() as _),
         );
 
         // The processor-based VM-execution controls govern the handling of
@@ -186,36 +1210,209 @@ impl VmxGuest {
         //   - Enable EPT and unrestricted guest to allow a real-mode guest, which
         //     is required for the UEFI version where a guest runs in real-mode
         //     after INIT-SIPI-SIPI.
+        //   - Intercept WBINVD, as INVD always is, so that the host writes back
+        //     the caches on behalf of the guest. See `handle_cache_invalidation`.
         //   - Let the guest executes RDTSCP, INVPCID and the XSAVE/XRSTORS family
         //     instructions. Those instructions are used in Windows 10+. If those
         //     are not set, attempt to execute them causes #UD, which results in
         //     a bug check.
+        //   - Let the guest switch EPT views with VMFUNC if there are views.
+        //   - Enable mode-based execute control for EPT, so that EPT views can
+        //     permit execution in user mode and supervisor mode separately.
+        //     See `EptPermissions`.
+        //   - Enable sub-page write permissions if configured. See `spp`.
+        //   - Conceal VMX from PT. See `processor_trace`.
+        //   - Scale the guest TSC if configured. See `tsc_scaling`.
+        //   - Intercept RDRAND and RDSEED in the deterministic time mode, as
+        //     well as RDTSC and RDTSCP with the primary controls. See
+        //     `deterministic_time`.
+        //   - Intercept the instructions accessing the descriptor-table
+        //     registers if configured. See `umip`.
+        // - HLT is intercepted if configured. See `HltPolicy`.
+        // - RDPMC is intercepted if configured. See `pmu`.
+        // - MONITOR and MWAIT are intercepted if configured. See `mwait`.
+        // - PAUSE-loop exiting is enabled if configured. See `exit_stats`.
+        //   Those are skipped if not supported, which happens when running
+        //   nested under another hypervisor. See `VmxCapabilities`.
+        let capabilities = &SHARED_GUEST_DATA.capabilities;
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let eptp_switching = capabilities.eptp_switching && !tables.views.is_empty();
+        let mut secondary_controls = vmcs::control::SecondaryControls::ENABLE_EPT
+            | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST
+            | vmcs::control::SecondaryControls::ENABLE_RDTSCP
+            | vmcs::control::SecondaryControls::ENABLE_INVPCID
+            | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS
+            | vmcs::control::SecondaryControls::MODE_BASED_EPT
+            | vmcs::control::SecondaryControls::WBINVD_EXITING;
+        if capabilities.processor_trace {
+            secondary_controls |= vmcs::control::SecondaryControls::CONCEAL_VMX_FROM_PT;
+        }
+        if eptp_switching {
+            secondary_controls |= vmcs::control::SecondaryControls::ENABLE_VM_FUNCTIONS;
+        }
+        if let Some(sppt) = &tables.sppt {
+            secondary_controls |= vmcs::control::SecondaryControls::SUB_PAGE_EPT;
+            vmwrite(
+                vmcs::control::SUBPAGE_PERM_TABLE_PTR_FULL,
+                sppt.lock().pointer().unwrap(),
+            );
+        }
+        let tsc_multiplier = Self::tsc_multiplier();
+        if tsc_multiplier.is_some() {
+            secondary_controls |= vmcs::control::SecondaryControls::USE_TSC_SCALING;
+        }
+        let random_exiting = vmcs::control::SecondaryControls::RDRAND_EXITING
+            | vmcs::control::SecondaryControls::RDSEED_EXITING;
+        let deterministic_time = deterministic_time::enabled();
+        if deterministic_time {
+            secondary_controls |= random_exiting;
+        }
+        if umip::is_enabled() {
+            secondary_controls |= vmcs::control::SecondaryControls::DTABLE_EXITING;
+        }
+        let pause_loop_exiting = SHARED_HOST_DATA.get().unwrap().config.pause_loop_exiting;
+        if pause_loop_exiting.is_some() {
+            secondary_controls |= vmcs::control::SecondaryControls::PAUSE_LOOP_EXITING;
+        }
+        let secondary_controls = secondary_controls.bits() & capabilities.secondary_controls;
+        if umip::is_enabled()
+            && secondary_controls & vmcs::control::SecondaryControls::DTABLE_EXITING.bits() == 0
+        {
+            log::warn!("Descriptor-table exiting is not supported and the UMIP policy is ignored");
+        }
+        if let Some(ple) = pause_loop_exiting {
+            if secondary_controls & vmcs::control::SecondaryControls::PAUSE_LOOP_EXITING.bits() != 0
+            {
+                // See: 25.6.13 Controls for PAUSE-Loop Exiting
+                vmwrite(vmcs::control::PLE_GAP, ple.gap);
+                vmwrite(vmcs::control::PLE_WINDOW, ple.window);
+            } else {
+                log::warn!("PAUSE-loop exiting is not supported and disabled");
+            }
+        }
+        // SMM transitions cause VM-exit only with the dual-monitor treatment,
+        // which requires an SMM monitor (STM) the hypervisor does not
+        // implement. Refuse it. See `smi`.
+        // See: 32.15 Dual-Monitor Treatment of SMIs and SMM
+        if SHARED_HOST_DATA.get().unwrap().config.smm_exiting {
+            const IA32_VMX_BASIC_DUAL_MONITOR_FLAG: u64 = 1 << 49;
+            if rdmsr(x86::msr::IA32_VMX_BASIC) & IA32_VMX_BASIC_DUAL_MONITOR_FLAG != 0 {
+                log::warn!(
+                    "SMM exiting requires the dual-monitor treatment, which is not implemented"
+                );
+            } else {
+                log::warn!(
+                    "SMM exiting requires the dual-monitor treatment, which is not supported"
+                );
+            }
+        }
+        let mut primary_controls = vmcs::control::PrimaryControls::USE_MSR_BITMAPS;
+        if tsc_multiplier.is_some() {
+            primary_controls |= vmcs::control::PrimaryControls::USE_TSC_OFFSETTING;
+        }
+        if SHARED_HOST_DATA.get().unwrap().config.hlt == HltPolicy::Intercept {
+            primary_controls |= vmcs::control::PrimaryControls::HLT_EXITING;
+        }
+        if pmu::rdpmc_exiting() {
+            primary_controls |= vmcs::control::PrimaryControls::RDPMC_EXITING;
+        }
+        if mwait::is_intercepted() {
+            primary_controls |= vmcs::control::PrimaryControls::MONITOR_EXITING
+                | vmcs::control::PrimaryControls::MWAIT_EXITING;
+        }
+        if deterministic_time {
+            primary_controls |= vmcs::control::PrimaryControls::RDTSC_EXITING;
+            if secondary_controls & random_exiting.bits() != random_exiting.bits() {
+                log::warn!("RDRAND and RDSEED cannot be intercepted and are not deterministic");
+            }
+        }
+        if !tables.views.is_empty() {
+            // MOV to CR3 is intercepted while views are bound to processes.
+            // See `set_cr3_exiting`.
+            vmwrite(vmcs::control::CR3_TARGET_COUNT, 0u32);
+        }
+        if secondary_controls != 0 {
+            primary_controls |= vmcs::control::PrimaryControls::SECONDARY_CONTROLS;
+        }
+        let mut primary_controls = primary_controls.bits() as u64;
+
+        // Keep the mitigations enabled at virtualization in IA32_SPEC_CTRL
+        // while letting the guest read back what it writes.
+        if capabilities.spec_ctrl_virtualization {
+            const PROCBASED_CTLS3_VIRTUALIZE_IA32_SPEC_CTRL: u64 = 1 << 7;
+
+            primary_controls |= PROCBASED_CTLS_ACTIVATE_TERTIARY_CONTROLS;
+            vmwrite(
+                VMCS_CONTROL_TERTIARY_PROCESSOR_BASED_VM_EXECUTION_CONTROLS,
+                Self::adjust_vmx_control(
+                    VmxControl::ProcessorBased3,
+                    PROCBASED_CTLS3_VIRTUALIZE_IA32_SPEC_CTRL,
+                ),
+            );
+            let spec_ctrl = rdmsr(speculation::IA32_SPEC_CTRL);
+            vmwrite(VMCS_CONTROL_IA32_SPEC_CTRL_MASK, spec_ctrl);
+            vmwrite(VMCS_CONTROL_IA32_SPEC_CTRL_SHADOW, spec_ctrl);
+        }
         vmwrite(
             vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::ProcessorBased,
-                (vmcs::control::PrimaryControls::USE_MSR_BITMAPS
-                    | vmcs::control::PrimaryControls::SECONDARY_CONTROLS)
-                    .bits() as _,
-            ),
-        );
-        vmwrite(
-            vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::ProcessorBased2,
-                (vmcs::control::SecondaryControls::ENABLE_EPT
-                    | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST
-                    | vmcs::control::SecondaryControls::ENABLE_RDTSCP
-                    | vmcs::control::SecondaryControls::ENABLE_INVPCID
-                    | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS)
-                    .bits() as _,
-            ),
+            Self::adjust_vmx_control(VmxControl::ProcessorBased, primary_controls),
         );
+        if secondary_controls != 0 {
+            vmwrite(
+                vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+                Self::adjust_vmx_control(VmxControl::ProcessorBased2, secondary_controls as _),
+            );
+        }
 
-        let msr_bitmaps_va = SHARED_GUEST_DATA.msr_bitmaps.as_ref() as *const _;
-        let msr_bitmaps_pa = platform_ops::get().pa(msr_bitmaps_va as *const _);
+        let msr_bitmaps_va = tables.msr_bitmaps.as_ref() as *const _;
+        let msr_bitmaps_pa = platform_ops::get().pa(msr_bitmaps_va as *const _).unwrap();
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmaps_pa);
-        vmwrite(vmcs::control::EPTP_FULL, SHARED_GUEST_DATA.epts.eptp().0);
+        if capabilities.ept {
+            vmwrite(
+                vmcs::control::EPTP_FULL,
+                tables.epts.read().eptp().unwrap().0,
+            );
+        }
+        if eptp_switching {
+            // See: 25.6.14 VM-Function Controls
+            const VM_FUNCTION_CONTROLS_EPTP_SWITCHING: u64 = 1 << 0;
+            let eptp_list_pa = platform_ops::get()
+                .pa(tables.eptp_list.as_ref() as *const _ as *const _)
+                .unwrap();
+            vmwrite(
+                vmcs::control::VM_FUNCTION_CONTROLS_FULL,
+                VM_FUNCTION_CONTROLS_EPTP_SWITCHING,
+            );
+            vmwrite(vmcs::control::EPTP_LIST_ADDR_FULL, eptp_list_pa);
+        }
+        if let Some(multiplier) = tsc_multiplier {
+            // See: 25.6.5 Time-Stamp Counter Offset and Multiplier
+            vmwrite(vmcs::control::TSC_MULTIPLIER_FULL, multiplier);
+            vmwrite(
+                vmcs::control::TSC_OFFSET_FULL,
+                tsc_scaling::offset(multiplier, tsc_scaling::VMX_FRACTION_BITS),
+            );
+        }
+
+        // Isolate the system call MSRs, which the VMCS does not have fields for,
+        // and IA32_SPEC_CTRL if it is not virtualized. The guest values are
+        // stored into the guest area on VM-exit and loaded from the same area
+        // on VM-entry. The host values are loaded from the host area on VM-exit.
+        // See: 25.7.2 VM-Exit Controls for MSRs
+        // See: 25.8.2 VM-Entry Controls for MSRs
+        let guest_area_pa = platform_ops::get()
+            .pa(addr_of!(self.msr_areas.guest) as *const _)
+            .unwrap();
+        let host_area_pa = platform_ops::get()
+            .pa(addr_of!(self.msr_areas.host) as *const _)
+            .unwrap();
+        let count = isolated_msrs().len() as u32;
+        vmwrite(vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL, guest_area_pa);
+        vmwrite(vmcs::control::VMEXIT_MSR_STORE_COUNT, count);
+        vmwrite(vmcs::control::VMEXIT_MSR_LOAD_ADDR_FULL, host_area_pa);
+        vmwrite(vmcs::control::VMEXIT_MSR_LOAD_COUNT, count);
+        vmwrite(vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL, guest_area_pa);
+        vmwrite(vmcs::control::VMENTRY_MSR_LOAD_COUNT, count);
     }
 
     /// Initializes the guest-state fields of the VMCS.
@@ -308,13 +1505,60 @@ impl VmxGuest {
         vmwrite(vmcs::guest::CR0, cr0().bits() as u64);
         vmwrite(vmcs::guest::CR3, cr3());
         vmwrite(vmcs::guest::CR4, cr4().bits() as u64);
+        // Own CR4.VMXE and hide it, consistently with CPUID and the VMX
+        // capability MSRs. See `vmx_hiding`. The guest owns the other bits, so
+        // that enabling SMEP, SMAP, PKE, UMIP and so on takes effect without
+        // VM-exit, and reads return the actual values.
+        // See: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+        vmwrite(vmcs::control::CR4_GUEST_HOST_MASK, CR4_HOST_OWNED);
+        vmwrite(
+            vmcs::control::CR4_READ_SHADOW,
+            cr4().bits() as u64 & !CR4_HOST_OWNED,
+        );
         vmwrite(vmcs::guest::RSP, self.registers.rsp);
         vmwrite(vmcs::guest::RIP, self.registers.rip);
         vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+
+        if SHARED_GUEST_DATA.capabilities.cet {
+            vmwrite(VMCS_GUEST_IA32_S_CET, rdmsr(cet::IA32_S_CET));
+            vmwrite(
+                VMCS_GUEST_IA32_INTERRUPT_SSP_TABLE_ADDR,
+                rdmsr(cet::IA32_INTERRUPT_SSP_TABLE_ADDR),
+            );
+            vmwrite(VMCS_GUEST_SSP, cet::guest_ssp(self.id));
+        }
+
+        vmwrite(
+            vmcs::guest::IA32_DEBUGCTL_FULL,
+            rdmsr(x86::msr::IA32_DEBUGCTL),
+        );
+        if SHARED_GUEST_DATA.capabilities.arch_lbr {
+            vmwrite(VMCS_GUEST_IA32_LBR_CTL, rdmsr(lbr::IA32_LBR_CTL));
+        }
+        if isolates_pmu() {
+            vmwrite(
+                vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL,
+                rdmsr(pmu::IA32_PERF_GLOBAL_CTRL),
+            );
+        }
+
+        // The guest starts with the current IA32_RTIT_CTL, or traced into the
+        // host-owned buffer. See `processor_trace`.
+        if SHARED_GUEST_DATA.capabilities.processor_trace {
+            let rtit_ctl = if processor_trace::host_owned() {
+                processor_trace::start(self.id).unwrap_or_else(|| {
+                    log::warn!("PT single-range output is not supported. Not tracing the guest");
+                    0
+                })
+            } else {
+                rdmsr(x86::msr::MSR_IA32_RTIT_CTL)
+            };
+            vmwrite(vmcs::guest::IA32_RTIT_CTL_FULL, rtit_ctl);
+        }
     }
 
     /// Initializes the host-state fields of the VMCS.
-    fn initialize_host(&self) {
+    pub(crate) fn initialize_host(&self) {
         let shared_host = SHARED_HOST_DATA.get().unwrap();
 
         // Use a custom CR3 if specified. Otherwise, use the current.
@@ -324,19 +1568,11 @@ impl VmxGuest {
             cr3()
         };
 
-        // Use a custom GDT, TR, and TSS if specified. Otherwise, use the current.
-        let (gdt_base, tr, tss_base) = if let Some(host_gdt_and_tss) = &shared_host.gdts {
-            let gdt_base = addr_of!(host_gdt_and_tss[self.id].gdt[0]) as u64;
-            let tr = host_gdt_and_tss[self.id].tr.unwrap();
-            let tss = host_gdt_and_tss[self.id].tss.as_ref().unwrap();
-            let tss_base = tss as *const _ as u64;
-            (gdt_base, tr, tss_base)
-        } else {
-            let gdtr = sgdt();
-            let tr = tr();
-            let tss_base = SegmentDescriptor::try_from_gdtr(&gdtr, tr).unwrap().base();
-            (gdtr.base as u64, tr, tss_base)
-        };
+        // Use the GDT, TR, and TSS of this processor for the host.
+        let host_gdt_and_tss = &shared_host.gdts[self.id];
+        let gdt_base = addr_of!(host_gdt_and_tss.gdt[0]) as u64;
+        let tr = host_gdt_and_tss.tr.unwrap();
+        let tss_base = core::ptr::from_ref(host_gdt_and_tss.tss.as_ref().unwrap()) as u64;
 
         // Use a custom IDT if specified. Otherwise, use the current.
         let idt_base = if let Some(host_idt) = &shared_host.idt {
@@ -367,11 +1603,24 @@ impl VmxGuest {
         vmwrite(vmcs::host::TR_BASE, tss_base);
         vmwrite(vmcs::host::GDTR_BASE, gdt_base);
         vmwrite(vmcs::host::IDTR_BASE, idt_base);
+
+        // Run the host without supervisor shadow stacks and indirect branch
+        // tracking. See `cet`.
+        if SHARED_GUEST_DATA.capabilities.cet {
+            vmwrite(VMCS_HOST_IA32_S_CET, 0u64);
+            vmwrite(VMCS_HOST_SSP, 0u64);
+            vmwrite(VMCS_HOST_IA32_INTERRUPT_SSP_TABLE_ADDR, 0u64);
+        }
+
+        // Stop the performance counters while the host runs. See `pmu`.
+        if isolates_pmu() {
+            vmwrite(vmcs::host::IA32_PERF_GLOBAL_CTRL_FULL, 0u64);
+        }
     }
 
     /// Returns the VM control value that is adjusted in consideration with the
     /// VMX capability MSR.
-    fn adjust_vmx_control(control: VmxControl, requested_value: u64) -> u64 {
+    pub(crate) fn adjust_vmx_control(control: VmxControl, requested_value: u64) -> u64 {
         const IA32_VMX_BASIC_VMX_CONTROLS_FLAG: u64 = 1 << 55;
 
         // This determines the right VMX capability MSR based on the value of
@@ -462,123 +1711,189 @@ impl VmxGuest {
         (access_rights >> 8) & 0b1111_0000_1111_1111
     }
 
+    /// Recomputes the memory types of all EPT views from the MTRRs the guest
+    /// changed, which do not apply to guest-physical accesses with EPT.
+    /// Skipped while the MTRRs are disabled, which the OS does only in the
+    /// middle of changing them.
+    // See: 13.11.8 MTRR Considerations in MP Systems
+    // See: 29.3.7.2 Memory Type Used for Translated Guest-Physical Addresses
+    fn update_memory_types(&self) {
+        if !mtrr::enabled() {
+            return;
+        }
+        let mtrr = Mtrr::new();
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let views = (0..=tables.views.len()).filter_map(|view| tables.view(view));
+        for view in views.chain(tables.hooked_epts.as_ref()) {
+            let mut epts = view.write();
+            let mut transaction = epts.transaction(&tables.shootdown);
+            transaction.set_memory_types(mtrr.clone());
+            transaction.commit().unwrap();
+        }
+    }
+
+    /// Handles instruction fetch from non-executable pages by switching EPTs
+    /// between the view with the original pages and the view with the shadow
+    /// pages. Returns `false` if the fault is not due to execute hooks. See
+    /// `hooks` for the overview.
+    fn handle_exec_hook_fault(&mut self, gpa: u64) -> bool {
+        let tables = SHARED_GUEST_DATA.tables(self.id);
+        let (Some(hooked_epts), Some(x_view)) = (&tables.hooked_epts, tables.hooked_eptp()) else {
+            return false;
+        };
+
+        // Executing a hooked page in the original view, switch to the shadow
+        // view. Executing any other page in the shadow view, switch back. The
+        // other views do not apply hooks and are left to `handle_ept_view_fault`.
+        let rw_view = tables.eptp_list.entries[DEFAULT_EPT_VIEW];
+        let eptp = self.cache.read(vmcs::control::EPTP_FULL);
+        let new_eptp = if eptp == rw_view && hooked_epts.read().is_executable(gpa) {
+            x_view
+        } else if eptp == x_view {
+            rw_view
+        } else {
+            return false;
+        };
+
+        // Cached translations are associated with the EPTP, and do not need
+        // invalidation on switching views.
+        // See: 29.4.1 Information That May Be Cached
+        self.cache.write(vmcs::control::EPTP_FULL, new_eptp);
+        true
+    }
+
     /// Handles VM-exit due to the INIT signal.
     // This function initializes the processor to the state after INIT as described
     // in the Intel SDM.
     // See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
     //      Reset, or INIT
     fn handle_init_signal(&mut self) {
-        self.registers.rflags = RFlags::FLAGS_A1.bits();
-        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+        // The processor starts in real-mode after INIT-SIPI-SIPI, which cannot
+        // be entered without unrestricted guest.
+        assert!(
+            SHARED_GUEST_DATA.capabilities.unrestricted_guest,
+            "INIT cannot be emulated without unrestricted guest"
+        );
 
-        self.registers.rip = 0xfff0;
+        let state = &ap_startup::INIT_STATE;
+        let signature = x86::cpuid::cpuid!(0x1).eax;
+        ap_startup::reset_registers(&mut self.registers, signature);
+        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
         vmwrite(vmcs::guest::RIP, self.registers.rip);
+        vmwrite(vmcs::guest::RSP, self.registers.rsp);
 
-        write_cr2(0);
-        vmwrite(vmcs::guest::CR3, 0u64);
+        write_cr2(state.cr2);
+        vmwrite(vmcs::guest::CR3, state.cr3);
         vmwrite(vmcs::control::CR0_READ_SHADOW, 0u64);
         vmwrite(vmcs::control::CR4_READ_SHADOW, 0u64);
 
         // Actual guest CR0 and CR4 must fulfill requirements for VMX. Apply those.
+        let cr0 = ap_startup::init_cr0(vmread(vmcs::guest::CR0));
         vmwrite(
             vmcs::guest::CR0,
-            get_adjusted_guest_cr0(Cr0::CR0_EXTENSION_TYPE).bits() as u64,
+            get_adjusted_guest_cr0(Cr0::from_bits_truncate(cr0 as usize)).bits() as u64,
         );
         vmwrite(
             vmcs::guest::CR4,
-            get_adjusted_guest_cr4(Cr4::empty()).bits() as u64,
+            get_adjusted_guest_cr4(Cr4::from_bits_truncate(state.cr4 as usize)).bits() as u64,
         );
 
-        let mut access_rights = VmxSegmentAccessRights(0);
-        access_rights.set_segment_type(CodeSegmentType::ExecuteReadAccessed as u32);
-        access_rights.set_descriptor_type(true);
-        access_rights.set_present(true);
-
-        vmwrite(vmcs::guest::CS_SELECTOR, 0xf000u64);
-        vmwrite(vmcs::guest::CS_BASE, 0xffff_0000u64);
-        vmwrite(vmcs::guest::CS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, access_rights.0);
-
-        access_rights.set_segment_type(DataSegmentType::ReadWriteAccessed as u32);
-        vmwrite(vmcs::guest::SS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::SS_BASE, 0u64);
-        vmwrite(vmcs::guest::SS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::DS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::DS_BASE, 0u64);
-        vmwrite(vmcs::guest::DS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::DS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::ES_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::ES_BASE, 0u64);
-        vmwrite(vmcs::guest::ES_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::ES_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::FS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::FS_BASE, 0u64);
-        vmwrite(vmcs::guest::FS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::FS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::GS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::GS_BASE, 0u64);
-        vmwrite(vmcs::guest::GS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::GS_ACCESS_RIGHTS, access_rights.0);
-
-        let extended_model_id = x86::cpuid::CpuId::new()
-            .get_feature_info()
-            .unwrap()
-            .extended_model_id();
-        self.registers.rdx = 0x600 | ((extended_model_id as u64) << 16);
-        self.registers.rax = 0x0;
-        self.registers.rbx = 0x0;
-        self.registers.rcx = 0x0;
-        self.registers.rsi = 0x0;
-        self.registers.rdi = 0x0;
-        self.registers.rbp = 0x0;
-
-        self.registers.rsp = 0x0;
-        vmwrite(vmcs::guest::RSP, self.registers.rsp);
-
-        vmwrite(vmcs::guest::GDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::GDTR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::IDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::IDTR_LIMIT, 0xffffu64);
-
-        access_rights.set_segment_type(SystemDescriptorTypes64::LDT as u32);
-        access_rights.set_descriptor_type(false);
-        vmwrite(vmcs::guest::LDTR_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::LDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::LDTR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, access_rights.0);
+        write_segment(
+            [
+                vmcs::guest::CS_SELECTOR,
+                vmcs::guest::CS_BASE,
+                vmcs::guest::CS_LIMIT,
+                vmcs::guest::CS_ACCESS_RIGHTS,
+            ],
+            &state.cs,
+        );
+        write_segment(
+            [
+                vmcs::guest::SS_SELECTOR,
+                vmcs::guest::SS_BASE,
+                vmcs::guest::SS_LIMIT,
+                vmcs::guest::SS_ACCESS_RIGHTS,
+            ],
+            &state.data,
+        );
+        write_segment(
+            [
+                vmcs::guest::DS_SELECTOR,
+                vmcs::guest::DS_BASE,
+                vmcs::guest::DS_LIMIT,
+                vmcs::guest::DS_ACCESS_RIGHTS,
+            ],
+            &state.data,
+        );
+        write_segment(
+            [
+                vmcs::guest::ES_SELECTOR,
+                vmcs::guest::ES_BASE,
+                vmcs::guest::ES_LIMIT,
+                vmcs::guest::ES_ACCESS_RIGHTS,
+            ],
+            &state.data,
+        );
+        write_segment(
+            [
+                vmcs::guest::FS_SELECTOR,
+                vmcs::guest::FS_BASE,
+                vmcs::guest::FS_LIMIT,
+                vmcs::guest::FS_ACCESS_RIGHTS,
+            ],
+            &state.data,
+        );
+        write_segment(
+            [
+                vmcs::guest::GS_SELECTOR,
+                vmcs::guest::GS_BASE,
+                vmcs::guest::GS_LIMIT,
+                vmcs::guest::GS_ACCESS_RIGHTS,
+            ],
+            &state.data,
+        );
+        write_segment(
+            [
+                vmcs::guest::LDTR_SELECTOR,
+                vmcs::guest::LDTR_BASE,
+                vmcs::guest::LDTR_LIMIT,
+                vmcs::guest::LDTR_ACCESS_RIGHTS,
+            ],
+            &state.ldtr,
+        );
+        write_segment(
+            [
+                vmcs::guest::TR_SELECTOR,
+                vmcs::guest::TR_BASE,
+                vmcs::guest::TR_LIMIT,
+                vmcs::guest::TR_ACCESS_RIGHTS,
+            ],
+            &state.tr,
+        );
 
-        access_rights.set_segment_type(SystemDescriptorTypes64::TssBusy as u32);
-        vmwrite(vmcs::guest::TR_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::TR_BASE, 0u64);
-        vmwrite(vmcs::guest::TR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, access_rights.0);
+        vmwrite(vmcs::guest::GDTR_BASE, state.gdtr_base);
+        vmwrite(vmcs::guest::GDTR_LIMIT, state.gdtr_limit);
+        vmwrite(vmcs::guest::IDTR_BASE, state.idtr_base);
+        vmwrite(vmcs::guest::IDTR_LIMIT, state.idtr_limit);
 
         unsafe {
             dr0_write(0);
             dr1_write(0);
             dr2_write(0);
             dr3_write(0);
-            dr6_write(Dr6::from_bits_unchecked(0xffff0ff0));
-            dr7_write(Dr7(0x400));
+            dr6_write(Dr6::from_bits_unchecked(state.dr6 as usize));
+            dr7_write(Dr7(state.dr7 as usize));
         };
 
-        self.registers.r8 = 0;
-        self.registers.r9 = 0;
-        self.registers.r10 = 0;
-        self.registers.r11 = 0;
-        self.registers.r12 = 0;
-        self.registers.r13 = 0;
-        self.registers.r14 = 0;
-        self.registers.r15 = 0;
+        vmwrite(vmcs::guest::IA32_EFER_FULL, state.efer);
 
-        vmwrite(vmcs::guest::IA32_EFER_FULL, 0u64);
-        vmwrite(vmcs::guest::FS_BASE, 0u64);
-        vmwrite(vmcs::guest::GS_BASE, 0u64);
+        // INIT clears the CET state, which is otherwise loaded as is on the
+        // next VM-entry.
+        if SHARED_GUEST_DATA.capabilities.cet {
+            vmwrite(VMCS_GUEST_IA32_S_CET, 0u64);
+            vmwrite(VMCS_GUEST_SSP, 0u64);
+            vmwrite(VMCS_GUEST_IA32_INTERRUPT_SSP_TABLE_ADDR, 0u64);
+        }
 
         let mut vmentry_controls = vmread(vmcs::control::VMENTRY_CONTROLS);
         vmentry_controls &= !(vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64);
@@ -600,6 +1915,9 @@ impl VmxGuest {
             vmcs::guest::ACTIVITY_STATE,
             GuestActivityState::WaitForSipi as u32,
         );
+
+        // The fields were written directly.
+        self.cache.invalidate();
     }
 
     /// Handles VM-exit due to the Startup-IPI (SIPI) signal.
@@ -608,16 +1926,23 @@ impl VmxGuest {
         //  vector information in bits 7:0. Bits 63:8 of the exit qualification are
         //  cleared to 0."
         // See: 27.2.1 Basic VM-Exit Information
-        let vector = vmread(vmcs::ro::EXIT_QUALIFICATION);
+        let vector = self.cache.read(vmcs::ro::EXIT_QUALIFICATION);
 
         // "At the end of the boot-strap procedure, the BSP sets ... broadcasts a
         //  SIPI message to all the APs in the system. Here, the SIPI message contains
         //  a vector to the BIOS AP initialization code (at 000VV000H, where VV is the
         //  vector contained in the SIPI message)."
         // See: 8.4.3 MP Initialization Protocol Algorithm for MP Systems
-        vmwrite(vmcs::guest::CS_SELECTOR, vector << 8);
-        vmwrite(vmcs::guest::CS_BASE, vector << 12);
-        self.registers.rip = 0;
+        write_segment(
+            [
+                vmcs::guest::CS_SELECTOR,
+                vmcs::guest::CS_BASE,
+                vmcs::guest::CS_LIMIT,
+                vmcs::guest::CS_ACCESS_RIGHTS,
+            ],
+            &ap_startup::sipi_cs(vector as u8),
+        );
+        self.registers.rip = ap_startup::SIPI_RIP;
         vmwrite(vmcs::guest::RIP, self.registers.rip);
 
         // Done. Note that the 2nd SIPI will be ignored if that occurs after this.
@@ -628,34 +1953,542 @@ impl VmxGuest {
             vmcs::guest::ACTIVITY_STATE,
             GuestActivityState::Active as u32,
         );
+        self.cache.invalidate();
+    }
+}
+
+/// The functions that translate VM-exits into [`VmExitReason`], indexed by the
+/// basic exit reasons. See [`DispatchTable`].
+static EXIT_HANDLERS: DispatchTable<VmxGuest, { exit_reasons::COUNT }> =
+    DispatchTable::<VmxGuest, { exit_reasons::COUNT }>::new()
+        .with(VMX_EXIT_REASON_INIT, |guest| {
+            guest.handle_init_signal();
+            VmExitReason::InitSignal
+        })
+        .with(VMX_EXIT_REASON_SIPI, |guest| {
+            guest.handle_sipi_signal();
+            VmExitReason::StartupIpi
+        })
+        .with(VMX_EXIT_REASON_CPUID, |guest| {
+            VmExitReason::Cpuid(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_RDMSR, |guest| {
+            VmExitReason::Rdmsr(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_WRMSR, |guest| {
+            VmExitReason::Wrmsr(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_XSETBV, |guest| {
+            VmExitReason::XSetBv(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_HLT, |guest| {
+            VmExitReason::Hlt(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_VMCALL, |guest| {
+            VmExitReason::Hypercall(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_INVD, |guest| {
+            VmExitReason::CacheInvalidation(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_WBINVD, |guest| {
+            VmExitReason::CacheInvalidation(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_MONITOR, |guest| {
+            VmExitReason::Monitor(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_MWAIT, |guest| {
+            VmExitReason::Mwait(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_RDPMC, |guest| {
+            VmExitReason::Rdpmc(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_RDTSC, |guest| {
+            VmExitReason::Rdtsc(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_RDTSCP, |guest| {
+            VmExitReason::Rdtscp(guest.instruction_info())
+        })
+        .with(VMX_EXIT_REASON_RDRAND, |guest| {
+            VmExitReason::Rdrand(random_info(
+                guest.next_rip(),
+                false,
+                guest.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_INFO),
+            ))
+        })
+        .with(VMX_EXIT_REASON_RDSEED, |guest| {
+            VmExitReason::Rdrand(random_info(
+                guest.next_rip(),
+                true,
+                guest.cache.read(vmcs::ro::VMEXIT_INSTRUCTION_INFO),
+            ))
+        })
+        .with(VMX_EXIT_REASON_EXCEPTION_OR_NMI, |guest| {
+            VmExitReason::Exception(guest.exception_info())
+        })
+        .with(VMX_EXIT_REASON_GDTR_IDTR_ACCESS, |guest| {
+            VmExitReason::DescriptorTableAccess(guest.descriptor_table_info(false))
+        })
+        .with(VMX_EXIT_REASON_LDTR_TR_ACCESS, |guest| {
+            VmExitReason::DescriptorTableAccess(guest.descriptor_table_info(true))
+        })
+        .with(VMX_EXIT_REASON_CR_ACCESS, |guest| {
+            VmExitReason::CrAccess(cr_access_info(
+                guest.next_rip(),
+                guest.cache.read(vmcs::ro::EXIT_QUALIFICATION),
+            ))
+        })
+        .with(VMX_EXIT_REASON_IO_INSTRUCTION, |guest| {
+            VmExitReason::IoInstruction(io_info(
+                guest.next_rip(),
+                guest.cache.read(vmcs::ro::EXIT_QUALIFICATION),
+            ))
+        })
+        .with(VMX_EXIT_REASON_EPT_VIOLATION, |guest| {
+            let info = ept_violation_info(
+                guest.cache.read(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL),
+                guest.cache.read(vmcs::ro::EXIT_QUALIFICATION),
+            );
+            if info.execute {
+                let _ = guest.handle_exec_hook_fault(info.gpa);
+            }
+            VmExitReason::NestedPageFault(info)
+        })
+        .with(VMX_EXIT_REASON_TRIPLE_FAULT, |_| VmExitReason::Shutdown)
+        .with(VMX_EXIT_REASON_MONITOR_TRAP_FLAG, |_| {
+            VmExitReason::MonitorTrap
+        })
+        .with(VMX_EXIT_REASON_PAUSE, |_| VmExitReason::Pause)
+        .with(VMX_EXIT_REASON_VMX_PREEMPTION_TIMER_EXPIRED, |_| {
+            // The saved value is 0. Restart the timer for the next check.
+            if let Some(value) = watchdog_timer_value() {
+                vmwrite(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE, value);
+            }
+            VmExitReason::PreemptionTimer
+        })
+        // "A machine-check event occurred during VM entry". Handle it as #MC in the
+        // guest. See `machine_check`.
+        // See: Table C-1. Basic Exit Reasons
+        .with(VMX_EXIT_REASON_MCE_DURING_VMENTRY, |_| {
+            VmExitReason::Exception(ExceptionInfo {
+                vector: machine_check::MC,
+                error_code: None,
+                next_rip: None,
+            })
+        })
+        // VMFUNC with an invalid view index. Let it fail as it would without the
+        // hypervisor.
+        .with(VMX_EXIT_REASON_VMFUNC, |_| {
+            VmExitReason::Exception(ExceptionInfo {
+                vector: 6,
+                error_code: None,
+                next_rip: None,
+            })
+        });
+
+/// Writes `segment` to the guest selector, base, limit and access rights
+/// `fields`.
+fn write_segment(fields: [u32; 4], segment: &ap_startup::SegmentState) {
+    let [selector, base, limit, access_rights] = fields;
+    vmwrite(selector, segment.selector);
+    vmwrite(base, segment.base);
+    vmwrite(limit, segment.limit);
+    vmwrite(access_rights, segment.attributes);
+}
+
+/// Returns the VMX-preemption timer value for the interval of the watchdog
+/// checks, half the timeout, if the watchdog is enabled and the processor
+/// supports the timer.
+fn watchdog_timer_value() -> Option<u32> {
+    let timeout = watchdog::timeout()?;
+    // "Bits 63:32 indicate the allowed 1-settings of these controls."
+    // See: A.3.1 Pin-Based VM-Execution Controls
+    if !rdmsr(x86::msr::IA32_VMX_PINBASED_CTLS).get_bit(32 + 6) {
+        return None;
+    }
+    // "The VMX-preemption timer counts down at rate proportional to that of
+    //  the timestamp counter (TSC). Specifically, the timer counts down by 1
+    //  every time bit X in the TSC changes due to a TSC increment. The value of
+    //  X is in the range 0–31 and can be determined by consulting the VMX
+    //  capability MSR IA32_VMX_MISC"
+    // See: 26.5.1 VMX-Preemption Timer
+    let rate = rdmsr(x86::msr::IA32_VMX_MISC).get_bits(0..=4);
+    Some(
+        u32::try_from((timeout / 2) >> rate)
+            .unwrap_or(u32::MAX)
+            .max(1),
+    )
+}
+
+/// The CR4 bits owned by the hypervisor. MOV to CR4 causes VM-exit only when
+/// it sets any of them.
+const CR4_HOST_OWNED: u64 = Cr4::CR4_ENABLE_VMX.bits() as u64;
+
+/// The MSRs that may be switched between the guest and the host on VM-entry
+/// and VM-exit. IA32_SPEC_CTRL, the last one, is switched only if it exists
+/// and is not virtualized. See [`isolated_msrs`].
+const ISOLATED_MSRS: [u32; 6] = [
+    x86::msr::IA32_STAR,
+    x86::msr::IA32_LSTAR,
+    x86::msr::IA32_CSTAR,
+    x86::msr::IA32_FMASK,
+    x86::msr::IA32_KERNEL_GSBASE,
+    speculation::IA32_SPEC_CTRL,
+];
+
+/// Returns the MSRs that are switched between the guest and the host on
+/// VM-entry and VM-exit.
+fn isolated_msrs() -> &'static [u32] {
+    if speculation::is_spec_ctrl_supported()
+        && !SHARED_GUEST_DATA.capabilities.spec_ctrl_virtualization
+    {
+        &ISOLATED_MSRS
+    } else {
+        &ISOLATED_MSRS[..ISOLATED_MSRS.len() - 1]
+    }
+}
+
+/// An entry of the MSR-store and MSR-load areas.
+///
+/// See: Table 25-15. Format of an MSR Entry
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct MsrEntry {
+    index: u32,
+    reserved: u32,
+    data: u64,
+}
+
+/// The MSR-store and MSR-load areas of a guest. Each area must be 16-byte
+/// aligned.
+#[derive(Debug)]
+#[repr(C, align(4096))]
+struct MsrAreas {
+    guest: [MsrEntry; ISOLATED_MSRS.len()],
+    host: [MsrEntry; ISOLATED_MSRS.len()],
+}
+
+impl MsrAreas {
+    /// Fills both areas with the current MSR values. The guest starts with the
+    /// same values as the host.
+    fn initialize(&mut self) {
+        for (i, &index) in isolated_msrs().iter().enumerate() {
+            let entry = MsrEntry {
+                index,
+                reserved: 0,
+                data: rdmsr(index),
+            };
+            self.guest[i] = entry;
+            self.host[i] = entry;
+        }
+    }
+}
+
+/// The guest state saved with `Guest::save_state`.
+struct SavedState {
+    /// The registers including RIP, RSP and RFLAGS.
+    registers: Registers,
+    /// The values of the fields in [`GUEST_STATE_FIELDS`] the processor supports.
+    fields: Vec<(u32, u64)>,
+    cr0_read_shadow: u64,
+    cr4_read_shadow: u64,
+    msrs: [MsrEntry; ISOLATED_MSRS.len()],
+}
+
+/// The guest-state fields of the VMCS saved with the guest state, except RIP,
+/// RSP and RFLAGS, which are in `Registers`, and the fields the host manages.
+///
+/// See: 25.4 GUEST-STATE AREA
+const GUEST_STATE_FIELDS: &[u32] = &[
+    vmcs::guest::ES_SELECTOR,
+    vmcs::guest::CS_SELECTOR,
+    vmcs::guest::SS_SELECTOR,
+    vmcs::guest::DS_SELECTOR,
+    vmcs::guest::FS_SELECTOR,
+    vmcs::guest::GS_SELECTOR,
+    vmcs::guest::LDTR_SELECTOR,
+    vmcs::guest::TR_SELECTOR,
+    vmcs::guest::IA32_DEBUGCTL_FULL,
+    vmcs::guest::IA32_PAT_FULL,
+    vmcs::guest::IA32_EFER_FULL,
+    vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL,
+    vmcs::guest::PDPTE0_FULL,
+    vmcs::guest::PDPTE1_FULL,
+    vmcs::guest::PDPTE2_FULL,
+    vmcs::guest::PDPTE3_FULL,
+    vmcs::guest::IA32_BNDCFGS_FULL,
+    vmcs::guest::IA32_RTIT_CTL_FULL,
+    VMCS_GUEST_IA32_LBR_CTL,
+    VMCS_GUEST_IA32_PKRS,
+    vmcs::guest::ES_LIMIT,
+    vmcs::guest::CS_LIMIT,
+    vmcs::guest::SS_LIMIT,
+    vmcs::guest::DS_LIMIT,
+    vmcs::guest::FS_LIMIT,
+    vmcs::guest::GS_LIMIT,
+    vmcs::guest::LDTR_LIMIT,
+    vmcs::guest::TR_LIMIT,
+    vmcs::guest::GDTR_LIMIT,
+    vmcs::guest::IDTR_LIMIT,
+    vmcs::guest::ES_ACCESS_RIGHTS,
+    vmcs::guest::CS_ACCESS_RIGHTS,
+    vmcs::guest::SS_ACCESS_RIGHTS,
+    vmcs::guest::DS_ACCESS_RIGHTS,
+    vmcs::guest::FS_ACCESS_RIGHTS,
+    vmcs::guest::GS_ACCESS_RIGHTS,
+    vmcs::guest::LDTR_ACCESS_RIGHTS,
+    vmcs::guest::TR_ACCESS_RIGHTS,
+    vmcs::guest::INTERRUPTIBILITY_STATE,
+    vmcs::guest::ACTIVITY_STATE,
+    vmcs::guest::IA32_SYSENTER_CS,
+    vmcs::guest::CR0,
+    vmcs::guest::CR3,
+    vmcs::guest::CR4,
+    vmcs::guest::ES_BASE,
+    vmcs::guest::CS_BASE,
+    vmcs::guest::SS_BASE,
+    vmcs::guest::DS_BASE,
+    vmcs::guest::FS_BASE,
+    vmcs::guest::GS_BASE,
+    vmcs::guest::LDTR_BASE,
+    vmcs::guest::TR_BASE,
+    vmcs::guest::GDTR_BASE,
+    vmcs::guest::IDTR_BASE,
+    vmcs::guest::DR7,
+    vmcs::guest::PENDING_DBG_EXCEPTIONS,
+    vmcs::guest::IA32_SYSENTER_ESP,
+    vmcs::guest::IA32_SYSENTER_EIP,
+    VMCS_GUEST_IA32_S_CET,
+    VMCS_GUEST_SSP,
+    VMCS_GUEST_IA32_INTERRUPT_SSP_TABLE_ADDR,
+];
+
+/// The MSR bitmaps, where each MSR is represented by a bit in the read bitmap
+/// and a bit in the write bitmap, to indicate whether RDMSR and WRMSR cause
+/// VM-exit, respectively.
+///
+/// See: 25.6.9 MSR-Bitmap Address
+#[derive(Debug)]
+#[repr(C, align(4096))]
+struct MsrBitmaps([u8; BASE_PAGE_SIZE]);
+const _: () = assert!(core::mem::size_of::<MsrBitmaps>() == BASE_PAGE_SIZE);
+
+impl MsrBitmaps {
+    /// Sets up the bitmaps to cause VM-exit on RDMSR of `msr`.
+    fn intercept_read(&mut self, msr: u32) {
+        self.set(0x0, msr);
+    }
+
+    /// Sets up the bitmaps to cause VM-exit on WRMSR of `msr`.
+    fn intercept_write(&mut self, msr: u32) {
+        self.set(0x800, msr);
+    }
+
+    fn set(&mut self, bitmap_offset: usize, msr: u32) {
+        let (base_offset, base_msr) = match msr {
+            0x0000_0000..=0x0000_1fff => (bitmap_offset, 0x0000_0000),
+            0xc000_0000..=0xc000_1fff => (bitmap_offset + 0x400, 0xc000_0000),
+            _ => panic!("{msr:#x?} is outside the MSR bitmaps"),
+        };
+        let bit_position = (msr - base_msr) as usize;
+        let byte = &mut self.0[base_offset + bit_position / 8];
+        let _ = byte.set_bit(bit_position % 8, true);
+    }
+
+    /// Returns `true` if no MSR access causes VM-exit.
+    fn is_zeroed(&self) -> bool {
+        self.0.iter().all(|&byte| byte == 0)
+    }
+}
+
+/// The MSR bitmaps and EPTs used by a guest. Either shared by all guests or
+/// owned by each of them. See `HvConfig::per_core_guest_tables`.
+struct GuestTables {
+    msr_bitmaps: Box<MsrBitmaps>,
+    /// The EPTs of the default view.
+    epts: RwLock<Epts>,
+    /// The snapshot of the memory mapped by `epts`. Lock this before `epts`.
+    snapshot: Mutex<Snapshot>,
+    /// The EPTs of the views other than the default one. See `HvConfig::ept_views`.
+    views: Vec<RwLock<Epts>>,
+    /// The EPTPs of all views, indexed by the view index.
+    eptp_list: Box<EptpList>,
+    /// The views bound to processes.
+    cr3_bindings: RwLock<Cr3Bindings>,
+    /// Whether any view is bound to a process, and thus, MOV to CR3 must cause
+    /// VM-exit to switch views.
+    cr3_exiting: AtomicBool,
+    /// The EPTs of the view where the guest executes the shadow pages of
+    /// execute hooks. `None` if no hooks are registered. See `hooks`.
+    hooked_epts: Option<RwLock<Epts>>,
+    /// The shootdown of translations cached from `epts`, `views` and
+    /// `hooked_epts`.
+    shootdown: Shootdown,
+    /// The sub-page write permissions of pages protected in any view. `None`
+    /// if SPP is not enabled. See `HvConfig::sub_page_permissions`.
+    sppt: Option<Mutex<SubPagePermissionTable>>,
+}
+
+impl GuestTables {
+    fn new(capabilities: &VmxCapabilities) -> Result<Self, PaError> {
+        let config = &SHARED_HOST_DATA.get().unwrap().config;
+        let mut epts = Epts::new();
+        epts.build_identity()?;
+        epts.reserve_split_pts(config.split_pt_pool);
+
+        let mut views = Vec::new();
+        for _ in 0..config.ept_views.min(MAX_EPT_VIEWS - 1) {
+            let mut view = Epts::new();
+            view.build_identity()?;
+            view.reserve_split_pts(config.split_pt_pool);
+            views.push(RwLock::new(view));
+        }
+
+        let exec_hooks = hooks::exec_hooks();
+        let hooked_epts = if exec_hooks.is_empty() || !capabilities.ept {
+            None
+        } else {
+            let mut hooked_epts = Epts::new();
+            hooked_epts.build_identity()?;
+            hooked_epts.reserve_split_pts(config.split_pt_pool);
+            hooked_epts.apply_exec_hooks_x_view(&exec_hooks)?;
+            epts.apply_exec_hooks_rw_view(&exec_hooks)?;
+            Some(RwLock::new(hooked_epts))
+        };
+        let mut msr_bitmaps = zeroed_box::<MsrBitmaps>();
+        if config.deterministic_time.is_some() {
+            for msr in deterministic_time::INTERCEPTED_READS {
+                msr_bitmaps.intercept_read(msr);
+            }
+            for msr in deterministic_time::INTERCEPTED_WRITES {
+                msr_bitmaps.intercept_write(msr);
+            }
+        }
+        if pmu::isolated() && capabilities.perf_global_ctrl {
+            msr_bitmaps.intercept_write(pmu::IA32_PERF_GLOBAL_CTRL);
+        }
+        for msr in microcode::INTERCEPTED_WRITES {
+            msr_bitmaps.intercept_write(msr);
+        }
+        if capabilities.ept {
+            for msr in mtrr::intercepted_writes() {
+                msr_bitmaps.intercept_write(msr);
+            }
+        }
+        if msr_monitor::enabled() {
+            for msr in msr_monitor::INTERCEPTED_WRITES {
+                msr_bitmaps.intercept_write(msr);
+            }
+        }
+        for msr in vmx_hiding::intercepted_msrs() {
+            msr_bitmaps.intercept_read(msr);
+            msr_bitmaps.intercept_write(msr);
+        }
+        if processor_trace::host_owned() {
+            for msr in processor_trace::INTERCEPTED_MSRS {
+                msr_bitmaps.intercept_read(msr);
+                msr_bitmaps.intercept_write(msr);
+            }
+        }
+
+        let sppt = if config.sub_page_permissions && capabilities.spp {
+            Some(Mutex::new(SubPagePermissionTable::new()?))
+        } else {
+            if config.sub_page_permissions {
+                log::warn!("Sub-page write permissions are not supported and disabled");
+            }
+            None
+        };
+
+        let mut eptp_list = zeroed_box::<EptpList>();
+        eptp_list.entries[DEFAULT_EPT_VIEW] = epts.eptp()?.0;
+        for (entry, view) in eptp_list.entries[1..].iter_mut().zip(&views) {
+            *entry = view.read().eptp()?.0;
+        }
+
+        Ok(Self {
+            msr_bitmaps,
+            epts: RwLock::new(epts),
+            snapshot: Mutex::new(Snapshot::new(config.snapshot_pool_pages)),
+            views,
+            eptp_list,
+            cr3_bindings: RwLock::new(Cr3Bindings::default()),
+            cr3_exiting: AtomicBool::new(false),
+            hooked_epts,
+            shootdown: Shootdown::default(),
+            sppt,
+        })
+    }
+
+    /// Invalidates the cached translations of all views on the current
+    /// processor.
+    fn invalidate(&self) {
+        for &eptp in &self.eptp_list.entries[..=self.views.len()] {
+            invept_single_context(eptp);
+        }
+        if let Some(eptp) = self.hooked_eptp() {
+            invept_single_context(eptp);
+        }
+    }
+
+    /// Returns the EPTP of `hooked_epts`, if exists.
+    fn hooked_eptp(&self) -> Option<u64> {
+        self.hooked_epts
+            .as_ref()
+            .map(|epts| epts.read().eptp().unwrap().0)
+    }
+
+    /// Returns the EPTs of the view `view`, if exists.
+    fn view(&self, view: usize) -> Option<&RwLock<Epts>> {
+        match view {
+            DEFAULT_EPT_VIEW => Some(&self.epts),
+            _ => self.views.get(view - 1),
+        }
     }
 }
 
 struct SharedGuestData {
-    msr_bitmaps: Box<Page>,
-    epts: Box<Epts>,
+    /// The tables shared by all processors. `None` if each processor has its own.
+    shared_tables: Option<GuestTables>,
+    /// The tables of each processor, created on first use so that processors
+    /// that come online later get theirs too.
+    per_core_tables: PerCpu<Once<GuestTables>>,
+    /// The optional VMX features supported by the processor.
+    capabilities: VmxCapabilities,
 }
 
-static SHARED_GUEST_DATA: Lazy<SharedGuestData> = Lazy::new(|| {
-    let mut epts = zeroed_box::<Epts>();
-    epts.build_identity();
+impl SharedGuestData {
+    fn new() -> Self {
+        let per_core = SHARED_HOST_DATA.get().unwrap().config.per_core_guest_tables;
+        let capabilities = VmxCapabilities::probe();
+        Self {
+            shared_tables: (!per_core).then(|| GuestTables::new(&capabilities).unwrap()),
+            per_core_tables: PerCpu::new([const { Once::new() }; MAX_PROCESSORS]),
+            capabilities,
+        }
+    }
 
-    SharedGuestData {
-        msr_bitmaps: zeroed_box::<Page>(),
-        epts,
+    /// Returns the tables used by the guest on the processor.
+    fn tables(&self, processor_id: usize) -> &GuestTables {
+        match &self.shared_tables {
+            Some(tables) => tables,
+            None => self.per_core_tables[processor_id]
+                .call_once(|| GuestTables::new(&self.capabilities).unwrap()),
+        }
     }
-});
+}
+
+static SHARED_GUEST_DATA: Lazy<SharedGuestData> = Lazy::new(SharedGuestData::new);
 
 unsafe extern "C" {
-    /// Runs the guest until VM-exit occurs.
-    unsafe fn run_vmx_guest(registers: &mut Registers) -> u64;
+    /// Runs the guest until VM-exit occurs. `launched` is non-zero if the
+    /// current VMCS is launched.
+    unsafe fn run_vmx_guest(registers: &mut Registers, launched: u64) -> u64;
 }
 global_asm!(include_str!("../capture_registers.inc"));
 global_asm!(include_str!("run_guest.S"));
 
-#[expect(dead_code)]
 #[derive(Clone, Copy, Debug)]
-enum VmxControl {
+pub(crate) enum VmxControl {
     PinBased,
     ProcessorBased,
     ProcessorBased2,
@@ -668,7 +2501,7 @@ enum VmxControl {
 #[expect(dead_code)]
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum GuestActivityState {
+pub(crate) enum GuestActivityState {
     /// The logical processor is executing instructions normally.
     Active = 0,
 
@@ -683,9 +2516,106 @@ enum GuestActivityState {
     WaitForSipi = 3,
 }
 
+/// Decodes the exit qualification of VM-exit due to control-register access.
+///
+/// See: Table 28-3. Exit Qualification for Control-Register Accesses
+pub(crate) fn cr_access_info(next_rip: u64, qualification: u64) -> CrAccessInfo {
+    const MOV_TO_CR: u64 = 0;
+
+    let qualification = CrAccessQualification(qualification);
+    CrAccessInfo {
+        next_rip,
+        cr: qualification.cr() as u8,
+        write: qualification.access_type() == MOV_TO_CR,
+        gpr: Some(qualification.gpr() as u8),
+    }
+}
+
+/// Decodes the exit qualification of VM-exit due to an I/O instruction.
+///
+/// See: Table 28-5. Exit Qualification for I/O Instructions
+pub(crate) fn io_info(next_rip: u64, qualification: u64) -> IoInfo {
+    let qualification = IoQualification(qualification);
+    IoInfo {
+        next_rip,
+        port: qualification.port() as u16,
+        size: qualification.size() as u8 + 1,
+        input: qualification.input(),
+        string: qualification.string(),
+        rep: qualification.rep(),
+    }
+}
+
+/// Decodes the VM-exit instruction information of VM-exit due to RDRAND, or
+/// RDSEED if `seed` is `true`.
+///
+/// See: 28.2.5 Information for VM Exits Due to Instruction Execution
+pub(crate) fn random_info(next_rip: u64, seed: bool, instruction_info: u64) -> RandomInfo {
+    let instruction_info = InstructionInformation(instruction_info);
+    RandomInfo {
+        next_rip,
+        seed,
+        gpr: instruction_info.reg1() as u8,
+        size: 2 << instruction_info.operand_size(),
+    }
+}
+
+/// Decodes the VM-exit instruction information and the exit qualification of
+/// VM-exit due to access to LDTR or TR if `ldtr_tr` is `true`, or GDTR or IDTR
+/// otherwise. The memory operand has no segment base applied, and the
+/// qualification is the displacement, or the offset itself with RIP-relative
+/// addressing.
+///
+/// See: Table 28-1. Exit Qualification for Various VM Exits
+/// See: 28.2.5 Information for VM Exits Due to Instruction Execution
+pub(crate) fn descriptor_table_access(
+    ldtr_tr: bool,
+    instruction_info: u64,
+    qualification: u64,
+) -> (DescriptorTableInstruction, Operand) {
+    let info = InstructionInformation(instruction_info);
+    let instruction = match (ldtr_tr, info.instruction_identity()) {
+        (false, 0) => DescriptorTableInstruction::Sgdt,
+        (false, 1) => DescriptorTableInstruction::Sidt,
+        (false, 2) => DescriptorTableInstruction::Lgdt,
+        (false, _) => DescriptorTableInstruction::Lidt,
+        (true, 0) => DescriptorTableInstruction::Sldt,
+        (true, 1) => DescriptorTableInstruction::Str,
+        (true, 2) => DescriptorTableInstruction::Lldt,
+        (true, _) => DescriptorTableInstruction::Ltr,
+    };
+    // Only LLDT, LTR, SLDT and STR may have a register operand.
+    if ldtr_tr && info.register_operand() {
+        return (instruction, Operand::Register(info.reg1() as u8));
+    }
+    let memory = MemoryOperand {
+        segment: info.segment() as u8,
+        base: (!info.base_invalid()).then_some(info.base() as u8),
+        index: (!info.index_invalid()).then_some(info.index() as u8),
+        scale: info.scaling() as u8,
+        displacement: qualification as i64,
+        rip_relative: false,
+        address_size: 2 << info.address_size(),
+    };
+    (instruction, Operand::Memory(memory))
+}
+
+/// Decodes the exit qualification of VM-exit due to an EPT violation at `gpa`.
+///
+/// See: Table 28-7. Exit Qualification for EPT Violations
+pub(crate) fn ept_violation_info(gpa: u64, qualification: u64) -> NestedPageFaultInfo {
+    let qualification = EptViolationQualification(qualification);
+    NestedPageFaultInfo {
+        gpa,
+        read: qualification.read(),
+        write: qualification.write(),
+        execute: qualification.execute(),
+    }
+}
+
 /// Returns the CR0 value after the FIXED0 and FIXED1 MSR values are applied
 /// for the guest.
-fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
+pub(crate) fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
     // Adjust the CR0 register according to the fixed0 and fixed1 MSR values.
     let mut new_cr0 = get_adjusted_cr0(cr0);
 
@@ -706,10 +2636,16 @@ fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
 
 /// Returns the CR4 value after the FIXED0 and FIXED1 MSR values are applied
 /// for the guest.
-fn get_adjusted_guest_cr4(cr4: Cr4) -> Cr4 {
+pub(crate) fn get_adjusted_guest_cr4(cr4: Cr4) -> Cr4 {
     get_adjusted_cr4(cr4)
 }
 
+/// Returns `true` if IA32_PERF_GLOBAL_CTRL is switched on VM-exit and
+/// VM-entry. See `pmu`.
+fn isolates_pmu() -> bool {
+    pmu::isolated() && SHARED_GUEST_DATA.capabilities.perf_global_ctrl
+}
+
 /// Returns the CR0 value after the FIXED0 and FIXED1 MSR values are applied.
 pub(crate) fn get_adjusted_cr0(cr0: Cr0) -> Cr0 {
     // In order to enter VMX operation, some bits in CR0 (and CR4) have to be
@@ -749,18 +2685,18 @@ bitfield::bitfield! {
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual.
     #[derive(Clone, Copy)]
-    struct VmxSegmentAccessRights(u32);
+    pub(crate) struct VmxSegmentAccessRights(u32);
     impl Debug;
 
     /// Extracts or sets the segment type (bits 3:0). This field specifies the type of segment or gate descriptor,
     /// including data, code, system segments, etc. The exact meaning of these bits varies based on the descriptor
     /// type (system, code, or data).
-    segment_type, set_segment_type: 3, 0;
+    pub(crate) segment_type, set_segment_type: 3, 0;
 
     /// Indicates the descriptor type (bit 4). A value of 0 signifies a system descriptor (like LDT or TSS),
     /// while 1 signifies a code or data descriptor. This distinction affects the interpretation of other fields
     /// in the descriptor.
-    descriptor_type, set_descriptor_type: 4;
+    pub(crate) descriptor_type, set_descriptor_type: 4;
 
     /// Represents the Descriptor Privilege Level (DPL, bits 6:5). This specifies the privilege level of the segment,
     /// ranging from 0 (highest privilege, kernel) to 3 (lowest privilege, user applications).
@@ -769,7 +2705,7 @@ bitfield::bitfield! {
     /// Indicates whether the segment is present (bit 7). If this bit is cleared, any attempt to access the segment
     /// results in a segment not present exception (#NP). This bit is used to control loading of segments that
     /// might not be currently available in memory.
-    present, set_present: 7;
+    pub(crate) present, set_present: 7;
 
     /// Available for use by system software (bit 12). This bit is available for use by system software and does not
     /// have a defined meaning in the VMX operation. It can be used by hypervisors to store additional information.
@@ -777,35 +2713,205 @@ bitfield::bitfield! {
 
     /// Indicates 64-bit mode active (for CS only, bit 13). For the CS segment, setting this bit indicates that
     /// the segment is running in 64-bit mode (long mode). This bit is ignored for other segment types.
-    long_mode, set_long_mode: 13;
+    pub(crate) long_mode, set_long_mode: 13;
 
     /// Default operation size (D/B, bit 14). For code segments, this bit controls the default operation size
     /// (0 for 16-bit, 1 for 32-bit). For stack segments (SS), it controls the stack pointer size.
-    default_big, set_default_big: 14;
+    pub(crate) default_big, set_default_big: 14;
 
     /// Granularity (bit 15). When set, the segment limit is scaled by 4K, allowing for larger segments.
     /// This bit is used in conjunction with the segment limit field to determine the actual size of the segment.
-    granularity, set_granularity: 15;
+    pub(crate) granularity, set_granularity: 15;
 
     /// Indicates if the segment is unusable (bit 16). If set, the segment cannot be used for memory access.
     /// An unusable segment is typically one that has been loaded with a null selector.
-    unusable, set_unusable: 16;
+    pub(crate) unusable, set_unusable: 16;
 
     // Reserved bits (31:17). These bits are reserved for future use and should always be cleared to ensure
     // compatibility with future processors.
 }
 
-#[derive(derive_deref::Deref, derive_deref::DerefMut)]
-struct Vmcs {
+bitfield::bitfield! {
+    /// The exit qualification of VM-exit due to control-register access.
+    ///
+    /// See: Table 28-3. Exit Qualification for Control-Register Accesses
+    #[derive(Clone, Copy)]
+    pub(crate) struct CrAccessQualification(u64);
+    impl Debug;
+
+    /// The number of the control register (bits 3:0).
+    pub(crate) cr, _: 3, 0;
+
+    /// The access type (bits 5:4). 0 for MOV to CR, 1 for MOV from CR, 2 for
+    /// CLTS and 3 for LMSW.
+    pub(crate) access_type, _: 5, 4;
+
+    /// The LMSW operand type (bit 6). 0 for a register and 1 for memory.
+    pub(crate) lmsw_memory, _: 6;
+
+    /// The general purpose register of MOV CR (bits 11:8), encoded as in
+    /// instructions.
+    pub(crate) gpr, _: 11, 8;
+
+    /// The source data of LMSW (bits 31:16).
+    pub(crate) lmsw_source, _: 31, 16;
+}
+
+bitfield::bitfield! {
+    /// The exit qualification of VM-exit due to an I/O instruction.
+    ///
+    /// See: Table 28-5. Exit Qualification for I/O Instructions
+    #[derive(Clone, Copy)]
+    pub(crate) struct IoQualification(u64);
+    impl Debug;
+
+    /// The size of access minus 1 (bits 2:0). 0 for 1 byte, 1 for 2 bytes and
+    /// 3 for 4 bytes.
+    pub(crate) size, _: 2, 0;
+
+    /// The direction of the access (bit 3). 0 for OUT and 1 for IN.
+    pub(crate) input, _: 3;
+
+    /// Whether the instruction is INS or OUTS (bit 4).
+    pub(crate) string, _: 4;
+
+    /// Whether the instruction has the REP prefix (bit 5).
+    pub(crate) rep, _: 5;
+
+    /// The operand encoding (bit 6). 0 for DX and 1 for an immediate.
+    pub(crate) immediate, _: 6;
+
+    /// The port number (bits 31:16).
+    pub(crate) port, _: 31, 16;
+}
+
+bitfield::bitfield! {
+    /// The exit qualification of VM-exit due to an EPT violation.
+    ///
+    /// See: Table 28-7. Exit Qualification for EPT Violations
+    #[derive(Clone, Copy)]
+    pub(crate) struct EptViolationQualification(u64);
+    impl Debug;
+
+    /// Whether the access was a data read (bit 0).
+    pub(crate) read, _: 0;
+
+    /// Whether the access was a data write (bit 1).
+    pub(crate) write, _: 1;
+
+    /// Whether the access was an instruction fetch (bit 2).
+    pub(crate) execute, _: 2;
+
+    /// Whether the GPA was readable, writable and executable (bits 5:3).
+    pub(crate) readable, _: 3;
+    pub(crate) writable, _: 4;
+    pub(crate) executable, _: 5;
+
+    /// Whether the guest linear address field is valid (bit 7).
+    pub(crate) gla_valid, _: 7;
+
+    /// Whether the access was to the linear address, rather than to a paging
+    /// structure entry during translation (bit 8). Valid if `gla_valid`.
+    pub(crate) gla_translated, _: 8;
+
+    /// Whether NMI unblocking due to IRET occurred (bit 12).
+    pub(crate) nmi_unblocking, _: 12;
+}
+
+bitfield::bitfield! {
+    /// The VM-exit instruction information for instructions with a register
+    /// operand, such as RDRAND, RDSEED, and those accessing descriptor tables.
+    ///
+    /// See: 28.2.5 Information for VM Exits Due to Instruction Execution
+    #[derive(Clone, Copy)]
+    pub(crate) struct InstructionInformation(u64);
+    impl Debug;
+
+    /// The scaling of the index register of the memory operand (bits 1:0).
+    pub(crate) scaling, _: 1, 0;
+
+    /// The register operand (bits 6:3), encoded as in instructions.
+    pub(crate) reg1, _: 6, 3;
+
+    /// The address size (bits 9:7). 0 for 16-bit, 1 for 32-bit and 2 for
+    /// 64-bit.
+    pub(crate) address_size, _: 9, 7;
+
+    /// Whether the operand is a register rather than memory (bit 10).
+    pub(crate) register_operand, _: 10;
+
+    /// The operand size (bits 12:11). 0 for 16-bit, 1 for 32-bit and 2 for
+    /// 64-bit.
+    pub(crate) operand_size, _: 12, 11;
+
+    /// The segment register of the memory operand (bits 17:15).
+    pub(crate) segment, _: 17, 15;
+
+    /// The index register of the memory operand (bits 21:18), and whether it
+    /// is invalid (bit 22).
+    pub(crate) index, _: 21, 18;
+    pub(crate) index_invalid, _: 22;
+
+    /// The base register of the memory operand (bits 26:23), and whether it
+    /// is invalid (bit 27).
+    pub(crate) base, _: 26, 23;
+    pub(crate) base_invalid, _: 27;
+
+    /// The second register operand (bits 31:28), encoded as in instructions.
+    pub(crate) reg2, _: 31, 28;
+
+    /// The instruction identity of VM-exit due to descriptor-table access
+    /// (bits 29:28).
+    pub(crate) instruction_identity, _: 29, 28;
+}
+
+pub(crate) struct Vmcs {
     ptr: Box<VmcsRaw>,
+
+    /// Whether VMLAUNCH succeeded with this VMCS since the last VMCLEAR, and
+    /// thus VMRESUME must be used instead.
+    /// See: 25.11.3 Initializing a VMCS
+    launched: bool,
 }
 
 impl Vmcs {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         let mut vmcs = zeroed_box::<VmcsRaw>();
         vmcs.revision_id = rdmsr(x86::msr::IA32_VMX_BASIC) as _;
         vmclear(&mut vmcs);
-        Self { ptr: vmcs }
+        Self {
+            ptr: vmcs,
+            launched: false,
+        }
+    }
+
+    /// Runs the guest with this VMCS, which must be current, until VM-exit
+    /// occurs. Returns RFLAGS after VMLAUNCH or VMRESUME.
+    pub(crate) fn enter(&mut self, registers: &mut Registers) -> u64 {
+        let flags = unsafe { run_vmx_guest(registers, u64::from(self.launched)) };
+
+        // Treat the VMCS as launched only once VM entry reached the guest. VM
+        // entry failures are reported with bit 31 of the exit reason.
+        // See: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+        if !self.launched
+            && vmx_succeed(RFlags::from_raw(flags)).is_ok()
+            && !vmread(vmcs::ro::EXIT_REASON).get_bit(31)
+        {
+            self.launched = true;
+        }
+        flags
+    }
+
+    /// Makes this VMCS active and current with VMPTRLD.
+    pub(crate) fn load(&mut self) {
+        vmptrld(&mut self.ptr);
+    }
+
+    /// Clears this VMCS with VMCLEAR, which makes it not current and not
+    /// launched.
+    pub(crate) fn clear(&mut self) {
+        vmclear(&mut self.ptr);
+        self.launched = false;
     }
 }
 
@@ -815,7 +2921,7 @@ impl Vmcs {
 /// See: 25.2 FORMAT OF THE VMCS REGION
 #[derive(Debug)]
 #[repr(C, align(4096))]
-struct VmcsRaw {
+pub(crate) struct VmcsRaw {
     revision_id: u32,
     abort_indicator: u32,
     #[debug(skip)]
@@ -824,26 +2930,26 @@ struct VmcsRaw {
 const _: () = assert!(core::mem::size_of::<VmcsRaw>() == BASE_PAGE_SIZE);
 
 /// The wrapper of the VMCLEAR instruction.
-fn vmclear(vmcs_region: &mut VmcsRaw) {
+pub(crate) fn vmclear(vmcs_region: &mut VmcsRaw) {
     let va = vmcs_region as *const _;
-    let pa = platform_ops::get().pa(va as *const _);
+    let pa = platform_ops::get().pa(va as *const _).unwrap();
     unsafe { x86::bits64::vmx::vmclear(pa).unwrap() };
 }
 
 /// The wrapper of the VMPTRLD instruction.
-fn vmptrld(vmcs_region: &mut VmcsRaw) {
+pub(crate) fn vmptrld(vmcs_region: &mut VmcsRaw) {
     let va = vmcs_region as *const _;
-    let pa = platform_ops::get().pa(va as *const _);
+    let pa = platform_ops::get().pa(va as *const _).unwrap();
     unsafe { x86::bits64::vmx::vmptrld(pa).unwrap() }
 }
 
 /// The wrapper of the VMREAD instruction.
-fn vmread(encoding: u32) -> u64 {
+pub(crate) fn vmread(encoding: u32) -> u64 {
     unsafe { x86::bits64::vmx::vmread(encoding) }.unwrap()
 }
 
 /// The wrapper of the VMWRITE instruction.
-fn vmwrite<T: Into<u64>>(encoding: u32, value: T)
+pub(crate) fn vmwrite<T: Into<u64>>(encoding: u32, value: T)
 where
     u64: From<T>,
 {
@@ -855,7 +2961,7 @@ where
 /// Checks that the latest VMX instruction succeeded.
 ///
 /// See: 31.2 CONVENTIONS
-fn vmx_succeed(flags: RFlags) -> Result<(), String> {
+pub(crate) fn vmx_succeed(flags: RFlags) -> Result<(), String> {
     if flags.contains(RFlags::FLAGS_ZF) {
         // See: 31.4 VM INSTRUCTION ERROR NUMBERS
         Err(format!(
@@ -895,7 +3001,48 @@ const VMCS_HOST_IA32_S_CET: u32 = 0x6C18;
 const VMCS_HOST_SSP: u32 = 0x6C1A;
 const VMCS_HOST_IA32_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6C1C;
 
+// The "load CET state" VM-exit and VM-entry controls, which the x86 crate does
+// not define.
+// See: 25.7.1 VM-Exit Controls
+// See: 25.8.1 VM-Entry Controls
+const VMEXIT_LOAD_CET_STATE: u64 = 1 << 28;
+const VMENTRY_LOAD_CET_STATE: u64 = 1 << 20;
+
+// The "clear IA32_LBR_CTL" VM-exit control and the "load guest IA32_LBR_CTL"
+// VM-entry control, which the x86 crate does not define either.
+const VMEXIT_CLEAR_IA32_LBR_CTL: u64 = 1 << 26;
+const VMENTRY_LOAD_IA32_LBR_CTL: u64 = 1 << 21;
+
+// The "activate tertiary controls" primary processor-based VM-execution
+// control, which the x86 crate does not define either.
+// See: 25.6.2 Processor-Based VM-Execution Controls
+const PROCBASED_CTLS_ACTIVATE_TERTIARY_CONTROLS: u64 = 1 << 17;
+
 impl core::fmt::Debug for Vmcs {
+    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        CurrentVmcs.fmt(format)
+    }
+}
+
+/// The current VMCS of the processor, formatted with VMREAD.
+pub(crate) struct CurrentVmcs;
+
+impl CurrentVmcs {
+    /// Returns the current VMCS if the processor is in VMX root operation
+    /// with a current VMCS. Must not be called in VMX non-root operation,
+    /// where VMPTRST causes VM-exit.
+    pub(crate) fn get() -> Option<Self> {
+        // "VMPTRST (...) stores FFFFFFFF_FFFFFFFFH if there is no current VMCS."
+        // See: VMPTRST—Store Pointer to Virtual-Machine Control Structure
+        if !cr4().contains(Cr4::CR4_ENABLE_VMX) {
+            return None;
+        }
+        let pa = unsafe { x86::bits64::vmx::vmptrst() }.ok()?;
+        (pa != u64::MAX).then_some(Self)
+    }
+}
+
+impl core::fmt::Debug for CurrentVmcs {
     #[rustfmt::skip]
     #[expect(clippy::too_many_lines)]
     fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -906,8 +3053,8 @@ impl core::fmt::Debug for Vmcs {
 
         // Dump the current VMCS. Not that this is not exhaustive.
         format.debug_struct("Vmcs")
-        .field("Current VMCS                                   ", &addr_of!(self.revision_id))
-        .field("Revision ID                                    ", &self.revision_id)
+        .field("Current VMCS                                   ", &unsafe { x86::bits64::vmx::vmptrst() }.unwrap_or(0))
+        .field("Revision ID                                    ", &(rdmsr(x86::msr::IA32_VMX_BASIC) & 0x7fff_ffff))
 
         // 16-Bit Guest-State Fields
         .field("Guest ES Selector                              ", &vmread_relaxed(vmcs::guest::ES_SELECTOR))
@@ -1124,3 +3271,50 @@ impl core::fmt::Debug for Vmcs {
         .finish_non_exhaustive()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_qualifications() {
+        // MOV CR4, RCX
+        let info = cr_access_info(0, 0x104);
+        assert_eq!((info.cr, info.write, info.gpr), (4, true, Some(1)));
+        // MOV RAX, CR3
+        let info = cr_access_info(0, 0x13);
+        assert_eq!((info.cr, info.write, info.gpr), (3, false, Some(0)));
+
+        // REP INSW with DX=0x1f0
+        let info = io_info(0, 0x01f0_0039);
+        assert_eq!((info.port, info.size), (0x1f0, 2));
+        assert!(info.input && info.string && info.rep);
+
+        let info = ept_violation_info(0x1000, 0x184);
+        assert!(!info.read && !info.write && info.execute);
+
+        // RDRAND R9D
+        let info = random_info(0, false, 0x848);
+        assert_eq!((info.gpr, info.size), (9, 4));
+
+        // SGDT [RAX+RBX*4-8]
+        let (instruction, operand) = descriptor_table_access(false, 0xd_8102, -8i64 as u64);
+        assert_eq!(instruction, DescriptorTableInstruction::Sgdt);
+        assert_eq!(
+            operand,
+            Operand::Memory(MemoryOperand {
+                segment: 3,
+                base: Some(0),
+                index: Some(3),
+                scale: 2,
+                displacement: -8,
+                rip_relative: false,
+                address_size: 8,
+            })
+        );
+        // STR ECX
+        let (instruction, operand) = descriptor_table_access(true, 0x1000_0408, 0);
+        assert_eq!(instruction, DescriptorTableInstruction::Str);
+        assert_eq!(operand, Operand::Register(1));
+    }
+}
@@ -0,0 +1,289 @@
+//! This module implements mini VMs on Intel processors. See
+//! [`crate::hypervisor::mini_vm`].
+
+use alloc::boxed::Box;
+use bit_field::BitField;
+use x86::{
+    bits64::rflags::RFlags,
+    controlregs::{Cr0, Cr4},
+    segmentation::{CodeSegmentType, DataSegmentType, SystemDescriptorTypes64},
+    vmx::vmcs,
+};
+
+use crate::hypervisor::{
+    mini_vm::{MiniVmError, MiniVmExit, MiniVmMode, MiniVmRequest},
+    platform_ops::PaError,
+    registers::Registers,
+    support::zeroed_box,
+    x86_instructions::rdmsr,
+};
+
+use super::{
+    epts::BlobEpts,
+    exit_reasons::{
+        VMX_EXIT_REASON_EPT_VIOLATION, VMX_EXIT_REASON_EXCEPTION_OR_NMI, VMX_EXIT_REASON_HLT,
+        VMX_EXIT_REASON_TRIPLE_FAULT, VMX_EXIT_REASON_VMCALL,
+        VMX_EXIT_REASON_VMX_PREEMPTION_TIMER_EXPIRED,
+    },
+    guest::{
+        GuestActivityState, Vmcs, VmxControl, VmxGuest, VmxSegmentAccessRights,
+        get_adjusted_guest_cr0, get_adjusted_guest_cr4, vmread, vmwrite, vmx_succeed,
+    },
+};
+
+/// A VM that runs a payload in a memory blob, separately from the guest.
+pub(crate) struct MiniVm {
+    pub(crate) vmcs: Vmcs,
+    epts: Box<BlobEpts>,
+    registers: Registers,
+}
+
+impl MiniVm {
+    /// Creates a mini VM that maps the blob in `request` at GPA 0.
+    pub(crate) fn new(request: &MiniVmRequest) -> Result<Self, PaError> {
+        let mut epts = zeroed_box::<BlobEpts>();
+        epts.build(request.blob_gpa, request.blob_size)?;
+        Ok(Self {
+            vmcs: Vmcs::new(),
+            epts,
+            registers: Registers::default(),
+        })
+    }
+
+    /// Initializes the VMCS of this mini VM, which must be current. The host
+    /// state is copied from `guest`.
+    pub(crate) fn initialize(&mut self, request: &MiniVmRequest, guest: &VmxGuest) {
+        self.initialize_control(request);
+        self.initialize_guest(request);
+        guest.initialize_host();
+    }
+
+    /// Runs the payload until the first VM-exit.
+    pub(crate) fn run(&mut self) -> Result<MiniVmExit, MiniVmError> {
+        const INTERRUPTION_TYPE_NMI: u64 = 2;
+
+        vmwrite(vmcs::guest::RIP, self.registers.rip);
+        vmwrite(vmcs::guest::RSP, self.registers.rsp);
+        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+
+        let flags = self.vmcs.enter(&mut self.registers);
+        if let Err(err) = vmx_succeed(RFlags::from_raw(flags)) {
+            log::error!("Failed to enter the mini VM: {err}");
+            return Err(MiniVmError::EntryFailed);
+        }
+
+        // "Bit 31 is set to 1 if the VM exit occurred during VM entry"
+        // See: Table 25-18. Format of Exit Reason
+        let exit_reason = vmread(vmcs::ro::EXIT_REASON);
+        if exit_reason.get_bit(31) {
+            log::error!("Failed to enter the mini VM: {exit_reason:#x?}");
+            return Err(MiniVmError::EntryFailed);
+        }
+
+        Ok(match exit_reason.get_bits(0..=15) {
+            VMX_EXIT_REASON_EXCEPTION_OR_NMI => {
+                // See: Table 25-19. Format of the VM-Exit Interruption-Information Field
+                let info = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
+                if info.get_bits(8..=10) == INTERRUPTION_TYPE_NMI {
+                    MiniVmExit::Nmi
+                } else {
+                    MiniVmExit::Exception {
+                        vector: info.get_bits(0..=7) as u8,
+                    }
+                }
+            }
+            VMX_EXIT_REASON_TRIPLE_FAULT => MiniVmExit::Shutdown,
+            VMX_EXIT_REASON_HLT => MiniVmExit::Hlt,
+            VMX_EXIT_REASON_VMCALL => MiniVmExit::Hypercall,
+            VMX_EXIT_REASON_EPT_VIOLATION => MiniVmExit::EptViolation {
+                gpa: vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL),
+            },
+            VMX_EXIT_REASON_VMX_PREEMPTION_TIMER_EXPIRED => MiniVmExit::Timeout,
+            reason => MiniVmExit::Other {
+                reason: reason as u32,
+            },
+        })
+    }
+
+    /// Returns the registers of the payload.
+    pub(crate) fn regs(&self) -> &Registers {
+        &self.registers
+    }
+
+    fn initialize_control(&self, request: &MiniVmRequest) {
+        // Let any interrupt, exception, I/O and MSR access and debug register
+        // access cause VM-exit, so that the payload cannot affect the system
+        // outside its blob. The MSR bitmaps are not used for the latter.
+        //
+        // Also bound the execution time with the VMX-preemption timer if
+        // supported. The timer counts down at a rate proportional to the TSC.
+        // See: 26.5.1 VMX-Preemption Timer
+        let mut pin_based = vmcs::control::PinbasedControls::EXTERNAL_INTERRUPT_EXITING
+            | vmcs::control::PinbasedControls::NMI_EXITING;
+        // "Bits 63:32 indicate the allowed 1-settings of these controls."
+        // See: A.3.1 Pin-Based VM-Execution Controls
+        if rdmsr(x86::msr::IA32_VMX_PINBASED_CTLS).get_bit(32 + 6) {
+            pin_based |= vmcs::control::PinbasedControls::VMX_PREEMPTION_TIMER;
+            vmwrite(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE, u32::MAX);
+        }
+        vmwrite(
+            vmcs::control::PINBASED_EXEC_CONTROLS,
+            VmxGuest::adjust_vmx_control(VmxControl::PinBased, pin_based.bits() as _),
+        );
+
+        let primary_controls = vmcs::control::PrimaryControls::HLT_EXITING
+            | vmcs::control::PrimaryControls::MOV_DR_EXITING
+            | vmcs::control::PrimaryControls::UNCOND_IO_EXITING
+            | vmcs::control::PrimaryControls::SECONDARY_CONTROLS;
+        vmwrite(
+            vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            VmxGuest::adjust_vmx_control(VmxControl::ProcessorBased, primary_controls.bits() as _),
+        );
+        let mut secondary_controls = vmcs::control::SecondaryControls::ENABLE_EPT;
+        if request.mode == MiniVmMode::Real {
+            secondary_controls |= vmcs::control::SecondaryControls::UNRESTRICTED_GUEST;
+        }
+        vmwrite(
+            vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            VmxGuest::adjust_vmx_control(
+                VmxControl::ProcessorBased2,
+                secondary_controls.bits() as _,
+            ),
+        );
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, u32::MAX);
+        vmwrite(vmcs::control::EPTP_FULL, self.epts.eptp().unwrap().0);
+
+        vmwrite(
+            vmcs::control::VMEXIT_CONTROLS,
+            VmxGuest::adjust_vmx_control(
+                VmxControl::VmExit,
+                vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as _,
+            ),
+        );
+        let entry_controls = if request.mode == MiniVmMode::Long {
+            vmcs::control::EntryControls::IA32E_MODE_GUEST.bits()
+        } else {
+            0
+        };
+        vmwrite(
+            vmcs::control::VMENTRY_CONTROLS,
+            VmxGuest::adjust_vmx_control(VmxControl::VmEntry, entry_controls as _),
+        );
+    }
+
+    fn initialize_guest(&mut self, request: &MiniVmRequest) {
+        let (cr0, cr4) = match request.mode {
+            MiniVmMode::Real => (Cr0::CR0_EXTENSION_TYPE, Cr4::empty()),
+            MiniVmMode::Long => (
+                Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING | Cr0::CR0_EXTENSION_TYPE,
+                Cr4::CR4_ENABLE_PAE,
+            ),
+        };
+        let cr0 = get_adjusted_guest_cr0(cr0);
+        let cr4 = get_adjusted_guest_cr4(cr4);
+        vmwrite(vmcs::guest::CR0, cr0.bits() as u64);
+        vmwrite(vmcs::control::CR0_READ_SHADOW, cr0.bits() as u64);
+        vmwrite(vmcs::guest::CR4, cr4.bits() as u64);
+        vmwrite(vmcs::control::CR4_READ_SHADOW, cr4.bits() as u64);
+        // The PML4 is at the start of the blob in the 64-bit mode.
+        vmwrite(vmcs::guest::CR3, 0u64);
+
+        // Flat segments. The code segment is 64-bit in the 64-bit mode, and
+        // 16-bit in real-mode.
+        let long = request.mode == MiniVmMode::Long;
+        let limit: u32 = if long { u32::MAX } else { 0xffff };
+        let mut code = VmxSegmentAccessRights(0);
+        code.set_segment_type(CodeSegmentType::ExecuteReadAccessed as u32);
+        code.set_descriptor_type(true);
+        code.set_present(true);
+        code.set_long_mode(long);
+        code.set_granularity(long);
+        let mut data = VmxSegmentAccessRights(0);
+        data.set_segment_type(DataSegmentType::ReadWriteAccessed as u32);
+        data.set_descriptor_type(true);
+        data.set_present(true);
+        data.set_default_big(long);
+        data.set_granularity(long);
+
+        let (code_selector, data_selector) = if long { (0x8u64, 0x10u64) } else { (0, 0) };
+        vmwrite(vmcs::guest::CS_SELECTOR, code_selector);
+        vmwrite(vmcs::guest::CS_BASE, 0u64);
+        vmwrite(vmcs::guest::CS_LIMIT, limit);
+        vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, code.0);
+        for (selector, base, limit_field, access_rights) in [
+            (
+                vmcs::guest::SS_SELECTOR,
+                vmcs::guest::SS_BASE,
+                vmcs::guest::SS_LIMIT,
+                vmcs::guest::SS_ACCESS_RIGHTS,
+            ),
+            (
+                vmcs::guest::DS_SELECTOR,
+                vmcs::guest::DS_BASE,
+                vmcs::guest::DS_LIMIT,
+                vmcs::guest::DS_ACCESS_RIGHTS,
+            ),
+            (
+                vmcs::guest::ES_SELECTOR,
+                vmcs::guest::ES_BASE,
+                vmcs::guest::ES_LIMIT,
+                vmcs::guest::ES_ACCESS_RIGHTS,
+            ),
+            (
+                vmcs::guest::FS_SELECTOR,
+                vmcs::guest::FS_BASE,
+                vmcs::guest::FS_LIMIT,
+                vmcs::guest::FS_ACCESS_RIGHTS,
+            ),
+            (
+                vmcs::guest::GS_SELECTOR,
+                vmcs::guest::GS_BASE,
+                vmcs::guest::GS_LIMIT,
+                vmcs::guest::GS_ACCESS_RIGHTS,
+            ),
+        ] {
+            vmwrite(selector, data_selector);
+            vmwrite(base, 0u64);
+            vmwrite(limit_field, limit);
+            vmwrite(access_rights, data.0);
+        }
+
+        // TR must be usable and a busy TSS, even though the payload never uses it.
+        // See: 27.3.1.2 Checks on Guest Segment Registers
+        let mut system = VmxSegmentAccessRights(0);
+        system.set_segment_type(SystemDescriptorTypes64::TssBusy as u32);
+        system.set_present(true);
+        vmwrite(vmcs::guest::TR_SELECTOR, 0u64);
+        vmwrite(vmcs::guest::TR_BASE, 0u64);
+        vmwrite(vmcs::guest::TR_LIMIT, 0x67u64);
+        vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, system.0);
+        system.set_unusable(true);
+        vmwrite(vmcs::guest::LDTR_SELECTOR, 0u64);
+        vmwrite(vmcs::guest::LDTR_BASE, 0u64);
+        vmwrite(vmcs::guest::LDTR_LIMIT, 0u64);
+        vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, system.0);
+
+        vmwrite(vmcs::guest::GDTR_BASE, 0u64);
+        vmwrite(vmcs::guest::GDTR_LIMIT, 0u64);
+        vmwrite(vmcs::guest::IDTR_BASE, 0u64);
+        vmwrite(vmcs::guest::IDTR_LIMIT, 0u64);
+
+        vmwrite(vmcs::guest::DR7, 0x400u64);
+        vmwrite(vmcs::guest::IA32_DEBUGCTL_FULL, 0u64);
+        vmwrite(vmcs::guest::LINK_PTR_FULL, u64::MAX);
+        vmwrite(
+            vmcs::guest::ACTIVITY_STATE,
+            GuestActivityState::Active as u32,
+        );
+        vmwrite(vmcs::guest::INTERRUPTIBILITY_STATE, 0u32);
+        vmwrite(vmcs::guest::PENDING_DBG_EXCEPTIONS, 0u64);
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, 0u32);
+
+        // Interrupts are disabled. See the module document.
+        self.registers = Registers {
+            rip: request.entry,
+            rflags: RFlags::FLAGS_A1.bits(),
+            ..Registers::default()
+        };
+    }
+}
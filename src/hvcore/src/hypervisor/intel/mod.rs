@@ -5,10 +5,18 @@
 use super::host::Architecture;
 
 mod epts;
+mod exit_reasons;
 mod guest;
+mod mini_vm;
 mod mtrr;
+mod spp;
+mod vmcs_cache;
 mod vmx;
 
+pub(crate) use guest::CurrentVmcs;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use guest::{cr_access_info, ept_violation_info, io_info};
+
 /// The Intel processor implements VMX as a virtualization extension.
 pub(crate) struct Intel;
 
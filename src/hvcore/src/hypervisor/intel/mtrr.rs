@@ -1,4 +1,4 @@
-use core::{arch::asm, ops::Range};
+use core::ops::Range;
 
 use alloc::vec::Vec;
 use num_derive::FromPrimitive;
@@ -7,7 +7,7 @@ use x86::bits64::paging::BASE_PAGE_SHIFT;
 
 use crate::hypervisor::x86_instructions::rdmsr;
 
-#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub(crate) enum MemoryType {
     Uncachable = 0,
     WriteCombining = 1,
@@ -17,7 +17,111 @@ pub(crate) enum MemoryType {
     UncachableMinus = 7,
 }
 
-#[derive(Debug)]
+/// The end of the range the fixed range MTRRs manage.
+const FIXED_MTRR_END: u64 = 0x10_0000;
+
+const IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG: u64 = 1 << 10;
+const IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG: u64 = 1 << 11;
+
+/// The fixed range MTRRs, in the order of the ranges they manage.
+const FIXED_MTRRS: [u32; 11] = [
+    x86::msr::IA32_MTRR_FIX64K_00000,
+    x86::msr::IA32_MTRR_FIX16K_80000,
+    x86::msr::IA32_MTRR_FIX16K_A0000,
+    x86::msr::IA32_MTRR_FIX4K_C0000,
+    x86::msr::IA32_MTRR_FIX4K_C8000,
+    x86::msr::IA32_MTRR_FIX4K_D0000,
+    x86::msr::IA32_MTRR_FIX4K_D8000,
+    x86::msr::IA32_MTRR_FIX4K_E0000,
+    x86::msr::IA32_MTRR_FIX4K_E8000,
+    x86::msr::IA32_MTRR_FIX4K_F0000,
+    x86::msr::IA32_MTRR_FIX4K_F8000,
+];
+
+/// The pairs of the variable range MTRRs, up to the architectural maximum.
+const VARIABLE_MTRRS: [u32; 20] = [
+    x86::msr::IA32_MTRR_PHYSBASE0,
+    x86::msr::IA32_MTRR_PHYSMASK0,
+    x86::msr::IA32_MTRR_PHYSBASE1,
+    x86::msr::IA32_MTRR_PHYSMASK1,
+    x86::msr::IA32_MTRR_PHYSBASE2,
+    x86::msr::IA32_MTRR_PHYSMASK2,
+    x86::msr::IA32_MTRR_PHYSBASE3,
+    x86::msr::IA32_MTRR_PHYSMASK3,
+    x86::msr::IA32_MTRR_PHYSBASE4,
+    x86::msr::IA32_MTRR_PHYSMASK4,
+    x86::msr::IA32_MTRR_PHYSBASE5,
+    x86::msr::IA32_MTRR_PHYSMASK5,
+    x86::msr::IA32_MTRR_PHYSBASE6,
+    x86::msr::IA32_MTRR_PHYSMASK6,
+    x86::msr::IA32_MTRR_PHYSBASE7,
+    x86::msr::IA32_MTRR_PHYSMASK7,
+    x86::msr::IA32_MTRR_PHYSBASE8,
+    x86::msr::IA32_MTRR_PHYSMASK8,
+    x86::msr::IA32_MTRR_PHYSBASE9,
+    x86::msr::IA32_MTRR_PHYSMASK9,
+];
+
+/// Returns the MTRRs the processor supports, writes to which are intercepted
+/// to update the EPT memory types.
+pub(crate) fn intercepted_writes() -> impl Iterator<Item = u32> {
+    const IA32_MTRRCAP_FIX_FLAG: u64 = 1 << 8;
+
+    let fixed_count = if rdmsr(x86::msr::IA32_MTRRCAP) & IA32_MTRRCAP_FIX_FLAG != 0 {
+        FIXED_MTRRS.len()
+    } else {
+        0
+    };
+    core::iter::once(x86::msr::IA32_MTRR_DEF_TYPE)
+        .chain(FIXED_MTRRS[..fixed_count].iter().copied())
+        .chain(VARIABLE_MTRRS[..variable_mtrr_count() * 2].iter().copied())
+}
+
+/// Returns `true` if the MTRRs are enabled with IA32_MTRR_DEF_TYPE.
+pub(crate) fn enabled() -> bool {
+    rdmsr(x86::msr::IA32_MTRR_DEF_TYPE) & IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG != 0
+}
+
+/// Returns `true` if `value` can be written to the MTRR `msr` without #GP.
+pub(crate) fn is_valid_write(msr: u32, value: u64) -> bool {
+    let max_phys_addr = x86::cpuid::cpuid!(0x8000_0008).eax & 0xff;
+    is_valid_value(msr, value, max_phys_addr)
+}
+
+/// Returns `true` if `value` is valid for the MTRR `msr` on a processor with
+/// `max_phys_addr` bits of physical addresses.
+///
+/// See: 13.11.2 Setting Memory Ranges with MTRRs
+fn is_valid_value(msr: u32, value: u64, max_phys_addr: u32) -> bool {
+    // UC- is a PAT memory type, and invalid for the MTRRs.
+    // See: Table 13-8. Memory Types That Can Be Encoded in MTRRs
+    let is_memory_type = |byte: u8| matches!(byte, 0 | 1 | 4..=6);
+    let above_max_phys_addr = u64::MAX << max_phys_addr.min(63);
+    if msr == x86::msr::IA32_MTRR_DEF_TYPE {
+        // Bits 9:8 and 63:12 are reserved.
+        value & !0xcff == 0 && is_memory_type(value as u8)
+    } else if FIXED_MTRRS.contains(&msr) {
+        value.to_le_bytes().into_iter().all(is_memory_type)
+    } else if let Some(index) = VARIABLE_MTRRS.iter().position(|&m| m == msr) {
+        if index % 2 == 0 {
+            // IA32_MTRR_PHYSBASEn. Bits 11:8 are reserved.
+            value & (above_max_phys_addr | 0xf00) == 0 && is_memory_type(value as u8)
+        } else {
+            // IA32_MTRR_PHYSMASKn. Bits 10:0 are reserved.
+            value & (above_max_phys_addr | 0x7ff) == 0
+        }
+    } else {
+        false
+    }
+}
+
+/// Returns the number of the variable range MTRRs the processor supports.
+fn variable_mtrr_count() -> usize {
+    let capabilities = rdmsr(x86::msr::IA32_MTRRCAP);
+    ((capabilities & 0b1111_1111) as usize).min(VARIABLE_MTRRS.len() / 2)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct Mtrr {
     default_memory_type: MemoryType,
     fixed: Vec<MemoryTypeRange>,
@@ -28,78 +132,96 @@ impl Mtrr {
     pub(crate) fn new() -> Self {
         let raw_mtrrs = RawMtrrs::new();
         log::trace!("{raw_mtrrs:#x?}");
+        Self::from_raw(&raw_mtrrs)
+    }
+
+    /// Builds the memory type ranges from the raw MTRR values.
+    fn from_raw(raw_mtrrs: &RawMtrrs) -> Self {
+        // "When the MTRRs are disabled, the UC memory type is applied to all of
+        //  physical memory." "When the fixed-range MTRRs are disabled, the
+        //  variable-range MTRRs apply to the first 1 MByte".
+        // See: 13.11.2.1 IA32_MTRR_DEF_TYPE MSR
+        if !raw_mtrrs.enabled {
+            return Self {
+                default_memory_type: MemoryType::Uncachable,
+                fixed: Vec::new(),
+                variable: Vec::new(),
+            };
+        }
         Self {
             default_memory_type: raw_mtrrs.default_memory_type,
-            fixed: Self::convert_from_raw_fixed(&raw_mtrrs.fixed),
+            fixed: if raw_mtrrs.fixed_enabled {
+                Self::convert_from_raw_fixed(&raw_mtrrs.fixed)
+            } else {
+                Vec::new()
+            },
             variable: Self::convert_from_raw_variable(&raw_mtrrs.variable),
         }
     }
 
-    pub(crate) fn find(&self, range: Range<u64>) -> Option<MemoryType> {
-        // Look up the fixed range MTRRs if the range start within 1MB (which is managed
-        // by the fixed range MTRRs), since the fixed range MTRRs are priority over
-        // the variable range MTRRs.
-        if range.start < 0x10_0000 {
-            // If the range crosses the 1MB boundary, report error. For simplicity,
-            // we do not attempt to resolve the memory type of the range that spans both
-            // fixed and variable range MTRRs. The caller should query a memory type for
-            // a shorter range instead. That is almost always required anyway since
-            // lowest 1MB has variety of memory types through fixed range MTRRs.
-            if range.end > 0x10_0000 {
-                return None;
+    /// Returns the memory type of `range`.
+    ///
+    /// If `range` consists of multiple memory types, returns the one that is
+    /// safe to use for the entire range, following the same precedence as for
+    /// overlapping variable range MTRRs.
+    pub(crate) fn find(&self, mut range: Range<u64>) -> MemoryType {
+        let mut memory_type = None;
+        let mut merge = |new: MemoryType| {
+            memory_type = Some(memory_type.map_or(new, |current| Self::resolve(current, new)));
+        };
+
+        // The fixed range MTRRs take precedence over the variable range MTRRs
+        // for the first 1MB, and cover all of it when enabled.
+        // See: 13.11.2.2 Fixed Range MTRRs
+        if range.start < FIXED_MTRR_END && !self.fixed.is_empty() {
+            let fixed_range = range.start..range.end.min(FIXED_MTRR_END);
+            self.fixed
+                .iter()
+                .filter(|mtrr| Self::overlaps(&mtrr.range, &fixed_range))
+                .for_each(|mtrr| merge(mtrr.memory_type));
+            if range.end <= FIXED_MTRR_END {
+                return memory_type.unwrap();
             }
-            return self.find_from_fixed(range);
+            range.start = FIXED_MTRR_END;
         }
-        // Otherwise, look up the variable range MTRRs.
-        self.find_from_variable(range)
-    }
 
-    fn find_from_fixed(&self, range: Range<u64>) -> Option<MemoryType> {
-        // Return the memory type of the first range that contains the given range.
-        // Within `self.fixed` there is no overlap of ranges, so the first match
-        // is the only match.
-        self.fixed
+        // Otherwise, look up the variable range MTRRs. The default memory type
+        // applies to any part of the range that none of them covers.
+        // See: 13.11.2.1 IA32_MTRR_DEF_TYPE MSR
+        let mut covered = false;
+        for mtrr in self
+            .variable
             .iter()
-            .find(|mtrr| mtrr.range.contains(&range.start) && mtrr.range.contains(&(range.end - 1)))
-            .map(|found| found.memory_type)
+            .filter(|mtrr| Self::overlaps(&mtrr.range, &range))
+        {
+            merge(mtrr.memory_type);
+            covered |= mtrr.range.start <= range.start && range.end <= mtrr.range.end;
+        }
+        if !covered {
+            merge(self.default_memory_type);
+        }
+        memory_type.unwrap()
     }
 
-    fn find_from_variable(&self, range: Range<u64>) -> Option<MemoryType> {
-        let mut return_memory_type = None::<MemoryType>;
-        for mtrr in &self.variable {
-            if mtrr.range.contains(&range.start) {
-                // If the entire range is not managed by this single entry, bail out.
-                // This means the given range is managed by multiple conflicting MTRR
-                // settings. The caller needs to call this function with smaller range.
-                if !mtrr.range.contains(&(range.end - 1)) {
-                    return None;
-                }
-
-                // Use if the current matching entry is for UC, as the UC memory type
-                // takes precedence.
-                if mtrr.memory_type == MemoryType::Uncachable {
-                    return Some(mtrr.memory_type);
-                }
-
-                // The WT memory type takes precedence over the WB memory type.
-                if let Some(memory_type) = return_memory_type {
-                    if memory_type == MemoryType::WriteBack
-                        && mtrr.memory_type == MemoryType::WriteThrough
-                    {
-                        return_memory_type = Some(mtrr.memory_type);
-                    }
-                } else {
-                    // Overwise, use the last matching MTRR's memory type.
-                    return_memory_type = Some(mtrr.memory_type);
-                }
-            }
+    /// Returns the memory type for memory where both `a` and `b` apply.
+    ///
+    /// "If two or more variable memory ranges match and one of the memory types
+    ///  is UC, the UC memory type used. If two or more variable memory ranges
+    ///  match and the memory types are WT and WB, the WT memory type is used.
+    ///  For overlaps not defined by the above rules, processor behavior is
+    ///  undefined." We use UC for undefined combinations.
+    /// See: 13.11.4.1 MTRR Precedences
+    fn resolve(a: MemoryType, b: MemoryType) -> MemoryType {
+        match (a, b) {
+            _ if a == b => a,
+            (MemoryType::WriteThrough, MemoryType::WriteBack)
+            | (MemoryType::WriteBack, MemoryType::WriteThrough) => MemoryType::WriteThrough,
+            _ => MemoryType::Uncachable,
         }
+    }
 
-        // Use the default type if none of MTRRs controls any page in this range.
-        if return_memory_type.is_none() {
-            return_memory_type = Some(self.default_memory_type);
-        }
-        return_memory_type
+    fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+        a.start < b.end && b.start < a.end
     }
 
     fn convert_from_raw_fixed(raw_fixed_mtrrs: &[RawFixedMtrr]) -> Vec<MemoryTypeRange> {
@@ -162,9 +284,7 @@ impl Mtrr {
     }
 
     fn bit_scan_forward(value: u64) -> u64 {
-        let result: u64;
-        unsafe { asm!("bsf {}, {}", out(reg) result, in(reg) value) };
-        result
+        u64::from(value.trailing_zeros())
     }
 
     fn update_combined_ranges(combined_ranges: &mut Vec<MemoryTypeRange>, range: MemoryTypeRange) {
@@ -186,7 +306,7 @@ impl Mtrr {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct MemoryTypeRange {
     memory_type: MemoryType,
     range: Range<u64>,
@@ -205,6 +325,8 @@ impl FixedMtrrRangeInfo {
 
 #[derive(Debug)]
 struct RawMtrrs {
+    enabled: bool,
+    fixed_enabled: bool,
     default_memory_type: MemoryType,
     fixed: Vec<RawFixedMtrr>,
     variable: Vec<RawVariableMtrr>,
@@ -212,71 +334,23 @@ struct RawMtrrs {
 
 impl RawMtrrs {
     fn new() -> Self {
-        const IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG: u64 = 1 << 10;
-        const IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG: u64 = 1 << 11;
-
-        const FIXED_MTRRS: [u32; 11] = [
-            x86::msr::IA32_MTRR_FIX64K_00000,
-            x86::msr::IA32_MTRR_FIX16K_80000,
-            x86::msr::IA32_MTRR_FIX16K_A0000,
-            x86::msr::IA32_MTRR_FIX4K_C0000,
-            x86::msr::IA32_MTRR_FIX4K_C8000,
-            x86::msr::IA32_MTRR_FIX4K_D0000,
-            x86::msr::IA32_MTRR_FIX4K_D8000,
-            x86::msr::IA32_MTRR_FIX4K_E0000,
-            x86::msr::IA32_MTRR_FIX4K_E8000,
-            x86::msr::IA32_MTRR_FIX4K_F0000,
-            x86::msr::IA32_MTRR_FIX4K_F8000,
-        ];
-
-        const VARIABLE_MTRRS: [u32; 20] = [
-            x86::msr::IA32_MTRR_PHYSBASE0,
-            x86::msr::IA32_MTRR_PHYSMASK0,
-            x86::msr::IA32_MTRR_PHYSBASE1,
-            x86::msr::IA32_MTRR_PHYSMASK1,
-            x86::msr::IA32_MTRR_PHYSBASE2,
-            x86::msr::IA32_MTRR_PHYSMASK2,
-            x86::msr::IA32_MTRR_PHYSBASE3,
-            x86::msr::IA32_MTRR_PHYSMASK3,
-            x86::msr::IA32_MTRR_PHYSBASE4,
-            x86::msr::IA32_MTRR_PHYSMASK4,
-            x86::msr::IA32_MTRR_PHYSBASE5,
-            x86::msr::IA32_MTRR_PHYSMASK5,
-            x86::msr::IA32_MTRR_PHYSBASE6,
-            x86::msr::IA32_MTRR_PHYSMASK6,
-            x86::msr::IA32_MTRR_PHYSBASE7,
-            x86::msr::IA32_MTRR_PHYSMASK7,
-            x86::msr::IA32_MTRR_PHYSBASE8,
-            x86::msr::IA32_MTRR_PHYSMASK8,
-            x86::msr::IA32_MTRR_PHYSBASE9,
-            x86::msr::IA32_MTRR_PHYSMASK9,
-        ];
-
-        // For simplicity, panic when the system does not support MTRRs or enable
-        // fixed range MTRRs.
         let default_type = rdmsr(x86::msr::IA32_MTRR_DEF_TYPE);
-
-        assert!(
-            (default_type & IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG) != 0,
-            "MTRRs not enabled"
-        );
-        assert!(
-            (default_type & IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG) != 0,
-            "Fixed range MTRRs not enabled"
-        );
+        let enabled = (default_type & IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG) != 0;
+        let fixed_enabled = (default_type & IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG) != 0;
         let default_memory_type =
             <MemoryType as FromPrimitive>::from_u8(default_type as _).unwrap();
 
-        // Read all fixed range MTRRs.
+        // Read all fixed range MTRRs if enabled. They may not exist otherwise.
         let mut fixed = Vec::<RawFixedMtrr>::new();
-        for msr in FIXED_MTRRS {
-            fixed.push(RawFixedMtrr { value: rdmsr(msr) });
+        if fixed_enabled {
+            for msr in FIXED_MTRRS {
+                fixed.push(RawFixedMtrr { value: rdmsr(msr) });
+            }
         }
 
         // Get how many variable range MTRRs is supported on this system and read
         // them.
-        let capabilities = rdmsr(x86::msr::IA32_MTRRCAP);
-        let variable_mtrr_count = (capabilities & 0b1111_1111) as usize;
+        let variable_mtrr_count = variable_mtrr_count();
 
         let mut variable = Vec::<RawVariableMtrr>::new();
         for i in (0..VARIABLE_MTRRS.len()).step_by(2) {
@@ -289,6 +363,8 @@ impl RawMtrrs {
         }
 
         Self {
+            enabled,
+            fixed_enabled,
             default_memory_type,
             fixed,
             variable,
@@ -306,3 +382,199 @@ struct RawVariableMtrr {
     base: u64,
     mask: u64,
 }
+
+#[cfg(test)]
+impl Mtrr {
+    /// Builds the memory type ranges from the given MSR values instead of
+    /// reading them from the processor.
+    pub(crate) fn from_values(
+        default_memory_type: MemoryType,
+        fixed: [u64; 11],
+        variable: &[(u64, u64)],
+    ) -> Self {
+        Self::from_raw(&RawMtrrs {
+            enabled: true,
+            fixed_enabled: true,
+            default_memory_type,
+            fixed: fixed.map(|value| RawFixedMtrr { value }).into(),
+            variable: variable
+                .iter()
+                .map(|&(base, mask)| RawVariableMtrr { base, mask })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXED_WB: u64 = 0x0606_0606_0606_0606;
+    const FIXED_UC: u64 = 0;
+    const VALID: u64 = 1 << 11;
+
+    #[test]
+    fn fixed_ranges() {
+        // 0xa0000 - 0xbffff is UC (legacy VGA), and the rest is WB.
+        let mut fixed = [FIXED_WB; 11];
+        fixed[2] = FIXED_UC;
+        let mtrr = Mtrr::from_values(MemoryType::WriteBack, fixed, &[]);
+
+        assert_eq!(mtrr.find(0..0x1000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0x9f000..0xa0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0xa0000..0xa1000), MemoryType::Uncachable);
+        assert_eq!(mtrr.find(0xbf000..0xc0000), MemoryType::Uncachable);
+        assert_eq!(mtrr.find(0xc0000..0xc1000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0xff000..0x10_0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0xff000..0x10_1000), MemoryType::WriteBack);
+
+        // Ranges spanning multiple memory types get the conservative one.
+        assert_eq!(mtrr.find(0x9f000..0xa1000), MemoryType::Uncachable);
+        assert_eq!(mtrr.find(0..0x20_0000), MemoryType::Uncachable);
+    }
+
+    #[test]
+    fn fixed_ranges_take_precedence() {
+        // The variable range MTRR makes the first 2MB UC, but is ignored for
+        // the first 1MB, which the fixed range MTRRs manage.
+        let mtrr = Mtrr::from_values(
+            MemoryType::WriteBack,
+            [FIXED_WB; 11],
+            &[(0, 0xf_ffe0_0000 | VALID)],
+        );
+        assert_eq!(mtrr.find(0..0x1000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0x10_0000..0x10_1000), MemoryType::Uncachable);
+    }
+
+    #[test]
+    fn variable_ranges() {
+        // 0x8000_0000 - 0xffff_ffff (2GB) is UC, and 0xc000_0000 - 0xcfff_ffff
+        // (256MB) is WT within that.
+        let mtrr = Mtrr::from_values(
+            MemoryType::WriteBack,
+            [FIXED_WB; 11],
+            &[
+                (0x8000_0000, 0xf_8000_0000 | VALID),
+                (0xc000_0000 | 4, 0xf_f000_0000 | VALID),
+            ],
+        );
+
+        assert_eq!(mtrr.find(0x10_0000..0x20_0000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0x8000_0000..0x8020_0000), MemoryType::Uncachable);
+        assert_eq!(mtrr.find(0xc000_0000..0xc020_0000), MemoryType::Uncachable);
+
+        // Not covered by any variable range MTRR. The default type applies.
+        assert_eq!(
+            mtrr.find(0x1_0000_0000..0x1_0020_0000),
+            MemoryType::WriteBack
+        );
+        assert_eq!(
+            mtrr.find(0x80_0000_0000..0x80_0020_0000),
+            MemoryType::WriteBack
+        );
+    }
+
+    #[test]
+    fn overlapping_variable_ranges() {
+        // 0x1_0000_0000 - 0x1_3fff_ffff is WB, and overlapped by WT for the
+        // first 256MB, and by WC for the last 256MB.
+        let mtrr = Mtrr::from_values(
+            MemoryType::Uncachable,
+            [FIXED_WB; 11],
+            &[
+                (0x1_0000_0000 | 6, 0xf_c000_0000 | VALID),
+                (0x1_0000_0000 | 4, 0xf_f000_0000 | VALID),
+                (0x1_3000_0000 | 1, 0xf_f000_0000 | VALID),
+            ],
+        );
+
+        assert_eq!(
+            mtrr.find(0x1_0000_0000..0x1_0020_0000),
+            MemoryType::WriteThrough
+        );
+        assert_eq!(
+            mtrr.find(0x1_1000_0000..0x1_1020_0000),
+            MemoryType::WriteBack
+        );
+        assert_eq!(
+            mtrr.find(0x1_3000_0000..0x1_3020_0000),
+            MemoryType::Uncachable
+        );
+
+        // Partially covered. The default type (UC) applies to the rest.
+        assert_eq!(
+            mtrr.find(0x1_3fe0_0000..0x1_4020_0000),
+            MemoryType::Uncachable
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let mut raw = RawMtrrs {
+            enabled: false,
+            fixed_enabled: true,
+            default_memory_type: MemoryType::WriteBack,
+            fixed: [FIXED_WB; 11].map(|value| RawFixedMtrr { value }).into(),
+            variable: alloc::vec![RawVariableMtrr {
+                base: 6,
+                mask: 0xf_8000_0000 | VALID,
+            }],
+        };
+        let mtrr = Mtrr::from_raw(&raw);
+        assert_eq!(mtrr.find(0..0x1000), MemoryType::Uncachable);
+        assert_eq!(mtrr.find(0x10_0000..0x20_0000), MemoryType::Uncachable);
+
+        // The variable range MTRRs apply to the first 1MB when the fixed range
+        // MTRRs are disabled.
+        raw.enabled = true;
+        raw.fixed_enabled = false;
+        raw.default_memory_type = MemoryType::Uncachable;
+        let mtrr = Mtrr::from_raw(&raw);
+        assert_eq!(mtrr.find(0..0x1000), MemoryType::WriteBack);
+        assert_eq!(mtrr.find(0x8000_0000..0x8020_0000), MemoryType::Uncachable);
+    }
+
+    #[test]
+    fn valid_values() {
+        use x86::msr::{IA32_MTRR_DEF_TYPE, IA32_MTRR_FIX16K_A0000, IA32_MTRR_PHYSBASE1};
+        use x86::msr::{IA32_MTRR_PHYSMASK1, IA32_MTRRCAP};
+
+        assert!(is_valid_value(IA32_MTRR_DEF_TYPE, 0xc06, 39));
+        assert!(!is_valid_value(IA32_MTRR_DEF_TYPE, 0xd06, 39));
+        assert!(!is_valid_value(IA32_MTRR_DEF_TYPE, 0xc02, 39));
+        assert!(!is_valid_value(IA32_MTRR_DEF_TYPE, 0xc07, 39));
+
+        assert!(is_valid_value(IA32_MTRR_FIX16K_A0000, FIXED_WB, 39));
+        assert!(is_valid_value(
+            IA32_MTRR_FIX16K_A0000,
+            0x0101_0101_0000_0000,
+            39
+        ));
+        assert!(!is_valid_value(
+            IA32_MTRR_FIX16K_A0000,
+            0x0606_0606_0606_0603,
+            39
+        ));
+
+        assert!(is_valid_value(IA32_MTRR_PHYSBASE1, 0xc000_0000, 39));
+        assert!(!is_valid_value(IA32_MTRR_PHYSBASE1, 0xc000_0100, 39));
+        assert!(!is_valid_value(IA32_MTRR_PHYSBASE1, 0x80_c000_0000, 39));
+        assert!(is_valid_value(
+            IA32_MTRR_PHYSMASK1,
+            0x7f_c000_0000 | VALID,
+            39
+        ));
+        assert!(!is_valid_value(
+            IA32_MTRR_PHYSMASK1,
+            0xf_c000_0000 | VALID | 1,
+            39
+        ));
+        assert!(!is_valid_value(
+            IA32_MTRR_PHYSMASK1,
+            0xff_c000_0000 | VALID,
+            39
+        ));
+
+        assert!(!is_valid_value(IA32_MTRRCAP, 0, 39));
+    }
+}
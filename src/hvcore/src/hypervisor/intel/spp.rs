@@ -0,0 +1,155 @@
+//! This module implements the sub-page permission table (SPPT) for EPT-based
+//! sub-page write permissions (SPP).
+//!
+//! SPP write-protects a 4KB page at the granularity of 128-byte sub-pages. A
+//! guest write to a page whose EPT entry is not writable and has the SPP bit
+//! set is permitted if the SPPT permits writes to the sub-page, and causes an
+//! EPT violation otherwise. This protects small structures, such as IDT
+//! entries or function pointers, without causing VM-exit on every write to the
+//! rest of the page.
+//!
+//! The SPPT is a 4-level structure indexed by the GPA like EPTs, whose leaf
+//! entries are the sub-page permission vectors of 4KB pages. Non-leaf entries
+//! are allocated as pages are protected.
+//!
+//! SPP is not described in the SDM revision this module refers to. See the
+//! "Sub-Page Write Permissions" section of the revisions that describe it.
+
+use alloc::{boxed::Box, vec::Vec};
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SHIFT;
+
+use crate::hypervisor::{
+    platform_ops::{self, PaError},
+    support::zeroed_box,
+};
+
+/// The number of 128-byte sub-pages in a 4KB page.
+const SUB_PAGE_COUNT: usize = 32;
+
+/// The bit of non-leaf SPPT entries indicating that the entry is valid.
+const SPPT_VALID: u64 = 1 << 0;
+
+pub(crate) struct SubPagePermissionTable {
+    pml4: Box<Table>,
+    pdpt: Box<Table>,
+
+    /// The PDs allocated on demand, with the PDPT indexes they are referenced
+    /// by.
+    pds: Vec<(usize, Box<Table>)>,
+
+    /// The PTs (the tables of sub-page permission vectors) allocated on
+    /// demand, with the GPAs of the 2MB regions they cover.
+    pts: Vec<(u64, Box<Table>)>,
+}
+
+impl SubPagePermissionTable {
+    /// Creates an SPPT that covers the first 512GB of GPAs with no page
+    /// protected.
+    pub(crate) fn new() -> Result<Self, PaError> {
+        let mut pml4 = zeroed_box::<Table>();
+        let pdpt = zeroed_box::<Table>();
+        pml4.0[0] = table_entry(&pdpt)?;
+        Ok(Self {
+            pml4,
+            pdpt,
+            pds: Vec::new(),
+            pts: Vec::new(),
+        })
+    }
+
+    /// Returns the value of the SPPTP VMCS field for this SPPT.
+    pub(crate) fn pointer(&self) -> Result<u64, PaError> {
+        platform_ops::get().pa(self.pml4.as_ref() as *const _ as _)
+    }
+
+    /// Permits writes to the sub-pages of the 4KB page at `gpa` whose bits are
+    /// set in `writable`, where bit N is bytes `N * 128` to `N * 128 + 127`.
+    pub(crate) fn set(&mut self, gpa: u64, writable: u32) -> Result<(), PaError> {
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        let large_gpa = gpa & !((1 << 21) - 1);
+
+        if !self.pds.iter().any(|(index, _)| *index == pdpt_index) {
+            let pd = zeroed_box::<Table>();
+            self.pdpt.0[pdpt_index] = table_entry(&pd)?;
+            self.pds.push((pdpt_index, pd));
+        }
+        if !self.pts.iter().any(|(pa, _)| *pa == large_gpa) {
+            let pt = zeroed_box::<Table>();
+            let entry = table_entry(&pt)?;
+            let (_, pd) = self
+                .pds
+                .iter_mut()
+                .find(|(index, _)| *index == pdpt_index)
+                .unwrap();
+            pd.0[pd_index] = entry;
+            self.pts.push((large_gpa, pt));
+        }
+
+        let (_, pt) = self
+            .pts
+            .iter_mut()
+            .find(|(pa, _)| *pa == large_gpa)
+            .unwrap();
+        pt.0[pt_index] = permission_vector(writable);
+        Ok(())
+    }
+}
+
+/// Returns the valid non-leaf SPPT entry referencing `table`.
+fn table_entry(table: &Table) -> Result<u64, PaError> {
+    let pa = platform_ops::get().pa(table as *const _ as _)?;
+    Ok((pa >> BASE_PAGE_SHIFT << BASE_PAGE_SHIFT) | SPPT_VALID)
+}
+
+/// Returns the sub-page permission vector for `writable`. The write permission
+/// of the sub-page N is the bit 2N of the vector, and the odd bits are
+/// reserved.
+fn permission_vector(writable: u32) -> u64 {
+    (0..SUB_PAGE_COUNT)
+        .filter(|&sub_page| writable.get_bit(sub_page))
+        .fold(0, |vector, sub_page| vector | 1 << (sub_page * 2))
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::addr_of;
+
+    use super::*;
+    use crate::hypervisor::testing;
+
+    #[test]
+    fn permission_vectors() {
+        assert_eq!(permission_vector(0), 0);
+        assert_eq!(permission_vector(0b101), 0b1_0001);
+        assert_eq!(permission_vector(1 << 31), 1 << 62);
+        assert_eq!(permission_vector(u32::MAX), 0x5555_5555_5555_5555);
+    }
+
+    #[test]
+    fn set() {
+        testing::init();
+
+        let mut sppt = SubPagePermissionTable::new().unwrap();
+        assert_eq!(sppt.pointer().unwrap(), addr_of!(*sppt.pml4) as u64);
+        sppt.set(0x4020_3000, 0b11).unwrap();
+        sppt.set(0x4020_4000, 0).unwrap();
+        sppt.set(0x4040_0000, 1).unwrap();
+        assert_eq!(sppt.pds.len(), 1);
+        assert_eq!(sppt.pts.len(), 2);
+
+        let pd = &sppt.pds[0].1;
+        assert_eq!(sppt.pdpt.0[1], addr_of!(**pd) as u64 | SPPT_VALID);
+        let pt = &sppt.pts[0].1;
+        assert_eq!(pd.0[1], addr_of!(**pt) as u64 | SPPT_VALID);
+        assert_eq!(pt.0[3], 0b101);
+        assert_eq!(pt.0[4], 0);
+        assert_eq!(sppt.pts[1].1.0[0], 1);
+    }
+}
@@ -0,0 +1,184 @@
+//! This module implements the per-VM-exit cache of VMCS fields.
+//!
+//! VMREAD and VMWRITE are relatively expensive, and VM-exit handlers tend to
+//! read the same fields repeatedly, for example, CR4 and the access rights of
+//! CS to determine the paging mode. The cache reads each field at most once per
+//! VM-exit, and defers writes until right before VM-entry, skipping those that
+//! do not change the value, such as the guest RSP and RFLAGS on most VM-exits.
+//!
+//! The cache must be flushed before VM-entry with [`VmcsCache::flush`], and
+//! invalidated on VM-exit with [`VmcsCache::invalidate`]. Code that accesses
+//! the VMCS with VMREAD and VMWRITE directly must flush the cache before, and
+//! invalidate it after, so that neither side sees stale values.
+
+use core::cell::RefCell;
+
+use super::guest::{vmread, vmwrite};
+
+/// The number of fields cached per VM-exit. Fields beyond this are accessed
+/// without caching.
+const CAPACITY: usize = 16;
+
+#[derive(Debug, Default)]
+pub(crate) struct VmcsCache {
+    entries: RefCell<Entries>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Entry {
+    encoding: u32,
+    value: u64,
+    dirty: bool,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    len: usize,
+    items: [Entry; CAPACITY],
+}
+
+impl Entries {
+    fn find(&mut self, encoding: u32) -> Option<&mut Entry> {
+        self.items[..self.len]
+            .iter_mut()
+            .find(|entry| entry.encoding == encoding)
+    }
+
+    fn insert(&mut self, entry: Entry) -> bool {
+        let Some(slot) = self.items.get_mut(self.len) else {
+            return false;
+        };
+        *slot = entry;
+        self.len += 1;
+        true
+    }
+}
+
+impl VmcsCache {
+    /// Returns the value of the field `encoding`, reading it with VMREAD only
+    /// the first time in a VM-exit.
+    pub(crate) fn read(&self, encoding: u32) -> u64 {
+        self.read_with(encoding, vmread)
+    }
+
+    /// Sets the value of the field `encoding`, which is written with VMWRITE
+    /// on [`VmcsCache::flush`] if it differs from the current value.
+    pub(crate) fn write<T: Into<u64>>(&self, encoding: u32, value: T) {
+        self.write_with(encoding, value.into(), vmwrite);
+    }
+
+    /// Writes the changed fields into the current VMCS.
+    pub(crate) fn flush(&self) {
+        self.flush_with(vmwrite);
+    }
+
+    /// Forgets all fields. Changes not flushed are lost.
+    pub(crate) fn invalidate(&self) {
+        self.entries.borrow_mut().len = 0;
+    }
+
+    fn read_with(&self, encoding: u32, read: impl FnOnce(u32) -> u64) -> u64 {
+        let mut entries = self.entries.borrow_mut();
+        if let Some(entry) = entries.find(encoding) {
+            return entry.value;
+        }
+        let value = read(encoding);
+        let _ = entries.insert(Entry {
+            encoding,
+            value,
+            dirty: false,
+        });
+        value
+    }
+
+    fn write_with(&self, encoding: u32, value: u64, write: impl FnOnce(u32, u64)) {
+        let mut entries = self.entries.borrow_mut();
+        if let Some(entry) = entries.find(encoding) {
+            if entry.value != value {
+                entry.value = value;
+                entry.dirty = true;
+            }
+            return;
+        }
+        let entry = Entry {
+            encoding,
+            value,
+            dirty: true,
+        };
+        if !entries.insert(entry) {
+            write(encoding, value);
+        }
+    }
+
+    fn flush_with(&self, mut write: impl FnMut(u32, u64)) {
+        let mut entries = self.entries.borrow_mut();
+        let len = entries.len;
+        for entry in &mut entries.items[..len] {
+            if entry.dirty {
+                write(entry.encoding, entry.value);
+                entry.dirty = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, vec::Vec};
+    use core::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn read_once_and_write_changes() {
+        let vmcs = RefCell::new(BTreeMap::from([(0x681e, 0x1000u64), (0x681c, 0x2000)]));
+        let reads = RefCell::new(Vec::new());
+        let read = |encoding| {
+            reads.borrow_mut().push(encoding);
+            vmcs.borrow()[&encoding]
+        };
+        let cache = VmcsCache::default();
+
+        // RIP is read once.
+        assert_eq!(cache.read_with(0x681e, read), 0x1000);
+        assert_eq!(cache.read_with(0x681e, read), 0x1000);
+        assert_eq!(cache.read_with(0x681c, read), 0x2000);
+        assert_eq!(*reads.borrow(), [0x681e, 0x681c]);
+
+        // Only changed fields are written back.
+        let mut writes = Vec::new();
+        cache.write_with(0x681e, 0x1003, |_, _| unreachable!());
+        cache.write_with(0x681c, 0x2000, |_, _| unreachable!());
+        cache.flush_with(|encoding, value| writes.push((encoding, value)));
+        assert_eq!(writes, [(0x681e, 0x1003)]);
+        writes.clear();
+        cache.flush_with(|encoding, value| writes.push((encoding, value)));
+        assert!(writes.is_empty());
+
+        cache.invalidate();
+        assert_eq!(cache.read_with(0x681e, read), 0x1000);
+        assert_eq!(reads.borrow().len(), 3);
+    }
+
+    #[test]
+    fn overflow() {
+        let cache = VmcsCache::default();
+        for encoding in 0..CAPACITY as u32 {
+            let _ = cache.read_with(encoding, u64::from);
+        }
+        // A field beyond the capacity is read every time, and written at once.
+        let mut reads = 0;
+        for _ in 0..2 {
+            let _ = cache.read_with(0x100, |_| {
+                reads += 1;
+                0
+            });
+        }
+        assert_eq!(reads, 2);
+        let mut written = None;
+        cache.write_with(0x100, 1, |encoding, value| {
+            written = Some((encoding, value))
+        });
+        assert_eq!(written, Some((0x100, 1)));
+    }
+}
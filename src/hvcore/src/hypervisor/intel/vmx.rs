@@ -1,12 +1,16 @@
 //! This module implements enablement of Intel VMX.
 
 use alloc::boxed::Box;
+use bit_field::BitField;
 use derive_more::Debug;
+use x86::{controlregs::Cr4, vmx::vmcs::control::SecondaryControls};
 
 use crate::hypervisor::{
+    capabilities::UnsupportedFeature,
+    cet, hooks,
     host::Extension,
     intel::guest::{get_adjusted_cr0, get_adjusted_cr4},
-    platform_ops,
+    lbr, platform_ops, pmu, processor_trace, speculation,
     support::zeroed_box,
     x86_instructions::{cr0, cr0_write, cr4, cr4_write, rdmsr, wrmsr},
 };
@@ -17,6 +21,60 @@ pub(crate) struct Vmx {
 }
 
 impl Extension for Vmx {
+    fn check_support() -> Result<(), UnsupportedFeature> {
+        const IA32_FEATURE_CONTROL_LOCK_BIT_FLAG: u64 = 1 << 0;
+        const IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_FLAG: u64 = 1 << 2;
+        const REQUIRED_CONTROLS: [(u32, u32, &str); 3] = [
+            (
+                x86::msr::IA32_VMX_PROCBASED_CTLS,
+                1 << 28,
+                "Use MSR bitmaps",
+            ),
+            (
+                x86::msr::IA32_VMX_EXIT_CTLS,
+                1 << 9,
+                "Host address-space size",
+            ),
+            (x86::msr::IA32_VMX_ENTRY_CTLS, 1 << 9, "IA-32e mode guest"),
+        ];
+
+        // See: 23.6 DISCOVERING SUPPORT FOR VMX
+        if !x86::cpuid::cpuid!(0x1).ecx.get_bit(5) {
+            return Err(UnsupportedFeature::Vmx);
+        }
+
+        // VMXON fails if the MSR is locked without allowing VMX outside SMX.
+        // See: 23.7 ENABLING AND ENTERING VMX OPERATION
+        let feature_control = rdmsr(x86::msr::IA32_FEATURE_CONTROL);
+        if (feature_control & IA32_FEATURE_CONTROL_LOCK_BIT_FLAG) != 0
+            && (feature_control & IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_FLAG) == 0
+        {
+            return Err(UnsupportedFeature::VmxDisabledByFirmware);
+        }
+
+        // The TRUE capability MSRs report the same allowed 1-settings. Checking
+        // the others is sufficient.
+        // See: A.3 VM-EXECUTION CONTROLS
+        for (msr, control, name) in REQUIRED_CONTROLS {
+            let allowed1 = (rdmsr(msr) >> 32) as u32;
+            if allowed1 & control == 0 {
+                return Err(UnsupportedFeature::VmxControl { name });
+            }
+        }
+
+        // The UEFI version emulates INIT-SIPI-SIPI, after which the guest runs
+        // in real-mode. That requires unrestricted guest. Execute hooks are
+        // implemented with EPT.
+        let capabilities = VmxCapabilities::read();
+        if (cfg!(feature = "uefi") || !hooks::exec_hooks().is_empty()) && !capabilities.ept {
+            return Err(UnsupportedFeature::Ept);
+        }
+        if cfg!(feature = "uefi") && !capabilities.unrestricted_guest {
+            return Err(UnsupportedFeature::UnrestrictedGuest);
+        }
+        Ok(())
+    }
+
     fn enable(&mut self) {
         // The current CR0, CR4 and IA32_FEATURE_CONTROL MSR may not satisfy the
         // requirements for enabling VMX. Update them as required,
@@ -29,6 +87,13 @@ impl Extension for Vmx {
         // root operation" allowing the use of the other VMX instructions.
         vmxon(&mut self.vmxon_region);
     }
+
+    fn disable(&mut self) {
+        // Leave VMX operation, then clear CR4.VMXE that is no longer required.
+        // See: VMXOFF—Leave VMX Operation
+        unsafe { x86::bits64::vmx::vmxoff() }.unwrap();
+        cr4_write(cr4() & !Cr4::CR4_ENABLE_VMX);
+    }
 }
 
 impl Vmx {
@@ -53,6 +118,195 @@ impl Vmx {
     }
 }
 
+/// The optional VMX features the hypervisor uses, as reported by the VMX
+/// capability MSRs.
+///
+/// They are usually supported on bare metal, but not always when running nested
+/// under another hypervisor, such as KVM, VMware or Hyper-V. The hypervisor
+/// runs without unsupported ones instead of failing VM-entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VmxCapabilities {
+    /// Whether EPT is supported with the memory types and page sizes the
+    /// hypervisor uses.
+    pub(crate) ept: bool,
+
+    /// Whether unrestricted guest is supported. This requires `ept`.
+    pub(crate) unrestricted_guest: bool,
+
+    /// The secondary processor-based VM-execution controls allowed to be 1.
+    /// Zero if the secondary controls are not supported at all.
+    pub(crate) secondary_controls: u32,
+
+    /// Whether VMFUNC leaf 0 (EPTP switching) is supported. This requires `ept`.
+    pub(crate) eptp_switching: bool,
+
+    /// Whether mode-based execute control for EPT (MBEC) is supported. This
+    /// requires `ept`.
+    pub(crate) mbec: bool,
+
+    /// Whether sub-page write permissions for EPT (SPP) are supported. This
+    /// requires `ept`.
+    pub(crate) spp: bool,
+
+    /// Whether the HLT activity state is supported.
+    pub(crate) hlt_activity_state: bool,
+
+    /// Whether the processor supports CET and the "load CET state" VM-exit and
+    /// VM-entry controls.
+    pub(crate) cet: bool,
+
+    /// Whether the processor supports PT in VMX operation, and the controls
+    /// to switch IA32_RTIT_CTL on VM-exit and VM-entry and to conceal VMX from
+    /// PT.
+    pub(crate) processor_trace: bool,
+
+    /// Whether the processor supports architectural LBRs and the controls to
+    /// switch IA32_LBR_CTL on VM-exit and VM-entry.
+    pub(crate) arch_lbr: bool,
+
+    /// Whether the processor supports IA32_PERF_GLOBAL_CTRL and the controls
+    /// to load it on VM-exit and VM-entry.
+    pub(crate) perf_global_ctrl: bool,
+
+    /// Whether the processor supports IA32_SPEC_CTRL and the "virtualize
+    /// IA32_SPEC_CTRL" tertiary processor-based VM-execution control. If so,
+    /// the guest cannot disable the mitigations enabled at virtualization.
+    pub(crate) spec_ctrl_virtualization: bool,
+}
+
+impl VmxCapabilities {
+    /// Reads the VMX capability MSRs and logs optional features that are
+    /// not supported.
+    pub(crate) fn probe() -> Self {
+        const WANTED_SECONDARY_CONTROLS: [(SecondaryControls, &str); 5] = [
+            (SecondaryControls::ENABLE_EPT, "EPT"),
+            (SecondaryControls::UNRESTRICTED_GUEST, "Unrestricted guest"),
+            (SecondaryControls::ENABLE_RDTSCP, "RDTSCP"),
+            (SecondaryControls::ENABLE_INVPCID, "INVPCID"),
+            (SecondaryControls::ENABLE_XSAVES_XRSTORS, "XSAVES/XRSTORS"),
+        ];
+
+        let capabilities = Self::read();
+        for (control, name) in WANTED_SECONDARY_CONTROLS {
+            if capabilities.secondary_controls & control.bits() == 0 {
+                log::warn!("{name} is not supported and disabled");
+            }
+        }
+        if cet::is_supported() && !capabilities.cet {
+            log::warn!("CET state cannot be switched on VM-exit and VM-entry");
+        }
+        if processor_trace::is_supported() && !capabilities.processor_trace {
+            log::warn!("IA32_RTIT_CTL cannot be switched on VM-exit and VM-entry");
+        }
+        if pmu::isolated() && !capabilities.perf_global_ctrl {
+            log::warn!(
+                "IA32_PERF_GLOBAL_CTRL cannot be switched. Performance counters are not isolated"
+            );
+        }
+        capabilities
+    }
+
+    /// Reads the VMX capability MSRs.
+    pub(crate) fn read() -> Self {
+        const PROCBASED_CTLS_ACTIVATE_TERTIARY_CONTROLS: u32 = 1 << 17;
+        const PROCBASED_CTLS_ACTIVATE_SECONDARY_CONTROLS: u32 = 1 << 31;
+        const IA32_VMX_PROCBASED_CTLS3: u32 = 0x492;
+
+        // "Bits 63:32 indicate the allowed 1-settings of these controls."
+        // See: A.3.2 Primary Processor-Based VM-Execution Controls
+        // See: A.3.3 Secondary Processor-Based VM-Execution Controls
+        let primary_allowed1 = (rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS) >> 32) as u32;
+        let mut secondary_controls = 0;
+        if primary_allowed1 & PROCBASED_CTLS_ACTIVATE_SECONDARY_CONTROLS != 0 {
+            secondary_controls = (rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32) as u32;
+        }
+
+        // The EPT paging-structures are 4-level, use 2MB pages, and are
+        // write-back.
+        // See: A.10 VPID AND EPT CAPABILITIES
+        let ept_vpid_cap = rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP);
+        if !ept_vpid_cap.get_bit(6) || !ept_vpid_cap.get_bit(14) || !ept_vpid_cap.get_bit(16) {
+            secondary_controls &= !SecondaryControls::ENABLE_EPT.bits();
+        }
+
+        // "If the "unrestricted guest" VM-execution control is 1, the "enable EPT"
+        //  VM-execution control must also be 1."
+        // See: 27.2.1.1 VM-Execution Control Fields
+        if secondary_controls & SecondaryControls::ENABLE_EPT.bits() == 0 {
+            secondary_controls &= !(SecondaryControls::UNRESTRICTED_GUEST.bits()
+                | SecondaryControls::MODE_BASED_EPT.bits()
+                | SecondaryControls::SUB_PAGE_EPT.bits());
+        }
+
+        // "Bits 63:0 (...) indicate the allowed 1-settings of the VM-function
+        //  controls". Bit 0 is EPTP switching.
+        // See: A.11 VM FUNCTIONS
+        let eptp_switching = secondary_controls & SecondaryControls::ENABLE_EPT.bits() != 0
+            && secondary_controls & SecondaryControls::ENABLE_VM_FUNCTIONS.bits() != 0
+            && rdmsr(x86::msr::IA32_VMX_VMFUNC).get_bit(0);
+
+        // "Bits 8:6 report, as a bitmap, the activity states supported by the
+        //  implementation". Bit 6 is the HLT state.
+        // See: A.6 MISCELLANEOUS DATA
+        let hlt_activity_state = rdmsr(x86::msr::IA32_VMX_MISC).get_bit(6);
+
+        // "Bits 63:32 indicate the allowed 1-settings of these controls." Bit
+        // 28 of the VM-exit controls and bit 20 of the VM-entry controls are
+        // "load CET state".
+        // See: A.4.1 Primary VM-Exit Controls
+        // See: A.5 VM-ENTRY CONTROLS
+        let cet = cet::is_supported()
+            && rdmsr(x86::msr::IA32_VMX_EXIT_CTLS).get_bit(32 + 28)
+            && rdmsr(x86::msr::IA32_VMX_ENTRY_CTLS).get_bit(32 + 20);
+
+        // Bit 14 of IA32_VMX_MISC indicates that PT can be used in VMX
+        // operation. Bits 24 and 25 of the VM-exit controls are "conceal VMX
+        // from PT" and "clear IA32_RTIT_CTL", and bits 17 and 18 of the VM-entry
+        // controls are "conceal VMX from PT" and "load IA32_RTIT_CTL".
+        // See: A.6 MISCELLANEOUS DATA
+        let processor_trace = processor_trace::is_supported()
+            && rdmsr(x86::msr::IA32_VMX_MISC).get_bit(14)
+            && rdmsr(x86::msr::IA32_VMX_EXIT_CTLS).get_bits(32 + 24..=32 + 25) == 0b11
+            && rdmsr(x86::msr::IA32_VMX_ENTRY_CTLS).get_bits(32 + 17..=32 + 18) == 0b11;
+
+        // Bit 26 of the VM-exit controls is "clear IA32_LBR_CTL", and bit 21
+        // of the VM-entry controls is "load guest IA32_LBR_CTL".
+        let arch_lbr = lbr::is_arch_lbr_supported()
+            && rdmsr(x86::msr::IA32_VMX_EXIT_CTLS).get_bit(32 + 26)
+            && rdmsr(x86::msr::IA32_VMX_ENTRY_CTLS).get_bit(32 + 21);
+
+        // Bit 12 of the VM-exit controls and bit 13 of the VM-entry controls
+        // are "load IA32_PERF_GLOBAL_CTRL".
+        let perf_global_ctrl = pmu::is_global_ctrl_supported()
+            && rdmsr(x86::msr::IA32_VMX_EXIT_CTLS).get_bit(32 + 12)
+            && rdmsr(x86::msr::IA32_VMX_ENTRY_CTLS).get_bit(32 + 13);
+
+        // "Bits 63:0 (...) indicate the allowed 1-settings of these controls."
+        // Bit 7 of the tertiary controls is "virtualize IA32_SPEC_CTRL". The
+        // MSR exists only if the primary controls allow the tertiary ones.
+        // See: A.3.4 Tertiary Processor-Based VM-Execution Controls
+        let spec_ctrl_virtualization = speculation::is_spec_ctrl_supported()
+            && primary_allowed1 & PROCBASED_CTLS_ACTIVATE_TERTIARY_CONTROLS != 0
+            && rdmsr(IA32_VMX_PROCBASED_CTLS3).get_bit(7);
+
+        Self {
+            ept: secondary_controls & SecondaryControls::ENABLE_EPT.bits() != 0,
+            unrestricted_guest: secondary_controls & SecondaryControls::UNRESTRICTED_GUEST.bits()
+                != 0,
+            secondary_controls,
+            eptp_switching,
+            mbec: secondary_controls & SecondaryControls::MODE_BASED_EPT.bits() != 0,
+            spp: secondary_controls & SecondaryControls::SUB_PAGE_EPT.bits() != 0,
+            hlt_activity_state,
+            cet,
+            processor_trace,
+            arch_lbr,
+            perf_global_ctrl,
+            spec_ctrl_virtualization,
+        }
+    }
+}
+
 /// Logical representation of a VMXON region.
 #[derive(derive_deref::Deref, derive_deref::DerefMut)]
 struct Vmxon {
@@ -93,6 +347,6 @@ struct VmxonRaw {
 /// The wrapper of the VMXON instruction.
 fn vmxon(vmxon_region: &mut VmxonRaw) {
     let va = vmxon_region as *const _;
-    let pa = platform_ops::get().pa(va as *const _);
+    let pa = platform_ops::get().pa(va as *const _).unwrap();
     unsafe { x86::bits64::vmx::vmxon(pa).unwrap() };
 }
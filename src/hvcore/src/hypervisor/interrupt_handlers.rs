@@ -7,7 +7,13 @@ use x86::{bits64::rflags::RFlags, dtables::DescriptorTablePointer, segmentation:
 
 use crate::hypervisor::x86_instructions::cr2;
 
-use super::support::zeroed_box;
+use super::{
+    gdb_stub::{self, GdbRegisters, Resume, Signal},
+    gdt_tss::{IST_DOUBLE_FAULT, IST_MACHINE_CHECK, IST_NMI},
+    irq, machine_check,
+    support::zeroed_box,
+    watchdog,
+};
 
 /// Logical representation of the IDT.
 #[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
@@ -17,12 +23,23 @@ pub struct InterruptDescriptorTable {
 
 impl InterruptDescriptorTable {
     pub fn new(cs: SegmentSelector) -> Self {
+        const NMI: usize = 2;
+        const DF: usize = 8;
+        const MC: usize = 18;
+
         // Build the IDT. Each interrupt handler (ie. asm_interrupt_handlerN) is
-        // 16 byte long and can be located from asm_interrupt_handler0.
+        // 16 byte long and can be located from asm_interrupt_handler0. NMI, #DF
+        // and #MC switch to the stacks of the host TSS. See `gdt_tss`.
         let mut idt = zeroed_box::<InterruptDescriptorTableRaw>();
         for i in 0..idt.0.len() {
-            let handler = asm_interrupt_handler0 as usize + 0x10 * i;
-            idt.0[i] = InterruptDescriptorTableEntry::new(handler, cs);
+            let handler = asm_interrupt_handler0 as *const () as usize + 0x10 * i;
+            let ist = match i {
+                NMI => IST_NMI,
+                DF => IST_DOUBLE_FAULT,
+                MC => IST_MACHINE_CHECK,
+                _ => 0,
+            };
+            idt.0[i] = InterruptDescriptorTableEntry::new(handler, cs, ist);
         }
 
         Self { ptr: idt }
@@ -47,7 +64,7 @@ const _: () = assert!(core::mem::size_of::<InterruptDescriptorTableRaw>() == 409
 pub struct InterruptDescriptorTableEntry {
     offset_low: u16,
     selector: u16,
-    reserved_1: u8,
+    ist: u8,
     gate_type: u8,
     offset_high: u16,
     offset_upper: u32,
@@ -56,13 +73,13 @@ pub struct InterruptDescriptorTableEntry {
 const _: () = assert!(core::mem::size_of::<InterruptDescriptorTableEntry>() == 16);
 
 impl InterruptDescriptorTableEntry {
-    fn new(handler: usize, cs: SegmentSelector) -> Self {
+    fn new(handler: usize, cs: SegmentSelector, ist: u8) -> Self {
         // P=1, DPL=00b, S=0, type=1110b => type_attr=1000_1110b => 0x8E
         const INTERRUPT_GATE: u8 = 0x8E;
         Self {
             offset_low: handler as _,
             selector: cs.bits(),
-            reserved_1: 0,
+            ist,
             gate_type: INTERRUPT_GATE,
             offset_high: (handler >> 16) as _,
             offset_upper: (handler >> 32) as _,
@@ -99,11 +116,54 @@ struct HostExceptionStack {
     ss: u64,               // Hardware saved
 }
 
+impl From<&HostExceptionStack> for GdbRegisters {
+    fn from(stack: &HostExceptionStack) -> Self {
+        Self {
+            gprs: [
+                stack.rax, stack.rbx, stack.rcx, stack.rdx, stack.rsi, stack.rdi, stack.rbp,
+                stack.rsp, stack.r8, stack.r9, stack.r10, stack.r11, stack.r12, stack.r13,
+                stack.r14, stack.r15, stack.rip,
+            ],
+            eflags: stack.rflags.bits() as u32,
+            cs: stack.cs as u32,
+            ss: stack.ss as u32,
+        }
+    }
+}
+
 /// The host interrupt handler.
 #[unsafe(no_mangle)]
 extern "C" fn handle_host_exception(stack: *mut HostExceptionStack) {
+    const DEBUG_EXCEPTION: u64 = 1;
+    const NMI: u64 = 2;
+    const BREAKPOINT_EXCEPTION: u64 = 3;
+
     assert!(!stack.is_null());
-    let stack = unsafe { &*stack };
+    let stack = unsafe { &mut *stack };
+    let _guard = irq::InterruptHandlerGuard::enter();
+
+    // Let GDB debug the host if enabled. Single-step is done with RFLAGS.TF,
+    // which causes #DB after the next instruction returned to with IRETQ.
+    // See: 18.3.1.4 Single-Step Exception Condition
+    if matches!(
+        stack.exception_number,
+        DEBUG_EXCEPTION | BREAKPOINT_EXCEPTION
+    ) && gdb_stub::enabled()
+    {
+        let resume = gdb_stub::stop(Signal::Trap, &GdbRegisters::from(&*stack));
+        stack.rflags.set(RFlags::FLAGS_TF, resume == Resume::Step);
+        return;
+    }
+
+    // NMI sent by the watchdog to find where the processor is stuck.
+    if stack.exception_number == NMI && watchdog::take_nmi() {
+        log::error!("NMI from the watchdog: {stack:#x?}");
+        return;
+    }
+
+    if stack.exception_number == u64::from(machine_check::MC) {
+        machine_check::log_banks("the host");
+    }
     panic!(
         "Exception {} occurred in host: {stack:#x?}, cr2: {:#x?}",
         stack.exception_number,
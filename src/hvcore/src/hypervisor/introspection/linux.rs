@@ -0,0 +1,567 @@
+//! This module implements helpers for Linux guests.
+//!
+//! The kernel is located with IA32_LSTAR, which points to `entry_SYSCALL_64`
+//! in the kernel image. The image is mapped in the kernel text mapping with 2MB
+//! pages, and the kernel unmaps the rest of the mapping during boot. Thus, the
+//! image is the run of mapped 2MB blocks around the handler.
+//!
+//! Symbols are resolved with kallsyms, the compressed symbol table built into
+//! the kernel (CONFIG_KALLSYMS). Its tables are found with the signature of the
+//! token table, where the tokens "0" to "9" are adjacent, and the other tables
+//! are located relative to it. Variables such as `sys_call_table` are only
+//! included with CONFIG_KALLSYMS_ALL.
+//!
+//! See: arch/x86/kernel/head64.c, kernel/kallsyms.c and scripts/kallsyms.c
+
+use core::ops::Range;
+
+use alloc::{boxed::Box, vec::Vec};
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use super::IntrospectionError;
+use crate::hypervisor::{
+    gva::{self, GuestAddressSpace},
+    support::zeroed_box,
+};
+
+/// The kernel text mapping, where the image is mapped with or without KASLR.
+/// See: Documentation/arch/x86/x86_64/mm.rst
+const KERNEL_TEXT_MAPPING: Range<u64> = 0xffff_ffff_8000_0000..0xffff_ffff_c000_0000;
+
+/// The tokens "0" to "9" with their terminating nulls, which are single-byte
+/// tokens at the indexes of their characters in `kallsyms_token_table`.
+const DIGIT_TOKENS: &[u8] = b"0\x001\x002\x003\x004\x005\x006\x007\x008\x009\x00";
+
+/// The largest length of a symbol name (KSYM_NAME_LEN), including the type.
+const MAX_SYMBOL_LENGTH: u64 = 512;
+
+/// The largest number of `kallsyms_markers`, allowing for 1M symbols.
+const MAX_MARKERS: usize = 0x1000;
+
+/// The Linux kernel image in guest memory.
+#[derive(Debug, Clone)]
+pub struct LinuxKernel {
+    /// The address space the kernel was located in. The kernel is mapped at
+    /// the same GVA in all address spaces.
+    cr3: u64,
+    image: Range<u64>,
+}
+
+impl LinuxKernel {
+    /// Locates the kernel with the system call handler at `lstar`, in the
+    /// address space `cr3`.
+    pub(crate) fn locate(cr3: u64, lstar: u64) -> Result<Self, IntrospectionError> {
+        let space = gva::address_space(cr3).ok_or(IntrospectionError::MemoryUnavailable)?;
+        let image = find_image(&space, lstar).ok_or(IntrospectionError::KernelNotFound)?;
+        Ok(Self { cr3, image })
+    }
+
+    /// Returns the GVA of the kernel image, that is, `_text`.
+    pub fn base(&self) -> u64 {
+        self.image.start
+    }
+
+    /// Returns the size of the kernel image in memory, rounded up to 2MB.
+    pub fn size(&self) -> u64 {
+        self.image.end - self.image.start
+    }
+
+    /// Locates the kallsyms tables of the kernel. This scans the kernel image,
+    /// thus, callers should keep the result instead of calling this on each
+    /// VM-exit.
+    pub fn kallsyms(&self) -> Result<Kallsyms, IntrospectionError> {
+        let space = gva::address_space(self.cr3).ok_or(IntrospectionError::MemoryUnavailable)?;
+        let tables =
+            find_kallsyms(&space, &self.image).ok_or(IntrospectionError::KallsymsNotFound)?;
+        Ok(Kallsyms {
+            cr3: self.cr3,
+            tables,
+        })
+    }
+}
+
+/// The kallsyms tables of the kernel.
+#[derive(Debug, Clone)]
+pub struct Kallsyms {
+    cr3: u64,
+    tables: KallsymsTables,
+}
+
+impl Kallsyms {
+    /// Returns the GVA of the symbol `name`, such as `"do_sys_openat2"`.
+    /// Per-CPU variables are not supported.
+    pub fn resolve(&self, name: &str) -> Result<u64, IntrospectionError> {
+        let space = gva::address_space(self.cr3).ok_or(IntrospectionError::MemoryUnavailable)?;
+        resolve_symbol(&space, &self.tables, name)
+    }
+
+    /// Returns the GVA of `sys_call_table`, the array of the system call
+    /// handlers indexed by the system call number.
+    pub fn sys_call_table(&self) -> Result<u64, IntrospectionError> {
+        self.resolve("sys_call_table")
+    }
+}
+
+/// The locations of the kallsyms tables.
+#[derive(Debug, Clone)]
+struct KallsymsTables {
+    /// `kallsyms_num_syms`.
+    num_syms: u32,
+
+    /// The GVA of `kallsyms_names`.
+    names: u64,
+
+    /// `kallsyms_token_table` split into tokens.
+    tokens: Vec<Vec<u8>>,
+
+    /// The GVA of `kallsyms_offsets`.
+    offsets: u64,
+
+    /// `kallsyms_relative_base`.
+    relative_base: u64,
+}
+
+/// Returns the range of the image that contains `lstar`.
+fn find_image<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    lstar: u64,
+) -> Option<Range<u64>> {
+    let block_size = LARGE_PAGE_SIZE as u64;
+    let is_mapped = |block: u64| space.translate(block).is_some();
+    let block = lstar & !(block_size - 1);
+    if !KERNEL_TEXT_MAPPING.contains(&lstar) || !is_mapped(block) {
+        return None;
+    }
+
+    let mut start = block;
+    while start > KERNEL_TEXT_MAPPING.start && is_mapped(start - block_size) {
+        start -= block_size;
+    }
+    let mut end = block + block_size;
+    while end < KERNEL_TEXT_MAPPING.end && is_mapped(end) {
+        end += block_size;
+    }
+    Some(start..end)
+}
+
+/// Returns the locations of the kallsyms tables in `image`.
+///
+/// The tables are emitted by scripts/kallsyms.c with 8-byte alignment in the
+/// following order:
+/// - `kallsyms_offsets` and `kallsyms_relative_base` (before Linux 6.2)
+/// - `kallsyms_num_syms`
+/// - `kallsyms_names`
+/// - `kallsyms_markers`
+/// - `kallsyms_token_table`
+/// - `kallsyms_token_index`
+/// - `kallsyms_offsets` and `kallsyms_relative_base` (Linux 6.2 and later)
+fn find_kallsyms<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    image: &Range<u64>,
+) -> Option<KallsymsTables> {
+    let mut reader = PageReader::new(space);
+    let mut matched = 0;
+    let mut address = image.start;
+    while address < image.end {
+        let Some(byte) = reader.u8(address) else {
+            // Skip the unmapped page.
+            matched = 0;
+            address = (address | (BASE_PAGE_SIZE as u64 - 1)) + 1;
+            continue;
+        };
+        address += 1;
+        // "0" appears only at the start of the signature, so a mismatch may
+        // only restart the match.
+        matched = if byte == DIGIT_TOKENS[matched] {
+            matched + 1
+        } else {
+            usize::from(byte == DIGIT_TOKENS[0])
+        };
+        if matched == DIGIT_TOKENS.len() {
+            matched = 0;
+            let digits = address - DIGIT_TOKENS.len() as u64;
+            if let Some(tables) = kallsyms_at(&mut reader, image, digits) {
+                return Some(tables);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the locations of the kallsyms tables if the token "0" of
+/// `kallsyms_token_table` is at `digits`.
+fn kallsyms_at<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    image: &Range<u64>,
+    digits: u64,
+) -> Option<KallsymsTables> {
+    let (table, index, tokens) = token_table(reader, digits)?;
+    let (markers_start, markers) = markers(reader, table)?;
+    let (names, num_syms) = names(reader, markers_start, &markers)?;
+
+    // The offsets are followed by the relative base, which is within the image.
+    let size = (u64::from(num_syms) * 4).next_multiple_of(8);
+    let candidates = [Some(index + 256 * 2), names.checked_sub(16 + size)];
+    let (offsets, relative_base) = candidates.into_iter().flatten().find_map(|offsets| {
+        let relative_base = reader.u64(offsets + size)?;
+        image
+            .contains(&relative_base)
+            .then_some((offsets, relative_base))
+    })?;
+    Some(KallsymsTables {
+        num_syms,
+        names,
+        tokens,
+        offsets,
+        relative_base,
+    })
+}
+
+/// Returns the GVAs of `kallsyms_token_table` and `kallsyms_token_index`, and
+/// the tokens, if the token "0" is at `digits`.
+fn token_table<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    digits: u64,
+) -> Option<(u64, u64, Vec<Vec<u8>>)> {
+    // The token index follows the last token, which is at the index 0xff.
+    let mut end = digits;
+    for _ in 0x30..0x100 {
+        end += reader.string(end)?.len() as u64 + 1;
+    }
+    let index = end.next_multiple_of(8);
+    let table = digits.checked_sub(u64::from(reader.u16(index + 0x30 * 2)?))?;
+
+    // Every offset in the index must match the tokens.
+    let mut tokens = Vec::with_capacity(256);
+    let mut token = table;
+    for i in 0..256 {
+        if u64::from(reader.u16(index + i * 2)?) != token - table {
+            return None;
+        }
+        let bytes = reader.string(token)?;
+        token += bytes.len() as u64 + 1;
+        tokens.push(bytes);
+    }
+    (token == end).then_some((table, index, tokens))
+}
+
+/// Returns the GVA of `kallsyms_markers` that precedes the token table at
+/// `table`, and the markers, that is, the offsets of every 256th symbol in
+/// `kallsyms_names`.
+fn markers<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    table: u64,
+) -> Option<(u64, Vec<u32>)> {
+    // The markers start with 0 and increase. With an odd number of them, the
+    // last one is followed by padding.
+    let mut position = table.checked_sub(4)?;
+    if reader.u32(position)? == 0 {
+        position -= 4;
+    }
+    let mut markers = Vec::new();
+    loop {
+        let marker = reader.u32(position)?;
+        if markers.last().is_some_and(|&next| marker >= next) || markers.len() == MAX_MARKERS {
+            return None;
+        }
+        markers.push(marker);
+        if marker == 0 {
+            break;
+        }
+        position -= 4;
+    }
+    markers.reverse();
+    position.is_multiple_of(8).then_some((position, markers))
+}
+
+/// Returns the GVA of `kallsyms_names` that precedes the markers at
+/// `markers_start`, and the number of symbols.
+fn names<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    markers_start: u64,
+    markers: &[u32],
+) -> Option<(u64, u32)> {
+    // The names start at least the last marker before the markers, and the
+    // symbols after the last marker take up to 2 + MAX_SYMBOL_LENGTH bytes.
+    let latest = markers_start.checked_sub(u64::from(*markers.last()?))? & !0x7;
+    let earliest = latest.saturating_sub(256 * (2 + MAX_SYMBOL_LENGTH));
+    for names in (earliest..=latest).rev().step_by(8) {
+        // The number of symbols precedes the names.
+        let Some(num_syms) = reader.u32(names - 8) else {
+            continue;
+        };
+        if num_syms.div_ceil(256) as usize == markers.len()
+            && names_end(reader, names, num_syms, markers) == Some(markers_start)
+        {
+            return Some((names, num_syms));
+        }
+    }
+    None
+}
+
+/// Returns the 8-byte aligned end of `num_syms` symbols at `names`, if every
+/// 256th symbol is at the offset in `markers`.
+fn names_end<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    names: u64,
+    num_syms: u32,
+    markers: &[u32],
+) -> Option<u64> {
+    let mut position = names;
+    for index in 0..num_syms {
+        if index.is_multiple_of(256) && position - names != u64::from(markers[index as usize / 256])
+        {
+            return None;
+        }
+        let (tokens, len) = name_entry(reader, position)?;
+        position = tokens + len;
+    }
+    Some(position.next_multiple_of(8))
+}
+
+/// Returns the GVA and the number of the tokens of the `kallsyms_names` entry
+/// at `position`. The entry starts with the number of the tokens, which takes
+/// 2 bytes if bit 7 of the first byte is set (Linux 6.1 and later).
+fn name_entry<F: Fn(u64) -> Option<u64>>(
+    reader: &mut PageReader<'_, F>,
+    position: u64,
+) -> Option<(u64, u64)> {
+    let len = u64::from(reader.u8(position)?);
+    if len & 0x80 == 0 {
+        Some((position + 1, len))
+    } else {
+        let high = u64::from(reader.u8(position + 1)?);
+        Some((position + 2, (len & 0x7f) | (high << 7)))
+    }
+}
+
+/// Returns the GVA of the symbol `name`.
+fn resolve_symbol<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    tables: &KallsymsTables,
+    name: &str,
+) -> Result<u64, IntrospectionError> {
+    let malformed = IntrospectionError::MalformedImage;
+    let mut reader = PageReader::new(space);
+    let mut symbol = Vec::new();
+    let mut position = tables.names;
+    for index in 0..tables.num_syms {
+        let (tokens, len) = name_entry(&mut reader, position).ok_or(malformed)?;
+        symbol.clear();
+        for token in tokens..tokens + len {
+            let token = reader.u8(token).ok_or(malformed)?;
+            symbol.extend_from_slice(&tables.tokens[usize::from(token)]);
+        }
+        // The first character is the type of the symbol, such as 'T' for text.
+        if symbol.get(1..) == Some(name.as_bytes()) {
+            let offset = reader
+                .u32(tables.offsets + u64::from(index) * 4)
+                .ok_or(malformed)?;
+            return Ok(symbol_address(tables.relative_base, offset));
+        }
+        position = tokens + len;
+    }
+    Err(IntrospectionError::SymbolNotFound)
+}
+
+/// Returns the address of the symbol at `offset` in `kallsyms_offsets`.
+///
+/// With CONFIG_KALLSYMS_ABSOLUTE_PERCPU, negative offsets are relative to
+/// `relative_base - 1` and the others are absolute per-CPU addresses.
+/// Otherwise, offsets are relative to `relative_base` and, in practice, smaller
+/// than the kernel text mapping, thus, positive.
+fn symbol_address(relative_base: u64, offset: u32) -> u64 {
+    let offset = offset.cast_signed();
+    if offset < 0 {
+        relative_base - 1 + u64::from(offset.unsigned_abs())
+    } else {
+        relative_base + u64::from(offset.cast_unsigned())
+    }
+}
+
+/// Reads guest memory a page at a time, so that sequential small reads
+/// translate each page only once.
+struct PageReader<'a, F: Fn(u64) -> Option<u64>> {
+    space: &'a GuestAddressSpace<F>,
+
+    /// The GVA of the page in `bytes`, if any.
+    page: Option<u64>,
+    bytes: Box<[u8; BASE_PAGE_SIZE]>,
+}
+
+impl<'a, F: Fn(u64) -> Option<u64>> PageReader<'a, F> {
+    fn new(space: &'a GuestAddressSpace<F>) -> Self {
+        Self {
+            space,
+            page: None,
+            bytes: zeroed_box::<[u8; BASE_PAGE_SIZE]>(),
+        }
+    }
+
+    fn u8(&mut self, gva: u64) -> Option<u8> {
+        let page_mask = BASE_PAGE_SIZE as u64 - 1;
+        let page = gva & !page_mask;
+        if self.page != Some(page) {
+            self.page = None;
+            self.space.read(page, self.bytes.as_mut_slice())?;
+            self.page = Some(page);
+        }
+        Some(self.bytes[(gva & page_mask) as usize])
+    }
+
+    fn u16(&mut self, gva: u64) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8(gva)?, self.u8(gva + 1)?]))
+    }
+
+    fn u32(&mut self, gva: u64) -> Option<u32> {
+        Some(u32::from(self.u16(gva)?) | (u32::from(self.u16(gva + 2)?) << 16))
+    }
+
+    fn u64(&mut self, gva: u64) -> Option<u64> {
+        Some(u64::from(self.u32(gva)?) | (u64::from(self.u32(gva + 4)?) << 32))
+    }
+
+    /// Reads the null-terminated string at `gva`, excluding the null.
+    fn string(&mut self, gva: u64) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for address in gva..gva + MAX_SYMBOL_LENGTH {
+            match self.u8(address)? {
+                0 => return Some(bytes),
+                byte => bytes.push(byte),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String};
+
+    use super::*;
+    use crate::hypervisor::gva::tests::FakeMemory;
+
+    const BASE: u64 = 0xffff_ffff_9a00_0000;
+    const BASE_GPA: u64 = 0x100_0000;
+
+    /// The number of pages at `BASE` holding kallsyms.
+    const PAGES: u64 = 4;
+
+    /// Builds a kernel image of two 2MB blocks, where only the first pages of
+    /// the blocks are mapped, with kallsyms of `symbols` at `BASE + 0x100`.
+    fn image(symbols: &[(String, u64)], before_linux_6_2: bool) -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        for page in 0..PAGES {
+            memory.map(BASE + page * 0x1000, BASE_GPA + page * 0x1000, true);
+        }
+        memory.map(BASE + 0x20_0000, BASE_GPA + PAGES * 0x1000, true);
+        memory.write(BASE_GPA, &[0; (PAGES as usize + 1) * BASE_PAGE_SIZE]);
+
+        // Each token is the character of its index, except the token 0.
+        let tokens: Vec<Vec<u8>> = (0..=255u8)
+            .map(|i| {
+                if i == 0 {
+                    b"zz".to_vec()
+                } else {
+                    alloc::vec![i]
+                }
+            })
+            .collect();
+        let mut names = Vec::new();
+        let mut markers = Vec::new();
+        let mut offsets = Vec::new();
+        for (i, (name, address)) in symbols.iter().enumerate() {
+            if i.is_multiple_of(256) {
+                markers.push(u32::try_from(names.len()).unwrap());
+            }
+            let len = name.len();
+            if len < 0x80 {
+                names.push(len as u8);
+            } else {
+                names.extend([(len as u8) | 0x80, (len >> 7) as u8]);
+            }
+            names.extend(name.bytes());
+            offsets.push(if before_linux_6_2 {
+                u32::try_from(address - BASE).unwrap()
+            } else {
+                (-i32::try_from(address - (BASE - 1)).unwrap()).cast_unsigned()
+            });
+        }
+
+        let mut blob = alloc::vec![0u8; 0x100];
+        let align = |blob: &mut Vec<u8>| blob.resize(blob.len().next_multiple_of(8), 0);
+        let put_offsets = |blob: &mut Vec<u8>| {
+            blob.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+            align(blob);
+            blob.extend(BASE.to_le_bytes());
+        };
+        if before_linux_6_2 {
+            put_offsets(&mut blob);
+        }
+        blob.extend(u32::try_from(symbols.len()).unwrap().to_le_bytes());
+        align(&mut blob);
+        blob.extend(&names);
+        align(&mut blob);
+        blob.extend(markers.iter().flat_map(|marker| marker.to_le_bytes()));
+        align(&mut blob);
+        let table = blob.len();
+        let mut index = Vec::new();
+        for token in &tokens {
+            index.push(u16::try_from(blob.len() - table).unwrap());
+            blob.extend(token);
+            blob.push(0);
+        }
+        align(&mut blob);
+        blob.extend(index.iter().flat_map(|offset| offset.to_le_bytes()));
+        if !before_linux_6_2 {
+            put_offsets(&mut blob);
+        }
+        assert!(blob.len() <= PAGES as usize * BASE_PAGE_SIZE);
+        memory.write(BASE_GPA, &blob);
+        memory
+    }
+
+    fn symbols() -> Vec<(String, u64)> {
+        let mut symbols = alloc::vec![
+            (String::from("T_text"), BASE),
+            (String::from("Tentry_SYSCALL_64"), BASE + 0x20_0040),
+            (String::from("Dsys_call_table"), BASE + 0x1_2340),
+            (format!("t{}", "a".repeat(200)), BASE + 0x200),
+        ];
+        symbols.extend((0..300).map(|i| (format!("tsym_{i}"), BASE + 0x1000 + i * 0x10)));
+        symbols
+    }
+
+    #[test]
+    fn locate_image() {
+        let memory = image(&symbols(), false);
+        let space = memory.address_space();
+        assert_eq!(
+            find_image(&space, BASE + 0x20_0040),
+            Some(BASE..BASE + 0x40_0000)
+        );
+        assert_eq!(find_image(&space, BASE + 0x40_0040), None);
+        assert_eq!(find_image(&space, 0xffff_f800_0000_0000), None);
+    }
+
+    #[test]
+    fn resolve() {
+        for before_linux_6_2 in [false, true] {
+            let symbols = symbols();
+            let memory = image(&symbols, before_linux_6_2);
+            let space = memory.address_space();
+            let tables = find_kallsyms(&space, &(BASE..BASE + 0x40_0000)).unwrap();
+            assert_eq!(tables.num_syms, 304);
+
+            for (name, address) in &symbols {
+                assert_eq!(resolve_symbol(&space, &tables, &name[1..]), Ok(*address));
+            }
+            for name in ["sys_call", "sys_call_table_", "sym_300"] {
+                assert_eq!(
+                    resolve_symbol(&space, &tables, name),
+                    Err(IntrospectionError::SymbolNotFound)
+                );
+            }
+        }
+    }
+}
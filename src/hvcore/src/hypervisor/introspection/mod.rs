@@ -0,0 +1,33 @@
+//! This module implements helpers to inspect the guest operating system, such
+//! as locating kernel functions by name so that hooks can target them.
+//!
+//! Guest memory is read with [`super::gva`], thus, these helpers are only
+//! available for 64-bit guests when guest memory is accessible.
+
+pub mod linux;
+pub mod windows;
+
+/// The error type for introspection helpers.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntrospectionError {
+    #[error("guest memory is not accessible")]
+    MemoryUnavailable,
+
+    #[error("the kernel image is not found")]
+    KernelNotFound,
+
+    #[error("the image is malformed or not mapped")]
+    MalformedImage,
+
+    #[error("the export is not found")]
+    ExportNotFound,
+
+    #[error("the export is forwarded to another module")]
+    ForwardedExport,
+
+    #[error("the kallsyms tables are not found")]
+    KallsymsNotFound,
+
+    #[error("the symbol is not found")]
+    SymbolNotFound,
+}
@@ -0,0 +1,238 @@
+//! This module implements helpers for Windows guests.
+//!
+//! The kernel (ntoskrnl.exe) is located with IA32_LSTAR, which points to the
+//! system call handler in the kernel image. The image base is the nearest page
+//! below it that starts with PE headers of an image covering the handler.
+//! Exports are then resolved with the export directory of the image.
+//!
+//! See: <https://learn.microsoft.com/en-us/windows/win32/debug/pe-format>
+
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::IntrospectionError;
+use crate::hypervisor::gva::{self, GuestAddressSpace};
+
+/// The largest distance from the kernel image base to the system call handler.
+const MAX_IMAGE_SIZE: u64 = 0x400_0000;
+
+/// The Windows kernel image in guest memory.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowsKernel {
+    /// The address space the kernel was located in. The kernel is mapped at
+    /// the same GVA in all address spaces.
+    cr3: u64,
+    base: u64,
+}
+
+impl WindowsKernel {
+    /// Locates the kernel with the system call handler at `lstar`, in the
+    /// address space `cr3`.
+    pub(crate) fn locate(cr3: u64, lstar: u64) -> Result<Self, IntrospectionError> {
+        let space = gva::address_space(cr3).ok_or(IntrospectionError::MemoryUnavailable)?;
+        let base = find_image_base(&space, lstar).ok_or(IntrospectionError::KernelNotFound)?;
+        Ok(Self { cr3, base })
+    }
+
+    /// Returns the GVA of the kernel image.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Returns the GVA of the function or variable the kernel exports as
+    /// `name`, such as `"NtCreateFile"`.
+    pub fn resolve_export(&self, name: &str) -> Result<u64, IntrospectionError> {
+        let space = gva::address_space(self.cr3).ok_or(IntrospectionError::MemoryUnavailable)?;
+        resolve_export(&space, self.base, name)
+    }
+}
+
+/// Returns the base of the image that contains `address`.
+fn find_image_base<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    address: u64,
+) -> Option<u64> {
+    let page = address & !(BASE_PAGE_SIZE as u64 - 1);
+    (0..MAX_IMAGE_SIZE)
+        .step_by(BASE_PAGE_SIZE)
+        .map_while(|distance| page.checked_sub(distance))
+        .find(|&base| {
+            nt_headers(space, base)
+                .and_then(|nt_headers| space.read_u32(nt_headers + SIZE_OF_IMAGE))
+                .is_some_and(|size| address - base < u64::from(size))
+        })
+}
+
+/// The offsets in IMAGE_NT_HEADERS64.
+const OPTIONAL_HEADER: u64 = 0x18;
+const SIZE_OF_IMAGE: u64 = OPTIONAL_HEADER + 0x38;
+const EXPORT_DIRECTORY: u64 = OPTIONAL_HEADER + 0x70;
+
+/// Returns the GVA of IMAGE_NT_HEADERS64 of the image at `base`, if the image
+/// is a valid PE32+ image.
+fn nt_headers<F: Fn(u64) -> Option<u64>>(space: &GuestAddressSpace<F>, base: u64) -> Option<u64> {
+    const IMAGE_DOS_SIGNATURE: u16 = 0x5a4d; // "MZ"
+    const IMAGE_NT_SIGNATURE: u32 = 0x4550; // "PE\0\0"
+    const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+    if space.read_u16(base)? != IMAGE_DOS_SIGNATURE {
+        return None;
+    }
+    // IMAGE_DOS_HEADER::e_lfanew. The headers are within the first page.
+    let e_lfanew = space.read_u32(base + 0x3c)?;
+    if u64::from(e_lfanew) >= BASE_PAGE_SIZE as u64 {
+        return None;
+    }
+    let nt_headers = base + u64::from(e_lfanew);
+    if space.read_u32(nt_headers)? != IMAGE_NT_SIGNATURE
+        || space.read_u16(nt_headers + OPTIONAL_HEADER)? != IMAGE_NT_OPTIONAL_HDR64_MAGIC
+    {
+        return None;
+    }
+    Some(nt_headers)
+}
+
+/// Returns the GVA of the export `name` of the image at `base`.
+fn resolve_export<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    base: u64,
+    name: &str,
+) -> Result<u64, IntrospectionError> {
+    let malformed = IntrospectionError::MalformedImage;
+    let nt_headers = nt_headers(space, base).ok_or(malformed)?;
+    let directory_rva = space
+        .read_u32(nt_headers + EXPORT_DIRECTORY)
+        .ok_or(malformed)?;
+    let directory_size = space
+        .read_u32(nt_headers + EXPORT_DIRECTORY + 4)
+        .ok_or(malformed)?;
+    if directory_rva == 0 {
+        return Err(IntrospectionError::ExportNotFound);
+    }
+
+    // IMAGE_EXPORT_DIRECTORY
+    let directory = base + u64::from(directory_rva);
+    let read_rva = |offset| {
+        space
+            .read_u32(directory + offset)
+            .map(|rva| base + u64::from(rva))
+    };
+    let number_of_names = space.read_u32(directory + 0x18).ok_or(malformed)?;
+    let functions = read_rva(0x1c).ok_or(malformed)?;
+    let names = read_rva(0x20).ok_or(malformed)?;
+    let ordinals = read_rva(0x24).ok_or(malformed)?;
+
+    // The names are sorted in the ascending order.
+    let (mut low, mut high) = (0, u64::from(number_of_names));
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let name_rva = space.read_u32(names + middle * 4).ok_or(malformed)?;
+        match compare_name(space, base + u64::from(name_rva), name).ok_or(malformed)? {
+            core::cmp::Ordering::Less => low = middle + 1,
+            core::cmp::Ordering::Greater => high = middle,
+            core::cmp::Ordering::Equal => {
+                let ordinal = space.read_u16(ordinals + middle * 2).ok_or(malformed)?;
+                let function_rva = space
+                    .read_u32(functions + u64::from(ordinal) * 4)
+                    .ok_or(malformed)?;
+                // An RVA within the export directory points to the name of the
+                // export this one is forwarded to.
+                if (directory_rva..directory_rva + directory_size).contains(&function_rva) {
+                    return Err(IntrospectionError::ForwardedExport);
+                }
+                return Ok(base + u64::from(function_rva));
+            }
+        }
+    }
+    Err(IntrospectionError::ExportNotFound)
+}
+
+/// Compares the null-terminated string at `gva` with `name`.
+fn compare_name<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    gva: u64,
+    name: &str,
+) -> Option<core::cmp::Ordering> {
+    // The terminating null is the smallest, so a prefix compares less.
+    for (address, expected) in (gva..).zip(name.bytes().chain([0])) {
+        let mut byte = [0u8];
+        space.read(address, &mut byte)?;
+        if byte[0] != expected || byte[0] == 0 {
+            return Some(byte[0].cmp(&expected));
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::gva::tests::FakeMemory;
+
+    const BASE: u64 = 0xffff_f804_1000_0000;
+    const BASE_GPA: u64 = 0x20_0000;
+
+    /// Builds a PE32+ image of 3 pages that exports `ExAllocatePool2`,
+    /// `KeBugCheckEx` and `NtClose`, where `NtClose` is forwarded.
+    fn image() -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        for page in 0..3 {
+            memory.map(BASE + page * 0x1000, BASE_GPA + page * 0x1000, true);
+        }
+        let put_u32 = |memory: &mut FakeMemory, rva: u64, value: u32| {
+            memory.write(BASE_GPA + rva, &value.to_le_bytes());
+        };
+
+        memory.write(BASE_GPA, b"MZ");
+        put_u32(&mut memory, 0x3c, 0x80);
+        memory.write(BASE_GPA + 0x80, b"PE\0\0");
+        memory.write(BASE_GPA + 0x98, &0x20bu16.to_le_bytes());
+        put_u32(&mut memory, 0x80 + SIZE_OF_IMAGE, 0x3000);
+        put_u32(&mut memory, 0x80 + EXPORT_DIRECTORY, 0x1000);
+        put_u32(&mut memory, 0x80 + EXPORT_DIRECTORY + 4, 0x100);
+
+        put_u32(&mut memory, 0x1018, 3);
+        put_u32(&mut memory, 0x101c, 0x1100);
+        put_u32(&mut memory, 0x1020, 0x1200);
+        put_u32(&mut memory, 0x1024, 0x1300);
+        let exports = [
+            (0x1400, &b"ExAllocatePool2\0"[..], 0x2010),
+            (0x1410, b"KeBugCheckEx\0", 0x2020),
+            (0x1420, b"NtClose\0", 0x1050),
+        ];
+        for (i, (name_rva, name, function_rva)) in (0u16..).zip(exports) {
+            let index = u64::from(i);
+            put_u32(&mut memory, 0x1100 + index * 4, function_rva);
+            put_u32(&mut memory, 0x1200 + index * 4, name_rva);
+            memory.write(BASE_GPA + 0x1300 + index * 2, &i.to_le_bytes());
+            memory.write(BASE_GPA + u64::from(name_rva), name);
+        }
+        memory
+    }
+
+    #[test]
+    fn locate_and_resolve() {
+        let memory = image();
+        let space = memory.address_space();
+        assert_eq!(find_image_base(&space, BASE + 0x2800), Some(BASE));
+        assert_eq!(find_image_base(&space, BASE + 0x3000), None);
+
+        assert_eq!(
+            resolve_export(&space, BASE, "ExAllocatePool2"),
+            Ok(BASE + 0x2010)
+        );
+        assert_eq!(
+            resolve_export(&space, BASE, "KeBugCheckEx"),
+            Ok(BASE + 0x2020)
+        );
+        assert_eq!(
+            resolve_export(&space, BASE, "NtClose"),
+            Err(IntrospectionError::ForwardedExport)
+        );
+        for name in ["KeBugCheck", "KeBugCheckExx", "A", "Z"] {
+            assert_eq!(
+                resolve_export(&space, BASE, name),
+                Err(IntrospectionError::ExportNotFound)
+            );
+        }
+    }
+}
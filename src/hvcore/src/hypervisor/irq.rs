@@ -0,0 +1,157 @@
+//! This module implements the interrupt hygiene of the host.
+//!
+//! The host handles VM-exits with interrupts disabled. VM-exit clears RFLAGS.IF
+//! on Intel, and #VMEXIT clears GIF on AMD, but the host does not rely on that
+//! and disables interrupts explicitly with [`ExitHandlerGuard`], which also
+//! detects re-entrance into VM-exit handling on the same processor.
+//!
+//! Code that runs in host interrupt handlers, such as [`super::gdb_stub`], may
+//! interrupt any host code, including one holding the allocator lock. Such code
+//! must not allocate or block, or the processor deadlocks with itself. In debug
+//! builds, [`debug_assert_may_block`] checks that the current processor does
+//! not run a host interrupt handler, and that interrupts are still disabled if
+//! it handles a VM-exit.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86::bits64::rflags::{self, RFlags};
+
+use super::{
+    apic_id,
+    percpu::{MAX_PROCESSORS, PerCpu},
+};
+
+/// Marks the current processor as handling a VM-exit while alive.
+#[derive(Debug)]
+pub(crate) struct ExitHandlerGuard {
+    id: usize,
+}
+
+impl ExitHandlerGuard {
+    /// Disables interrupts and marks the processor `id` as handling a VM-exit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the processor already handles a VM-exit.
+    pub(crate) fn enter(id: usize) -> Self {
+        unsafe { x86::irq::disable() };
+        assert!(
+            IN_EXIT_HANDLER.set(id),
+            "Re-entered VM-exit handling on processor #{id}"
+        );
+        Self { id }
+    }
+}
+
+impl Drop for ExitHandlerGuard {
+    fn drop(&mut self) {
+        debug_assert!(
+            !interrupts_enabled(),
+            "Interrupts were enabled during VM-exit handling"
+        );
+        IN_EXIT_HANDLER.clear(self.id);
+    }
+}
+
+/// Marks the current processor as running a host interrupt handler while
+/// alive.
+#[derive(Debug)]
+pub(crate) struct InterruptHandlerGuard {
+    id: Option<usize>,
+}
+
+impl InterruptHandlerGuard {
+    pub(crate) fn enter() -> Self {
+        let id = apic_id::current_processor_id();
+        if let Some(id) = id {
+            let _ = IN_INTERRUPT_HANDLER.set(id);
+        }
+        Self { id }
+    }
+}
+
+impl Drop for InterruptHandlerGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            IN_INTERRUPT_HANDLER.clear(id);
+        }
+    }
+}
+
+/// Asserts in debug builds that the current processor may allocate memory or
+/// wait for a lock.
+pub(crate) fn debug_assert_may_block() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let Some(id) = apic_id::current_processor_id() else {
+        return;
+    };
+    assert!(
+        !IN_INTERRUPT_HANDLER.get(id),
+        "Blocking in a host interrupt handler on processor #{id}"
+    );
+    assert!(
+        !IN_EXIT_HANDLER.get(id) || !interrupts_enabled(),
+        "Blocking with interrupts enabled in VM-exit handling on processor #{id}"
+    );
+}
+
+fn interrupts_enabled() -> bool {
+    rflags::read().contains(RFlags::FLAGS_IF)
+}
+
+/// A flag for each processor.
+struct PerCpuFlags(PerCpu<AtomicBool>);
+
+impl PerCpuFlags {
+    const fn new() -> Self {
+        Self(PerCpu::new(
+            [const { AtomicBool::new(false) }; MAX_PROCESSORS],
+        ))
+    }
+
+    /// Sets the flag of the processor `id`, and returns `false` if it was
+    /// already set. Processors with larger IDs are not tracked.
+    fn set(&self, id: usize) -> bool {
+        self.0
+            .get(id)
+            .is_none_or(|flag| !flag.swap(true, Ordering::Relaxed))
+    }
+
+    fn clear(&self, id: usize) {
+        if let Some(flag) = self.0.get(id) {
+            flag.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, id: usize) -> bool {
+        self.0
+            .get(id)
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+}
+
+static IN_EXIT_HANDLER: PerCpuFlags = PerCpuFlags::new();
+static IN_INTERRUPT_HANDLER: PerCpuFlags = PerCpuFlags::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_cpu_flags() {
+        let flags = PerCpuFlags::new();
+        assert!(flags.set(1));
+        assert!(flags.get(1));
+        assert!(!flags.get(0));
+        assert!(!flags.set(1));
+        flags.clear(1);
+        assert!(!flags.get(1));
+        assert!(flags.set(1));
+
+        assert!(flags.set(MAX_PROCESSORS));
+        assert!(flags.set(MAX_PROCESSORS));
+        assert!(!flags.get(MAX_PROCESSORS));
+    }
+}
@@ -0,0 +1,86 @@
+//! This module implements the handling of last branch records (LBRs), so that
+//! the records of the guest do not have the branches of the host mixed in.
+
+use alloc::{vec, vec::Vec};
+use bit_field::BitField;
+
+use super::x86_instructions::rdmsr;
+
+/// A branch recorded by the processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastBranch {
+    /// The address of the branch instruction.
+    pub from: u64,
+
+    /// The address of the branch target.
+    pub to: u64,
+}
+
+/// Where the branch records of the guest are while the host runs.
+///
+/// On Intel, IA32_DEBUGCTL and IA32_LBR_CTL are cleared on VM-exit, and the LBR
+/// MSRs keep the records of the guest. Only architectural LBRs are read, as the
+/// number and the MSRs of model-specific LBRs vary by processor model. On AMD,
+/// LBR virtualization swaps the LBR MSRs on VMRUN and #VMEXIT, and only the
+/// last branch is saved.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum GuestLastBranches {
+    /// The records are not available.
+    Unavailable,
+
+    /// The records are in the architectural LBR MSRs of the processor.
+    ArchLbrMsrs,
+
+    /// The last branch saved by the processor on VM-exit.
+    Saved(LastBranch),
+}
+
+impl GuestLastBranches {
+    /// Returns the records, from the most recent one.
+    pub(crate) fn read(self) -> Vec<LastBranch> {
+        match self {
+            Self::Unavailable => Vec::new(),
+            Self::ArchLbrMsrs => (0..rdmsr(IA32_LBR_DEPTH) as u32)
+                .map(|index| LastBranch {
+                    from: rdmsr(IA32_LBR_0_FROM_IP + index),
+                    to: rdmsr(IA32_LBR_0_TO_IP + index),
+                })
+                .filter(|branch| branch.from != 0)
+                .collect(),
+            Self::Saved(branch) if branch.from == 0 => Vec::new(),
+            Self::Saved(branch) => vec![branch],
+        }
+    }
+}
+
+/// Returns `true` if the processor supports architectural LBRs.
+pub(crate) fn is_arch_lbr_supported() -> bool {
+    // See: CPUID—CPU Identification, Leaf 07H
+    x86::cpuid::cpuid!(0x7, 0x0).edx.get_bit(19)
+}
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+pub(crate) const IA32_LBR_CTL: u32 = 0x14ce;
+const IA32_LBR_DEPTH: u32 = 0x14cf;
+const IA32_LBR_0_FROM_IP: u32 = 0x1500;
+const IA32_LBR_0_TO_IP: u32 = 0x1600;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_saved() {
+        let branch = LastBranch {
+            from: 0xffff_f800_1234_5678,
+            to: 0xffff_f800_1234_0000,
+        };
+        assert_eq!(GuestLastBranches::Unavailable.read(), Vec::new());
+        assert_eq!(GuestLastBranches::Saved(branch).read(), vec![branch]);
+        assert_eq!(
+            GuestLastBranches::Saved(LastBranch { from: 0, to: 0 }).read(),
+            Vec::new()
+        );
+    }
+}
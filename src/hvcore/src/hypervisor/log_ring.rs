@@ -0,0 +1,87 @@
+//! This module implements the ring buffer that keeps the most recent log
+//! output, so that it can be retrieved where serial output is unavailable, for
+//! example, from crash dumps. See [`super::crash_dump`].
+
+use core::fmt;
+
+use spin::Mutex;
+
+/// The size of the ring buffer in bytes.
+pub const LOG_RING_SIZE: usize = 0x4000;
+
+/// The most recent bytes written, overwriting the oldest ones.
+pub(crate) struct Ring<const N: usize> {
+    bytes: [u8; N],
+
+    /// The total number of bytes written. `written % N` is the next position.
+    written: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            written: 0,
+        }
+    }
+
+    /// Returns the kept bytes, oldest first, in two parts.
+    pub(crate) fn as_slices(&self) -> (&[u8], &[u8]) {
+        if self.written <= N {
+            (&self.bytes[..self.written], &[])
+        } else {
+            let (newer, older) = self.bytes.split_at(self.written % N);
+            (older, newer)
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for Ring<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.bytes[self.written % N] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Appends the formatted `args` to the ring buffer.
+pub(crate) fn write(args: fmt::Arguments<'_>) {
+    let _ = fmt::Write::write_fmt(&mut *LOG_RING.lock(), args);
+}
+
+/// Writes the contents of the ring buffer, oldest first. Writes nothing if the
+/// ring buffer is being written, as this may be called when the system has
+/// crashed while a processor held the lock.
+pub fn write_recent(out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(ring) = LOG_RING.try_lock() else {
+        return Ok(());
+    };
+    let (older, newer) = ring.as_slices();
+    for part in [older, newer] {
+        // The oldest line may be cut in the middle of a UTF-8 sequence.
+        for chunk in part.utf8_chunks() {
+            out.write_str(chunk.valid())?;
+        }
+    }
+    Ok(())
+}
+
+static LOG_RING: Mutex<Ring<LOG_RING_SIZE>> = Mutex::new(Ring::new());
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+
+    #[test]
+    fn wrap_around() {
+        let mut ring = Ring::<8>::new();
+        ring.write_str("abc").unwrap();
+        assert_eq!(ring.as_slices(), (&b"abc"[..], &b""[..]));
+        ring.write_str("defghij").unwrap();
+        assert_eq!(ring.as_slices(), (&b"cdefgh"[..], &b"ij"[..]));
+    }
+}
@@ -0,0 +1,114 @@
+//! This module implements logging of machine-check errors.
+//!
+//! #MC is always intercepted while the guest runs. The host logs the error
+//! reporting banks of the machine-check architecture (MCA), and then reflects
+//! #MC to the guest, whose handler reads and clears the banks as it would
+//! without the hypervisor. On Intel, a machine check during VM-entry is
+//! handled the same way. A machine check in the host is logged before the host
+//! panics.
+//!
+//! Without this, a hardware error ends in the shutdown state or a host panic
+//! with no record of its cause.
+
+use core::fmt;
+
+use bit_field::BitField;
+
+use super::x86_instructions::rdmsr;
+
+/// The vector of #MC.
+pub(crate) const MC: u8 = 18;
+
+// See: 16.3.1 Machine-Check Global Control MSRs
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+
+// See: 16.3.2 Error-Reporting Register Banks
+const IA32_MC0_STATUS: u32 = 0x401;
+const IA32_MC0_ADDR: u32 = 0x402;
+const IA32_MC0_MISC: u32 = 0x403;
+
+/// Logs the global status and the banks with a valid error, with `context`
+/// telling where the machine check occurred.
+pub(crate) fn log_banks(context: &str) {
+    if !is_supported() {
+        log::error!("Machine check in {context}. MCA is not supported");
+        return;
+    }
+
+    let bank_count = rdmsr(IA32_MCG_CAP).get_bits(0..=7) as u32;
+    log::error!(
+        "Machine check in {context}. MCG_STATUS: {:#x}",
+        rdmsr(IA32_MCG_STATUS)
+    );
+    for bank in 0..bank_count {
+        let status = BankStatus(rdmsr(IA32_MC0_STATUS + bank * 4));
+        if !status.valid() {
+            continue;
+        }
+        let addr = status.addr_valid().then(|| rdmsr(IA32_MC0_ADDR + bank * 4));
+        let misc = status.misc_valid().then(|| rdmsr(IA32_MC0_MISC + bank * 4));
+        log::error!("MC{bank}_STATUS: {status}, ADDR: {addr:#x?}, MISC: {misc:#x?}");
+    }
+}
+
+/// Returns `true` if the processor supports MCA.
+fn is_supported() -> bool {
+    // See: Table 1-20. More on Feature Information Returned in the EDX Register
+    x86::cpuid::cpuid!(0x1).edx.get_bit(14)
+}
+
+/// The value of IA32_MCi_STATUS.
+// See: Figure 16-6. IA32_MCi_STATUS Register
+#[derive(Clone, Copy)]
+struct BankStatus(u64);
+
+impl BankStatus {
+    fn valid(self) -> bool {
+        self.0.get_bit(63)
+    }
+
+    fn misc_valid(self) -> bool {
+        self.0.get_bit(59)
+    }
+
+    fn addr_valid(self) -> bool {
+        self.0.get_bit(58)
+    }
+}
+
+impl fmt::Display for BankStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} (MCA error code {:#06x}, model specific {:#06x}",
+            self.0,
+            self.0.get_bits(0..=15),
+            self.0.get_bits(16..=31)
+        )?;
+        for (bit, name) in [(62, "OVER"), (61, "UC"), (60, "EN"), (57, "PCC")] {
+            if self.0.get_bit(bit) {
+                write!(f, " {name}")?;
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn bank_status() {
+        let status = BankStatus(1 << 63 | 1 << 61 | 1 << 58 | 0x1234_0150);
+        assert!(status.valid());
+        assert!(status.addr_valid());
+        assert!(!status.misc_valid());
+        assert_eq!(
+            status.to_string(),
+            "0xa400000012340150 (MCA error code 0x0150, model specific 0x1234 UC)"
+        );
+    }
+}
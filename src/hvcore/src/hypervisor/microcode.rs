@@ -0,0 +1,111 @@
+//! This module implements the handling of late microcode updates by the guest.
+
+use alloc::vec;
+
+use super::{
+    SHARED_HOST_DATA,
+    config::MicrocodeUpdatePolicy,
+    gva,
+    x86_instructions::{rdmsr, wrmsr},
+};
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+pub(crate) const IA32_BIOS_UPDT_TRIG: u32 = 0x79;
+pub(crate) const IA32_BIOS_SIGN_ID: u32 = 0x8b;
+
+/// The MSRs writes to which are intercepted.
+pub(crate) const INTERCEPTED_WRITES: [u32; 2] = [IA32_BIOS_UPDT_TRIG, IA32_BIOS_SIGN_ID];
+
+/// The largest update the host copies, far above the size of actual updates.
+const MAX_UPDATE_SIZE: usize = 0x40_0000;
+
+/// Handles WRMSR of the update data at `address` to IA32_BIOS_UPDT_TRIG by
+/// the guest at `rip`, with the guest CR3 `cr3` if the guest is in 64-bit
+/// mode. Returns `true` if the processor loaded the update.
+///
+/// When the host runs on its own identity mapping, it cannot use the guest
+/// linear address, and copies the update data into its own buffer. The update
+/// is dropped with a warning then if the guest is not in 64-bit mode.
+pub(crate) fn load_update(address: u64, rip: u64, cr3: Option<u64>) -> bool {
+    if SHARED_HOST_DATA.get().unwrap().config.microcode_update == MicrocodeUpdatePolicy::Block {
+        log::warn!("Blocked a microcode update at {address:#x} from {rip:#x}");
+        return false;
+    }
+
+    let old_revision = revision();
+    if SHARED_HOST_DATA.get().unwrap().pt.is_none() {
+        // The host shares the kernel address space with the guest, where the
+        // update data is.
+        wrmsr(IA32_BIOS_UPDT_TRIG, address);
+    } else {
+        let Some(space) = cr3.and_then(gva::address_space) else {
+            log::warn!("Dropped a microcode update from {rip:#x} outside 64-bit mode");
+            return false;
+        };
+
+        // The update data must be 16-byte aligned. Copy it into `u128`s.
+        // See: 10.11.6 Microcode Update Loader
+        let size = space
+            .read_u32(address + 28)
+            .zip(space.read_u32(address + 32))
+            .and_then(|(data_size, total_size)| update_size(data_size, total_size));
+        let Some(size) = size else {
+            log::warn!("Dropped a microcode update at {address:#x} with an invalid header");
+            return false;
+        };
+        let mut buffer = vec![0u128; size.div_ceil(16)];
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), size) };
+        if space.read(address, bytes).is_none() {
+            log::warn!("Dropped a microcode update at {address:#x} not mapped");
+            return false;
+        }
+        wrmsr(IA32_BIOS_UPDT_TRIG, buffer.as_ptr() as u64);
+    }
+    let new_revision = revision();
+    log::info!("Microcode update from {rip:#x}: revision {old_revision:#x} -> {new_revision:#x}");
+    new_revision != old_revision
+}
+
+/// Returns the revision of the loaded microcode update.
+///
+/// See: 10.11.7 Update Signature and Verification
+fn revision() -> u32 {
+    wrmsr(IA32_BIOS_SIGN_ID, 0);
+    let _ = x86::cpuid::cpuid!(0x1);
+    (rdmsr(IA32_BIOS_SIGN_ID) >> 32) as u32
+}
+
+/// Returns the size of the update given the Data Size and Total Size fields of
+/// its header, or `None` if they are invalid.
+///
+/// See: 10.11.1 Microcode Update
+fn update_size(data_size: u32, total_size: u32) -> Option<usize> {
+    const HEADER_SIZE: usize = 48;
+
+    // "If this value is 00000000H, then the microcode update encrypted data
+    //  is 2000 bytes (or 2048 bytes total)."
+    if data_size == 0 {
+        return Some(2048);
+    }
+    let (data_size, total_size) = (data_size as usize, total_size as usize);
+    (total_size >= data_size + HEADER_SIZE
+        && total_size.is_multiple_of(1024)
+        && total_size <= MAX_UPDATE_SIZE)
+        .then_some(total_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_sizes() {
+        assert_eq!(update_size(0, 0), Some(2048));
+        assert_eq!(update_size(0x2_fc00 - 48, 0x2_fc00), Some(0x2_fc00));
+        assert_eq!(update_size(0x1000, 0x1000), None);
+        assert_eq!(update_size(0x1000, 0x1234), None);
+        assert_eq!(update_size(0x1000, 0x80_0000), None);
+    }
+}
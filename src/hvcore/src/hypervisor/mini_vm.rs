@@ -0,0 +1,157 @@
+//! This module implements the vendor-neutral interface of mini VMs.
+//!
+//! A mini VM runs a small payload, such as firmware probing code, in isolation
+//! from the pass-through guest. The guest gives a physically contiguous memory
+//! blob with [`HC_RUN_MINI_VM_REAL`] or [`HC_RUN_MINI_VM_LONG`]. The host then
+//! creates another VMCS with EPTs that map only the blob at GPA 0, runs the
+//! payload on the current processor until the first VM-exit, and returns to
+//! the guest with the result.
+//!
+//! The payload starts with all general purpose registers and RSP cleared. Any
+//! exception, I/O and MSR access, and instructions that cause VM-exit
+//! unconditionally, such as CPUID, end the run. Payloads should end with HLT,
+//! and keep interrupts disabled, as an external interrupt ends the run too.
+//!
+//! [`HC_RUN_MINI_VM_REAL`]: super::hypercall::HC_RUN_MINI_VM_REAL
+//! [`HC_RUN_MINI_VM_LONG`]: super::hypercall::HC_RUN_MINI_VM_LONG
+
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use super::paging_structures::IDENTITY_MAP_SIZE;
+
+/// The largest blob a mini VM can run.
+pub const MAX_BLOB_SIZE: u64 = LARGE_PAGE_SIZE as u64;
+
+/// The processor mode a payload starts in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiniVmMode {
+    /// 16-bit real-mode with all segment bases 0. Requires unrestricted guest.
+    Real,
+
+    /// 64-bit mode with paging. The blob must start with the PML4 of the
+    /// paging structures that map the payload. Segments are flat.
+    Long,
+}
+
+/// The payload to run in a mini VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiniVmRequest {
+    /// The GPA of the blob. Must be 4KB aligned.
+    pub blob_gpa: u64,
+
+    /// The size of the blob in bytes. Must be 4KB aligned and up to
+    /// [`MAX_BLOB_SIZE`].
+    pub blob_size: u64,
+
+    pub mode: MiniVmMode,
+
+    /// The RIP the payload starts at.
+    pub entry: u64,
+}
+
+impl MiniVmRequest {
+    /// Checks that the blob can be mapped into a mini VM.
+    pub(crate) fn validate(&self) -> Result<(), MiniVmError> {
+        let page_size = BASE_PAGE_SIZE as u64;
+        if !self.blob_gpa.is_multiple_of(page_size)
+            || !self.blob_size.is_multiple_of(page_size)
+            || self.blob_size == 0
+            || self.blob_size > MAX_BLOB_SIZE
+            || self.blob_gpa.saturating_add(self.blob_size) > IDENTITY_MAP_SIZE
+        {
+            return Err(MiniVmError::InvalidBlob);
+        }
+        Ok(())
+    }
+}
+
+/// Why a mini VM stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiniVmExit {
+    /// The payload executed HLT. This is the normal way for payloads to end.
+    Hlt,
+
+    /// The payload executed VMCALL.
+    Hypercall,
+
+    /// An exception occurred in the payload.
+    Exception { vector: u8 },
+
+    /// The payload accessed a GPA outside the blob.
+    EptViolation { gpa: u64 },
+
+    /// The payload entered the shutdown state, for example, by a triple fault.
+    Shutdown,
+
+    /// The payload did not end within the time budget.
+    Timeout,
+
+    /// NMI occurred while running the payload. It is reflected to the guest.
+    Nmi,
+
+    /// Any other VM-exit, with the architecture specific exit reason.
+    Other { reason: u32 },
+}
+
+impl MiniVmExit {
+    /// Returns the code of this exit, and the vector, GPA or exit reason, which
+    /// are returned to the guest in RDX and R9.
+    pub fn to_raw(self) -> (u64, u64) {
+        match self {
+            Self::Hlt => (0, 0),
+            Self::Hypercall => (1, 0),
+            Self::Exception { vector } => (2, u64::from(vector)),
+            Self::EptViolation { gpa } => (3, gpa),
+            Self::Shutdown => (4, 0),
+            Self::Timeout => (5, 0),
+            Self::Nmi => (6, 0),
+            Self::Other { reason } => (7, u64::from(reason)),
+        }
+    }
+}
+
+/// The error type for mini VM hypercalls. The value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum MiniVmError {
+    #[error("mini VMs are not supported on this processor")]
+    Unsupported = 1,
+
+    #[error("the blob is not aligned, empty, too large or not mapped")]
+    InvalidBlob = 2,
+
+    #[error("the processor could not enter the mini VM")]
+    EntryFailed = 3,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_validation() {
+        let request = MiniVmRequest {
+            blob_gpa: 0x1000_0000,
+            blob_size: 0x2000,
+            mode: MiniVmMode::Real,
+            entry: 0,
+        };
+        assert_eq!(request.validate(), Ok(()));
+
+        for (blob_gpa, blob_size) in [
+            (0x1000_0010, 0x2000),
+            (0x1000_0000, 0x2010),
+            (0x1000_0000, 0),
+            (0x1000_0000, MAX_BLOB_SIZE + 0x1000),
+            (IDENTITY_MAP_SIZE - 0x1000, 0x2000),
+            (!0xfff, 0x2000),
+        ] {
+            let request = MiniVmRequest {
+                blob_gpa,
+                blob_size,
+                ..request
+            };
+            assert_eq!(request.validate(), Err(MiniVmError::InvalidBlob));
+        }
+    }
+}
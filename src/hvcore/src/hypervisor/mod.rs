@@ -1,64 +1,206 @@
 //! This module implements the platform agnostic hypervisor core.
 
-#[cfg(not(test))]
+pub mod acpi;
+#[cfg(not(any(test, feature = "testing")))]
 pub mod allocator;
+#[cfg(feature = "amd")]
 mod amd;
+mod ap_startup;
+mod apic_base;
 mod apic_id;
+pub mod backtrace;
+pub mod benchmark;
+pub mod boot_info;
+pub mod builder;
+pub mod capabilities;
+mod cet;
+pub mod config;
+pub mod crash_dump;
+mod decoder;
+pub mod deterministic_time;
+pub mod devirtualize;
+pub mod ept_views;
+mod event_injection;
+pub mod exception_policy;
+mod exit_dispatch;
+pub mod exit_handlers;
+pub mod exit_profile;
+pub mod exit_stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod fuzzing;
+mod gdb_stub;
 pub mod gdt_tss;
+mod gva;
+pub mod hooks;
 mod host;
+pub mod hypercall;
+mod hypercall_auth;
+mod hyperv;
+#[cfg(feature = "intel")]
 mod intel;
 pub mod interrupt_handlers;
+pub mod introspection;
+mod irq;
+pub mod lbr;
+pub mod log_ring;
+mod machine_check;
+mod microcode;
+pub mod mini_vm;
+mod msr_monitor;
+mod mwait;
 pub mod paging_structures;
 pub mod panic;
+pub mod panic_buffer;
+mod percpu;
+pub mod phys_read;
+pub mod phys_scan;
 pub mod platform_ops;
-mod registers;
+mod pmu;
+mod pool;
+pub mod processor_trace;
+pub mod pv_clock;
+pub mod registers;
 mod segment;
+pub mod self_test;
 mod serial_logger;
+pub mod shutdown;
+mod smi;
+pub mod snapshot;
+mod speculation;
+pub mod status;
 mod support;
 mod switch_stack;
+mod system_state;
+pub mod table_integrity;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod topology;
+pub mod tsc_scaling;
+mod umip;
+mod vmx_hiding;
+pub mod watchdog;
 mod x86_instructions;
+mod xstate;
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
+use bit_field::BitField;
 use spin::Once;
-use x86::cpuid::cpuid;
+use x86::{bits64::rflags::RFlags, cpuid::cpuid};
 
 use crate::{GdtTss, PagingStructures, hypervisor::registers::Registers};
 
-use self::interrupt_handlers::InterruptDescriptorTable;
+use self::{
+    capabilities::UnsupportedFeature, config::HvConfig,
+    interrupt_handlers::InterruptDescriptorTable, percpu::MAX_PROCESSORS,
+    system_state::SystemState,
+};
 
 /// Hyperjacks the current system by virtualizing all logical processors on this
 /// system.
-pub fn virtualize_system(shared_host: SharedHostData) {
-    serial_logger::init(log::LevelFilter::Info);
+///
+/// # Errors
+///
+/// Returns [`UnsupportedFeature`] if the processor lacks a feature the
+/// hypervisor requires. The system is left unvirtualized in that case.
+pub fn virtualize_system(shared_host: SharedHostData) -> Result<(), UnsupportedFeature> {
+    serial_logger::init(
+        shared_host
+            .config
+            .log_level
+            .unwrap_or(log::LevelFilter::Info),
+    );
     log::info!("Virtualizing the all processors");
 
+    if let Some(vendor) = underlying_hypervisor() {
+        log::warn!("Running under another hypervisor {vendor:?}. Some features may be disabled");
+    }
+    if let Err(e) = capabilities::check_support() {
+        log::error!("The processor is not supported: {e}");
+        return Err(e);
+    }
+
     apic_id::init();
     let _ = SHARED_HOST_DATA.call_once(|| shared_host);
+    table_integrity::init();
+    msr_monitor::init();
+    topology::init();
+    benchmark::measure_native();
 
     // Virtualize each logical processor.
-    platform_ops::get().run_on_all_processors(|| {
-        // Take a snapshot of current register values. This will be the initial
-        // state of the guest _including RIP_. This means that the guest starts execution
-        // right after this function call. Think of it as the setjmp() C standard
-        // function.
-        let registers = Registers::capture_current();
-
-        // In the first run, our hypervisor is not installed and the branch is
-        // taken. After starting the guest, the second run, the hypervisor is already
-        // installed and we will bail out.
-        if !is_our_hypervisor_present() {
-            log::info!("Virtualizing the current processor");
-
-            // We are about to execute host code with newly allocated stack.
-            // This is required because the guest will start executing with the
-            // current stack. If we do not change the stack for the host, as soon
-            // as the guest starts, it will smash host's stack.
-            switch_stack::jump_with_new_stack(host::main, &registers);
-        }
-        log::info!("Virtualized the current processor");
-    });
+    platform_ops::get().run_on_all_processors(&virtualize_current_processor);
 
     log::info!("Virtualized the all processors");
+    self_test::run();
+    benchmark::measure_virtualized();
+    exit_profile::enter_steady_state();
+    Ok(())
+}
+
+/// Virtualizes all logical processors again after [`devirtualize::devirtualize_system`].
+///
+/// The hypervisor reuses the [`SharedHostData`] given to [`virtualize_system`].
+/// Only the Windows driver calls this on resume from sleep. The UEFI driver
+/// does not hook S3 resume, and the processors resume without the hypervisor.
+pub fn revirtualize_system() {
+    log::info!("Re-virtualizing the all processors");
+    platform_ops::get().run_on_all_processors(&virtualize_current_processor);
+    log::info!("Re-virtualized the all processors");
+}
+
+/// Virtualizes the current logical processor if it is not virtualized yet.
+///
+/// This is for processors that come online after [`virtualize_system`], such
+/// as hot-added ones. [`virtualize_system`] must have been called.
+pub fn virtualize_current_processor() {
+    assert!(SHARED_HOST_DATA.is_completed());
+    let id = apic_id::register_current();
+    if id >= MAX_PROCESSORS {
+        log::error!("The processor #{id} exceeds {MAX_PROCESSORS} processors. Not virtualizing it");
+        return;
+    }
+    if id >= SHARED_HOST_DATA.get().unwrap().gdts.len() {
+        log::error!("No host GDT for the processor #{id}. Not virtualizing it");
+        return;
+    }
+
+    // Disable interrupts until the guest resumes, so that an interrupt cannot
+    // change the state between the snapshots below and the state the guest
+    // starts with. See `host::main` for how it could. The guest starts with
+    // interrupts disabled, and enables them again below.
+    let interrupts_enabled = x86::bits64::rflags::read().contains(RFlags::FLAGS_IF);
+    unsafe { x86::irq::disable() };
+    let expected_state = SystemState::capture();
+
+    // Take a snapshot of current register values. This will be the initial
+    // state of the guest _including RIP_. This means that the guest starts execution
+    // right after this function call. Think of it as the setjmp() C standard
+    // function.
+    let registers = Registers::capture_current();
+
+    // In the first run, our hypervisor is not installed and the branch is
+    // taken. After starting the guest, the second run, the hypervisor is already
+    // installed and we will bail out.
+    if !is_our_hypervisor_present() {
+        log::info!("Virtualizing the current processor");
+
+        // The guest resumes with the shadow stack as of the snapshot, which is
+        // not changed by the balanced calls made since then.
+        cet::capture_guest_ssp(id);
+
+        // We are about to execute host code with newly allocated stack.
+        // This is required because the guest will start executing with the
+        // current stack. If we do not change the stack for the host, as soon
+        // as the guest starts, it will smash host's stack.
+        switch_stack::jump_with_new_stack(host::main, &registers);
+    }
+    log::info!("Virtualized the current processor");
+
+    for (name, before, after) in expected_state.mismatches(&SystemState::capture()) {
+        log::error!("{name} changed across virtualization: {before:#x} -> {after:#x}");
+    }
+    if interrupts_enabled {
+        unsafe { x86::irq::enable() };
+    }
 }
 
 /// A collection of data that the host depends on for its entire lifespan.
@@ -72,9 +214,13 @@ pub struct SharedHostData {
     /// host and the guest.
     pub idt: Option<InterruptDescriptorTable>,
 
-    /// The GDT and TSS for the host for each logical processor. If `None`,
-    /// the current GDTs and TSSes are used for both the host and the guest.
-    pub gdts: Option<Vec<GdtTss>>,
+    /// The GDT and TSS for the host for each logical processor, indexed by the
+    /// processor ID, created with [`GdtTss::new_for_host`]. Processors without
+    /// one are not virtualized.
+    pub gdts: Vec<GdtTss>,
+
+    /// Optional features and policies of the hypervisor.
+    pub config: HvConfig,
 }
 
 static SHARED_HOST_DATA: Once<SharedHostData> = Once::new();
@@ -85,6 +231,21 @@ const OUR_HV_VENDOR_NAME_EBX: u32 = u32::from_ne_bytes(*b"Bare");
 const OUR_HV_VENDOR_NAME_ECX: u32 = u32::from_ne_bytes(*b"viso");
 const OUR_HV_VENDOR_NAME_EDX: u32 = u32::from_ne_bytes(*b"r!  ");
 
+/// Returns the vendor name of the hypervisor the system runs under, if any.
+fn underlying_hypervisor() -> Option<String> {
+    // "ECX Bit 31: Not Used. Always returns 0." on bare metal, and is set by
+    // hypervisors by convention.
+    // See: Table 1-17. Feature Information Returned in the ECX Register
+    if !cpuid!(0x1).ecx.get_bit(31) {
+        return None;
+    }
+    let regs = cpuid!(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS);
+    let vendor = [regs.ebx, regs.ecx, regs.edx]
+        .map(u32::to_le_bytes)
+        .concat();
+    Some(String::from_utf8_lossy(&vendor).into_owned())
+}
+
 /// Tests whether the current processor is already virtualized by our hypervisor.
 fn is_our_hypervisor_present() -> bool {
     let regs = cpuid!(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS);
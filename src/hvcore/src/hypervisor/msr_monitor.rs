@@ -0,0 +1,98 @@
+//! This module implements the detection of changes to the MSRs that rootkits
+//! overwrite to hijack control flow: the system call entry points and the
+//! APIC base.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::msr::{IA32_APIC_BASE, IA32_LSTAR, IA32_SYSENTER_EIP};
+
+use super::{
+    SHARED_HOST_DATA, apic_id,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+    x86_instructions::rdmsr,
+};
+
+/// The MSRs writes to which are intercepted when enabled.
+pub(crate) const INTERCEPTED_WRITES: [u32; 3] = [IA32_LSTAR, IA32_SYSENTER_EIP, IA32_APIC_BASE];
+
+/// Returns `true` if the MSRs are monitored.
+pub(crate) fn enabled() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.msr_monitor
+}
+
+/// Captures the values of the MSRs on all processors if enabled. Called before
+/// virtualizing the processors. Processors that come online later are not
+/// monitored.
+pub(crate) fn init() {
+    if !enabled() {
+        return;
+    }
+    platform_ops::get().run_on_all_processors(&|| {
+        let Some(values) = CAPTURED.current() else {
+            return;
+        };
+        for (value, msr) in values.iter().zip(INTERCEPTED_WRITES) {
+            value.store(rdmsr(msr), Ordering::Relaxed);
+        }
+    });
+}
+
+/// Checks WRMSR of `value` to `msr` by the guest at `rip` on the current
+/// processor, and logs it if the value differs from the captured one.
+///
+/// The write is never blocked, as the OS legitimately rewrites the MSRs, for
+/// example, on resume from sleep.
+pub(crate) fn check_write(msr: u32, value: u64, rip: u64) {
+    if !enabled() {
+        return;
+    }
+    let Some(index) = INTERCEPTED_WRITES.iter().position(|&m| m == msr) else {
+        return;
+    };
+    let Some(values) = CAPTURED.current() else {
+        return;
+    };
+    let expected = values[index].load(Ordering::Relaxed);
+    if let Some(name) = unexpected_write(msr, expected, value) {
+        log::warn!(
+            "{name} of processor #{} changed from {expected:#x} to {value:#x} by {rip:#x}",
+            apic_id::current_processor_id().unwrap_or_default()
+        );
+    }
+}
+
+/// Returns the name of `msr` if writing `value` to it is a change from
+/// `expected`.
+fn unexpected_write(msr: u32, expected: u64, value: u64) -> Option<&'static str> {
+    if expected == value {
+        return None;
+    }
+    match msr {
+        IA32_LSTAR => Some("LSTAR"),
+        IA32_SYSENTER_EIP => Some("SYSENTER_EIP"),
+        IA32_APIC_BASE => Some("APIC_BASE"),
+        _ => None,
+    }
+}
+
+/// The values of [`INTERCEPTED_WRITES`] at virtualization, in the same order.
+static CAPTURED: PerCpu<[AtomicU64; INTERCEPTED_WRITES.len()]> = PerCpu::new(
+    [const { [const { AtomicU64::new(0) }; INTERCEPTED_WRITES.len()] }; MAX_PROCESSORS],
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unexpected_writes() {
+        assert_eq!(unexpected_write(IA32_LSTAR, 0x1000, 0x1000), None);
+        assert_eq!(unexpected_write(IA32_LSTAR, 0x1000, 0x2000), Some("LSTAR"));
+        assert_eq!(
+            unexpected_write(IA32_APIC_BASE, 0xfee0_0900, 0xfee0_0d00),
+            Some("APIC_BASE")
+        );
+        assert_eq!(unexpected_write(IA32_SYSENTER_EIP, 0, 0), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! This module implements the policy for MONITOR and MWAIT, which put the
+//! processor into an implementation-specific optimized state, often a deep
+//! C-state, until a write to the monitored address or an interrupt.
+
+use x86::{bits64::rflags::RFlags, cpuid::CpuIdResult};
+
+use super::{
+    SHARED_HOST_DATA,
+    config::MwaitPolicy,
+    host::{Guest, InstructionInfo},
+};
+
+/// Returns `true` if MONITOR and MWAIT should cause VM-exit.
+pub(crate) fn is_intercepted() -> bool {
+    policy() != MwaitPolicy::Native
+}
+
+/// Returns the result of CPUID `leaf` the guest sees under the policy.
+pub(crate) fn filter_cpuid(leaf: u32, cpuid_result: CpuIdResult) -> CpuIdResult {
+    filter(policy(), leaf, cpuid_result)
+}
+
+/// Handles MONITOR, or MONITORX on AMD.
+pub(crate) fn handle_monitor<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+    if policy() == MwaitPolicy::Disable {
+        inject_ud(guest);
+        return;
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+/// Handles MWAIT, or MWAITX on AMD, whose timer is ignored.
+///
+/// With [`MwaitPolicy::Yield`], MWAIT with interrupts disabled completes
+/// immediately, as HLT would wait for an NMI instead. The guest spins if it
+/// waits so in a loop.
+pub(crate) fn handle_mwait<T: Guest>(guest: &mut T, info: &InstructionInfo) {
+    if policy() == MwaitPolicy::Disable {
+        inject_ud(guest);
+        return;
+    }
+    guest.regs().rip = info.next_rip;
+    if RFlags::from_raw(guest.regs().rflags).contains(RFlags::FLAGS_IF) {
+        let _ = guest.halt();
+    }
+}
+
+fn policy() -> MwaitPolicy {
+    SHARED_HOST_DATA.get().unwrap().config.mwait
+}
+
+fn inject_ud<T: Guest>(guest: &mut T) {
+    const UD: u8 = 6;
+    guest.inject_exception(UD, None);
+}
+
+/// Clears the feature bits of MONITOR and MWAIT, and the leaf of their
+/// parameters, with [`MwaitPolicy::Disable`].
+///
+/// See: Table 3-10. Feature Information Returned in the ECX Register
+/// See: E.4.2 Function 8000_0001h—Extended Processor and Processor Feature Identifiers
+fn filter(policy: MwaitPolicy, leaf: u32, mut cpuid_result: CpuIdResult) -> CpuIdResult {
+    const CPUID_MONITOR_MWAIT: u32 = 5;
+
+    if policy != MwaitPolicy::Disable {
+        return cpuid_result;
+    }
+    match leaf {
+        // MONITOR/MWAIT.
+        1 => cpuid_result.ecx &= !(1 << 3),
+        CPUID_MONITOR_MWAIT => {
+            cpuid_result = CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        // MONITORX/MWAITX. Reserved on Intel.
+        0x8000_0001 => cpuid_result.ecx &= !(1 << 29),
+        _ => {}
+    }
+    cpuid_result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpuid() {
+        let result = |ecx| CpuIdResult {
+            eax: 0x40,
+            ebx: 0x40,
+            ecx,
+            edx: 0x11142120,
+        };
+        let unchanged = filter(MwaitPolicy::Yield, 1, result(0xfffa_3203));
+        assert_eq!(unchanged, result(0xfffa_3203));
+
+        assert_eq!(
+            filter(MwaitPolicy::Disable, 1, result(0xfffa_3203)).ecx,
+            0xfffa_3203 & !(1 << 3)
+        );
+        assert_eq!(filter(MwaitPolicy::Disable, 5, result(3)).edx, 0);
+        assert_eq!(
+            filter(MwaitPolicy::Disable, 0x8000_0001, result(0x2000_0001)).ecx,
+            1
+        );
+        assert_eq!(filter(MwaitPolicy::Disable, 7, result(3)), result(3));
+    }
+}
@@ -1,9 +1,12 @@
 use core::ptr::addr_of;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
-use super::{platform_ops, support::zeroed_box};
+use super::{
+    platform_ops::{self, PaError},
+    support::zeroed_box,
+};
 
 #[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
 pub struct PagingStructures {
@@ -35,8 +38,8 @@ pub struct PagingStructuresRaw {
 }
 
 impl PagingStructuresRaw {
-    pub fn build_identity(&mut self) {
-        build_identity_internal(self, false);
+    pub fn build_identity(&mut self) -> Result<(), PaError> {
+        build_identity_internal(self, false)
     }
 }
 
@@ -67,9 +70,43 @@ bitfield::bitfield! {
     pub user, set_user: 2;
     pub large, set_large: 7;
     pub pfn, set_pfn: 51, 12;
+    pub no_execute, set_no_execute: 63;
 }
 
-pub(crate) fn build_identity_internal(ps: &mut PagingStructuresRaw, npt: bool) {
+/// The range of addresses the identity mapping covers, that is, the first PML4
+/// entry.
+pub(crate) const IDENTITY_MAP_SIZE: u64 = 512 * 0x4000_0000;
+
+/// An inconsistency found in the identity mapping.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IdentityMapError {
+    #[error("`{gpa:#x}` is not mapped")]
+    NotPresent { gpa: u64 },
+
+    #[error("`{gpa:#x}` is mapped to `{pa:#x}`")]
+    WrongAddress { gpa: u64, pa: u64 },
+
+    #[error("`{gpa:#x}` is translated through an unexpected table `{pa:#x}`")]
+    WrongTable { gpa: u64, pa: u64 },
+
+    #[error("`{gpa:#x}` is mapped with unexpected permissions")]
+    WrongPermissions { gpa: u64 },
+
+    #[error("`{gpa:#x}` is mapped with the memory type {actual} instead of {expected}")]
+    WrongMemoryType {
+        gpa: u64,
+        actual: u64,
+        expected: u64,
+    },
+
+    #[error(transparent)]
+    Pa(#[from] PaError),
+}
+
+pub(crate) fn build_identity_internal(
+    ps: &mut PagingStructuresRaw,
+    npt: bool,
+) -> Result<(), PaError> {
     let ops = platform_ops::get();
     let user = npt;
 
@@ -77,14 +114,14 @@ pub(crate) fn build_identity_internal(ps: &mut PagingStructuresRaw, npt: bool) {
     pml4.0.entries[0].set_present(true);
     pml4.0.entries[0].set_writable(true);
     pml4.0.entries[0].set_user(user);
-    pml4.0.entries[0].set_pfn(ops.pa(addr_of!(ps.pdpt) as _) >> BASE_PAGE_SHIFT);
+    pml4.0.entries[0].set_pfn(ops.pa(addr_of!(ps.pdpt) as _)? >> BASE_PAGE_SHIFT);
 
     let mut pa = 0;
     for (i, pdpte) in ps.pdpt.0.entries.iter_mut().enumerate() {
         pdpte.set_present(true);
         pdpte.set_writable(true);
         pdpte.set_user(user);
-        pdpte.set_pfn(ops.pa(addr_of!(ps.pd[i]) as _) >> BASE_PAGE_SHIFT);
+        pdpte.set_pfn(ops.pa(addr_of!(ps.pd[i]) as _)? >> BASE_PAGE_SHIFT);
         for pde in &mut ps.pd[i].0.entries {
             // The first 2MB is mapped with 4KB pages if it is not for NPT. This
             // is to make the zero page non-present and cause #PF in case of null
@@ -93,7 +130,7 @@ pub(crate) fn build_identity_internal(ps: &mut PagingStructuresRaw, npt: bool) {
                 pde.set_present(true);
                 pde.set_writable(true);
                 pde.set_user(user);
-                pde.set_pfn(ops.pa(addr_of!(ps.pt) as _) >> BASE_PAGE_SHIFT);
+                pde.set_pfn(ops.pa(addr_of!(ps.pt) as _)? >> BASE_PAGE_SHIFT);
                 for pte in &mut ps.pt.0.entries {
                     pte.set_present(true);
                     pte.set_writable(true);
@@ -113,4 +150,149 @@ pub(crate) fn build_identity_internal(ps: &mut PagingStructuresRaw, npt: bool) {
             }
         }
     }
+
+    // Catch mistakes in the above early in debug builds, rather than as guest
+    // hangs later.
+    if cfg!(debug_assertions)
+        && let Err(e) = check_identity(ps, npt)
+    {
+        panic!("The identity mapping is broken: {e}");
+    }
+    Ok(())
+}
+
+/// Walks the paging structures built by [`build_identity_internal`] and
+/// verifies that every address in [`IDENTITY_MAP_SIZE`] maps to itself with
+/// the expected permissions.
+pub(crate) fn check_identity(ps: &PagingStructuresRaw, npt: bool) -> Result<(), IdentityMapError> {
+    let ops = platform_ops::get();
+    let pdpt_pa = ops.pa(addr_of!(ps.pdpt) as _)?;
+    let pt_pa = ops.pa(addr_of!(ps.pt) as _)?;
+    let pd_pas = ps
+        .pd
+        .iter()
+        .map(|pd| ops.pa(addr_of!(*pd) as _))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let check_entry = |gpa: u64, entry: &Entry| {
+        if !entry.present() {
+            return Err(IdentityMapError::NotPresent { gpa });
+        }
+        if !entry.writable() || entry.user() != npt || entry.no_execute() {
+            return Err(IdentityMapError::WrongPermissions { gpa });
+        }
+        Ok(())
+    };
+    let check_table = |gpa: u64, entry: &Entry, expected: u64| {
+        check_entry(gpa, entry)?;
+        let pa = entry.pfn() << BASE_PAGE_SHIFT;
+        if entry.large() || pa != expected {
+            return Err(IdentityMapError::WrongTable { gpa, pa });
+        }
+        Ok(())
+    };
+
+    let mut gpa = 0;
+    while gpa < IDENTITY_MAP_SIZE {
+        let pml4e = &ps.pml4.0.entries[(gpa >> 39) as usize & 0x1ff];
+        check_table(gpa, pml4e, pdpt_pa)?;
+        let pdpt_index = (gpa >> 30) as usize & 0x1ff;
+        let pdpte = &ps.pdpt.0.entries[pdpt_index];
+        check_table(gpa, pdpte, pd_pas[pdpt_index])?;
+        let pde = &ps.pd[pdpt_index].0.entries[(gpa >> 21) as usize & 0x1ff];
+
+        let (entry, size) = if gpa < LARGE_PAGE_SIZE as u64 && !npt {
+            check_table(gpa, pde, pt_pa)?;
+            let pte = &ps.pt.0.entries[(gpa >> 12) as usize & 0x1ff];
+
+            // The null page is intentionally non-present.
+            if gpa == 0 {
+                if pte.present() {
+                    return Err(IdentityMapError::WrongPermissions { gpa });
+                }
+                gpa += BASE_PAGE_SIZE as u64;
+                continue;
+            }
+            (pte, BASE_PAGE_SIZE as u64)
+        } else {
+            if !pde.large() {
+                return Err(IdentityMapError::WrongTable {
+                    gpa,
+                    pa: pde.pfn() << BASE_PAGE_SHIFT,
+                });
+            }
+            (pde, LARGE_PAGE_SIZE as u64)
+        };
+
+        check_entry(gpa, entry)?;
+        let pa = entry.pfn() << BASE_PAGE_SHIFT;
+        if pa != gpa {
+            return Err(IdentityMapError::WrongAddress { gpa, pa });
+        }
+        gpa += size;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::testing;
+
+    #[test]
+    fn identity_check() {
+        testing::init();
+
+        for npt in [false, true] {
+            let mut ps = PagingStructures::new();
+            build_identity_internal(&mut ps, npt).unwrap();
+            assert_eq!(check_identity(&ps, npt), Ok(()));
+        }
+
+        // The null page is not mapped unless it is for NPT.
+        let mut ps = PagingStructures::new();
+        ps.build_identity().unwrap();
+        assert!(!ps.pt.0.entries[0].present());
+        assert!(ps.pt.0.entries[1].present());
+    }
+
+    #[test]
+    fn identity_check_detects_errors() {
+        testing::init();
+
+        let mut ps = PagingStructures::new();
+        ps.build_identity().unwrap();
+
+        ps.pd[511].0.entries[511].set_present(false);
+        assert_eq!(
+            check_identity(&ps, false),
+            Err(IdentityMapError::NotPresent {
+                gpa: IDENTITY_MAP_SIZE - LARGE_PAGE_SIZE as u64
+            })
+        );
+        ps.pd[511].0.entries[511].set_present(true);
+
+        ps.pt.0.entries[1].set_pfn(2);
+        assert_eq!(
+            check_identity(&ps, false),
+            Err(IdentityMapError::WrongAddress {
+                gpa: 0x1000,
+                pa: 0x2000
+            })
+        );
+        ps.pt.0.entries[1].set_pfn(1);
+
+        ps.pt.0.entries[0].set_present(true);
+        assert_eq!(
+            check_identity(&ps, false),
+            Err(IdentityMapError::WrongPermissions { gpa: 0 })
+        );
+        ps.pt.0.entries[0].set_present(false);
+
+        ps.pd[0].0.entries[1].set_large(false);
+        assert!(matches!(
+            check_identity(&ps, false),
+            Err(IdentityMapError::WrongTable { gpa: 0x20_0000, .. })
+        ));
+    }
 }
@@ -1,5 +1,14 @@
+use super::{apic_id, gdb_stub, panic_buffer};
+
 pub fn panic_impl(info: &core::panic::PanicInfo<'_>) -> ! {
     log::error!("{info}");
+
+    // Do not wait for the lock, as this processor may have panicked with it.
+    let id = apic_id::APIC_ID_MAP
+        .try_read()
+        .and_then(|map| map.get(&apic_id::get()).copied());
+    panic_buffer::save(id, info);
+    gdb_stub::stop_on_panic();
     loop {
         unsafe {
             x86::irq::disable();
@@ -0,0 +1,185 @@
+//! This module implements the panic record that survives warm reboot.
+//!
+//! The platform gives a memory region with [`set_region`]. When the hypervisor
+//! panics, it writes the panic message, the current VMCS if the panic occurred
+//! in the host on an Intel processor, and the recent log output into the
+//! region. As the contents of memory usually survive warm reboot, the platform
+//! can read the record back with [`read_record`] on the next boot, even on
+//! machines without a serial port.
+//!
+//! The record is a [`PanicRecordHeader`] followed by the UTF-8 text. The region
+//! must not be used by the OS, for example, it should be reserved memory at a
+//! fixed physical address.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+
+#[cfg(feature = "intel")]
+use super::intel::CurrentVmcs;
+use super::{
+    crash_dump::BufferWriter,
+    log_ring,
+    percpu::{MAX_PROCESSORS, PerCpu},
+};
+
+/// The value of [`PanicRecordHeader::magic`], "BVPANIC" followed by 0.
+pub const PANIC_RECORD_MAGIC: u64 = u64::from_le_bytes(*b"BVPANIC\0");
+
+/// The header of the panic record.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct PanicRecordHeader {
+    pub magic: u64,
+
+    /// The size of the text in bytes.
+    pub len: u32,
+
+    /// The checksum of the text. See [`checksum`].
+    pub checksum: u32,
+}
+
+/// Sets the region the panic record is written into. The region must stay
+/// valid and not be used for anything else.
+///
+/// # Safety
+///
+/// `ptr` must be valid for writes of `size` bytes, and 8-byte aligned.
+pub unsafe fn set_region(ptr: *mut u8, size: usize) {
+    let _ = REGION.call_once(|| Region {
+        address: ptr as usize,
+        size,
+    });
+}
+
+/// Returns the text of the panic record in `region`, if `region` holds a valid
+/// record.
+pub fn read_record(region: &[u8]) -> Option<&str> {
+    let header_size = size_of::<PanicRecordHeader>();
+    let header = region.get(..header_size)?;
+    let magic = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let expected = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    if magic != PANIC_RECORD_MAGIC {
+        return None;
+    }
+    let text = region.get(header_size..header_size + len as usize)?;
+    if checksum(text) != expected {
+        return None;
+    }
+    core::str::from_utf8(text).ok()
+}
+
+/// Invalidates the panic record in `region`, so that it is not read again.
+pub fn clear_record(region: &mut [u8]) {
+    let header_size = size_of::<PanicRecordHeader>().min(region.len());
+    region[..header_size].fill(0);
+}
+
+/// Returns the FNV-1a hash of `bytes`.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Marks whether the processor `id` runs the host, that is, handles VM-exit.
+/// The current VMCS can only be read in the host.
+pub(crate) fn set_in_host(id: usize, in_host: bool) {
+    if let Some(flag) = IN_HOST.get(id) {
+        flag.store(in_host, Ordering::Relaxed);
+    }
+}
+
+/// Writes the panic record for `info`, if the region is set. Only the first
+/// panic is recorded.
+pub(crate) fn save(id: Option<usize>, info: &core::panic::PanicInfo<'_>) {
+    let Some(region) = REGION.get() else {
+        return;
+    };
+    if SAVED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let header_size = size_of::<PanicRecordHeader>();
+    if region.size <= header_size {
+        return;
+    }
+    // Safety: the platform guarantees the region is valid and unused.
+    let text = unsafe {
+        core::slice::from_raw_parts_mut(
+            (region.address + header_size) as *mut u8,
+            region.size - header_size,
+        )
+    };
+    let mut writer = BufferWriter::new(text);
+    let _ = write_text(&mut writer, id, info);
+    let len = writer.len();
+
+    let header = PanicRecordHeader {
+        magic: PANIC_RECORD_MAGIC,
+        len: len as u32,
+        checksum: checksum(&text[..len]),
+    };
+    unsafe { (region.address as *mut PanicRecordHeader).write_volatile(header) };
+}
+
+fn write_text(
+    out: &mut dyn Write,
+    id: Option<usize>,
+    info: &core::panic::PanicInfo<'_>,
+) -> fmt::Result {
+    writeln!(out, "Panic on processor {id:?}: {info}")?;
+    #[cfg(feature = "intel")]
+    {
+        let in_host = id
+            .and_then(|id| IN_HOST.get(id))
+            .is_some_and(|flag| flag.load(Ordering::Relaxed));
+        if in_host && let Some(vmcs) = CurrentVmcs::get() {
+            writeln!(out, "{vmcs:#x?}")?;
+        }
+    }
+    writeln!(out, "Recent log:")?;
+    log_ring::write_recent(out)
+}
+
+/// The memory region given with [`set_region`].
+struct Region {
+    address: usize,
+    size: usize,
+}
+
+static REGION: Once<Region> = Once::new();
+static SAVED: AtomicBool = AtomicBool::new(false);
+static IN_HOST: PerCpu<AtomicBool> =
+    PerCpu::new([const { AtomicBool::new(false) }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn read_and_clear() {
+        let text = b"panicked at host.rs";
+        let mut region = vec![0u8; 64];
+        region[..8].copy_from_slice(&PANIC_RECORD_MAGIC.to_le_bytes());
+        region[8..12].copy_from_slice(&(text.len() as u32).to_le_bytes());
+        region[12..16].copy_from_slice(&checksum(text).to_le_bytes());
+        region[16..16 + text.len()].copy_from_slice(text);
+        assert_eq!(read_record(&region), Some("panicked at host.rs"));
+
+        // A corrupted text is rejected.
+        region[16] = b'P';
+        assert_eq!(read_record(&region), None);
+        region[16] = b'p';
+
+        clear_record(&mut region);
+        assert_eq!(read_record(&region), None);
+        assert_eq!(read_record(&[0; 4]), None);
+    }
+}
@@ -0,0 +1,43 @@
+//! This module implements the storage of per-processor data.
+//!
+//! [`PerCpu`] holds a value for each processor, indexed by the processor ID
+//! `apic_id` assigns. The ID of a processor does not change for the lifetime
+//! of the hypervisor, including across INIT-SIPI-SIPI and re-virtualization,
+//! so the data survives them. [`PerCpu::current`] finds the value of the
+//! current processor without taking any lock, and thus, is usable from VM-exit
+//! and interrupt handlers.
+
+use core::ops::Deref;
+
+use super::apic_id;
+
+/// The maximum number of processors the per-processor data is kept for.
+/// Processors with larger IDs are not virtualized.
+pub const MAX_PROCESSORS: usize = 256;
+
+/// A value for each processor. Derefs to the slice of the values, indexed by
+/// the processor ID.
+#[derive(Debug)]
+pub(crate) struct PerCpu<T>([T; MAX_PROCESSORS]);
+
+impl<T> PerCpu<T> {
+    /// Creates the storage with the initial values, typically with
+    /// `[const { T::new() }; MAX_PROCESSORS]`.
+    pub(crate) const fn new(values: [T; MAX_PROCESSORS]) -> Self {
+        Self(values)
+    }
+
+    /// Returns the value of the current processor, or `None` if it is not
+    /// registered yet or its ID is out of range.
+    pub(crate) fn current(&self) -> Option<&T> {
+        self.0.get(apic_id::current_processor_id()?)
+    }
+}
+
+impl<T> Deref for PerCpu<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
@@ -0,0 +1,211 @@
+//! This module implements [`HC_READ_PHYS`], which copies guest physical memory
+//! into a buffer of the guest.
+//!
+//! Forensic tools may acquire memory with this hypercall even when the OS
+//! API to map physical memory is hooked. Physical memory is read through the
+//! identity mapping of the host, thus, this is only available when the host
+//! runs on the identity mapping given with `SharedHostData::pt`.
+//!
+//! The platform registers memory of the hypervisor with [`conceal`]. Such
+//! memory is neither read nor written by the hypercall, so that the guest
+//! cannot inspect or corrupt the hypervisor through it.
+//!
+//! [`HC_READ_PHYS`]: super::hypercall::HC_READ_PHYS
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use spin::RwLock;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::{
+    SHARED_HOST_DATA,
+    gva::{self, GuestAddressSpace},
+    paging_structures::IDENTITY_MAP_SIZE,
+};
+
+/// The largest number of bytes copied with one hypercall, which bounds the
+/// time spent in the VM-exit.
+pub const MAX_READ_SIZE: u64 = 0x10_0000;
+
+/// The error type for [`HC_READ_PHYS`](super::hypercall::HC_READ_PHYS). The
+/// value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum PhysReadError {
+    #[error("guest memory is not accessible")]
+    Unavailable = 1,
+
+    #[error("the range is empty, too large or not mapped")]
+    InvalidRange = 2,
+
+    #[error("the range overlaps with memory concealed by the hypervisor")]
+    Concealed = 3,
+
+    #[error("the buffer is not mapped or not writable")]
+    InvalidBuffer = 4,
+}
+
+/// Conceals the physical memory `range` from [`HC_READ_PHYS`].
+///
+/// [`HC_READ_PHYS`]: super::hypercall::HC_READ_PHYS
+pub fn conceal(range: Range<u64>) {
+    CONCEALED.write().push(range);
+}
+
+/// Returns the physical memory ranges concealed with [`conceal`].
+pub(crate) fn concealed() -> Vec<Range<u64>> {
+    CONCEALED.read().clone()
+}
+
+/// Copies `size` bytes of guest physical memory at `source` into the buffer at
+/// the GVA `destination` in the address space `cr3`. The buffer must not be in
+/// user-mode pages if `smap` is `true`, as the caller could not write it
+/// either. See [`gva::smap_applies`].
+pub(crate) fn read_phys(
+    cr3: Option<u64>,
+    smap: bool,
+    source: u64,
+    destination: u64,
+    size: u64,
+) -> Result<(), PhysReadError> {
+    if SHARED_HOST_DATA.get().unwrap().pt.is_none() {
+        return Err(PhysReadError::Unavailable);
+    }
+    let source = validate_range(source, size)?;
+    let space = cr3
+        .and_then(gva::address_space)
+        .map(|space| space.enforce_smap(smap))
+        .ok_or(PhysReadError::InvalidBuffer)?;
+
+    // Translate the whole buffer before copying anything.
+    let concealed = CONCEALED.read();
+    let buffers = translate_buffer(&space, &concealed, destination, size, false)
+        .ok_or(PhysReadError::InvalidBuffer)?;
+    if overlaps(&concealed, &source) {
+        return Err(PhysReadError::Concealed);
+    }
+
+    // Safety: both ranges are within the identity mapping and do not overlap
+    // with memory of the hypervisor.
+    let mut source = source.start;
+    for buffer in buffers {
+        let len = buffer.end - buffer.start;
+        unsafe { core::ptr::copy(source as *const u8, buffer.start as *mut u8, len as usize) };
+        source += len;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to the GVA `destination` in the address space `cr3` as
+/// [`read_phys`] writes its buffer, on behalf of guest code in user mode if
+/// `user` is `true`, or in supervisor mode under SMAP if `smap` is `true`.
+/// Returns `false` if any of the bytes is not writable for the guest code, or
+/// is in memory of the hypervisor.
+pub(crate) fn write_guest(
+    cr3: u64,
+    smap: bool,
+    user: bool,
+    destination: u64,
+    bytes: &[u8],
+) -> bool {
+    let Some(space) = gva::address_space(cr3) else {
+        return false;
+    };
+    let space = space.enforce_smap(smap && !user);
+    let concealed = CONCEALED.read();
+    let Some(buffers) = translate_buffer(&space, &concealed, destination, bytes.len() as u64, user)
+    else {
+        return false;
+    };
+
+    // Safety: the buffers are within the identity mapping and do not overlap
+    // with memory of the hypervisor.
+    let mut source = bytes;
+    for buffer in buffers {
+        let (head, tail) = source.split_at((buffer.end - buffer.start) as usize);
+        unsafe {
+            core::ptr::copy_nonoverlapping(head.as_ptr(), buffer.start as *mut u8, head.len());
+        };
+        source = tail;
+    }
+    true
+}
+
+/// Translates the writable buffer of `size` bytes at the GVA `buffer` in
+/// `space` into GPA ranges, one for each page. Returns `None` if any of them
+/// is not mapped, not writable, not accessible in user mode if `user` is
+/// `true`, or overlaps with `concealed`.
+fn translate_buffer<F: Fn(u64) -> Option<u64>>(
+    space: &GuestAddressSpace<F>,
+    concealed: &[Range<u64>],
+    buffer: u64,
+    size: u64,
+    user: bool,
+) -> Option<Vec<Range<u64>>> {
+    let end = buffer.checked_add(size)?;
+    let mut ranges = Vec::new();
+    let mut gva = buffer;
+    while gva < end {
+        let page_offset = gva & (BASE_PAGE_SIZE as u64 - 1);
+        let len = (BASE_PAGE_SIZE as u64 - page_offset).min(end - gva);
+        let translation = space
+            .translate(gva)
+            .filter(|translation| translation.writable && (translation.user || !user))?;
+        let range = translation.gpa..translation.gpa + len;
+        if range.end > IDENTITY_MAP_SIZE || overlaps(concealed, &range) {
+            return None;
+        }
+        ranges.push(range);
+        gva += len;
+    }
+    Some(ranges)
+}
+
+/// Returns the physical memory range of `size` bytes at `source`, if it can be
+/// read with one hypercall.
+fn validate_range(source: u64, size: u64) -> Result<Range<u64>, PhysReadError> {
+    if size == 0 || size > MAX_READ_SIZE || source.saturating_add(size) > IDENTITY_MAP_SIZE {
+        return Err(PhysReadError::InvalidRange);
+    }
+    Ok(source..source + size)
+}
+
+/// Returns `true` if `range` overlaps with any of `concealed`.
+fn overlaps(concealed: &[Range<u64>], range: &Range<u64>) -> bool {
+    concealed
+        .iter()
+        .any(|concealed| concealed.start < range.end && range.start < concealed.end)
+}
+
+static CONCEALED: RwLock<Vec<Range<u64>>> = RwLock::new(Vec::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range() {
+        assert_eq!(validate_range(0x1000, 0x10), Ok(0x1000..0x1010));
+        for (source, size) in [
+            (0x1000, 0),
+            (0x1000, MAX_READ_SIZE + 1),
+            (IDENTITY_MAP_SIZE - 0x10, 0x20),
+            (u64::MAX, 0x10),
+        ] {
+            assert_eq!(
+                validate_range(source, size),
+                Err(PhysReadError::InvalidRange)
+            );
+        }
+    }
+
+    #[test]
+    fn concealed() {
+        let concealed = [0x1000..0x3000, 0x8000..0x9000];
+        assert!(overlaps(&concealed, &(0x2fff..0x3001)));
+        assert!(overlaps(&concealed, &(0x7000..0xa000)));
+        assert!(!overlaps(&concealed, &(0x3000..0x8000)));
+        assert!(!overlaps(&concealed, &(0..0x1000)));
+    }
+}
@@ -0,0 +1,198 @@
+//! This module implements [`HC_SCAN_PHYS`], which searches guest physical
+//! memory for a byte pattern.
+//!
+//! [`HC_SCAN_PHYS`]: super::hypercall::HC_SCAN_PHYS
+
+use core::ops::Range;
+
+use alloc::{vec, vec::Vec};
+
+use super::{SHARED_HOST_DATA, gva, paging_structures::IDENTITY_MAP_SIZE, phys_read};
+
+/// The largest number of bytes of a pattern.
+pub const MAX_PATTERN_SIZE: usize = 64;
+
+/// The largest number of GPAs scanned for each VM-exit, which bounds the time
+/// spent in the VM-exit. The guest then re-executes the hypercall with the
+/// start GPA advanced, after interrupts pending in the meantime are delivered.
+pub const SCAN_SLICE: u64 = 0x10_0000;
+
+/// The pattern to search for, placed in guest memory by the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ScanPattern {
+    /// The number of bytes in `bytes` and `mask` to match, from 1 to
+    /// [`MAX_PATTERN_SIZE`].
+    pub size: u64,
+
+    /// The bytes to match.
+    pub bytes: [u8; MAX_PATTERN_SIZE],
+
+    /// The bits of each byte to compare. `0xff` matches the byte exactly, and
+    /// 0 makes it a wildcard.
+    pub mask: [u8; MAX_PATTERN_SIZE],
+}
+
+impl ScanPattern {
+    /// Returns the offset of the first match in `memory`.
+    fn find(&self, memory: &[u8]) -> Option<usize> {
+        let size = self.size as usize;
+        memory.windows(size).position(|window| {
+            window
+                .iter()
+                .zip(&self.bytes[..size])
+                .zip(&self.mask[..size])
+                .all(|((&byte, &expected), &mask)| byte & mask == expected & mask)
+        })
+    }
+}
+
+/// The error type for [`HC_SCAN_PHYS`](super::hypercall::HC_SCAN_PHYS). The
+/// value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum PhysScanError {
+    #[error("guest memory is not accessible")]
+    Unavailable = 1,
+
+    #[error("the range is reversed or not mapped")]
+    InvalidRange = 2,
+
+    #[error("the pattern is not mapped or its size is invalid")]
+    InvalidPattern = 3,
+
+    #[error("no match in the rest of the range")]
+    NotFound = 4,
+}
+
+/// The result of scanning a slice of the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ScanProgress {
+    /// The pattern matches at the GPA.
+    Found(u64),
+
+    /// No match up to the GPA, which the scan continues from.
+    Pending(u64),
+}
+
+/// Scans guest physical memory from `start` up to `end` for the
+/// [`ScanPattern`] at the GVA `pattern` in the address space `cr3`, at most
+/// [`SCAN_SLICE`] GPAs at once. The pattern must not be in user-mode pages if
+/// `smap` is `true`. See [`gva::smap_applies`].
+///
+/// Memory is read through the identity mapping of the host, and memory
+/// concealed with [`phys_read::conceal`] is skipped. The range must not contain
+/// MMIO, which reads may have side effects on.
+pub(crate) fn scan(
+    cr3: Option<u64>,
+    smap: bool,
+    pattern: u64,
+    start: u64,
+    end: u64,
+) -> Result<ScanProgress, PhysScanError> {
+    if SHARED_HOST_DATA.get().unwrap().pt.is_none() {
+        return Err(PhysScanError::Unavailable);
+    }
+    if start > end || end > IDENTITY_MAP_SIZE {
+        return Err(PhysScanError::InvalidRange);
+    }
+    let pattern = cr3
+        .and_then(gva::address_space)
+        .map(|space| space.enforce_smap(smap))
+        .and_then(|space| {
+            let mut raw = [0u8; size_of::<ScanPattern>()];
+            space.read(pattern, &mut raw)?;
+            // Safety: any bytes are a valid `ScanPattern`.
+            Some(unsafe { raw.as_ptr().cast::<ScanPattern>().read_unaligned() })
+        })
+        .filter(|pattern| (1..=MAX_PATTERN_SIZE as u64).contains(&pattern.size))
+        .ok_or(PhysScanError::InvalidPattern)?;
+    if end - start < pattern.size {
+        return Err(PhysScanError::NotFound);
+    }
+
+    // Scan matches starting before `slice_end`, which may extend past it.
+    let last = end - pattern.size;
+    let slice_end = start.saturating_add(SCAN_SLICE).min(last + 1);
+    let window = start..slice_end + pattern.size - 1;
+    for segment in readable_segments(&phys_read::concealed(), window) {
+        // Safety: the segment is within the identity mapping and does not
+        // overlap with memory of the hypervisor.
+        let memory = unsafe {
+            core::slice::from_raw_parts(
+                segment.start as *const u8,
+                (segment.end - segment.start) as usize,
+            )
+        };
+        if let Some(offset) = pattern.find(memory) {
+            return Ok(ScanProgress::Found(segment.start + offset as u64));
+        }
+    }
+    if slice_end > last {
+        Err(PhysScanError::NotFound)
+    } else {
+        Ok(ScanProgress::Pending(slice_end))
+    }
+}
+
+/// Returns the parts of `range` that do not overlap with any of `concealed`,
+/// in ascending order.
+fn readable_segments(concealed: &[Range<u64>], range: Range<u64>) -> Vec<Range<u64>> {
+    let mut segments = vec![range];
+    for concealed in concealed {
+        segments = segments
+            .into_iter()
+            .flat_map(|segment| {
+                [
+                    segment.start..segment.end.min(concealed.start),
+                    segment.start.max(concealed.end)..segment.end,
+                ]
+            })
+            .filter(|segment| segment.start < segment.end)
+            .collect();
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(bytes: &[u8], mask: &[u8]) -> ScanPattern {
+        let mut pattern = ScanPattern {
+            size: bytes.len() as u64,
+            bytes: [0; MAX_PATTERN_SIZE],
+            mask: [0; MAX_PATTERN_SIZE],
+        };
+        pattern.bytes[..bytes.len()].copy_from_slice(bytes);
+        pattern.mask[..mask.len()].copy_from_slice(mask);
+        pattern
+    }
+
+    #[test]
+    fn find() {
+        let memory = b"\x00MZ\x90\x00\x03\x00MZ\x91\x00\x03";
+        let exact = pattern(b"MZ\x91", &[0xff; 3]);
+        assert_eq!(exact.find(memory), Some(7));
+
+        let wildcard = pattern(b"MZ\x00\x00\x03", &[0xff, 0xff, 0, 0xff, 0xff]);
+        assert_eq!(wildcard.find(memory), Some(1));
+
+        let missing = pattern(b"PE", &[0xff; 2]);
+        assert_eq!(missing.find(memory), None);
+        assert_eq!(exact.find(b"MZ"), None);
+    }
+
+    #[test]
+    fn segments() {
+        assert_eq!(
+            readable_segments(&[0x2000..0x3000, 0x5000..0x6000], 0x1000..0x8000),
+            [0x1000..0x2000, 0x3000..0x5000, 0x6000..0x8000]
+        );
+        assert_eq!(
+            readable_segments(&[0..0x2000, 0x7000..0x9000], 0x1000..0x8000).first(),
+            Some(&(0x2000..0x7000))
+        );
+        assert!(readable_segments(&[0..0x4000, 0x4000..0x9000], 0x1000..0x8000).is_empty());
+    }
+}
@@ -4,11 +4,51 @@ use spin::Once;
 /// A set of platform specific API to be called during the host setup phase.
 pub trait PlatformOps {
     /// Runs `callback` on all logical processors one by one.
+    ///
+    /// `callback` may capture the data it works on, such as the configuration
+    /// for each processor or where to store the results, instead of relying on
+    /// statics.
     // This function cannot be called in a nested manner.
-    fn run_on_all_processors(&self, callback: fn());
+    fn run_on_all_processors(&self, callback: &(dyn Fn() + Sync));
 
-    // Returns a physical address of a linear address specified by `va`.
-    fn pa(&self, va: *const core::ffi::c_void) -> u64;
+    /// Runs `callback` on the logical processor specified by `index`, and
+    /// returns after it completes. `index` is the platform's index of the
+    /// processor, from 0 to the number of processors minus 1, which is not
+    /// necessarily the processor ID of the hypervisor.
+    // This function cannot be called in a nested manner, nor while
+    // `run_on_all_processors` is in progress.
+    fn run_on_processor(&self, index: usize, callback: &(dyn Fn() + Sync)) -> Result<(), RunError>;
+
+    /// Returns a physical address of a linear address specified by `va`, or
+    /// an error if `va` is not mapped.
+    fn pa(&self, va: *const core::ffi::c_void) -> Result<u64, PaError>;
+
+    /// Allocates `size` bytes of physically contiguous and page aligned memory
+    /// whose physical address does not exceed `max_pa`. Returns null on failure.
+    // The heap is only physically contiguous within a page. Use this for
+    // structures larger than a page whose physical address is given to the
+    // processor.
+    fn alloc_contiguous(&self, size: usize, max_pa: u64) -> *mut core::ffi::c_void;
+
+    /// Frees memory allocated with `alloc_contiguous`.
+    fn free_contiguous(&self, ptr: *mut core::ffi::c_void, size: usize);
+}
+
+/// The error type for [`PlatformOps::pa`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaError {
+    #[error("`{va:#x}` is not mapped to physical memory")]
+    NotMapped { va: usize },
+}
+
+/// The error type for [`PlatformOps::run_on_processor`].
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunError {
+    #[error("processor #{index} does not exist or is disabled")]
+    InvalidIndex { index: usize },
+
+    #[error("the platform failed to run the callback on processor #{index}")]
+    Failed { index: usize },
 }
 
 /// Initializes the platform specific API as provided by `ops`.
@@ -19,6 +59,11 @@ pub fn init(ops: Box<dyn PlatformOps>) {
     PLATFORM_OPS.call_once(|| Ops { ops });
 }
 
+/// Returns `true` if [`init`] has been called.
+pub fn is_initialized() -> bool {
+    PLATFORM_OPS.is_completed()
+}
+
 /// Returns the platform specific API.
 pub fn get() -> Arc<Box<dyn PlatformOps>> {
     PLATFORM_OPS.get().unwrap().ops.clone()
@@ -0,0 +1,238 @@
+//! This module implements the isolation of performance counters between the
+//! host and the guest, and the handling of RDPMC.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bit_field::BitField;
+use spin::Lazy;
+use x86::cpuid::CpuIdResult;
+
+use super::{
+    SHARED_HOST_DATA,
+    config::{PmuPolicy, RdpmcPolicy},
+    host::{Guest, InstructionInfo},
+    percpu::{MAX_PROCESSORS, PerCpu},
+    x86_instructions::{rdmsr, rdpmc, wrmsr},
+};
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+pub(crate) const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+/// The GuestOnly bit of the AMD event selectors.
+///
+/// See: 13.2 Performance-Monitoring Counters
+pub(crate) const GUEST_ONLY: u64 = 1 << 40;
+
+/// Returns `true` if the counters are isolated.
+pub(crate) fn isolated() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.pmu == PmuPolicy::Isolate
+}
+
+/// Clears the GuestOnly bit the host set in the event selectors of the current
+/// processor with the counters isolated on AMD, so that the counters count as
+/// the guest programmed after devirtualization.
+pub(crate) fn restore_event_selectors() {
+    if !isolated() || x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel"
+    {
+        return;
+    }
+    for selector in amd_event_selectors() {
+        wrmsr(selector, rdmsr(selector) & !GUEST_ONLY);
+    }
+}
+
+/// Returns `true` if RDPMC should cause VM-exit.
+pub(crate) fn rdpmc_exiting() -> bool {
+    rdpmc_policy() != RdpmcPolicy::Off
+}
+
+/// Handles RDPMC on the processor `id` according to [`HvConfig::rdpmc`].
+/// RDPMC of a counter that does not exist injects #GP(0) as the processor does.
+///
+/// [`HvConfig::rdpmc`]: super::config::HvConfig::rdpmc
+pub(crate) fn handle_rdpmc<T: Guest>(guest: &mut T, id: usize, info: &InstructionInfo) {
+    const GP: u8 = 13;
+
+    let ecx = guest.regs().rcx as u32;
+    let Some(index) = counter_index(ecx, *COUNTERS) else {
+        guest.inject_exception(GP, Some(0));
+        return;
+    };
+    let value = match rdpmc_policy() {
+        RdpmcPolicy::Off | RdpmcPolicy::Passthrough => rdpmc(ecx),
+        RdpmcPolicy::Zero => 0,
+        RdpmcPolicy::Virtualize => VIRTUAL_COUNTERS.get(id).map_or(0, |counters| {
+            counters[index].fetch_add(1, Ordering::Relaxed) + 1
+        }),
+    };
+    guest.regs().rax = value & 0xffff_ffff;
+    guest.regs().rdx = value >> 32;
+    guest.regs().rip = info.next_rip;
+}
+
+/// Returns `true` if IA32_PERF_GLOBAL_CTRL is supported by the processor.
+pub(crate) fn is_global_ctrl_supported() -> bool {
+    // See: Architectural Performance Monitoring Version 2
+    x86::cpuid::cpuid!(0xa, 0).eax.get_bits(0..=7) >= 2
+}
+
+/// Returns `true` if `value` is written to `msr` by the guest with the
+/// counters isolated, and writing it to IA32_PERF_GLOBAL_CTRL causes #GP(0).
+pub(crate) fn is_invalid_write(msr: u32, value: u64) -> bool {
+    msr == IA32_PERF_GLOBAL_CTRL
+        && isolated()
+        && value & !global_ctrl_mask(x86::cpuid::cpuid!(0xa, 0)) != 0
+}
+
+/// Returns the event selectors of the AMD processor, whose writes are
+/// intercepted with the counters isolated.
+///
+/// See: 13.2 Performance-Monitoring Counters
+pub(crate) fn amd_event_selectors() -> impl Iterator<Item = u32> {
+    const PERF_EVT_SEL0: u32 = 0xc001_0000;
+    const PERF_CTL0: u32 = 0xc001_0200;
+
+    // The extended core counters are supported if CPUID Fn8000_0001_ECX[23]
+    // is set. Their selectors are interleaved with the counters.
+    // See: E.4.2 Function 8000_0001h—Extended Processor and Processor Feature
+    //      Identifiers
+    let extended = if x86::cpuid::cpuid!(0x8000_0001).ecx.get_bit(23) {
+        6
+    } else {
+        0
+    };
+    (PERF_EVT_SEL0..PERF_EVT_SEL0 + 4).chain((0..extended).map(|index| PERF_CTL0 + index * 2))
+}
+
+fn rdpmc_policy() -> RdpmcPolicy {
+    SHARED_HOST_DATA.get().unwrap().config.rdpmc
+}
+
+/// The numbers of the general-purpose and fixed-function counters RDPMC may
+/// read, up to 32 and 16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Counters {
+    general: u32,
+    fixed: u32,
+}
+
+impl Counters {
+    /// Returns the counters of the Intel processor with CPUID leaf 0AH.
+    ///
+    /// See: Architectural Performance Monitoring Version 2
+    fn intel(leaf: CpuIdResult) -> Self {
+        let version = leaf.eax.get_bits(0..=7);
+        Self {
+            general: leaf.eax.get_bits(8..=15).min(32),
+            fixed: if version >= 2 {
+                leaf.edx.get_bits(0..=4).min(16)
+            } else {
+                0
+            },
+        }
+    }
+
+    /// Returns the core counters of the AMD processor, which are 6 with the
+    /// extended ones, and 4 otherwise. See [`amd_event_selectors`].
+    fn amd() -> Self {
+        Self {
+            general: if x86::cpuid::cpuid!(0x8000_0001).ecx.get_bit(23) {
+                6
+            } else {
+                4
+            },
+            fixed: 0,
+        }
+    }
+}
+
+/// Returns the index of the counter RDPMC reads with `ecx` among `counters`,
+/// the general-purpose ones followed by the fixed-function ones, or `None` if
+/// it does not exist.
+///
+/// See: RDPMC—Read Performance-Monitoring Counters
+fn counter_index(ecx: u32, counters: Counters) -> Option<usize> {
+    const FIXED_FUNCTION: u32 = 1 << 30;
+
+    if ecx & FIXED_FUNCTION == 0 {
+        (ecx < counters.general).then_some(ecx as usize)
+    } else {
+        let index = ecx & !FIXED_FUNCTION;
+        (index < counters.fixed).then_some((counters.general + index) as usize)
+    }
+}
+
+/// Returns the bits of IA32_PERF_GLOBAL_CTRL that may be set, given CPUID leaf
+/// 0AH: the enable bits of the general-purpose counters from bit 0 and those
+/// of the fixed-function counters from bit 32.
+///
+/// See: Architectural Performance Monitoring Version 2
+fn global_ctrl_mask(leaf: CpuIdResult) -> u64 {
+    let general = leaf.eax.get_bits(8..=15).min(32);
+    let fixed = leaf.edx.get_bits(0..=4);
+    ((1 << general) - 1) | (((1 << fixed) - 1) << 32)
+}
+
+static COUNTERS: Lazy<Counters> = Lazy::new(|| {
+    if x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel" {
+        Counters::intel(x86::cpuid::cpuid!(0xa, 0))
+    } else {
+        Counters::amd()
+    }
+});
+
+/// The virtual counters of each processor with [`RdpmcPolicy::Virtualize`],
+/// indexed as [`counter_index`] returns.
+static VIRTUAL_COUNTERS: PerCpu<[AtomicU64; 48]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 48] }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_ctrl_masks() {
+        let leaf = |eax, edx| CpuIdResult {
+            eax,
+            ebx: 0,
+            ecx: 0,
+            edx,
+        };
+        // Version 5 with 8 general-purpose and 4 fixed-function counters.
+        assert_eq!(global_ctrl_mask(leaf(0x0408_0805, 0x8604)), 0xf_0000_00ff);
+        // Version 2 with 4 general-purpose and 3 fixed-function counters.
+        assert_eq!(global_ctrl_mask(leaf(0x0728_0402, 0x0603)), 0x7_0000_000f);
+        assert_eq!(global_ctrl_mask(leaf(0, 0)), 0);
+    }
+
+    #[test]
+    fn counter_indexes() {
+        let leaf = |eax, edx| CpuIdResult {
+            eax,
+            ebx: 0,
+            ecx: 0,
+            edx,
+        };
+        // 8 general-purpose and 4 fixed-function counters.
+        let counters = Counters::intel(leaf(0x0408_0805, 0x8604));
+        assert_eq!(
+            counters,
+            Counters {
+                general: 8,
+                fixed: 4
+            }
+        );
+        assert_eq!(counter_index(0, counters), Some(0));
+        assert_eq!(counter_index(7, counters), Some(7));
+        assert_eq!(counter_index(8, counters), None);
+        assert_eq!(counter_index(0x4000_0000, counters), Some(8));
+        assert_eq!(counter_index(0x4000_0003, counters), Some(11));
+        assert_eq!(counter_index(0x4000_0004, counters), None);
+        // The fast-read bit.
+        assert_eq!(counter_index(0x8000_0000, counters), None);
+        // Version 1 has no fixed-function counters.
+        let counters = Counters::intel(leaf(0x0728_0201, 0x0603));
+        assert_eq!(counter_index(0x4000_0000, counters), None);
+    }
+}
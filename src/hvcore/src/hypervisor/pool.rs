@@ -0,0 +1,71 @@
+//! This module implements pools of objects allocated in advance.
+//!
+//! Allocating from the global allocator while handling a VM-exit may fail when
+//! the fixed-size heap is exhausted, and takes the allocator lock the guest may
+//! hold on the same processor. Objects needed at VM-exit time, such as pages
+//! for snapshots and page tables for splitting large pages, are taken from
+//! pools filled at initialization instead. See [`super::allocator`]. Log
+//! records do not need a pool, as [`super::log_ring`] is a fixed-size buffer.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::support::zeroed_box;
+
+/// A pool of zero-initialized boxes of `T`.
+#[derive(Debug)]
+pub(crate) struct Pool<T> {
+    free: Vec<Box<T>>,
+}
+
+impl<T> Pool<T> {
+    /// Creates a pool with `count` objects.
+    pub(crate) fn new(count: usize) -> Self {
+        let mut pool = Self { free: Vec::new() };
+        pool.fill(count);
+        pool
+    }
+
+    /// Adds `count` objects to the pool.
+    pub(crate) fn fill(&mut self, count: usize) {
+        self.free.reserve(count);
+        self.free.extend((0..count).map(|_| zeroed_box::<T>()));
+    }
+
+    /// Takes an object from the pool, or `None` if the pool is empty. The
+    /// object is zero-initialized unless it was returned with [`Pool::put`].
+    pub(crate) fn take(&mut self) -> Option<Box<T>> {
+        self.free.pop()
+    }
+
+    /// Returns `object` to the pool as is. This does not allocate if `object`
+    /// was taken from this pool.
+    pub(crate) fn put(&mut self, object: Box<T>) {
+        self.free.push(object);
+    }
+
+    /// Returns `true` if the pool is empty.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_and_put() {
+        let mut pool = Pool::<[u64; 4]>::new(2);
+
+        let mut object = pool.take().unwrap();
+        assert_eq!(*object, [0; 4]);
+        object[0] = 1;
+        let _ = pool.take().unwrap();
+        assert!(pool.take().is_none());
+        assert!(pool.is_empty());
+
+        pool.put(object);
+        assert_eq!(*pool.take().unwrap(), [1, 0, 0, 0]);
+        assert!(pool.is_empty());
+    }
+}
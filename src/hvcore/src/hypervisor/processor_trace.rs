@@ -0,0 +1,131 @@
+//! This module implements the handling of Intel Processor Trace (PT).
+
+use alloc::boxed::Box;
+use bit_field::BitField;
+use spin::Once;
+use x86::cpuid::CpuIdResult;
+
+use super::{
+    SHARED_HOST_DATA,
+    config::ProcessorTracePolicy,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+    support::zeroed_box,
+    x86_instructions::wrmsr,
+};
+
+/// The size of the buffer each processor traces the guest into in the
+/// host-owned mode.
+pub const BUFFER_SIZE: usize = 0x1_0000;
+
+/// The MSRs access to which is intercepted in the host-owned mode: the
+/// IA32_RTIT_* MSRs, including the CR3 filter and the four address ranges.
+pub(crate) const INTERCEPTED_MSRS: [u32; 13] = [
+    x86::msr::MSR_IA32_RTIT_OUTPUT_BASE,
+    x86::msr::MSR_IA32_RTIT_OUTPUT_MASK_PTRS,
+    x86::msr::MSR_IA32_RTIT_CTL,
+    x86::msr::MSR_IA32_RTIT_STATUS,
+    IA32_RTIT_CR3_MATCH,
+    IA32_RTIT_ADDR0_A,
+    IA32_RTIT_ADDR0_A + 1,
+    IA32_RTIT_ADDR0_A + 2,
+    IA32_RTIT_ADDR0_A + 3,
+    IA32_RTIT_ADDR0_A + 4,
+    IA32_RTIT_ADDR0_A + 5,
+    IA32_RTIT_ADDR0_A + 6,
+    IA32_RTIT_ADDR0_A + 7,
+];
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+const IA32_RTIT_CR3_MATCH: u32 = 0x572;
+const IA32_RTIT_ADDR0_A: u32 = 0x580;
+
+// See: 33.2.7 Intel® Processor Trace MSRs
+const RTIT_CTL_TRACE_EN: u64 = 1 << 0;
+const RTIT_CTL_OS: u64 = 1 << 2;
+const RTIT_CTL_USER: u64 = 1 << 3;
+const RTIT_CTL_BRANCH_EN: u64 = 1 << 13;
+
+/// Returns `true` if PT is supported by the processor.
+pub(crate) fn is_supported() -> bool {
+    // See: Table 1-23. Structured Extended Feature Leaf, Function 0, EBX, ECX,
+    //      and EDX Exx Bit Definitions
+    x86::cpuid::cpuid!(0x7, 0x0).ebx.get_bit(25)
+}
+
+/// Returns `true` if PT is reserved for the hypervisor.
+pub(crate) fn host_owned() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.processor_trace == ProcessorTracePolicy::Host
+        && is_supported()
+}
+
+/// Returns the result of CPUID `leaf` and `sub_leaf` the guest sees, given
+/// that of the processor. PT is reported as unsupported in the host-owned
+/// mode.
+pub(crate) fn filter_cpuid(leaf: u32, sub_leaf: u32, mut cpuid_result: CpuIdResult) -> CpuIdResult {
+    if !host_owned() {
+        return cpuid_result;
+    }
+    // See: CPUID—CPU Identification, Leaf 07H and Leaf 14H
+    if leaf == 7 && sub_leaf == 0 {
+        cpuid_result.ebx &= !(1 << 25);
+    } else if leaf == 0x14 {
+        cpuid_result = CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+    }
+    cpuid_result
+}
+
+/// Points the output of PT on the current processor `id` to its host-owned
+/// buffer, and returns the value of IA32_RTIT_CTL to trace the guest with, or
+/// `None` if the single-range output scheme is not supported.
+pub(crate) fn start(id: usize) -> Option<u64> {
+    // CPUID.(EAX=14H,ECX=0):ECX[2] indicates the single-range output scheme.
+    // See: CPUID—CPU Identification, Leaf 14H
+    if !x86::cpuid::cpuid!(0x14, 0x0).ecx.get_bit(2) {
+        return None;
+    }
+
+    // The single range is a power-of-two sized region aligned to its size.
+    // The mask is the size minus one, and the output offset in the upper 32
+    // bits starts at zero.
+    // See: 33.2.7 Intel® Processor Trace MSRs
+    let buffer = BUFFERS.get(id)?.call_once(zeroed_box::<Buffer>);
+    let pa = platform_ops::get()
+        .pa(buffer.0.as_ptr() as *const _)
+        .unwrap();
+    wrmsr(x86::msr::MSR_IA32_RTIT_OUTPUT_BASE, pa);
+    wrmsr(
+        x86::msr::MSR_IA32_RTIT_OUTPUT_MASK_PTRS,
+        BUFFER_SIZE as u64 - 1,
+    );
+
+    // Trace branches in both kernel and user mode. ToPA is clear for the
+    // single-range output.
+    Some(RTIT_CTL_TRACE_EN | RTIT_CTL_OS | RTIT_CTL_USER | RTIT_CTL_BRANCH_EN)
+}
+
+/// Returns the buffer the processor `id` traces the guest into in the
+/// host-owned mode, or `None` if it does not trace.
+///
+/// The processor keeps writing the buffer while the guest runs, and wraps
+/// around when it is full. The offset of the next write is in bits 63:32 of
+/// IA32_RTIT_OUTPUT_MASK_PTRS of the processor.
+pub fn buffer(id: usize) -> Option<*const [u8]> {
+    let buffer = BUFFERS.get(id)?.get()?;
+    Some(core::ptr::slice_from_raw_parts(
+        buffer.0.as_ptr(),
+        BUFFER_SIZE,
+    ))
+}
+
+static BUFFERS: PerCpu<Once<Box<Buffer>>> = PerCpu::new([const { Once::new() }; MAX_PROCESSORS]);
+
+#[repr(C, align(0x10000))]
+struct Buffer([u8; BUFFER_SIZE]);
+const _: () = assert!(core::mem::size_of::<Buffer>() == BUFFER_SIZE);
@@ -0,0 +1,181 @@
+//! This module implements the paravirtual clock, a page shared with the guest
+//! that converts the guest TSC into the host TSC.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::boxed::Box;
+use spin::Once;
+use x86::cpuid::CpuIdResult;
+
+use super::{SHARED_HOST_DATA, deterministic_time, platform_ops, support::zeroed_box, tsc_scaling};
+
+/// The CPUID leaf reporting the features of the hypervisor in EAX, in the
+/// range of hypervisor leaves but away from those of Hyper-V.
+pub const CPUID_FEATURES: u32 = 0x4000_0100;
+
+/// The bit of EAX of [`CPUID_FEATURES`] indicating that the paravirtual clock
+/// is available.
+pub const CPUID_FEATURES_PV_CLOCK: u32 = 1 << 0;
+
+/// The error type for [`HC_PV_CLOCK_PAGE`](super::hypercall::HC_PV_CLOCK_PAGE).
+/// The value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum PvClockError {
+    #[error("the paravirtual clock is disabled")]
+    Disabled = 1,
+
+    #[error("the guest TSC does not advance with the host TSC")]
+    Unavailable = 2,
+}
+
+/// The layout of the paravirtual clock page.
+///
+/// In-guest agents convert guest timestamps into the host ones of traces such
+/// as [`super::exit_stats`] with it, in the same way as the Hyper-V reference
+/// TSC page and the KVM paravirtual clock.
+///
+/// To read the fields consistently, the guest reads `sequence`, the other
+/// fields and `sequence` again, and retries if it changed. `0` in `sequence`
+/// means the page is not valid.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct PvClockPage {
+    pub sequence: AtomicU32,
+    pub reserved: u32,
+
+    /// The guest TSC at `host_tsc_base`.
+    pub guest_tsc_base: u64,
+
+    /// The host TSC at `guest_tsc_base`.
+    pub host_tsc_base: u64,
+
+    /// The host TSC ticks per guest TSC tick, an unsigned fixed-point number
+    /// with 32 fractional bits.
+    pub scale: u64,
+
+    /// The host TSC frequency in kHz, or `0` if the processor does not report
+    /// it.
+    pub host_tsc_khz: u64,
+}
+
+impl PvClockPage {
+    /// Converts `guest_tsc` into the host TSC.
+    pub fn host_tsc(&self, guest_tsc: u64) -> u64 {
+        let elapsed = guest_tsc.wrapping_sub(self.guest_tsc_base);
+        let scaled = ((u128::from(elapsed) * u128::from(self.scale)) >> 32) as u64;
+        self.host_tsc_base.wrapping_add(scaled)
+    }
+}
+
+/// Returns the result of CPUID `leaf` the guest sees, given that of the
+/// processor.
+pub(crate) fn filter_cpuid(leaf: u32, cpuid_result: CpuIdResult) -> CpuIdResult {
+    if leaf != CPUID_FEATURES {
+        return cpuid_result;
+    }
+    let mut eax = 0;
+    if enabled() {
+        eax |= CPUID_FEATURES_PV_CLOCK;
+    }
+    CpuIdResult {
+        eax,
+        ebx: 0,
+        ecx: 0,
+        edx: 0,
+    }
+}
+
+/// Returns the GPA of the page, filling it on the first call. The page is not
+/// available in the deterministic time mode, where the guest TSC does not
+/// advance with the host TSC.
+pub(crate) fn page_gpa() -> Result<u64, PvClockError> {
+    if !SHARED_HOST_DATA.get().unwrap().config.pv_clock {
+        return Err(PvClockError::Disabled);
+    }
+    if deterministic_time::enabled() {
+        return Err(PvClockError::Unavailable);
+    }
+    let page = PAGE.call_once(|| {
+        let mut page = zeroed_box::<Page>();
+        fill(&mut page.0);
+        page
+    });
+    Ok(platform_ops::get()
+        .pa(page.as_ref() as *const _ as *const _)
+        .unwrap())
+}
+
+fn enabled() -> bool {
+    SHARED_HOST_DATA.get().unwrap().config.pv_clock && !deterministic_time::enabled()
+}
+
+/// Fills `page` with the relation between the guest and host TSCs. They are
+/// the same unless TSC scaling is active, where the guest TSC starts from the
+/// host TSC at the time scaling started. See [`tsc_scaling`].
+fn fill(page: &mut PvClockPage) {
+    let ratio = SHARED_HOST_DATA.get().unwrap().config.tsc_ratio;
+    match (tsc_scaling::start_tsc(), ratio) {
+        (Some(start), Some(ratio)) => {
+            page.guest_tsc_base = start;
+            page.host_tsc_base = start;
+            page.scale = (u64::from(ratio.denominator) << 32) / u64::from(ratio.numerator);
+        }
+        _ => page.scale = 1 << 32,
+    }
+    if x86::cpuid::cpuid!(0x0).eax >= 0x15 {
+        page.host_tsc_khz = host_tsc_khz(x86::cpuid::cpuid!(0x15));
+    }
+    page.sequence.store(1, Ordering::Release);
+}
+
+/// Returns the TSC frequency in kHz given CPUID leaf 15H, or `0` if it is not
+/// enumerated.
+///
+/// See: CPUID—CPU Identification, Leaf 15H
+fn host_tsc_khz(leaf: CpuIdResult) -> u64 {
+    let (denominator, numerator, crystal_hz) = (leaf.eax, leaf.ebx, leaf.ecx);
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return 0;
+    }
+    u64::from(crystal_hz) * u64::from(numerator) / u64::from(denominator) / 1000
+}
+
+static PAGE: Once<Box<Page>> = Once::new();
+
+#[repr(C, align(4096))]
+struct Page(PvClockPage);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_tsc() {
+        let mut page = PvClockPage {
+            scale: 1 << 32,
+            ..Default::default()
+        };
+        assert_eq!(page.host_tsc(0x1234), 0x1234);
+
+        // The guest TSC runs at twice the host frequency from 1000.
+        page.guest_tsc_base = 1000;
+        page.host_tsc_base = 1000;
+        page.scale = 1 << 31;
+        assert_eq!(page.host_tsc(1000), 1000);
+        assert_eq!(page.host_tsc(3000), 2000);
+    }
+
+    #[test]
+    fn tsc_khz() {
+        let leaf = |eax, ebx, ecx| CpuIdResult {
+            eax,
+            ebx,
+            ecx,
+            edx: 0,
+        };
+        assert_eq!(host_tsc_khz(leaf(2, 200, 24_000_000)), 2_400_000);
+        assert_eq!(host_tsc_khz(leaf(2, 200, 0)), 0);
+        assert_eq!(host_tsc_khz(leaf(0, 0, 0)), 0);
+    }
+}
@@ -1,32 +1,34 @@
 use core::arch::global_asm;
 
+/// The general purpose registers, RFLAGS, RSP, RIP and some of XMM registers
+/// of the guest.
 #[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub(crate) struct Registers {
-    pub(crate) rax: u64,
-    pub(crate) rbx: u64,
-    pub(crate) rcx: u64,
-    pub(crate) rdx: u64,
-    pub(crate) rdi: u64,
-    pub(crate) rsi: u64,
-    pub(crate) rbp: u64,
-    pub(crate) r8: u64,
-    pub(crate) r9: u64,
-    pub(crate) r10: u64,
-    pub(crate) r11: u64,
-    pub(crate) r12: u64,
-    pub(crate) r13: u64,
-    pub(crate) r14: u64,
-    pub(crate) r15: u64,
-    pub(crate) rflags: u64,
-    pub(crate) rsp: u64,
-    pub(crate) rip: u64,
-    pub(crate) xmm0: Xmm,
-    pub(crate) xmm1: Xmm,
-    pub(crate) xmm2: Xmm,
-    pub(crate) xmm3: Xmm,
-    pub(crate) xmm4: Xmm,
-    pub(crate) xmm5: Xmm,
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub xmm0: Xmm,
+    pub xmm1: Xmm,
+    pub xmm2: Xmm,
+    pub xmm3: Xmm,
+    pub xmm4: Xmm,
+    pub xmm5: Xmm,
 }
 const _: () = assert!(core::mem::size_of::<Registers>() == 0xf0);
 
@@ -37,13 +39,81 @@ impl Registers {
         unsafe { capture_registers(&mut registers) };
         registers
     }
+
+    /// Returns the value of the general purpose register encoded as `index` in
+    /// instructions, eg, 0 for RAX and 15 for R15.
+    pub fn gpr(&self, index: u8) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("Invalid register index {index}"),
+        }
+    }
+
+    /// Sets `value` to the general purpose register encoded as `index` in
+    /// instructions. See [`Registers::gpr`].
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        let gpr = match index {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            _ => panic!("Invalid register index {index}"),
+        };
+        *gpr = value;
+    }
+}
+
+/// The system registers of the guest at VM-exit that are not in
+/// [`Registers`], read from the VMCS or VMCB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FullGuestState {
+    pub cs: u16,
+    pub ss: u16,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub gs_base: u64,
+    pub gdtr_base: u64,
+    pub gdtr_limit: u16,
+    pub idtr_base: u64,
+    pub idtr_limit: u16,
+    pub ldtr: u16,
+    pub tr: u16,
 }
 
 #[repr(C, align(16))]
 #[derive(Clone, Copy, Debug, Default)]
-pub(crate) struct Xmm {
-    pub(crate) low: u64,
-    pub(crate) hight: u64,
+pub struct Xmm {
+    pub low: u64,
+    pub hight: u64,
 }
 
 unsafe extern "C" {
@@ -0,0 +1,329 @@
+//! This module implements the self-test run right after virtualization.
+//!
+//! When `HvConfig::self_test` is set, each processor runs the probes below as
+//! the guest, and the results are logged as a matrix of processors and probes.
+//! The results can also be queried with [`HC_SELF_TEST_RESULTS`], so that tools
+//! running later, such as `check_hv_vendor` for UEFI, can report them.
+//! - [`Probe::Cpuid`]: CPUID causes VM-exit and returns our vendor name.
+//! - [`Probe::EptViolation`]: a write to a page made read-only in an EPT view
+//!   causes a nested page fault, and is retried in the default view. Skipped
+//!   unless `HvConfig::ept_views` is set on an Intel processor.
+//! - [`Probe::Hypercall`]: a hypercall returns to the next instruction with the
+//!   result.
+//! - [`Probe::MsrIntercepts`]: reads of MSRs covered by the MSR bitmaps (or
+//!   the MSR permission map) pass through without VM-exit.
+//! - [`Probe::ApStartup`]: the application processor was started by the
+//!   platform and runs under the hypervisor. On UEFI, the platform does not
+//!   necessarily use INIT-SIPI-SIPI, and the emulation of INIT and SIPI is
+//!   exercised only when the OS starts the processors. Skipped on the
+//!   bootstrap processor.
+//!
+//! [`HC_SELF_TEST_RESULTS`]: super::hypercall::HC_SELF_TEST_RESULTS
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86::{bits64::paging::BASE_PAGE_SIZE, msr};
+
+use super::{
+    SHARED_HOST_DATA, apic_id,
+    ept_views::EptViewError,
+    exit_stats,
+    host::VmExitKind,
+    hypercall::{
+        HC_EPT_VIEW_PROTECT, HC_EPT_VIEW_SWITCH, HC_SELF_TEST_RESULTS, hypercall_with_token,
+    },
+    hypercall_auth::AUTH,
+    is_our_hypervisor_present,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+    support::{Page, zeroed_box},
+    x86_instructions::rdmsr,
+};
+
+/// A check of the self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Cpuid,
+    EptViolation,
+    Hypercall,
+    MsrIntercepts,
+    ApStartup,
+}
+
+impl Probe {
+    /// All probes, in the order of their values.
+    pub const ALL: [Self; 5] = [
+        Self::Cpuid,
+        Self::EptViolation,
+        Self::Hypercall,
+        Self::MsrIntercepts,
+        Self::ApStartup,
+    ];
+}
+
+/// The outcome of a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Outcome {
+    NotRun = 0,
+    Passed = 1,
+    Failed = 2,
+    Skipped = 3,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::NotRun => "-",
+            Self::Passed => "pass",
+            Self::Failed => "FAIL",
+            Self::Skipped => "skip",
+        })
+    }
+}
+
+/// The outcomes of all probes on a processor, with 2 bits for each probe in
+/// the order of [`Probe::ALL`]. This is the value [`HC_SELF_TEST_RESULTS`]
+/// returns.
+///
+/// [`HC_SELF_TEST_RESULTS`]: super::hypercall::HC_SELF_TEST_RESULTS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelfTestResults(pub u64);
+
+impl SelfTestResults {
+    pub fn get(self, probe: Probe) -> Outcome {
+        match (self.0 >> (probe as u64 * 2)) & 0b11 {
+            0 => Outcome::NotRun,
+            1 => Outcome::Passed,
+            2 => Outcome::Failed,
+            _ => Outcome::Skipped,
+        }
+    }
+
+    pub fn set(&mut self, probe: Probe, outcome: Outcome) {
+        let shift = probe as u64 * 2;
+        self.0 = (self.0 & !(0b11 << shift)) | ((outcome as u64) << shift);
+    }
+
+    /// Tests whether no probe failed or was not run.
+    pub fn passed(self) -> bool {
+        Probe::ALL
+            .iter()
+            .all(|probe| matches!(self.get(*probe), Outcome::Passed | Outcome::Skipped))
+    }
+}
+
+/// Returns the results of the self-test on the current processor, or the
+/// default value if it has not run.
+pub(crate) fn current_results() -> SelfTestResults {
+    apic_id::current_processor_id().map_or(SelfTestResults::default(), results)
+}
+
+/// Returns the results of the self-test on the processor `id`, or the default
+/// value if it has not run.
+pub fn results(id: usize) -> SelfTestResults {
+    RESULTS
+        .get(id)
+        .map_or(SelfTestResults::default(), |results| {
+            SelfTestResults(results.load(Ordering::Relaxed))
+        })
+}
+
+/// Runs the self-test on all processors if enabled, and logs the results.
+pub(crate) fn run() {
+    if !SHARED_HOST_DATA.get().unwrap().config.self_test {
+        return;
+    }
+
+    for results in RESULTS.iter() {
+        results.store(0, Ordering::Relaxed);
+    }
+    platform_ops::get().run_on_all_processors(&run_on_current_processor);
+
+    let count = apic_id::PROCESSOR_COUNT
+        .load(Ordering::Relaxed)
+        .min(MAX_PROCESSORS);
+    let all_results: alloc::vec::Vec<_> = (0..count).map(results).collect();
+    let mut matrix = alloc::string::String::new();
+    let _ = write_matrix(&mut matrix, &all_results);
+    for line in matrix.lines() {
+        log::info!("{line}");
+    }
+    if all_results.iter().all(|results| results.passed()) {
+        log::info!("Self-test passed on {count} processors");
+    } else {
+        log::error!("Self-test failed");
+    }
+}
+
+/// Writes the matrix of `all_results`, one row for each processor.
+fn write_matrix(out: &mut dyn fmt::Write, all_results: &[SelfTestResults]) -> fmt::Result {
+    write!(out, "{:6}", "CPU")?;
+    for probe in Probe::ALL {
+        write!(out, " {:14}", alloc::format!("{probe:?}"))?;
+    }
+    writeln!(out)?;
+    for (id, results) in all_results.iter().enumerate() {
+        write!(out, "#{id:<5}")?;
+        for probe in Probe::ALL {
+            write!(out, " {:14}", results.get(probe))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn run_on_current_processor() {
+    let Some(id) = apic_id::current_processor_id() else {
+        log::error!("The processor is not registered");
+        return;
+    };
+    let Some(slot) = RESULTS.get(id) else {
+        return;
+    };
+
+    let mut results = SelfTestResults::default();
+    for probe in Probe::ALL {
+        let outcome = match probe {
+            Probe::Cpuid => probe_cpuid(id),
+            Probe::EptViolation => probe_ept_violation(id),
+            Probe::Hypercall => probe_hypercall(id),
+            Probe::MsrIntercepts => probe_msr_intercepts(id),
+            Probe::ApStartup => probe_ap_startup(id),
+        };
+        if outcome == Outcome::Failed {
+            log::error!("Self-test {probe:?} failed");
+        }
+        results.set(probe, outcome);
+    }
+    slot.store(results.0, Ordering::Relaxed);
+}
+
+fn outcome(passed: bool) -> Outcome {
+    if passed {
+        Outcome::Passed
+    } else {
+        Outcome::Failed
+    }
+}
+
+fn probe_cpuid(id: usize) -> Outcome {
+    let before = exit_stats::count(id, VmExitKind::Cpuid);
+    let present = is_our_hypervisor_present();
+    outcome(present && exit_stats::count(id, VmExitKind::Cpuid) > before)
+}
+
+fn probe_ept_violation(id: usize) -> Outcome {
+    const VIEW: u64 = 1;
+    const READ_EXECUTE: u64 = 0b101;
+    const READ_WRITE_EXECUTE: u64 = 0b111;
+    const MAGIC: u64 = 0x5e1f_7e57;
+
+    if SHARED_HOST_DATA.get().unwrap().config.ept_views == 0 {
+        return Outcome::Skipped;
+    }
+
+    let mut page = zeroed_box::<Page>();
+    let ptr = core::ptr::from_mut(page.as_mut()).cast::<u64>();
+    let gpa = platform_ops::get().pa(ptr.cast()).unwrap();
+    let size = BASE_PAGE_SIZE as u64;
+    let token = AUTH.token();
+
+    let result = hypercall_with_token(HC_EPT_VIEW_PROTECT, [VIEW, gpa | READ_EXECUTE, size], token);
+    if result == EptViewError::Unsupported as u64 {
+        return Outcome::Skipped;
+    }
+    if result != 0 || hypercall_with_token(HC_EPT_VIEW_SWITCH, [VIEW, 0, 0], token) != 0 {
+        return Outcome::Failed;
+    }
+
+    // The write causes a nested page fault, where the processor is switched
+    // back to the default view, and is retried there.
+    let before = exit_stats::count(id, VmExitKind::NestedPageFault);
+    unsafe { ptr.write_volatile(MAGIC) };
+    let faulted = exit_stats::count(id, VmExitKind::NestedPageFault) > before;
+    let written = unsafe { ptr.read_volatile() } == MAGIC;
+
+    let _ = hypercall_with_token(HC_EPT_VIEW_SWITCH, [0, 0, 0], token);
+    let restored = hypercall_with_token(
+        HC_EPT_VIEW_PROTECT,
+        [VIEW, gpa | READ_WRITE_EXECUTE, size],
+        token,
+    ) == 0;
+    outcome(faulted && written && restored)
+}
+
+fn probe_hypercall(id: usize) -> Outcome {
+    // The results of this processor are not stored yet.
+    let before = exit_stats::count(id, VmExitKind::Hypercall);
+    let result = hypercall_with_token(HC_SELF_TEST_RESULTS, [0; 3], AUTH.token());
+    outcome(result == 0 && exit_stats::count(id, VmExitKind::Hypercall) == before + 1)
+}
+
+fn probe_msr_intercepts(id: usize) -> Outcome {
+    let before = exit_stats::count(id, VmExitKind::Rdmsr);
+    let efer = rdmsr(msr::IA32_EFER);
+    let apic_base = rdmsr(msr::IA32_APIC_BASE);
+    let consistent = rdmsr(msr::IA32_EFER) == efer && rdmsr(msr::IA32_APIC_BASE) == apic_base;
+
+    // "IA32_EFER.LMA (bit 10)" is set as the guest runs in 64-bit mode.
+    // See: 2.2.1 Extended Feature Enable Register (IA32_EFER)
+    let long_mode = efer & (1 << 10) != 0;
+    outcome(consistent && long_mode && exit_stats::count(id, VmExitKind::Rdmsr) == before)
+}
+
+fn probe_ap_startup(id: usize) -> Outcome {
+    if id == 0 {
+        return Outcome::Skipped;
+    }
+    outcome(is_our_hypervisor_present())
+}
+
+static RESULTS: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    #[test]
+    fn pack_results() {
+        let mut results = SelfTestResults::default();
+        assert_eq!(results.get(Probe::Cpuid), Outcome::NotRun);
+        assert!(!results.passed());
+
+        for probe in Probe::ALL {
+            results.set(probe, Outcome::Passed);
+        }
+        results.set(Probe::ApStartup, Outcome::Skipped);
+        assert!(results.passed());
+        assert_eq!(results.0, 0b11_01_01_01_01);
+
+        results.set(Probe::Hypercall, Outcome::Failed);
+        assert_eq!(results.get(Probe::Hypercall), Outcome::Failed);
+        assert_eq!(results.get(Probe::MsrIntercepts), Outcome::Passed);
+        assert!(!results.passed());
+    }
+
+    #[test]
+    fn matrix() {
+        let mut results = SelfTestResults::default();
+        results.set(Probe::Cpuid, Outcome::Passed);
+        results.set(Probe::EptViolation, Outcome::Failed);
+
+        let mut matrix = String::new();
+        write_matrix(&mut matrix, &[results]).unwrap();
+        let lines: alloc::vec::Vec<_> = matrix.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            [
+                "CPU    Cpuid          EptViolation   Hypercall      MsrIntercepts  ApStartup",
+                "#0     pass           FAIL           -              -              -",
+            ]
+        );
+    }
+}
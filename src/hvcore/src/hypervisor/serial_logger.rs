@@ -4,7 +4,7 @@
 use core::fmt::Write;
 use spin::{Mutex, Once};
 
-use super::support::InterruptGuard;
+use super::{apic_id, log_ring, support::InterruptGuard};
 
 static LOGGER: Once<SerialLogger> = Once::new();
 
@@ -46,24 +46,33 @@ impl log::Log for SerialLogger {
             // Disable interrupt while acquiring the mutex, to reduce the chance
             // of reentering this code.
             let _intr_guard = InterruptGuard::new();
-            let id = apic_id();
-            let mut uart = self.port.lock();
-            let _ = uart.write_fmt(format_args!(
-                "#{id}:{:5}: {}\n",
-                record.level(),
-                record.args()
-            ));
+            let id = apic_id::get();
+            let line = format_args!("#{id}:{:5}: {}\n", record.level(), record.args());
+            log_ring::write(line);
+            let _ = self.port.lock().write_fmt(line);
         }
     }
 
     fn flush(&self) {}
 }
 
-struct Uart {
+/// Returns the serial port the log is written to, without initializing it
+/// again. Used by the GDB stub. See [`super::gdb_stub`].
+pub(crate) fn debug_port() -> Uart {
+    Uart {
+        port: SerialPort::Com1 as u16,
+    }
+}
+
+pub(crate) struct Uart {
     port: u16,
 }
 
 impl Uart {
+    const UART_OFFSET_LINE_STATUS: u16 = 5;
+    const UART_OFFSET_LINE_STATUS_DR: u8 = 1u8 << 0;
+    const UART_OFFSET_LINE_STATUS_THRE: u8 = 1u8 << 5;
+
     fn new(port: SerialPort, baud_rate: u64) -> Self {
         let port = port as u16;
         Self::init(port, baud_rate);
@@ -94,16 +103,32 @@ impl Uart {
     }
 }
 
+impl Uart {
+    /// Waits for and returns a received byte.
+    pub(crate) fn read_byte(&mut self) -> u8 {
+        while (inb(self.port + Self::UART_OFFSET_LINE_STATUS) & Self::UART_OFFSET_LINE_STATUS_DR)
+            == 0
+        {
+            core::hint::spin_loop();
+        }
+        inb(self.port)
+    }
+
+    /// Waits for the transmitter to become empty and sends `data`.
+    pub(crate) fn write_byte(&mut self, data: u8) {
+        while (inb(self.port + Self::UART_OFFSET_LINE_STATUS) & Self::UART_OFFSET_LINE_STATUS_THRE)
+            == 0
+        {
+            core::hint::spin_loop();
+        }
+        outb(self.port, data);
+    }
+}
+
 impl core::fmt::Write for Uart {
     fn write_str(&mut self, msg: &str) -> Result<(), core::fmt::Error> {
-        const UART_OFFSET_LINE_STATUS: u16 = 5;
-        const UART_OFFSET_LINE_STATUS_THRE: u8 = 1u8 << 5;
-
         for data in msg.bytes() {
-            while (inb(self.port + UART_OFFSET_LINE_STATUS) & UART_OFFSET_LINE_STATUS_THRE) == 0 {
-                core::hint::spin_loop();
-            }
-            outb(self.port, data);
+            self.write_byte(data);
         }
         Ok(())
     }
@@ -116,9 +141,3 @@ fn outb(port: u16, data: u8) {
 fn inb(port: u16) -> u8 {
     unsafe { x86::io::inb(port) }
 }
-
-fn apic_id() -> u8 {
-    // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
-    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
-    (x86::cpuid::cpuid!(0x1).ebx >> 24) as _
-}
@@ -0,0 +1,108 @@
+//! This module implements the controlled teardown of the hypervisor with
+//! [`HC_SHUTDOWN_QUERY`] and [`HC_SHUTDOWN_COMMIT`].
+//!
+//! Before unloading the hypervisor, the guest queries the status of each
+//! processor to collect the health of the hypervisor: the number of VM-exits,
+//! the last failed hypercall, and the number of hooks still in effect. It then
+//! commits the shutdown on each processor, which undoes what the hypervisor
+//! changed in this order, and devirtualizes the processor:
+//! 1. Tears down hooks: exception policies are reset to passthrough, the
+//!    default EPT view is activated, and the descriptor tables are no longer
+//!    write-protected on the processor.
+//! 2. Restores the values of intercepted MSRs the hypervisor changed, that
+//!    is, the event selectors with the counters isolated on AMD.
+//! 3. Devirtualizes the processor as [`HC_DEVIRTUALIZE`].
+//!
+//! Exception policies are shared by all processors. Execute hooks cannot be
+//! removed, and disappear with the nested paging structures.
+//!
+//! [`HC_SHUTDOWN_QUERY`]: super::hypercall::HC_SHUTDOWN_QUERY
+//! [`HC_SHUTDOWN_COMMIT`]: super::hypercall::HC_SHUTDOWN_COMMIT
+//! [`HC_DEVIRTUALIZE`]: super::hypercall::HC_DEVIRTUALIZE
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{
+    apic_id,
+    ept_views::DEFAULT_EPT_VIEW,
+    exception_policy, exit_stats, hooks,
+    host::Guest,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    pmu, table_integrity,
+};
+
+/// The status of a processor returned with
+/// [`HC_SHUTDOWN_QUERY`](super::hypercall::HC_SHUTDOWN_QUERY).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessorStatus {
+    /// The total number of VM-exits.
+    pub exits: u64,
+
+    /// The last failed hypercall, with the number in the upper 32 bits and the
+    /// value returned in RAX in the lower 32 bits, or 0.
+    pub last_error: u64,
+
+    /// The number of hooks in effect: execute hooks, write-protected pages of
+    /// descriptor tables, and exceptions with a policy.
+    pub hooks: u64,
+}
+
+/// The error type for
+/// [`HC_SHUTDOWN_QUERY`](super::hypercall::HC_SHUTDOWN_QUERY). The value is
+/// returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ShutdownError {
+    #[error("the processor ID is out of range")]
+    InvalidProcessor = 1,
+}
+
+/// Returns the status of the processor `id`.
+pub(crate) fn query(id: usize) -> Result<ProcessorStatus, ShutdownError> {
+    let last_error = LAST_ERRORS
+        .get(id)
+        .filter(|_| id < apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed))
+        .ok_or(ShutdownError::InvalidProcessor)?;
+    let exception_policies = exception_policy::bitmap() & !exception_policy::REQUIRED_BITMAP;
+    Ok(ProcessorStatus {
+        exits: exit_stats::total(id),
+        last_error: last_error.load(Ordering::Relaxed),
+        hooks: (hooks::exec_hooks().len() + table_integrity::page_count()) as u64
+            + u64::from(exception_policies.count_ones()),
+    })
+}
+
+/// Records the failure of the hypercall `number` that returns `value` on the
+/// current processor.
+pub(crate) fn record_error(number: u64, value: u64) {
+    if let Some(last_error) = LAST_ERRORS.current() {
+        last_error.store(encode_error(number, value), Ordering::Relaxed);
+    }
+}
+
+/// Tears down hooks and restores intercepted MSRs on the current processor,
+/// before it is devirtualized.
+pub(crate) fn commit<T: Guest>(guest: &mut T) {
+    exception_policy::reset();
+    let _ = guest.switch_ept_view(DEFAULT_EPT_VIEW);
+    table_integrity::unprotect_all(guest);
+    pmu::restore_event_selectors();
+}
+
+fn encode_error(number: u64, value: u64) -> u64 {
+    (number << 32) | (value & 0xffff_ffff)
+}
+
+static LAST_ERRORS: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::hypercall::HC_ACCESS_DENIED;
+
+    #[test]
+    fn error_encoding() {
+        assert_eq!(encode_error(0xe, 3), 0xe_0000_0003);
+        assert_eq!(encode_error(0xc, HC_ACCESS_DENIED), 0xc_acce_55de);
+    }
+}
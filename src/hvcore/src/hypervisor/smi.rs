@@ -0,0 +1,245 @@
+//! This module implements the handling of system management interrupts (SMIs),
+//! which the hypervisor leaves unblocked in both the guest and the host.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU8, AtomicU16, AtomicU64, Ordering},
+};
+
+use x86::dtables::DescriptorTablePointer;
+
+use super::{
+    SHARED_HOST_DATA,
+    config::SmiStorm,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    x86_instructions::{cr3, lgdt, lidt, rdmsr, sgdt, sidt},
+};
+
+/// The number of SMIs since reset.
+const MSR_SMI_COUNT: u32 = 0x34;
+
+/// Where the number of SMIs of a processor comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Source {
+    /// SMIs are not counted.
+    None = 0,
+    /// MSR_SMI_COUNT, on Intel processors since Nehalem.
+    Msr = 1,
+    /// The SMI intercept, on AMD. SMIs taken while the host runs are not
+    /// counted.
+    Intercept = 2,
+}
+
+/// The SMI counters and the host state of a processor.
+struct SmiState {
+    source: AtomicU8,
+
+    /// The number of SMIs at virtualization.
+    baseline: AtomicU64,
+
+    /// The number of SMIs intercepted.
+    intercepted: AtomicU64,
+
+    /// The TSC and the number of SMIs when the current window started.
+    window_start: AtomicU64,
+    window_count: AtomicU64,
+
+    /// The host state on the first VM-exit. CR3 is 0 until recorded.
+    cr3: AtomicU64,
+    gdtr_base: AtomicU64,
+    gdtr_limit: AtomicU16,
+    idtr_base: AtomicU64,
+    idtr_limit: AtomicU16,
+}
+
+impl SmiState {
+    const fn new() -> Self {
+        Self {
+            source: AtomicU8::new(Source::None as u8),
+            baseline: AtomicU64::new(0),
+            intercepted: AtomicU64::new(0),
+            window_start: AtomicU64::new(0),
+            window_count: AtomicU64::new(0),
+            cr3: AtomicU64::new(0),
+            gdtr_base: AtomicU64::new(0),
+            gdtr_limit: AtomicU16::new(0),
+            idtr_base: AtomicU64::new(0),
+            idtr_limit: AtomicU16::new(0),
+        }
+    }
+
+    fn source(&self) -> Source {
+        match self.source.load(Ordering::Relaxed) {
+            1 => Source::Msr,
+            2 => Source::Intercept,
+            _ => Source::None,
+        }
+    }
+
+    /// Returns the number of SMIs the processor has taken, as the source
+    /// counts them.
+    fn current(&self) -> u64 {
+        match self.source() {
+            Source::Msr => rdmsr(MSR_SMI_COUNT),
+            Source::Intercept => self.intercepted.load(Ordering::Relaxed),
+            Source::None => 0,
+        }
+    }
+}
+
+/// Starts counting SMIs on the processor `id` if configured.
+pub(crate) fn initialize(id: usize) {
+    let config = &SHARED_HOST_DATA.get().unwrap().config;
+    let Some(state) = STATES.get(id) else {
+        return;
+    };
+    if config.smi_storm.is_none() {
+        return;
+    }
+
+    let intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    let source = if intel {
+        Source::Msr
+    } else if config.smm_exiting {
+        Source::Intercept
+    } else {
+        log::warn!("SMIs are counted only with smm_exiting on AMD");
+        Source::None
+    };
+    state.source.store(source as u8, Ordering::Relaxed);
+    let count = state.current();
+    state.baseline.store(count, Ordering::Relaxed);
+    state.window_count.store(count, Ordering::Relaxed);
+    state
+        .window_start
+        .store(unsafe { _rdtsc() }, Ordering::Relaxed);
+}
+
+/// Samples the number of SMIs on the processor `id` if the current window has
+/// elapsed, and reports SMI storms. Called on VM-exit, so a processor whose
+/// guest rarely causes VM-exit is sampled over longer windows, and the rate is
+/// averaged over them.
+pub(crate) fn sample(id: usize) {
+    let Some(storm) = SHARED_HOST_DATA.get().unwrap().config.smi_storm else {
+        return;
+    };
+    let Some(state) = STATES.get(id) else {
+        return;
+    };
+    if state.source() == Source::None {
+        return;
+    }
+    // The host state is the one VM-exit loads, which may differ from the
+    // state at virtualization, for example, with the page tables of the host.
+    if state.cr3.load(Ordering::Relaxed) == 0 {
+        record_host_state(state);
+    }
+
+    let now = unsafe { _rdtsc() };
+    let elapsed = now.wrapping_sub(state.window_start.load(Ordering::Relaxed));
+    if elapsed < storm.window {
+        return;
+    }
+    let count = state.current();
+    let smis = count.wrapping_sub(state.window_count.load(Ordering::Relaxed));
+    state.window_start.store(now, Ordering::Relaxed);
+    state.window_count.store(count, Ordering::Relaxed);
+    if smis == 0 {
+        return;
+    }
+
+    if is_storm(&storm, smis, elapsed) {
+        log::warn!("SMI storm on processor #{id}: {smis} SMIs in {elapsed} cycles");
+    }
+    verify_host_state(id, state);
+}
+
+/// Handles the VM-exit due to an SMI on the processor `id`. The SMI is still
+/// pending, and is taken as soon as GIF is set. NMIs pending then are delivered
+/// to the host too, which panics on them.
+///
+/// See: 15.13.3 SMI Intercept
+pub(crate) fn handle_smi(id: usize) {
+    if let Some(state) = STATES.get(id) {
+        let _ = state.intercepted.fetch_add(1, Ordering::Relaxed);
+    }
+    // Take the SMI in the host rather than on VMRUN, which would cause the
+    // same VM-exit again. Interrupts remain disabled with RFLAGS.IF.
+    // See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+    unsafe { core::arch::asm!("stgi", "clgi") };
+}
+
+/// Returns the number of SMIs the processor `id` has taken since
+/// virtualization as of the last sample, or `None` if they are not counted.
+pub(crate) fn count(id: usize) -> Option<u64> {
+    let state = STATES.get(id)?;
+    if state.source() == Source::None {
+        return None;
+    }
+    let count = state.window_count.load(Ordering::Relaxed);
+    Some(count.wrapping_sub(state.baseline.load(Ordering::Relaxed)))
+}
+
+/// Returns `true` if `smis` in `elapsed` cycles are at least as frequent as
+/// `storm`.
+fn is_storm(storm: &SmiStorm, smis: u64, elapsed: u64) -> bool {
+    u128::from(smis) * u128::from(storm.window) >= u128::from(storm.count) * u128::from(elapsed)
+}
+
+fn record_host_state(state: &SmiState) {
+    let (gdtr, idtr) = (sgdt(), sidt());
+    state.gdtr_base.store(gdtr.base as u64, Ordering::Relaxed);
+    state.gdtr_limit.store(gdtr.limit, Ordering::Relaxed);
+    state.idtr_base.store(idtr.base as u64, Ordering::Relaxed);
+    state.idtr_limit.store(idtr.limit, Ordering::Relaxed);
+    state.cr3.store(cr3(), Ordering::Relaxed);
+}
+
+/// Checks the host state of the processor `id` against the recorded one,
+/// reloads GDTR and IDTR if changed, and reports CR3.
+fn verify_host_state(id: usize, state: &SmiState) {
+    let (gdtr, idtr) = (sgdt(), sidt());
+    let expected_gdtr = DescriptorTablePointer::<u64> {
+        base: state.gdtr_base.load(Ordering::Relaxed) as *const u64,
+        limit: state.gdtr_limit.load(Ordering::Relaxed),
+    };
+    let expected_idtr = DescriptorTablePointer::<u64> {
+        base: state.idtr_base.load(Ordering::Relaxed) as *const u64,
+        limit: state.idtr_limit.load(Ordering::Relaxed),
+    };
+    if gdtr.base != expected_gdtr.base || gdtr.limit != expected_gdtr.limit {
+        log::error!("Host GDTR changed across SMIs on processor #{id}: {gdtr:x?}");
+        lgdt(&expected_gdtr);
+    }
+    if idtr.base != expected_idtr.base || idtr.limit != expected_idtr.limit {
+        log::error!("Host IDTR changed across SMIs on processor #{id}: {idtr:x?}");
+        lidt(&expected_idtr);
+    }
+
+    let cr3 = cr3();
+    if cr3 != state.cr3.load(Ordering::Relaxed) {
+        log::error!("Host CR3 changed across SMIs on processor #{id}: {cr3:#x}");
+    }
+}
+
+static STATES: PerCpu<SmiState> = PerCpu::new([const { SmiState::new() }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storms() {
+        let storm = SmiStorm {
+            count: 10,
+            window: 1_000_000,
+        };
+        assert!(is_storm(&storm, 10, 1_000_000));
+        assert!(!is_storm(&storm, 9, 1_000_000));
+        // A window sampled late is averaged over its length.
+        assert!(!is_storm(&storm, 10, 2_000_000));
+        assert!(is_storm(&storm, 20, 2_000_000));
+        assert!(is_storm(&storm, u64::MAX, u64::MAX));
+    }
+}
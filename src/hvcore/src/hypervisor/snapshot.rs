@@ -0,0 +1,185 @@
+//! This module implements copy-on-write snapshots of guest memory, which let
+//! the guest be reset to the snapshot point repeatedly.
+
+use alloc::{boxed::Box, vec::Vec};
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::{pool::Pool, support::Page};
+
+/// The error type for snapshot hypercalls. The value is returned in RAX.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SnapshotError {
+    #[error("snapshots are not supported on this processor or platform")]
+    Unsupported = 1,
+
+    #[error("no snapshot is taken")]
+    NoSnapshot = 2,
+
+    #[error("the snapshot pool ran out of pages and the snapshot is lost")]
+    PoolExhausted = 3,
+}
+
+/// The copies of guest pages written since the snapshot was taken.
+///
+/// Guest memory is accessed at the virtual address equal to its physical
+/// address, so this requires the host to run on the identity mapping given with
+/// `SharedHostData::pt`.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// Free pages to copy guest pages into.
+    pool: Pool<Page>,
+
+    /// The GPAs of the copied pages and their contents at the snapshot point.
+    saved: Vec<(u64, Box<Page>)>,
+
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    None,
+    Active,
+    /// A page could not be saved as the pool ran out.
+    Overflowed,
+}
+
+impl Snapshot {
+    /// Creates an empty snapshot with a pool of `pool_pages` pages.
+    pub(crate) fn new(pool_pages: usize) -> Self {
+        Self {
+            pool: Pool::new(pool_pages),
+            saved: Vec::with_capacity(pool_pages),
+            state: State::None,
+        }
+    }
+
+    /// Returns `true` if the pool has pages to take a snapshot with.
+    pub(crate) fn is_available(&self) -> bool {
+        !self.pool.is_empty() || !self.saved.is_empty()
+    }
+
+    /// Returns `true` if a snapshot is taken and writes should be saved.
+    pub(crate) fn is_active(&self) -> bool {
+        self.state != State::None
+    }
+
+    /// Takes a new snapshot, discarding the current one, if any. The caller
+    /// must make all guest RAM read-only.
+    pub(crate) fn take(&mut self) {
+        self.discard();
+        self.state = State::Active;
+    }
+
+    /// Copies the contents of the page at `gpa` if it is not saved yet. The
+    /// caller must then make the page writable.
+    pub(crate) fn save(&mut self, gpa: u64) -> Result<(), SnapshotError> {
+        assert!(self.is_active());
+        let gpa = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        if self.saved.iter().any(|(saved_gpa, _)| *saved_gpa == gpa) {
+            return Ok(());
+        }
+
+        let Some(mut page) = self.pool.take() else {
+            self.state = State::Overflowed;
+            return Err(SnapshotError::PoolExhausted);
+        };
+        unsafe { core::ptr::copy_nonoverlapping(gpa as *const Page, page.as_mut(), 1) };
+        self.saved.push((gpa, page));
+        Ok(())
+    }
+
+    /// Copies the saved pages back to guest memory, and returns their GPAs. The
+    /// caller must make those pages read-only again. The snapshot stays active.
+    ///
+    /// If the pool ran out since the snapshot was taken, the snapshot is
+    /// discarded instead, and the caller must make all guest RAM writable.
+    pub(crate) fn restore(&mut self) -> Result<Vec<u64>, SnapshotError> {
+        match self.state {
+            State::None => return Err(SnapshotError::NoSnapshot),
+            State::Overflowed => {
+                self.discard();
+                return Err(SnapshotError::PoolExhausted);
+            }
+            State::Active => {}
+        }
+
+        let mut gpas = Vec::with_capacity(self.saved.len());
+        while let Some((gpa, page)) = self.saved.pop() {
+            unsafe { core::ptr::copy_nonoverlapping(page.as_ref(), gpa as *mut Page, 1) };
+            self.pool.put(page);
+            gpas.push(gpa);
+        }
+        Ok(gpas)
+    }
+
+    /// Discards the snapshot. The caller must make all guest RAM writable.
+    pub(crate) fn discard(&mut self) {
+        for (_, page) in self.saved.drain(..) {
+            self.pool.put(page);
+        }
+        self.state = State::None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::support::zeroed_box;
+
+    /// A page of guest memory, accessible unlike [`Page`].
+    #[repr(C, align(4096))]
+    struct GuestPage([u8; BASE_PAGE_SIZE]);
+
+    fn gpa_of(page: &GuestPage) -> u64 {
+        core::ptr::from_ref(page) as u64
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let mut guest_page = zeroed_box::<GuestPage>();
+        let gpa = gpa_of(&guest_page);
+        guest_page.0[0] = 0xaa;
+
+        let mut snapshot = Snapshot::new(1);
+        assert!(!snapshot.is_active());
+        assert_eq!(snapshot.restore(), Err(SnapshotError::NoSnapshot));
+
+        snapshot.take();
+        snapshot.save(gpa + 0x10).unwrap();
+        guest_page.0[0] = 0xbb;
+        // The second fault on the same page does not overwrite the copy.
+        snapshot.save(gpa).unwrap();
+        guest_page.0[1] = 0xcc;
+
+        assert_eq!(snapshot.restore(), Ok(alloc::vec![gpa]));
+        assert_eq!(guest_page.0[0], 0xaa);
+        assert_eq!(guest_page.0[1], 0);
+
+        // The snapshot can be restored repeatedly.
+        assert!(snapshot.is_active());
+        snapshot.save(gpa).unwrap();
+        guest_page.0[0] = 0xdd;
+        assert_eq!(snapshot.restore(), Ok(alloc::vec![gpa]));
+        assert_eq!(guest_page.0[0], 0xaa);
+    }
+
+    #[test]
+    fn pool_exhaustion() {
+        let guest_pages = [zeroed_box::<GuestPage>(), zeroed_box::<GuestPage>()];
+
+        let mut snapshot = Snapshot::new(1);
+        snapshot.take();
+        snapshot.save(gpa_of(&guest_pages[0])).unwrap();
+        assert_eq!(
+            snapshot.save(gpa_of(&guest_pages[1])),
+            Err(SnapshotError::PoolExhausted)
+        );
+        assert_eq!(snapshot.restore(), Err(SnapshotError::PoolExhausted));
+        assert!(!snapshot.is_active());
+
+        // The pool is usable again after the snapshot is discarded.
+        snapshot.take();
+        snapshot.save(gpa_of(&guest_pages[1])).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+//! This module implements the handling of the speculation control MSRs,
+//! IA32_SPEC_CTRL and IA32_PRED_CMD.
+
+use bit_field::BitField;
+use spin::Lazy;
+
+use super::{SHARED_HOST_DATA, x86_instructions::wrmsr};
+
+// See: 2.1 Architectural MSRs, Intel® 64 and IA-32 Architectures Software
+// Developer's Manual Volume 4
+pub(crate) const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const PRED_CMD_IBPB: u64 = 1 << 0;
+
+/// Returns `true` if the processor has IA32_SPEC_CTRL.
+///
+/// If so, the guest keeps its value across VM-exits, and the host runs with at
+/// least the mitigations enabled at virtualization. IA32_PRED_CMD is write-only
+/// and passed through.
+pub(crate) fn is_spec_ctrl_supported() -> bool {
+    if is_intel() {
+        // IBRS and IBPB, STIBP or SSBD.
+        // See: CPUID—CPU Identification, Leaf 07H
+        let edx = x86::cpuid::cpuid!(0x7, 0x0).edx;
+        edx.get_bit(26) || edx.get_bit(27) || edx.get_bit(31)
+    } else {
+        // IBRS, STIBP or SSBD.
+        // See: E.4.7 Function 8000_0008h—Processor Capacity Parameters and
+        //      Extended Feature Identification
+        let ebx = x86::cpuid::cpuid!(0x8000_0008).ebx;
+        ebx.get_bit(14) || ebx.get_bit(15) || ebx.get_bit(24)
+    }
+}
+
+/// Issues IBPB if configured and supported. Called on each VM-exit.
+pub(crate) fn barrier_after_exit() {
+    if *IBPB_ON_EXIT {
+        wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB);
+    }
+}
+
+/// Whether IBPB is issued on VM-exit, cached as it is checked on every
+/// VM-exit.
+static IBPB_ON_EXIT: Lazy<bool> =
+    Lazy::new(|| SHARED_HOST_DATA.get().unwrap().config.ibpb_on_exit && is_ibpb_supported());
+
+fn is_ibpb_supported() -> bool {
+    if is_intel() {
+        // See: CPUID—CPU Identification, Leaf 07H
+        x86::cpuid::cpuid!(0x7, 0x0).edx.get_bit(26)
+    } else {
+        // See: E.4.7 Function 8000_0008h—Processor Capacity Parameters and
+        //      Extended Feature Identification
+        x86::cpuid::cpuid!(0x8000_0008).ebx.get_bit(12)
+    }
+}
+
+fn is_intel() -> bool {
+    x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel"
+}
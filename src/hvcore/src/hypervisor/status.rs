@@ -0,0 +1,296 @@
+//! This module implements the query of the virtualization state, so that the
+//! `check_hv_vendor` utility and third-party code can verify that all
+//! processors run under the hypervisor.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use super::{
+    SHARED_HOST_DATA, apic_id, deterministic_time, gdb_stub, hypercall,
+    hypercall_auth::AUTH,
+    hyperv, is_our_hypervisor_present, msr_monitor,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    pmu, topology, umip,
+};
+
+/// The number of processors reported by a single [`HC_STATUS`].
+///
+/// [`HC_STATUS`]: super::hypercall::HC_STATUS
+const PROCESSORS_PER_QUERY: usize = 64;
+
+/// The backend of the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Backend {
+    /// VMX and EPT.
+    Intel = 1,
+    /// SVM and NPT.
+    Amd = 2,
+}
+
+impl Backend {
+    fn current() -> Self {
+        if x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel" {
+            Self::Intel
+        } else {
+            Self::Amd
+        }
+    }
+
+    fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            1 => Some(Self::Intel),
+            2 => Some(Self::Amd),
+            _ => None,
+        }
+    }
+}
+
+/// The features in effect, as bits of [`Self::ALL`]. This is the value
+/// [`HC_STATUS`] returns in R8.
+///
+/// [`HC_STATUS`]: super::hypercall::HC_STATUS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActiveFeatures(pub u64);
+
+impl ActiveFeatures {
+    /// Copy-on-write snapshots of guest memory.
+    pub const SNAPSHOTS: u64 = 1 << 0;
+    /// EPT views in addition to the default one.
+    pub const EPT_VIEWS: u64 = 1 << 1;
+    /// The deterministic time mode.
+    pub const DETERMINISTIC_TIME: u64 = 1 << 2;
+    /// TSC scaling with `HvConfig::tsc_ratio`.
+    pub const TSC_SCALING: u64 = 1 << 3;
+    /// The GDB stub.
+    pub const GDB_STUB: u64 = 1 << 4;
+    /// The paravirtual clock page.
+    pub const PV_CLOCK: u64 = 1 << 5;
+    /// The advertise-hypervisor mode.
+    pub const ADVERTISE_HYPERVISOR: u64 = 1 << 6;
+    /// The MSR monitor.
+    pub const MSR_MONITOR: u64 = 1 << 7;
+    /// The UMIP policy intercepting descriptor-table instructions.
+    pub const UMIP: u64 = 1 << 8;
+    /// Isolation of the performance counters.
+    pub const PMU_ISOLATION: u64 = 1 << 9;
+    /// The watchdog.
+    pub const WATCHDOG: u64 = 1 << 10;
+    /// The virtualized topology CPUID leaves.
+    pub const TOPOLOGY: u64 = 1 << 11;
+    /// Hypercalls require the registered token.
+    pub const HYPERCALL_AUTH: u64 = 1 << 12;
+
+    /// All features with their names.
+    pub const ALL: [(u64, &'static str); 13] = [
+        (Self::SNAPSHOTS, "Snapshots"),
+        (Self::EPT_VIEWS, "EptViews"),
+        (Self::DETERMINISTIC_TIME, "DeterministicTime"),
+        (Self::TSC_SCALING, "TscScaling"),
+        (Self::GDB_STUB, "GdbStub"),
+        (Self::PV_CLOCK, "PvClock"),
+        (Self::ADVERTISE_HYPERVISOR, "AdvertiseHypervisor"),
+        (Self::MSR_MONITOR, "MsrMonitor"),
+        (Self::UMIP, "Umip"),
+        (Self::PMU_ISOLATION, "PmuIsolation"),
+        (Self::WATCHDOG, "Watchdog"),
+        (Self::TOPOLOGY, "Topology"),
+        (Self::HYPERCALL_AUTH, "HypercallAuth"),
+    ];
+
+    /// Tests whether all of `features` are in effect.
+    pub fn contains(self, features: u64) -> bool {
+        self.0 & features == features
+    }
+
+    /// Returns the features in effect on this system.
+    fn current() -> Self {
+        let config = &SHARED_HOST_DATA.get().unwrap().config;
+        let features = [
+            (Self::SNAPSHOTS, config.snapshot_pool_pages != 0),
+            (Self::EPT_VIEWS, config.ept_views != 0),
+            (Self::DETERMINISTIC_TIME, deterministic_time::enabled()),
+            (Self::TSC_SCALING, config.tsc_ratio.is_some()),
+            (Self::GDB_STUB, gdb_stub::enabled()),
+            (Self::PV_CLOCK, config.pv_clock),
+            (Self::ADVERTISE_HYPERVISOR, hyperv::advertised()),
+            (Self::MSR_MONITOR, msr_monitor::enabled()),
+            (Self::UMIP, umip::is_enabled()),
+            (Self::PMU_ISOLATION, pmu::isolated()),
+            (Self::WATCHDOG, config.watchdog.is_some()),
+            (Self::TOPOLOGY, topology::enabled()),
+            (Self::HYPERCALL_AUTH, AUTH.token() != 0),
+        ];
+        Self(
+            features
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .fold(0, |bits, (feature, _)| bits | feature),
+        )
+    }
+}
+
+impl fmt::Display for ActiveFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Self::ALL
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name);
+        if let Some(first) = names.next() {
+            write!(f, "{first}")?;
+        }
+        for name in names {
+            write!(f, ",{name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the hypervisor returned by [`status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HvStatus {
+    /// The backend handling the processors.
+    pub backend: Backend,
+
+    /// The features in effect.
+    pub features: ActiveFeatures,
+
+    /// Whether each processor is virtualized, indexed by the processor IDs the
+    /// hypervisor assigned in the order they came online, not by APIC IDs.
+    /// Processors that have never come online are not reported.
+    pub virtualized: Vec<bool>,
+}
+
+impl HvStatus {
+    /// Tests whether all processors that have come online are virtualized.
+    pub fn all_virtualized(&self) -> bool {
+        self.virtualized.iter().all(|virtualized| *virtualized)
+    }
+}
+
+/// The error type for [`status`] and [`HC_STATUS`]. The value is returned in
+/// RAX.
+///
+/// [`HC_STATUS`]: super::hypercall::HC_STATUS
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum StatusError {
+    #[error("the index of processors is out of range")]
+    InvalidIndex = 1,
+
+    #[error("the current processor is not virtualized")]
+    NotVirtualized = 2,
+
+    #[error("the hypervisor returned an unexpected value")]
+    UnexpectedValue = 3,
+}
+
+/// Tests whether the current processor is virtualized by the hypervisor with
+/// CPUID, which code at any CPL may execute.
+pub fn is_virtualized() -> bool {
+    is_our_hypervisor_present()
+}
+
+/// Returns the state of the hypervisor, queried from the current processor.
+/// Must be called at CPL 0, as the hypervisor injects #UD into hypercalls from
+/// other CPLs.
+///
+/// # Errors
+///
+/// Returns [`StatusError::NotVirtualized`] if the current processor is not
+/// virtualized, and other [`StatusError`]s if the hypervisor fails the query.
+pub fn status() -> Result<HvStatus, StatusError> {
+    if !is_virtualized() {
+        return Err(StatusError::NotVirtualized);
+    }
+    let mut backend = None;
+    let mut features = ActiveFeatures::default();
+    let mut virtualized = Vec::new();
+    for index in 0..MAX_PROCESSORS.div_ceil(PROCESSORS_PER_QUERY) {
+        let [rax, rdx, r8, r9] =
+            hypercall::hypercall_with_outputs(hypercall::HC_STATUS, [index as u64, 0, 0]);
+        if rax != 0 {
+            return Err(if rax == StatusError::InvalidIndex as u64 {
+                StatusError::InvalidIndex
+            } else {
+                StatusError::UnexpectedValue
+            });
+        }
+        let (raw_backend, count) = (rdx & 0xffff_ffff, (rdx >> 32) as usize);
+        backend = Some(Backend::from_raw(raw_backend).ok_or(StatusError::UnexpectedValue)?);
+        features = ActiveFeatures(r8);
+        virtualized.extend(
+            (0..PROCESSORS_PER_QUERY)
+                .take(count.saturating_sub(virtualized.len()))
+                .map(|bit| r9 & (1 << bit) != 0),
+        );
+        if virtualized.len() >= count {
+            break;
+        }
+    }
+    Ok(HvStatus {
+        backend: backend.ok_or(StatusError::UnexpectedValue)?,
+        features,
+        virtualized,
+    })
+}
+
+/// Records whether the processor `id` runs the guest.
+pub(crate) fn set_virtualized(id: usize, virtualized: bool) {
+    if let Some(flag) = VIRTUALIZED.get(id) {
+        flag.store(virtualized, Ordering::Relaxed);
+    }
+}
+
+/// Returns the values of [`HC_STATUS`] for the processors from `index` times
+/// [`PROCESSORS_PER_QUERY`]: the backend and the number of processors, the
+/// features, and the bitmap of virtualized processors.
+///
+/// [`HC_STATUS`]: super::hypercall::HC_STATUS
+pub(crate) fn query(index: u64) -> Result<[u64; 3], StatusError> {
+    let first = usize::try_from(index)
+        .ok()
+        .and_then(|index| index.checked_mul(PROCESSORS_PER_QUERY))
+        .filter(|first| *first < MAX_PROCESSORS)
+        .ok_or(StatusError::InvalidIndex)?;
+    let count = apic_id::PROCESSOR_COUNT
+        .load(Ordering::Relaxed)
+        .min(MAX_PROCESSORS);
+    let bitmap = VIRTUALIZED[first..(first + PROCESSORS_PER_QUERY).min(MAX_PROCESSORS)]
+        .iter()
+        .enumerate()
+        .filter(|(_, flag)| flag.load(Ordering::Relaxed))
+        .fold(0u64, |bitmap, (bit, _)| bitmap | (1 << bit));
+    Ok([
+        Backend::current() as u64 | ((count as u64) << 32),
+        ActiveFeatures::current().0,
+        bitmap,
+    ])
+}
+
+/// Whether each processor runs the guest.
+static VIRTUALIZED: PerCpu<AtomicBool> =
+    PerCpu::new([const { AtomicBool::new(false) }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn features() {
+        let features = ActiveFeatures(ActiveFeatures::EPT_VIEWS | ActiveFeatures::HYPERCALL_AUTH);
+        assert!(features.contains(ActiveFeatures::EPT_VIEWS));
+        assert!(!features.contains(ActiveFeatures::EPT_VIEWS | ActiveFeatures::UMIP));
+        assert_eq!(features.to_string(), "EptViews,HypercallAuth");
+        assert_eq!(ActiveFeatures::default().to_string(), "");
+        for (i, (feature, _)) in ActiveFeatures::ALL.iter().enumerate() {
+            assert_eq!(*feature, 1 << i);
+        }
+    }
+}
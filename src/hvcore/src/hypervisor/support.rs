@@ -1,10 +1,25 @@
-use core::alloc::Layout;
+use core::{
+    alloc::Layout,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
 
 use alloc::{alloc::handle_alloc_error, boxed::Box};
 use x86::bits64::{paging::BASE_PAGE_SIZE, rflags};
 
+use crate::hypervisor::platform_ops;
+
 /// Returns zero-initialized Box of `T` without using stack during construction.
+///
+/// `Box::new` may build the value on the stack before moving it to the heap,
+/// especially in debug builds, which overflows small stacks, such as 12KB
+/// kernel stacks on Windows, with structures like `EptsRaw` that are several
+/// MB. This allocates the exact layout of `T` with `alloc_zeroed` instead.
+/// `T` must be valid when all bytes are zero.
 pub(crate) fn zeroed_box<T>() -> Box<T> {
+    // `alloc_zeroed` with a zero-sized layout is undefined behavior.
+    const { assert!(size_of::<T>() != 0) };
+
     let layout = Layout::new::<T>();
     let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) }.cast::<T>();
     if ptr.is_null() {
@@ -13,6 +28,53 @@ pub(crate) fn zeroed_box<T>() -> Box<T> {
     unsafe { Box::from_raw(ptr) }
 }
 
+/// Zero-initialized `T` in physically contiguous memory allocated with
+/// [`platform_ops::PlatformOps::alloc_contiguous`].
+pub(crate) struct ContiguousBox<T> {
+    ptr: NonNull<T>,
+}
+
+impl<T> ContiguousBox<T> {
+    /// Allocates `T` at the physical address that does not exceed `max_pa`.
+    pub(crate) fn new(max_pa: u64) -> Self {
+        const { assert!(align_of::<T>() <= BASE_PAGE_SIZE) };
+
+        let size = size_of::<T>();
+        let ptr = platform_ops::get()
+            .alloc_contiguous(size, max_pa)
+            .cast::<T>();
+        let Some(ptr) = NonNull::new(ptr) else {
+            handle_alloc_error(Layout::new::<T>());
+        };
+        unsafe { ptr.cast::<u8>().write_bytes(0, size) };
+        Self { ptr }
+    }
+}
+
+impl<T> Deref for ContiguousBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for ContiguousBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for ContiguousBox<T> {
+    fn drop(&mut self) {
+        unsafe { self.ptr.drop_in_place() };
+        platform_ops::get().free_contiguous(self.ptr.as_ptr().cast(), size_of::<T>());
+    }
+}
+
+unsafe impl<T: Send> Send for ContiguousBox<T> {}
+unsafe impl<T: Sync> Sync for ContiguousBox<T> {}
+
 /// The structure representing a single memory page (4KB).
 //
 // This does not _always_ have to be allocated at the page aligned address, but
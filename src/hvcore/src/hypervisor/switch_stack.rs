@@ -1,18 +1,33 @@
 use alloc::alloc::handle_alloc_error;
-use core::{alloc::Layout, arch::global_asm};
+use core::{
+    alloc::Layout,
+    arch::global_asm,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
-use crate::hypervisor::support::Page;
+use crate::hypervisor::{
+    apic_id,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    support::Page,
+};
 
 use super::registers::Registers;
 
 /// Switches the current stack to newly allocated 0x40000-byte space and jumps
 /// to `destination`.
 pub(crate) fn jump_with_new_stack(destination: fn(&Registers) -> !, registers: &Registers) -> ! {
-    // Allocate separate stack space. This is never freed.
+    // Allocate separate stack space. This is never freed, but reused when the
+    // processor is virtualized again after devirtualization, where the stack
+    // is no longer in use.
     let layout = Layout::array::<Page>(0x10).unwrap();
-    let stack = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    let processor_id = apic_id::current_processor_id().unwrap();
+    let mut stack = STACKS[processor_id].load(Ordering::Relaxed);
     if stack.is_null() {
-        handle_alloc_error(layout);
+        stack = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        if stack.is_null() {
+            handle_alloc_error(layout);
+        }
+        STACKS[processor_id].store(stack, Ordering::Relaxed);
     }
     let stack_base = stack as u64 + layout.size() as u64 - 0x8;
     log::trace!("Stack range: {:#x?}", (stack as u64..stack_base));
@@ -20,6 +35,10 @@ pub(crate) fn jump_with_new_stack(destination: fn(&Registers) -> !, registers: &
     unsafe { switch_stack(registers, destination as *const () as _, stack_base) };
 }
 
+/// The host stack of each processor.
+static STACKS: PerCpu<AtomicPtr<u8>> =
+    PerCpu::new([const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_PROCESSORS]);
+
 unsafe extern "C" {
     /// Jumps to the landing code with the new stack pointer.
     unsafe fn switch_stack(registers: &Registers, destination: usize, stack_base: u64) -> !;
@@ -0,0 +1,148 @@
+//! This module implements the verification of the system state across
+//! virtualization of a processor.
+//!
+//! The Windows driver virtualizes processors while the OS is fully running.
+//! The guest must resume with exactly the state the processor had right
+//! before virtualization, otherwise the OS may hang or crash much later, for
+//! example, when it reloads a segment or switches threads. Each processor is
+//! virtualized with interrupts disabled from capturing the registers until the
+//! guest resumes, and [`SystemState`] captured before virtualization is
+//! compared with that captured by the guest after it. Mismatches are logged as
+//! errors.
+
+use x86::{
+    controlregs::Cr4,
+    msr::{IA32_EFER, IA32_FS_BASE, IA32_GS_BASE, IA32_KERNEL_GSBASE},
+};
+
+use super::x86_instructions::{cr0, cr3, cr4, ldtr, rdmsr, sgdt, sidt, tr};
+
+/// The system registers the guest must resume with unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct SystemState {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    gdtr_base: u64,
+    gdtr_limit: u64,
+    idtr_base: u64,
+    idtr_limit: u64,
+    cs: u64,
+    ss: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+    tr: u64,
+    ldtr: u64,
+    fs_base: u64,
+    gs_base: u64,
+    kernel_gs_base: u64,
+}
+
+impl SystemState {
+    /// Captures the system registers of the current processor.
+    pub(crate) fn capture() -> Self {
+        let gdtr = sgdt();
+        let idtr = sidt();
+        Self {
+            cr0: cr0().bits() as u64,
+            cr3: cr3(),
+            cr4: cr4().bits() as u64,
+            efer: rdmsr(IA32_EFER),
+            gdtr_base: gdtr.base as u64,
+            gdtr_limit: u64::from(gdtr.limit),
+            idtr_base: idtr.base as u64,
+            idtr_limit: u64::from(idtr.limit),
+            cs: u64::from(x86::segmentation::cs().bits()),
+            ss: u64::from(x86::segmentation::ss().bits()),
+            ds: u64::from(x86::segmentation::ds().bits()),
+            es: u64::from(x86::segmentation::es().bits()),
+            fs: u64::from(x86::segmentation::fs().bits()),
+            gs: u64::from(x86::segmentation::gs().bits()),
+            tr: u64::from(tr().bits()),
+            ldtr: u64::from(ldtr().bits()),
+            fs_base: rdmsr(IA32_FS_BASE),
+            gs_base: rdmsr(IA32_GS_BASE),
+            kernel_gs_base: rdmsr(IA32_KERNEL_GSBASE),
+        }
+    }
+
+    /// Returns the names and values of the registers that differ between
+    /// `self`, captured before virtualization, and `after`. The bits that
+    /// enable the virtualization extension, which the guest may see set after
+    /// virtualization, are ignored.
+    pub(crate) fn mismatches(
+        &self,
+        after: &Self,
+    ) -> impl Iterator<Item = (&'static str, u64, u64)> {
+        const EFER_SVME: u64 = 1 << 12;
+
+        let ignored = |value: u64, mask: u64| value & !mask;
+        let cr4_vmxe = Cr4::CR4_ENABLE_VMX.bits() as u64;
+        let pairs = [
+            ("CR0", self.cr0, after.cr0),
+            ("CR3", self.cr3, after.cr3),
+            (
+                "CR4",
+                ignored(self.cr4, cr4_vmxe),
+                ignored(after.cr4, cr4_vmxe),
+            ),
+            (
+                "EFER",
+                ignored(self.efer, EFER_SVME),
+                ignored(after.efer, EFER_SVME),
+            ),
+            ("GDTR base", self.gdtr_base, after.gdtr_base),
+            ("GDTR limit", self.gdtr_limit, after.gdtr_limit),
+            ("IDTR base", self.idtr_base, after.idtr_base),
+            ("IDTR limit", self.idtr_limit, after.idtr_limit),
+            ("CS", self.cs, after.cs),
+            ("SS", self.ss, after.ss),
+            ("DS", self.ds, after.ds),
+            ("ES", self.es, after.es),
+            ("FS", self.fs, after.fs),
+            ("GS", self.gs, after.gs),
+            ("TR", self.tr, after.tr),
+            ("LDTR", self.ldtr, after.ldtr),
+            ("FS base", self.fs_base, after.fs_base),
+            ("GS base", self.gs_base, after.gs_base),
+            ("KernelGSBase", self.kernel_gs_base, after.kernel_gs_base),
+        ];
+        pairs
+            .into_iter()
+            .filter(|(_, before, after)| before != after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn mismatches() {
+        let before = SystemState {
+            cr4: 0x6f8,
+            efer: 0xd01,
+            gdtr_base: 0xffff_8000_0000_1000,
+            ..Default::default()
+        };
+        let mut after = before;
+        after.cr4 |= Cr4::CR4_ENABLE_VMX.bits() as u64;
+        after.efer |= 1 << 12;
+        assert_eq!(before.mismatches(&after).count(), 0);
+
+        after.gdtr_base = 0x1000;
+        after.tr = 0x40;
+        assert_eq!(
+            before.mismatches(&after).collect::<Vec<_>>(),
+            [
+                ("GDTR base", 0xffff_8000_0000_1000, 0x1000),
+                ("TR", 0, 0x40)
+            ]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! This module implements the detection of writes to the GDTs and IDTs of the
+//! guest, a common technique of rootkits to hook interrupts and system calls.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::RwLock;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use super::{
+    SHARED_HOST_DATA, apic_id,
+    config::DescriptorTablePolicy,
+    ept_views::{DEFAULT_EPT_VIEW, EptPermissions},
+    host::Guest,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    platform_ops,
+    x86_instructions::{sgdt, sidt},
+};
+
+/// A write-protected page of a descriptor table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProtectedPage {
+    /// The GPA of the page.
+    gpa: u64,
+
+    /// `"GDT"` or `"IDT"`.
+    table: &'static str,
+
+    /// The ID of the processor whose table is in the page.
+    processor_id: usize,
+}
+
+/// Captures the GDTs and IDTs of all processors if enabled. Called before
+/// virtualizing the processors.
+///
+/// Processors that come online later, and tables the guest switches to with
+/// LGDT and LIDT, are not protected. Other data in the same pages are protected
+/// too, which may cause frequent VM-exits if the OS writes to them.
+pub(crate) fn init() {
+    if policy() == DescriptorTablePolicy::Off {
+        return;
+    }
+    platform_ops::get().run_on_all_processors(&|| {
+        let Some(id) = apic_id::current_processor_id() else {
+            return;
+        };
+        let gdtr = sgdt();
+        let idtr = sidt();
+        record(id, "GDT", gdtr.base as u64, gdtr.limit);
+        record(id, "IDT", idtr.base as u64, idtr.limit);
+    });
+    log::info!(
+        "Protecting {} pages of the descriptor tables",
+        PAGES.read().len()
+    );
+}
+
+/// Write-protects the captured pages in the EPTs the current processor uses.
+pub(crate) fn protect_all<T: Guest>(guest: &mut T) {
+    for page in PAGES.read().iter() {
+        protect(guest, page.gpa, false);
+    }
+}
+
+/// Makes the captured pages writable again in the EPTs the current processor
+/// uses, and cancels the pending write-protection. See
+/// [`super::shutdown`].
+pub(crate) fn unprotect_all<T: Guest>(guest: &mut T) {
+    if let Some(id) = apic_id::current_processor_id() {
+        let _ = handle_monitor_trap(guest, id);
+    }
+    for page in PAGES.read().iter() {
+        protect(guest, page.gpa, true);
+    }
+}
+
+/// Returns the number of the write-protected pages.
+pub(crate) fn page_count() -> usize {
+    PAGES.read().len()
+}
+
+/// Handles the EPT violation due to a write to `gpa` on the processor `id`.
+/// Returns `false` if `gpa` is not in a protected page.
+///
+/// Writes by the processor itself, such as to set the busy flag of the TSS on
+/// LTR, are handled as well.
+pub(crate) fn handle_write<T: Guest>(guest: &mut T, id: usize, gpa: u64) -> bool {
+    const GP: u8 = 13;
+
+    let policy = policy();
+    if policy == DescriptorTablePolicy::Off {
+        return false;
+    }
+    let Some(page) = find(gpa) else {
+        return false;
+    };
+    let rip = guest.regs().rip;
+    if policy == DescriptorTablePolicy::Block {
+        log::error!(
+            "Blocked the write to {} of processor #{} at {gpa:#x} from {rip:#x}",
+            page.table,
+            page.processor_id
+        );
+        guest.inject_exception(GP, Some(0));
+        return true;
+    }
+
+    log::warn!(
+        "{} of processor #{} at {gpa:#x} is written from {rip:#x}",
+        page.table,
+        page.processor_id
+    );
+    // Let the write complete, and write-protect the page again after the
+    // instruction. See `handle_monitor_trap`.
+    if let Some(pending) = PENDING.get(id)
+        && guest.set_monitor_trap(true)
+    {
+        pending.store(page.gpa, Ordering::Relaxed);
+    } else {
+        log::warn!("{:#x} is no longer protected", page.gpa);
+    }
+    protect(guest, page.gpa, true);
+    true
+}
+
+/// Write-protects the page the processor `id` let the guest write to in
+/// [`handle_write`]. Returns `false` if there is no such page, that is, the
+/// monitor trap flag is not set by this module.
+pub(crate) fn handle_monitor_trap<T: Guest>(guest: &mut T, id: usize) -> bool {
+    let gpa = PENDING
+        .get(id)
+        .map_or(0, |pending| pending.swap(0, Ordering::Relaxed));
+    if gpa == 0 {
+        return false;
+    }
+    let _ = guest.set_monitor_trap(false);
+    protect(guest, gpa, false);
+    true
+}
+
+fn policy() -> DescriptorTablePolicy {
+    SHARED_HOST_DATA
+        .get()
+        .unwrap()
+        .config
+        .descriptor_table_protection
+}
+
+/// Records the pages of the table at `base` with `limit` of the processor
+/// `id`.
+fn record(id: usize, table: &'static str, base: u64, limit: u16) {
+    let page_size = BASE_PAGE_SIZE as u64;
+    let mut pages = PAGES.write();
+    for va in (base & !(page_size - 1)..=base + u64::from(limit)).step_by(BASE_PAGE_SIZE) {
+        let Ok(gpa) = platform_ops::get().pa(va as *const _) else {
+            log::warn!("{table} of processor #{id} at {va:#x} is not mapped");
+            continue;
+        };
+        let gpa = gpa & !(page_size - 1);
+        if pages.iter().all(|page| page.gpa != gpa) {
+            pages.push(ProtectedPage {
+                gpa,
+                table,
+                processor_id: id,
+            });
+        }
+    }
+}
+
+/// Returns the protected page that contains `gpa`.
+fn find(gpa: u64) -> Option<ProtectedPage> {
+    let page = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+    PAGES.read().iter().find(|p| p.gpa == page).copied()
+}
+
+fn protect<T: Guest>(guest: &mut T, gpa: u64, writable: bool) {
+    let range = gpa..gpa + BASE_PAGE_SIZE as u64;
+    let permissions = EptPermissions::new(true, writable, true);
+    if let Err(e) = guest.protect_ept_view(DEFAULT_EPT_VIEW, range, permissions) {
+        log::warn!("Failed to protect {gpa:#x}: {e}");
+    }
+}
+
+static PAGES: RwLock<Vec<ProtectedPage>> = RwLock::new(Vec::new());
+
+/// The page to write-protect after the current instruction, or 0.
+static PENDING: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_PROCESSORS]);
@@ -0,0 +1,81 @@
+//! This module implements a mock of the platform specific API for unit tests.
+//!
+//! Code that only builds and inspects data structures, such as the paging
+//! structures and EPTs, can be tested on a developer machine without VMX or SVM
+//! by registering [`MockPlatformOps`] with [`init`].
+
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    boxed::Box,
+};
+use core::{alloc::Layout, ffi::c_void};
+
+use super::platform_ops::{self, PaError, PlatformOps, RunError};
+
+/// A mock of [`PlatformOps`] for a single processor system where every address
+/// is identity mapped.
+#[derive(Debug, Default)]
+pub struct MockPlatformOps;
+
+impl PlatformOps for MockPlatformOps {
+    fn run_on_all_processors(&self, callback: &(dyn Fn() + Sync)) {
+        callback();
+    }
+
+    fn run_on_processor(&self, index: usize, callback: &(dyn Fn() + Sync)) -> Result<(), RunError> {
+        if index != 0 {
+            return Err(RunError::InvalidIndex { index });
+        }
+        callback();
+        Ok(())
+    }
+
+    fn pa(&self, va: *const c_void) -> Result<u64, PaError> {
+        Ok(va as u64)
+    }
+
+    fn alloc_contiguous(&self, size: usize, _max_pa: u64) -> *mut c_void {
+        unsafe { alloc_zeroed(contiguous_layout(size)) }.cast()
+    }
+
+    fn free_contiguous(&self, ptr: *mut c_void, size: usize) {
+        unsafe { dealloc(ptr.cast(), contiguous_layout(size)) };
+    }
+}
+
+/// Registers [`MockPlatformOps`] as the platform specific API. Does nothing if
+/// it is already registered.
+pub fn init() {
+    platform_ops::init(Box::new(MockPlatformOps));
+}
+
+fn contiguous_layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 0x1000).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn callback_context() {
+        let ops = MockPlatformOps;
+        let calls = AtomicUsize::new(0);
+        ops.run_on_all_processors(&|| {
+            let _ = calls.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(
+            ops.run_on_processor(0, &|| {
+                let _ = calls.fetch_add(10, Ordering::Relaxed);
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            ops.run_on_processor(1, &|| unreachable!()),
+            Err(RunError::InvalidIndex { index: 1 })
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 11);
+    }
+}
@@ -0,0 +1,337 @@
+//! This module implements the virtualization of the CPUID leaves enumerating
+//! the processor topology: the extended topology leaves 0xB and 0x1F, and the
+//! deterministic cache parameters leaf 4 on Intel.
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use spin::Once;
+use x86::cpuid::{CpuIdResult, cpuid};
+
+use super::{
+    SHARED_HOST_DATA,
+    apic_id::{self, ApicId},
+};
+
+/// The extended topology enumeration leaf.
+const CPUID_EXTENDED_TOPOLOGY: u32 = 0xb;
+
+/// The V2 extended topology enumeration leaf.
+const CPUID_EXTENDED_TOPOLOGY_V2: u32 = 0x1f;
+
+/// The deterministic cache parameters leaf.
+const CPUID_CACHE_PARAMETERS: u32 = 4;
+
+/// The level type of SMT.
+const LEVEL_SMT: u8 = 1;
+
+/// The level type of Core.
+const LEVEL_CORE: u8 = 2;
+
+/// Builds the table on the current processor if enabled. Called before
+/// virtualizing the processors.
+pub(crate) fn init() {
+    if !SHARED_HOST_DATA.get().unwrap().config.virtualize_topology {
+        return;
+    }
+    let intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    let max_leaf = cpuid!(0).eax;
+    let (leaf, extended) = if max_leaf >= CPUID_EXTENDED_TOPOLOGY_V2
+        && cpuid!(CPUID_EXTENDED_TOPOLOGY_V2, 0).ebx != 0
+    {
+        (CPUID_EXTENDED_TOPOLOGY_V2, true)
+    } else if max_leaf >= CPUID_EXTENDED_TOPOLOGY {
+        (CPUID_EXTENDED_TOPOLOGY, false)
+    } else {
+        log::warn!("Extended topology enumeration is not supported and not virtualized");
+        return;
+    };
+
+    let levels = (0..u8::MAX)
+        .map(|sub_leaf| Level::from(cpuid!(leaf, u32::from(sub_leaf))))
+        .take_while(|level| level.kind != 0)
+        .collect::<Vec<_>>();
+    match TopologyTable::new(levels, extended, intel, apic_id::known_apic_ids()) {
+        Some(table) => {
+            log::info!("Virtualizing the topology: {table:x?}");
+            let _ = TABLE.call_once(|| table);
+        }
+        None => log::warn!("The topology reported by leaf {leaf:#x} is inconsistent"),
+    }
+}
+
+/// Returns `true` if the topology leaves are virtualized.
+pub(crate) fn enabled() -> bool {
+    TABLE.get().is_some()
+}
+
+/// Returns the result of CPUID `leaf` and `sub_leaf` the processor `id` sees.
+pub(crate) fn filter_cpuid(
+    id: usize,
+    leaf: u32,
+    sub_leaf: u32,
+    cpuid_result: CpuIdResult,
+) -> CpuIdResult {
+    let Some(table) = TABLE.get() else {
+        return cpuid_result;
+    };
+    if !matches!(
+        leaf,
+        CPUID_EXTENDED_TOPOLOGY | CPUID_EXTENDED_TOPOLOGY_V2 | CPUID_CACHE_PARAMETERS
+    ) {
+        return cpuid_result;
+    }
+    let apic_id = apic_id::apic_id_from(id).unwrap_or(cpuid_result.edx);
+    table.filter(leaf, sub_leaf, apic_id, cpuid_result)
+}
+
+/// A level of the extended topology enumeration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Level {
+    /// The level type, such as [`LEVEL_SMT`].
+    kind: u8,
+
+    /// The number of bits to shift the x2APIC ID right to get the ID of the
+    /// next level.
+    shift: u8,
+}
+
+impl From<CpuIdResult> for Level {
+    fn from(cpuid_result: CpuIdResult) -> Self {
+        Self {
+            kind: cpuid_result.ecx.get_bits(8..=15) as u8,
+            shift: cpuid_result.eax.get_bits(0..=4) as u8,
+        }
+    }
+}
+
+/// The topology CPUID is answered from.
+///
+/// The levels are taken from the bootstrap processor and are the same for all
+/// processors, so processors of hybrid architectures that report different
+/// levels see those of the bootstrap processor.
+#[derive(Debug)]
+pub(crate) struct TopologyTable {
+    /// The levels of leaf 0x1F, or 0xB if not supported, from the lowest. The
+    /// last one spans the package.
+    levels: Vec<Level>,
+
+    /// The levels of leaf 0xB, which only defines SMT and Core. The levels
+    /// between them are folded into Core, which spans the package.
+    legacy_levels: Vec<Level>,
+
+    /// Whether the processor supports leaf 0x1F. The leaf is not virtualized
+    /// otherwise.
+    extended: bool,
+
+    /// Whether leaf 4 is virtualized, that is, the processor is Intel.
+    intel: bool,
+
+    /// The x2APIC IDs of the processors online or listed in the MADT, sorted.
+    /// Other processors are counted only for themselves.
+    apic_ids: Vec<ApicId>,
+}
+
+impl TopologyTable {
+    /// Returns the table, or `None` if `levels` are empty or their shifts
+    /// decrease.
+    fn new(
+        levels: Vec<Level>,
+        extended: bool,
+        intel: bool,
+        mut apic_ids: Vec<ApicId>,
+    ) -> Option<Self> {
+        if levels.is_empty()
+            || levels.windows(2).any(|pair| pair[0].shift > pair[1].shift)
+            || levels.iter().any(|level| level.shift > 31)
+        {
+            return None;
+        }
+        let core = Level {
+            kind: LEVEL_CORE,
+            shift: levels[levels.len() - 1].shift,
+        };
+        let legacy_levels = if levels[0].kind == LEVEL_SMT && levels.len() > 1 {
+            alloc::vec![levels[0], core]
+        } else {
+            alloc::vec![core]
+        };
+        apic_ids.sort_unstable();
+        apic_ids.dedup();
+        Some(Self {
+            levels,
+            legacy_levels,
+            extended,
+            intel,
+            apic_ids,
+        })
+    }
+
+    /// Returns `cpuid_result` of `leaf` and `sub_leaf` answered for the
+    /// processor with `apic_id`.
+    fn filter(
+        &self,
+        leaf: u32,
+        sub_leaf: u32,
+        apic_id: ApicId,
+        cpuid_result: CpuIdResult,
+    ) -> CpuIdResult {
+        match leaf {
+            CPUID_EXTENDED_TOPOLOGY => self.topology_leaf(&self.legacy_levels, sub_leaf, apic_id),
+            CPUID_EXTENDED_TOPOLOGY_V2 if self.extended => {
+                self.topology_leaf(&self.levels, sub_leaf, apic_id)
+            }
+            CPUID_CACHE_PARAMETERS if self.intel => self.cache_leaf(cpuid_result),
+            _ => cpuid_result,
+        }
+    }
+
+    /// Returns leaf 0xB or 0x1F for `sub_leaf` with `levels`. Sub-leaves
+    /// beyond them report the invalid level type.
+    ///
+    /// See: Table 3-8. Information Returned by CPUID Instruction
+    /// See: E.3.9 Function Bh—Extended Topology Enumeration
+    fn topology_leaf(&self, levels: &[Level], sub_leaf: u32, apic_id: ApicId) -> CpuIdResult {
+        let mut cpuid_result = CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: sub_leaf & 0xff,
+            edx: apic_id,
+        };
+        if let Some(level) = usize::try_from(sub_leaf)
+            .ok()
+            .and_then(|index| levels.get(index))
+        {
+            let count = self.count(apic_id, level.shift);
+            cpuid_result.eax = u32::from(level.shift);
+            cpuid_result.ebx = u32::try_from(count).unwrap_or(u32::MAX).min(0xffff);
+            let _ = cpuid_result.ecx.set_bits(8..=15, u32::from(level.kind));
+        }
+        cpuid_result
+    }
+
+    /// Returns leaf 4 with the number of cores in the package and processors
+    /// sharing the cache limited to the span of the package level. The
+    /// numbers are reported minus 1 in EAX[31:26] and EAX[25:14].
+    ///
+    /// See: Table 3-8. Information Returned by CPUID Instruction
+    fn cache_leaf(&self, mut cpuid_result: CpuIdResult) -> CpuIdResult {
+        // No more caches.
+        if cpuid_result.eax.get_bits(0..=4) == 0 {
+            return cpuid_result;
+        }
+        let package_shift = u32::from(self.levels[self.levels.len() - 1].shift);
+        let smt_shift = match self.levels[0] {
+            level if level.kind == LEVEL_SMT => u32::from(level.shift),
+            _ => 0,
+        };
+        let cores = (1u32 << (package_shift - smt_shift)).min(64);
+        let _ = cpuid_result.eax.set_bits(26..=31, cores - 1);
+        let sharing = cpuid_result.eax.get_bits(14..=25) + 1;
+        let sharing = sharing.min((1u32 << package_shift).min(4096));
+        let _ = cpuid_result.eax.set_bits(14..=25, sharing - 1);
+        cpuid_result
+    }
+
+    /// Returns the number of processors whose x2APIC IDs above `shift` are
+    /// the same as `apic_id`, including the processor with `apic_id`.
+    fn count(&self, apic_id: ApicId, shift: u8) -> usize {
+        let domain = |id: ApicId| id.checked_shr(u32::from(shift)).unwrap_or(0);
+        self.apic_ids
+            .iter()
+            .filter(|&&other| other != apic_id && domain(other) == domain(apic_id))
+            .count()
+            + 1
+    }
+}
+
+static TABLE: Once<TopologyTable> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn table() -> TopologyTable {
+        const LEVEL_DIE: u8 = 5;
+
+        // 2 threads in a core, up to 8 cores in a module, up to 4 modules in
+        // a die, which spans the package. Core 8 is hot-added.
+        let levels = vec![
+            Level {
+                kind: LEVEL_SMT,
+                shift: 1,
+            },
+            Level {
+                kind: LEVEL_CORE,
+                shift: 4,
+            },
+            Level {
+                kind: LEVEL_DIE,
+                shift: 6,
+            },
+        ];
+        let apic_ids = vec![0x11, 0, 1, 2, 3, 4, 5, 6, 7, 0x10, 3];
+        TopologyTable::new(levels, true, true, apic_ids).unwrap()
+    }
+
+    fn result(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdResult {
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn topology_leaves() {
+        let table = table();
+        let original = result(0xdead, 0xdead, 0xdead, 0xdead);
+        assert_eq!(table.filter(0x1f, 0, 3, original), result(1, 2, 0x100, 3));
+        assert_eq!(table.filter(0x1f, 1, 3, original), result(4, 8, 0x201, 3));
+        assert_eq!(table.filter(0x1f, 2, 3, original), result(6, 10, 0x502, 3));
+        assert_eq!(table.filter(0x1f, 3, 3, original), result(0, 0, 3, 3));
+        // Leaf 0xB folds the die into the core level.
+        assert_eq!(
+            table.filter(0xb, 0, 0x10, original),
+            result(1, 2, 0x100, 0x10)
+        );
+        assert_eq!(
+            table.filter(0xb, 1, 0x10, original),
+            result(6, 10, 0x201, 0x10)
+        );
+        assert_eq!(table.filter(0xb, 2, 0x10, original), result(0, 0, 2, 0x10));
+        // A processor not in the table is counted for itself.
+        assert_eq!(
+            table.filter(0xb, 0, 0x40, original),
+            result(1, 1, 0x100, 0x40)
+        );
+        assert_eq!(table.filter(1, 0, 3, original), original);
+    }
+
+    #[test]
+    fn cache_leaf() {
+        let table = table();
+        let cache = result(0x0000_0121 | (0xfff << 14), 0x01c0_003f, 0x3f, 0);
+        let filtered = table.filter(4, 0, 3, cache);
+        assert_eq!(filtered.eax, 0x0000_0121 | (63 << 14) | (31 << 26));
+        assert_eq!(filtered.ebx, cache.ebx);
+        // No more caches.
+        assert_eq!(
+            table.filter(4, 4, 3, result(0, 0, 0, 0)),
+            result(0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn inconsistent_levels() {
+        let levels = vec![
+            Level {
+                kind: LEVEL_SMT,
+                shift: 4,
+            },
+            Level {
+                kind: LEVEL_CORE,
+                shift: 1,
+            },
+        ];
+        assert!(TopologyTable::new(levels, false, true, vec![0]).is_none());
+        assert!(TopologyTable::new(vec![], false, true, vec![0]).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+//! This module implements TSC scaling, which presents the guest with a TSC
+//! running at a different frequency than that of the host.
+
+use core::arch::x86_64::_rdtsc;
+
+use spin::Once;
+
+/// The ratio of the guest TSC frequency to the host TSC frequency.
+///
+/// RDTSC, RDTSCP and RDMSR of IA32_TSC are scaled, but writes to IA32_TSC and
+/// the TSC frequency reported with CPUID leaves 15h and 16h are not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscRatio {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl TscRatio {
+    /// Returns the ratio for the TSC multiplier field, an unsigned fixed-point
+    /// number with 48 fractional bits, or `None` if it is not representable.
+    ///
+    /// See: 25.6.5 Time-Stamp Counter Offset and Multiplier
+    pub(crate) fn vmx_multiplier(self) -> Option<u64> {
+        self.fixed_point(16, VMX_FRACTION_BITS)
+    }
+
+    /// Returns the ratio for the TSC ratio MSR, an unsigned fixed-point number
+    /// with an 8-bit integer and 32-bit fractional parts, or `None` if it is
+    /// not representable.
+    ///
+    /// See: 15.30.5 TSC Ratio MSR (C000_0104h)
+    pub(crate) fn svm_ratio(self) -> Option<u64> {
+        self.fixed_point(8, SVM_FRACTION_BITS)
+    }
+
+    fn fixed_point(self, integer_bits: u32, fraction_bits: u32) -> Option<u64> {
+        if self.denominator == 0 {
+            return None;
+        }
+        let value = (u128::from(self.numerator) << fraction_bits) / u128::from(self.denominator);
+        (value != 0 && value >> (integer_bits + fraction_bits) == 0).then_some(value as u64)
+    }
+}
+
+/// The number of fractional bits of [`TscRatio::vmx_multiplier`].
+pub(crate) const VMX_FRACTION_BITS: u32 = 48;
+
+/// The number of fractional bits of [`TscRatio::svm_ratio`].
+pub(crate) const SVM_FRACTION_BITS: u32 = 32;
+
+/// Returns the TSC offset to apply with the fixed-point `ratio`, so that the
+/// guest TSC starts from the host TSC at the time scaling started on the first
+/// processor.
+pub(crate) fn offset(ratio: u64, fraction_bits: u32) -> u64 {
+    let start = *START_TSC.call_once(|| unsafe { _rdtsc() });
+    offset_from(start, ratio, fraction_bits)
+}
+
+fn offset_from(start: u64, ratio: u64, fraction_bits: u32) -> u64 {
+    start.wrapping_sub(scale(start, ratio, fraction_bits))
+}
+
+/// Returns `tsc` multiplied by the fixed-point `ratio`, as the processor does.
+fn scale(tsc: u64, ratio: u64, fraction_bits: u32) -> u64 {
+    ((u128::from(tsc) * u128::from(ratio)) >> fraction_bits) as u64
+}
+
+/// Returns the host TSC when scaling started, or `None` if it has not.
+pub(crate) fn start_tsc() -> Option<u64> {
+    START_TSC.get().copied()
+}
+
+/// The host TSC when scaling started.
+static START_TSC: Once<u64> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_point() {
+        let half = TscRatio {
+            numerator: 1,
+            denominator: 2,
+        };
+        assert_eq!(half.vmx_multiplier(), Some(1 << 47));
+        assert_eq!(half.svm_ratio(), Some(1 << 31));
+
+        let max = TscRatio {
+            numerator: 255,
+            denominator: 1,
+        };
+        assert_eq!(max.svm_ratio(), Some(255 << 32));
+        let over = TscRatio {
+            numerator: 256,
+            denominator: 1,
+        };
+        assert_eq!(over.svm_ratio(), None);
+        assert_eq!(over.vmx_multiplier(), Some(256 << 48));
+
+        for (numerator, denominator) in [(0, 1), (1, 0), (0x1_0000, 1)] {
+            let ratio = TscRatio {
+                numerator,
+                denominator,
+            };
+            assert_eq!(ratio.vmx_multiplier(), None);
+        }
+    }
+
+    #[test]
+    fn continuous() {
+        let ratio = TscRatio {
+            numerator: 1,
+            denominator: 2,
+        }
+        .vmx_multiplier()
+        .unwrap();
+        let start = 0x1234_5678_9abc;
+        let offset = offset_from(start, ratio, VMX_FRACTION_BITS);
+
+        // The guest TSC equals the host TSC when scaling started, and advances
+        // at half its rate afterwards.
+        let guest = |host| scale(host, ratio, VMX_FRACTION_BITS).wrapping_add(offset);
+        assert_eq!(guest(start), start);
+        assert_eq!(guest(start + 3_000_000) - guest(start), 1_500_000);
+    }
+}
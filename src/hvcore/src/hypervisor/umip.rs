@@ -0,0 +1,261 @@
+//! This module implements the policy for the instructions storing the
+//! descriptor-table registers, `SGDT`, `SIDT`, `SLDT` and `STR`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bit_field::BitField;
+
+use super::{
+    SHARED_HOST_DATA,
+    config::UmipPolicy,
+    gva,
+    host::{DescriptorTableInfo, DescriptorTableInstruction, DescriptorTableOperand, Guest},
+    percpu::{MAX_PROCESSORS, PerCpu},
+    phys_read,
+    registers::FullGuestState,
+};
+
+/// Returns `true` if the instructions should cause VM-exit.
+pub(crate) fn is_enabled() -> bool {
+    policy() != UmipPolicy::Off
+}
+
+/// Handles the VM-exit due to the instruction accessing a descriptor-table
+/// register on the processor `id`.
+///
+/// In kernel mode, the instruction is emulated with the actual values of the
+/// guest. `LGDT`, `LIDT`, `LLDT` and `LTR`, which cause VM-exit on Intel as
+/// well, are executed by the guest with descriptor-table exiting disabled for a
+/// single instruction with the monitor trap flag.
+pub(crate) fn handle<T: Guest>(guest: &mut T, id: usize, info: &DescriptorTableInfo) {
+    const GP: u8 = 13;
+
+    let Some(value) = StoredValue::new(info.instruction, &guest.full_state()) else {
+        step(guest, id);
+        return;
+    };
+    let cpl = guest.cpl();
+    let value = if cpl == 0 {
+        value
+    } else if policy() == UmipPolicy::Spoof {
+        log::trace!("Spoofing {:?} at {:#x}", info.instruction, guest.regs().rip);
+        StoredValue::spoofed(info.instruction)
+    } else {
+        guest.inject_exception(GP, Some(0));
+        return;
+    };
+
+    let stored = match (info.operand, value) {
+        (Some(DescriptorTableOperand::Register { gpr, size }), StoredValue::Selector(selector)) => {
+            let current = guest.regs().gpr(gpr);
+            guest
+                .regs()
+                .set_gpr(gpr, register_value(current, selector, size));
+            true
+        }
+        (Some(DescriptorTableOperand::Memory(address)), _) => {
+            let (bytes, len) = value.to_bytes(info.long_mode);
+            write(guest, cpl, address, &bytes[..len])
+        }
+        _ => false,
+    };
+    if !stored {
+        log::warn!(
+            "Failed to emulate {:?} with {:x?} at {:#x}",
+            info.instruction,
+            info.operand,
+            guest.regs().rip
+        );
+        guest.inject_exception(GP, Some(0));
+        return;
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+/// Enables descriptor-table exiting again after the instruction the processor
+/// `id` let the guest execute in [`handle`]. Returns `false` if the monitor
+/// trap flag is not set by this module.
+pub(crate) fn handle_monitor_trap<T: Guest>(guest: &mut T, id: usize) -> bool {
+    let pending = PENDING
+        .get(id)
+        .is_some_and(|pending| pending.swap(false, Ordering::Relaxed));
+    if !pending {
+        return false;
+    }
+    let _ = guest.set_monitor_trap(false);
+    guest.set_descriptor_table_exiting(true);
+    true
+}
+
+fn policy() -> UmipPolicy {
+    SHARED_HOST_DATA.get().unwrap().config.umip
+}
+
+/// Lets the guest execute the current instruction without VM-exit.
+fn step<T: Guest>(guest: &mut T, id: usize) {
+    guest.set_descriptor_table_exiting(false);
+    if let Some(pending) = PENDING.get(id)
+        && guest.set_monitor_trap(true)
+    {
+        pending.store(true, Ordering::Relaxed);
+    } else {
+        log::warn!("Descriptor-table exiting is disabled on processor {id}");
+    }
+}
+
+/// Writes `bytes` to `address` on behalf of the guest at `cpl`. Returns `false`
+/// if the guest is not in IA-32e mode with 4-level paging, the host does not
+/// run on [`super::SharedHostData::pt`], or `address` is not writable, on
+/// which the caller injects #GP(0) instead of #PF. Segment limits are not
+/// checked.
+fn write<T: Guest>(guest: &mut T, cpl: u8, address: u64, bytes: &[u8]) -> bool {
+    const EFER_LMA: usize = 10;
+    const CR4_LA57: usize = 12;
+
+    let state = guest.full_state();
+    if !state.efer.get_bit(EFER_LMA) || state.cr4.get_bit(CR4_LA57) {
+        return false;
+    }
+    let smap = gva::smap_applies(state.cr4, guest.regs().rflags);
+    phys_read::write_guest(state.cr3, smap, cpl == 3, address, bytes)
+}
+
+/// The value an instruction storing a descriptor-table register stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StoredValue {
+    /// The base and limit of GDTR or IDTR.
+    Table { base: u64, limit: u16 },
+    /// The selector in LDTR or TR.
+    Selector(u16),
+}
+
+impl StoredValue {
+    /// Returns the value `instruction` stores with `state`, or `None` if it
+    /// loads the register.
+    fn new(instruction: DescriptorTableInstruction, state: &FullGuestState) -> Option<Self> {
+        match instruction {
+            DescriptorTableInstruction::Sgdt => Some(Self::Table {
+                base: state.gdtr_base,
+                limit: state.gdtr_limit,
+            }),
+            DescriptorTableInstruction::Sidt => Some(Self::Table {
+                base: state.idtr_base,
+                limit: state.idtr_limit,
+            }),
+            DescriptorTableInstruction::Sldt => Some(Self::Selector(state.ldtr)),
+            DescriptorTableInstruction::Str => Some(Self::Selector(state.tr)),
+            DescriptorTableInstruction::Lgdt
+            | DescriptorTableInstruction::Lidt
+            | DescriptorTableInstruction::Lldt
+            | DescriptorTableInstruction::Ltr => None,
+        }
+    }
+
+    /// Returns the value stored instead of the actual one in user mode.
+    ///
+    /// See: arch/x86/kernel/umip.c in Linux
+    fn spoofed(instruction: DescriptorTableInstruction) -> Self {
+        const DUMMY_GDT_BASE: u64 = 0xffff_ffff_fffe_0000;
+        const DUMMY_IDT_BASE: u64 = 0xffff_ffff_ffff_0000;
+        const DUMMY_TR: u16 = 0x40;
+
+        match instruction {
+            DescriptorTableInstruction::Sgdt | DescriptorTableInstruction::Lgdt => Self::Table {
+                base: DUMMY_GDT_BASE,
+                limit: 0,
+            },
+            DescriptorTableInstruction::Sidt | DescriptorTableInstruction::Lidt => Self::Table {
+                base: DUMMY_IDT_BASE,
+                limit: 0,
+            },
+            DescriptorTableInstruction::Sldt | DescriptorTableInstruction::Lldt => {
+                Self::Selector(0)
+            }
+            DescriptorTableInstruction::Str | DescriptorTableInstruction::Ltr => {
+                Self::Selector(DUMMY_TR)
+            }
+        }
+    }
+
+    /// Returns the bytes stored to a memory operand, and the number of them.
+    /// The base is 8 bytes in 64-bit mode, and 4 bytes otherwise regardless of
+    /// the operand size.
+    ///
+    /// See: SGDT—Store Global Descriptor Table Register
+    /// See: SLDT—Store Local Descriptor Table Register
+    fn to_bytes(self, long_mode: bool) -> ([u8; 10], usize) {
+        let mut bytes = [0u8; 10];
+        match self {
+            Self::Table { base, limit } => {
+                let len = if long_mode { 10 } else { 6 };
+                bytes[..2].copy_from_slice(&limit.to_le_bytes());
+                bytes[2..len].copy_from_slice(&base.to_le_bytes()[..len - 2]);
+                (bytes, len)
+            }
+            Self::Selector(selector) => {
+                bytes[..2].copy_from_slice(&selector.to_le_bytes());
+                (bytes, 2)
+            }
+        }
+    }
+}
+
+/// Returns the value of the register `current` after storing `selector` to it
+/// with the operand `size`. 16-bit operands keep the upper bits, and larger
+/// ones are zero-extended.
+///
+/// See: STR—Store Task Register
+fn register_value(current: u64, selector: u16, size: u8) -> u64 {
+    if size == 2 {
+        (current & !0xffff) | u64::from(selector)
+    } else {
+        u64::from(selector)
+    }
+}
+
+/// Whether the processor let the guest execute an instruction with
+/// descriptor-table exiting disabled.
+static PENDING: PerCpu<AtomicBool> =
+    PerCpu::new([const { AtomicBool::new(false) }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_bytes() {
+        let sidt = StoredValue::spoofed(DescriptorTableInstruction::Sidt);
+        assert_eq!(
+            sidt.to_bytes(true),
+            (
+                [0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+                10
+            )
+        );
+        // Only the lower 32 bits of the base outside 64-bit mode.
+        let (bytes, len) = sidt.to_bytes(false);
+        assert_eq!(&bytes[..len], &[0x00, 0x00, 0x00, 0x00, 0xff, 0xff]);
+
+        let sgdt = StoredValue::Table {
+            base: 0x1122_3344_5566_7788,
+            limit: 0x7f,
+        };
+        let (bytes, len) = sgdt.to_bytes(true);
+        assert_eq!(
+            &bytes[..len],
+            &[0x7f, 0x00, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+
+        let str = StoredValue::spoofed(DescriptorTableInstruction::Str);
+        let (bytes, len) = str.to_bytes(true);
+        assert_eq!(&bytes[..len], &[0x40, 0x00]);
+    }
+
+    #[test]
+    fn stored_registers() {
+        let current = 0xdead_beef_cafe_f00d;
+        assert_eq!(register_value(current, 0x40, 2), 0xdead_beef_cafe_0040);
+        assert_eq!(register_value(current, 0x40, 4), 0x40);
+        assert_eq!(register_value(current, 0x40, 8), 0x40);
+    }
+}
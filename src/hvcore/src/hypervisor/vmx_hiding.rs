@@ -0,0 +1,68 @@
+//! This module implements the virtualization of IA32_FEATURE_CONTROL and the
+//! VMX capability MSRs, consistent with CPUID, which reports VMX as
+//! unsupported to the guest. See `host::filter_cpuid`.
+
+use bit_field::BitField;
+
+/// The VMX capability MSRs, from IA32_VMX_BASIC to IA32_VMX_EXIT_CTLS2.
+///
+/// See: APPENDIX A VMX CAPABILITY REPORTING FACILITY
+const VMX_CAPABILITY_MSRS: core::ops::RangeInclusive<u32> = 0x480..=0x493;
+
+/// Returns the MSRs access to which is intercepted. Intel only. Nested VMX is
+/// not supported, and VMX is always hidden.
+pub(crate) fn intercepted_msrs() -> impl Iterator<Item = u32> {
+    core::iter::once(x86::msr::IA32_FEATURE_CONTROL).chain(VMX_CAPABILITY_MSRS)
+}
+
+/// Returns `true` if RDMSR of `msr` by the guest causes #GP(0), as the VMX
+/// capability MSRs do not exist on a processor without VMX.
+pub(crate) fn is_unreadable(msr: u32) -> bool {
+    VMX_CAPABILITY_MSRS.contains(&msr)
+}
+
+/// Returns `true` if WRMSR to `msr` by the guest causes #GP(0), as
+/// IA32_FEATURE_CONTROL is read-only once locked.
+pub(crate) fn is_unwritable(msr: u32) -> bool {
+    msr == x86::msr::IA32_FEATURE_CONTROL || VMX_CAPABILITY_MSRS.contains(&msr)
+}
+
+/// Returns the value of `msr` the guest reads given that of the processor, or
+/// `value` as is if `msr` is not virtualized.
+pub(crate) fn filter_rdmsr(msr: u32, value: u64) -> u64 {
+    if msr == x86::msr::IA32_FEATURE_CONTROL {
+        feature_control(value)
+    } else {
+        value
+    }
+}
+
+/// Returns IA32_FEATURE_CONTROL locked with VMX disabled both inside and
+/// outside SMX operation, as firmware leaves it when VMX is disabled in the
+/// setup.
+///
+/// See: 23.7 ENABLING AND ENTERING VMX OPERATION
+fn feature_control(mut value: u64) -> u64 {
+    const LOCK: usize = 0;
+    const ENABLE_VMX_INSIDE_SMX: usize = 1;
+    const ENABLE_VMX_OUTSIDE_SMX: usize = 2;
+
+    let _ = value.set_bit(LOCK, true);
+    let _ = value.set_bit(ENABLE_VMX_INSIDE_SMX, false);
+    let _ = value.set_bit(ENABLE_VMX_OUTSIDE_SMX, false);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_control_values() {
+        assert_eq!(feature_control(0b101), 0b1);
+        assert_eq!(feature_control(0b111), 0b1);
+        // The other bits, such as LMCE, are kept.
+        assert_eq!(feature_control((1 << 20) | 0b100), (1 << 20) | 0b1);
+        assert_eq!(filter_rdmsr(0x10, 0b100), 0b100);
+    }
+}
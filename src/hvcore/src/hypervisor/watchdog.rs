@@ -0,0 +1,219 @@
+//! This module implements the watchdog that detects processors stuck in the
+//! host, for example, on a deadlock in VM-exit handling.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86::msr::IA32_APIC_BASE;
+
+use super::{
+    SHARED_HOST_DATA, apic_id, exit_stats,
+    host::VmExitReason,
+    percpu::{MAX_PROCESSORS, PerCpu},
+    x86_instructions::{rdmsr, wrmsr},
+};
+
+/// The number of recent VM-exits reported for a stuck processor.
+pub const TRACE_LENGTH: usize = 8;
+
+/// A VM-exit recorded in a heartbeat.
+struct TraceEvent {
+    tsc: AtomicU64,
+    rip: AtomicU64,
+    /// The index of the kind in [`exit_stats::KINDS`], or `u8::MAX` for the
+    /// others.
+    kind: AtomicU8,
+}
+
+/// The heartbeat of a processor.
+struct Heartbeat {
+    /// The TSC when the processor started handling the current VM-exit, or 0
+    /// while the guest runs.
+    exit_start: AtomicU64,
+
+    /// Whether the current VM-exit is already reported.
+    reported: AtomicBool,
+
+    /// Whether NMI is sent by the watchdog and not received yet.
+    nmi_pending: AtomicBool,
+
+    /// The recent VM-exits, written round-robin at `next_event`.
+    events: [TraceEvent; TRACE_LENGTH],
+    next_event: AtomicUsize,
+}
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Self {
+            exit_start: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            nmi_pending: AtomicBool::new(false),
+            events: [const {
+                TraceEvent {
+                    tsc: AtomicU64::new(0),
+                    rip: AtomicU64::new(0),
+                    kind: AtomicU8::new(0),
+                }
+            }; TRACE_LENGTH],
+            next_event: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Returns the timeout in TSC cycles if the watchdog is enabled.
+pub(crate) fn timeout() -> Option<u64> {
+    SHARED_HOST_DATA.get().unwrap().config.watchdog
+}
+
+/// Records that the processor `id` started handling the VM-exit `reason` at
+/// the guest `rip`.
+pub(crate) fn exit_started(id: usize, reason: &VmExitReason, rip: u64) {
+    if timeout().is_none() {
+        return;
+    }
+    let Some(heartbeat) = HEARTBEATS.get(id) else {
+        return;
+    };
+    let tsc = unsafe { _rdtsc() };
+    let index = heartbeat.next_event.fetch_add(1, Ordering::Relaxed) % TRACE_LENGTH;
+    let event = &heartbeat.events[index];
+    event.tsc.store(tsc, Ordering::Relaxed);
+    event.rip.store(rip, Ordering::Relaxed);
+    event.kind.store(
+        reason.kind().map_or(u8::MAX, |kind| kind as u8),
+        Ordering::Relaxed,
+    );
+    heartbeat.exit_start.store(tsc, Ordering::Release);
+}
+
+/// Records that the processor `id` completed handling the VM-exit.
+pub(crate) fn exit_completed(id: usize) {
+    if timeout().is_none() {
+        return;
+    }
+    if let Some(heartbeat) = HEARTBEATS.get(id) {
+        heartbeat.exit_start.store(0, Ordering::Release);
+        heartbeat.reported.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Checks the heartbeats of the processors other than `id`, and reports those
+/// stuck in the host. Called on the VMX-preemption timer, which counts down
+/// only while the guest runs, so nothing is reported if all other processors
+/// are stuck too.
+pub(crate) fn check(id: usize) {
+    let Some(timeout) = timeout() else {
+        return;
+    };
+    let now = unsafe { _rdtsc() };
+    for (other, heartbeat) in HEARTBEATS.iter().enumerate() {
+        let exit_start = heartbeat.exit_start.load(Ordering::Acquire);
+        if other == id
+            || !is_stuck(exit_start, now, timeout)
+            || heartbeat.reported.swap(true, Ordering::Relaxed)
+        {
+            continue;
+        }
+        report(other, heartbeat, now, exit_start);
+        if SHARED_HOST_DATA.get().unwrap().config.watchdog_nmi {
+            heartbeat.nmi_pending.store(true, Ordering::Release);
+            if !send_nmi(other) {
+                heartbeat.nmi_pending.store(false, Ordering::Relaxed);
+                log::warn!("Failed to send NMI to processor #{other}");
+            }
+        }
+    }
+}
+
+/// Returns `true` if NMI on the current processor is sent by the watchdog,
+/// and thus, should be logged and dismissed.
+pub(crate) fn take_nmi() -> bool {
+    HEARTBEATS
+        .current()
+        .is_some_and(|heartbeat| heartbeat.nmi_pending.swap(false, Ordering::Acquire))
+}
+
+/// Returns `true` if a processor that started handling a VM-exit at
+/// `exit_start` has been in the host longer than `timeout` at `now`.
+fn is_stuck(exit_start: u64, now: u64, timeout: u64) -> bool {
+    exit_start != 0 && now.saturating_sub(exit_start) > timeout
+}
+
+/// Logs the stuck processor `id` and its recent VM-exits. Does not allocate, as
+/// the stuck processor may hold the allocator lock. A processor stuck while
+/// holding the lock of the logger cannot be reported.
+fn report(id: usize, heartbeat: &Heartbeat, now: u64, exit_start: u64) {
+    log::error!(
+        "Processor #{id} has been handling a VM-exit for {} cycles. Recent VM-exits:",
+        now - exit_start
+    );
+    let next = heartbeat.next_event.load(Ordering::Relaxed);
+    for i in 0..TRACE_LENGTH {
+        let event = &heartbeat.events[(next + i) % TRACE_LENGTH];
+        let tsc = event.tsc.load(Ordering::Relaxed);
+        if tsc == 0 {
+            continue;
+        }
+        let rip = event.rip.load(Ordering::Relaxed);
+        let ago = now.saturating_sub(tsc);
+        match exit_stats::KINDS.get(usize::from(event.kind.load(Ordering::Relaxed))) {
+            Some(kind) => log::error!("  {kind:?} at {rip:#x}, {ago} cycles ago"),
+            None => log::error!("  Other at {rip:#x}, {ago} cycles ago"),
+        }
+    }
+}
+
+/// Sends NMI to the processor `id`. Returns `false` if the APIC is not
+/// accessible, that is, in the xAPIC mode unless the host runs on its own
+/// identity mapping.
+///
+/// If the processor resumes the guest before the NMI arrives, the guest
+/// receives it, which the OS may treat as a hardware failure.
+fn send_nmi(id: usize) -> bool {
+    // See: 12.6.1 Interrupt Command Register (ICR)
+    // See: 12.12.9 ICR Operation in x2APIC Mode
+    const X2APIC_ENABLE: u64 = 1 << 10;
+    const X2APIC_MSR_ICR: u32 = 0x830;
+    const APIC_ICR_LOW: u64 = 0x300;
+    const APIC_ICR_HIGH: u64 = 0x310;
+    const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+    let Some(apic_id) = apic_id::apic_id_from(id) else {
+        return false;
+    };
+    let apic_base = rdmsr(IA32_APIC_BASE);
+    if apic_base & X2APIC_ENABLE != 0 {
+        wrmsr(
+            X2APIC_MSR_ICR,
+            (u64::from(apic_id) << 32) | u64::from(DELIVERY_MODE_NMI),
+        );
+    } else if SHARED_HOST_DATA.get().unwrap().pt.is_some() {
+        // Safety: the APIC page is identity mapped in the host.
+        let base = apic_base & !0xfff;
+        unsafe {
+            ((base + APIC_ICR_HIGH) as *mut u32).write_volatile(apic_id << 24);
+            ((base + APIC_ICR_LOW) as *mut u32).write_volatile(DELIVERY_MODE_NMI);
+        }
+    } else {
+        return false;
+    }
+    true
+}
+
+static HEARTBEATS: PerCpu<Heartbeat> = PerCpu::new([const { Heartbeat::new() }; MAX_PROCESSORS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stuck() {
+        assert!(!is_stuck(0, 5_000, 1_000));
+        assert!(!is_stuck(4_500, 5_000, 1_000));
+        assert!(is_stuck(3_000, 5_000, 1_000));
+        // The TSC of another processor may be slightly ahead.
+        assert!(!is_stuck(5_100, 5_000, 1_000));
+    }
+}
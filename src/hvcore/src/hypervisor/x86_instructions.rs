@@ -4,7 +4,7 @@ use core::arch::asm;
 
 use x86::{
     bits64::rflags::RFlags,
-    controlregs::{Cr0, Cr4, Xcr0},
+    controlregs::{Cr0, Cr4},
     dtables::DescriptorTablePointer,
     segmentation::SegmentSelector,
 };
@@ -58,6 +58,11 @@ pub(crate) fn cr4_write(val: Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Write a value to the GDTR.
+pub(crate) fn lgdt(gdtr: &DescriptorTablePointer<u64>) {
+    unsafe { x86::dtables::lgdt(gdtr) };
+}
+
 /// Write a value to the IDTR.
 pub(crate) fn lidt(idtr: &DescriptorTablePointer<u64>) {
     unsafe { x86::dtables::lidt(idtr) };
@@ -120,9 +125,38 @@ pub(crate) fn lar(selector: SegmentSelector) -> u32 {
 }
 
 /// Writes a value to XCR.
-pub(crate) fn xsetbv(xcr: u32, val: Xcr0) {
+// Does not use `x86::controlregs::xcr0_write` as `Xcr0` lacks some of the
+// defined bits, such as AMX.
+pub(crate) fn xsetbv(xcr: u32, val: u64) {
     assert!(xcr == 0);
-    unsafe { x86::controlregs::xcr0_write(val) };
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") xcr,
+            in("eax") val as u32,
+            in("edx") (val >> 32) as u32,
+        );
+    };
+}
+
+/// Writes back and invalidates the caches.
+pub(crate) fn wbinvd() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+/// Reads the performance-monitoring counter specified by `ecx`.
+pub(crate) fn rdpmc(ecx: u32) -> u64 {
+    let (eax, edx): (u32, u32);
+    unsafe {
+        asm!(
+            "rdpmc",
+            in("ecx") ecx,
+            out("eax") eax,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags),
+        );
+    };
+    u64::from(edx) << 32 | u64::from(eax)
 }
 
 /// Reads the TR.
@@ -134,3 +168,34 @@ pub(crate) fn tr() -> SegmentSelector {
 pub(crate) fn ldtr() -> SegmentSelector {
     unsafe { x86::dtables::ldtr() }
 }
+
+/// Invalidates cached EPT translations derived from `eptp`.
+///
+/// See: INVEPT—Invalidate Translations Derived from EPT
+pub(crate) fn invept_single_context(eptp: u64) {
+    // Single-context invalidation. The second quadword of the descriptor is
+    // reserved and must be zero.
+    const SINGLE_CONTEXT: u64 = 1;
+    let descriptor: [u64; 2] = [eptp, 0];
+    unsafe {
+        asm!(
+            "invept {}, [{}]",
+            in(reg) SINGLE_CONTEXT,
+            in(reg) &raw const descriptor,
+            options(nostack),
+        );
+    };
+}
+
+/// Reads the current shadow stack pointer (SSP), or returns 0 if shadow stacks
+/// are not enabled.
+///
+/// See: RDSSPD/RDSSPQ—Read Shadow Stack Pointer
+#[inline(always)]
+pub(crate) fn rdsspq() -> u64 {
+    // RDSSPQ is a NOP when shadow stacks are not enabled, leaving the operand
+    // unmodified.
+    let mut ssp: u64 = 0;
+    unsafe { asm!("rdsspq {}", inout(reg) ssp, options(nomem, nostack, preserves_flags)) };
+    ssp
+}
@@ -0,0 +1,130 @@
+//! This module implements saving and restoring of the guest extended state.
+//!
+//! The host does not switch the extended state (x87, SSE, AVX etc) on VM-exit,
+//! except XMM0-5 that are saved in `Registers`. Exit handlers that use vector
+//! instructions would corrupt the guest state unless the rest is saved with
+//! XSAVES on VM-exit and restored with XRSTORS before VM-entry.
+//!
+//! See: 13.11 Operation of XSAVES
+//! See: 13.12 Operation of XRSTORS
+
+use alloc::boxed::Box;
+use bit_field::BitField;
+use core::{
+    arch::asm,
+    ptr::{addr_of, addr_of_mut},
+};
+use x86::{controlregs::Cr4, cpuid::cpuid};
+
+use crate::hypervisor::{
+    support::{Page, zeroed_box},
+    x86_instructions::{cr4, cr4_write},
+};
+
+/// The number of pages reserved for the XSAVE area of each processor.
+const XSAVE_AREA_PAGES: usize = 4;
+
+/// The extended state of the guest, saved while the host runs.
+pub(crate) struct ExtendedState {
+    area: Box<[Page; XSAVE_AREA_PAGES]>,
+    saved: bool,
+}
+
+impl ExtendedState {
+    /// Allocates the XSAVE area, or returns `None` if the processor does not
+    /// support XSAVES and XRSTORS.
+    pub(crate) fn new() -> Option<Self> {
+        // See: Table 1-3. Information Returned by CPUID Instruction (Leaf 0DH)
+        let regs = cpuid!(0x1);
+        if !regs.ecx.get_bit(26) || !cpuid!(0xd, 1).eax.get_bit(3) {
+            log::warn!("XSAVES is not supported. The extended state is not saved");
+            return None;
+        }
+
+        let size = max_area_size();
+        if size > XSAVE_AREA_PAGES * size_of::<Page>() {
+            log::warn!(
+                "The XSAVE area ({size:#x} bytes) is too large. The extended state is not saved"
+            );
+            return None;
+        }
+
+        Some(Self {
+            area: zeroed_box::<[Page; XSAVE_AREA_PAGES]>(),
+            saved: false,
+        })
+    }
+
+    /// Saves all extended state components enabled in XCR0 and IA32_XSS into the
+    /// XSAVE area.
+    pub(crate) fn save(&mut self) {
+        // The host CR4 might not have this bit, which is required for executing
+        // the `XSAVES` instruction.
+        cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
+
+        let area = addr_of_mut!(*self.area);
+        unsafe {
+            asm!(
+                "xsaves64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            );
+        };
+        self.saved = true;
+    }
+
+    /// Restores the extended state components saved with [`Self::save`]. Does
+    /// nothing if nothing has been saved yet.
+    pub(crate) fn restore(&mut self) {
+        if !self.saved {
+            return;
+        }
+        self.load();
+        self.saved = false;
+    }
+
+    /// Loads the saved extended state into the processor, keeping it saved.
+    pub(crate) fn load(&self) {
+        cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
+
+        let area = addr_of!(*self.area);
+        unsafe {
+            asm!(
+                "xrstors64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            );
+        };
+    }
+
+    /// Copies the extended state saved in `other`.
+    pub(crate) fn copy_from(&mut self, other: &Self) {
+        unsafe { core::ptr::copy_nonoverlapping(other.area.as_ref(), self.area.as_mut(), 1) };
+        self.saved = other.saved;
+    }
+}
+
+/// Returns the upper bound of the size of the XSAVE area in the compacted
+/// format, with all supported components enabled.
+///
+/// See: 13.4.3 Extended Region of an XSAVE Area
+fn max_area_size() -> usize {
+    const LEGACY_REGION_AND_HEADER_SIZE: usize = 512 + 64;
+    const ALIGNMENT: usize = 64;
+
+    let leaf0 = cpuid!(0xd, 0);
+    let leaf1 = cpuid!(0xd, 1);
+    let xcr0_supported = u64::from(leaf0.eax) | (u64::from(leaf0.edx) << 32);
+    let xss_supported = u64::from(leaf1.ecx) | (u64::from(leaf1.edx) << 32);
+    let components = xcr0_supported | xss_supported;
+
+    let mut size = LEGACY_REGION_AND_HEADER_SIZE;
+    for i in 2..63 {
+        if components.get_bit(i) {
+            size += cpuid!(0xd, i as u32).eax as usize + ALIGNMENT - 1;
+        }
+    }
+    size
+}
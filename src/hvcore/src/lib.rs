@@ -1,15 +1,29 @@
 #![no_std]
+// Items shared by the backends are unused when one is compiled out.
+#![cfg_attr(not(all(feature = "intel", feature = "amd")), allow(dead_code))]
 
 extern crate alloc;
 
+#[cfg(not(any(feature = "intel", feature = "amd")))]
+compile_error!("Either or both of the `intel` and `amd` features must be enabled");
+
 pub mod hypervisor;
 
 pub use hypervisor::SharedHostData;
-#[cfg(not(test))]
+#[cfg(not(any(test, feature = "testing")))]
 pub use hypervisor::allocator;
+pub use hypervisor::builder::{Hypervisor, HypervisorBuilder, HypervisorError};
+pub use hypervisor::capabilities::{UnsupportedFeature, check_support};
+pub use hypervisor::config::{ConfigError, HvConfig};
+pub use hypervisor::devirtualize::{DevirtualizeError, devirtualize_system};
+pub use hypervisor::exit_handlers::{VmExitAction, VmExitContext, VmExitHandler};
 pub use hypervisor::gdt_tss::GdtTss;
 pub use hypervisor::interrupt_handlers::InterruptDescriptorTable;
 pub use hypervisor::paging_structures::PagingStructures;
 pub use hypervisor::panic::panic_impl;
 pub use hypervisor::platform_ops;
+pub use hypervisor::platform_ops::PlatformOps;
+pub use hypervisor::revirtualize_system;
+pub use hypervisor::status::{HvStatus, StatusError, is_virtualized, status};
+pub use hypervisor::virtualize_current_processor;
 pub use hypervisor::virtualize_system;
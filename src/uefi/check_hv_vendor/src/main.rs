@@ -3,10 +3,26 @@
 //! ```shell
 //! fs1:\> check_hv_vendor.efi
 //! Executing CPUID(0x40000000) on all logical processors
-//! CPU 0: Barevisor!
-//! CPU 1: Barevisor!
-//! CPU 2: Barevisor!
-//! CPU 3: Barevisor!
+//! CPU 0: Barevisor! Cpuid=pass EptViolation=skip Hypercall=pass MsrIntercepts=pass ApStartup=skip
+//! CPU 1: Barevisor! Cpuid=pass EptViolation=skip Hypercall=pass MsrIntercepts=pass ApStartup=pass
+//! CPU 2: Barevisor! Cpuid=pass EptViolation=skip Hypercall=pass MsrIntercepts=pass ApStartup=pass
+//! CPU 3: Barevisor! Cpuid=pass EptViolation=skip Hypercall=pass MsrIntercepts=pass ApStartup=pass
+//! ```
+//!
+//! The results of the self-test are shown if the hypervisor ran it. See
+//! `HvConfig::self_test`. Likewise, the median cycles of CPUID, RDMSR and
+//! VMCALL before and after virtualization are shown as below if the hypervisor
+//! measured them. See `HvConfig::exit_latency_budget`.
+//!
+//! ```text
+//!         CPUID 120 -> 1512, RDMSR 84 -> 86, VMCALL 1488 cycles
+//! ```
+//!
+//! Finally, the state the hypervisor reports is shown, and whether all
+//! processors are virtualized.
+//!
+//! ```text
+//! Backend: Intel, features: EptViews,Umip, virtualized: 4/4 processors
 //! ```
 
 #![no_main]
@@ -14,13 +30,57 @@
 
 extern crate alloc;
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    arch::asm,
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::string::String;
+use alloc::{format, string::String};
 use uefi::{boot, prelude::*, println, proto::pi::mp::MpServices};
 
 static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+/// The hypercall that returns the results of the self-test on the current
+/// processor. See `hv::hypervisor::hypercall::HC_SELF_TEST_RESULTS`.
+const HC_SELF_TEST_RESULTS: u64 = 0x10;
+
+/// The hypercall that returns the median cycles of the round trips. See
+/// `hv::hypervisor::hypercall::HC_BENCHMARK_RESULTS`.
+const HC_BENCHMARK_RESULTS: u64 = 0x11;
+
+/// The hypercall that returns the state of the hypervisor. See
+/// `hv::hypervisor::hypercall::HC_STATUS`.
+const HC_STATUS: u64 = 0x1a;
+
+/// The names of the features in the order of their bits in the state. See
+/// `hv::hypervisor::status::ActiveFeatures`.
+const FEATURES: [&str; 13] = [
+    "Snapshots",
+    "EptViews",
+    "DeterministicTime",
+    "TscScaling",
+    "GdbStub",
+    "PvClock",
+    "AdvertiseHypervisor",
+    "MsrMonitor",
+    "Umip",
+    "PmuIsolation",
+    "Watchdog",
+    "Topology",
+    "HypercallAuth",
+];
+
+/// The names of the self-test probes, in the order of their 2-bit outcomes in
+/// the results.
+const PROBES: [&str; 5] = [
+    "Cpuid",
+    "EptViolation",
+    "Hypercall",
+    "MsrIntercepts",
+    "ApStartup",
+];
+
 #[entry]
 fn main() -> Status {
     if let Err(e) = uefi::helpers::init() {
@@ -39,15 +99,123 @@ fn main() -> Status {
         } else {
             String::new()
         };
-        println!("CPU{core_id:2}: {vendor}");
+        if vendor == "Barevisor!" {
+            println!("CPU{core_id:2}: {vendor}{}", self_test_results());
+            let [_, native_cpuid, native_rdmsr, _] = hypercall(HC_BENCHMARK_RESULTS, 0);
+            let [_, cpuid, rdmsr, vmcall] = hypercall(HC_BENCHMARK_RESULTS, 1);
+            if cpuid != 0 {
+                println!(
+                    "        CPUID {native_cpuid} -> {cpuid}, RDMSR {native_rdmsr} -> {rdmsr}, \
+                     VMCALL {vmcall} cycles"
+                );
+            }
+        } else {
+            println!("CPU{core_id:2}: {vendor}");
+        }
     }) {
         println!("{e}");
         return e.status();
     }
 
+    if let Some(status) = hypervisor_status() {
+        println!("{status}");
+    }
     Status::SUCCESS
 }
 
+/// Returns the state the hypervisor reports, or `None` if the current
+/// processor is not virtualized.
+fn hypervisor_status() -> Option<String> {
+    let regs = raw_cpuid::cpuid!(0x4000_0000);
+    let vendor = [*b"Bare", *b"viso", *b"r!  "].map(u32::from_le_bytes);
+    if [regs.ebx, regs.ecx, regs.edx] != vendor {
+        return None;
+    }
+
+    let mut virtualized = 0;
+    let mut text = String::new();
+    for index in 0..4 {
+        let [result, rdx, features, bitmap] = hypercall(HC_STATUS, index);
+        if result != 0 {
+            return Some(format!("HC_STATUS failed: {result:#x}"));
+        }
+        let count = (rdx >> 32) as u32;
+        virtualized += bitmap.count_ones();
+        if index == 0 {
+            let backend = match rdx & 0xffff_ffff {
+                1 => "Intel",
+                2 => "AMD",
+                _ => "(unknown)",
+            };
+            let features = FEATURES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| features & (1 << i) != 0)
+                .map(|(_, name)| *name)
+                .collect::<alloc::vec::Vec<_>>()
+                .join(",");
+            text = format!("Backend: {backend}, features: {features}");
+        }
+        if count <= (index as u32 + 1) * 64 {
+            let _ = write!(text, ", virtualized: {virtualized}/{count} processors");
+            break;
+        }
+    }
+    Some(text)
+}
+
+/// Returns the results of the self-test on the current processor, or an empty
+/// string if the hypervisor did not run it.
+fn self_test_results() -> String {
+    let [results, ..] = hypercall(HC_SELF_TEST_RESULTS, 0);
+    if results == 0 {
+        return String::new();
+    }
+    let mut text = String::new();
+    for (i, probe) in PROBES.iter().enumerate() {
+        let outcome = match (results >> (i * 2)) & 0b11 {
+            0 => "-",
+            1 => "pass",
+            2 => "FAIL",
+            _ => "skip",
+        };
+        let _ = write!(text, " {probe}={outcome}");
+    }
+    text
+}
+
+/// Makes the hypercall `number` with `rdx`, and returns RAX, RDX, R8 and R9.
+fn hypercall(number: u64, rdx: u64) -> [u64; 4] {
+    let mut regs = [0u64; 4];
+    let vendor = raw_cpuid::CpuId::new().get_vendor_info().unwrap();
+    if vendor.as_str() == "GenuineIntel" {
+        unsafe {
+            asm!(
+                "vmcall",
+                in("rcx") number,
+                inout("rdx") rdx => regs[1],
+                inout("r8") 0u64 => regs[2],
+                inout("r9") 0u64 => regs[3],
+                in("r10") 0u64,
+                lateout("rax") regs[0],
+            );
+        };
+    } else {
+        unsafe {
+            asm!(
+                "vmmcall",
+                in("rcx") number,
+                inout("rdx") rdx => regs[1],
+                inout("r8") 0u64 => regs[2],
+                inout("r9") 0u64 => regs[3],
+                in("r10") 0u64,
+                lateout("rax") regs[0],
+            );
+        };
+    }
+    regs
+}
+
 fn run_on_all_processors(callback: fn()) -> uefi::Result<()> {
     let handle = boot::get_handle_for_protocol::<MpServices>()?;
     let mp_services = boot::open_protocol_exclusive::<MpServices>(handle)?;
@@ -0,0 +1,99 @@
+//! This module implements the capture of the boot-phase information of the
+//! guest into `hv::hypervisor::boot_info`: the locations of the ACPI tables at
+//! load time, and the final memory map when the OS loader calls
+//! ExitBootServices().
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{ffi::c_void, ptr::NonNull};
+
+use hv::hypervisor::boot_info::{self, MemoryRegion};
+use uefi::{
+    Event,
+    boot::{EventType, MemoryDescriptor, Tpl},
+    prelude::*,
+    table::cfg::{ACPI_GUID, ACPI2_GUID},
+};
+
+use crate::println;
+
+/// The size of the buffer the memory map is captured into, far above the size
+/// of actual memory maps.
+const MEMORY_MAP_BUFFER_SIZE: usize = 0x2_0000;
+
+/// Records the ACPI tables, and with the MADT, the processors of the system.
+/// Called before virtualization.
+pub(crate) fn record_acpi_tables() {
+    let rsdp = system::with_config_table(|entries| {
+        // Prefer the ACPI 2.0 RSDP, which has the XSDT.
+        [ACPI2_GUID, ACPI_GUID].iter().find_map(|guid| {
+            entries
+                .iter()
+                .find(|entry| entry.guid == *guid)
+                .map(|entry| entry.address as u64)
+        })
+    });
+    match rsdp {
+        // Safety: UEFI identity-maps memory, and the tables are there.
+        Some(rsdp) => unsafe { boot_info::record_acpi_tables(rsdp) },
+        None => println!("The ACPI tables are not found"),
+    }
+}
+
+/// Registers the capture of the memory map at ExitBootServices().
+pub(crate) fn register_memory_map_capture() -> uefi::Result<()> {
+    // The notification function must not allocate memory with the boot
+    // services. Allocate the buffer now.
+    let buffer: &mut Vec<u8> = Box::leak(Box::new(vec![0u8; MEMORY_MAP_BUFFER_SIZE]));
+    let _event = unsafe {
+        boot::create_event(
+            EventType::SIGNAL_EXIT_BOOT_SERVICES,
+            Tpl::NOTIFY,
+            Some(capture_memory_map),
+            Some(NonNull::from(buffer).cast()),
+        )
+    }?;
+    Ok(())
+}
+
+/// Records the memory map into the host. Called when the OS loader calls
+/// ExitBootServices(), with the buffer in `context`.
+unsafe extern "efiapi" fn capture_memory_map(_event: Event, context: Option<NonNull<c_void>>) {
+    let buffer = unsafe { context.unwrap().cast::<Vec<u8>>().as_mut() };
+
+    // `boot::memory_map` allocates the buffer with the boot services. Call
+    // GetMemoryMap() directly with the buffer instead.
+    let (mut size, mut key, mut descriptor_size, mut version) = (buffer.len(), 0, 0, 0);
+    let status = unsafe {
+        let boot_services = uefi::table::system_table_raw()
+            .unwrap()
+            .as_ref()
+            .boot_services;
+        ((*boot_services).get_memory_map)(
+            &raw mut size,
+            buffer.as_mut_ptr().cast(),
+            &raw mut key,
+            &raw mut descriptor_size,
+            &raw mut version,
+        )
+    };
+    // Leave the memory map unrecorded on failure. Nothing is printed, as the
+    // console may not be usable during ExitBootServices().
+    if status != Status::SUCCESS || descriptor_size < size_of::<MemoryDescriptor>() {
+        return;
+    }
+
+    let regions = buffer[..size]
+        .chunks_exact(descriptor_size)
+        .map(|chunk| {
+            // Safety: each chunk starts with `MemoryDescriptor`.
+            let descriptor = unsafe { chunk.as_ptr().cast::<MemoryDescriptor>().read_unaligned() };
+            MemoryRegion {
+                memory_type: descriptor.ty.0,
+                start: descriptor.phys_start,
+                pages: descriptor.page_count,
+                attributes: descriptor.att.bits(),
+            }
+        })
+        .collect();
+    boot_info::record_memory_map(regions);
+}
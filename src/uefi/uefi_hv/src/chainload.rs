@@ -0,0 +1,131 @@
+//! This module implements the chainloader mode, where the driver boots the OS
+//! loader right after virtualizing the system instead of returning to the
+//! firmware, so that "virtualize then boot" is a single step.
+//!
+//! The mode is enabled with the `BarevisorBootLoader` EFI variable, which holds
+//! either the path of the OS loader, such as `\EFI\ubuntu\grubx64.efi`, or
+//! `auto` to look for one of [`KNOWN_BOOT_LOADERS`]. The loader is searched on
+//! all volumes, and the first one found is loaded and started. Without the
+//! variable, the driver returns to the firmware as before.
+
+use core::mem::MaybeUninit;
+
+use uefi::{
+    CStr16, Identify,
+    boot::{LoadImageSource, OpenProtocolAttributes, OpenProtocolParams, SearchType},
+    cstr16,
+    prelude::*,
+    proto::{
+        BootPolicy,
+        device_path::{DevicePath, build},
+        media::{
+            file::{File, FileAttribute, FileMode},
+            fs::SimpleFileSystem,
+        },
+    },
+};
+
+use crate::{CONFIG_VENDOR, decode_text, println};
+
+/// The OS loaders looked for in the `auto` mode, in this order.
+const KNOWN_BOOT_LOADERS: [&CStr16; 4] = [
+    cstr16!("\\EFI\\Microsoft\\Boot\\bootmgfw.efi"),
+    cstr16!("\\EFI\\ubuntu\\grubx64.efi"),
+    cstr16!("\\EFI\\debian\\grubx64.efi"),
+    cstr16!("\\EFI\\fedora\\grubx64.efi"),
+];
+
+/// Loads and starts the OS loader if the chainloader mode is enabled. Returns
+/// if the mode is disabled, the loader is not found, or it fails to start or
+/// exits.
+pub(crate) fn boot_os_loader() {
+    let mut buffer = [0u8; 1024];
+    let Ok((data, _)) =
+        runtime::get_variable(cstr16!("BarevisorBootLoader"), &CONFIG_VENDOR, &mut buffer)
+    else {
+        return;
+    };
+    let Some(path) = decode_text(data) else {
+        println!("BarevisorBootLoader is not valid text");
+        return;
+    };
+
+    let mut path_buffer = [0u16; 260];
+    let result = if path.trim() == "auto" {
+        KNOWN_BOOT_LOADERS
+            .iter()
+            .find_map(|&path| boot_from_any_volume(path))
+    } else {
+        let Ok(path) = CStr16::from_str_with_buf(path.trim(), &mut path_buffer) else {
+            println!("BarevisorBootLoader is not a valid path");
+            return;
+        };
+        boot_from_any_volume(path)
+    };
+    match result {
+        None => println!("The OS loader is not found"),
+        Some(Err(e)) => println!("Failed to start the OS loader: {e}"),
+        Some(Ok(())) => println!("The OS loader exited"),
+    }
+}
+
+/// Loads and starts `path` from the first volume that has the file. Returns
+/// `None` if no volume has it.
+fn boot_from_any_volume(path: &CStr16) -> Option<uefi::Result> {
+    let handles =
+        boot::locate_handle_buffer(SearchType::ByProtocol(&SimpleFileSystem::GUID)).ok()?;
+    let device = handles
+        .iter()
+        .copied()
+        .find(|&handle| exists(handle, path))?;
+
+    println!("Starting {path}");
+    Some(start_image(device, path))
+}
+
+/// Returns `true` if the volume on `device` has the file `path`.
+fn exists(device: Handle, path: &CStr16) -> bool {
+    let Ok(mut file_system) = boot::open_protocol_exclusive::<SimpleFileSystem>(device) else {
+        return false;
+    };
+    file_system
+        .open_volume()
+        .and_then(|mut root| root.open(path, FileMode::Read, FileAttribute::empty()))
+        .is_ok()
+}
+
+/// Loads and starts the image `path` on the volume on `device`, with the full
+/// device path, so that the loader can find its own files, such as the BCD
+/// store, relative to its device.
+fn start_image(device: Handle, path: &CStr16) -> uefi::Result {
+    // Safety: the device path protocol is not uninstalled while booting.
+    let device_path = unsafe {
+        boot::open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: device,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }?;
+
+    let mut buffer = [MaybeUninit::uninit(); 1024];
+    let mut builder = build::DevicePathBuilder::with_buf(&mut buffer);
+    for node in device_path.node_iter() {
+        builder = builder.push(&node).map_err(|_| Status::BUFFER_TOO_SMALL)?;
+    }
+    let file_path = builder
+        .push(&build::media::FilePath { path_name: path })
+        .and_then(build::DevicePathBuilder::finalize)
+        .map_err(|_| Status::BUFFER_TOO_SMALL)?;
+
+    let image = boot::load_image(
+        boot::image_handle(),
+        LoadImageSource::FromDevicePath {
+            device_path: file_path,
+            boot_policy: BootPolicy::ExactMatch,
+        },
+    )?;
+    boot::start_image(image)
+}
@@ -4,15 +4,26 @@
 
 extern crate alloc;
 
+mod boot_info;
+mod chainload;
 mod ops;
 mod println;
+mod relocation;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, string::String, vec::Vec};
 use hv::{GdtTss, PagingStructures};
+use relocation::RelocatedImage;
 use uefi::{
+    CStr16,
     boot::{AllocateType, MemoryType},
+    cstr16, guid,
     prelude::*,
-    proto::{loaded_image::LoadedImage, pi::mp::MpServices},
+    proto::{
+        loaded_image::LoadedImage,
+        media::file::{File, FileAttribute, FileMode},
+        pi::mp::MpServices,
+    },
+    runtime::VariableVendor,
 };
 use x86::bits64::task::TaskStateSegment;
 
@@ -26,16 +37,67 @@ fn main() -> Status {
         MemoryType::RUNTIME_SERVICES_DATA,
         hv::allocator::ALLOCATION_PAGES,
     ) {
-        Ok(ptr) => hv::allocator::init(ptr.as_ptr()),
+        Ok(ptr) => {
+            hv::allocator::init(ptr.as_ptr());
+
+            // Keep the heap out of reach of `HC_READ_PHYS`. UEFI identity-maps
+            // memory, so the address is also the physical address.
+            let heap = ptr.as_ptr() as u64;
+            let heap_size = hv::allocator::ALLOCATION_BYTES as u64;
+            hv::hypervisor::phys_read::conceal(heap..heap + heap_size);
+        }
         Err(e) => {
             println!("Memory allocation failed: {e}");
             return e.status();
         }
     }
 
+    let config = load_config();
+    if config.relocate_image {
+        match relocation::relocate() {
+            Ok(image) => {
+                // Continue in the copy. This image must not run anything but
+                // returning to the firmware from here, as the copy owns the
+                // heap. See the `relocation` module.
+                let load_copy: fn(hv::HvConfig, Option<RelocatedImage>) -> Status = unsafe {
+                    core::mem::transmute(
+                        (load as *const ()).wrapping_byte_add(image.delta as usize),
+                    )
+                };
+                return load_copy(config, Some(image));
+            }
+            Err(e) => println!("relocate failed, running at the load address: {e}"),
+        }
+    }
+    load(config, None)
+}
+
+/// Loads the hypervisor with `config`. `image` is the copy of this image if it
+/// has been relocated.
+fn load(config: hv::HvConfig, image: Option<RelocatedImage>) -> Status {
+    let relocation_failed = config.relocate_image && image.is_none();
+    if let Some(image) = image {
+        hv::hypervisor::phys_read::conceal(image.range);
+        hv::hypervisor::phys_read::conceal(image.original.clone());
+        if let Err(e) = relocation::scrub_on_exit_boot_services(image.original) {
+            println!("scrub_on_exit_boot_services failed: {e}");
+        }
+    }
+
     // Register the platform specific API.
     hv::platform_ops::init(Box::new(ops::UefiOps));
 
+    // Show the panic record of the previous boot, if any, and record panics of
+    // this boot.
+    init_panic_buffer();
+
+    // Refuse to load on processors the hypervisor does not support before
+    // making any change to the system.
+    if let Err(e) = hv::check_support() {
+        println!("The processor is not supported: {e}");
+        return Status::UNSUPPORTED;
+    }
+
     // Prevent relocation. See the function comment.
     if let Err(e) = zap_relocation_table() {
         println!("zap_relocation_table failed: {e}");
@@ -46,34 +108,118 @@ fn main() -> Status {
     // Intel processors requires a host GDT to use a TSS. UEFI's default GDT does
     // not use a TSS and needs the update.
     if x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel" {
-        hv::platform_ops::get().run_on_all_processors(|| {
+        hv::platform_ops::get().run_on_all_processors(&|| {
             let new_gdt = Box::leak(Box::new(GdtTss::new_from_current()));
             new_gdt.append_tss(TaskStateSegment::new()).apply().unwrap();
         });
     }
 
+    // Record the ACPI tables, so that the hypervisor knows all processors in
+    // the MADT, including those not started yet.
+    boot_info::record_acpi_tables();
+
     // The UEFI version of the hypervisor needs to have its own IDT, GDT, TSS and
     // paging structures. Create them and use them for the host. Unlike the Windows
     // version, the current IDT, GDT, TSS and paging structures are destroyed as
     // the system transition to the runtime-phase. Thus, the host cannot depend
     // on them and needs its own data structures.
-    match create_shared_host_data() {
-        Ok(shared_host) => hv::virtualize_system(shared_host),
+    match create_shared_host_data(config) {
+        Ok(shared_host) => {
+            if let Err(e) = hv::virtualize_system(shared_host) {
+                println!("virtualize_system failed: {e}");
+                return Status::UNSUPPORTED;
+            }
+        }
         Err(e) => {
             println!("create_shared_host_data failed: {e}");
             return e.status();
         }
     }
 
+    // Report the failure of `relocate_image` into the log of the hypervisor
+    // as well, which is kept unlike the console output.
+    if relocation_failed {
+        log::error!("relocate_image failed. The host runs at the load address");
+    }
+
+    // Record the final memory map for introspection.
+    if let Err(e) = boot_info::register_memory_map_capture() {
+        println!("register_memory_map_capture failed: {e}");
+    }
+
     println!("Loaded uefi_hv.efi");
+
+    // Boot the OS loader if the chainloader mode is enabled.
+    chainload::boot_os_loader();
     Status::SUCCESS
 }
 
-/// Creates `hv::SharedHostData`.
+/// The vendor GUID of the EFI variables of the hypervisor.
+const CONFIG_VENDOR: VariableVendor = VariableVendor(guid!("5e0b1f4a-6a2c-4b8e-9d3f-1c7e2a9b4d60"));
+
+/// The name of the file on the ESP holding the configuration, as a fallback of
+/// the `BarevisorConfig` variable.
+const CONFIG_FILE_NAME: &CStr16 = cstr16!("barevisor.cfg");
+
+/// Loads the configuration from the `BarevisorConfig` EFI variable, or if it
+/// does not exist, from `\barevisor.cfg` on the volume this image was loaded
+/// from. Both hold text in the format of `hv::HvConfig::parse`. Returns the
+/// default configuration if neither exists or the text is not valid.
+///
+/// The variable can be set from the UEFI shell, for example:
+/// `setvar BarevisorConfig -guid 5e0b1f4a-6a2c-4b8e-9d3f-1c7e2a9b4d60 -bs -nv =L"hlt=intercept;pmu=isolate"`
+/// for UTF-16 text, or with `=S"..."` for ASCII text.
+fn load_config() -> hv::HvConfig {
+    let mut buffer = [0u8; 4096];
+    let text = match runtime::get_variable(cstr16!("BarevisorConfig"), &CONFIG_VENDOR, &mut buffer)
+    {
+        Ok((data, _)) => decode_text(data),
+        Err(_) => read_config_file(&mut buffer).and_then(decode_text),
+    };
+    let Some(text) = text else {
+        return hv::HvConfig::default();
+    };
+
+    println!("Loading the configuration:\n{text}");
+    hv::HvConfig::parse(&text).unwrap_or_else(|e| {
+        println!("The configuration is not valid, using the default: {e}");
+        hv::HvConfig::default()
+    })
+}
+
+/// Reads the configuration file into `buffer`, and returns the read part.
+fn read_config_file(buffer: &mut [u8]) -> Option<&[u8]> {
+    let mut file_system = boot::get_image_file_system(boot::image_handle()).ok()?;
+    let mut file = file_system
+        .open_volume()
+        .ok()?
+        .open(CONFIG_FILE_NAME, FileMode::Read, FileAttribute::empty())
+        .ok()?
+        .into_regular_file()?;
+    let size = file.read(buffer).ok()?;
+    Some(&buffer[..size])
+}
+
+/// Decodes `data` as UTF-16LE text if it starts with the byte order mark or
+/// looks like UTF-16, and as UTF-8 text otherwise.
+fn decode_text(data: &[u8]) -> Option<String> {
+    let data = data.strip_suffix(&[0, 0]).unwrap_or(data);
+    if data.starts_with(&[0xff, 0xfe]) || data.get(1) == Some(&0) {
+        let units = data
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .skip_while(|&unit| unit == 0xfeff);
+        char::decode_utf16(units).collect::<Result<_, _>>().ok()
+    } else {
+        core::str::from_utf8(data).ok().map(String::from)
+    }
+}
+
+/// Creates `hv::SharedHostData` with `config`.
 // - GDT and TSS are clones of the current.
 // - IDT is as implemented in `hv::InterruptDescriptorTable`.
 // - Paging structures are identity mapped and all RWX.
-fn create_shared_host_data() -> uefi::Result<hv::SharedHostData> {
+fn create_shared_host_data(config: hv::HvConfig) -> uefi::Result<hv::SharedHostData> {
     /// Gets the number of usable logical processors on this system.
     fn processor_count() -> uefi::Result<u32> {
         let handle = boot::get_handle_for_protocol::<MpServices>()?;
@@ -81,26 +227,54 @@ fn create_shared_host_data() -> uefi::Result<hv::SharedHostData> {
         Ok(u32::try_from(mp_services.get_number_of_processors().unwrap().enabled).unwrap())
     }
 
-    // Each logical processor needs to have its own GDT, so clone the current
-    // GDT and TSS for each processor.
-    let gdt_tss = GdtTss::new_from_current();
-    let mut host_gdt_tss = Vec::<GdtTss>::new();
-    for _ in 0..processor_count()? {
-        host_gdt_tss.push(gdt_tss.clone());
-    }
+    // Each logical processor needs to have its own GDT and TSS with its own IST
+    // stacks, so create them from the current GDT for each processor.
+    let host_gdt_tss: Vec<GdtTss> = (0..processor_count()?)
+        .map(|_| GdtTss::new_for_host())
+        .collect();
 
     let host_idt = hv::InterruptDescriptorTable::new(host_gdt_tss[0].cs);
 
     let mut host_pt = PagingStructures::new();
-    host_pt.build_identity();
+    if let Err(e) = host_pt.build_identity() {
+        println!("build_identity failed: {e}");
+        return Err(Status::ABORTED.into());
+    }
 
     Ok(hv::SharedHostData {
         pt: Some(host_pt),
         idt: Some(host_idt),
-        gdts: Some(host_gdt_tss),
+        gdts: host_gdt_tss,
+        config,
     })
 }
 
+/// Reads back the panic record from the previous boot and sets up the region for
+/// this boot. The region is reserved memory at a fixed physical address so that
+/// the next boot finds it at the same address. If the address is not available
+/// on this machine, panics are not recorded.
+fn init_panic_buffer() {
+    const PANIC_BUFFER_PA: u64 = 0x3f00_0000;
+    const PANIC_BUFFER_PAGES: usize = 16;
+
+    let Ok(ptr) = boot::allocate_pages(
+        AllocateType::Address(PANIC_BUFFER_PA),
+        MemoryType::RESERVED,
+        PANIC_BUFFER_PAGES,
+    ) else {
+        println!("The panic buffer is unavailable at {PANIC_BUFFER_PA:#x}");
+        return;
+    };
+
+    let size = PANIC_BUFFER_PAGES * 0x1000;
+    let region = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), size) };
+    if let Some(record) = hv::hypervisor::panic_buffer::read_record(region) {
+        println!("The hypervisor panicked on the previous boot:\n{record}");
+    }
+    hv::hypervisor::panic_buffer::clear_record(region);
+    unsafe { hv::hypervisor::panic_buffer::set_region(ptr.as_ptr(), size) };
+}
+
 /// Prevents relocation of current module by zapping the Relocation Table in
 /// the PE header.
 // UEFI keeps the list of runtime drivers and applies patches into their code and
@@ -114,8 +288,7 @@ fn create_shared_host_data() -> uefi::Result<hv::SharedHostData> {
 // other way to avoid this issue is to load the hypervisor as shellcode (ie, not
 // being an UEFI runtime driver).
 fn zap_relocation_table() -> uefi::Result<()> {
-    const NT_RELOCATION_DIRECTORY_RVA: u64 = 0x128;
-    const NT_RELOCATION_DIRECTORY_SIZE: u64 = 0x12c;
+    use relocation::{NT_RELOCATION_DIRECTORY_RVA, NT_RELOCATION_DIRECTORY_SIZE};
 
     let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
     let (image_base, image_size) = loaded_image.info();
@@ -1,12 +1,16 @@
-use core::ffi::c_void;
+use core::{ffi::c_void, ptr::NonNull};
 
-use hv::platform_ops::PlatformOps;
-use uefi::{prelude::*, proto::pi::mp::MpServices};
+use hv::platform_ops::{PaError, PlatformOps, RunError};
+use uefi::{
+    boot::{AllocateType, MemoryType},
+    prelude::*,
+    proto::pi::mp::MpServices,
+};
 
 pub(crate) struct UefiOps;
 
 impl PlatformOps for UefiOps {
-    fn run_on_all_processors(&self, callback: fn()) {
+    fn run_on_all_processors(&self, callback: &(dyn Fn() + Sync)) {
         let handle = boot::get_handle_for_protocol::<MpServices>().unwrap();
         let mp_services = boot::open_protocol_exclusive::<MpServices>(handle).unwrap();
 
@@ -15,18 +19,59 @@ impl PlatformOps for UefiOps {
         // The API may return NOT_STARTED if there is no AP on the system. Treat
         // it as ok and all other failures as error.
         if let Err(e) =
-            mp_services.startup_all_aps(true, run_callback, callback as *mut _, None, None)
+            mp_services.startup_all_aps(true, run_callback, context(&callback), None, None)
         {
             assert!(e.status() == Status::NOT_STARTED, "{e}");
         }
     }
 
-    fn pa(&self, va: *const c_void) -> u64 {
-        va as _
+    fn run_on_processor(&self, index: usize, callback: &(dyn Fn() + Sync)) -> Result<(), RunError> {
+        let handle = boot::get_handle_for_protocol::<MpServices>().unwrap();
+        let mp_services = boot::open_protocol_exclusive::<MpServices>(handle).unwrap();
+
+        // The API refuses to start the caller itself. Run the callback directly
+        // instead.
+        if mp_services.who_am_i().unwrap() == index {
+            callback();
+            return Ok(());
+        }
+
+        mp_services
+            .startup_this_ap(index, run_callback, context(&callback), None, None)
+            .map_err(|e| match e.status() {
+                Status::NOT_FOUND | Status::INVALID_PARAMETER => RunError::InvalidIndex { index },
+                _ => RunError::Failed { index },
+            })
+    }
+
+    fn pa(&self, va: *const c_void) -> Result<u64, PaError> {
+        // The address space is identity mapped.
+        Ok(va as _)
     }
+
+    fn alloc_contiguous(&self, size: usize, max_pa: u64) -> *mut c_void {
+        // Pages are physically contiguous as the address space is identity mapped.
+        boot::allocate_pages(
+            AllocateType::MaxAddress(max_pa),
+            MemoryType::RUNTIME_SERVICES_DATA,
+            size.div_ceil(0x1000),
+        )
+        .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr().cast())
+    }
+
+    fn free_contiguous(&self, ptr: *mut c_void, size: usize) {
+        let ptr = NonNull::new(ptr.cast::<u8>()).unwrap();
+        unsafe { boot::free_pages(ptr, size.div_ceil(0x1000)) }.unwrap();
+    }
+}
+
+/// Returns the argument for [`run_callback`] to call `callback`. The argument
+/// is valid while `callback` is.
+fn context(callback: &&(dyn Fn() + Sync)) -> *mut c_void {
+    core::ptr::from_ref(callback).cast_mut().cast()
 }
 
 extern "efiapi" fn run_callback(context: *mut c_void) {
-    let callback: fn() = unsafe { core::mem::transmute(context) };
+    let callback = unsafe { *context.cast::<&(dyn Fn() + Sync)>() };
     callback();
 }
@@ -0,0 +1,254 @@
+//! This module implements the relocation of this image to a random physical
+//! address, so that the guest cannot assume where the host code and data are.
+
+use alloc::boxed::Box;
+use core::{
+    arch::{asm, x86_64::_rdtsc},
+    ffi::c_void,
+    ops::Range,
+    ptr::NonNull,
+};
+
+use uefi::{
+    Event,
+    boot::{AllocateType, EventType, MemoryType, Tpl},
+    mem::memory_map::MemoryMap,
+    prelude::*,
+    proto::loaded_image::LoadedImage,
+};
+use x86::controlregs::{Cr0, cr0, cr0_write};
+
+use crate::println;
+
+/// The offsets of the RVA and the size of the base relocation table in the PE
+/// header of this image.
+pub(crate) const NT_RELOCATION_DIRECTORY_RVA: u64 = 0x128;
+pub(crate) const NT_RELOCATION_DIRECTORY_SIZE: u64 = 0x12c;
+
+/// The lowest address of the copy. Memory below 1MB is left for the startup
+/// of application processors.
+const MIN_ADDRESS: u64 = 0x10_0000;
+
+/// The copy of this image made by [`relocate`].
+pub(crate) struct RelocatedImage {
+    /// The address of the copy minus that of this image.
+    pub(crate) delta: u64,
+
+    /// The physical address range of the copy.
+    pub(crate) range: Range<u64>,
+
+    /// The physical address range of this image.
+    pub(crate) original: Range<u64>,
+}
+
+/// Copies this image to a random address and applies the base relocations to
+/// the copy.
+///
+/// The copy takes the state of this image at this point, including the heap
+/// allocator, and the heap and the panic buffer are not relocated. The caller
+/// must continue in the copy and run nothing else in this image other than
+/// returning to the firmware, and this image must not hold pointers into itself
+/// in its state, which the base relocations do not cover.
+///
+/// # Errors
+///
+/// Returns an error if this image has no base relocations, they are not
+/// supported, or no free memory is large enough for the copy.
+pub(crate) fn relocate() -> uefi::Result<RelocatedImage> {
+    let (image_base, image_size) = {
+        let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
+        let (image_base, image_size) = loaded_image.info();
+        (image_base as u64, usize::try_from(image_size).unwrap())
+    };
+    let (table_rva, table_size) = unsafe {
+        (
+            *((image_base + NT_RELOCATION_DIRECTORY_RVA) as *const u32),
+            *((image_base + NT_RELOCATION_DIRECTORY_SIZE) as *const u32),
+        )
+    };
+    if table_size == 0 {
+        return Err(Status::LOAD_ERROR.into());
+    }
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            (image_base + u64::from(table_rva)) as *const u8,
+            table_size as usize,
+        )
+    };
+
+    let pages = image_size.div_ceil(0x1000);
+    let copy_ptr = allocate_random(pages)?;
+    let copy_base = copy_ptr.as_ptr() as u64;
+    let delta = copy_base.wrapping_sub(image_base);
+    println!("Relocating the image from {image_base:#x} to {copy_base:#x}");
+
+    // Nothing may change the state of this image from here, so that the copy
+    // starts with the state as copied.
+    let copy = unsafe { core::slice::from_raw_parts_mut(copy_ptr.as_ptr(), pages * 0x1000) };
+    let (image, tail) = copy.split_at_mut(image_size);
+    image.copy_from_slice(unsafe {
+        core::slice::from_raw_parts(image_base as *const u8, image_size)
+    });
+    tail.fill(0);
+    if let Err(e) = apply_relocations(image, table, delta) {
+        unsafe { boot::free_pages(copy_ptr, pages) }?;
+        return Err(e);
+    }
+
+    Ok(RelocatedImage {
+        delta,
+        range: copy_base..copy_base + (pages * 0x1000) as u64,
+        original: image_base..image_base + image_size as u64,
+    })
+}
+
+/// Registers filling the `original` image with zeros at ExitBootServices(). By
+/// then, neither the firmware nor the copy runs it, including the return from
+/// the entry point, which the copy returns to.
+pub(crate) fn scrub_on_exit_boot_services(original: Range<u64>) -> uefi::Result<()> {
+    let context: &mut Range<u64> = Box::leak(Box::new(original));
+    let _event = unsafe {
+        boot::create_event(
+            EventType::SIGNAL_EXIT_BOOT_SERVICES,
+            Tpl::NOTIFY,
+            Some(scrub),
+            Some(NonNull::from(context).cast()),
+        )
+    }?;
+    Ok(())
+}
+
+/// Fills the range in `context` with zeros. Called when the OS loader calls
+/// ExitBootServices().
+unsafe extern "efiapi" fn scrub(_event: Event, context: Option<NonNull<c_void>>) {
+    let range = unsafe { context.unwrap().cast::<Range<u64>>().as_ref() };
+
+    // The firmware may map the code of the image read-only. Clear CR0.WP, so
+    // that the write is not blocked by it.
+    let original_cr0 = unsafe { cr0() };
+    unsafe {
+        cr0_write(original_cr0 - Cr0::CR0_WRITE_PROTECT);
+        core::ptr::write_bytes(
+            range.start as *mut u8,
+            0,
+            (range.end - range.start) as usize,
+        );
+        cr0_write(original_cr0);
+    }
+}
+
+/// Allocates `pages` of pages at a random address in conventional memory.
+fn allocate_random(pages: usize) -> uefi::Result<NonNull<u8>> {
+    const ATTEMPTS: usize = 16;
+
+    let memory_map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    let free = || {
+        memory_map
+            .entries()
+            .filter(|descriptor| descriptor.ty == MemoryType::CONVENTIONAL)
+            .map(|descriptor| {
+                let start = descriptor.phys_start.max(MIN_ADDRESS);
+                let end = descriptor.phys_start + descriptor.page_count * 0x1000;
+                (start, end.saturating_sub(start) / 0x1000)
+            })
+    };
+    let pages = pages as u64;
+    let candidates: u64 = free()
+        .map(|(_, free_pages)| (free_pages + 1).saturating_sub(pages))
+        .sum();
+    if candidates == 0 {
+        return Err(Status::OUT_OF_RESOURCES.into());
+    }
+
+    // The memory map may be stale by the time of the allocation, as the
+    // firmware allocates memory on its own. Retry with other addresses then.
+    for _ in 0..ATTEMPTS {
+        let Some(address) = nth_candidate(free(), pages, random() % candidates) else {
+            break;
+        };
+        if let Ok(ptr) = boot::allocate_pages(
+            AllocateType::Address(address),
+            MemoryType::RUNTIME_SERVICES_CODE,
+            pages as usize,
+        ) {
+            return Ok(ptr);
+        }
+    }
+    Err(Status::OUT_OF_RESOURCES.into())
+}
+
+/// Returns the `index`-th page-aligned address where `pages` pages fit in the
+/// `free` ranges, each of which is the start address and the number of pages.
+fn nth_candidate(
+    free: impl Iterator<Item = (u64, u64)>,
+    pages: u64,
+    mut index: u64,
+) -> Option<u64> {
+    for (start, free_pages) in free {
+        let candidates = (free_pages + 1).saturating_sub(pages);
+        if index < candidates {
+            return Some(start + index * 0x1000);
+        }
+        index -= candidates;
+    }
+    None
+}
+
+/// Adds `delta` to the addresses in `image` that the base relocation `table`
+/// lists. Only the DIR64 type, the only one in x64 images, is supported.
+///
+/// See: PE Format, The .reloc Section (Image Only)
+fn apply_relocations(image: &mut [u8], table: &[u8], delta: u64) -> uefi::Result<()> {
+    const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+    const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+    let mut blocks = table;
+    while blocks.len() >= 8 {
+        let page_rva = u32::from_le_bytes(blocks[0..4].try_into().unwrap()) as usize;
+        let block_size = u32::from_le_bytes(blocks[4..8].try_into().unwrap()) as usize;
+        if block_size < 8 || block_size > blocks.len() {
+            return Err(Status::LOAD_ERROR.into());
+        }
+        for entry in blocks[8..block_size].chunks_exact(2) {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_DIR64 => {
+                    let offset = page_rva + usize::from(entry & 0xfff);
+                    let target = image
+                        .get_mut(offset..offset + 8)
+                        .ok_or(Status::LOAD_ERROR)?;
+                    let address = u64::from_le_bytes((&*target).try_into().unwrap());
+                    target.copy_from_slice(&address.wrapping_add(delta).to_le_bytes());
+                }
+                _ => return Err(Status::UNSUPPORTED.into()),
+            }
+        }
+        blocks = &blocks[block_size..];
+    }
+    Ok(())
+}
+
+/// Returns a random number from RDRAND, or the TSC if RDRAND is not supported
+/// or keeps failing, which is predictable to some extent.
+///
+/// See: Intel Digital Random Number Generator (DRNG) Software Implementation Guide, 5.2.1 Retry Recommendations
+fn random() -> u64 {
+    const RETRIES: usize = 10;
+
+    let cpuid = x86::cpuid::CpuId::new();
+    if cpuid
+        .get_feature_info()
+        .is_some_and(|info| info.has_rdrand())
+    {
+        for _ in 0..RETRIES {
+            let (value, success): (u64, u8);
+            unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) success) };
+            if success != 0 {
+                return value;
+            }
+        }
+    }
+    println!("RDRAND is not available. Choosing the address with the TSC");
+    unsafe { _rdtsc() }
+}
@@ -0,0 +1,78 @@
+//! This module implements the bugcheck callback that appends the state of the
+//! hypervisor to crash dumps, so that bugchecks caused by the hypervisor can be
+//! investigated from the dump file.
+//!
+//! The state is saved as secondary dump data, and can be displayed with the
+//! `.enumtag` command of Windbg.
+
+use alloc::boxed::Box;
+use core::ffi::c_void;
+
+use wdk_sys::{
+    _KBUGCHECK_CALLBACK_REASON::KbCallbackSecondaryDumpData, GUID, KBUGCHECK_CALLBACK_REASON,
+    KBUGCHECK_REASON_CALLBACK_RECORD, KBUGCHECK_SECONDARY_DUMP_DATA, NTSTATUS, STATUS_SUCCESS,
+    STATUS_UNSUCCESSFUL, ULONG, ntddk::KeRegisterBugCheckReasonCallback,
+};
+
+use crate::eprintln;
+
+/// The tag of the secondary dump data: {6c3b58e4-1f4a-4f0e-9d4b-62617265760a}
+const DUMP_DATA_GUID: GUID = GUID {
+    Data1: 0x6c3b_58e4,
+    Data2: 0x1f4a,
+    Data3: 0x4f0e,
+    Data4: [0x9d, 0x4b, 0x62, 0x61, 0x72, 0x65, 0x76, 0x0a],
+};
+
+/// Registers the callback that appends the state of the hypervisor to crash
+/// dumps.
+pub(crate) fn register() -> NTSTATUS {
+    // The record must stay valid while the callback is registered, that is,
+    // forever, as this driver does not unload.
+    let record = Box::leak(Box::new(unsafe {
+        core::mem::zeroed::<KBUGCHECK_REASON_CALLBACK_RECORD>()
+    }));
+    let registered = unsafe {
+        KeRegisterBugCheckReasonCallback(
+            record,
+            Some(bugcheck_callback),
+            KbCallbackSecondaryDumpData,
+            c"barevisor".as_ptr().cast_mut().cast(),
+        )
+    };
+    if registered == 0 {
+        eprintln!("KeRegisterBugCheckReasonCallback failed");
+        return STATUS_UNSUCCESSFUL;
+    }
+    STATUS_SUCCESS
+}
+
+/// Writes the report of the hypervisor state into the buffer given by the
+/// system.
+unsafe extern "C" fn bugcheck_callback(
+    reason: KBUGCHECK_CALLBACK_REASON,
+    _record: *mut KBUGCHECK_REASON_CALLBACK_RECORD,
+    reason_specific_data: *mut c_void,
+    _reason_specific_data_length: ULONG,
+) {
+    // "InBuffer: A pointer to a buffer that is allocated by the system. The
+    //  callback routine can use this buffer to store the data, and set
+    //  OutBuffer to InBuffer." The callback is called at IRQL = HIGH_LEVEL,
+    // so it must not allocate memory.
+    // See: KBUGCHECK_SECONDARY_DUMP_DATA structure
+    if reason != KbCallbackSecondaryDumpData {
+        return;
+    }
+    let data = unsafe { &mut *reason_specific_data.cast::<KBUGCHECK_SECONDARY_DUMP_DATA>() };
+    if data.InBuffer.is_null() {
+        return;
+    }
+    let size = data.InBufferLength.min(data.MaximumAllowed) as usize;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(data.InBuffer.cast::<u8>(), size) };
+    let mut writer = hv::hypervisor::crash_dump::BufferWriter::new(buffer);
+    let _ = hv::hypervisor::crash_dump::write_report(&mut writer);
+
+    data.OutBuffer = data.InBuffer;
+    data.OutBufferLength = writer.len() as ULONG;
+    data.Guid = DUMP_DATA_GUID;
+}
@@ -3,20 +3,25 @@
 
 extern crate alloc;
 
+mod bugcheck_callback;
 mod eprintln;
 mod ops;
+mod power_callback;
+mod processor_change;
+mod registry;
 
 use alloc::boxed::Box;
 use wdk_sys::{
-    DRIVER_OBJECT, NTSTATUS, PCUNICODE_STRING, POOL_FLAG_NON_PAGED, STATUS_INSUFFICIENT_RESOURCES,
-    STATUS_SUCCESS, ntddk::ExAllocatePool2,
+    DRIVER_OBJECT, NT_SUCCESS, NTSTATUS, PCUNICODE_STRING, POOL_FLAG_NON_PAGED,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_NOT_SUPPORTED, STATUS_SUCCESS,
+    ntddk::{ExAllocatePool2, ExFreePool},
 };
 
 #[unsafe(link_section = "INIT")]
 #[unsafe(export_name = "DriverEntry")]
 extern "C" fn driver_entry(
     _driver: &mut DRIVER_OBJECT,
-    _registry_path: PCUNICODE_STRING,
+    registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
     const POOL_TAG: u32 = u32::from_ne_bytes(*b"Bare");
     eprintln!("Loading win_hv.sys");
@@ -35,14 +40,47 @@ extern "C" fn driver_entry(
     }
     hv::allocator::init(ptr.cast::<u8>());
 
-    // Register the platform specific API.
-    hv::platform_ops::init(Box::new(ops::WindowsOps));
+    // Virtualize the system with the platform specific API. Only GDTs and TSSes
+    // are given, meaning that host's IDT and page tables are those of the
+    // system process (PID=4). This makes the host debuggable with Windbg but
+    // also breakable from CPL0. The host TSSes give NMI, #DF and #MC handlers
+    // of the system their own stacks.
+    let result = hv::Hypervisor::builder()
+        .with_platform(Box::new(ops::WindowsOps))
+        .with_config(registry::load_config(unsafe { &*registry_path }))
+        // Hot-added processors need host GDTs too. See `processor_change`.
+        .with_processor_count(ops::max_processor_count() as usize)
+        .virtualize();
+    if let Err(e) = result {
+        eprintln!("virtualize failed: {e}");
+        unsafe { ExFreePool(ptr) };
+        return STATUS_NOT_SUPPORTED;
+    }
+
+    // From here, the processors run on the host code of this image, and the
+    // driver must stay loaded. Failures are logged and STATUS_SUCCESS is still
+    // returned, as a failure status would unload the image.
+
+    // Virtualize processors that come online later too.
+    if !NT_SUCCESS(processor_change::register()) {
+        eprintln!("Processors added later are not virtualized");
+    }
 
-    // Virtualize the system. No `SharedHostData` is given, meaning that host's
-    // IDT, GDT, TSS and page tables are all that of the system process (PID=4).
-    // This makes the host debuggable with Windbg but also breakable from CPL0.
-    hv::virtualize_system(hv::SharedHostData::default());
+    // Append the state of the hypervisor to crash dumps.
+    if !NT_SUCCESS(bugcheck_callback::register()) {
+        eprintln!("Crash dumps do not include the state of the hypervisor");
+    }
+
+    // Devirtualize the system before sleep and virtualize it again on resume.
+    if !NT_SUCCESS(power_callback::register()) {
+        eprintln!("The processors are not virtualized again after sleep");
+    }
 
+    // DriverUnload is deliberately not set, so the driver cannot be unloaded
+    // underneath processors still running on its host code and VMCS or VMCB.
+    // `hv::devirtualize_system` rolls back when any processor cannot be
+    // devirtualized, which an unload routine would need to handle by keeping
+    // the driver resident.
     eprintln!("Loaded win_hv.sys");
     STATUS_SUCCESS
 }
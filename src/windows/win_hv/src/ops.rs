@@ -1,23 +1,33 @@
 //! This module implements Windows kernel driver-based implementation of
 //! [`hv::PlatformOps`].
 
-use hv::platform_ops::PlatformOps;
+use hv::platform_ops::{PaError, PlatformOps, RunError};
 use wdk_sys::{
-    ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PAGED_CODE, PROCESSOR_NUMBER,
+    ALL_PROCESSOR_GROUPS, GROUP_AFFINITY, NT_SUCCESS, PAGED_CODE, PHYSICAL_ADDRESS,
+    PROCESSOR_NUMBER,
     ntddk::{
         KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx,
-        KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread, MmGetPhysicalAddress,
+        KeQueryMaximumProcessorCountEx, KeRevertToUserGroupAffinityThread,
+        KeSetSystemGroupAffinityThread, MmAllocateContiguousMemory, MmFreeContiguousMemory,
+        MmGetPhysicalAddress,
     },
 };
 
 pub(crate) struct WindowsOps;
 
-impl PlatformOps for WindowsOps {
-    fn run_on_all_processors(&self, callback: fn()) {
-        fn processor_count() -> u32 {
-            unsafe { KeQueryActiveProcessorCountEx(u16::try_from(ALL_PROCESSOR_GROUPS).unwrap()) }
-        }
+/// Returns the number of active logical processors.
+pub(crate) fn processor_count() -> u32 {
+    unsafe { KeQueryActiveProcessorCountEx(u16::try_from(ALL_PROCESSOR_GROUPS).unwrap()) }
+}
+
+/// Returns the maximum number of logical processors, including those that may
+/// be added later.
+pub(crate) fn max_processor_count() -> u32 {
+    unsafe { KeQueryMaximumProcessorCountEx(u16::try_from(ALL_PROCESSOR_GROUPS).unwrap()) }
+}
 
+impl PlatformOps for WindowsOps {
+    fn run_on_all_processors(&self, callback: &(dyn Fn() + Sync)) {
         PAGED_CODE!();
 
         for index in 0..processor_count() {
@@ -25,24 +35,63 @@ impl PlatformOps for WindowsOps {
             let status = unsafe { KeGetProcessorNumberFromIndex(index, &raw mut processor_number) };
             assert!(NT_SUCCESS(status));
 
-            let mut old_affinity = GROUP_AFFINITY::default();
-            let mut affinity = GROUP_AFFINITY {
-                Group: processor_number.Group,
-                Mask: 1 << processor_number.Number,
-                Reserved: [0, 0, 0],
-            };
-            unsafe { KeSetSystemGroupAffinityThread(&raw mut affinity, &raw mut old_affinity) };
+            run_on_processor(&processor_number, callback);
+        }
+    }
 
-            callback();
+    fn run_on_processor(&self, index: usize, callback: &(dyn Fn() + Sync)) -> Result<(), RunError> {
+        PAGED_CODE!();
 
-            unsafe { KeRevertToUserGroupAffinityThread(&raw mut old_affinity) };
+        let invalid_index = RunError::InvalidIndex { index };
+        let index = u32::try_from(index).map_err(|_| invalid_index)?;
+        if index >= processor_count() {
+            return Err(invalid_index);
+        }
+        let mut processor_number = PROCESSOR_NUMBER::default();
+        let status = unsafe { KeGetProcessorNumberFromIndex(index, &raw mut processor_number) };
+        if !NT_SUCCESS(status) {
+            return Err(invalid_index);
         }
+
+        run_on_processor(&processor_number, callback);
+        Ok(())
     }
 
-    fn pa(&self, va: *const core::ffi::c_void) -> u64 {
+    fn pa(&self, va: *const core::ffi::c_void) -> Result<u64, PaError> {
+        // MmGetPhysicalAddress returns zero if `va` is not mapped.
         #[expect(clippy::cast_sign_loss)]
-        unsafe {
-            MmGetPhysicalAddress(va.cast_mut()).QuadPart as u64
+        let pa = unsafe { MmGetPhysicalAddress(va.cast_mut()).QuadPart as u64 };
+        if pa == 0 {
+            Err(PaError::NotMapped { va: va as usize })
+        } else {
+            Ok(pa)
         }
     }
+
+    fn alloc_contiguous(&self, size: usize, max_pa: u64) -> *mut core::ffi::c_void {
+        #[expect(clippy::cast_possible_wrap)]
+        let highest_acceptable_address = PHYSICAL_ADDRESS {
+            QuadPart: max_pa as i64,
+        };
+        unsafe { MmAllocateContiguousMemory(size as u64, highest_acceptable_address) }
+    }
+
+    fn free_contiguous(&self, ptr: *mut core::ffi::c_void, _size: usize) {
+        unsafe { MmFreeContiguousMemory(ptr) };
+    }
+}
+
+/// Runs `callback` on the logical processor specified by `processor_number`.
+pub(crate) fn run_on_processor(processor_number: &PROCESSOR_NUMBER, callback: &dyn Fn()) {
+    let mut old_affinity = GROUP_AFFINITY::default();
+    let mut affinity = GROUP_AFFINITY {
+        Group: processor_number.Group,
+        Mask: 1 << processor_number.Number,
+        Reserved: [0, 0, 0],
+    };
+    unsafe { KeSetSystemGroupAffinityThread(&raw mut affinity, &raw mut old_affinity) };
+
+    callback();
+
+    unsafe { KeRevertToUserGroupAffinityThread(&raw mut old_affinity) };
 }
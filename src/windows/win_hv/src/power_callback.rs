@@ -0,0 +1,105 @@
+//! This module implements devirtualization before the system enters a sleep
+//! state and re-virtualization after it resumes.
+
+use alloc::vec::Vec;
+use core::{ffi::c_void, ptr::null_mut};
+
+use spin::Mutex;
+use wdk_sys::{
+    NT_SUCCESS, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJECT_ATTRIBUTES, PCALLBACK_OBJECT,
+    STATUS_SUCCESS, STATUS_UNSUCCESSFUL, UNICODE_STRING,
+    ntddk::{ExCreateCallback, ExRegisterCallback, ExUnregisterCallback, ObfDereferenceObject},
+};
+
+use crate::eprintln;
+
+/// Registers the callback that is called on system power state changes.
+pub(crate) fn register() -> NTSTATUS {
+    let mut name: Vec<u16> = "\\Callback\\PowerState".encode_utf16().collect();
+    let length = u16::try_from(name.len() * size_of::<u16>()).unwrap();
+    let mut name = UNICODE_STRING {
+        Length: length,
+        MaximumLength: length,
+        Buffer: name.as_mut_ptr(),
+    };
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: u32::try_from(size_of::<OBJECT_ATTRIBUTES>()).unwrap(),
+        ObjectName: &raw mut name,
+        Attributes: OBJ_CASE_INSENSITIVE,
+        ..Default::default()
+    };
+
+    let mut callback_object: PCALLBACK_OBJECT = null_mut();
+    let status = unsafe { ExCreateCallback(&raw mut callback_object, &raw mut attributes, 0, 1) };
+    if !NT_SUCCESS(status) {
+        eprintln!("ExCreateCallback failed: {status:#x}");
+        return status;
+    }
+
+    let handle =
+        unsafe { ExRegisterCallback(callback_object, Some(power_state_callback), null_mut()) };
+    if handle.is_null() {
+        eprintln!("ExRegisterCallback failed");
+        unsafe { ObfDereferenceObject(callback_object.cast()) };
+        return STATUS_UNSUCCESSFUL;
+    }
+
+    *REGISTRATION.lock() = Some(Registration {
+        callback_object,
+        handle,
+    });
+    STATUS_SUCCESS
+}
+
+/// Unregisters the callback registered with [`register`], and releases the
+/// reference to the callback object. Does nothing if it is not registered.
+#[expect(dead_code)]
+pub(crate) fn unregister() {
+    let Some(registration) = REGISTRATION.lock().take() else {
+        return;
+    };
+    unsafe {
+        ExUnregisterCallback(registration.handle);
+        ObfDereferenceObject(registration.callback_object.cast());
+    }
+}
+
+/// The callback object and the handle of the registered callback.
+struct Registration {
+    callback_object: PCALLBACK_OBJECT,
+    handle: *mut c_void,
+}
+unsafe impl Send for Registration {}
+
+/// The registration made by [`register`]. `None` if it is not registered.
+static REGISTRATION: Mutex<Option<Registration>> = Mutex::new(None);
+
+/// Devirtualizes the processors before the system leaves S0, and virtualizes
+/// them again after it returns to S0, as the virtualization extension is reset
+/// when the processors lose power in S3 and S4.
+unsafe extern "C" fn power_state_callback(
+    _callback_context: *mut c_void,
+    argument1: *mut c_void,
+    argument2: *mut c_void,
+) {
+    // "PO_CB_SYSTEM_STATE_LOCK: Argument2 is FALSE when the computer is about
+    //  to exit the S0 state, and TRUE when the computer has just reentered S0."
+    // See: ExCreateCallback function
+    const PO_CB_SYSTEM_STATE_LOCK: usize = 3;
+
+    if argument1 as usize != PO_CB_SYSTEM_STATE_LOCK {
+        return;
+    }
+
+    if argument2.is_null() {
+        eprintln!("Devirtualizing the system before sleep");
+        if let Err(e) = hv::devirtualize_system() {
+            // The processors lose the virtualization state in sleep anyway,
+            // and are virtualized again on resume where needed.
+            eprintln!("Entering sleep virtualized: {e}");
+        }
+    } else {
+        eprintln!("Re-virtualizing the system after resume");
+        hv::revirtualize_system();
+    }
+}
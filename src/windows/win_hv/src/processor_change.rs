@@ -0,0 +1,48 @@
+//! This module implements virtualization of logical processors that come online
+//! after the driver is loaded, such as hot-added ones.
+
+use core::{ffi::c_void, ptr::null_mut};
+
+use wdk_sys::{
+    _KE_PROCESSOR_CHANGE_NOTIFY_STATE::KeProcessorAddCompleteNotify, NTSTATUS,
+    PKE_PROCESSOR_CHANGE_NOTIFY_CONTEXT, PNTSTATUS, STATUS_SUCCESS, STATUS_UNSUCCESSFUL,
+    ntddk::KeRegisterProcessorChangeCallback,
+};
+
+use crate::{eprintln, ops};
+
+/// Registers the callback that virtualizes processors when they are added.
+pub(crate) fn register() -> NTSTATUS {
+    let handle = unsafe {
+        KeRegisterProcessorChangeCallback(Some(processor_change_callback), null_mut(), 0)
+    };
+    if handle.is_null() {
+        eprintln!("KeRegisterProcessorChangeCallback failed");
+        return STATUS_UNSUCCESSFUL;
+    }
+    STATUS_SUCCESS
+}
+
+/// Virtualizes the added processor once it is started.
+unsafe extern "C" fn processor_change_callback(
+    _callback_context: *mut c_void,
+    change_context: PKE_PROCESSOR_CHANGE_NOTIFY_CONTEXT,
+    _operation_status: PNTSTATUS,
+) {
+    // "KeProcessorAddCompleteNotify: The operating system has successfully
+    //  added the processor." The callback is called at IRQL = PASSIVE_LEVEL.
+    // See: PROCESSOR_CALLBACK_FUNCTION callback function
+    let change_context = unsafe { &*change_context };
+    if change_context.State != KeProcessorAddCompleteNotify {
+        return;
+    }
+
+    eprintln!(
+        "Virtualizing the added processor {}",
+        change_context.NtNumber
+    );
+    ops::run_on_processor(
+        &change_context.ProcNumber,
+        &hv::virtualize_current_processor,
+    );
+}
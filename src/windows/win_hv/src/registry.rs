@@ -0,0 +1,143 @@
+//! This module implements loading of the configuration from the registry.
+//!
+//! The configuration is read from the values under the `Parameters` subkey of
+//! the service key of the driver, for example,
+//! `HKLM\SYSTEM\CurrentControlSet\Services\win_hv\Parameters`. Each value is
+//! applied with `hv::HvConfig::set`, with the value name as the key. REG_SZ
+//! values are taken as is, and REG_DWORD and REG_QWORD values as numbers, so
+//! `1` and `0` work for `bool` fields. For example:
+//!
+//! ```text
+//! reg add HKLM\SYSTEM\CurrentControlSet\Services\win_hv\Parameters /v hlt /t REG_SZ /d intercept
+//! reg add HKLM\SYSTEM\CurrentControlSet\Services\win_hv\Parameters /v pv_clock /t REG_DWORD /d 1
+//! ```
+
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::ptr::null_mut;
+
+use wdk_sys::{
+    _KEY_VALUE_INFORMATION_CLASS::KeyValueFullInformation,
+    HANDLE, KEY_READ, KEY_VALUE_FULL_INFORMATION, NT_SUCCESS, OBJ_CASE_INSENSITIVE,
+    OBJ_KERNEL_HANDLE, OBJECT_ATTRIBUTES, REG_DWORD, REG_QWORD, REG_SZ, STATUS_BUFFER_OVERFLOW,
+    STATUS_BUFFER_TOO_SMALL, UNICODE_STRING,
+    ntddk::{ZwClose, ZwEnumerateValueKey, ZwOpenKey},
+};
+
+use crate::eprintln;
+
+/// Loads the configuration from the `Parameters` subkey of `registry_path`.
+/// Returns the default configuration if the subkey does not exist. Values that
+/// are not valid are ignored with a message.
+pub(crate) fn load_config(registry_path: &UNICODE_STRING) -> hv::HvConfig {
+    let mut config = hv::HvConfig::default();
+    let Some(key) = open_parameters_key(registry_path) else {
+        return config;
+    };
+
+    for index in 0.. {
+        let Some((name, value)) = enumerate_value(key, index) else {
+            break;
+        };
+        let Some(value) = value else {
+            eprintln!("Ignored the registry value {name} of an unsupported type");
+            continue;
+        };
+        if let Err(e) = config.set(&name, &value) {
+            eprintln!("Ignored the registry value {name}={value}: {e}");
+        }
+    }
+    let _ = unsafe { ZwClose(key) };
+    config
+}
+
+/// Opens `<registry_path>\Parameters` for read.
+fn open_parameters_key(registry_path: &UNICODE_STRING) -> Option<HANDLE> {
+    let service_key = unsafe {
+        core::slice::from_raw_parts(
+            registry_path.Buffer,
+            usize::from(registry_path.Length) / size_of::<u16>(),
+        )
+    };
+    let mut name: Vec<u16> = service_key
+        .iter()
+        .copied()
+        .chain("\\Parameters".encode_utf16())
+        .collect();
+    let length = u16::try_from(name.len() * size_of::<u16>()).ok()?;
+    let mut name = UNICODE_STRING {
+        Length: length,
+        MaximumLength: length,
+        Buffer: name.as_mut_ptr(),
+    };
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: u32::try_from(size_of::<OBJECT_ATTRIBUTES>()).unwrap(),
+        ObjectName: &raw mut name,
+        Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+        ..Default::default()
+    };
+
+    let mut key: HANDLE = null_mut();
+    let status = unsafe { ZwOpenKey(&raw mut key, KEY_READ, &raw mut attributes) };
+    NT_SUCCESS(status).then_some(key)
+}
+
+/// Returns the name and the value as text of the `index`-th value of `key`,
+/// or `None` if there is no more value. The value is `None` if its type is
+/// not supported.
+fn enumerate_value(key: HANDLE, index: u32) -> Option<(String, Option<String>)> {
+    // Retry with the size the function returns if the buffer is too small.
+    let mut buffer = vec![0u64; 64];
+    loop {
+        let mut result_length = 0;
+        let status = unsafe {
+            ZwEnumerateValueKey(
+                key,
+                index,
+                KeyValueFullInformation,
+                buffer.as_mut_ptr().cast(),
+                u32::try_from(buffer.len() * size_of::<u64>()).unwrap(),
+                &raw mut result_length,
+            )
+        };
+        if status == STATUS_BUFFER_OVERFLOW || status == STATUS_BUFFER_TOO_SMALL {
+            buffer = vec![0u64; (result_length as usize).div_ceil(size_of::<u64>())];
+            continue;
+        }
+        if !NT_SUCCESS(status) {
+            return None;
+        }
+        break;
+    }
+
+    let info = unsafe { &*buffer.as_ptr().cast::<KEY_VALUE_FULL_INFORMATION>() };
+    let base = buffer.as_ptr().cast::<u8>();
+    let name = unsafe {
+        core::slice::from_raw_parts(
+            info.Name.as_ptr(),
+            info.NameLength as usize / size_of::<u16>(),
+        )
+    };
+    let data = unsafe {
+        core::slice::from_raw_parts(base.add(info.DataOffset as usize), info.DataLength as usize)
+    };
+
+    let name = String::from_utf16_lossy(name);
+    let value = match (info.Type, data.len()) {
+        (REG_DWORD, 4) => Some(u32::from_le_bytes(data.try_into().unwrap()).to_string()),
+        (REG_QWORD, 8) => Some(u64::from_le_bytes(data.try_into().unwrap()).to_string()),
+        (REG_SZ, _) => {
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            Some(String::from_utf16_lossy(&units))
+        }
+        _ => None,
+    };
+    Some((name, value))
+}